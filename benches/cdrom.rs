@@ -0,0 +1,90 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use psx_emu::PSXEmu;
+
+mod fixtures;
+use fixtures::*;
+
+const SECTORS_PER_ITER: usize = 75;
+/// Bail out instead of spinning forever if the drive stops responding
+const MAX_CYCLES_PER_IRQ: usize = 2_000_000;
+
+const CD_INDEX: u32 = 0x1F80_1800;
+const CD_COMMAND: u32 = 0x1F80_1801;
+const CD_PARAMETER: u32 = 0x1F80_1802;
+const CD_REQUEST: u32 = 0x1F80_1803;
+
+const DPCR: u32 = 0x1F80_10F0;
+const CD_DMA_MADR: u32 = 0x1F80_10B0;
+const CD_DMA_BCR: u32 = 0x1F80_10B4;
+const CD_DMA_CHCR: u32 = 0x1F80_10B8;
+
+fn write_cd(emu: &mut PSXEmu, index: u8, addr: u32, value: u8) {
+    emu.main_bus.write_byte(CD_INDEX, index, &mut emu.scheduler);
+    emu.main_bus.write_byte(addr, value, &mut emu.scheduler);
+}
+
+/// Runs the machine until the drive raises an interrupt, then acknowledges it
+fn wait_for_irq(emu: &mut PSXEmu) -> u8 {
+    for _ in 0..MAX_CYCLES_PER_IRQ {
+        emu.step_cycle();
+        emu.main_bus.write_byte(CD_INDEX, 1, &mut emu.scheduler);
+        let flag = emu.main_bus.read_byte(CD_REQUEST) & 0x1F;
+        if flag != 0 {
+            write_cd(emu, 1, CD_REQUEST, 0x1F);
+            return flag;
+        }
+    }
+    panic!("CDROM never raised an interrupt");
+}
+
+fn command(emu: &mut PSXEmu, command: u8, parameters: &[u8]) {
+    for parameter in parameters {
+        write_cd(emu, 0, CD_PARAMETER, *parameter);
+    }
+    write_cd(emu, 0, CD_COMMAND, command);
+    wait_for_irq(emu);
+}
+
+/// Seeks to the start of the disc and starts a ReadN in double speed, data only mode
+fn start_reading(emu: &mut PSXEmu) {
+    emu.main_bus.write_word(DPCR, 0x0000_8000, &mut emu.scheduler);
+    write_cd(emu, 1, CD_PARAMETER, 0x1F);
+    command(emu, 0x0E, &[0x80]);
+    command(emu, 0x02, &[0x00, 0x02, 0x00]);
+    command(emu, 0x06, &[]);
+}
+
+/// Waits for each sector, then DMAs it into RAM like a game's read callback would
+fn stream_sectors(emu: &mut PSXEmu, sectors: usize) {
+    for _ in 0..sectors {
+        while wait_for_irq(emu) != 1 {}
+
+        write_cd(emu, 0, CD_REQUEST, 0x80);
+        emu.main_bus.write_word(CD_DMA_MADR, DATA_ADDR & 0x1F_FFFF, &mut emu.scheduler);
+        emu.main_bus.write_word(CD_DMA_BCR, 0x200, &mut emu.scheduler);
+        emu.main_bus.write_word(CD_DMA_CHCR, 0x1100_0000, &mut emu.scheduler);
+        emu.step_cycle();
+    }
+}
+
+fn sector_streaming(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cdrom");
+    group.throughput(Throughput::Elements(SECTORS_PER_ITER as u64));
+    group.sample_size(10);
+    group.bench_function("read_dma", |b| {
+        b.iter_batched(
+            || {
+                let mut emu = emu();
+                emu.load_disc(generated_disc(SECTORS_PER_ITER + 16));
+                start_reading(&mut emu);
+                emu
+            },
+            |mut emu| stream_sectors(&mut emu, SECTORS_PER_ITER),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, sector_streaming);
+criterion_main!(benches);
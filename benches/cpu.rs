@@ -0,0 +1,45 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+mod fixtures;
+use fixtures::*;
+
+const INSTRUCTIONS_PER_ITER: u64 = 10_000;
+
+/// A loop mixing loads, stores, ALU ops and a branch, roughly the shape of typical game code
+fn alu_loop() -> Vec<u32> {
+    let mut asm = Assembler::new(PROGRAM_ADDR);
+    asm.li(S0, DATA_ADDR);
+    asm.addiu(T0, ZERO, 1);
+
+    let top = asm.label();
+    asm.lw(T1, 0, S0);
+    asm.addu(T2, T1, T0);
+    asm.sll(T3, T2, 2);
+    asm.xor(T4, T3, T1);
+    asm.sw(T4, 4, S0);
+    asm.slt(T5, T4, T2);
+    asm.or(T6, T5, T3);
+    asm.addiu(T0, T0, 1);
+    asm.bne(T0, ZERO, top);
+    asm.sw(T6, 0, S0);
+    asm.finish()
+}
+
+fn interpreter(c: &mut Criterion) {
+    let mut emu = emu();
+    load_program(&mut emu, &alu_loop());
+
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(INSTRUCTIONS_PER_ITER));
+    group.bench_function("alu_loop", |b| {
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS_PER_ITER {
+                emu.run_cpu_instruction();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
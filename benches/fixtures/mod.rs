@@ -0,0 +1,223 @@
+//! Fixtures shared by the benchmarks. Everything is generated here so `cargo bench` runs
+//! without a BIOS dump or game disc. Each bench only uses part of this module.
+#![allow(dead_code)]
+
+use psx_emu::cdrom::disc::{Disc, DiscTrack};
+use psx_emu::PSXEmu;
+
+pub const BIOS_SIZE: usize = 512 * 1024;
+/// Set this to a real BIOS image to make the frame benchmark boot it instead of the synthetic one
+pub const BIOS_ENV_VAR: &str = "FOGSTATION_BENCH_BIOS";
+
+/// Where benchmark programs are placed in RAM
+pub const PROGRAM_ADDR: u32 = 0x8001_0000;
+/// Scratch RAM used by the programs for loads and stores
+pub const DATA_ADDR: u32 = 0x8002_0000;
+
+const BYTES_PER_SECTOR: usize = 2352;
+
+// Register numbers
+pub const ZERO: u32 = 0;
+pub const T0: u32 = 8;
+pub const T1: u32 = 9;
+pub const T2: u32 = 10;
+pub const T3: u32 = 11;
+pub const T4: u32 = 12;
+pub const T5: u32 = 13;
+pub const T6: u32 = 14;
+pub const S0: u32 = 16;
+pub const S1: u32 = 17;
+
+/// Just enough of a MIPS assembler to write the benchmark programs. Branch targets are word indexes into the program
+pub struct Assembler {
+    base: u32,
+    words: Vec<u32>,
+}
+
+impl Assembler {
+    pub fn new(base: u32) -> Self {
+        Self { base, words: vec![] }
+    }
+
+    /// Index of the next instruction, for use as a branch target
+    pub fn label(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn finish(self) -> Vec<u32> {
+        self.words
+    }
+
+    fn r_type(&mut self, rs: u32, rt: u32, rd: u32, shamt: u32, funct: u32) {
+        self.words.push((rs << 21) | (rt << 16) | (rd << 11) | (shamt << 6) | funct);
+    }
+
+    fn i_type(&mut self, op: u32, rs: u32, rt: u32, imm: u16) {
+        self.words.push((op << 26) | (rs << 21) | (rt << 16) | imm as u32);
+    }
+
+    pub fn nop(&mut self) {
+        self.words.push(0);
+    }
+
+    pub fn sll(&mut self, rd: u32, rt: u32, shamt: u32) {
+        self.r_type(0, rt, rd, shamt, 0x00);
+    }
+
+    pub fn addu(&mut self, rd: u32, rs: u32, rt: u32) {
+        self.r_type(rs, rt, rd, 0, 0x21);
+    }
+
+    pub fn or(&mut self, rd: u32, rs: u32, rt: u32) {
+        self.r_type(rs, rt, rd, 0, 0x25);
+    }
+
+    pub fn xor(&mut self, rd: u32, rs: u32, rt: u32) {
+        self.r_type(rs, rt, rd, 0, 0x26);
+    }
+
+    pub fn slt(&mut self, rd: u32, rs: u32, rt: u32) {
+        self.r_type(rs, rt, rd, 0, 0x2A);
+    }
+
+    pub fn addiu(&mut self, rt: u32, rs: u32, imm: i16) {
+        self.i_type(0x09, rs, rt, imm as u16);
+    }
+
+    pub fn ori(&mut self, rt: u32, rs: u32, imm: u16) {
+        self.i_type(0x0D, rs, rt, imm);
+    }
+
+    pub fn lui(&mut self, rt: u32, imm: u16) {
+        self.i_type(0x0F, 0, rt, imm);
+    }
+
+    pub fn lw(&mut self, rt: u32, offset: i16, base: u32) {
+        self.i_type(0x23, base, rt, offset as u16);
+    }
+
+    pub fn sw(&mut self, rt: u32, offset: i16, base: u32) {
+        self.i_type(0x2B, base, rt, offset as u16);
+    }
+
+    pub fn bne(&mut self, rs: u32, rt: u32, target: usize) {
+        let offset = target as i32 - (self.words.len() as i32 + 1);
+        self.i_type(0x05, rs, rt, offset as i16 as u16);
+    }
+
+    pub fn j(&mut self, target: usize) {
+        let addr = self.base + target as u32 * 4;
+        self.words.push((0x02 << 26) | ((addr >> 2) & 0x3FF_FFFF));
+    }
+
+    pub fn mtc0(&mut self, rt: u32, rd: u32) {
+        self.words.push((0x10 << 26) | (0x04 << 21) | (rt << 16) | (rd << 11));
+    }
+
+    pub fn mtc2(&mut self, rt: u32, rd: u32) {
+        self.words.push((0x12 << 26) | (0x04 << 21) | (rt << 16) | (rd << 11));
+    }
+
+    pub fn ctc2(&mut self, rt: u32, rd: u32) {
+        self.words.push((0x12 << 26) | (0x06 << 21) | (rt << 16) | (rd << 11));
+    }
+
+    /// Issues a GTE command, e.g. 0x0280030 for RTPT
+    pub fn cop2(&mut self, command: u32) {
+        self.words.push((0x12 << 26) | (1 << 25) | (command & 0x1FF_FFFF));
+    }
+
+    /// Loads a full 32 bit value
+    pub fn li(&mut self, rt: u32, value: u32) {
+        self.lui(rt, (value >> 16) as u16);
+        self.ori(rt, rt, value as u16);
+    }
+}
+
+/// Iterations of the busy loop between redraws. Each one is 3 instructions, so the screen is redrawn
+/// a few times per frame, like a game that is running behind
+const REDRAW_DELAY: u32 = 0x4000;
+
+/// A BIOS image that sets up a 320x240 display, then keeps redrawing a full screen rectangle.
+/// Enough to keep the CPU, GPU and scheduler busy for whole frame benchmarks
+pub fn synthetic_bios() -> Vec<u8> {
+    let mut asm = Assembler::new(0xBFC0_0000);
+    asm.lui(S0, 0x1F80);
+
+    // Display setup through GP1, then the drawing area through GP0
+    let gp1_setup = [0x0000_0000, 0x0300_0000, 0x0800_0001, 0x0500_0000, 0x0600_0000 | (0x260 + 320 * 8) << 12 | 0x260, 0x0700_0000 | (0x10 + 240) << 10 | 0x10];
+    for command in gp1_setup {
+        asm.li(T0, command);
+        asm.sw(T0, 0x1814, S0);
+    }
+    let gp0_setup = [0xE100_0000, 0xE300_0000, 0xE400_0000 | (239 << 10) | 319, 0xE500_0000];
+    for command in gp0_setup {
+        asm.li(T0, command);
+        asm.sw(T0, 0x1810, S0);
+    }
+
+    let draw_loop = asm.label();
+    for word in [0x6000_8040, 0x0000_0000, (240 << 16) | 320] {
+        asm.li(T0, word);
+        asm.sw(T0, 0x1810, S0);
+    }
+    asm.li(T1, REDRAW_DELAY);
+    let delay_loop = asm.label();
+    asm.addiu(T1, T1, -1);
+    asm.bne(T1, ZERO, delay_loop);
+    asm.nop();
+    asm.j(draw_loop);
+    asm.nop();
+
+    let mut image = vec![0; BIOS_SIZE];
+    for (i, word) in asm.finish().into_iter().enumerate() {
+        image[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    image
+}
+
+/// Uses the BIOS from FOGSTATION_BENCH_BIOS if it is set, so results can be compared with a real boot
+pub fn bios() -> Vec<u8> {
+    match std::env::var(BIOS_ENV_VAR) {
+        Ok(path) => std::fs::read(&path).unwrap_or_else(|e| panic!("Unable to read {}: {}", path, e)),
+        Err(_) => synthetic_bios(),
+    }
+}
+
+/// An emulator running the synthetic BIOS, for benchmarks that drive the hardware directly
+pub fn emu() -> PSXEmu {
+    PSXEmu::new(synthetic_bios()).expect("Synthetic BIOS is the wrong size")
+}
+
+/// Copies a program into RAM and points the CPU at it
+pub fn load_program(emu: &mut PSXEmu, program: &[u32]) {
+    for (i, word) in program.iter().enumerate() {
+        emu.main_bus
+            .write_word(PROGRAM_ADDR + i as u32 * 4, *word, &mut emu.scheduler);
+    }
+    emu.r3000.set_pc(PROGRAM_ADDR);
+}
+
+fn to_bcd(value: usize) -> u8 {
+    ((value / 10) << 4 | (value % 10)) as u8
+}
+
+/// A single track disc of Mode 2 sectors. Each sector has a valid header and its user data filled with its LBA
+pub fn generated_disc(sectors: usize) -> Disc {
+    let mut track = vec![0; sectors * BYTES_PER_SECTOR];
+    for (lba, sector) in track.chunks_exact_mut(BYTES_PER_SECTOR).enumerate() {
+        // Sync pattern
+        sector[1..11].fill(0xFF);
+        // The data track starts 2 seconds in
+        let address = lba + 150;
+        sector[12] = to_bcd(address / 75 / 60);
+        sector[13] = to_bcd(address / 75 % 60);
+        sector[14] = to_bcd(address % 75);
+        sector[15] = 2;
+        sector[24..24 + 0x800].fill(lba as u8);
+    }
+
+    let mut disc = Disc::new("bench.bin");
+    disc.add_track(DiscTrack::new(track));
+    disc
+}
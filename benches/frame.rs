@@ -0,0 +1,32 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use psx_emu::PSXEmu;
+
+mod fixtures;
+use fixtures::*;
+
+const FRAMES_PER_ITER: u64 = 10;
+
+/// Boots from reset and runs whole frames. This uses the synthetic BIOS unless FOGSTATION_BENCH_BIOS
+/// points at a real one, in which case it covers the first frames of the real boot sequence
+fn whole_frames(c: &mut Criterion) {
+    let bios = bios();
+
+    let mut group = c.benchmark_group("frame");
+    group.throughput(Throughput::Elements(FRAMES_PER_ITER));
+    group.sample_size(10);
+    group.bench_function("boot", |b| {
+        b.iter_batched(
+            || PSXEmu::new(bios.clone()).expect("BIOS is the wrong size"),
+            |mut emu| {
+                for _ in 0..FRAMES_PER_ITER {
+                    emu.run_frame();
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, whole_frames);
+criterion_main!(benches);
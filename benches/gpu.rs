@@ -0,0 +1,71 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use psx_emu::PSXEmu;
+
+mod fixtures;
+use fixtures::*;
+
+/// Every triangle covers half of a 320x240 screen
+const PIXELS_PER_TRIANGLE: u64 = 320 * 240 / 2;
+
+fn vertex(x: u32, y: u32) -> u32 {
+    (y << 16) | x
+}
+
+/// Sets up a 320x240 drawing area, plus a white 15 bit texture at (640, 0) for the textured triangles
+fn gpu_emu() -> PSXEmu {
+    let mut emu = emu();
+    let gpu = &mut emu.main_bus.gpu;
+    for command in [
+        0xE100_0000 | (2 << 7) | 10,
+        0xE300_0000,
+        0xE400_0000 | (239 << 10) | 319,
+        0xE500_0000,
+        0x02FF_FFFF,
+        640,
+        (256 << 16) | 256,
+    ] {
+        gpu.send_gp0_command(command);
+    }
+    emu
+}
+
+fn fill_rate(c: &mut Criterion) {
+    let white = 0x00FF_FFFF;
+    let triangles: [(&str, Vec<u32>); 3] = [
+        ("flat", vec![0x2000_8040, vertex(0, 0), vertex(320, 0), vertex(0, 240)]),
+        (
+            "gouraud",
+            vec![0x3000_00FF, vertex(0, 0), 0x0000_FF00, vertex(320, 0), 0x00FF_0000, vertex(0, 240)],
+        ),
+        (
+            // Texpage is 15 bit at x 640, so the clut word is ignored
+            "textured",
+            vec![
+                0x2400_0000 | white,
+                vertex(0, 0),
+                0,
+                vertex(320, 0),
+                ((2 << 7 | 10) << 16) | 0xFF,
+                vertex(0, 240),
+                0xFF00,
+            ],
+        ),
+    ];
+
+    let mut group = c.benchmark_group("gpu");
+    group.throughput(Throughput::Elements(PIXELS_PER_TRIANGLE));
+    for (name, commands) in triangles {
+        let mut emu = gpu_emu();
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for command in &commands {
+                    emu.main_bus.gpu.send_gp0_command(*command);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fill_rate);
+criterion_main!(benches);
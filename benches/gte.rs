@@ -0,0 +1,81 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+mod fixtures;
+use fixtures::*;
+
+const INSTRUCTIONS_PER_ITER: u64 = 10_000;
+
+const RTPT: u32 = 0x0280030;
+const NCDS: u32 = 0x0E80413;
+
+/// Enables cop2, loads a rotation matrix and three vertices, then loops on a GTE command.
+/// The loop is mostly GTE commands, so the numbers are dominated by the GTE rather than the interpreter
+fn gte_loop(command: u32) -> Vec<u32> {
+    let mut asm = Assembler::new(PROGRAM_ADDR);
+    asm.lui(T0, 0x4000);
+    asm.mtc0(T0, 12);
+
+    // Identity rotation, a screen offset and projection distance so RTPT does real divides
+    asm.li(T0, 0x1000);
+    asm.ctc2(T0, 0);
+    asm.ctc2(ZERO, 1);
+    asm.ctc2(T0, 2);
+    asm.ctc2(ZERO, 3);
+    asm.ctc2(T0, 4);
+    asm.li(T0, 160 << 16);
+    asm.ctc2(T0, 24);
+    asm.li(T0, 120 << 16);
+    asm.ctc2(T0, 25);
+    asm.li(T0, 200);
+    asm.ctc2(T0, 26);
+    // Light and color matrices for NCDS
+    asm.li(T0, 0x0800_0800);
+    for reg in 8..=12 {
+        asm.ctc2(T0, reg);
+    }
+    for reg in 16..=20 {
+        asm.ctc2(T0, reg);
+    }
+
+    // V0-V2, with z far enough out to avoid clipping
+    for (i, (xy, z)) in [(0x0010_0020u32, 0x400u32), (0x0030_FFE0, 0x500), (0xFFD0_0010, 0x600)]
+        .iter()
+        .enumerate()
+    {
+        asm.li(T0, *xy);
+        asm.mtc2(T0, i as u32 * 2);
+        asm.li(T0, *z);
+        asm.mtc2(T0, i as u32 * 2 + 1);
+    }
+    asm.li(T0, 0x0080_4020);
+    asm.mtc2(T0, 6);
+
+    let top = asm.label();
+    for _ in 0..8 {
+        asm.cop2(command);
+    }
+    asm.j(top);
+    asm.nop();
+    asm.finish()
+}
+
+fn gte(c: &mut Criterion) {
+    let mut group = c.benchmark_group("gte");
+    group.throughput(Throughput::Elements(INSTRUCTIONS_PER_ITER));
+
+    for (name, command) in [("rtpt", RTPT), ("ncds", NCDS)] {
+        let mut emu = emu();
+        load_program(&mut emu, &gte_loop(command));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                for _ in 0..INSTRUCTIONS_PER_ITER {
+                    emu.run_cpu_instruction();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, gte);
+criterion_main!(benches);
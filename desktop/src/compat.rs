@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+/// Read from the working directory unless --compat-db points somewhere else
+pub const DEFAULT_COMPAT_PATH: &str = "compat.toml";
+
+/// What's known about a game. In the file, each game is a table named after its serial:
+///
+/// ```toml
+/// [SCUS-94163]
+/// needs_analog = true
+/// note = "Camera is stuck without the right stick"
+/// ```
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct CompatEntry {
+    pub needs_spu: bool,
+    pub needs_analog: bool,
+    pub needs_multitap: bool,
+    pub known_broken: bool,
+    pub note: Option<String>,
+}
+
+impl CompatEntry {
+    /// Text for the boot warning. None if the entry has nothing worth warning about
+    pub fn warning(&self) -> Option<String> {
+        let needs: Vec<&str> = [
+            (self.needs_spu, "sound"),
+            (self.needs_analog, "an analog pad"),
+            (self.needs_multitap, "a multitap"),
+        ]
+        .iter()
+        .filter(|(needed, _)| *needed)
+        .map(|(_, name)| *name)
+        .collect();
+
+        let mut parts = vec![];
+        if self.known_broken {
+            parts.push("Known not to work".to_string());
+        }
+        if !needs.is_empty() {
+            parts.push(format!("Needs {}", needs.join(", ")));
+        }
+        if let Some(note) = &self.note {
+            parts.push(note.clone());
+        }
+
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(". "))
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CompatDb {
+    entries: HashMap<String, CompatEntry>,
+}
+
+impl CompatDb {
+    pub fn parse(contents: &str) -> Result<Self, toml::de::Error> {
+        Ok(CompatDb {
+            entries: toml::from_str(contents)?,
+        })
+    }
+
+    /// Loads the table at `path`, or compat.toml if there isn't one. A missing compat.toml just
+    /// means an empty table, but anything else that goes wrong is reported
+    pub fn load(path: Option<&Path>) -> Self {
+        let contents = match (path, fs::read_to_string(path.unwrap_or(Path::new(DEFAULT_COMPAT_PATH)))) {
+            (_, Ok(contents)) => contents,
+            (Some(path), Err(e)) => {
+                println!("Unable to read {}: {}. Compatibility warnings are off", path.display(), e);
+                return CompatDb::default();
+            }
+            (None, Err(_)) => return CompatDb::default(),
+        };
+
+        CompatDb::parse(&contents).unwrap_or_else(|e| {
+            let path = path.unwrap_or(Path::new(DEFAULT_COMPAT_PATH));
+            println!("Failed to parse {}: {}. Compatibility warnings are off", path.display(), e);
+            CompatDb::default()
+        })
+    }
+
+    /// Boot warning for the game, if it has one. Unknown serials get nothing
+    pub fn warning(&self, serial: &str) -> Option<String> {
+        self.entries.get(serial).and_then(CompatEntry::warning)
+    }
+}
+
+#[cfg(test)]
+mod compat_tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings() {
+        let db = CompatDb::parse(
+            r#"
+            [SCUS-94163]
+            needs_analog = true
+            needs_multitap = true
+            note = "Hangs after the intro"
+            known_broken = true
+
+            [SLUS-00001]
+            needs_spu = false
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.warning("SCUS-94163").as_deref(),
+            Some("Known not to work. Needs an analog pad, a multitap. Hangs after the intro")
+        );
+        // Listed with nothing to say, and not listed at all
+        assert_eq!(db.warning("SLUS-00001"), None);
+        assert_eq!(db.warning("SLES-12345"), None);
+    }
+
+    #[test]
+    fn test_bad_flag_type() {
+        assert!(CompatDb::parse("[SCUS-94163]\nneeds_spu = \"yes\"").is_err());
+    }
+}
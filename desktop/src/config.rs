@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use psx_emu::gpu::VideoMode;
+use serde::{Deserialize, Serialize};
+
+use crate::shader::DEFAULT_SHADER_NAME;
+use crate::watch::WatchEntry;
+
+const CONFIG_PATH: &str = "fogstation.toml";
+const MAX_RECENT_FILES: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AspectRatio {
+    /// Always show a 4:3 picture, whatever the resolution
+    Force4x3,
+    /// Size pixels by the dot clock, so narrow modes and non standard line counts keep the shape they have on a TV
+    Native,
+}
+
+/// Which video mode the GPU is timed with. The game still sees the mode it asked for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum VideoModeSetting {
+    /// Whatever the game asks for
+    Auto,
+    /// Some PAL games run about 17% fast like this, since their logic steps once per frame
+    ForceNtsc,
+    ForcePal,
+}
+
+impl VideoModeSetting {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VideoModeSetting::Auto => "Auto",
+            VideoModeSetting::ForceNtsc => "Force NTSC",
+            VideoModeSetting::ForcePal => "Force PAL",
+        }
+    }
+
+    /// The override to hand to the emulator
+    pub fn mode(&self) -> Option<VideoMode> {
+        match self {
+            VideoModeSetting::Auto => None,
+            VideoModeSetting::ForceNtsc => Some(VideoMode::Ntsc),
+            VideoModeSetting::ForcePal => Some(VideoMode::Pal),
+        }
+    }
+}
+
+/// What the emulator does while the window is minimized or another window has focus
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum BackgroundBehavior {
+    KeepRunning,
+    Pause,
+    /// Run at a few frames a second while unfocused, and pause while minimized
+    Throttle,
+}
+
+impl BackgroundBehavior {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackgroundBehavior::KeepRunning => "Keep Running",
+            BackgroundBehavior::Pause => "Pause",
+            BackgroundBehavior::Throttle => "Throttle",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct DisplayConfig {
+    pub aspect_ratio: AspectRatio,
+    /// Only scale the picture by whole multiples of its line count
+    pub integer_scaling: bool,
+    /// Pixels cut from each edge of the displayed area to hide overscan
+    pub crop_top: u32,
+    pub crop_bottom: u32,
+    pub crop_left: u32,
+    pub crop_right: u32,
+    /// Name of a built-in shader, or the file name of one in the shaders directory
+    pub shader: String,
+    /// Redraw polygons with OpenGL at a higher resolution. Experimental
+    pub hardware_renderer: bool,
+    /// Resolution multiplier for the OpenGL renderer
+    pub render_scale: u32,
+    /// Draw primitives on a separate thread from the CPU
+    pub threaded_gpu: bool,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            aspect_ratio: AspectRatio::Force4x3,
+            integer_scaling: false,
+            crop_top: 0,
+            crop_bottom: 0,
+            crop_left: 0,
+            crop_right: 0,
+            shader: DEFAULT_SHADER_NAME.to_string(),
+            hardware_renderer: false,
+            render_scale: 2,
+            threaded_gpu: false,
+        }
+    }
+}
+
+/// Keyboard bindings for controller 1, stored as egui key names
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub cross: String,
+    pub square: String,
+    pub triangle: String,
+    pub circle: String,
+    pub up: String,
+    pub down: String,
+    pub left: String,
+    pub right: String,
+    pub l1: String,
+    pub l2: String,
+    pub r1: String,
+    pub r2: String,
+    pub select: String,
+    pub start: String,
+    /// Presses the pad's analog button, switching it between digital and analog mode
+    pub analog: String,
+    /// Runs one frame while halted. Holding it keeps advancing
+    pub frame_advance: String,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            cross: "K".to_string(),
+            square: "J".to_string(),
+            triangle: "I".to_string(),
+            circle: "L".to_string(),
+            up: "W".to_string(),
+            down: "S".to_string(),
+            left: "A".to_string(),
+            right: "D".to_string(),
+            l1: "E".to_string(),
+            l2: "Q".to_string(),
+            r1: "U".to_string(),
+            r2: "P".to_string(),
+            select: "Backspace".to_string(),
+            start: "Enter".to_string(),
+            analog: "F3".to_string(),
+            frame_advance: "F4".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct MemoryCardConfig {
+    /// Card images live here, named after the game's serial
+    pub directory: PathBuf,
+    /// Use one card for every game instead of one per game
+    pub shared: bool,
+}
+
+impl Default for MemoryCardConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("memcards"),
+            shared: false,
+        }
+    }
+}
+
+/// Main window geometry and which tool windows were open when the app closed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct WindowConfig {
+    pub width: f32,
+    pub height: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<f32>,
+    pub show_vram_window: bool,
+    pub show_gpu_call_window: bool,
+    pub show_cd_debugger: bool,
+    pub show_gamepad_window: bool,
+    pub show_display_window: bool,
+    pub show_game_window: bool,
+    pub show_memory_card_window: bool,
+    pub show_watch_window: bool,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            width: 1024.0,
+            height: 768.0,
+            x: None,
+            y: None,
+            show_vram_window: false,
+            show_gpu_call_window: false,
+            show_cd_debugger: false,
+            show_gamepad_window: false,
+            show_display_window: false,
+            show_game_window: false,
+            show_memory_card_window: false,
+            show_watch_window: false,
+        }
+    }
+}
+
+/// Settings a single game can override. None means the global value is used
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct GameOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame_limiter: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video_mode: Option<VideoModeSetting>,
+    /// Cpu clock multiplier. Breaks games with cycle counted busy loops, so it is only set per game
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_overclock: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aspect_ratio: Option<AspectRatio>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub integer_scaling: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shader: Option<String>,
+}
+
+/// Global settings with the current game's overrides applied
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolvedSettings {
+    pub display: DisplayConfig,
+    pub frame_limiter: bool,
+    pub video_mode: VideoModeSetting,
+    pub cpu_overclock: f32,
+}
+
+// toml can't write plain values after tables, so every table field has to come after the plain ones
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    /// Used when no BIOS is given on the command line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bios_path: Option<PathBuf>,
+    pub frame_limiter: bool,
+    pub background: BackgroundBehavior,
+    /// Halt before the first BIOS instruction, so breakpoints can be set before anything runs
+    pub halt_on_boot: bool,
+    /// Discs and EXEs that were loaded successfully, most recent first
+    pub recent_files: Vec<PathBuf>,
+    pub display: DisplayConfig,
+    pub input: KeyBindings,
+    pub window: WindowConfig,
+    pub memory_card: MemoryCardConfig,
+    /// Per-game overrides, keyed by disc serial
+    pub games: HashMap<String, GameOverrides>,
+    /// Watch panel entries, keyed by disc serial
+    pub watches: HashMap<String, Vec<WatchEntry>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bios_path: None,
+            frame_limiter: true,
+            background: BackgroundBehavior::KeepRunning,
+            halt_on_boot: false,
+            recent_files: vec![],
+            display: DisplayConfig::default(),
+            input: KeyBindings::default(),
+            window: WindowConfig::default(),
+            memory_card: MemoryCardConfig::default(),
+            games: HashMap::new(),
+            watches: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file from the working directory, falling back to defaults if it is missing or invalid
+    pub fn load() -> Self {
+        match fs::read_to_string(CONFIG_PATH) {
+            Ok(contents) => toml::from_str(&contents).unwrap_or_else(|e| {
+                println!("Failed to parse {}: {}. Using default settings", CONFIG_PATH, e);
+                Config::default()
+            }),
+            Err(_) => Config::default(),
+        }
+    }
+
+    /// Applies the game's overrides, if it has any, on top of the global settings
+    pub fn resolve(&self, serial: Option<&str>) -> ResolvedSettings {
+        let mut settings = ResolvedSettings {
+            display: self.display.clone(),
+            frame_limiter: self.frame_limiter,
+            video_mode: VideoModeSetting::Auto,
+            cpu_overclock: 1.0,
+        };
+
+        if let Some(game) = serial.and_then(|serial| self.games.get(serial)) {
+            if let Some(frame_limiter) = game.frame_limiter {
+                settings.frame_limiter = frame_limiter;
+            }
+            if let Some(video_mode) = game.video_mode {
+                settings.video_mode = video_mode;
+            }
+            if let Some(cpu_overclock) = game.cpu_overclock {
+                settings.cpu_overclock = cpu_overclock;
+            }
+            if let Some(aspect_ratio) = game.aspect_ratio {
+                settings.display.aspect_ratio = aspect_ratio;
+            }
+            if let Some(integer_scaling) = game.integer_scaling {
+                settings.display.integer_scaling = integer_scaling;
+            }
+            if let Some(shader) = &game.shader {
+                settings.display.shader = shader.clone();
+            }
+        }
+
+        settings
+    }
+
+    /// Moves the path to the front of the recent files list
+    pub fn add_recent_file(&mut self, path: PathBuf) {
+        self.recent_files.retain(|p| *p != path);
+        self.recent_files.insert(0, path);
+        self.recent_files.truncate(MAX_RECENT_FILES);
+    }
+
+    pub fn save(&self) {
+        let contents = match toml::to_string_pretty(self) {
+            Ok(contents) => contents,
+            Err(e) => {
+                println!("Failed to serialize settings: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = fs::write(CONFIG_PATH, contents) {
+            println!("Failed to write {}: {}", CONFIG_PATH, e);
+        }
+    }
+}
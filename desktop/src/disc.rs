@@ -1,25 +1,59 @@
 use psx_emu::cdrom::disc::{Disc, DiscTrack};
 use rcue::parser::parse_from_file;
-use std::fs::File;
-use std::io::Read;
+use std::fs;
 use std::path::{Path, PathBuf};
 
-pub fn load_disc_from_cuesheet(cuesheet_path: PathBuf) -> Disc {
+pub fn load_disc_from_cuesheet(cuesheet_path: PathBuf) -> Result<Disc, String> {
     let mut cue_dir = cuesheet_path.clone();
 
-    let cue = parse_from_file(cuesheet_path.to_str().unwrap(), true).unwrap();
+    let cue = parse_from_file(&cuesheet_path.to_string_lossy(), true)
+        .map_err(|e| format!("Unable to parse {}: {}", cuesheet_path.display(), e))?;
 
-    let mut disc = Disc::new(cue_dir.file_name().unwrap().to_str().unwrap());
+    let mut disc = Disc::new(&cue_dir.file_name().unwrap_or_default().to_string_lossy());
     cue_dir.pop();
 
     for file in &cue.files {
         let mut track_path = cue_dir.clone();
         let track_name = file.file.clone();
         track_path.push(Path::new(&track_name));
-        let mut file = File::open(track_path).unwrap();
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).unwrap();
-        disc.add_track(DiscTrack::new(data));
+        let data = fs::read(&track_path)
+            .map_err(|e| format!("Unable to read track {}: {}", track_path.display(), e))?;
+        // Each bin holds exactly one track, so the file's first track says what kind it is
+        let audio = file.tracks.first().is_some_and(|track| track.format == "AUDIO");
+        disc.add_track(if audio { DiscTrack::audio(data) } else { DiscTrack::new(data) });
     }
-    disc
+
+    if disc.track_count() == 0 {
+        return Err(format!("{} doesn't list any tracks", cuesheet_path.display()));
+    }
+    Ok(disc)
+}
+
+/// Loads a bin file. If a cue sheet with the same name sits next to it that is used instead,
+/// otherwise the bin is treated as a single data track
+pub fn load_disc_from_bin(bin_path: PathBuf) -> Result<Disc, String> {
+    let cue_path = bin_path.with_extension("cue");
+    if cue_path.exists() {
+        return load_disc_from_cuesheet(cue_path);
+    }
+
+    let data = fs::read(&bin_path)
+        .map_err(|e| format!("Unable to read {}: {}", bin_path.display(), e))?;
+    let mut disc = Disc::new(&bin_path.file_name().unwrap_or_default().to_string_lossy());
+    disc.add_track(DiscTrack::new(data));
+    Ok(disc)
+}
+
+/// Picks the loader based on the file extension
+pub fn load_disc(path: PathBuf) -> Result<Disc, String> {
+    match extension(&path).as_deref() {
+        Some("cue") => load_disc_from_cuesheet(path),
+        Some("bin") => load_disc_from_bin(path),
+        _ => Err(format!("{} is not a .cue or .bin file", path.display())),
+    }
+}
+
+/// Lowercase file extension, if the path has one
+pub fn extension(path: &Path) -> Option<String> {
+    path.extension().map(|ext| ext.to_string_lossy().to_lowercase())
 }
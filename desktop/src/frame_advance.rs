@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+/// How long the frame advance key has to be held before it starts repeating
+const REPEAT_DELAY: Duration = Duration::from_millis(400);
+
+/// Time between advances while the key stays down. Slow enough to follow what changes each frame
+const REPEAT_INTERVAL: Duration = Duration::from_millis(125);
+
+/// Turns the frame advance key's state into advances: one when it goes down, then a steady repeat
+/// while it's held. The OS key repeat isn't used, since its rate depends on the host
+pub struct FrameAdvanceKey {
+    /// When the next advance is due, while the key is down
+    next_advance: Option<Instant>,
+}
+
+impl FrameAdvanceKey {
+    pub fn new() -> Self {
+        Self { next_advance: None }
+    }
+
+    /// Call once per gui frame with whether the key is down. Returns true when a frame should be
+    /// advanced
+    pub fn update(&mut self, held: bool, now: Instant) -> bool {
+        match (held, self.next_advance) {
+            (false, _) => {
+                self.next_advance = None;
+                false
+            }
+            (true, None) => {
+                self.next_advance = Some(now + REPEAT_DELAY);
+                true
+            }
+            (true, Some(due)) if now >= due => {
+                self.next_advance = Some(now + REPEAT_INTERVAL);
+                true
+            }
+            (true, Some(_)) => false,
+        }
+    }
+
+    /// Time until the next repeat, so the gui can wake up for it
+    pub fn time_until_repeat(&self, now: Instant) -> Option<Duration> {
+        self.next_advance
+            .map(|due| due.saturating_duration_since(now))
+    }
+}
+
+#[cfg(test)]
+mod frame_advance_tests {
+    use super::*;
+
+    #[test]
+    fn test_press_then_repeat() {
+        let mut key = FrameAdvanceKey::new();
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        assert!(key.update(true, at(0)));
+        // Nothing more until the repeat delay is up
+        assert!(!key.update(true, at(100)));
+        assert!(!key.update(true, at(399)));
+        assert_eq!(
+            key.time_until_repeat(at(399)),
+            Some(Duration::from_millis(1))
+        );
+        assert!(key.update(true, at(400)));
+        assert!(!key.update(true, at(500)));
+        assert!(key.update(true, at(525)));
+
+        // Letting go and pressing again advances right away
+        assert!(!key.update(false, at(530)));
+        assert_eq!(key.time_until_repeat(at(530)), None);
+        assert!(key.update(true, at(540)));
+    }
+}
@@ -0,0 +1,78 @@
+use gilrs::{Button, EventType, GamepadId, Gilrs};
+use psx_emu::controller::{ButtonState, ControllerType};
+
+/// A pad gilrs knows about, for the gui's controller picker
+#[derive(Clone)]
+pub(crate) struct GamepadInfo {
+    pub id: GamepadId,
+    pub name: String,
+    pub connected: bool,
+}
+
+/// Reads gamepads on the emu thread, so they are sampled once per emulated frame no matter how
+/// fast the gui is running
+pub(crate) struct GamepadInput {
+    gilrs: Gilrs,
+    active: Option<GamepadId>,
+}
+
+impl GamepadInput {
+    pub(crate) fn new(gilrs: Gilrs) -> Self {
+        Self {
+            gilrs,
+            active: None,
+        }
+    }
+
+    /// Pad to read from. None uses the keyboard
+    pub(crate) fn set_active(&mut self, id: Option<GamepadId>) {
+        self.active = id;
+    }
+
+    pub(crate) fn gamepads(&self) -> Vec<GamepadInfo> {
+        self.gilrs
+            .gamepads()
+            .map(|(id, gamepad)| GamepadInfo {
+                id,
+                name: gamepad.name().to_string(),
+                connected: gamepad.is_connected(),
+            })
+            .collect()
+    }
+
+    /// Handles everything gilrs has queued so the pad states are current. Returns true if a pad
+    /// was plugged in or unplugged
+    pub(crate) fn update(&mut self) -> bool {
+        let mut changed = false;
+        while let Some(event) = self.gilrs.next_event() {
+            if matches!(event.event, EventType::Connected | EventType::Disconnected) {
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Buttons held on the active pad. None if no pad is active or it was unplugged
+    pub(crate) fn sample(&self) -> Option<ButtonState> {
+        let gamepad = self.gilrs.connected_gamepad(self.active?)?;
+        Some(ButtonState {
+            controller_type: ControllerType::DigitalPad,
+            button_x: gamepad.is_pressed(Button::South),
+            button_square: gamepad.is_pressed(Button::West),
+            button_triangle: gamepad.is_pressed(Button::North),
+            button_circle: gamepad.is_pressed(Button::East),
+            button_up: gamepad.is_pressed(Button::DPadUp),
+            button_down: gamepad.is_pressed(Button::DPadDown),
+            button_left: gamepad.is_pressed(Button::DPadLeft),
+            button_right: gamepad.is_pressed(Button::DPadRight),
+            button_l1: gamepad.is_pressed(Button::LeftTrigger),
+            button_l2: gamepad.is_pressed(Button::LeftTrigger2),
+            button_l3: false,
+            button_r1: gamepad.is_pressed(Button::RightTrigger),
+            button_r2: gamepad.is_pressed(Button::RightTrigger2),
+            button_r3: false,
+            button_select: gamepad.is_pressed(Button::Select),
+            button_start: gamepad.is_pressed(Button::Start),
+        })
+    }
+}
@@ -77,17 +77,27 @@ impl SingleThreadOps for EmuState {
             ResumeAction::Continue => {
                 let mut cycles = 0;
                 self.emu.clear_halt();
+                self.halted = false;
                 self.comm.tx.send(ClientMessage::Continuing).unwrap();
                 loop {
                     if self.emu.halt_requested() {
+                        self.halted = true;
                         self.comm.tx.send(ClientMessage::Halted).unwrap();
                         return Ok(StopReason::SwBreak);
                     }
                     if let Err(e) = emu_loop_step(self) {
                         println!("EmuThread: Encountered error: {:?}, exiting...", e);
+                        // This gdbstub has no stop reason for an exit, so report a halt
+                        println!("EmuThread: Program exited with status {}", self.emu.exit_code().unwrap_or(0));
+                        return Ok(StopReason::Halted);
                     };
+                    // Halted from the gui, or by a reset with halt on boot turned on
+                    if self.halted {
+                        return Ok(StopReason::GdbInterrupt);
+                    }
                     cycles += 1;
                     if cycles % 1024 == 0 && check_gdb_interrupt() {
+                        self.halted = true;
                         self.comm.tx.send(ClientMessage::Halted).unwrap();
                         return Ok(StopReason::GdbInterrupt);
                     }
@@ -107,11 +117,11 @@ impl SingleThreadOps for EmuState {
 
         regs.hi = self.emu.r3000.hi;
         regs.lo = self.emu.r3000.lo;
-        regs.pc = self.emu.r3000.pc;
+        regs.pc = self.emu.current_instruction_pc();
 
         regs.cp0.status = self.emu.r3000.cop0.read_reg(12);
         regs.cp0.cause = self.emu.r3000.cop0.read_reg(13);
-        regs.cp0.badvaddr = self.emu.r3000.cop0.read_reg(14);
+        regs.cp0.badvaddr = self.emu.r3000.cop0.read_reg(8);
 
         Ok(())
     }
@@ -126,11 +136,15 @@ impl SingleThreadOps for EmuState {
 
         self.emu.r3000.hi = regs.hi;
         self.emu.r3000.lo = regs.lo;
-        self.emu.r3000.pc = regs.pc;
+        // GDB writes every register back, even when only one changed. Moving pc would drop a
+        // pending branch, so only touch it if the debugger actually changed it
+        if regs.pc != self.emu.current_instruction_pc() {
+            self.emu.r3000.set_pc(regs.pc);
+        }
 
-        self.emu.r3000.cop0.write_reg(12, regs.cp0.status);
-        self.emu.r3000.cop0.write_reg(13, regs.cp0.cause);
-        self.emu.r3000.cop0.write_reg(14, regs.cp0.badvaddr);
+        self.emu.r3000.cop0.set_reg(12, regs.cp0.status);
+        self.emu.r3000.cop0.set_reg(13, regs.cp0.cause);
+        self.emu.r3000.cop0.set_reg(8, regs.cp0.badvaddr);
 
         Ok(())
     }
@@ -1,64 +1,155 @@
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use eframe::{
     egui::{self, Color32, Direction, Key, Layout, Pos2, Rect, TextureId},
     epaint::TextureHandle,
-    glow::{self, HasContext, NativeTexture}, egui_glow,
+    egui_glow,
+    glow::{self, HasContext},
 };
-use gilrs::{Button, GamepadId, Gilrs};
+use gilrs::GamepadId;
 use psx_emu::{
-    controller::{ButtonState, ControllerType},
-    gpu::{DrawCall, Resolution},
+    cdrom::SectorBufferInfo,
+    controller::{ButtonState, ControllerType, PadMode},
+    draw_log,
+    gpu::{DrawCall, DrawOperation, Resolution, VideoMode},
+    BiosInfo, FrameTiming, MAX_CPU_OVERCLOCK,
 };
 
-use crate::{ClientMessage, ClientState, EmuMessage};
+use crate::config::{AspectRatio, BackgroundBehavior, Config, DisplayConfig, GameOverrides, KeyBindings, ResolvedSettings, VideoModeSetting};
+use crate::frame_advance::FrameAdvanceKey;
+use crate::speed::SpeedMeter;
+use crate::vram_viewer::{inspect, VramArea, VramViewer, TEXEL_DECODES};
+use crate::shader::{available_shaders, DisplayShaderManager, ShaderPreset};
+use crate::disc::extension;
+use crate::gamepad::GamepadInfo;
+use crate::hw_renderer::{HwFrame, HwRenderer};
+use crate::osd::Osd;
+use crate::watch::{WatchEntry, WatchType, WATCH_TYPES};
+use crate::{BackgroundState, ClientMessage, ClientState, DrawCallCounts, EmuMessage, GpuCapture, MemoryCardContents};
 
 const VRAM_WIDTH: usize = 1024;
 const VRAM_HEIGHT: usize = 512;
+/// Save icons animate at a few frames per second, like they do in the BIOS card manager
+const ICON_FRAME_TIME: f64 = 0.25;
+const ICON_DISPLAY_SIZE: f32 = 32.0;
 
 pub(crate) fn run_gui(state: ClientState) {
+    let config = Config::load();
+
+    let mut viewport = egui::ViewportBuilder::default()
+        .with_inner_size([config.window.width, config.window.height]);
+    if let (Some(x), Some(y)) = (config.window.x, config.window.y) {
+        viewport = viewport.with_position([x, y]);
+    }
+
     let native_options = eframe::NativeOptions {
         renderer: eframe::Renderer::Glow,
+        viewport,
         ..Default::default()
     };
 
     eframe::run_native(
         "FogStation",
         native_options,
-        Box::new(|cc| Box::new(FogStationApp::new(state, cc))),
+        Box::new(|cc| Box::new(FogStationApp::new(state, config, cc))),
     );
 }
 
 struct FogStationApp {
     emu_handle: ClientState,
     times: AverageList,
+    speed: SpeedMeter,
+    frame_advance: FrameAdvanceKey,
     latest_resolution: Resolution,
     awaiting_gdb: bool,
     latest_pc: u32,
     irq_mask: u32,
     vram_texture: Option<TextureHandle>,
     show_vram_window: bool,
+    vram_viewer: VramViewer,
+    /// Raw VRAM from the latest frame, for the VRAM viewer's readout
+    last_vram: Vec<u16>,
+    display_area: VramArea,
+    draw_area: VramArea,
     gdb_connected: bool,
-    display_origin: (usize, usize),
+    /// Set once the emu thread has shut down and the window is closing
+    emu_exited: bool,
     latest_gpu_log: Vec<DrawCall>,
     show_gpu_call_window: bool,
     highlighted_gpu_calls: Vec<usize>,
+    /// Call whose VRAM changes are marked in the VRAM viewer
+    diffed_gpu_call: Option<usize>,
+    /// Show the diffed call's area as it was before the call, instead of after
+    show_diff_before: bool,
     last_frame_data: Vec<u8>,
     memory_logging: bool,
-    gilrs_instance: Gilrs,
+    /// Pads the emu thread can read from
+    gamepads: Vec<GamepadInfo>,
     active_controller_id: Option<GamepadId>,
+    /// Keyboard state last sent to the emu thread
+    last_keyboard_state: Option<ButtonState>,
     show_gamepad_window: bool,
     has_initialized: bool,
     disp_shader_manager: Arc<Mutex<DisplayShaderManager>>,
     last_display_data: Vec<u8>,
+    hw_renderer: Arc<Mutex<HwRenderer>>,
+    /// Latest frame from the OpenGL renderer. Only sent while it's turned on
+    last_hw_frame: Option<Arc<HwFrame>>,
     show_cd_debugger: bool,
     latest_cd_mask: u8,
-    latest_cd_flag: u8
+    latest_cd_flag: u8,
+    latest_cd_sector_buffer: SectorBufferInfo,
+    latest_cd_mode: u8,
+    cd_speed_changing: bool,
+    post_code: Option<u8>,
+    pad_mode: PadMode,
+    bios_info: Option<BiosInfo>,
+    config: Config,
+    show_display_window: bool,
+    dot_clock_divider: u32,
+    video_mode: VideoMode,
+    available_shaders: Vec<ShaderPreset>,
+    load_error: Option<String>,
+    game_serial: Option<String>,
+    /// Shown above the game until dismissed or another game is loaded
+    compat_warning: Option<String>,
+    /// Global config with the current game's overrides applied
+    settings: ResolvedSettings,
+    show_game_window: bool,
+    /// Inner and outer rect of the main window, saved on exit
+    window_rects: Option<(Rect, Rect)>,
+    /// Last focus and minimized state sent to the emu thread
+    background_state: BackgroundState,
+    show_memory_card_window: bool,
+    memory_card: Option<MemoryCardContents>,
+    /// Icon frames for each save on the card, in the same order as memory_card.saves
+    save_icons: Vec<Vec<TextureHandle>>,
+    memory_card_error: Option<String>,
+    show_watch_window: bool,
+    watches: Vec<WatchEntry>,
+    /// Bytes read at each watch after the latest frame
+    watch_values: Vec<Option<Vec<u8>>>,
+    new_watch_label: String,
+    new_watch_address: String,
+    new_watch_type: WatchType,
+    /// Snapshot CLUTs and the VRAM each call changes into the GPU log
+    gpu_deep_capture: bool,
+    gpu_capture: GpuCapture,
+    gpu_call_counts: DrawCallCounts,
+    /// Drop oversized polygons like the hardware
+    strict_size_check: bool,
+    gpu_log_error: Option<String>,
+    osd: Osd,
+    show_fps_overlay: bool,
+    show_perf_hud: bool,
+    latest_frame_timing: FrameTiming,
     //shader_layer: ShaderLayer,
 }
 
 impl FogStationApp {
-    fn new(state: ClientState, cc: &eframe::CreationContext<'_>) -> Self {
+    fn new(state: ClientState, config: Config, cc: &eframe::CreationContext<'_>) -> Self {
         let default_resolution = Resolution {
             width: 640,
             height: 480,
@@ -69,32 +160,97 @@ impl FogStationApp {
             .as_ref()
             .expect("You need to run eframe with the glow backend");
 
+        let available_shaders = available_shaders();
+        let mut disp_shader_manager = DisplayShaderManager::new(gl);
+        match available_shaders.iter().find(|s| s.name == config.display.shader) {
+            Some(shader) => disp_shader_manager.request_shader(shader.clone()),
+            None => println!("Shader {} not found, using the default", config.display.shader),
+        }
+        let settings = config.resolve(None);
+        let windows = config.window.clone();
+        if settings.display.hardware_renderer {
+            state.comm.tx.send(EmuMessage::SetHardwareRenderer(true)).unwrap();
+        }
+        if settings.display.threaded_gpu {
+            state.comm.tx.send(EmuMessage::SetThreadedGpu(true)).unwrap();
+        }
+        // Gamepads are read on the emu thread
+        state.comm.tx.send(EmuMessage::EnableGamepads).unwrap();
+
         Self {
             emu_handle: state,
             times: AverageList::new(),
+            speed: SpeedMeter::new(),
+            frame_advance: FrameAdvanceKey::new(),
             latest_resolution: default_resolution,
             awaiting_gdb: false,
             latest_pc: 0,
             irq_mask: 0,
             vram_texture: None,
-            show_vram_window: false,
+            show_vram_window: windows.show_vram_window,
+            vram_viewer: VramViewer::new(),
+            last_vram: vec![],
+            display_area: VramArea::default(),
+            draw_area: VramArea::default(),
             gdb_connected: false,
-            display_origin: (0, 0),
+            emu_exited: false,
             latest_gpu_log: vec![],
-            show_gpu_call_window: false,
+            show_gpu_call_window: windows.show_gpu_call_window,
             highlighted_gpu_calls: vec![],
+            diffed_gpu_call: None,
+            show_diff_before: false,
             last_frame_data: vec![],
             memory_logging: false,
-            gilrs_instance: Gilrs::new().unwrap(),
+            gamepads: vec![],
             active_controller_id: None,
-            show_gamepad_window: false,
+            last_keyboard_state: None,
+            show_gamepad_window: windows.show_gamepad_window,
             has_initialized: false,
-            disp_shader_manager: Arc::new(Mutex::new(DisplayShaderManager::new(gl))),
+            disp_shader_manager: Arc::new(Mutex::new(disp_shader_manager)),
             last_display_data: vec![0; 640 * 480 * 4],
+            hw_renderer: Arc::new(Mutex::new(HwRenderer::new(gl))),
+            last_hw_frame: None,
             //shader_layer: ShaderLayer::new(cc.gl.as_ref().unwrap().clone()),
-            show_cd_debugger: false,
+            show_cd_debugger: windows.show_cd_debugger,
             latest_cd_mask: 0,
             latest_cd_flag: 0,
+            latest_cd_sector_buffer: SectorBufferInfo::default(),
+            latest_cd_mode: 0,
+            cd_speed_changing: false,
+            post_code: None,
+            pad_mode: PadMode::Digital,
+            bios_info: None,
+            config,
+            show_display_window: windows.show_display_window,
+            dot_clock_divider: 4,
+            video_mode: VideoMode::Ntsc,
+            available_shaders,
+            load_error: None,
+            game_serial: None,
+            compat_warning: None,
+            settings,
+            show_game_window: windows.show_game_window,
+            window_rects: None,
+            background_state: BackgroundState::Focused,
+            show_memory_card_window: windows.show_memory_card_window,
+            memory_card: None,
+            save_icons: vec![],
+            memory_card_error: None,
+            show_watch_window: windows.show_watch_window,
+            watches: vec![],
+            watch_values: vec![],
+            new_watch_label: String::new(),
+            new_watch_address: String::new(),
+            new_watch_type: WatchType::U32,
+            gpu_deep_capture: false,
+            gpu_capture: GpuCapture::Counts,
+            gpu_call_counts: DrawCallCounts::default(),
+            strict_size_check: true,
+            gpu_log_error: None,
+            osd: Osd::new(),
+            show_fps_overlay: false,
+            show_perf_hud: false,
+            latest_frame_timing: FrameTiming::default(),
         }
     }
 
@@ -107,38 +263,376 @@ impl FogStationApp {
         }
     }
 
+    /// Asks the emu thread to load a disc or EXE, picking the message from the file extension
+    fn load_file(&mut self, path: PathBuf) {
+        let message = match extension(&path).as_deref() {
+            Some("cue") | Some("bin") => EmuMessage::LoadDisc(path),
+            Some("exe") | Some("psexe") => EmuMessage::LoadExe(path),
+            _ => {
+                self.load_error = Some(format!("Don't know how to open {}", path.display()));
+                return;
+            }
+        };
+        self.emu_handle.comm.tx.send(message).unwrap();
+    }
+
+    /// Re-applies the global settings and the current game's overrides, pushing anything that changed to the emu thread
+    fn apply_settings(&mut self) {
+        let settings = self.config.resolve(self.game_serial.as_deref());
+
+        if settings.display.shader != self.settings.display.shader {
+            match self.available_shaders.iter().find(|s| s.name == settings.display.shader) {
+                Some(shader) => self.disp_shader_manager.lock().unwrap().request_shader(shader.clone()),
+                None => println!("Shader {} not found", settings.display.shader),
+            }
+        }
+
+        if settings.frame_limiter != self.settings.frame_limiter {
+            self.emu_handle.frame_limited = settings.frame_limiter;
+            self.emu_handle
+                .comm
+                .tx
+                .send(EmuMessage::SetFrameLimiter(settings.frame_limiter))
+                .unwrap();
+        }
+
+        self.emu_handle
+            .comm
+            .tx
+            .send(EmuMessage::SetVideoModeOverride(settings.video_mode.mode()))
+            .unwrap();
+
+        if settings.cpu_overclock != self.settings.cpu_overclock {
+            self.emu_handle
+                .comm
+                .tx
+                .send(EmuMessage::SetCpuOverclock(settings.cpu_overclock))
+                .unwrap();
+        }
+
+        if settings.display.hardware_renderer != self.settings.display.hardware_renderer {
+            self.last_hw_frame = None;
+            self.emu_handle
+                .comm
+                .tx
+                .send(EmuMessage::SetHardwareRenderer(settings.display.hardware_renderer))
+                .unwrap();
+        }
+
+        if settings.display.threaded_gpu != self.settings.display.threaded_gpu {
+            self.emu_handle
+                .comm
+                .tx
+                .send(EmuMessage::SetThreadedGpu(settings.display.threaded_gpu))
+                .unwrap();
+        }
+
+        self.settings = settings;
+    }
+
+    /// Label shown next to a global setting that the current game overrides
+    fn override_marker(&self, ui: &mut egui::Ui, overridden: bool) {
+        if let (true, Some(serial)) = (overridden, &self.game_serial) {
+            ui.colored_label(Color32::YELLOW, format!("(overridden by {})", serial));
+        }
+    }
+
+    fn memory_card_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_memory_card_window;
+        egui::Window::new("Memory Card").open(&mut open).show(ctx, |ui| {
+            if ui
+                .checkbox(&mut self.config.memory_card.shared, "Use one card for every game")
+                .changed()
+            {
+                self.config.save();
+                self.emu_handle
+                    .comm
+                    .tx
+                    .send(EmuMessage::SetSharedMemoryCard(self.config.memory_card.shared))
+                    .unwrap();
+            }
+
+            if let Some(error) = &self.memory_card_error {
+                ui.colored_label(Color32::RED, error);
+                if ui.button("Dismiss").clicked() {
+                    self.memory_card_error = None;
+                }
+            }
+            ui.separator();
+
+            let contents = match &self.memory_card {
+                Some(contents) => contents,
+                None => {
+                    ui.label("No memory card inserted");
+                    return;
+                }
+            };
+
+            ui.label(format!("{}", contents.path.display()));
+            ui.horizontal(|ui| {
+                ui.label(format!("{} of 15 blocks free", contents.free_blocks));
+                if ui.button("Import...").clicked() {
+                    if let Some(path) = rfd::FileDialog::new()
+                        .add_filter("Save file", &["mcs"])
+                        .pick_file()
+                    {
+                        self.emu_handle.comm.tx.send(EmuMessage::ImportSave(path)).unwrap();
+                    }
+                }
+            });
+            ui.separator();
+
+            if contents.saves.is_empty() {
+                ui.label("The card is empty");
+            }
+
+            let icon_frame = (ui.input(|i| i.time) / ICON_FRAME_TIME) as usize;
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                egui::Grid::new("memory_card_grid").striped(true).show(ui, |ui| {
+                    for (save, icons) in contents.saves.iter().zip(&self.save_icons) {
+                        if icons.is_empty() {
+                            ui.label("");
+                        } else {
+                            let icon = &icons[icon_frame % icons.len()];
+                            ui.image((icon.id(), egui::vec2(ICON_DISPLAY_SIZE, ICON_DISPLAY_SIZE)));
+                        }
+                        ui.vertical(|ui| {
+                            ui.label(&save.title);
+                            ui.small(&save.file_name);
+                        });
+                        ui.label(format!(
+                            "{} block{}",
+                            save.block_count,
+                            if save.block_count == 1 { "" } else { "s" }
+                        ));
+                        if ui.button("Export...").clicked() {
+                            if let Some(path) = rfd::FileDialog::new()
+                                .add_filter("Save file", &["mcs"])
+                                .set_file_name(format!("{}.mcs", save.file_name))
+                                .save_file()
+                            {
+                                self.emu_handle
+                                    .comm
+                                    .tx
+                                    .send(EmuMessage::ExportSave(save.first_block, path))
+                                    .unwrap();
+                            }
+                        }
+                        if ui.button("Delete").clicked() {
+                            self.emu_handle
+                                .comm
+                                .tx
+                                .send(EmuMessage::DeleteSave(save.first_block))
+                                .unwrap();
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
+
+            // Keep the icons animating while the emulator is halted
+            ctx.request_repaint_after(Duration::from_secs_f64(ICON_FRAME_TIME));
+        });
+        self.show_memory_card_window = open;
+    }
+
+    /// VRAM with the display and draw areas outlined. Scroll to zoom, drag to pan, and hover a
+    /// cell to read it
+    fn vram_window(&mut self, ctx: &egui::Context) {
+        egui::Window::new("VRAM Viewer").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_label("Decode As")
+                    .selected_text(self.vram_viewer.decode.name())
+                    .show_ui(ui, |ui| {
+                        for decode in TEXEL_DECODES {
+                            ui.selectable_value(
+                                &mut self.vram_viewer.decode,
+                                decode,
+                                decode.name(),
+                            );
+                        }
+                    });
+                ui.label(format!("Zoom: {:.1}x", self.vram_viewer.zoom));
+                if ui.button("Reset View").clicked() {
+                    self.vram_viewer.zoom = 1.0;
+                    self.vram_viewer.offset = egui::Vec2::ZERO;
+                }
+            });
+
+            let (view, response) = ui.allocate_exact_size(
+                egui::vec2(VRAM_WIDTH as f32, VRAM_HEIGHT as f32),
+                egui::Sense::drag(),
+            );
+            if let Some(cursor) = response.hover_pos() {
+                let scroll = ui.input(|i| i.raw_scroll_delta.y);
+                if scroll != 0.0 {
+                    // About 1.4x per notch of a mouse wheel
+                    self.vram_viewer
+                        .zoom_at(view, cursor, (scroll / 100.0).exp2());
+                }
+            }
+            if response.dragged() {
+                self.vram_viewer.pan(view, response.drag_delta());
+            }
+
+            let painter = ui.painter_at(view);
+            if let Some(vram) = &self.vram_texture {
+                painter.image(
+                    vram.id(),
+                    self.vram_viewer.vram_rect(view),
+                    Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
+                    Color32::WHITE,
+                );
+            }
+            for (area, color) in [
+                (self.display_area, Color32::GREEN),
+                (self.draw_area, Color32::YELLOW),
+            ] {
+                painter.rect_stroke(
+                    self.vram_viewer.area_rect(view, area),
+                    0.0,
+                    egui::Stroke::new(1.0, color),
+                );
+            }
+
+            ui.horizontal(|ui| {
+                ui.colored_label(Color32::GREEN, "Display Area");
+                ui.colored_label(Color32::YELLOW, "Draw Area");
+            });
+            let hovered = response.hover_pos().and_then(|pos| {
+                let pos = self.vram_viewer.to_vram(view, pos);
+                inspect(&self.last_vram, pos.x, pos.y, self.vram_viewer.decode)
+            });
+            match hovered {
+                Some(cell) => {
+                    let (r, g, b) = cell.rgb;
+                    let mut readout = format!(
+                        "({}, {})  {:#06X}  R {} G {} B {}  Mask {}",
+                        cell.x, cell.y, cell.value, r, g, b, cell.mask as u8
+                    );
+                    if let Some(index) = cell.clut_index {
+                        readout.push_str(&format!("  CLUT Index {}", index));
+                    }
+                    ui.monospace(readout);
+                }
+                None => {
+                    ui.monospace("Hover over VRAM to inspect it");
+                }
+            }
+        });
+    }
+
+    fn watch_window(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_watch_window;
+        let mut removed = None;
+        egui::Window::new("Watches").open(&mut open).show(ctx, |ui| {
+            egui::Grid::new("watch_grid").striped(true).show(ui, |ui| {
+                ui.label("Label");
+                ui.label("Address");
+                ui.label("Type");
+                ui.label("Value");
+                ui.end_row();
+
+                for (i, watch) in self.watches.iter().enumerate() {
+                    ui.label(&watch.label);
+                    ui.monospace(format!("{:08X}", watch.address));
+                    ui.label(watch.kind.name());
+                    let value = match self.watch_values.get(i) {
+                        Some(Some(bytes)) => watch.kind.format(bytes),
+                        Some(None) => "Not in RAM".to_string(),
+                        None => String::new(),
+                    };
+                    ui.monospace(value);
+                    if ui.button("Remove").clicked() {
+                        removed = Some(i);
+                    }
+                    ui.end_row();
+                }
+            });
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.new_watch_label)
+                        .hint_text("Label")
+                        .desired_width(100.0),
+                );
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.new_watch_address)
+                        .hint_text("Address (hex)")
+                        .desired_width(80.0),
+                );
+                egui::ComboBox::from_id_source("new_watch_type")
+                    .selected_text(self.new_watch_type.name())
+                    .show_ui(ui, |ui| {
+                        for kind in WATCH_TYPES {
+                            ui.selectable_value(&mut self.new_watch_type, kind, kind.name());
+                        }
+                    });
+                let address = u32::from_str_radix(
+                    self.new_watch_address.trim().trim_start_matches("0x"),
+                    16,
+                );
+                if ui.add_enabled(address.is_ok(), egui::Button::new("Add")).clicked() {
+                    if let Ok(address) = address {
+                        self.watches.push(WatchEntry {
+                            label: std::mem::take(&mut self.new_watch_label),
+                            address,
+                            kind: self.new_watch_type,
+                        });
+                        self.new_watch_address.clear();
+                        self.watch_list_changed();
+                    }
+                }
+            });
+        });
+        if let Some(i) = removed {
+            self.watches.remove(i);
+            self.watch_list_changed();
+        }
+        self.show_watch_window = open;
+    }
+
+    /// Saves the watches for the current game and hands them to the emu thread
+    fn watch_list_changed(&mut self) {
+        if let Some(serial) = &self.game_serial {
+            if self.watches.is_empty() {
+                self.config.watches.remove(serial);
+            } else {
+                self.config.watches.insert(serial.clone(), self.watches.clone());
+            }
+            self.config.save();
+        }
+        // The values line up with the old list until the next frame arrives
+        self.watch_values.clear();
+        self.emu_handle
+            .comm
+            .tx
+            .send(EmuMessage::SetWatchList(self.watches.clone()))
+            .unwrap();
+    }
+
     fn halted(&self) -> bool {
         self.emu_handle.halted
     }
 
-    fn get_button_state(&self, input_state: &egui::InputState) -> ButtonState {
-        if let Some(gamepad_id) = self.active_controller_id {
-            let gamepad = self.gilrs_instance.gamepad(gamepad_id);
-            ButtonState {
-                controller_type: ControllerType::DigitalPad,
-                button_x: gamepad.is_pressed(Button::South),
-                button_square: gamepad.is_pressed(Button::West),
-                button_triangle: gamepad.is_pressed(Button::North),
-                button_circle: gamepad.is_pressed(Button::East),
-                button_up: gamepad.is_pressed(Button::DPadUp),
-                button_down: gamepad.is_pressed(Button::DPadDown),
-                button_left: gamepad.is_pressed(Button::DPadLeft),
-                button_right: gamepad.is_pressed(Button::DPadRight),
-                button_l1: gamepad.is_pressed(Button::LeftTrigger),
-                button_l2: gamepad.is_pressed(Button::LeftTrigger2),
-                button_l3: false,
-                button_r1: gamepad.is_pressed(Button::RightTrigger),
-                button_r2: gamepad.is_pressed(Button::RightTrigger2),
-                button_r3: false,
-                button_select: gamepad.is_pressed(Button::Select),
-                button_start: gamepad.is_pressed(Button::Start),
-            }
-        } else {
-            get_button_state_from_keyboard(input_state)
+    /// Redraws the VRAM viewer from the last frame, with the GPU debugger's highlights and diff on top
+    fn update_vram_view(&mut self, ctx: &egui::Context) {
+        if self.last_frame_data.is_empty() {
+            return;
         }
+        let mut new_frame = self.last_frame_data.clone();
+        apply_highlights(self, &mut new_frame);
+        apply_vram_change(self, &mut new_frame);
+
+        self.vram_texture = Some(ctx.load_texture(
+            "VRAM",
+            egui::ColorImage::from_rgba_unmultiplied([VRAM_WIDTH, VRAM_HEIGHT], &new_frame),
+            egui::TextureOptions::NEAREST,
+        ));
     }
 
-    fn custom_painting(&mut self, ui: &mut egui::Ui, frame_data: Vec<u8>, frame_width: f32, frame_height: f32, psx_disp_width: i32, psx_disp_height: i32) {
+    fn custom_painting(&mut self, ui: &mut egui::Ui, frame_data: Vec<u8>, frame_width: f32, frame_height: f32, psx_disp_width: i32, psx_disp_height: i32, uv_min: [f32; 2], uv_max: [f32; 2]) {
         let (rect, response) =
             ui.allocate_exact_size(egui::Vec2::new(frame_width as f32, frame_height as f32), egui::Sense::drag());
 
@@ -147,46 +641,153 @@ impl FogStationApp {
         // Clone locals so we can move them into the paint callback:
         //let angle = self.angle;
         let disp_manager = self.disp_shader_manager.clone();
+        let display_size = egui::vec2(psx_disp_width as f32, psx_disp_height as f32);
+        // 24 bit frames can't be redrawn, so they always come from the software renderer
+        let hardware_frame = match &self.last_hw_frame {
+            Some(frame) if self.settings.display.hardware_renderer && !frame.full_color => Some((
+                self.hw_renderer.clone(),
+                frame.clone(),
+                self.settings.display.render_scale.max(1),
+            )),
+            _ => None,
+        };
 
         let callback = egui::PaintCallback {
             rect,
-            callback: std::sync::Arc::new(egui_glow::CallbackFn::new(move |_info, painter| {
-                disp_manager.lock().unwrap().paint(painter.gl(), &frame_data, psx_disp_width, psx_disp_height);
+            callback: std::sync::Arc::new(egui_glow::CallbackFn::new(move |info, painter| {
+                let viewport = info.viewport_in_pixels();
+                let output_size = [viewport.width_px as f32, viewport.height_px as f32];
+                let gl = painter.gl();
+                match &hardware_frame {
+                    Some((hw_renderer, frame, scale)) => {
+                        let texture = hw_renderer.lock().unwrap().render(gl, frame, *scale);
+                        unsafe {
+                            gl.bind_framebuffer(glow::FRAMEBUFFER, painter.intermediate_fbo());
+                            gl.viewport(viewport.left_px, viewport.from_bottom_px, viewport.width_px, viewport.height_px);
+                            gl.enable(glow::SCISSOR_TEST);
+                            gl.enable(glow::BLEND);
+                        }
+
+                        // The target covers all of VRAM, so move the display area's uvs into it
+                        let (origin_x, origin_y) = frame.display_origin;
+                        let to_vram_uv = |uv: [f32; 2]| {
+                            [
+                                (origin_x as f32 + uv[0] * psx_disp_width as f32) / VRAM_WIDTH as f32,
+                                (origin_y as f32 + uv[1] * psx_disp_height as f32) / VRAM_HEIGHT as f32,
+                            ]
+                        };
+                        let source_size = [(VRAM_WIDTH * *scale as usize) as f32, (VRAM_HEIGHT * *scale as usize) as f32];
+                        disp_manager.lock().unwrap().paint_texture(gl, texture, source_size, output_size, to_vram_uv(uv_min), to_vram_uv(uv_max));
+                    }
+                    None => {
+                        disp_manager.lock().unwrap().paint(gl, &frame_data, psx_disp_width, psx_disp_height, output_size, uv_min, uv_max);
+                    }
+                }
             })),
         };
         ui.painter().add(callback);
+        self.osd.paint(ui.painter(), rect, display_size, uv_min, uv_max);
     }
 
 }
 
 impl eframe::App for FogStationApp {
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        let window = &mut self.config.window;
+        if let Some((inner, outer)) = self.window_rects {
+            window.width = inner.width();
+            window.height = inner.height();
+            window.x = Some(outer.min.x);
+            window.y = Some(outer.min.y);
+        }
+        window.show_vram_window = self.show_vram_window;
+        window.show_gpu_call_window = self.show_gpu_call_window;
+        window.show_cd_debugger = self.show_cd_debugger;
+        window.show_gamepad_window = self.show_gamepad_window;
+        window.show_display_window = self.show_display_window;
+        window.show_game_window = self.show_game_window;
+        window.show_memory_card_window = self.show_memory_card_window;
+        window.show_watch_window = self.show_watch_window;
+        self.config.save();
+
+        // Give the emu thread a chance to save the memory card. It can't respond while it is waiting on gdb
+        if !self.awaiting_gdb && !self.gdb_connected {
+            // The send fails if the thread already shut down from Quit, but it still needs joining
+            let _ = self.emu_handle.comm.tx.send(EmuMessage::Kill);
+            if let Some(emu_thread) = self.emu_handle.emu_thread.take() {
+                let _ = emu_thread.join();
+            }
+        }
+    }
+
     fn update(&mut self, ctx: &eframe::egui::Context, frame: &mut eframe::Frame) {
 
         if !self.has_initialized {
+            let repaint_ctx = ctx.clone();
             self.emu_handle
                 .comm
                 .tx
-                .send(EmuMessage::RecieveGuiContext(ctx.clone()))
+                .send(EmuMessage::SetFrameCallback(Box::new(move || {
+                    repaint_ctx.request_repaint()
+                })))
                 .unwrap();
     
             self.has_initialized = true;
         }
 
-        // TODO: Fix this. Runs the envent loop enough to grab most of the controller updates
-        for _ in 0..16 {
-            self.gilrs_instance.next_event();
+        let keyboard_state = ctx.input(|i| get_button_state_from_keyboard(i, &self.config.input));
+        self.window_rects = ctx.input(|i| {
+            let viewport = i.viewport();
+            viewport.inner_rect.zip(viewport.outer_rect)
+        });
+        let background_state = ctx.input(|i| {
+            let viewport = i.viewport();
+            if viewport.minimized == Some(true) {
+                BackgroundState::Minimized
+            } else if viewport.focused == Some(false) {
+                BackgroundState::Unfocused
+            } else {
+                BackgroundState::Focused
+            }
+        });
+        if background_state != self.background_state {
+            self.background_state = background_state;
+            let _ = self
+                .emu_handle
+                .comm
+                .tx
+                .send(EmuMessage::SetBackgroundState(background_state));
+        }
+        // The emu thread samples the controllers itself each frame, so only send changes. Ignore
+        // failures, the emu thread may have just shut down
+        if self.last_keyboard_state != Some(keyboard_state) {
+            self.last_keyboard_state = Some(keyboard_state);
+            let _ = self
+                .emu_handle
+                .comm
+                .tx
+                .send(EmuMessage::UpdateControllers(keyboard_state));
+        }
+        let analog_key = Key::from_name(&self.config.input.analog);
+        if analog_key.map_or(false, |key| ctx.input(|i| i.key_pressed(key))) {
+            let _ = self.emu_handle.comm.tx.send(EmuMessage::PressAnalogButton(0));
+        }
+        let advance_key = Key::from_name(&self.config.input.frame_advance);
+        let advance_held =
+            self.halted() && advance_key.map_or(false, |key| ctx.input(|i| i.key_down(key)));
+        let now = Instant::now();
+        if self.frame_advance.update(advance_held, now) {
+            let _ = self.emu_handle.comm.tx.send(EmuMessage::AdvanceFrame);
+        }
+        if let Some(wait) = self.frame_advance.time_until_repeat(now) {
+            ctx.request_repaint_after(wait);
         }
-        let psx_button_state = ctx.input(|i| { self.get_button_state(i) } );
-        self.emu_handle
-            .comm
-            .tx
-            .send(EmuMessage::UpdateControllers(psx_button_state))
-            .unwrap();
         // Process emu messages until empty
         loop {
             match self.emu_handle.comm.rx.try_recv() {
                 Ok(msg) => match msg {
-                    ClientMessage::FrameReady(vram_frame, frame_time, is_full_color) => {
+                    ClientMessage::FrameReady(vram_frame, display_data, info, watch_values) => {
+                        self.watch_values = watch_values;
                         let pixel_data = transform_psx16_to_32(
                             &vram_frame,
                             0,
@@ -201,32 +802,19 @@ impl eframe::App for FogStationApp {
                                 [VRAM_WIDTH, VRAM_HEIGHT],
                                 &pixel_data,
                             ),
-                            egui::TextureOptions::LINEAR,
+                            egui::TextureOptions::NEAREST,
                         ));
 
-                        let display_data = if is_full_color {
-                            transform_psx24_to_32(
-                                &vram_frame,
-                                self.display_origin.0 as u32,
-                                self.display_origin.1 as u32,
-                                self.latest_resolution.width,
-                                self.latest_resolution.height,
-                            )
-                        } else {
-                            transform_psx16_to_32(
-                                &vram_frame,
-                                self.display_origin.0 as u32,
-                                self.display_origin.1 as u32,
-                                self.latest_resolution.width,
-                                self.latest_resolution.height,
-                            )
-                        };
-
-
                         self.last_frame_data = pixel_data;
+                        self.last_vram = vram_frame;
+                        self.display_area = info.display_area;
+                        self.draw_area = info.draw_area;
                         self.last_display_data = display_data;
-                        self.times.push(frame_time as usize);
+                        self.times.push(info.time as usize);
+                        self.speed.record(info.number, info.target_rate, Instant::now());
                     }
+                    ClientMessage::HardwareFrame(frame) => self.last_hw_frame = Some(Arc::new(frame)),
+                    ClientMessage::FrameTiming(timing) => self.latest_frame_timing = timing,
                     ClientMessage::ResolutionChanged(res) => self.latest_resolution = res,
                     ClientMessage::AwaitingGDBClient => {
                         self.awaiting_gdb = true;
@@ -244,21 +832,90 @@ impl eframe::App for FogStationApp {
                     }
                     ClientMessage::Halted => self.emu_handle.halted = true,
                     ClientMessage::Continuing => self.emu_handle.halted = false,
-                    ClientMessage::DisplayOriginChanged(new_origin) => {
-                        self.display_origin = new_origin
+                    ClientMessage::DisplayTimingChanged(divider, video_mode) => {
+                        self.dot_clock_divider = divider;
+                        self.video_mode = video_mode;
                     }
                     ClientMessage::LatestGPULog(call_log) => {
                         self.latest_gpu_log = call_log;
                         self.highlighted_gpu_calls.clear();
-                        println!("Calls in log: {}", self.latest_gpu_log.len());
+                        self.diffed_gpu_call = None;
+                        log::debug!("Calls in log: {}", self.latest_gpu_log.len());
                     }
+                    ClientMessage::GpuCallCounts(counts) => self.gpu_call_counts = counts,
                     ClientMessage::LatestCdMask(mask) => self.latest_cd_mask = mask,
                     ClientMessage::LatestCdFlag(flag) => self.latest_cd_flag = flag,
+                    ClientMessage::LatestCdSectorBuffer(info) => self.latest_cd_sector_buffer = info,
+                    ClientMessage::LatestCdMode(mode, speed_changing) => {
+                        self.latest_cd_mode = mode;
+                        self.cd_speed_changing = speed_changing;
+                    }
+                    ClientMessage::PostCode(code) => self.post_code = code,
+                    ClientMessage::PadModeChanged(mode) => self.pad_mode = mode,
+                    ClientMessage::GamepadsChanged(gamepads) => self.gamepads = gamepads,
+                    ClientMessage::BiosDetected(info) => self.bios_info = Some(info),
+                    ClientMessage::LoadSucceeded(path) => {
+                        let name = path.file_name().unwrap_or_default().to_string_lossy();
+                        self.osd.notify(format!("Loaded {}", name));
+                        self.config.add_recent_file(path);
+                        self.config.save();
+                    }
+                    ClientMessage::LoadFailed(error) => self.load_error = Some(error),
+                    ClientMessage::MemoryCardContents(contents) => {
+                        self.save_icons = contents
+                            .iter()
+                            .flat_map(|contents| contents.saves.iter())
+                            .map(|save| {
+                                save.icon_frames
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(i, frame)| {
+                                        ctx.load_texture(
+                                            format!("save_icon_{}_{}", save.first_block, i),
+                                            egui::ColorImage::from_rgba_unmultiplied([16, 16], frame),
+                                            egui::TextureOptions::NEAREST,
+                                        )
+                                    })
+                                    .collect()
+                            })
+                            .collect();
+                        self.memory_card = contents;
+                    }
+                    ClientMessage::MemoryCardError(error) => self.memory_card_error = Some(error),
+                    ClientMessage::GameChanged(serial) => {
+                        if let Some(serial) = &serial {
+                            println!("Game serial: {}", serial);
+                        }
+                        self.game_serial = serial;
+                        self.compat_warning = None;
+                        self.apply_settings();
+                        self.watches = self
+                            .game_serial
+                            .as_ref()
+                            .and_then(|serial| self.config.watches.get(serial))
+                            .cloned()
+                            .unwrap_or_default();
+                        self.watch_list_changed();
+                    }
+                    ClientMessage::CompatWarning(warning) => {
+                        println!("Compatibility warning: {}", warning);
+                        self.compat_warning = Some(warning);
+                    }
+                    ClientMessage::Exited(status) => {
+                        println!("Emu thread exited with status {}", status);
+                        self.emu_exited = true;
+                        ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                    }
                 },
                 Err(e) => {
                     match e {
                         std::sync::mpsc::TryRecvError::Empty => break, // No messages left, break out of the loop
-                        std::sync::mpsc::TryRecvError::Disconnected => panic!("Emu thread died!"),
+                        std::sync::mpsc::TryRecvError::Disconnected => {
+                            if self.emu_exited {
+                                break;
+                            }
+                            panic!("Emu thread died!")
+                        }
                     }
                 }
             }
@@ -267,20 +924,81 @@ impl eframe::App for FogStationApp {
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
                 ui.menu_button("File", |ui| {
+                    if ui.button("Open Disc...").clicked() {
+                        ui.close_menu();
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("Disc image", &["cue", "bin"])
+                            .pick_file()
+                        {
+                            self.load_file(path);
+                        }
+                    }
+                    if ui.button("Open EXE...").clicked() {
+                        ui.close_menu();
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("PS-X EXE", &["exe", "psexe"])
+                            .pick_file()
+                        {
+                            self.load_file(path);
+                        }
+                    }
+                    ui.menu_button("Recent", |ui| {
+                        if self.config.recent_files.is_empty() {
+                            ui.label("No recent files");
+                        }
+                        for path in self.config.recent_files.clone() {
+                            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                            if ui.button(name).on_hover_text(path.display().to_string()).clicked() {
+                                ui.close_menu();
+                                self.load_file(path);
+                            }
+                        }
+                    });
+                    ui.separator();
                     if ui.button("Quit").clicked() {
-                        println!("This is where I would quit, IF I HAD ONE");
-                        //frame.quit();
+                        ui.close_menu();
+                        if self.awaiting_gdb || self.gdb_connected {
+                            // The emu thread is stuck talking to gdb, so there's no one to wait for
+                            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                        } else {
+                            // The window closes once the emu thread reports it has shut down
+                            let _ = self.emu_handle.comm.tx.send(EmuMessage::Kill);
+                        }
                     }
                 });
 
                 ui.menu_button("Settings", |ui| {
                     ui.checkbox(&mut self.show_gamepad_window, "Controller");
+                    ui.checkbox(&mut self.show_display_window, "Display");
+                    ui.add_enabled(
+                        self.game_serial.is_some(),
+                        egui::Checkbox::new(&mut self.show_game_window, "Game"),
+                    );
+                    ui.checkbox(&mut self.show_memory_card_window, "Memory Card");
                 });
                 ui.menu_button("Control", |ui| {
                     let halt_button_text = if self.halted() { "Resume" } else { "Halt" };
                     if ui.button(halt_button_text).clicked() {
                         self.set_halt(!self.halted());
                     };
+                    if ui
+                        .add_enabled(
+                            self.halted(),
+                            egui::Button::new("Advance Frame")
+                                .shortcut_text(self.config.input.frame_advance.as_str()),
+                        )
+                        .clicked()
+                    {
+                        self.emu_handle.comm.tx.send(EmuMessage::AdvanceFrame).unwrap();
+                    }
+                    if ui.button("Reset (soft)").clicked() {
+                        self.emu_handle.comm.tx.send(EmuMessage::SoftReset).unwrap();
+                        ui.close_menu();
+                    }
+                    if ui.button("Power cycle").clicked() {
+                        self.emu_handle.comm.tx.send(EmuMessage::Reset).unwrap();
+                        ui.close_menu();
+                    }
 
                     if ui
                         .checkbox(&mut self.emu_handle.frame_limited, "Frame Limiter")
@@ -292,10 +1010,50 @@ impl eframe::App for FogStationApp {
                             .send(EmuMessage::SetFrameLimiter(self.emu_handle.frame_limited))
                             .unwrap();
                     };
+                    if ui.checkbox(&mut self.config.halt_on_boot, "Halt on Boot").clicked() {
+                        self.config.save();
+                        self.emu_handle
+                            .comm
+                            .tx
+                            .send(EmuMessage::SetHaltOnBoot(self.config.halt_on_boot))
+                            .unwrap();
+                    }
+                    ui.menu_button("In Background", |ui| {
+                        for behavior in [
+                            BackgroundBehavior::KeepRunning,
+                            BackgroundBehavior::Pause,
+                            BackgroundBehavior::Throttle,
+                        ] {
+                            if ui
+                                .radio_value(&mut self.config.background, behavior, behavior.name())
+                                .clicked()
+                            {
+                                self.config.save();
+                                self.emu_handle
+                                    .comm
+                                    .tx
+                                    .send(EmuMessage::SetBackgroundBehavior(behavior))
+                                    .unwrap();
+                            }
+                        }
+                    });
+                    ui.checkbox(&mut self.show_fps_overlay, "FPS Overlay");
+                    ui.checkbox(&mut self.show_perf_hud, "Performance HUD");
                 });
                 ui.menu_button("Debug", |ui| {
                     ui.checkbox(&mut self.show_vram_window, "VRAM Viewer");
                     ui.checkbox(&mut self.show_gpu_call_window, "GPU Call Debugger");
+                    ui.checkbox(&mut self.show_watch_window, "Watches");
+                    if ui
+                        .checkbox(&mut self.strict_size_check, "Drop Oversized Polygons")
+                        .clicked()
+                    {
+                        self.emu_handle
+                            .comm
+                            .tx
+                            .send(EmuMessage::SetStrictSizeCheck(self.strict_size_check))
+                            .unwrap();
+                    }
                     if ui
                         .checkbox(&mut self.memory_logging, "Memory Logging")
                         .clicked()
@@ -315,8 +1073,28 @@ impl eframe::App for FogStationApp {
                         ui.label(format!("IRQ mask: {:#X}", self.irq_mask));
                     } else {
                         ui.label(format!("{:.2} fps", 1000.0 / self.times.average()));
+                        if let Some(speed) = self.speed.speed() {
+                            ui.label(format!("emulation speed: {:.0}%", speed * 100.0)).on_hover_text(format!(
+                                "Frames skipped: {}\nFrames duplicated: {}",
+                                self.speed.skipped, self.speed.duplicated
+                            ));
+                        }
+                    }
+
+                    if let Some(info) = &self.bios_info {
+                        ui.label(format!("BIOS: {:?}", info.region));
                     }
 
+                    if let Some(code) = self.post_code {
+                        ui.label(format!("POST: {:#X}", code));
+                    }
+
+                    ui.label(match self.pad_mode {
+                        PadMode::Digital => "Pad: Digital",
+                        PadMode::Analog => "Pad: Analog",
+                        PadMode::AnalogLocked => "Pad: Analog (locked)",
+                    });
+
                     if self.awaiting_gdb {
                         ui.label("Awaiting GDB connection...");
                     }
@@ -328,51 +1106,346 @@ impl eframe::App for FogStationApp {
             });
         });
 
-        if self.show_vram_window {
-            egui::Window::new("VRAM Viewer").show(ctx, |ui| {
-                if let Some(vram) = &self.vram_texture {
-                    ui.image(vram);
-                }
+        if let Some(warning) = self.compat_warning.clone() {
+            egui::TopBottomPanel::top("compat_banner").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.colored_label(Color32::YELLOW, warning);
+                    if ui.button("Dismiss").clicked() {
+                        self.compat_warning = None;
+                    }
+                });
             });
         }
 
+        let dropped_files: Vec<PathBuf> = ctx.input(|i| {
+            i.raw.dropped_files.iter().filter_map(|f| f.path.clone()).collect()
+        });
+        // Only the first file counts if several are dropped at once
+        if let Some(path) = dropped_files.into_iter().next() {
+            self.load_file(path);
+        }
+
+        if let Some(error) = self.load_error.clone() {
+            egui::Window::new("Load Failed")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+                .show(ctx, |ui| {
+                    ui.label(error);
+                    if ui.button("OK").clicked() {
+                        self.load_error = None;
+                    }
+                });
+        }
+
+        if self.show_memory_card_window {
+            self.memory_card_window(ctx);
+        }
+
+        if self.show_watch_window {
+            self.watch_window(ctx);
+        }
+
+        if self.show_vram_window {
+            self.vram_window(ctx);
+        }
+
         if self.show_gamepad_window {
             egui::Window::new("Settings | Controller").show(ctx, |ui| {
                 let current_id = self.active_controller_id;
-                let current_gamepad = if let Some(id) = current_id {
-                    Some(self.gilrs_instance.gamepad(id))
-                } else {
-                    None
-                };
+                let current_gamepad = current_id
+                    .and_then(|id| self.gamepads.iter().find(|gamepad| gamepad.id == id));
                 egui::ComboBox::from_label("Input Source")
                     .selected_text(format!(
                         "{}",
-                        match &current_gamepad {
-                            Some(gamepad) => gamepad.name(),
+                        match current_gamepad {
+                            Some(gamepad) => gamepad.name.as_str(),
                             _ => "Keyboard",
                         }
                     ))
                     .show_ui(ui, |ui| {
                         ui.selectable_value(&mut self.active_controller_id, None, "Keyboard");
-                        for (id, gamepad) in self.gilrs_instance.gamepads() {
-                            let connected_string = if gamepad.is_connected() {
+                        for gamepad in &self.gamepads {
+                            let connected_string = if gamepad.connected {
                                 ""
                             } else {
                                 " DISCONNECTED"
                             };
                             ui.selectable_value(
                                 &mut self.active_controller_id,
-                                Some(id),
-                                format!("{}{}", gamepad.name(), connected_string),
+                                Some(gamepad.id),
+                                format!("{}{}", gamepad.name, connected_string),
                             );
                         }
                     });
+                if self.active_controller_id != current_id {
+                    let _ = self
+                        .emu_handle
+                        .comm
+                        .tx
+                        .send(EmuMessage::SelectGamepad(self.active_controller_id));
+                }
             });
         }
 
+        if self.show_display_window {
+            let game = self
+                .game_serial
+                .as_ref()
+                .and_then(|serial| self.config.games.get(serial))
+                .cloned()
+                .unwrap_or_default();
+            let old_display = self.config.display.clone();
+
+            egui::Window::new("Settings | Display").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_label("Aspect Ratio")
+                        .selected_text(aspect_ratio_name(self.config.display.aspect_ratio))
+                        .show_ui(ui, |ui| {
+                            for aspect_ratio in [AspectRatio::Force4x3, AspectRatio::Native] {
+                                ui.selectable_value(&mut self.config.display.aspect_ratio, aspect_ratio, aspect_ratio_name(aspect_ratio));
+                            }
+                        });
+                    self.override_marker(ui, game.aspect_ratio.is_some());
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.config.display.integer_scaling, "Integer Scaling");
+                    self.override_marker(ui, game.integer_scaling.is_some());
+                });
+
+                ui.label("Overscan Crop");
+                let display = &mut self.config.display;
+                egui::Grid::new("display_crop_grid").show(ui, |ui| {
+                    ui.label("Top");
+                    ui.add(egui::DragValue::new(&mut display.crop_top).clamp_range(0..=64));
+                    ui.label("Bottom");
+                    ui.add(egui::DragValue::new(&mut display.crop_bottom).clamp_range(0..=64));
+                    ui.end_row();
+                    ui.label("Left");
+                    ui.add(egui::DragValue::new(&mut display.crop_left).clamp_range(0..=64));
+                    ui.label("Right");
+                    ui.add(egui::DragValue::new(&mut display.crop_right).clamp_range(0..=64));
+                    ui.end_row();
+                });
+
+                ui.horizontal(|ui| {
+                    egui::ComboBox::from_label("Shader")
+                        .selected_text(self.config.display.shader.clone())
+                        .show_ui(ui, |ui| {
+                            for shader in &self.available_shaders {
+                                ui.selectable_value(&mut self.config.display.shader, shader.name.clone(), shader.name.clone());
+                            }
+                        });
+                    if ui.button("Rescan").clicked() {
+                        self.available_shaders = available_shaders();
+                    }
+                    self.override_marker(ui, game.shader.is_some());
+                });
+
+                if let Some(error) = self.disp_shader_manager.lock().unwrap().last_error() {
+                    ui.colored_label(Color32::RED, format!("Shader failed to compile, using the default:\n{}", error));
+                }
+
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut self.config.display.hardware_renderer, "OpenGL Renderer (experimental)");
+                    ui.add_enabled(
+                        self.config.display.hardware_renderer,
+                        egui::DragValue::new(&mut self.config.display.render_scale).clamp_range(1..=8).suffix("x"),
+                    );
+                });
+                ui.checkbox(&mut self.config.display.threaded_gpu, "Draw on a separate thread");
+            });
+
+            if self.config.display != old_display {
+                self.config.save();
+                self.apply_settings();
+            }
+        }
+
+        if self.show_game_window {
+            if let Some(serial) = self.game_serial.clone() {
+                let old_game = self.config.games.get(&serial).cloned().unwrap_or_default();
+                let mut game = old_game.clone();
+                let global = self.config.clone();
+
+                egui::Window::new(format!("Settings | Game ({})", serial)).show(ctx, |ui| {
+                    ui.label("Checked settings override the global value for this game");
+                    egui::Grid::new("game_override_grid").show(ui, |ui| {
+                        override_row(ui, "Frame Limiter", &mut game.frame_limiter, global.frame_limiter, |ui, value| {
+                            ui.checkbox(value, "");
+                        });
+                        override_row(ui, "Video Mode", &mut game.video_mode, VideoModeSetting::Auto, |ui, value| {
+                            egui::ComboBox::from_id_source("game_video_mode")
+                                .selected_text(value.name())
+                                .show_ui(ui, |ui| {
+                                    for mode in [VideoModeSetting::Auto, VideoModeSetting::ForceNtsc, VideoModeSetting::ForcePal] {
+                                        ui.selectable_value(value, mode, mode.name());
+                                    }
+                                });
+                        });
+                        override_row(ui, "CPU Overclock", &mut game.cpu_overclock, 1.0, |ui, value| {
+                            ui.add(
+                                egui::DragValue::new(value)
+                                    .clamp_range(1.0..=MAX_CPU_OVERCLOCK)
+                                    .speed(0.1)
+                                    .suffix("x"),
+                            )
+                            .on_hover_text("Gives the game more cpu time per frame. Games that time things with busy loops will run wrong");
+                        });
+                        override_row(ui, "Aspect Ratio", &mut game.aspect_ratio, global.display.aspect_ratio, |ui, value| {
+                            egui::ComboBox::from_id_source("game_aspect_ratio")
+                                .selected_text(aspect_ratio_name(*value))
+                                .show_ui(ui, |ui| {
+                                    for aspect_ratio in [AspectRatio::Force4x3, AspectRatio::Native] {
+                                        ui.selectable_value(value, aspect_ratio, aspect_ratio_name(aspect_ratio));
+                                    }
+                                });
+                        });
+                        override_row(ui, "Integer Scaling", &mut game.integer_scaling, global.display.integer_scaling, |ui, value| {
+                            ui.checkbox(value, "");
+                        });
+                        override_row(ui, "Shader", &mut game.shader, global.display.shader.clone(), |ui, value| {
+                            egui::ComboBox::from_id_source("game_shader")
+                                .selected_text(value.clone())
+                                .show_ui(ui, |ui| {
+                                    for shader in &self.available_shaders {
+                                        ui.selectable_value(value, shader.name.clone(), shader.name.clone());
+                                    }
+                                });
+                        });
+                    });
+                });
+
+                if game != old_game {
+                    if game == GameOverrides::default() {
+                        self.config.games.remove(&serial);
+                    } else {
+                        self.config.games.insert(serial, game);
+                    }
+                    self.config.save();
+                    self.apply_settings();
+                }
+            }
+        }
+
         if self.show_gpu_call_window {
             egui::Window::new("GPU Call Debugger").show(ctx, |ui| {
-                if self.halted() {
+                ui.horizontal(|ui| {
+                    ui.label("Send:");
+                    let mut capture_changed = false;
+                    for (capture, name) in [
+                        (GpuCapture::Off, "Nothing"),
+                        (GpuCapture::Counts, "Counts"),
+                        (GpuCapture::Full, "Every Frame"),
+                    ] {
+                        capture_changed |= ui.radio_value(&mut self.gpu_capture, capture, name).changed();
+                    }
+                    if capture_changed {
+                        let _ = self.emu_handle.comm.tx.send(EmuMessage::SetGpuCapture(self.gpu_capture));
+                    }
+                    if ui
+                        .add_enabled(!self.halted(), egui::Button::new("Capture Next Frame"))
+                        .clicked()
+                    {
+                        let _ = self.emu_handle.comm.tx.send(EmuMessage::CaptureNextFrame);
+                    }
+                });
+
+                if self.gpu_capture != GpuCapture::Off {
+                    let counts = &self.gpu_call_counts;
+                    let kinds: Vec<String> = counts
+                        .by_operation
+                        .iter()
+                        .map(|(operation, count)| format!("{} {}", operation, count))
+                        .collect();
+                    ui.label(format!(
+                        "Last frame: {} calls, {} dropped. {}",
+                        counts.total,
+                        counts.dropped,
+                        kinds.join(", ")
+                    ));
+                }
+
+                ui.horizontal(|ui| {
+                    if ui
+                        .checkbox(&mut self.gpu_deep_capture, "Deep Capture")
+                        .on_hover_text("Keeps a copy of each call's palette and the VRAM it changed. Uses more memory")
+                        .changed()
+                    {
+                        self.emu_handle
+                            .comm
+                            .tx
+                            .send(EmuMessage::SetGpuDeepCapture(self.gpu_deep_capture))
+                            .unwrap();
+                    }
+
+                    if ui
+                        .add_enabled(!self.latest_gpu_log.is_empty(), egui::Button::new("Export..."))
+                        .clicked()
+                    {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("Draw log", &["fsdl"])
+                            .set_file_name("draw_log.fsdl")
+                            .save_file()
+                        {
+                            if let Err(e) = draw_log::save_draw_log(&self.latest_gpu_log, &path) {
+                                self.gpu_log_error = Some(format!("Unable to save {}: {}", path.display(), e));
+                            }
+                        }
+                    }
+
+                    if ui.button("Load...").clicked() {
+                        if let Some(path) = rfd::FileDialog::new()
+                            .add_filter("Draw log", &["fsdl"])
+                            .pick_file()
+                        {
+                            match draw_log::load_draw_log(&path) {
+                                Ok(log) => {
+                                    self.latest_gpu_log = log;
+                                    self.highlighted_gpu_calls.clear();
+                                    self.diffed_gpu_call = None;
+                                    self.update_vram_view(ctx);
+                                }
+                                Err(e) => {
+                                    self.gpu_log_error = Some(format!("Unable to load {}: {}", path.display(), e))
+                                }
+                            }
+                        }
+                    }
+                });
+
+                if let Some(error) = &self.gpu_log_error {
+                    ui.colored_label(Color32::RED, error);
+                    if ui.button("Dismiss").clicked() {
+                        self.gpu_log_error = None;
+                    }
+                }
+                ui.separator();
+
+                if let Some(index) = self.diffed_gpu_call {
+                    let change = self.latest_gpu_log[index].vram_change.as_ref().unwrap();
+                    let mut view_changed = false;
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "Call {} changed {} pixels around ({}, {})",
+                            index,
+                            change.changed_pixels().count(),
+                            change.x,
+                            change.y
+                        ));
+                        view_changed |= ui.radio_value(&mut self.show_diff_before, true, "Before").changed();
+                        view_changed |= ui.radio_value(&mut self.show_diff_before, false, "After").changed();
+                        if ui.button("Clear").clicked() {
+                            self.diffed_gpu_call = None;
+                            view_changed = true;
+                        }
+                    });
+                    if view_changed {
+                        self.update_vram_view(ctx);
+                    }
+                }
+
+                if self.halted() || !self.latest_gpu_log.is_empty() {
                     if self.latest_gpu_log.len() == 0 {
                         ui.label("No GPU calls were made during this frame :(");
                     } else {
@@ -386,11 +1459,14 @@ impl eframe::App for FogStationApp {
                                 ui.label("Surface");
                                 ui.label("Transparency");
                                 ui.label("CLUT Depth");
+                                ui.label("CLUT");
                                 ui.label("Highlighted?");
+                                ui.label("Changes");
                                 ui.end_row();
                             });
 
                         // Grid contents
+                        let mut refresh_vram_view = false;
                         egui::ScrollArea::vertical().show(ui, |ui| {
                             egui::Grid::new("draw_element_grid").show(ui, |ui| {
                                 for (i, command) in self.latest_gpu_log.iter().enumerate() {
@@ -419,7 +1495,17 @@ impl eframe::App for FogStationApp {
                                         ui.label("N/A");
                                     }
 
-                                    ui.label(command.clut_size.to_string());
+                                    if let Some(color_mode) = command.clut_size {
+                                        ui.label(color_mode.to_string());
+                                    } else {
+                                        ui.label("N/A");
+                                    }
+
+                                    if let (Some(x), Some(y)) = (command.clut_x, command.clut_y) {
+                                        ui.label(format!("({}, {})", x, y));
+                                    } else {
+                                        ui.label("N/A");
+                                    }
 
                                     let mut should_be_highlighted =
                                         self.highlighted_gpu_calls.contains(&i);
@@ -444,29 +1530,30 @@ impl eframe::App for FogStationApp {
                                         should_update_highlights = true;
                                     }
 
-                                    // Push a newly highlighted frame to the screen
-                                    if should_update_highlights {
-                                        let mut new_frame = self.last_frame_data.clone();
-
-                                        apply_highlights(&self, &mut new_frame);
-
-                                        self.vram_texture = Some(ctx.load_texture(
-                                            "VRAM",
-                                            egui::ColorImage::from_rgba_unmultiplied(
-                                                [VRAM_WIDTH, VRAM_HEIGHT],
-                                                &new_frame,
-                                            ),
-                                            egui::TextureOptions::LINEAR,
-                                        ));
+                                    let has_change = command.vram_change.is_some();
+                                    let diffed = self.diffed_gpu_call == Some(i);
+                                    if ui
+                                        .add_enabled(has_change, egui::RadioButton::new(diffed, ""))
+                                        .on_disabled_hover_text("Needs deep capture")
+                                        .clicked()
+                                    {
+                                        self.diffed_gpu_call = if diffed { None } else { Some(i) };
+                                        should_update_highlights = true;
                                     }
 
+                                    // Push a newly highlighted frame to the screen
+                                    refresh_vram_view |= should_update_highlights;
+
                                     ui.end_row();
                                 }
                             });
                         });
+                        if refresh_vram_view {
+                            self.update_vram_view(ctx);
+                        }
                     }
                 } else {
-                    ui.label("Must be halted to use gpu call debugger");
+                    ui.label("Halt or capture a frame to see its calls");
                 }
             });
         }
@@ -475,24 +1562,77 @@ impl eframe::App for FogStationApp {
             egui::Window::new("Debugging | CDROM").show(ctx, |ui| {
                ui.label(format!("CD Mask: {:#X}", self.latest_cd_mask));
                ui.label(format!("CD Flags: {:#X}", self.latest_cd_flag));
+               let buffer = &self.latest_cd_sector_buffer;
+               ui.label(format!("Unread sectors: {}", buffer.unread));
+               ui.label(format!("Write slot: {} Read slot: {}", buffer.write_slot, buffer.read_slot));
+               ui.label(format!("Dropped sectors: {}", buffer.dropped));
+               let speed = if self.latest_cd_mode & 0x80 != 0 { "double" } else { "single" };
+               ui.label(format!("Mode: {:#04X} ({} speed)", self.latest_cd_mode, speed));
+               if self.cd_speed_changing {
+                   ui.label("Changing speed, reads are paused");
+               }
+               ui.label("Sector size changes apply from the next sector read");
+            });
+        }
+
+        if self.show_perf_hud {
+            let timing = &self.latest_frame_timing;
+            let share = |cycles: u64| 100.0 * cycles as f64 / timing.cycles.max(1) as f64;
+            egui::Window::new("Performance").show(ctx, |ui| {
+                ui.label(format!("Host frame time: {:.2} ms", self.times.average()));
+                ui.label(format!("Emulated cycles: {}", timing.cycles));
+                ui.label(format!(
+                    "Instructions: {} ({:.1}% of cycles)",
+                    timing.instructions,
+                    share(timing.instructions)
+                ));
+                ui.label(format!(
+                    "CPU stalls: {} cycles ({:.1}%)",
+                    timing.stall_cycles,
+                    share(timing.stall_cycles)
+                ));
+                ui.label(format!("DMA: {} cycles ({:.1}%)", timing.dma_cycles, share(timing.dma_cycles)));
+                ui.label(format!(
+                    "Triangles: {} drawn, {} dropped",
+                    timing.gpu.triangles_drawn, timing.gpu.triangles_dropped
+                ));
+                ui.separator();
+                egui::Grid::new("frame_events").striped(true).show(ui, |ui| {
+                    for (name, count) in &timing.events {
+                        ui.label(*name);
+                        ui.label(count.to_string());
+                        ui.end_row();
+                    }
+                });
             });
         }
 
+        if self.show_fps_overlay && !self.halted() {
+            let mut text = format!("{:.1} fps", 1000.0 / self.times.average());
+            if let Some(speed) = self.speed.speed() {
+                text += &format!(" {:.0}%", speed * 100.0);
+            }
+            self.osd.rect(2.0, 2.0, 100.0, 12.0, Color32::from_rgba_premultiplied(0, 0, 0, 160));
+            self.osd.text(4.0, 4.0, Color32::WHITE, text);
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             let frame_data_copy = self.last_display_data.clone();
             ui.with_layout(
                 egui::Layout::centered_and_justified(Direction::TopDown),
                 |ui| {
                     let pane_size = ui.max_rect();
-                    let (scaled_height, scaled_width) =
-                        if pane_size.width() > pane_size.height() * 1.3333 {
-                            (pane_size.height(), pane_size.height() * 1.3333)
-                        } else {
-                            (pane_size.width() * 0.75, pane_size.width())
-                        };
-                    
+                    let geometry = display_geometry(
+                        &self.settings.display,
+                        &self.latest_resolution,
+                        self.dot_clock_divider,
+                        self.video_mode,
+                        pane_size.width(),
+                        pane_size.height(),
+                    );
+
                     egui::Frame::canvas(ui.style()).show(ui, |ui| {
-                        self.custom_painting(ui, frame_data_copy, scaled_width, scaled_height, self.latest_resolution.width as i32, self.latest_resolution.height as i32);
+                        self.custom_painting(ui, frame_data_copy, geometry.width, geometry.height, self.latest_resolution.width as i32, self.latest_resolution.height as i32, geometry.uv_min, geometry.uv_max);
                     });
                 },
             );
@@ -500,28 +1640,131 @@ impl eframe::App for FogStationApp {
     }
 }
 
-fn get_button_state_from_keyboard(input_state: &egui::InputState) -> ButtonState {
+/// Size of the on screen picture and the part of the display texture shown in it
+struct DisplayGeometry {
+    width: f32,
+    height: f32,
+    uv_min: [f32; 2],
+    uv_max: [f32; 2],
+}
+
+/// Fits the displayed area into the pane. Cropping and aspect correction only change the quad size
+/// and texture coordinates, so the pixels themselves are never resampled here
+fn display_geometry(
+    config: &DisplayConfig,
+    resolution: &Resolution,
+    dot_clock_divider: u32,
+    video_mode: VideoMode,
+    pane_width: f32,
+    pane_height: f32,
+) -> DisplayGeometry {
+    let width = resolution.width.max(1) as f32;
+    let height = resolution.height.max(1) as f32;
+
+    let crop_x = (config.crop_left + config.crop_right) as f32;
+    let crop_y = (config.crop_top + config.crop_bottom) as f32;
+    let cropped_width = (width - crop_x).max(1.0);
+    let cropped_height = (height - crop_y).max(1.0);
+
+    // Aspect ratio of the whole uncropped displayed area
+    let full_aspect = match config.aspect_ratio {
+        AspectRatio::Force4x3 => 4.0 / 3.0,
+        AspectRatio::Native => {
+            // A full 4:3 line is 2560 video clocks wide, and a full field is 240 (NTSC) or 288 (PAL) lines tall
+            let field_lines = match video_mode {
+                VideoMode::Ntsc => 240.0,
+                VideoMode::Pal => 288.0,
+            };
+            let frame_lines = if height > field_lines { field_lines * 2.0 } else { field_lines };
+            let visible_x = (width * dot_clock_divider as f32) / 2560.0;
+            let visible_y = height / frame_lines;
+            (4.0 / 3.0) * visible_x / visible_y
+        }
+    };
+    let aspect = full_aspect * (cropped_width / width) / (cropped_height / height);
+
+    let (mut out_width, mut out_height) = if pane_width > pane_height * aspect {
+        (pane_height * aspect, pane_height)
+    } else {
+        (pane_width, pane_width / aspect)
+    };
+
+    if config.integer_scaling {
+        // Scale by whole multiples of the line count. Too small a pane falls back to plain fitting
+        let scale = (out_height / cropped_height).floor();
+        if scale >= 1.0 {
+            out_height = cropped_height * scale;
+            out_width = out_height * aspect;
+        }
+    }
+
+    DisplayGeometry {
+        width: out_width,
+        height: out_height,
+        uv_min: [config.crop_left as f32 / width, config.crop_top as f32 / height],
+        uv_max: [
+            (width - config.crop_right as f32) / width,
+            (height - config.crop_bottom as f32) / height,
+        ],
+    }
+}
+
+fn get_button_state_from_keyboard(input_state: &egui::InputState, bindings: &KeyBindings) -> ButtonState {
+    // Unknown key names in the config just leave that button unbound
+    let down = |name: &str| Key::from_name(name).map_or(false, |key| input_state.key_down(key));
     ButtonState {
         controller_type: ControllerType::DigitalPad,
-        button_x: input_state.key_down(Key::K),
-        button_square: input_state.key_down(Key::J),
-        button_triangle: input_state.key_down(Key::I),
-        button_circle: input_state.key_down(Key::L),
-        button_up: input_state.key_down(Key::W),
-        button_down: input_state.key_down(Key::S),
-        button_left: input_state.key_down(Key::A),
-        button_right: input_state.key_down(Key::D),
-        button_l1: input_state.key_down(Key::E),
-        button_l2: input_state.key_down(Key::Q),
+        button_x: down(&bindings.cross),
+        button_square: down(&bindings.square),
+        button_triangle: down(&bindings.triangle),
+        button_circle: down(&bindings.circle),
+        button_up: down(&bindings.up),
+        button_down: down(&bindings.down),
+        button_left: down(&bindings.left),
+        button_right: down(&bindings.right),
+        button_l1: down(&bindings.l1),
+        button_l2: down(&bindings.l2),
         button_l3: false,
-        button_r1: input_state.key_down(Key::U),
-        button_r2: input_state.key_down(Key::P),
+        button_r1: down(&bindings.r1),
+        button_r2: down(&bindings.r2),
         button_r3: false,
-        button_select: input_state.key_down(Key::Backspace),
-        button_start: input_state.key_down(Key::Enter),
+        button_select: down(&bindings.select),
+        button_start: down(&bindings.start),
+    }
+}
+
+fn aspect_ratio_name(aspect_ratio: AspectRatio) -> &'static str {
+    match aspect_ratio {
+        AspectRatio::Force4x3 => "4:3",
+        AspectRatio::Native => "Native",
     }
 }
 
+/// One row of the per-game settings grid. The checkbox toggles the override, starting it from the global value
+fn override_row<T: Clone>(
+    ui: &mut egui::Ui,
+    label: &str,
+    value: &mut Option<T>,
+    global: T,
+    editor: impl FnOnce(&mut egui::Ui, &mut T),
+) {
+    let mut overridden = value.is_some();
+    ui.checkbox(&mut overridden, label);
+    match (overridden, value.is_some()) {
+        (true, false) => *value = Some(global),
+        (false, true) => *value = None,
+        _ => (),
+    }
+
+    match value {
+        Some(value) => editor(ui, value),
+        None => {
+            ui.label("(global)");
+        }
+    }
+    ui.end_row();
+}
+
 fn transform_psx16_to_32(
     psx_data: &Vec<u16>,
     origin_x: u32,
@@ -543,33 +1786,25 @@ fn transform_psx16_to_32(
         .collect::<Vec<u8>>()
 }
 
-fn transform_psx24_to_32(
-    psx_data: &Vec<u16>,
-    origin_x: u32,
-    origin_y: u32,
-    width: u32,
-    height: u32,
-) -> Vec<u8> {
-    psx_data
-        .iter()
-        .fold(vec![], |mut vec, val| {
-            vec.extend(val.to_le_bytes());
-            vec
-        })
-        .iter()
-        .enumerate()
-        .filter(|(i, _v)| {
-            (i % (VRAM_WIDTH * 2)) >= (origin_x * 2) as usize
-                && ((i) / (VRAM_WIDTH * 2)) >= origin_y as usize
-                && (i % (VRAM_WIDTH * 2)) < ((origin_x * 2) + (width * 3)) as usize
-                && ((i) / (VRAM_WIDTH * 2)) < (origin_y + height) as usize
-        })
-        .map(|(_i, v)| *v)
-        .collect::<Vec<u8>>()
-        .chunks_exact(3)
-        .map(|colors| [colors[0], colors[1], colors[2], 255])
-        .flatten()
-        .collect()
+/// Draws the diffed call's area as it was before or after the call, with every pixel the call
+/// changed in magenta
+fn apply_vram_change(app: &FogStationApp, pixel_data: &mut [u8]) {
+    let change = match app.diffed_gpu_call.and_then(|index| app.latest_gpu_log[index].vram_change.as_ref()) {
+        Some(change) => change,
+        None => return,
+    };
+
+    let pixels = if app.show_diff_before { &change.before } else { &change.after };
+    for (i, pixel) in pixels.iter().enumerate() {
+        let x = change.x as usize + i % change.width as usize;
+        let y = change.y as usize + i / change.width as usize;
+        let addr = (y * VRAM_WIDTH + x) * 4;
+        pixel_data[addr..addr + 4].copy_from_slice(&ps_pixel_to_gl(pixel));
+    }
+    for (x, y) in change.changed_pixels() {
+        let addr = (y as usize * VRAM_WIDTH + x as usize) * 4;
+        pixel_data[addr..addr + 4].copy_from_slice(&[255, 0, 255, 255]);
+    }
 }
 
 fn apply_highlights(app: &FogStationApp, pixel_data: &mut Vec<u8>) {
@@ -580,36 +1815,15 @@ fn apply_highlights(app: &FogStationApp, pixel_data: &mut Vec<u8>) {
             let min_x = points.iter().min_by_key(|v| v.x).unwrap().x;
             let min_y = points.iter().min_by_key(|v| v.y).unwrap().y;
 
-            let max_x = points.iter().max_by_key(|v| v.x).unwrap().x;
-            let max_y = points.iter().max_by_key(|v| v.y).unwrap().y;
-
-            let tex_base_x = (call.tex_base_x * 64) as i16;
-            let tex_base_y = (call.tex_base_y * 256) as i16;
-
-            let tex_min_x = points.iter().min_by_key(|v| v.tex_x).unwrap().tex_x;
-            let tex_min_y = points.iter().min_by_key(|v| v.tex_y).unwrap().tex_y;
-
-            let clut_div = match call.clut_size {
-                psx_emu::gpu::TextureColorMode::FourBit => 4,
-                psx_emu::gpu::TextureColorMode::EightBit => 2,
-                psx_emu::gpu::TextureColorMode::FifteenBit => 1,
-            };
-
-            // Do some wacky division stuff so the adjust the highlight size for clut
-            let tex_max_x = ((points.iter().max_by_key(|v| v.tex_x).unwrap().tex_x - tex_min_x)
-                / clut_div)
-                + tex_min_x;
-            let tex_max_y = points.iter().max_by_key(|v| v.tex_y).unwrap().tex_y;
+            let mut max_x = points.iter().max_by_key(|v| v.x).unwrap().x;
+            let mut max_y = points.iter().max_by_key(|v| v.y).unwrap().y;
+            // Lines cover their end points, and a straight one would have no area otherwise
+            if matches!(call.operation, DrawOperation::Line | DrawOperation::PolyLine) {
+                max_x += 1;
+                max_y += 1;
+            }
 
-            println!(
-                "Highlighting ({}, {}) -> ({}, {})",
-                min_x, min_y, max_x, max_y
-            );
-            println!(
-                "Tex coords ({}, {}) -> ({}, {})",
-                tex_min_x, tex_min_y, tex_max_x, tex_max_y
-            );
-            println!("base x {} base y {}", tex_base_x, tex_base_y);
+            log::trace!("Highlighting ({}, {}) -> ({}, {})", min_x, min_y, max_x, max_y);
 
             for y in min_y..max_y {
                 for x in min_x..max_x {
@@ -623,15 +1837,41 @@ fn apply_highlights(app: &FogStationApp, pixel_data: &mut Vec<u8>) {
                 }
             }
 
-            for y in tex_min_y..tex_max_y {
-                for x in tex_min_x..tex_max_x {
-                    let addr = (((y + tex_base_y) as i32) * 1024 + (x + tex_base_x) as i32) * 3;
-                    let current_pixel = pixel_data[addr as usize];
-                    let highlight_color = Color32::from_rgba_unmultiplied(0, 155, 0, 155);
+            // Only textured calls read from a texture page
+            if let (Some(color_mode), Some(page_x), Some(page_y)) = (call.clut_size, call.tex_base_x, call.tex_base_y) {
+                let tex_base_x = (page_x * 64) as i16;
+                let tex_base_y = (page_y * 256) as i16;
 
-                    pixel_data[addr as usize] += highlight_color.r();
-                    pixel_data[(addr + 1) as usize] += highlight_color.g();
-                    pixel_data[(addr + 2) as usize] += highlight_color.b();
+                let tex_min_x = points.iter().min_by_key(|v| v.tex_x).unwrap().tex_x;
+                let tex_min_y = points.iter().min_by_key(|v| v.tex_y).unwrap().tex_y;
+
+                let clut_div = match color_mode {
+                    psx_emu::gpu::TextureColorMode::FourBit => 4,
+                    psx_emu::gpu::TextureColorMode::EightBit => 2,
+                    psx_emu::gpu::TextureColorMode::FifteenBit => 1,
+                };
+
+                // Do some wacky division stuff so the adjust the highlight size for clut
+                let tex_max_x = ((points.iter().max_by_key(|v| v.tex_x).unwrap().tex_x - tex_min_x)
+                    / clut_div)
+                    + tex_min_x;
+                let tex_max_y = points.iter().max_by_key(|v| v.tex_y).unwrap().tex_y;
+
+                log::trace!(
+                    "Highlighting texture ({}, {}) -> ({}, {}) from base ({}, {})",
+                    tex_min_x, tex_min_y, tex_max_x, tex_max_y, tex_base_x, tex_base_y
+                );
+
+                for y in tex_min_y..tex_max_y {
+                    for x in tex_min_x..tex_max_x {
+                        let addr = (((y + tex_base_y) as i32) * 1024 + (x + tex_base_x) as i32) * 3;
+                        let current_pixel = pixel_data[addr as usize];
+                        let highlight_color = Color32::from_rgba_unmultiplied(0, 155, 0, 155);
+
+                        pixel_data[addr as usize] += highlight_color.r();
+                        pixel_data[(addr + 1) as usize] += highlight_color.g();
+                        pixel_data[(addr + 2) as usize] += highlight_color.b();
+                    }
                 }
             }
         }
@@ -672,125 +1912,3 @@ impl AverageList {
         sum as f64 / 32.0
     }
 }
-
-
-
-const DEFAULT_FRAGMENT_SHADER: &str = r#"
-#version 330
-
-out vec4 FragColor;
-
-in vec2 TexCoord;
-
-uniform sampler2D displayTex;
-
-void main()
-{
-    FragColor = texture(displayTex, TexCoord);
-}
-"#;
-
-const DEFAULT_VERTEX_SHADER: &str = r#"
-#version 330
-
-const vec3 verts[3] = vec3[3](
-    vec3(-1.0, -1.0, 0.0),
-    vec3(3.0, -1.0, 0.0),
-    vec3(-1.0, 3.0, 0.0)
-);
-
-out vec2 TexCoord;
-
-void main()
-{
-    gl_Position = vec4(verts[gl_VertexID], 1.0);
-    TexCoord = vec2((0.5 - 0.00833) * gl_Position.x + 0.5, (0.5 - 0.00625) * -gl_Position.y + 0.5);
-}
-"#;
-
-
-
-
-struct DisplayShaderManager {
-    program: glow::Program,
-    vertex_array: glow::VertexArray,
-}
-
-impl DisplayShaderManager {
-    fn new(gl: &glow::Context) -> Self {
-        use glow::HasContext as _;
-
-        unsafe {
-            let program = gl.create_program().expect("Cannot create program");
-
-            let (vertex_shader_source, fragment_shader_source) = (
-                DEFAULT_VERTEX_SHADER, DEFAULT_FRAGMENT_SHADER
-            );
-
-            let shader_sources = [
-                (glow::VERTEX_SHADER, vertex_shader_source),
-                (glow::FRAGMENT_SHADER, fragment_shader_source),
-            ];
-
-            let shaders: Vec<_> = shader_sources
-                .iter()
-                .map(|(shader_type, shader_source)| {
-                    let shader = gl
-                        .create_shader(*shader_type)
-                        .expect("Cannot create shader");
-                    gl.shader_source(shader, &shader_source);
-                    gl.compile_shader(shader);
-                    assert!(
-                        gl.get_shader_compile_status(shader),
-                        "Failed to compile {shader_type}: {}",
-                        gl.get_shader_info_log(shader)
-                    );
-                    gl.attach_shader(program, shader);
-                    shader
-                })
-                .collect();
-
-            gl.link_program(program);
-            if !gl.get_program_link_status(program) {
-                panic!("{}", gl.get_program_info_log(program));
-            }
-
-            for shader in shaders {
-                gl.detach_shader(program, shader);
-                gl.delete_shader(shader);
-            }
-
-            let vertex_array = gl
-                .create_vertex_array()
-                .expect("Cannot create vertex array");
-
-            Self {
-                program,
-                vertex_array,
-            }
-        }
-    }
-
-    fn destroy(&self, gl: &glow::Context) {
-        use glow::HasContext as _;
-        unsafe {
-            gl.delete_program(self.program);
-            gl.delete_vertex_array(self.vertex_array);
-        }
-    }
-
-    fn paint(&self, gl: &glow::Context, image_data: &[u8], display_width: i32, display_height: i32) {
-        use glow::HasContext as _;
-        unsafe {
-            gl.use_program(Some(self.program));
-            let disp_tex = gl.create_texture().unwrap();
-            gl.active_texture(glow::TEXTURE0);
-            gl.bind_texture(glow::TEXTURE_2D, Some(disp_tex));
-            gl.tex_image_2d(glow::TEXTURE_2D, 0.into(), glow::RGBA as i32, display_width, display_height, 0, glow::RGBA, glow::UNSIGNED_BYTE, Some(image_data));
-            gl.generate_mipmap(glow::TEXTURE_2D);
-            gl.bind_vertex_array(Some(self.vertex_array));
-            gl.draw_arrays(glow::TRIANGLES, 0, 3);
-            gl.delete_texture(disp_tex);
-        }
-    }
-}
\ No newline at end of file
@@ -0,0 +1,500 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use eframe::glow::{self, HasContext};
+use psx_emu::gpu::{
+    DrawState, Point, PolygonFill, RectFill, RendererBackend, SoftwareRenderer, TextureColorMode,
+    TextureDraw, TextureSource, Vram,
+};
+
+const VRAM_WIDTH: i32 = 1024;
+const VRAM_HEIGHT: i32 = 512;
+/// Position, color, texture coordinate, texture page and CLUT, texture mode and raw flag, clip
+/// rect, texture window mask and offset
+const VERTEX_FLOATS: usize = 21;
+const VERTEX_ATTRIBUTES: [(u32, i32); 7] = [(0, 2), (1, 3), (2, 2), (3, 4), (4, 2), (5, 4), (6, 4)];
+
+const MODE_UNTEXTURED: f32 = -1.0;
+/// Copies VRAM at the texture coordinate as is. Puts CPU transfers and blits, which are only done
+/// in software, back on top of anything drawn before them
+const MODE_DIRECT: f32 = 3.0;
+
+const VERTEX_SHADER: &str = r#"
+#version 330
+
+layout(location = 0) in vec2 position;
+layout(location = 1) in vec3 color;
+layout(location = 2) in vec2 texCoord;
+layout(location = 3) in vec4 texInfo;
+layout(location = 4) in vec2 flags;
+layout(location = 5) in vec4 clip;
+layout(location = 6) in vec4 texWindow;
+
+out vec3 Color;
+out vec2 TexCoord;
+flat out ivec4 TexInfo;
+flat out ivec2 Flags;
+flat out vec4 Clip;
+flat out ivec4 TexWindow;
+
+void main()
+{
+    // VRAM line 0 is the first row of the target, like it is in the VRAM texture
+    gl_Position = vec4(position.x / 512.0 - 1.0, position.y / 256.0 - 1.0, 0.0, 1.0);
+    Color = color;
+    TexCoord = texCoord;
+    TexInfo = ivec4(texInfo);
+    Flags = ivec2(flags);
+    Clip = clip;
+    TexWindow = ivec4(texWindow);
+}
+"#;
+
+const FRAGMENT_SHADER: &str = r#"
+#version 330
+
+in vec3 Color;
+in vec2 TexCoord;
+flat in ivec4 TexInfo;
+flat in ivec2 Flags;
+flat in vec4 Clip;
+flat in ivec4 TexWindow;
+
+out vec4 FragColor;
+
+uniform usampler2D vram;
+uniform float scale;
+
+uint vramAt(ivec2 pos)
+{
+    return texelFetch(vram, ivec2(pos.x & 1023, pos.y & 511), 0).r;
+}
+
+vec3 toColor(uint pixel)
+{
+    return vec3(float(pixel & 31u), float((pixel >> 5) & 31u), float((pixel >> 10) & 31u)) / 31.0;
+}
+
+void main()
+{
+    // Same rule as the software renderer, pixels on the edge of the drawing area aren't drawn
+    vec2 pos = floor(gl_FragCoord.xy / scale);
+    if (pos.x <= Clip.x || pos.x >= Clip.z || pos.y <= Clip.y || pos.y >= Clip.w) {
+        discard;
+    }
+
+    int mode = Flags.x;
+    if (mode < 0) {
+        FragColor = vec4(Color, 1.0);
+        return;
+    }
+    if (mode == 3) {
+        FragColor = vec4(toColor(vramAt(ivec2(TexCoord))), 1.0);
+        return;
+    }
+
+    ivec2 uv = ivec2(TexCoord) & 255;
+    uv = (uv & ~TexWindow.xy) | (TexWindow.zw & TexWindow.xy);
+    ivec2 page = TexInfo.xy * ivec2(64, 256);
+    ivec2 clut = ivec2(TexInfo.z * 16, TexInfo.w);
+
+    uint texel;
+    if (mode == 0) {
+        uint index = (vramAt(page + ivec2(uv.x / 4, uv.y)) >> uint((uv.x % 4) * 4)) & 15u;
+        texel = vramAt(clut + ivec2(int(index), 0));
+    } else if (mode == 1) {
+        uint index = (vramAt(page + ivec2(uv.x / 2, uv.y)) >> uint((uv.x % 2) * 8)) & 255u;
+        texel = vramAt(clut + ivec2(int(index), 0));
+    } else {
+        texel = vramAt(page + uv);
+    }
+
+    if (texel == 0u) {
+        discard;
+    }
+
+    vec3 color = toColor(texel);
+    if (Flags.y == 0) {
+        color = min(color * Color * 2.0, vec3(1.0));
+    }
+    FragColor = vec4(color, 1.0);
+}
+"#;
+
+/// Everything the OpenGL renderer needs to draw a frame
+pub(crate) struct HwFrame {
+    pub(crate) vertices: Vec<f32>,
+    /// VRAM at the end of the frame, as drawn by the software renderer
+    pub(crate) vram: Vec<u16>,
+    pub(crate) display_origin: (usize, usize),
+    /// 24 bit frames are shown from the software renderer instead
+    pub(crate) full_color: bool,
+}
+
+/// Per primitive vertex attributes
+struct VertexInfo {
+    texture: [f32; 4],
+    mode: f32,
+    raw: f32,
+    clip: [f32; 4],
+    window: [f32; 4],
+}
+
+impl VertexInfo {
+    fn new(state: &DrawState, texture: Option<TextureSource>, raw: bool) -> Self {
+        let (texture, mode) = match texture {
+            Some(TextureSource { page_x, page_y, clut_x, clut_y }) => (
+                [page_x as f32, page_y as f32, clut_x as f32, clut_y as f32],
+                match state.texmode {
+                    TextureColorMode::FourBit => 0.0,
+                    TextureColorMode::EightBit => 1.0,
+                    TextureColorMode::FifteenBit => 2.0,
+                },
+            ),
+            None => ([0.0; 4], MODE_UNTEXTURED),
+        };
+
+        Self {
+            texture,
+            mode,
+            raw: if raw { 1.0 } else { 0.0 },
+            clip: [
+                state.area_tl.x as f32,
+                state.area_tl.y as f32,
+                state.area_br.x as f32,
+                state.area_br.y as f32,
+            ],
+            window: [
+                state.tex_mask_x as f32,
+                state.tex_mask_y as f32,
+                state.tex_offset_x as f32,
+                state.tex_offset_y as f32,
+            ],
+        }
+    }
+
+    fn direct() -> Self {
+        Self {
+            texture: [0.0; 4],
+            mode: MODE_DIRECT,
+            raw: 1.0,
+            clip: [-1.0, -1.0, VRAM_WIDTH as f32, VRAM_HEIGHT as f32],
+            window: [0.0; 4],
+        }
+    }
+}
+
+fn push_vertex(vertices: &mut Vec<f32>, x: f32, y: f32, color: u16, tex: (f32, f32), info: &VertexInfo) {
+    let channel = |shift: u16| ((color >> shift) & 0x1F) as f32 / 31.0;
+    vertices.extend([x, y, channel(0), channel(5), channel(10), tex.0, tex.1]);
+    vertices.extend(info.texture);
+    vertices.extend([info.mode, info.raw]);
+    vertices.extend(info.clip);
+    vertices.extend(info.window);
+}
+
+/// Two triangles covering the rect. Texture coordinates run from `tex` across the same size
+fn push_rect(
+    vertices: &mut Vec<f32>,
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    color: u16,
+    tex: (f32, f32),
+    info: &VertexInfo,
+) {
+    let corners = [(0.0, 0.0), (width, 0.0), (0.0, height), (width, 0.0), (0.0, height), (width, height)];
+    for (dx, dy) in corners {
+        push_vertex(vertices, x + dx, y + dy, color, (tex.0 + dx, tex.1 + dy), info);
+    }
+}
+
+/// Renderer backend used by the emu thread when the OpenGL renderer is on. Everything is still
+/// drawn in software, which keeps VRAM right for readback, the mask bit and 24 bit display mode.
+/// Polygons, rectangles, lines and fills are also recorded in order, so the gui thread can redraw
+/// them at a higher resolution. Semi-transparency isn't drawn by the OpenGL renderer yet
+pub(crate) struct HwRecorder {
+    software: SoftwareRenderer,
+    vertices: Vec<f32>,
+    /// Vertices of the last finished frame, taken by the emu thread
+    finished: Arc<Mutex<Vec<f32>>>,
+}
+
+impl HwRecorder {
+    pub(crate) fn new(finished: Arc<Mutex<Vec<f32>>>) -> Self {
+        Self {
+            software: SoftwareRenderer,
+            vertices: vec![],
+            finished,
+        }
+    }
+}
+
+impl RendererBackend for HwRecorder {
+    fn draw_triangle(
+        &mut self,
+        vram: &mut Vram,
+        state: &DrawState,
+        points: &[Point],
+        fill: PolygonFill,
+        transparent: bool,
+    ) {
+        self.software.draw_triangle(vram, state, points, fill, transparent);
+
+        let (info, solid_color) = match fill {
+            PolygonFill::Solid(color) => (VertexInfo::new(state, None, false), Some(color)),
+            PolygonFill::Shaded => (VertexInfo::new(state, None, false), None),
+            // The software renderer only blends shaded textures with the vertex colors
+            PolygonFill::Textured(texture, draw_type) => {
+                (VertexInfo::new(state, Some(texture), draw_type == TextureDraw::Flat), None)
+            }
+        };
+        for point in points {
+            push_vertex(
+                &mut self.vertices,
+                point.x as f32,
+                point.y as f32,
+                solid_color.unwrap_or(point.color),
+                (point.tex_x as f32, point.tex_y as f32),
+                &info,
+            );
+        }
+    }
+
+    fn draw_rect(
+        &mut self,
+        vram: &mut Vram,
+        state: &DrawState,
+        tl: &Point,
+        width: i32,
+        height: i32,
+        fill: RectFill,
+        transparent: bool,
+    ) {
+        self.software.draw_rect(vram, state, tl, width, height, fill, transparent);
+
+        let (info, color) = match fill {
+            RectFill::Solid(color) => (VertexInfo::new(state, None, false), color),
+            RectFill::Textured(texture) => (VertexInfo::new(state, Some(texture), true), 0),
+        };
+        push_rect(
+            &mut self.vertices,
+            tl.x as f32,
+            tl.y as f32,
+            width as f32,
+            height as f32,
+            color,
+            (tl.tex_x as f32, tl.tex_y as f32),
+            &info,
+        );
+    }
+
+    fn draw_line(&mut self, vram: &mut Vram, state: &DrawState, start: &Point, end: &Point, transparent: bool) {
+        self.software.draw_line(vram, state, start, end, transparent);
+
+        // A one pixel wide quad along the line, reaching past the end so the last pixel is covered
+        let (dx, dy) = ((end.x - start.x) as f32, (end.y - start.y) as f32);
+        let length = dx.abs().max(dy.abs()).max(1.0);
+        let (step_x, step_y) = (dx / length, dy / length);
+        let (side_x, side_y) = if dx.abs() >= dy.abs() { (0.0, 1.0) } else { (1.0, 0.0) };
+        let (x0, y0) = (start.x as f32, start.y as f32);
+        let (x1, y1) = (end.x as f32 + step_x, end.y as f32 + step_y);
+
+        let info = VertexInfo::new(state, None, false);
+        let corners = [
+            (x0, y0, start.color),
+            (x1, y1, end.color),
+            (x0 + side_x, y0 + side_y, start.color),
+            (x1, y1, end.color),
+            (x0 + side_x, y0 + side_y, start.color),
+            (x1 + side_x, y1 + side_y, end.color),
+        ];
+        for (x, y, color) in corners {
+            push_vertex(&mut self.vertices, x, y, color, (0.0, 0.0), &info);
+        }
+    }
+
+    fn fill(&mut self, vram: &mut Vram, state: &DrawState, tl: &Point, br: &Point, color: u16) {
+        self.software.fill(vram, state, tl, br, color);
+
+        let info = VertexInfo::new(state, None, false);
+        push_rect(
+            &mut self.vertices,
+            tl.x as f32,
+            tl.y as f32,
+            (br.x - tl.x) as f32,
+            (br.y - tl.y) as f32,
+            color,
+            (0.0, 0.0),
+            &info,
+        );
+    }
+
+    fn blit(
+        &mut self,
+        vram: &mut Vram,
+        state: &DrawState,
+        source: (u32, u32),
+        dest: (u32, u32),
+        width: u32,
+        height: u32,
+    ) {
+        self.software.blit(vram, state, source, dest, width, height);
+        let (x, y) = (dest.0 as f32, dest.1 as f32);
+        push_rect(&mut self.vertices, x, y, width as f32, height as f32, 0, (x, y), &VertexInfo::direct());
+    }
+
+    fn upload(&mut self, vram: &mut Vram, state: &DrawState, x: u32, y: u32, width: u32, pixels: &[u16]) {
+        self.software.upload(vram, state, x, y, width, pixels);
+        let height = (pixels.len() as u32 + width - 1) / width;
+        let (x, y) = (x as f32, y as f32);
+        push_rect(&mut self.vertices, x, y, width as f32, height as f32, 0, (x, y), &VertexInfo::direct());
+    }
+
+    fn end_frame(&mut self, _vram: &Vram) {
+        *self.finished.lock().unwrap() = mem::take(&mut self.vertices);
+    }
+}
+
+/// Draws recorded frames into an offscreen target covering all of VRAM, `scale` times larger
+pub(crate) struct HwRenderer {
+    program: glow::Program,
+    vertex_array: glow::VertexArray,
+    vertex_buffer: glow::Buffer,
+    vram_texture: glow::Texture,
+    /// Framebuffer and its color texture, with the scale they were made for
+    target: Option<(glow::Framebuffer, glow::Texture, u32)>,
+    /// Frame currently in the target
+    last_frame: Option<Arc<HwFrame>>,
+}
+
+impl HwRenderer {
+    pub(crate) fn new(gl: &glow::Context) -> Self {
+        unsafe {
+            let program = compile_program(gl).expect("Failed to compile the hardware renderer shaders");
+
+            let vertex_array = gl.create_vertex_array().expect("Cannot create vertex array");
+            let vertex_buffer = gl.create_buffer().expect("Cannot create vertex buffer");
+            gl.bind_vertex_array(Some(vertex_array));
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(vertex_buffer));
+            let stride = (VERTEX_FLOATS * mem::size_of::<f32>()) as i32;
+            let mut offset = 0;
+            for (location, size) in VERTEX_ATTRIBUTES {
+                gl.enable_vertex_attrib_array(location);
+                gl.vertex_attrib_pointer_f32(location, size, glow::FLOAT, false, stride, offset);
+                offset += size * mem::size_of::<f32>() as i32;
+            }
+            gl.bind_vertex_array(None);
+
+            let vram_texture = gl.create_texture().expect("Cannot create VRAM texture");
+            gl.bind_texture(glow::TEXTURE_2D, Some(vram_texture));
+            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::NEAREST as i32);
+            gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::NEAREST as i32);
+            gl.tex_image_2d(glow::TEXTURE_2D, 0, glow::R16UI as i32, VRAM_WIDTH, VRAM_HEIGHT, 0, glow::RED_INTEGER, glow::UNSIGNED_SHORT, None);
+
+            Self {
+                program,
+                vertex_array,
+                vertex_buffer,
+                vram_texture,
+                target: None,
+                last_frame: None,
+            }
+        }
+    }
+
+    /// Makes sure the target holds `frame` at `scale` and returns its texture. Leaves the
+    /// framebuffer unbound and the viewport, scissor test and blending for the caller to restore
+    pub(crate) fn render(&mut self, gl: &glow::Context, frame: &Arc<HwFrame>, scale: u32) -> glow::Texture {
+        let (framebuffer, texture) = self.target(gl, scale);
+        let up_to_date = self.last_frame.as_ref().map_or(false, |last| Arc::ptr_eq(last, frame));
+        if up_to_date {
+            return texture;
+        }
+        self.last_frame = Some(frame.clone());
+
+        // Start from the software picture, then redraw every recorded primitive over it
+        let mut vertices = Vec::with_capacity(frame.vertices.len() + 6 * VERTEX_FLOATS);
+        push_rect(&mut vertices, 0.0, 0.0, VRAM_WIDTH as f32, VRAM_HEIGHT as f32, 0, (0.0, 0.0), &VertexInfo::direct());
+        vertices.extend_from_slice(&frame.vertices);
+        let bytes: Vec<u8> = vertices.iter().flat_map(|value| value.to_ne_bytes()).collect();
+        let vram: Vec<u8> = frame.vram.iter().flat_map(|pixel| pixel.to_ne_bytes()).collect();
+
+        unsafe {
+            gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+            gl.viewport(0, 0, VRAM_WIDTH * scale as i32, VRAM_HEIGHT * scale as i32);
+            gl.disable(glow::SCISSOR_TEST);
+            gl.disable(glow::BLEND);
+
+            gl.active_texture(glow::TEXTURE0);
+            gl.bind_texture(glow::TEXTURE_2D, Some(self.vram_texture));
+            gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 2);
+            gl.tex_sub_image_2d(glow::TEXTURE_2D, 0, 0, 0, VRAM_WIDTH, VRAM_HEIGHT, glow::RED_INTEGER, glow::UNSIGNED_SHORT, glow::PixelUnpackData::Slice(&vram));
+            gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 4);
+
+            gl.use_program(Some(self.program));
+            gl.uniform_1_i32(gl.get_uniform_location(self.program, "vram").as_ref(), 0);
+            gl.uniform_1_f32(gl.get_uniform_location(self.program, "scale").as_ref(), scale as f32);
+
+            gl.bind_vertex_array(Some(self.vertex_array));
+            gl.bind_buffer(glow::ARRAY_BUFFER, Some(self.vertex_buffer));
+            gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, &bytes, glow::STREAM_DRAW);
+            gl.draw_arrays(glow::TRIANGLES, 0, (vertices.len() / VERTEX_FLOATS) as i32);
+            gl.bind_vertex_array(None);
+
+            gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+        }
+
+        texture
+    }
+
+    /// Returns the target for `scale`, recreating it if the scale changed
+    fn target(&mut self, gl: &glow::Context, scale: u32) -> (glow::Framebuffer, glow::Texture) {
+        match self.target {
+            Some((framebuffer, texture, target_scale)) if target_scale == scale => (framebuffer, texture),
+            _ => unsafe {
+                if let Some((framebuffer, texture, _)) = self.target.take() {
+                    gl.delete_framebuffer(framebuffer);
+                    gl.delete_texture(texture);
+                }
+                self.last_frame = None;
+
+                let texture = gl.create_texture().unwrap();
+                gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+                gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as i32);
+                gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as i32);
+                gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE as i32);
+                gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE as i32);
+                gl.tex_image_2d(glow::TEXTURE_2D, 0, glow::RGBA as i32, VRAM_WIDTH * scale as i32, VRAM_HEIGHT * scale as i32, 0, glow::RGBA, glow::UNSIGNED_BYTE, None);
+
+                let framebuffer = gl.create_framebuffer().unwrap();
+                gl.bind_framebuffer(glow::FRAMEBUFFER, Some(framebuffer));
+                gl.framebuffer_texture_2d(glow::FRAMEBUFFER, glow::COLOR_ATTACHMENT0, glow::TEXTURE_2D, Some(texture), 0);
+                gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+
+                self.target = Some((framebuffer, texture, scale));
+                (framebuffer, texture)
+            },
+        }
+    }
+}
+
+unsafe fn compile_program(gl: &glow::Context) -> Result<glow::Program, String> {
+    let program = gl.create_program()?;
+    for (shader_type, source) in [(glow::VERTEX_SHADER, VERTEX_SHADER), (glow::FRAGMENT_SHADER, FRAGMENT_SHADER)] {
+        let shader = gl.create_shader(shader_type)?;
+        gl.shader_source(shader, source);
+        gl.compile_shader(shader);
+        if !gl.get_shader_compile_status(shader) {
+            return Err(gl.get_shader_info_log(shader));
+        }
+        gl.attach_shader(program, shader);
+        gl.delete_shader(shader);
+    }
+    gl.link_program(program);
+    if !gl.get_program_link_status(program) {
+        return Err(gl.get_program_info_log(program));
+    }
+    Ok(program)
+}
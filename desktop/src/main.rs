@@ -1,59 +1,201 @@
 use byteorder::{ByteOrder, LittleEndian};
+use compat::CompatDb;
 use disc::*;
-use eframe::egui::Context;
+use config::{BackgroundBehavior, Config, MemoryCardConfig};
+use gamepad::{GamepadInfo, GamepadInput};
+use gilrs::{GamepadId, Gilrs};
+use memcard::CardFile;
+use pacer::FramePacer;
+use semihost::Semihost;
+use vram_viewer::VramArea;
+use watch::{read_watches, WatchEntry};
 use gdbstub::{DisconnectReason, GdbStub, GdbStubError};
+use hw_renderer::{HwFrame, HwRecorder};
 use getopts::Matches;
 use getopts::Options;
-use psx_emu::controller::ButtonState;
+use psx_emu::cdrom::SectorBufferInfo;
+use psx_emu::controller::{ButtonState, PadMode};
 use psx_emu::gpu::DrawCall;
-use psx_emu::gpu::Resolution;
-use psx_emu::toggle_memory_logging;
-use psx_emu::PSXEmu;
+use psx_emu::gpu::{Resolution, SoftwareRenderer, VideoMode};
+use psx_emu::memcard::SaveInfo;
+use psx_emu::{BiosInfo, FrameTiming, PSXEmu, RunStatus};
+use log::LevelFilter;
 use simple_logger::SimpleLogger;
 use std::env;
 use std::fs;
 use std::net::{TcpListener, TcpStream};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::mpsc::TryRecvError;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
-use std::time::SystemTime;
+use std::time::Duration;
 
+mod compat;
+mod config;
 mod disc;
+mod frame_advance;
+mod gamepad;
 mod gdb;
 mod gui;
+mod hw_renderer;
+mod memcard;
+mod osd;
+mod pacer;
+mod semihost;
+mod shader;
+mod speed;
+mod vram_viewer;
+mod watch;
 
 const DEFAULT_GDB_PORT: u16 = 4444;
 const DEFAULT_BIOS_PATH: &str = "SCPH1001.BIN";
 const START_HALTED: bool = false;
 const START_FRAME_LIMITED: bool = true;
+/// How long the emu thread sleeps between message checks while halted
+const HALTED_POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// Frame rate used while throttled in the background
+const BACKGROUND_REFRESH_RATE: f64 = 10.0;
 
 #[allow(dead_code)]
 struct ClientState {
     comm: ClientComms,
-    emu_thread: JoinHandle<()>,
+    emu_thread: Option<JoinHandle<()>>,
     halted: bool,
     frame_limited: bool,
 }
 
+/// Whether the gui window has focus or can be seen at all
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BackgroundState {
+    Focused,
+    Unfocused,
+    Minimized,
+}
+
+/// How much of each frame's GPU call log the emu thread sends to the gui
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GpuCapture {
+    /// Nothing is logged
+    Off,
+    /// Only how many calls of each kind were made
+    Counts,
+    /// The whole log, every frame
+    Full,
+}
+
+/// Summary of one frame's GPU calls
+#[derive(Clone, Debug, Default, PartialEq)]
+struct DrawCallCounts {
+    total: usize,
+    /// Calls the size limit threw away
+    dropped: usize,
+    /// Calls of each kind, in the order they first appeared
+    by_operation: Vec<(String, usize)>,
+}
+
+fn count_calls(log: &[DrawCall]) -> DrawCallCounts {
+    let mut counts = DrawCallCounts {
+        total: log.len(),
+        ..Default::default()
+    };
+    for call in log {
+        counts.dropped += call.call_dropped as usize;
+        let name = call.operation.to_string();
+        match counts.by_operation.iter_mut().find(|(operation, _)| *operation == name) {
+            Some((_, count)) => *count += 1,
+            None => counts.by_operation.push((name, 1)),
+        }
+    }
+    counts
+}
+
+/// What the emu thread should actually do given the window state. A gdb session always runs,
+/// since the debugger usually has focus
+fn background_action(behavior: BackgroundBehavior, window: BackgroundState, debugging: bool) -> BackgroundBehavior {
+    match (behavior, window) {
+        _ if debugging => BackgroundBehavior::KeepRunning,
+        (_, BackgroundState::Focused) => BackgroundBehavior::KeepRunning,
+        (BackgroundBehavior::Throttle, BackgroundState::Minimized) => BackgroundBehavior::Pause,
+        (behavior, _) => behavior,
+    }
+}
+
 struct EmuState {
     emu: PSXEmu,
     comm: EmuComms,
     halted: bool,
     current_resolution: Resolution,
     debugging: bool,
-    last_frame_time: SystemTime,
+    pacer: FramePacer,
     waiting_for_client: bool,
-    gui_ctx: Option<Context>,
+    /// Called after every FrameReady is sent, so the gui knows to repaint
+    on_frame: Option<Box<dyn Fn() + Send>>,
     frame_limited: bool,
-    current_origin: (usize, usize),
+    /// The limiter has already warned that it can't keep up
+    falling_behind: bool,
+    current_timing: (u32, VideoMode),
     latest_draw_log: Vec<DrawCall>,
+    gpu_capture: GpuCapture,
+    /// Log the next frame and send all of it, whatever gpu_capture is
+    capture_next_frame: bool,
+    /// Run one frame even though halted
+    advance_frame: bool,
+    last_post_code: u8,
+    first_frame_rendered: bool,
+    game_serial: Option<String>,
+    /// Looked up whenever game_serial changes
+    compat: CompatDb,
+    card_config: MemoryCardConfig,
+    /// File backing the card in slot 1. None if the card couldn't be loaded
+    card_file: Option<CardFile>,
+    /// Vertices of the last frame, when the OpenGL renderer is on
+    hw_vertices: Option<Arc<Mutex<Vec<f32>>>>,
+    /// Answers file I/O from the program. None unless --semihost-dir was given
+    semihost: Option<Semihost>,
+    pad_mode: PadMode,
+    /// None until the gui enables gamepads, or if gilrs couldn't start
+    gamepad: Option<GamepadInput>,
+    /// Latest keyboard state from the gui, used when no gamepad is active
+    keyboard_buttons: ButtonState,
+    /// Addresses read after every frame and sent along with it
+    watches: Vec<WatchEntry>,
+    background_state: BackgroundState,
+    background_behavior: BackgroundBehavior,
+    /// Halt before the first instruction after every boot, reset and load
+    halt_on_boot: bool,
 }
 
 impl EmuState {
+    /// Sends the gui everything it shows while halted
+    fn report_halt(&mut self) {
+        self.send_message(ClientMessage::Halted);
+        self.send_message(ClientMessage::LatestPC(self.emu.current_instruction_pc()));
+        self.send_message(ClientMessage::LatestGPULog(self.latest_draw_log.clone()));
+        self.send_message(ClientMessage::LatestIrqMask(self.emu.get_irq_mask()));
+        self.send_message(ClientMessage::LatestCdMask(self.emu.main_bus.cd_drive.get_enable()));
+        self.send_message(ClientMessage::LatestCdFlag(self.emu.main_bus.cd_drive.get_flag()));
+        self.send_message(ClientMessage::LatestCdSectorBuffer(
+            self.emu.main_bus.cd_drive.sector_buffer_info(),
+        ));
+        self.send_message(ClientMessage::LatestCdMode(
+            self.emu.main_bus.cd_drive.drive_mode(),
+            self.emu.main_bus.cd_drive.speed_change_in_progress(),
+        ));
+    }
+
+    /// Called once the machine is back at the reset vector
+    fn booted(&mut self) {
+        self.pacer.reset();
+        if self.halt_on_boot {
+            self.halted = true;
+            self.report_halt();
+        }
+    }
+
     fn send_message(&mut self, msg: ClientMessage) {
         self
             .comm
@@ -61,6 +203,90 @@ impl EmuState {
             .send(msg)
             .unwrap();
     }
+
+    /// Samples the controllers. Called right before each frame, since games poll the pad in the
+    /// vblank interrupt that starts the frame
+    fn poll_controllers(&mut self) {
+        let mut gamepads_changed = None;
+        let gamepad_buttons = self.gamepad.as_mut().and_then(|gamepad| {
+            if gamepad.update() {
+                gamepads_changed = Some(gamepad.gamepads());
+            }
+            gamepad.sample()
+        });
+        if let Some(gamepads) = gamepads_changed {
+            self.send_message(ClientMessage::GamepadsChanged(gamepads));
+        }
+        self.emu
+            .update_controller_state(gamepad_buttons.unwrap_or(self.keyboard_buttons));
+    }
+
+    /// Saves the memory card and ejects the disc, then lets the client know the thread is done
+    fn shut_down(&mut self) {
+        if let Some(card_file) = &mut self.card_file {
+            card_file.flush(&self.emu);
+        }
+        self.emu.remove_disc();
+        let status = self.emu.exit_code().map_or(0, |code| code as i32);
+        // The client may already be gone
+        let _ = self.comm.tx.send(ClientMessage::Exited(status));
+    }
+
+    /// Tells the client about the new game, warning it if the compat table knows of problems
+    fn change_game(&mut self, serial: Option<String>) {
+        self.send_message(ClientMessage::GameChanged(serial.clone()));
+        if let Some(warning) = serial.as_deref().and_then(|serial| self.compat.warning(serial)) {
+            self.send_message(ClientMessage::CompatWarning(warning));
+        }
+        self.game_serial = serial;
+    }
+
+    /// Saves the current card, then swaps in the one for the current game
+    fn change_memory_card(&mut self) {
+        if let Some(card_file) = &mut self.card_file {
+            card_file.flush(&self.emu);
+        }
+        self.emu.remove_memory_card();
+
+        let path = memcard::card_path(&self.card_config, self.game_serial.as_deref());
+        self.card_file = match memcard::insert_card(&mut self.emu, &path) {
+            Ok(card_file) => Some(card_file),
+            Err(e) => {
+                println!("{}. Running without a memory card", e);
+                self.send_message(ClientMessage::MemoryCardError(e));
+                None
+            }
+        };
+        self.send_memory_card_contents();
+    }
+
+    fn send_memory_card_contents(&mut self) {
+        let contents = match (&self.card_file, self.emu.memory_card()) {
+            (Some(card_file), Some(card)) => Some(MemoryCardContents {
+                path: card_file.path.clone(),
+                saves: card.saves(),
+                free_blocks: card.free_blocks(),
+            }),
+            _ => None,
+        };
+        self.send_message(ClientMessage::MemoryCardContents(contents));
+    }
+
+    /// Saves edits made through the card manager straight away, and updates its view of the card
+    fn memory_card_edited(&mut self) {
+        if let Some(card_file) = &mut self.card_file {
+            card_file.mark_dirty();
+            card_file.flush(&self.emu);
+        }
+        self.send_memory_card_contents();
+    }
+}
+
+/// Summary of the inserted card for the card manager
+pub(crate) struct MemoryCardContents {
+    pub path: PathBuf,
+    pub saves: Vec<SaveInfo>,
+    pub free_blocks: usize,
 }
 
 fn main() {
@@ -75,6 +301,16 @@ fn main() {
     opts.optflag("l", "log", "Enable logging");
     opts.optflag("h", "headless", "Run without GUI");
     opts.optflag("g", "gdb", "Start GDB server on port 4444");
+    opts.optflag("", "fast-boot", "Patch the BIOS to skip the boot logo");
+    opts.optflag("", "tty", "Patch the BIOS to force enable TTY output");
+    opts.optflag("", "halt-on-boot", "Halt before the first BIOS instruction runs");
+    opts.optopt(
+        "",
+        "semihost-dir",
+        "Let programs open files in DIR through semihosting",
+        "DIR",
+    );
+    opts.optopt("", "compat-db", "Read game compatibility notes from FILE instead of compat.toml", "FILE");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -103,7 +339,7 @@ fn main() {
     let emu_thread = start_emu_thread(matches, emu_comm);
 
     let state = ClientState {
-        emu_thread,
+        emu_thread: Some(emu_thread),
         comm: client_comm,
         halted: START_HALTED,
         frame_limited: START_FRAME_LIMITED,
@@ -116,13 +352,29 @@ fn main() {
     }
 }
 
-fn run_headless(state: ClientState) {
+fn run_headless(mut state: ClientState) {
+    // Ctrl+C shuts down the same way the gui's Quit does, so the memory card gets saved
+    let interrupt_tx = state.comm.tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = interrupt_tx.send(EmuMessage::Kill);
+    })
+    .expect("Unable to install the Ctrl+C handler");
+
     state.comm.tx.send(EmuMessage::Continue).unwrap();
-    loop {
-        match state.comm.rx.try_recv() {
-            _ => (),
-        };
+    let status = loop {
+        match state.comm.rx.recv() {
+            Ok(ClientMessage::Exited(status)) => break status,
+            Ok(ClientMessage::CompatWarning(warning)) => println!("Compatibility warning: {}", warning),
+            Ok(_) => (),
+            // The emu thread died without saying goodbye
+            Err(_) => break 1,
+        }
+    };
+
+    if let Some(emu_thread) = state.emu_thread.take() {
+        let _ = emu_thread.join();
     }
+    std::process::exit(status);
 }
 
 fn wait_for_gdb_connection(port: u16) -> std::io::Result<TcpStream> {
@@ -139,9 +391,26 @@ fn wait_for_gdb_connection(port: u16) -> std::io::Result<TcpStream> {
 }
 
 fn create_emu(matches: Matches, emu_comm: EmuComms) -> EmuState {
+    // Warnings from the core are always shown. Logging turns on everything, instruction traces included
+    let level = if matches.opt_present("l") {
+        LevelFilter::Trace
+    } else {
+        LevelFilter::Warn
+    };
+    SimpleLogger::new()
+        .with_level(level)
+        // Only written while memory logging is turned on
+        .with_module_level("psx::mem", LevelFilter::Trace)
+        .init()
+        .unwrap();
+
+    let config = Config::load();
     let bios_path = if let Some(new_path) = matches.opt_str("b") {
         println!("Using alternate bios file: {}", new_path);
         new_path
+    } else if let Some(config_path) = config.bios_path {
+        println!("Using bios file from settings: {}", config_path.display());
+        config_path.to_string_lossy().to_string()
     } else {
         println!("Using defualt bios file: {}", DEFAULT_BIOS_PATH);
         DEFAULT_BIOS_PATH.to_string()
@@ -154,34 +423,51 @@ fn create_emu(matches: Matches, emu_comm: EmuComms) -> EmuState {
         }
     };
 
-    let mut emu = PSXEmu::new(bios_data);
-    emu.reset();
+    // Test programs are run headless, so only let them log and exit there
+    let emu_result = PSXEmu::builder()
+        .bios(bios_data)
+        .fast_boot(matches.opt_present("fast-boot"))
+        .force_tty(matches.opt_present("tty"))
+        .test_harness(matches.opt_present("h"))
+        .build();
+    let mut emu = match emu_result {
+        Ok(emu) => emu,
+        Err(e) => {
+            eprintln!("Unable to load bios file {}: {}", bios_path, e);
+            std::process::exit(1);
+        }
+    };
 
-    if matches.opt_present("l") {
-        SimpleLogger::new().init().unwrap();
-    }
+    let bios_info = emu.bios_info().clone();
+    println!(
+        "BIOS: {} ({:?}){}",
+        bios_info.version,
+        bios_info.region,
+        if bios_info.known { "" } else { " [unknown image]" }
+    );
 
     //Loads entire disc into memory (Don't worry about it)
     if let Some(disc_path) = matches.opt_str("c") {
         println!("Loading CUE: {}", disc_path);
-        let disc = load_disc_from_cuesheet(Path::new(&disc_path).to_path_buf());
-        emu.load_disc(disc);
+        match load_disc_from_cuesheet(Path::new(&disc_path).to_path_buf()) {
+            Ok(disc) => emu.load_disc(disc),
+            Err(e) => eprintln!("{}. Starting without a disc", e),
+        }
     }
 
     if let Some(exe_path) = matches.opt_str("e") {
         println!("Loading executable: {}", exe_path);
-        let exe = fs::read(exe_path).unwrap();
-        let exe_data = exe[0x800..].to_vec();
-        let destination = LittleEndian::read_u32(&exe[0x18..0x1C]);
-        let entrypoint = LittleEndian::read_u32(&exe[0x10..0x14]);
-        let init_sp = LittleEndian::read_u32(&exe[0x30..0x34]);
-        println!(
-            "Destination is {:#X}\nEntrypoint is {:#X}\nSP is {:#X}",
-            destination, entrypoint, init_sp
-        );
-        emu.load_executable(destination, entrypoint, init_sp, &exe_data);
+        if let Err(e) = load_exe_file(&mut emu, Path::new(&exe_path)) {
+            eprintln!("{}", e);
+        }
     }
 
+    let semihost = matches.opt_str("semihost-dir").map(|dir| {
+        println!("Semihosting files from {}", dir);
+        emu.set_semihosting(true);
+        Semihost::new(PathBuf::from(dir))
+    });
+
     EmuState {
         emu: emu,
         comm: emu_comm,
@@ -191,13 +477,66 @@ fn create_emu(matches: Matches, emu_comm: EmuComms) -> EmuState {
             height: 480,
         },
         debugging: matches.opt_present("g"),
-        last_frame_time: SystemTime::now(),
+        pacer: FramePacer::new(VideoMode::Ntsc.refresh_rate()),
         waiting_for_client: false,
-        gui_ctx: None,
+        on_frame: None,
         frame_limited: START_FRAME_LIMITED,
-        current_origin: (0, 0),
+        falling_behind: false,
+        current_timing: (4, VideoMode::Ntsc),
         latest_draw_log: vec![],
+        gpu_capture: GpuCapture::Counts,
+        capture_next_frame: false,
+        advance_frame: false,
+        last_post_code: 0,
+        first_frame_rendered: false,
+        game_serial: None,
+        compat: CompatDb::load(matches.opt_str("compat-db").as_deref().map(Path::new)),
+        card_config: config.memory_card,
+        card_file: None,
+        hw_vertices: None,
+        semihost,
+        pad_mode: PadMode::Digital,
+        gamepad: None,
+        keyboard_buttons: ButtonState::new_digital_pad(),
+        watches: Vec::new(),
+        background_state: BackgroundState::Focused,
+        background_behavior: config.background,
+        halt_on_boot: matches.opt_present("halt-on-boot") || config.halt_on_boot,
+    }
+}
+
+/// Reads a PS-X EXE and copies it into RAM. It is jumped to once the BIOS finishes booting
+fn load_exe_file(emu: &mut PSXEmu, path: &Path) -> Result<(), String> {
+    let exe = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    if exe.len() < 0x800 || &exe[0..8] != b"PS-X EXE" {
+        return Err(format!("{} is not a PS-X EXE", path.display()));
     }
+
+    let exe_data = exe[0x800..].to_vec();
+    let destination = LittleEndian::read_u32(&exe[0x18..0x1C]);
+    let entrypoint = LittleEndian::read_u32(&exe[0x10..0x14]);
+    let init_sp = LittleEndian::read_u32(&exe[0x30..0x34]);
+    println!(
+        "Destination is {:#X}\nEntrypoint is {:#X}\nSP is {:#X}",
+        destination, entrypoint, init_sp
+    );
+    emu.load_executable(destination, entrypoint, init_sp, &exe_data);
+    Ok(())
+}
+
+/// Copies a .mcs save file onto the inserted card
+fn import_save_file(emu: &mut PSXEmu, path: &Path) -> Result<(), String> {
+    let card = emu.memory_card_mut().ok_or("No memory card is inserted")?;
+    let mcs = fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    card.import_save(&mcs)
+        .map_err(|e| format!("Unable to import {}: {}", path.display(), e))
+}
+
+/// Writes a save from the inserted card out as a .mcs file
+fn export_save_file(emu: &PSXEmu, first_block: usize, path: &Path) -> Result<(), String> {
+    let card = emu.memory_card().ok_or("No memory card is inserted")?;
+    let mcs = card.export_save(first_block).ok_or("That save no longer exists")?;
+    fs::write(path, mcs).map_err(|e| format!("Unable to write {}: {}", path.display(), e))
 }
 
 #[allow(dead_code)]
@@ -208,28 +547,113 @@ enum EmuMessage {
     RemoveBreakpoint(u32),
     Kill,
     StepCPU,
+    /// Runs one frame while halted, then halts again. Advances that arrive before the frame runs
+    /// are merged into it
+    AdvanceFrame,
+    /// Keyboard state, sent by the gui when it changes
     UpdateControllers(ButtonState),
+    /// Starts reading gamepads. gilrs can't leave the thread it was made on, so the emu thread
+    /// creates it when the gui asks
+    EnableGamepads,
+    /// Pad to read from. None uses the keyboard
+    SelectGamepad(Option<GamepadId>),
+    /// Power cycles the console, clearing RAM
     Reset,
+    /// Presses the console's reset button
+    SoftReset,
+    SetHaltOnBoot(bool),
     StartFrame,
-    RecieveGuiContext(Context),
+    /// Installs the callback run after each frame is sent to the gui
+    SetFrameCallback(Box<dyn Fn() + Send>),
     SetFrameLimiter(bool),
+    /// Time the GPU in this mode whatever the game asks for. None follows the game
+    SetVideoModeOverride(Option<VideoMode>),
+    /// Run the cpu this many times faster than stock
+    SetCpuOverclock(f32),
+    /// Sent by the gui whenever the window gains or loses focus, or is minimized
+    SetBackgroundState(BackgroundState),
+    SetBackgroundBehavior(BackgroundBehavior),
+    /// Press the analog button on the pad in the given port
+    PressAnalogButton(usize),
+    /// Switch between the software and OpenGL renderers
+    SetHardwareRenderer(bool),
+    SetThreadedGpu(bool),
+    LoadDisc(PathBuf),
+    LoadExe(PathBuf),
     ClearGpuLog,
+    SetGpuCapture(GpuCapture),
+    /// Log just the next frame and send the whole log
+    CaptureNextFrame,
+    SetGpuDeepCapture(bool),
+    /// Drop oversized polygons like the hardware
+    SetStrictSizeCheck(bool),
     SetMemLogging(bool),
+    /// Switch between the shared card and per-game cards
+    SetSharedMemoryCard(bool),
+    /// Deletes the save starting at the given block
+    DeleteSave(usize),
+    ImportSave(PathBuf),
+    ExportSave(usize, PathBuf),
+    /// Replaces the addresses read after each frame
+    SetWatchList(Vec<WatchEntry>),
+}
+
+struct FrameInfo {
+    /// The core's frame count, so the gui can spot frames it missed or got twice
+    number: u64,
+    /// Milliseconds since the previous frame
+    time: u128,
+    /// The rate the pacer was aiming for
+    target_rate: f64,
+    /// Outlined in the VRAM viewer
+    display_area: VramArea,
+    draw_area: VramArea,
 }
 
 enum ClientMessage {
-    FrameReady(Vec<u16>, u128, bool),
+    /// VRAM, the rendered display area, the frame's timing and the bytes at each watch. Watches
+    /// outside of RAM are None
+    FrameReady(Vec<u16>, Vec<u8>, FrameInfo, Vec<Option<Vec<u8>>>),
+    /// Sent before FrameReady when the OpenGL renderer is on
+    HardwareFrame(HwFrame),
+    /// Where the emulated cycles of the last frame went
+    FrameTiming(FrameTiming),
+    /// Mode of the pad in port 1, sent when it changes
+    PadModeChanged(PadMode),
+    /// Pads gilrs knows about, sent when one is plugged in or unplugged
+    GamepadsChanged(Vec<GamepadInfo>),
     ResolutionChanged(Resolution),
     AwaitingGDBClient,
     GDBClientConnected,
     LatestPC(u32),
     Halted,
     Continuing,
-    DisplayOriginChanged((usize, usize)),
+    /// Dot clock divider and video mode, used for aspect ratio correction
+    DisplayTimingChanged(u32, VideoMode),
     LatestGPULog(Vec<DrawCall>),
+    /// Sent with every frame unless GPU logging is off
+    GpuCallCounts(DrawCallCounts),
     LatestIrqMask(u32),
     LatestCdMask(u8),
     LatestCdFlag(u8),
+    LatestCdSectorBuffer(SectorBufferInfo),
+    /// Mode byte from SetMode, and whether the motor is still changing speed
+    LatestCdMode(u8, bool),
+    PostCode(Option<u8>),
+    BiosDetected(BiosInfo),
+    /// A disc or EXE was loaded and the machine reset
+    LoadSucceeded(PathBuf),
+    LoadFailed(String),
+    /// Serial of the disc that was just inserted, used to look up per-game settings
+    GameChanged(Option<String>),
+    /// Known problems with the game that was just inserted, from the compat table. Sent after GameChanged
+    CompatWarning(String),
+    /// Sent whenever the inserted card changes or is saved. None if no card could be loaded
+    MemoryCardContents(Option<MemoryCardContents>),
+    MemoryCardError(String),
+    /// The emu thread has shut down. Carries the program's exit status if it asked to exit,
+    /// otherwise 0
+    Exited(i32),
 }
 
 struct EmuComms {
@@ -245,6 +669,16 @@ struct ClientComms {
 fn start_emu_thread(matches: Matches, emu_comm: EmuComms) -> JoinHandle<()> {
     thread::spawn(move || {
         let mut state = create_emu(matches, emu_comm);
+        let bios_info = state.emu.bios_info().clone();
+        state.send_message(ClientMessage::BiosDetected(bios_info));
+        let serial = state.emu.loaded_disc().as_ref().and_then(|disc| disc.serial().map(String::from));
+        state.change_game(serial);
+        state.change_memory_card();
+        // GDB always starts halted, and the machine runs once the debugger continues
+        if state.debugging || state.halt_on_boot {
+            state.halted = true;
+            state.report_halt();
+        }
         let mut debugger = if state.debugging {
             state.send_message(ClientMessage::AwaitingGDBClient);
             let gdb_conn = wait_for_gdb_connection(DEFAULT_GDB_PORT).unwrap();
@@ -254,7 +688,7 @@ fn start_emu_thread(matches: Matches, emu_comm: EmuComms) -> JoinHandle<()> {
             None
         };
 
-        if let Some(dbg) = &mut debugger {
+        if let Some(mut dbg) = debugger {
             match dbg.run(&mut state) {
                 Ok(disconnect_reason) => match disconnect_reason {
                     DisconnectReason::Disconnect => println!("Client disconnected!"),
@@ -266,6 +700,8 @@ fn start_emu_thread(matches: Matches, emu_comm: EmuComms) -> JoinHandle<()> {
                 }
                 Err(e) => println!("Something else happened {}", e.to_string()),
             }
+            // Closes the gdb socket
+            drop(dbg);
         } else {
             loop {
                 if let Err(e) = emu_loop_step(&mut state) {
@@ -277,6 +713,8 @@ fn start_emu_thread(matches: Matches, emu_comm: EmuComms) -> JoinHandle<()> {
                 }
             }
         }
+
+        state.shut_down();
     })
 }
 
@@ -287,59 +725,190 @@ enum EmuThreadError {
     GracefulExit,
 }
 
+/// Takes everything the GUI has queued, so a burst of messages is handled before the next frame
+/// instead of one per step. Only the newest controller update matters, so older ones are dropped
+/// and the latest is handled last. A Kill skips everything else.
+fn drain_messages(rx: &Receiver<EmuMessage>) -> Vec<EmuMessage> {
+    let mut messages = Vec::new();
+    let mut latest_controllers = None;
+    loop {
+        match rx.try_recv() {
+            Ok(EmuMessage::Kill) => return vec![EmuMessage::Kill],
+            Ok(EmuMessage::UpdateControllers(button_state)) => latest_controllers = Some(button_state),
+            Ok(msg) => messages.push(msg),
+            Err(TryRecvError::Empty) => break,
+            Err(TryRecvError::Disconnected) => panic!("GUI thread died!"),
+        }
+    }
+
+    if let Some(button_state) = latest_controllers {
+        messages.push(EmuMessage::UpdateControllers(button_state));
+    }
+    messages
+}
+
 fn emu_loop_step(state: &mut EmuState) -> Result<(), EmuThreadError> {
     // Handle incoming messages
-    loop {
-        match state.comm.rx.try_recv() {
-            Ok(msg) => {
-                match msg {
-                    EmuMessage::Halt => {
-                        state.halted = true;
-                        state.send_message(ClientMessage::LatestPC(state.emu.pc()));
-                        state.send_message(ClientMessage::LatestGPULog(state.latest_draw_log.clone()));
-                        state.send_message(ClientMessage::LatestIrqMask(state.emu.get_irq_mask()));
-                        state.send_message(ClientMessage::LatestCdMask(state.emu.main_bus.cd_drive.get_enable()));
-                        state.send_message(ClientMessage::LatestCdFlag(state.emu.main_bus.cd_drive.get_flag()));
-                    }
-                    EmuMessage::Continue => {
-                        state.halted = false;
-                        state.emu.clear_halt();
-                    }
-                    EmuMessage::AddBreakpoint(addr) => state.emu.add_sw_breakpoint(addr),
-                    EmuMessage::RemoveBreakpoint(addr) => state.emu.remove_sw_breakpoint(addr),
-                    EmuMessage::Kill => return Err(EmuThreadError::Killed),
-                    EmuMessage::StepCPU => { state.emu.run_cpu_instruction(); }, // Warning! Doing this too many times will desync the gpu
-                    EmuMessage::UpdateControllers(button_state) => {
-                        state.emu.update_controller_state(button_state)
-                    }
-                    EmuMessage::Reset => state.emu.reset(),
-                    EmuMessage::StartFrame => state.waiting_for_client = false,
-                    EmuMessage::RecieveGuiContext(signal) => state.gui_ctx = Some(signal),
-                    EmuMessage::SetFrameLimiter(val) => state.frame_limited = val,
-                    EmuMessage::ClearGpuLog => state.emu.clear_gpu_call_log(),
-                    EmuMessage::SetMemLogging(enabled) => toggle_memory_logging(enabled),
+    for msg in drain_messages(&state.comm.rx) {
+        match msg {
+            EmuMessage::Halt => {
+                state.halted = true;
+                state.report_halt();
+            }
+            EmuMessage::Continue => {
+                state.halted = false;
+                state.emu.clear_halt();
+                state.pacer.reset();
+            }
+            EmuMessage::AddBreakpoint(addr) => state.emu.add_sw_breakpoint(addr),
+            EmuMessage::RemoveBreakpoint(addr) => state.emu.remove_sw_breakpoint(addr),
+            EmuMessage::Kill => return Err(EmuThreadError::Killed),
+            EmuMessage::StepCPU => { state.emu.run_cpu_instruction(); }, // Warning! Doing this too many times will desync the gpu
+            EmuMessage::AdvanceFrame => {
+                if state.halted {
+                    state.advance_frame = true;
+                    state.emu.clear_halt();
                 }
             }
-            Err(e) => {
-                match e {
-                    std::sync::mpsc::TryRecvError::Empty => break, // No messages left, break out of the loop
-                    std::sync::mpsc::TryRecvError::Disconnected => panic!("GUI thread died!"),
+            EmuMessage::UpdateControllers(button_state) => state.keyboard_buttons = button_state,
+            EmuMessage::EnableGamepads => match Gilrs::new() {
+                Ok(gilrs) => {
+                    let gamepad = GamepadInput::new(gilrs);
+                    state.send_message(ClientMessage::GamepadsChanged(gamepad.gamepads()));
+                    state.gamepad = Some(gamepad);
+                }
+                // Only the keyboard works without gilrs
+                Err(e) => println!("Unable to start gamepad support: {}", e),
+            },
+            EmuMessage::SelectGamepad(id) => {
+                if let Some(gamepad) = &mut state.gamepad {
+                    gamepad.set_active(id);
+                }
+            }
+            EmuMessage::Reset => {
+                state.emu.reset();
+                state.booted();
+            }
+            EmuMessage::SoftReset => {
+                state.emu.soft_reset();
+                state.booted();
+            }
+            EmuMessage::SetHaltOnBoot(enabled) => state.halt_on_boot = enabled,
+            EmuMessage::StartFrame => state.waiting_for_client = false,
+            EmuMessage::SetFrameCallback(callback) => state.on_frame = Some(callback),
+            EmuMessage::SetFrameLimiter(val) => {
+                state.frame_limited = val;
+                state.pacer.reset();
+            }
+            EmuMessage::SetVideoModeOverride(mode) => state.emu.set_video_mode_override(mode),
+            EmuMessage::SetCpuOverclock(multiplier) => state.emu.set_cpu_overclock(multiplier),
+            EmuMessage::SetBackgroundState(background_state) => state.background_state = background_state,
+            EmuMessage::SetBackgroundBehavior(behavior) => state.background_behavior = behavior,
+            EmuMessage::PressAnalogButton(port) => state.emu.press_analog_button(port),
+            EmuMessage::SetThreadedGpu(enabled) => state.emu.set_threaded_gpu(enabled),
+            EmuMessage::SetHardwareRenderer(enabled) => {
+                if enabled {
+                    let vertices = Arc::new(Mutex::new(Vec::new()));
+                    state.emu.set_renderer(Box::new(HwRecorder::new(vertices.clone())));
+                    state.hw_vertices = Some(vertices);
+                } else {
+                    state.emu.set_renderer(Box::new(SoftwareRenderer));
+                    state.hw_vertices = None;
+                }
+            }
+            EmuMessage::LoadDisc(path) => match load_disc(path.clone()) {
+                Ok(disc) => {
+                    println!("Loading disc: {}", path.display());
+                    let serial = disc.serial().map(String::from);
+                    state.emu.remove_disc();
+                    state.emu.clear_executable();
+                    state.emu.load_disc(disc);
+                    state.emu.reset();
+                    state.booted();
+                    state.send_message(ClientMessage::LoadSucceeded(path));
+                    state.change_game(serial);
+                    state.change_memory_card();
+                }
+                Err(e) => state.send_message(ClientMessage::LoadFailed(e)),
+            },
+            EmuMessage::LoadExe(path) => match load_exe_file(&mut state.emu, &path) {
+                Ok(()) => {
+                    // Reset copies the EXE back into the cleared RAM
+                    state.emu.reset();
+                    state.booted();
+                    state.send_message(ClientMessage::LoadSucceeded(path));
+                }
+                Err(e) => state.send_message(ClientMessage::LoadFailed(e)),
+            },
+            EmuMessage::SetSharedMemoryCard(shared) => {
+                state.card_config.shared = shared;
+                state.change_memory_card();
+            }
+            EmuMessage::DeleteSave(first_block) => {
+                if let Some(card) = state.emu.memory_card_mut() {
+                    card.delete_save(first_block);
+                    state.memory_card_edited();
                 }
             }
+            EmuMessage::ImportSave(path) => match import_save_file(&mut state.emu, &path) {
+                Ok(()) => state.memory_card_edited(),
+                Err(e) => state.send_message(ClientMessage::MemoryCardError(e)),
+            },
+            EmuMessage::ExportSave(first_block, path) => {
+                if let Err(e) = export_save_file(&state.emu, first_block, &path) {
+                    state.send_message(ClientMessage::MemoryCardError(e));
+                }
+            }
+            EmuMessage::ClearGpuLog => state.emu.clear_gpu_call_log(),
+            EmuMessage::SetGpuCapture(capture) => {
+                state.gpu_capture = capture;
+                state.emu.set_gpu_logging(capture != GpuCapture::Off || state.capture_next_frame);
+            }
+            EmuMessage::CaptureNextFrame => {
+                // Whatever was logged so far belongs to an earlier frame
+                state.emu.clear_gpu_call_log();
+                state.emu.set_gpu_logging(true);
+                state.capture_next_frame = true;
+            }
+            EmuMessage::SetGpuDeepCapture(enabled) => state.emu.set_gpu_deep_capture(enabled),
+            EmuMessage::SetStrictSizeCheck(enabled) => state.emu.set_gpu_strict_size_check(enabled),
+            EmuMessage::SetMemLogging(enabled) => state.emu.set_memory_logging(enabled),
+            EmuMessage::SetWatchList(watches) => state.watches = watches,
         }
     }
 
-    if state.emu.exit_requested() {
-        return Err(EmuThreadError::GracefulExit);
-    }
-
     if state.emu.halt_requested() {
         state.halted = true;
     }
 
-    if !state.halted && !state.waiting_for_client {
-        state.emu.run_frame();
+    let card_saved = match &mut state.card_file {
+        Some(card_file) => card_file.flush_if_quiet(&state.emu),
+        None => false,
+    };
+    if card_saved {
+        state.send_memory_card_contents();
+    }
 
+    let background = background_action(state.background_behavior, state.background_state, state.debugging);
+    // An advance runs through the same path as any other frame, so the gui gets the frame and
+    // anything else a frame produces
+    let advancing = std::mem::take(&mut state.advance_frame);
+    if advancing || (!state.halted && !state.waiting_for_client && background != BackgroundBehavior::Pause) {
+        state.poll_controllers();
+        let run = state.emu.run_frame();
+        print!("{}", state.emu.take_tty_output());
+        print!("{}", state.emu.take_test_log());
+        if let RunStatus::ExitRequested(_) = run {
+            // shut_down passes the status on to the client
+            return Err(EmuThreadError::GracefulExit);
+        }
+        // The program waits on its call until now, so each call costs up to a frame
+        if let Some(semihost) = &mut state.semihost {
+            semihost.service(&mut state.emu);
+        }
+        let timing = state.emu.take_frame_timing();
+        let triangles_drawn = timing.gpu.triangles_drawn;
+        state.send_message(ClientMessage::FrameTiming(timing));
 
         //Check for any viewport resolution changes
         if state.emu.display_resolution() != state.current_resolution {
@@ -347,46 +916,205 @@ fn emu_loop_step(state: &mut EmuState) -> Result<(), EmuThreadError> {
             state.send_message(ClientMessage::ResolutionChanged(state.current_resolution.clone()));
         };
 
-        if state.emu.display_origin() != state.current_origin {
-            state.current_origin = state.emu.display_origin();
-            state.send_message(ClientMessage::DisplayOriginChanged(state.current_origin));
+        if state.emu.controller_mode(0) != state.pad_mode {
+            state.pad_mode = state.emu.controller_mode(0);
+            state.send_message(ClientMessage::PadModeChanged(state.pad_mode));
         }
 
-        //Calculate frame time delta
-        let mut frame_time = SystemTime::now()
-            .duration_since(state.last_frame_time)
-            .expect("Error getting frame duration")
-            .as_millis();
+        let timing = (state.emu.dot_clock_divider(), state.emu.video_mode());
+        if timing != state.current_timing {
+            state.current_timing = timing;
+            state.send_message(ClientMessage::DisplayTimingChanged(timing.0, timing.1));
+        }
 
         let frame = state.emu.get_vram().clone();
-        let depth_full = state.emu.is_full_color_depth();
-        // Wait for frame limiter time to pass
-        while state.frame_limited && frame_time < 17 {
-            frame_time = SystemTime::now()
-                .duration_since(state.last_frame_time)
-                .expect("Error getting frame duration")
-                .as_millis();
+        let mut display = Vec::new();
+        state.emu.render_display(&mut display);
+        let watch_values = read_watches(&state.emu, &state.watches);
+
+        // Wait until the frame is due. The game can switch video modes at any time, so check every frame
+        let throttled = background == BackgroundBehavior::Throttle;
+        state.pacer.set_refresh_rate(if throttled {
+            BACKGROUND_REFRESH_RATE
+        } else {
+            state.emu.video_mode().refresh_rate()
+        });
+        let frame_time = if (state.frame_limited || throttled) && !advancing {
+            state.pacer.wait()
+        } else {
+            state.pacer.skip()
+        }
+        .as_millis();
+        let falling_behind = state.frame_limited && state.pacer.frames_behind() > 0;
+        if falling_behind && !state.falling_behind {
+            log::warn!(
+                "Emulation is running {} frame(s) behind, the host can't keep up with {:.2} fps",
+                state.pacer.frames_behind(),
+                state.pacer.refresh_rate()
+            );
+        }
+        state.falling_behind = falling_behind;
+        let (origin_x, origin_y) = state.emu.display_origin();
+        let resolution = state.emu.display_resolution();
+        let ((left, top), (right, bottom)) = state.emu.draw_area();
+        let info = FrameInfo {
+            number: state.emu.frame_count(),
+            time: frame_time,
+            target_rate: state.pacer.refresh_rate(),
+            display_area: VramArea {
+                x: origin_x as u32,
+                y: origin_y as u32,
+                // 24 bit pixels take up one and a half cells
+                width: if state.emu.is_full_color_depth() {
+                    resolution.width * 3 / 2
+                } else {
+                    resolution.width
+                },
+                height: resolution.height,
+            },
+            draw_area: VramArea {
+                x: left,
+                y: top,
+                width: (right + 1).saturating_sub(left),
+                height: (bottom + 1).saturating_sub(top),
+            },
+        };
+
+        if let Some(vertices) = &state.hw_vertices {
+            let frame = HwFrame {
+                vertices: std::mem::take(&mut *vertices.lock().unwrap()),
+                vram: frame.clone(),
+                display_origin: state.emu.display_origin(),
+                full_color: state.emu.is_full_color_depth(),
+            };
+            state.send_message(ClientMessage::HardwareFrame(frame));
         }
 
         // Send the new frame over to the gui thread
         if let Err(_) = state
             .comm
             .tx
-            .send(ClientMessage::FrameReady(frame, frame_time, depth_full))
+            .send(ClientMessage::FrameReady(frame, display, info, watch_values))
         {
             //The other side hung up, so lets end the emu thread
             return Err(EmuThreadError::ClientDied);
         };
-        // Request redraw
-        if let Some(gui_ctx) = &state.gui_ctx {
-            gui_ctx.request_repaint();
+        if let Some(on_frame) = &state.on_frame {
+            on_frame();
         }
 
+        // Taken every frame, even if nobody asked for it, so the log never grows past a frame
         state.latest_draw_log = state.emu.take_gpu_call_log();
+        if state.gpu_capture != GpuCapture::Off {
+            state.send_message(ClientMessage::GpuCallCounts(count_calls(&state.latest_draw_log)));
+        }
+        if state.gpu_capture == GpuCapture::Full || state.capture_next_frame {
+            state.send_message(ClientMessage::LatestGPULog(state.latest_draw_log.clone()));
+        }
+        if state.capture_next_frame {
+            state.capture_next_frame = false;
+            state.emu.set_gpu_logging(state.gpu_capture != GpuCapture::Off);
+        }
+
+        // Report BIOS boot progress until something actually gets drawn. The log is empty while
+        // logging is off, so polygon counts are checked too
+        if !state.first_frame_rendered {
+            if !state.latest_draw_log.is_empty() || triangles_drawn > 0 {
+                state.first_frame_rendered = true;
+                state.send_message(ClientMessage::PostCode(None));
+            } else if state.emu.post_code() != state.last_post_code {
+                state.last_post_code = state.emu.post_code();
+                state.send_message(ClientMessage::PostCode(Some(state.last_post_code)));
+            }
+        }
+
+        if advancing {
+            state.report_halt();
+        }
 
         //state.waiting_for_client = true; // Wait until next frame is ready
-        state.last_frame_time = SystemTime::now();
+    } else {
+        // Nothing to run, so don't spin while waiting for the gui to wake us back up
+        thread::sleep(HALTED_POLL_INTERVAL);
+        state.pacer.reset();
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod emu_thread_tests {
+    use super::*;
+    use psx_emu::gpu::DrawOperation;
+
+    #[test]
+    fn test_halt_is_not_held_up_by_controller_updates() {
+        let (tx, rx) = channel();
+        for _ in 0..1000 {
+            tx.send(EmuMessage::UpdateControllers(ButtonState::new_digital_pad())).unwrap();
+        }
+        tx.send(EmuMessage::Halt).unwrap();
+        tx.send(EmuMessage::UpdateControllers(ButtonState::new_digital_pad())).unwrap();
+
+        let messages = drain_messages(&rx);
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0], EmuMessage::Halt));
+        assert!(matches!(messages[1], EmuMessage::UpdateControllers(_)));
+        assert!(drain_messages(&rx).is_empty());
+    }
+
+    #[test]
+    fn test_kill_skips_the_queue() {
+        let (tx, rx) = channel();
+        tx.send(EmuMessage::Reset).unwrap();
+        tx.send(EmuMessage::Kill).unwrap();
+
+        let messages = drain_messages(&rx);
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(messages[0], EmuMessage::Kill));
+    }
+
+    #[test]
+    fn test_count_calls() {
+        let call = |operation, call_dropped| DrawCall {
+            operation,
+            shading: None,
+            surface: None,
+            transparency: None,
+            points: None,
+            blending_enabled: false,
+            call_dropped,
+            clut_size: None,
+            tex_base_x: None,
+            tex_base_y: None,
+            clut_x: None,
+            clut_y: None,
+            clut: None,
+            vram_change: None,
+        };
+        let log = [
+            call(DrawOperation::Triangle, false),
+            call(DrawOperation::QuickFill, false),
+            call(DrawOperation::Triangle, true),
+        ];
+
+        let counts = count_calls(&log);
+        assert_eq!(counts.total, 3);
+        assert_eq!(counts.dropped, 1);
+        assert_eq!(
+            counts.by_operation,
+            vec![("Tri".to_string(), 2), ("QuickFill".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_background_action() {
+        use BackgroundBehavior::*;
+        assert_eq!(background_action(Pause, BackgroundState::Focused, false), KeepRunning);
+        assert_eq!(background_action(Pause, BackgroundState::Unfocused, false), Pause);
+        assert_eq!(background_action(Throttle, BackgroundState::Unfocused, false), Throttle);
+        assert_eq!(background_action(Throttle, BackgroundState::Minimized, false), Pause);
+        assert_eq!(background_action(KeepRunning, BackgroundState::Minimized, false), KeepRunning);
+        assert_eq!(background_action(Pause, BackgroundState::Minimized, true), KeepRunning);
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use psx_emu::memcard::MemoryCard;
+use psx_emu::PSXEmu;
+
+use crate::config::MemoryCardConfig;
+
+/// Games write a save as many separate sectors, so wait until the card has been left alone
+/// for this long before writing it out. Otherwise we would hit the disk once per sector
+const FLUSH_DELAY: Duration = Duration::from_secs(1);
+const SHARED_CARD_NAME: &str = "shared";
+
+/// Tracks which file the inserted card belongs to, and whether it has unsaved writes
+pub struct CardFile {
+    pub path: PathBuf,
+    last_write_count: u64,
+    last_write: Option<Instant>,
+}
+
+/// Games get their own card named after their serial. Anything without a serial, like an EXE, uses the shared card
+pub fn card_path(config: &MemoryCardConfig, serial: Option<&str>) -> PathBuf {
+    let name = match serial {
+        Some(serial) if !config.shared => serial,
+        _ => SHARED_CARD_NAME,
+    };
+    config.directory.join(format!("{}.mcd", name))
+}
+
+/// Loads the card at path into slot 1, or inserts a freshly formatted one if the file doesn't exist yet.
+/// If the file exists but can't be used the slot is left empty, so the file never gets overwritten
+pub fn insert_card(emu: &mut PSXEmu, path: &Path) -> Result<CardFile, String> {
+    let card = if path.exists() {
+        let data = fs::read(path).map_err(|e| format!("Unable to read memory card {}: {}", path.display(), e))?;
+        MemoryCard::from_data(data).map_err(|e| format!("Unable to load memory card {}: {}", path.display(), e))?
+    } else {
+        MemoryCard::new()
+    };
+
+    let last_write_count = card.write_count();
+    emu.insert_memory_card(card);
+    println!("Memory card: {}", path.display());
+
+    Ok(CardFile {
+        path: path.to_path_buf(),
+        last_write_count,
+        last_write: None,
+    })
+}
+
+impl CardFile {
+    /// Call once per frame. Saves the card once the game has stopped writing to it for a while.
+    /// Returns true if the card was saved
+    pub fn flush_if_quiet(&mut self, emu: &PSXEmu) -> bool {
+        let write_count = match emu.memory_card() {
+            Some(card) => card.write_count(),
+            None => return false,
+        };
+
+        if write_count != self.last_write_count {
+            self.last_write_count = write_count;
+            self.last_write = Some(Instant::now());
+            return false;
+        }
+
+        match self.last_write {
+            Some(time) if time.elapsed() >= FLUSH_DELAY => self.flush(emu),
+            _ => false,
+        }
+    }
+
+    /// Marks the card as modified outside of the console, e.g. by the card manager
+    pub fn mark_dirty(&mut self) {
+        self.last_write = Some(Instant::now());
+    }
+
+    /// Writes the card out now if it has any unsaved changes
+    pub fn flush(&mut self, emu: &PSXEmu) -> bool {
+        if self.last_write.is_none() {
+            return false;
+        }
+
+        let card = match emu.memory_card() {
+            Some(card) => card,
+            None => return false,
+        };
+
+        if let Some(dir) = self.path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                println!("Unable to create {}: {}", dir.display(), e);
+                return false;
+            }
+        }
+
+        match fs::write(&self.path, card.data()) {
+            Ok(()) => {
+                self.last_write_count = card.write_count();
+                self.last_write = None;
+                true
+            }
+            Err(e) => {
+                println!("Unable to write memory card {}: {}", self.path.display(), e);
+                false
+            }
+        }
+    }
+}
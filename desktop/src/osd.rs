@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+use eframe::egui::{Align2, Color32, FontId, Painter, Pos2, Rect, Vec2};
+
+/// Height of OSD text in display pixels
+const TEXT_SIZE: f32 = 8.0;
+/// Text never gets smaller than this many screen points, so it stays readable in small windows
+const MIN_TEXT_SIZE: f32 = 12.0;
+const NOTIFICATION_TIME: Duration = Duration::from_secs(3);
+const NOTIFICATION_BACKGROUND: Color32 = Color32::from_rgba_premultiplied(0, 0, 0, 160);
+/// Gap between notifications and the edge of the display, in display pixels
+const MARGIN: f32 = 4.0;
+
+enum OsdShape {
+    Text {
+        pos: Pos2,
+        color: Color32,
+        text: String,
+    },
+    Rect {
+        rect: Rect,
+        color: Color32,
+    },
+}
+
+struct Notification {
+    message: String,
+    expires: Instant,
+}
+
+/// On screen display drawn over the game picture. Shapes are positioned in display pixels, so they
+/// line up with the game at any window size, crop or aspect ratio. Shapes only last for the frame
+/// they were added in, while notifications stay up for a few seconds.
+pub(crate) struct Osd {
+    shapes: Vec<OsdShape>,
+    notifications: Vec<Notification>,
+}
+
+impl Osd {
+    pub(crate) fn new() -> Self {
+        Self {
+            shapes: vec![],
+            notifications: vec![],
+        }
+    }
+
+    pub(crate) fn text(&mut self, x: f32, y: f32, color: Color32, text: impl Into<String>) {
+        self.shapes.push(OsdShape::Text {
+            pos: Pos2::new(x, y),
+            color,
+            text: text.into(),
+        });
+    }
+
+    pub(crate) fn rect(&mut self, x: f32, y: f32, width: f32, height: f32, color: Color32) {
+        self.shapes.push(OsdShape::Rect {
+            rect: Rect::from_min_size(Pos2::new(x, y), Vec2::new(width, height)),
+            color,
+        });
+    }
+
+    /// Shows a message in the bottom left corner of the display
+    pub(crate) fn notify(&mut self, message: impl Into<String>) {
+        self.notifications.push(Notification {
+            message: message.into(),
+            expires: Instant::now() + NOTIFICATION_TIME,
+        });
+    }
+
+    /// Draws this frame's shapes and any live notifications into `screen`, the rect the display is
+    /// shown in, then clears the shapes. `uv_min` and `uv_max` are the part of the display that is
+    /// visible, as passed to the display shader
+    pub(crate) fn paint(
+        &mut self,
+        painter: &Painter,
+        screen: Rect,
+        display_size: Vec2,
+        uv_min: [f32; 2],
+        uv_max: [f32; 2],
+    ) {
+        let visible_min = Vec2::new(uv_min[0] * display_size.x, uv_min[1] * display_size.y);
+        let visible_size = Vec2::new(
+            (uv_max[0] - uv_min[0]) * display_size.x,
+            (uv_max[1] - uv_min[1]) * display_size.y,
+        );
+        if visible_size.x <= 0.0 || visible_size.y <= 0.0 {
+            return;
+        }
+        let scale = screen.size() / visible_size;
+        let to_screen = |pos: Pos2| screen.min + (pos.to_vec2() - visible_min) * scale;
+        let font = FontId::monospace((TEXT_SIZE * scale.y).max(MIN_TEXT_SIZE));
+
+        let painter = painter.with_clip_rect(screen);
+        for shape in self.shapes.drain(..) {
+            match shape {
+                OsdShape::Text { pos, color, text } => {
+                    painter.text(to_screen(pos), Align2::LEFT_TOP, text, font.clone(), color);
+                }
+                OsdShape::Rect { rect, color } => {
+                    painter.rect_filled(
+                        Rect::from_min_max(to_screen(rect.min), to_screen(rect.max)),
+                        0.0,
+                        color,
+                    );
+                }
+            }
+        }
+
+        let now = Instant::now();
+        self.notifications.retain(|notification| notification.expires > now);
+
+        // Newest notification at the bottom, older ones stacked above it
+        let margin = MARGIN * scale;
+        let mut bottom = screen.max.y - margin.y;
+        for notification in self.notifications.iter().rev() {
+            let galley = painter.layout_no_wrap(
+                notification.message.clone(),
+                font.clone(),
+                Color32::WHITE,
+            );
+            let rect = Align2::LEFT_BOTTOM.anchor_rect(Rect::from_min_size(
+                Pos2::new(screen.min.x + margin.x, bottom),
+                galley.size(),
+            ));
+            painter.rect_filled(rect.expand(font.size * 0.25), 0.0, NOTIFICATION_BACKGROUND);
+            painter.galley(rect.min, galley, Color32::WHITE);
+            bottom = rect.min.y - font.size * 0.75;
+        }
+
+        // Make sure notifications get cleared even when nothing else is repainting the window
+        if let Some(next_expiry) = self.notifications.iter().map(|n| n.expires).min() {
+            painter
+                .ctx()
+                .request_repaint_after(next_expiry.saturating_duration_since(now));
+        }
+    }
+}
+
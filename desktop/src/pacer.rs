@@ -0,0 +1,107 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sleeping is only accurate to a millisecond or so, so the last stretch before a deadline is spun
+const SPIN_THRESHOLD: Duration = Duration::from_micros(1500);
+
+/// If we fall this many frames behind, give up on catching up and start pacing from now
+const MAX_FRAMES_BEHIND: u32 = 4;
+
+/// Paces frames to the emulated refresh rate.
+///
+/// Deadlines are advanced by exactly one frame duration each frame instead of being measured from
+/// when the last frame finished, so timing error never accumulates and the long term rate is exact.
+///
+/// This is the only pacing mode for now. Audio-synced pacing (nudging the rate by about ±0.5% to keep
+/// the audio device's queue centered) needs an audio sink that can report how many samples are queued.
+/// The core mixes samples for `take_audio_samples`, but the desktop client has no audio output to play
+/// them on yet. Once it does, the controller would sit next to this and scale frame_duration, with fast
+/// forward bypassing both like the limiter toggle does today.
+pub struct FramePacer {
+    frame_duration: Duration,
+    next_deadline: Option<Instant>,
+    last_frame: Instant,
+    /// Whole frames the last wait started past its deadline
+    frames_behind: u32,
+}
+
+impl FramePacer {
+    pub fn new(refresh_rate: f64) -> Self {
+        Self {
+            frame_duration: Duration::from_secs_f64(1.0 / refresh_rate),
+            next_deadline: None,
+            last_frame: Instant::now(),
+            frames_behind: 0,
+        }
+    }
+
+    pub fn refresh_rate(&self) -> f64 {
+        1.0 / self.frame_duration.as_secs_f64()
+    }
+
+    /// How many whole frames late the last limited frame was. Anything over 0 means the host
+    /// couldn't keep up with the emulated refresh rate
+    pub fn frames_behind(&self) -> u32 {
+        self.frames_behind
+    }
+
+    /// Changes the target rate. Takes effect from the next frame
+    pub fn set_refresh_rate(&mut self, refresh_rate: f64) {
+        let frame_duration = Duration::from_secs_f64(1.0 / refresh_rate);
+        if frame_duration != self.frame_duration {
+            self.frame_duration = frame_duration;
+            self.reset();
+        }
+    }
+
+    /// Forgets the current schedule. Call this after anything that stops frames for a while,
+    /// like halting or loading, so the pacer doesn't try to make up the lost time
+    pub fn reset(&mut self) {
+        self.next_deadline = None;
+        self.last_frame = Instant::now();
+        self.frames_behind = 0;
+    }
+
+    /// Blocks until it is time to present the next frame. Returns the time since the previous frame
+    pub fn wait(&mut self) -> Duration {
+        let now = Instant::now();
+        self.frames_behind = match self.next_deadline {
+            Some(deadline) => {
+                let late = now.saturating_duration_since(deadline);
+                (late.as_secs_f64() / self.frame_duration.as_secs_f64()) as u32
+            }
+            None => 0,
+        };
+        let deadline = match self.next_deadline {
+            Some(deadline) if now <= deadline + self.frame_duration * MAX_FRAMES_BEHIND => deadline,
+            _ => now,
+        };
+
+        if let Some(remaining) = deadline.checked_duration_since(now) {
+            if remaining > SPIN_THRESHOLD {
+                thread::sleep(remaining - SPIN_THRESHOLD);
+            }
+            while Instant::now() < deadline {
+                std::hint::spin_loop();
+            }
+        }
+
+        self.next_deadline = Some(deadline + self.frame_duration);
+        self.mark_frame()
+    }
+
+    /// Records a frame without waiting, for when the limiter is off.
+    /// Returns the time since the previous frame
+    pub fn skip(&mut self) -> Duration {
+        self.next_deadline = None;
+        self.frames_behind = 0;
+        self.mark_frame()
+    }
+
+    fn mark_frame(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_frame);
+        self.last_frame = now;
+        elapsed
+    }
+}
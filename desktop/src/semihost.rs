@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use psx_emu::PSXEmu;
+
+// Operations, passed in a0
+const OP_OPEN: u32 = 1;
+const OP_CLOSE: u32 = 2;
+const OP_READ: u32 = 3;
+const OP_WRITE: u32 = 4;
+
+// Open flags. These are the values from GDB's File-I/O protocol
+const O_ACCMODE: u32 = 0x3;
+const O_WRONLY: u32 = 0x1;
+const O_RDWR: u32 = 0x2;
+const O_APPEND: u32 = 0x8;
+const O_CREAT: u32 = 0x200;
+const O_TRUNC: u32 = 0x400;
+const O_EXCL: u32 = 0x800;
+
+/// Returned in v0 when a call fails
+const FAILED: u32 = u32::MAX;
+const MAX_PATH_LEN: u32 = 256;
+
+/// Answers semihosting calls from the running program with files from a host directory. Paths
+/// are relative to that directory, and can't climb out of it
+pub(crate) struct Semihost {
+    root: PathBuf,
+    files: HashMap<u32, File>,
+    next_fd: u32,
+}
+
+impl Semihost {
+    pub(crate) fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            files: HashMap::new(),
+            // Leave the usual stdin/stdout/stderr numbers unused
+            next_fd: 3,
+        }
+    }
+
+    /// Answers the call the program is waiting on, if there is one
+    pub(crate) fn service(&mut self, emu: &mut PSXEmu) {
+        let call = match emu.semihost_call() {
+            Some(call) => call,
+            None => return,
+        };
+        let [a1, a2, a3] = call.args;
+        let result = match call.op {
+            OP_OPEN => self.open(emu, a1, a2),
+            OP_CLOSE => self.files.remove(&a1).map(|_| 0),
+            OP_READ => self.read(emu, a1, a2, a3),
+            OP_WRITE => self.write(emu, a1, a2, a3),
+            op => {
+                eprintln!("Semihost: Unknown operation {}", op);
+                None
+            }
+        };
+        emu.finish_semihost_call(result.unwrap_or(FAILED));
+    }
+
+    fn open(&mut self, emu: &mut PSXEmu, path_addr: u32, flags: u32) -> Option<u32> {
+        let path = read_path(emu, path_addr)?;
+        let full_path = match sandboxed_path(&self.root, &path) {
+            Some(full_path) => full_path,
+            None => {
+                eprintln!("Semihost: Refusing to open {} outside of {}", path, self.root.display());
+                return None;
+            }
+        };
+
+        let mut options = OpenOptions::new();
+        options
+            .read(flags & O_ACCMODE != O_WRONLY)
+            .write(flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        if flags & O_EXCL != 0 {
+            options.create_new(true);
+        } else {
+            options.create(flags & O_CREAT != 0);
+        }
+
+        match options.open(&full_path) {
+            Ok(file) => {
+                let fd = self.next_fd;
+                self.next_fd += 1;
+                self.files.insert(fd, file);
+                Some(fd)
+            }
+            Err(e) => {
+                eprintln!("Semihost: Unable to open {}: {}", full_path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn read(&mut self, emu: &mut PSXEmu, fd: u32, buffer: u32, len: u32) -> Option<u32> {
+        let file = self.files.get_mut(&fd)?;
+        let mut data = vec![0; len as usize];
+        let count = file.read(&mut data).ok()?;
+        for (i, byte) in data[..count].iter().enumerate() {
+            emu.r3000.write_bus_byte(
+                buffer.wrapping_add(i as u32),
+                *byte,
+                &mut emu.main_bus,
+                &mut emu.scheduler,
+            );
+        }
+        Some(count as u32)
+    }
+
+    fn write(&mut self, emu: &mut PSXEmu, fd: u32, buffer: u32, len: u32) -> Option<u32> {
+        let file = self.files.get_mut(&fd)?;
+        let data: Vec<u8> = (0..len)
+            .map(|i| emu.r3000.read_bus_byte(buffer.wrapping_add(i), &mut emu.main_bus))
+            .collect();
+        file.write_all(&data).ok()?;
+        Some(len)
+    }
+}
+
+/// Reads the NUL terminated path the program passed
+fn read_path(emu: &mut PSXEmu, addr: u32) -> Option<String> {
+    let mut bytes = Vec::new();
+    for i in 0..MAX_PATH_LEN {
+        match emu.r3000.read_bus_byte(addr.wrapping_add(i), &mut emu.main_bus) {
+            0 => return String::from_utf8(bytes).ok(),
+            byte => bytes.push(byte),
+        }
+    }
+    None
+}
+
+/// Joins a path from the program onto the root. Absolute paths and `..` are rejected, so every
+/// path stays inside the root
+fn sandboxed_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut full_path = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => full_path.push(part),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    Some(full_path)
+}
+
+#[cfg(test)]
+mod semihost_tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_stay_in_the_root() {
+        let root = Path::new("assets");
+        assert_eq!(sandboxed_path(root, "level1/map.bin"), Some(root.join("level1/map.bin")));
+        assert_eq!(sandboxed_path(root, "./tex.tim"), Some(root.join("tex.tim")));
+        assert_eq!(sandboxed_path(root, "../secret"), None);
+        assert_eq!(sandboxed_path(root, "a/../../secret"), None);
+        assert_eq!(sandboxed_path(root, "/etc/passwd"), None);
+    }
+}
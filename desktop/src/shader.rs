@@ -0,0 +1,348 @@
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+use eframe::glow::{self, HasContext, NativeTexture};
+
+/// User shaders are loaded from here, relative to the working directory
+const SHADER_DIR: &str = "shaders";
+
+pub const DEFAULT_SHADER_NAME: &str = "Nearest";
+
+const VERTEX_SHADER: &str = r#"
+#version 330
+
+const vec3 verts[3] = vec3[3](
+    vec3(-1.0, -1.0, 0.0),
+    vec3(3.0, -1.0, 0.0),
+    vec3(-1.0, 3.0, 0.0)
+);
+
+out vec2 TexCoord;
+
+// Visible part of the display texture, used for overscan cropping
+uniform vec2 uvMin;
+uniform vec2 uvMax;
+
+void main()
+{
+    gl_Position = vec4(verts[gl_VertexID], 1.0);
+    vec2 screenPos = vec2(0.5 * gl_Position.x + 0.5, 0.5 - 0.5 * gl_Position.y);
+    TexCoord = mix(uvMin, uvMax, screenPos);
+}
+"#;
+
+const NEAREST_FRAGMENT_SHADER: &str = r#"
+#version 330
+
+out vec4 FragColor;
+in vec2 TexCoord;
+
+uniform sampler2D displayTex;
+uniform vec2 sourceSize;
+
+void main()
+{
+    FragColor = texelFetch(displayTex, ivec2(TexCoord * sourceSize), 0);
+}
+"#;
+
+/// Nearest neighbour up to the largest integer scale, then bilinear for the remainder,
+/// which keeps pixels sharp without uneven pixel widths
+const BILINEAR_SHARP_FRAGMENT_SHADER: &str = r#"
+#version 330
+
+out vec4 FragColor;
+in vec2 TexCoord;
+
+uniform sampler2D displayTex;
+uniform vec2 sourceSize;
+uniform vec2 outputSize;
+
+void main()
+{
+    vec2 texel = TexCoord * sourceSize;
+    vec2 scale = max(floor(outputSize / sourceSize), vec2(1.0));
+    vec2 regionRange = 0.5 - 0.5 / scale;
+    vec2 centerDist = fract(texel) - 0.5;
+    vec2 f = (centerDist - clamp(centerDist, -regionRange, regionRange)) * scale + 0.5;
+    FragColor = texture(displayTex, (floor(texel) + f) / sourceSize);
+}
+"#;
+
+/// Darkens the gaps between source lines like a CRT beam
+const SCANLINE_FRAGMENT_SHADER: &str = r#"
+#version 330
+
+out vec4 FragColor;
+in vec2 TexCoord;
+
+uniform sampler2D displayTex;
+uniform vec2 sourceSize;
+
+void main()
+{
+    vec2 texel = TexCoord * sourceSize;
+    vec3 color = texelFetch(displayTex, ivec2(texel), 0).rgb;
+    float distance = abs(fract(texel.y) - 0.5) * 2.0;
+    float beam = 1.0 - 0.6 * distance * distance;
+    FragColor = vec4(min(color * beam * 1.15, vec3(1.0)), 1.0);
+}
+"#;
+
+const BUILT_IN_SHADERS: [(&str, &str); 3] = [
+    (DEFAULT_SHADER_NAME, NEAREST_FRAGMENT_SHADER),
+    ("Bilinear Sharp", BILINEAR_SHARP_FRAGMENT_SHADER),
+    ("Scanlines", SCANLINE_FRAGMENT_SHADER),
+];
+
+#[derive(Clone, PartialEq)]
+pub struct ShaderPreset {
+    pub name: String,
+    pub fragment_source: String,
+}
+
+/// Returns the built-in shaders followed by every `.glsl` file in the shaders directory.
+///
+/// User shaders are fragment shaders only. They get `in vec2 TexCoord`, must write `out vec4 FragColor`,
+/// and can use these uniforms:
+/// - `sampler2D displayTex`: the displayed area, sampled with linear filtering
+/// - `vec2 sourceSize`: size of displayTex in pixels
+/// - `vec2 outputSize`: size of the on screen picture in pixels
+/// - `int frameCount`: frames painted since the shader was loaded
+/// - `float time`: seconds since the shader was loaded
+pub fn available_shaders() -> Vec<ShaderPreset> {
+    let mut shaders: Vec<ShaderPreset> = BUILT_IN_SHADERS
+        .iter()
+        .map(|(name, source)| ShaderPreset {
+            name: name.to_string(),
+            fragment_source: source.to_string(),
+        })
+        .collect();
+
+    if let Ok(entries) = fs::read_dir(Path::new(SHADER_DIR)) {
+        let mut files: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "glsl"))
+            .collect();
+        files.sort();
+
+        for path in files {
+            match fs::read_to_string(&path) {
+                Ok(source) => shaders.push(ShaderPreset {
+                    name: path.file_name().unwrap().to_string_lossy().to_string(),
+                    fragment_source: source,
+                }),
+                Err(e) => println!("Failed to read shader {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    shaders
+}
+
+pub struct DisplayShaderManager {
+    default_program: glow::Program,
+    /// Currently selected shader, if it isn't the default
+    custom_program: Option<glow::Program>,
+    pending_shader: Option<ShaderPreset>,
+    last_error: Option<String>,
+    vertex_array: glow::VertexArray,
+    texture: Option<(NativeTexture, i32, i32)>,
+    frame_count: i32,
+    start_time: Instant,
+}
+
+impl DisplayShaderManager {
+    pub fn new(gl: &glow::Context) -> Self {
+        unsafe {
+            let default_program = compile_program(gl, NEAREST_FRAGMENT_SHADER)
+                .expect("Failed to compile the default display shader");
+
+            let vertex_array = gl
+                .create_vertex_array()
+                .expect("Cannot create vertex array");
+
+            Self {
+                default_program,
+                custom_program: None,
+                pending_shader: None,
+                last_error: None,
+                vertex_array,
+                texture: None,
+                frame_count: 0,
+                start_time: Instant::now(),
+            }
+        }
+    }
+
+    /// Switches to a different shader. It is compiled on the next paint, since that is where the gl context lives
+    pub fn request_shader(&mut self, shader: ShaderPreset) {
+        self.pending_shader = Some(shader);
+    }
+
+    /// Returns the compile error of the last requested shader, if it failed
+    pub fn last_error(&self) -> Option<&String> {
+        self.last_error.as_ref()
+    }
+
+    #[allow(dead_code)]
+    pub fn destroy(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_program(self.default_program);
+            if let Some(program) = self.custom_program {
+                gl.delete_program(program);
+            }
+            if let Some((texture, _, _)) = self.texture {
+                gl.delete_texture(texture);
+            }
+            gl.delete_vertex_array(self.vertex_array);
+        }
+    }
+
+    fn apply_pending_shader(&mut self, gl: &glow::Context) {
+        let shader = match self.pending_shader.take() {
+            Some(shader) => shader,
+            None => return,
+        };
+
+        unsafe {
+            if let Some(program) = self.custom_program.take() {
+                gl.delete_program(program);
+            }
+
+            if shader.fragment_source != NEAREST_FRAGMENT_SHADER {
+                match compile_program(gl, &shader.fragment_source) {
+                    Ok(program) => {
+                        self.custom_program = Some(program);
+                        self.last_error = None;
+                    }
+                    Err(e) => {
+                        println!("Failed to compile shader {}, using the default instead", shader.name);
+                        self.last_error = Some(e);
+                    }
+                }
+            } else {
+                self.last_error = None;
+            }
+        }
+
+        self.frame_count = 0;
+        self.start_time = Instant::now();
+    }
+
+    /// Uploads the display texture, reusing the existing one if the size hasn't changed
+    fn upload_texture(&mut self, gl: &glow::Context, image_data: &[u8], width: i32, height: i32) -> NativeTexture {
+        unsafe {
+            match self.texture {
+                Some((texture, tex_width, tex_height)) if tex_width == width && tex_height == height => {
+                    gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+                    gl.tex_sub_image_2d(glow::TEXTURE_2D, 0, 0, 0, width, height, glow::RGBA, glow::UNSIGNED_BYTE, glow::PixelUnpackData::Slice(image_data));
+                    texture
+                }
+                _ => {
+                    if let Some((texture, _, _)) = self.texture.take() {
+                        gl.delete_texture(texture);
+                    }
+                    let texture = gl.create_texture().unwrap();
+                    gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+                    gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as i32);
+                    gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as i32);
+                    gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE as i32);
+                    gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE as i32);
+                    gl.tex_image_2d(glow::TEXTURE_2D, 0, glow::RGBA as i32, width, height, 0, glow::RGBA, glow::UNSIGNED_BYTE, Some(image_data));
+                    self.texture = Some((texture, width, height));
+                    texture
+                }
+            }
+        }
+    }
+
+    pub fn paint(&mut self, gl: &glow::Context, image_data: &[u8], display_width: i32, display_height: i32, output_size: [f32; 2], uv_min: [f32; 2], uv_max: [f32; 2]) {
+        self.apply_pending_shader(gl);
+
+        // The gui thread can paint before the first frame arrives, or mid resolution change
+        if image_data.len() != (display_width * display_height * 4) as usize {
+            return;
+        }
+
+        unsafe {
+            gl.active_texture(glow::TEXTURE0);
+        }
+        let texture = self.upload_texture(gl, image_data, display_width, display_height);
+        self.draw(gl, texture, [display_width as f32, display_height as f32], output_size, uv_min, uv_max);
+    }
+
+    /// Paints a texture that is already on the GPU, like the hardware renderer's output. `uv_min` and
+    /// `uv_max` are the displayed part of it
+    pub fn paint_texture(&mut self, gl: &glow::Context, texture: NativeTexture, source_size: [f32; 2], output_size: [f32; 2], uv_min: [f32; 2], uv_max: [f32; 2]) {
+        self.apply_pending_shader(gl);
+        self.draw(gl, texture, source_size, output_size, uv_min, uv_max);
+    }
+
+    fn draw(&mut self, gl: &glow::Context, texture: NativeTexture, source_size: [f32; 2], output_size: [f32; 2], uv_min: [f32; 2], uv_max: [f32; 2]) {
+        let program = self.custom_program.unwrap_or(self.default_program);
+        unsafe {
+            gl.use_program(Some(program));
+            gl.active_texture(glow::TEXTURE0);
+            gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+
+            gl.uniform_1_i32(gl.get_uniform_location(program, "displayTex").as_ref(), 0);
+            gl.uniform_2_f32(gl.get_uniform_location(program, "uvMin").as_ref(), uv_min[0], uv_min[1]);
+            gl.uniform_2_f32(gl.get_uniform_location(program, "uvMax").as_ref(), uv_max[0], uv_max[1]);
+            gl.uniform_2_f32(gl.get_uniform_location(program, "sourceSize").as_ref(), source_size[0], source_size[1]);
+            gl.uniform_2_f32(gl.get_uniform_location(program, "outputSize").as_ref(), output_size[0], output_size[1]);
+            gl.uniform_1_i32(gl.get_uniform_location(program, "frameCount").as_ref(), self.frame_count);
+            gl.uniform_1_f32(gl.get_uniform_location(program, "time").as_ref(), self.start_time.elapsed().as_secs_f32());
+
+            gl.bind_vertex_array(Some(self.vertex_array));
+            gl.draw_arrays(glow::TRIANGLES, 0, 3);
+        }
+
+        self.frame_count = self.frame_count.wrapping_add(1);
+    }
+}
+
+/// Compiles the shared vertex shader with the given fragment shader. Returns the compile or link log on failure
+unsafe fn compile_program(gl: &glow::Context, fragment_source: &str) -> Result<glow::Program, String> {
+    let program = gl.create_program()?;
+
+    let shader_sources = [
+        (glow::VERTEX_SHADER, VERTEX_SHADER),
+        (glow::FRAGMENT_SHADER, fragment_source),
+    ];
+
+    let mut shaders = vec![];
+    let mut result = Ok(());
+    for (shader_type, shader_source) in shader_sources.iter() {
+        let shader = gl.create_shader(*shader_type)?;
+        gl.shader_source(shader, shader_source);
+        gl.compile_shader(shader);
+        if !gl.get_shader_compile_status(shader) {
+            result = Err(gl.get_shader_info_log(shader));
+        }
+        gl.attach_shader(program, shader);
+        shaders.push(shader);
+    }
+
+    if result.is_ok() {
+        gl.link_program(program);
+        if !gl.get_program_link_status(program) {
+            result = Err(gl.get_program_info_log(program));
+        }
+    }
+
+    for shader in shaders {
+        gl.detach_shader(program, shader);
+        gl.delete_shader(shader);
+    }
+
+    match result {
+        Ok(()) => Ok(program),
+        Err(e) => {
+            gl.delete_program(program);
+            Err(e)
+        }
+    }
+}
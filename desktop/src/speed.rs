@@ -0,0 +1,114 @@
+use std::time::{Duration, Instant};
+
+/// How much host time each speed reading covers
+const SPEED_WINDOW: Duration = Duration::from_secs(1);
+
+/// A gap between frames longer than this means the emulator was halted or loading, not slow
+const STALL_TIME: Duration = Duration::from_millis(500);
+
+/// Measures emulation speed from the frame numbers the emu thread sends, and counts frames that
+/// never arrived or arrived twice
+pub struct SpeedMeter {
+    last_number: Option<u64>,
+    last_frame: Instant,
+    window_start: Instant,
+    /// Emulated frames since window_start
+    window_frames: u64,
+    speed: Option<f64>,
+    pub skipped: u64,
+    pub duplicated: u64,
+}
+
+impl SpeedMeter {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            last_number: None,
+            last_frame: now,
+            window_start: now,
+            window_frames: 0,
+            speed: None,
+            skipped: 0,
+            duplicated: 0,
+        }
+    }
+
+    /// Records a frame received at `now`. `target_rate` is the refresh rate the emulator is
+    /// meant to run at
+    pub fn record(&mut self, number: u64, target_rate: f64, now: Instant) {
+        if now.duration_since(self.last_frame) > STALL_TIME {
+            self.restart(now);
+        }
+        self.last_frame = now;
+
+        match self.last_number {
+            Some(last) if number == last => self.duplicated += 1,
+            // The core's count goes back to 0 on reset
+            Some(last) if number < last => self.restart(now),
+            Some(last) => {
+                self.skipped += number - last - 1;
+                self.window_frames += number - last;
+            }
+            None => self.restart(now),
+        }
+        self.last_number = Some(number);
+
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= SPEED_WINDOW {
+            let rate = self.window_frames as f64 / elapsed.as_secs_f64();
+            self.speed = Some(rate / target_rate);
+            self.window_start = now;
+            self.window_frames = 0;
+        }
+    }
+
+    /// Emulated frames per host second as a fraction of the target rate, once a full window has
+    /// been measured
+    pub fn speed(&self) -> Option<f64> {
+        self.speed
+    }
+
+    fn restart(&mut self, now: Instant) {
+        self.window_start = now;
+        self.window_frames = 0;
+        self.speed = None;
+    }
+}
+
+#[cfg(test)]
+mod speed_tests {
+    use super::*;
+
+    #[test]
+    fn test_speed_and_gaps() {
+        let mut meter = SpeedMeter::new();
+        let start = Instant::now();
+        let frame = Duration::from_secs_f64(1.0 / 60.0);
+
+        // 50 emulated frames a second against a 60 fps target, with one frame lost and one repeated in place of the next
+        for i in 0..=55u64 {
+            let number = match i {
+                10 => continue,
+                20 => 19,
+                _ => i,
+            };
+            meter.record(number, 60.0, start + frame.mul_f64(i as f64 * 1.2));
+        }
+
+        // Frame 20 never arrived either, since 19 came in its place
+        assert_eq!(meter.skipped, 2);
+        assert_eq!(meter.duplicated, 1);
+        let speed = meter.speed().unwrap();
+        assert!((speed - 50.0 / 60.0).abs() < 0.01, "speed was {}", speed);
+    }
+
+    #[test]
+    fn test_stall_restarts_window() {
+        let mut meter = SpeedMeter::new();
+        let start = Instant::now();
+        meter.record(1, 60.0, start);
+        meter.record(2, 60.0, start + Duration::from_secs(5));
+        assert_eq!(meter.speed(), None);
+        assert_eq!(meter.skipped, 0);
+    }
+}
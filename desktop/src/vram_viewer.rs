@@ -0,0 +1,215 @@
+use eframe::egui::{Pos2, Rect, Vec2};
+
+const VRAM_WIDTH: usize = 1024;
+const VRAM_HEIGHT: usize = 512;
+const MIN_ZOOM: f32 = 0.5;
+const MAX_ZOOM: f32 = 32.0;
+
+/// A rectangle of VRAM cells, for the viewer's overlays
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VramArea {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// What the hovered cell is decoded as. The CLUT modes also show which palette entry the texel
+/// under the cursor picks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TexelDecode {
+    Direct,
+    FourBit,
+    EightBit,
+}
+
+pub const TEXEL_DECODES: [TexelDecode; 3] = [
+    TexelDecode::Direct,
+    TexelDecode::FourBit,
+    TexelDecode::EightBit,
+];
+
+impl TexelDecode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TexelDecode::Direct => "15 bit",
+            TexelDecode::FourBit => "4 bit CLUT",
+            TexelDecode::EightBit => "8 bit CLUT",
+        }
+    }
+
+    fn texels_per_cell(&self) -> u32 {
+        match self {
+            TexelDecode::Direct => 1,
+            TexelDecode::FourBit => 4,
+            TexelDecode::EightBit => 2,
+        }
+    }
+}
+
+/// Everything the viewer shows about the cell under the cursor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CellInfo {
+    pub x: u32,
+    pub y: u32,
+    pub value: u16,
+    /// 5 bit channels
+    pub rgb: (u8, u8, u8),
+    pub mask: bool,
+    /// Palette entry the hovered texel decodes to in a CLUT mode
+    pub clut_index: Option<u8>,
+}
+
+/// Reads the cell at VRAM position (`x`, `y`). The fraction of `x` picks which texel of the cell
+/// a CLUT mode decodes
+pub fn inspect(vram: &[u16], x: f32, y: f32, decode: TexelDecode) -> Option<CellInfo> {
+    if x < 0.0 || y < 0.0 || x >= VRAM_WIDTH as f32 || y >= VRAM_HEIGHT as f32 {
+        return None;
+    }
+    let (cell_x, cell_y) = (x as u32, y as u32);
+    let value = *vram.get(cell_y as usize * VRAM_WIDTH + cell_x as usize)?;
+
+    let texels = decode.texels_per_cell();
+    let texel = ((x.fract() * texels as f32) as u32).min(texels - 1);
+    let bits = 16 / texels;
+    let clut_index = match decode {
+        TexelDecode::Direct => None,
+        _ => Some(((value >> (texel * bits)) & ((1 << bits) - 1)) as u8),
+    };
+
+    Some(CellInfo {
+        x: cell_x,
+        y: cell_y,
+        value,
+        rgb: (
+            (value & 0x1F) as u8,
+            ((value >> 5) & 0x1F) as u8,
+            ((value >> 10) & 0x1F) as u8,
+        ),
+        mask: value & 0x8000 != 0,
+        clut_index,
+    })
+}
+
+/// Zoom and pan of the viewer. `offset` is the VRAM position at the top left of the view
+pub struct VramViewer {
+    pub zoom: f32,
+    pub offset: Vec2,
+    pub decode: TexelDecode,
+}
+
+impl VramViewer {
+    pub fn new() -> Self {
+        Self {
+            zoom: 1.0,
+            offset: Vec2::ZERO,
+            decode: TexelDecode::Direct,
+        }
+    }
+
+    /// Where all of VRAM lands on screen when the view is drawn into `view`
+    pub fn vram_rect(&self, view: Rect) -> Rect {
+        Rect::from_min_size(
+            view.min - self.offset * self.zoom,
+            Vec2::new(VRAM_WIDTH as f32, VRAM_HEIGHT as f32) * self.zoom,
+        )
+    }
+
+    /// VRAM position under a point on screen
+    pub fn to_vram(&self, view: Rect, pos: Pos2) -> Pos2 {
+        (self.offset + (pos - view.min) / self.zoom).to_pos2()
+    }
+
+    /// Screen rectangle covering a VRAM area
+    pub fn area_rect(&self, view: Rect, area: VramArea) -> Rect {
+        let vram = self.vram_rect(view);
+        Rect::from_min_size(
+            vram.min + Vec2::new(area.x as f32, area.y as f32) * self.zoom,
+            Vec2::new(area.width as f32, area.height as f32) * self.zoom,
+        )
+    }
+
+    /// Multiplies the zoom by `factor`, keeping the VRAM under `cursor` where it is
+    pub fn zoom_at(&mut self, view: Rect, cursor: Pos2, factor: f32) {
+        let anchor = self.to_vram(view, cursor);
+        self.zoom = (self.zoom * factor).clamp(MIN_ZOOM, MAX_ZOOM);
+        self.offset = anchor.to_vec2() - (cursor - view.min) / self.zoom;
+        self.clamp_offset(view);
+    }
+
+    /// Moves the view by a drag of `delta` screen pixels
+    pub fn pan(&mut self, view: Rect, delta: Vec2) {
+        self.offset -= delta / self.zoom;
+        self.clamp_offset(view);
+    }
+
+    /// Stops the view from scrolling past the edges of VRAM
+    fn clamp_offset(&mut self, view: Rect) {
+        let visible = view.size() / self.zoom;
+        let max_x = (VRAM_WIDTH as f32 - visible.x).max(0.0);
+        let max_y = (VRAM_HEIGHT as f32 - visible.y).max(0.0);
+        self.offset = Vec2::new(
+            self.offset.x.clamp(0.0, max_x),
+            self.offset.y.clamp(0.0, max_y),
+        );
+    }
+}
+
+#[cfg(test)]
+mod vram_viewer_tests {
+    use super::*;
+
+    fn view() -> Rect {
+        Rect::from_min_size(Pos2::new(10.0, 20.0), Vec2::new(1024.0, 512.0))
+    }
+
+    #[test]
+    fn test_zoom_keeps_cursor_in_place() {
+        let mut viewer = VramViewer::new();
+        let cursor = Pos2::new(310.0, 220.0);
+        let before = viewer.to_vram(view(), cursor);
+        viewer.zoom_at(view(), cursor, 4.0);
+        assert_eq!(viewer.zoom, 4.0);
+        assert_eq!(viewer.to_vram(view(), cursor), before);
+
+        // Dragging right moves the view left, and it stops at the edge of VRAM
+        viewer.pan(view(), Vec2::new(40.0, 0.0));
+        assert_eq!(viewer.offset.x, before.x - 75.0 - 10.0);
+        viewer.pan(view(), Vec2::new(10_000.0, 0.0));
+        assert_eq!(viewer.offset.x, 0.0);
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut vram = vec![0; VRAM_WIDTH * VRAM_HEIGHT];
+        // Mask bit, blue 1, green 2, red 3
+        vram[5 * VRAM_WIDTH + 7] = 0x8000 | (1 << 10) | (2 << 5) | 3;
+
+        let info = inspect(&vram, 7.9, 5.2, TexelDecode::Direct).unwrap();
+        assert_eq!((info.x, info.y, info.value), (7, 5, 0x8443));
+        assert_eq!(info.rgb, (3, 2, 1));
+        assert!(info.mask);
+        assert_eq!(info.clut_index, None);
+
+        // The cell's nibbles from low to high are 3, 4, 4 and 8
+        assert_eq!(
+            inspect(&vram, 7.1, 5.0, TexelDecode::FourBit)
+                .unwrap()
+                .clut_index,
+            Some(3)
+        );
+        assert_eq!(
+            inspect(&vram, 7.9, 5.0, TexelDecode::FourBit)
+                .unwrap()
+                .clut_index,
+            Some(8)
+        );
+        assert_eq!(
+            inspect(&vram, 7.6, 5.0, TexelDecode::EightBit)
+                .unwrap()
+                .clut_index,
+            Some(0x84)
+        );
+        assert_eq!(inspect(&vram, -1.0, 5.0, TexelDecode::Direct), None);
+    }
+}
@@ -0,0 +1,114 @@
+use psx_emu::PSXEmu;
+use serde::{Deserialize, Serialize};
+
+/// Longest string an ASCII watch shows
+const ASCII_WATCH_LEN: u32 = 16;
+/// Main RAM and its mirrors, as a physical address range
+const RAM_END: u32 = 0x0080_0000;
+
+/// How a watched address is read and shown
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum WatchType {
+    U8,
+    U16,
+    U32,
+    /// Signed 20.12 fixed point, which games use for most of their 3D math
+    Fixed,
+    /// Text up to the first NUL
+    Ascii,
+}
+
+pub const WATCH_TYPES: [WatchType; 5] = [
+    WatchType::U8,
+    WatchType::U16,
+    WatchType::U32,
+    WatchType::Fixed,
+    WatchType::Ascii,
+];
+
+impl WatchType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WatchType::U8 => "u8",
+            WatchType::U16 => "u16",
+            WatchType::U32 => "u32",
+            WatchType::Fixed => "20.12",
+            WatchType::Ascii => "ASCII",
+        }
+    }
+
+    fn size(&self) -> u32 {
+        match self {
+            WatchType::U8 => 1,
+            WatchType::U16 => 2,
+            WatchType::U32 | WatchType::Fixed => 4,
+            WatchType::Ascii => ASCII_WATCH_LEN,
+        }
+    }
+
+    /// Formats the bytes the emu thread read for a watch of this type
+    pub fn format(&self, bytes: &[u8]) -> String {
+        let word = |len: usize| {
+            let mut buf = [0; 4];
+            buf[..len].copy_from_slice(&bytes[..len]);
+            u32::from_le_bytes(buf)
+        };
+        match self {
+            WatchType::U8 => format!("{} ({:#04X})", bytes[0], bytes[0]),
+            WatchType::U16 => format!("{} ({:#06X})", word(2), word(2)),
+            WatchType::U32 => format!("{} ({:#010X})", word(4), word(4)),
+            WatchType::Fixed => format!("{:.4}", word(4) as i32 as f64 / 4096.0),
+            WatchType::Ascii => bytes
+                .iter()
+                .take_while(|byte| **byte != 0)
+                .map(|byte| {
+                    if byte.is_ascii_graphic() || *byte == b' ' {
+                        *byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WatchEntry {
+    pub label: String,
+    pub address: u32,
+    pub kind: WatchType,
+}
+
+/// Reads every watch straight out of RAM, so watching never disturbs the machine. Watches outside
+/// of main RAM read as None
+pub fn read_watches(emu: &PSXEmu, watches: &[WatchEntry]) -> Vec<Option<Vec<u8>>> {
+    let ram = emu.ram();
+    watches
+        .iter()
+        .map(|watch| {
+            let address = watch.address & 0x1FFF_FFFF;
+            if address >= RAM_END {
+                return None;
+            }
+            let bytes = (address..address + watch.kind.size())
+                .map(|addr| ram[addr as usize % ram.len()])
+                .collect();
+            Some(bytes)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(WatchType::U8.format(&[0xFF]), "255 (0xFF)");
+        assert_eq!(WatchType::U16.format(&[0x34, 0x12]), "4660 (0x1234)");
+        assert_eq!(WatchType::Fixed.format(&0x1800u32.to_le_bytes()), "1.5000");
+        assert_eq!(WatchType::Fixed.format(&(-0x800i32).to_le_bytes()), "-0.5000");
+        assert_eq!(WatchType::Ascii.format(b"SLUS\x01_0\0junk"), "SLUS._0");
+    }
+}
@@ -0,0 +1,61 @@
+//! Formats a draw log entry the same way a frontend's GPU log view would.
+//! Run with `cargo run --example draw_call`
+
+use psx_emu::gpu::{DrawCall, DrawOperation, Point, Shading, Surface, TextureColorMode, Transparency};
+
+fn describe(call: &DrawCall) -> String {
+    let mut parts = vec![call.operation.to_string()];
+
+    if let Some(shading) = call.shading {
+        parts.push(shading.to_string());
+    }
+    if let Some(surface) = call.surface {
+        parts.push(surface.to_string());
+        if let (Some(mode), Some(x), Some(y)) = (call.clut_size, call.tex_base_x, call.tex_base_y) {
+            parts.push(format!("{} page ({}, {})", mode, x, y));
+        }
+    }
+    if let Some(transparency) = call.transparency {
+        parts.push(transparency.to_string());
+    }
+    if let Some(points) = &call.points {
+        let points: Vec<String> = points.iter().map(|p| format!("({}, {})", p.x, p.y)).collect();
+        parts.push(points.join(" "));
+    }
+    if call.call_dropped {
+        parts.push("dropped".to_string());
+    }
+
+    parts.join(" | ")
+}
+
+fn point(x: i32, y: i32) -> Point {
+    Point {
+        x,
+        y,
+        color: 0,
+        tex_x: 0,
+        tex_y: 0,
+    }
+}
+
+fn main() {
+    let call = DrawCall {
+        operation: DrawOperation::Triangle,
+        shading: Some(Shading::Gouraud),
+        surface: Some(Surface::Textured),
+        transparency: Some(Transparency::Solid),
+        points: Some(vec![point(0, 0), point(64, 0), point(0, 64)]),
+        blending_enabled: false,
+        call_dropped: false,
+        clut_size: Some(TextureColorMode::FourBit),
+        tex_base_x: Some(10),
+        tex_base_y: Some(1),
+        clut_x: Some(0),
+        clut_y: Some(480),
+        clut: None,
+        vram_change: None,
+    };
+
+    println!("{}", describe(&call));
+}
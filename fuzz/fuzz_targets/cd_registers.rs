@@ -0,0 +1,49 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use psx_emu::cdrom::disc::{Disc, DiscTrack};
+use psx_emu::PSXEmu;
+
+const CD_BASE: u32 = 0x1F80_1800;
+const BIOS_SIZE: usize = 512 * 1024;
+const DISC_SECTORS: usize = 32;
+const BYTES_PER_SECTOR: usize = 2352;
+
+/// One access to the drive's four registers. Advance runs the scheduler so queued responses get delivered
+#[derive(Arbitrary, Debug)]
+enum CdAccess {
+    Write { register: u8, value: u8 },
+    Read { register: u8 },
+    Advance { kilocycles: u8 },
+}
+
+fuzz_target!(|input: (bool, Vec<CdAccess>)| {
+    let (insert_disc, accesses) = input;
+
+    // Only the scheduler and the drive are used. The CPU never runs, so the BIOS contents don't matter
+    let mut emu = PSXEmu::new(vec![0; BIOS_SIZE]).unwrap();
+    if insert_disc {
+        let mut disc = Disc::new("fuzz.bin");
+        disc.add_track(DiscTrack::new(vec![0; DISC_SECTORS * BYTES_PER_SECTOR]));
+        emu.load_disc(disc);
+    }
+
+    for access in accesses {
+        match access {
+            CdAccess::Write { register, value } => {
+                let addr = CD_BASE + (register & 0x3) as u32;
+                emu.main_bus.cd_drive.write_byte(addr, value, &mut emu.scheduler);
+            }
+            CdAccess::Read { register } => {
+                let addr = CD_BASE + (register & 0x3) as u32;
+                emu.main_bus.cd_drive.read_byte(addr);
+            }
+            CdAccess::Advance { kilocycles } => {
+                for _ in 0..kilocycles as usize * 1024 {
+                    emu.scheduler.run_cycle(&mut emu.r3000, &mut emu.main_bus);
+                }
+            }
+        }
+    }
+});
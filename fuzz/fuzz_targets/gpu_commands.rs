@@ -0,0 +1,35 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use psx_emu::gpu::Gpu;
+
+const VRAM_SIZE: usize = 1024 * 512;
+
+/// Everything a game can do to the GPU through its two ports
+#[derive(Arbitrary, Debug)]
+enum GpuAccess {
+    Gp0(u32),
+    Gp1(u32),
+    ReadGpuRead,
+    ReadStatus,
+}
+
+fuzz_target!(|accesses: Vec<GpuAccess>| {
+    let mut gpu = Gpu::new();
+
+    for access in accesses {
+        match access {
+            GpuAccess::Gp0(word) => gpu.send_gp0_command(word),
+            GpuAccess::Gp1(word) => gpu.send_gp1_command(word),
+            GpuAccess::ReadGpuRead => {
+                gpu.read_word_gp0();
+            }
+            GpuAccess::ReadStatus => {
+                gpu.read_status_register();
+            }
+        }
+    }
+
+    assert_eq!(gpu.get_vram().len(), VRAM_SIZE, "VRAM was resized");
+});
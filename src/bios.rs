@@ -1,12 +1,168 @@
+use std::fmt::Display;
+
 use byteorder::{ByteOrder, LittleEndian};
+use log::warn;
+
+/// Every retail BIOS is a 512KB ROM
+pub const BIOS_SIZE: usize = 512 * 1024;
+
+const VERSION_STRING_PREFIX: &[u8] = b"System ROM Version ";
+
+/// Replaces the shell entry point with `jr ra; nop`, so the bootstrap goes straight to the disc
+const FAST_BOOT_PATCH: [(usize, u32); 2] = [(0x18000, 0x03E00008), (0x18004, 0x00000000)];
+
+/// Sets the kernel's TTY enable flag during init (`li at, 1; sw at, -0x5640(gp)`)
+const FORCE_TTY_PATCH: [(usize, u32); 2] = [(0x6F0C, 0x24010001), (0x6F14, 0xAF81A9C0)];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BiosRegion {
+    NorthAmerica,
+    Europe,
+    Japan,
+    Unknown,
+}
+
+impl BiosRegion {
+    /// PAL consoles run at 50hz. Everything else is treated as NTSC
+    pub fn is_pal(&self) -> bool {
+        *self == BiosRegion::Europe
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BiosInfo {
+    pub region: BiosRegion,
+    pub version: String,
+    /// MD5 of the image, as lowercase hex
+    pub hash: String,
+    /// True if the image matched the table of known BIOS revisions
+    pub known: bool,
+    /// True if the fast boot and TTY patches are known to work on this revision
+    pub patchable: bool,
+}
+
+#[derive(Debug)]
+pub enum BiosError {
+    InvalidSize(usize),
+    /// The emulator was built without a BIOS image
+    Missing,
+}
+
+impl Display for BiosError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BiosError::InvalidSize(size) => write!(
+                f,
+                "BIOS image is {} bytes, expected {} bytes",
+                size, BIOS_SIZE
+            ),
+            BiosError::Missing => write!(f, "No BIOS image was provided"),
+        }
+    }
+}
+
+impl std::error::Error for BiosError {}
+
+struct KnownBios {
+    hash: &'static str,
+    version: &'static str,
+    region: BiosRegion,
+    patchable: bool,
+}
+
+const KNOWN_BIOSES: [KnownBios; 8] = [
+    KnownBios { hash: "239665b1a3dade1b5a52c06338011044", version: "SCPH-1000 (v1.0 J)", region: BiosRegion::Japan, patchable: false },
+    KnownBios { hash: "924e392ed05558ffdb115408c263dccf", version: "SCPH-1001 (v2.2 A)", region: BiosRegion::NorthAmerica, patchable: true },
+    KnownBios { hash: "8dd7d5296a650fac7319bce665a6a53c", version: "SCPH-5500 (v3.0 J)", region: BiosRegion::Japan, patchable: true },
+    KnownBios { hash: "490f666e1afb15b7362b406ed1cea246", version: "SCPH-5501 (v3.0 A)", region: BiosRegion::NorthAmerica, patchable: true },
+    KnownBios { hash: "32736f17079d0b2b7024407c39bd3050", version: "SCPH-5502 (v3.0 E)", region: BiosRegion::Europe, patchable: true },
+    KnownBios { hash: "1e68c231d0896b7eadcad1d7d8e76129", version: "SCPH-7001 (v4.1 A)", region: BiosRegion::NorthAmerica, patchable: true },
+    KnownBios { hash: "b9d9a0286c33dc6b7237bb13cd46fdee", version: "SCPH-7502 (v4.1 E)", region: BiosRegion::Europe, patchable: true },
+    KnownBios { hash: "6e3735ff4c7dc899ee98981385f6f3d0", version: "SCPH-101 (v4.5 A)", region: BiosRegion::NorthAmerica, patchable: true },
+];
 
 pub struct Bios {
     data: Vec<u8>,
+    original_data: Vec<u8>,
+    info: BiosInfo,
 }
 
 impl Bios {
-    pub fn new(data: Vec<u8>) -> Bios {
-        Bios { data }
+    /// Validates and identifies a BIOS image. Images that aren't in the known table are still
+    /// accepted, but a warning is logged
+    pub fn new(data: Vec<u8>) -> Result<Bios, BiosError> {
+        if data.len() != BIOS_SIZE {
+            return Err(BiosError::InvalidSize(data.len()));
+        }
+
+        let info = identify(&data);
+        if !info.known {
+            warn!(
+                target: "psx::bios",
+                "Unknown BIOS image (md5 {}). Detected version: {}",
+                info.hash, info.version
+            );
+        }
+
+        Ok(Bios {
+            original_data: data.clone(),
+            data,
+            info,
+        })
+    }
+
+    /// Stands in for the BIOS of a machine loaded from a save state until the running one is moved in
+    #[cfg(feature = "savestate")]
+    pub(crate) fn empty() -> Bios {
+        Bios {
+            data: Vec::new(),
+            original_data: Vec::new(),
+            info: BiosInfo {
+                region: BiosRegion::Unknown,
+                version: String::new(),
+                hash: String::new(),
+                known: false,
+                patchable: false,
+            },
+        }
+    }
+
+    pub fn info(&self) -> &BiosInfo {
+        &self.info
+    }
+
+    /// Restores the original image, then applies the requested patches.
+    /// Patches are only applied to revisions they are known to work on
+    pub fn apply_patches(&mut self, fast_boot: bool, force_tty: bool) {
+        self.data.copy_from_slice(&self.original_data);
+
+        if !fast_boot && !force_tty {
+            return;
+        }
+
+        if !self.info.patchable {
+            warn!(
+                target: "psx::bios",
+                "BIOS patches are not supported on {}, running unpatched",
+                self.info.version
+            );
+            return;
+        }
+
+        if fast_boot {
+            self.patch(&FAST_BOOT_PATCH);
+        }
+
+        if force_tty {
+            self.patch(&FORCE_TTY_PATCH);
+        }
+    }
+
+    fn patch(&mut self, patch: &[(usize, u32)]) {
+        for (offset, value) in patch {
+            LittleEndian::write_u32(&mut self.data[*offset..*offset + 4], *value);
+        }
     }
 
     pub fn read_word(&self, addr: u32) -> u32 {
@@ -25,3 +181,114 @@ impl Bios {
         &self.data
     }
 }
+
+fn identify(data: &[u8]) -> BiosInfo {
+    let hash = format!("{:x}", md5::compute(data));
+
+    if let Some(known) = KNOWN_BIOSES.iter().find(|b| b.hash == hash) {
+        return BiosInfo {
+            region: known.region,
+            version: known.version.to_string(),
+            hash,
+            known: true,
+            patchable: known.patchable,
+        };
+    }
+
+    // OpenBIOS is built from source, so there is no fixed hash to match against
+    if find_bytes(data, b"OpenBIOS").is_some() {
+        return BiosInfo {
+            region: BiosRegion::Unknown,
+            version: "OpenBIOS".to_string(),
+            hash,
+            known: true,
+            patchable: false,
+        };
+    }
+
+    // Fall back to the version string most retail images carry, e.g. "System ROM Version 4.1 12/16/97 A"
+    let (version, region) = match find_bytes(data, VERSION_STRING_PREFIX) {
+        Some(start) => {
+            let version_bytes: Vec<u8> = data[start..]
+                .iter()
+                .take_while(|b| **b != 0 && b.is_ascii())
+                .cloned()
+                .collect();
+            let version = String::from_utf8_lossy(&version_bytes).trim().to_string();
+            let region = match version.chars().last() {
+                Some('A') => BiosRegion::NorthAmerica,
+                Some('E') => BiosRegion::Europe,
+                Some('J') => BiosRegion::Japan,
+                _ => BiosRegion::Unknown,
+            };
+            (version, region)
+        }
+        None => ("Unknown".to_string(), BiosRegion::Unknown),
+    };
+
+    BiosInfo {
+        region,
+        version,
+        hash,
+        known: false,
+        patchable: false,
+    }
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod bios_tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_size() {
+        assert!(matches!(
+            Bios::new(vec![0; 1024]),
+            Err(BiosError::InvalidSize(1024))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_image_version_string() {
+        let mut data = vec![0; BIOS_SIZE];
+        let version = b"System ROM Version 4.1 12/16/97 E";
+        data[0x7FF32..0x7FF32 + version.len()].copy_from_slice(version);
+        let bios = Bios::new(data).unwrap();
+        assert!(!bios.info().known);
+        assert_eq!(bios.info().region, BiosRegion::Europe);
+        assert_eq!(bios.info().version, "System ROM Version 4.1 12/16/97 E");
+    }
+
+    #[test]
+    fn test_patches_skip_unknown_images() {
+        let mut bios = Bios::new(vec![0; BIOS_SIZE]).unwrap();
+        bios.apply_patches(true, true);
+        assert_eq!(bios.read_word(0x18000), 0);
+    }
+
+    #[test]
+    fn test_patches_restore() {
+        let mut bios = Bios::new(vec![0; BIOS_SIZE]).unwrap();
+        bios.info.patchable = true;
+        bios.apply_patches(true, false);
+        assert_eq!(bios.read_word(0x18000), 0x03E00008);
+        assert_eq!(bios.read_word(0x6F0C), 0);
+        bios.apply_patches(false, true);
+        assert_eq!(bios.read_word(0x18000), 0);
+        assert_eq!(bios.read_word(0x6F0C), 0x24010001);
+    }
+
+    #[test]
+    fn test_openbios() {
+        let mut data = vec![0; BIOS_SIZE];
+        data[0x100..0x108].copy_from_slice(b"OpenBIOS");
+        let bios = Bios::new(data).unwrap();
+        assert!(bios.info().known);
+        assert_eq!(bios.info().version, "OpenBIOS");
+    }
+}
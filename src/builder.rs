@@ -0,0 +1,138 @@
+use crate::bios::{Bios, BiosError};
+use crate::bus::MainBus;
+use crate::cpu::R3000;
+use crate::gpu::{Gpu, RendererBackend};
+use crate::memory::{Memory, RamSize};
+use crate::scheduler::Scheduler;
+use crate::{PSXEmu, OVERCLOCK_ONE};
+
+/// Options for creating a `PSXEmu`. Anything left unset matches what `PSXEmu::new` does
+pub struct PSXEmuBuilder {
+    bios: Option<Vec<u8>>,
+    ram_size: RamSize,
+    fast_boot: bool,
+    force_tty: bool,
+    test_harness: bool,
+    deterministic: bool,
+    renderer: Option<Box<dyn RendererBackend>>,
+}
+
+impl PSXEmuBuilder {
+    pub(crate) fn new() -> Self {
+        Self {
+            bios: None,
+            ram_size: RamSize::TwoMegabytes,
+            fast_boot: false,
+            force_tty: false,
+            test_harness: false,
+            deterministic: false,
+            renderer: None,
+        }
+    }
+
+    /// BIOS image to boot from. Required
+    pub fn bios(mut self, data: Vec<u8>) -> Self {
+        self.bios = Some(data);
+        self
+    }
+
+    /// See `PSXEmu::set_ram_size`
+    pub fn ram_size(mut self, size: RamSize) -> Self {
+        self.ram_size = size;
+        self
+    }
+
+    /// See `PSXEmu::set_fast_boot`
+    pub fn fast_boot(mut self, enabled: bool) -> Self {
+        self.fast_boot = enabled;
+        self
+    }
+
+    /// See `PSXEmu::set_force_tty`
+    pub fn force_tty(mut self, enabled: bool) -> Self {
+        self.force_tty = enabled;
+        self
+    }
+
+    /// See `PSXEmu::set_test_harness`
+    pub fn test_harness(mut self, enabled: bool) -> Self {
+        self.test_harness = enabled;
+        self
+    }
+
+    /// See `PSXEmu::set_deterministic`
+    pub fn deterministic(mut self, enabled: bool) -> Self {
+        self.deterministic = enabled;
+        self
+    }
+
+    /// See `PSXEmu::set_renderer`
+    pub fn renderer(mut self, renderer: Box<dyn RendererBackend>) -> Self {
+        self.renderer = Some(renderer);
+        self
+    }
+
+    /// Creates the emulator and resets it, ready to run. Fails if no BIOS was given or it isn't a
+    /// valid size
+    pub fn build(self) -> Result<PSXEmu, BiosError> {
+        let bios = Bios::new(self.bios.ok_or(BiosError::Missing)?)?;
+        let mut gpu = Gpu::new();
+        if let Some(renderer) = self.renderer {
+            gpu.set_renderer(renderer);
+        }
+        let mut bus = MainBus::new(bios, Memory::with_size(self.ram_size), gpu);
+        bus.test_harness = self.test_harness;
+
+        let mut emu = PSXEmu {
+            r3000: R3000::new(),
+            main_bus: bus,
+            scheduler: Scheduler::new(),
+            halt_requested: false,
+            sw_breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            frame_count: 0,
+            timed_instructions: 0,
+            timing_start_cycle: 0,
+            exit_requested: false,
+            fast_boot: self.fast_boot,
+            force_tty: self.force_tty,
+            deterministic: self.deterministic,
+            executable: None,
+            cpu_clock_rate: OVERCLOCK_ONE,
+            instruction_budget: 0,
+        };
+        emu.reset();
+
+        Ok(emu)
+    }
+}
+
+#[cfg(test)]
+mod builder_tests {
+    use super::*;
+    use crate::bios::BIOS_SIZE;
+
+    #[test]
+    fn test_missing_bios() {
+        assert!(matches!(PSXEmu::builder().build(), Err(BiosError::Missing)));
+        assert!(matches!(
+            PSXEmu::builder().bios(vec![0; 1024]).build(),
+            Err(BiosError::InvalidSize(1024))
+        ));
+    }
+
+    #[test]
+    fn test_options_are_applied() {
+        let emu = PSXEmu::builder()
+            .bios(vec![0; BIOS_SIZE])
+            .ram_size(RamSize::EightMegabytes)
+            .fast_boot(true)
+            .test_harness(true)
+            .build()
+            .unwrap();
+        assert_eq!(emu.ram().len(), RamSize::EightMegabytes.bytes());
+        assert!(emu.fast_boot);
+        assert!(!emu.force_tty);
+        assert!(emu.main_bus.test_harness);
+    }
+}
@@ -1,16 +1,26 @@
-use log::{info, warn};
+use log::{info, trace, warn};
 
 use crate::bios::Bios;
+use crate::cache::{CacheControl, ICache};
 use crate::cdrom::CDDrive;
 use crate::controller::Controllers;
 use crate::dma::DMAState;
 use crate::gpu::Gpu;
 use crate::mdec::MDEC;
-use crate::memory::Memory;
+use crate::memory::{power_on_pattern, Memory, RamSize};
+use crate::mmio::*;
 use crate::spu::SPU;
-use crate::{LOGGING, Scheduler, TimerState};
+use crate::{Scheduler, TimerState};
 
+// Test interface, compatible with PCSX-Redux. Only active when test_harness is set
+/// Each byte written here is appended to the test log
+pub const TEST_LOG_ADDR: u32 = 0x1F80_2080;
+/// A half word written here requests an exit, with the value as the exit code
+pub const TEST_EXIT_ADDR: u32 = 0x1F80_2082;
+
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct MainBus {
+    #[cfg_attr(feature = "savestate", serde(skip, default = "Bios::empty"))]
     pub bios: Bios,
     pub memory: Memory,
     pub gpu: Gpu,
@@ -21,10 +31,24 @@ pub struct MainBus {
     scratchpad: Memory,
     pub(super) controllers: Controllers,
     pub(crate) mdec: MDEC,
-
+    cache_control: CacheControl,
+    icache: ICache,
+    ram_size_reg: u32,
+    post_code: u8,
 
     pub last_touched_addr: u32,
-    pub exit_requested: bool
+    pub exit_requested: bool,
+    /// Status the program passed to the PCSX exit command
+    pub exit_code: u16,
+    /// Enables the test interface. See `TEST_LOG_ADDR` and `TEST_EXIT_ADDR`
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    pub test_harness: bool,
+    /// Characters written to `TEST_LOG_ADDR` that haven't been taken yet
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    pub test_log: String,
+    /// Log every memory access
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    pub memory_logging: bool,
 }
 
 impl MainBus {
@@ -40,39 +64,165 @@ impl MainBus {
             controllers: Controllers::new(),
             mdec: MDEC::new(),
             timers: TimerState::new(),
+            cache_control: CacheControl(0),
+            icache: ICache::new(),
+            ram_size_reg: 0x00000B88,
+            post_code: 0,
 
             last_touched_addr: 0,
-            exit_requested: false
+            exit_requested: false,
+            exit_code: 0,
+            test_harness: false,
+            test_log: String::new(),
+            memory_logging: false,
+        }
+    }
+
+    /// Puts every device back in its power on state and clears RAM. The BIOS, the RAM size, the
+    /// disc and card, the pads and the host's settings are kept
+    pub fn reset(&mut self) {
+        self.memory.data.fill(0);
+        self.soft_reset();
+    }
+
+    /// Moves the BIOS, disc, card, renderer and the host's settings over from `old`, which this bus
+    /// replaces
+    #[cfg(feature = "savestate")]
+    pub(crate) fn keep_host_state(&mut self, old: MainBus) {
+        self.bios = old.bios;
+        self.gpu.keep_host_state(old.gpu);
+        self.cd_drive.keep_host_state(old.cd_drive);
+        self.controllers.keep_host_state(old.controllers);
+        self.test_harness = old.test_harness;
+        self.test_log = old.test_log;
+        self.memory_logging = old.memory_logging;
+    }
+
+    /// Fills RAM, the scratchpad, VRAM and SPU RAM with the deterministic power on pattern
+    pub(crate) fn fill_power_on_pattern(&mut self) {
+        for (byte, value) in self.memory.data.iter_mut().zip(power_on_pattern()) {
+            *byte = value;
+        }
+        for (byte, value) in self.scratchpad.data.iter_mut().zip(power_on_pattern()) {
+            *byte = value;
         }
+        let mut bytes = power_on_pattern();
+        self.gpu.fill_vram(std::iter::from_fn(|| Some(u16::from_le_bytes([bytes.next()?, bytes.next()?]))));
+        self.spu.fill_memory(power_on_pattern());
+    }
+
+    /// Resets every device like the console's reset button. RAM keeps its contents
+    pub fn soft_reset(&mut self) {
+        self.gpu.reset();
+        self.dma = DMAState::new();
+        self.spu = SPU::new();
+        self.cd_drive.reset();
+        self.timers = TimerState::new();
+        self.scratchpad = Memory::new_scratchpad();
+        self.controllers.power_on_reset();
+        self.mdec = MDEC::new();
+        self.cache_control = CacheControl(0);
+        self.icache = ICache::new();
+        self.ram_size_reg = 0x00000B88;
+        self.post_code = 0;
+
+        self.last_touched_addr = 0;
+        self.exit_requested = false;
+        self.exit_code = 0;
+        self.test_log.clear();
+    }
+
+    /// Swaps main memory for a new, zeroed memory of the given size
+    pub fn set_ram_size(&mut self, size: RamSize) {
+        self.memory = Memory::with_size(size);
     }
 
     pub fn peek_word(&self, og_addr: u32) -> u32 {
         let addr = translate_address(og_addr);
-        if addr <= 0x001f_ffff {
-            self.memory.read_word(addr)
+        if addr <= RAM_END {
+            self.memory.read_word(self.memory.mirror(addr))
         } else {
             0x42
         }
     }
 
+    /// Fetches an instruction word, going through the instruction cache when it is enabled.
+    /// Only KUSEG and KSEG0 are cached
+    pub fn fetch_instruction(&mut self, og_addr: u32, scheduler: &mut Scheduler) -> u32 {
+        if og_addr >= 0xA000_0000 || !self.cache_control.icache_enabled() {
+            return self.read_word(og_addr, scheduler);
+        }
+
+        let phys_addr = og_addr & 0x1FFF_FFFF;
+        if let Some(word) = self.icache.lookup(phys_addr) {
+            return word;
+        }
+
+        // Miss. Fill the line from the requested word to the end of the line
+        let mut fetched = 0;
+        let line_end = (og_addr & !0xF) + 0x10;
+        let mut addr = og_addr;
+        while addr < line_end {
+            let word = self.read_word(addr, scheduler);
+            if addr == og_addr {
+                fetched = word;
+            }
+            self.icache.fill(addr & 0x1FFF_FFFF, word);
+            addr += 4;
+        }
+        fetched
+    }
+
+    /// Word write while the cpu has the cache isolated (SR bit 16). These never reach memory
+    pub fn write_isolated_word(&mut self, og_addr: u32, word: u32) {
+        self.icache
+            .write_isolated(og_addr & 0x1FFF_FFFF, word, self.cache_control.tag_test_mode());
+    }
+
+    /// Latest boot stage code the BIOS wrote to the POST register
+    pub fn post_code(&self) -> u8 {
+        self.post_code
+    }
+
+    fn write_post(&mut self, value: u8) {
+        if value != self.post_code {
+            info!(target: "psx::bus", "POST code changed to {:#X}", value);
+        }
+        self.post_code = value;
+    }
+
+    /// Sets up the caches the way the BIOS leaves them for a game, with the code cache flushed. For
+    /// boots that skip the BIOS code doing it, and after writing code straight into RAM
+    pub(crate) fn set_boot_cache_state(&mut self) {
+        self.cache_control = CacheControl::AFTER_BIOS;
+        self.icache = ICache::new();
+    }
+
+    fn write_cache_control(&mut self, value: u32) {
+        info!(target: "psx::bus", "Cache control write {:#X}", value);
+        self.cache_control = CacheControl(value);
+    }
+
     pub fn read_word(&mut self, og_addr: u32, scheduler: &mut Scheduler) -> u32 {
         let addr = translate_address(og_addr);
         // if og_addr == 0x800c14a8{
         //     return 3;
         // }
         let value = match addr {
-            0x0..=0x001f_ffff => self.memory.read_word(addr),
-            0x1f801810 => self.gpu.read_word_gp0(),
-            0x1f801814 => self.gpu.read_status_register(),
-            0x1F80101C => 0x00070777, //Expansion 2 delay/size
-            0x1F801080..=0x1F8010F4 => self.dma.read_word(addr),
-            0x1F800000..=0x1F8003FF => self.scratchpad.read_word(addr - 0x1F800000),
-            0x1F801014 => 0x200931E1, //SPU_DELAY
-            0x1F801060 => 0x00000B88, //RAM_SIZE
-            0x1F801820..=0x1F801824 => self.mdec.bus_read_word(addr),
-            0x1fc0_0000..=0x1fc7_ffff => self.bios.read_word(addr - 0x1fc0_0000),
-            0x1F802000..=0x1F802080 => 0, // Expansion 2
-            0x1F801100..=0x1F801128 => self.timers.read_word(addr & 0x1fffffff, scheduler),
+            RAM_BASE..=RAM_END => self.memory.read_word(self.memory.mirror(addr)),
+            GPU_GP0 => self.gpu.read_word_gp0(),
+            GPU_GP1 => self.gpu.read_status_register(),
+            EXPANSION_2_DELAY => 0x00070777,
+            DMA_BASE..=DMA_END => self.dma.read_word(addr),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END if self.cache_control.scratchpad_enabled() => self.scratchpad.read_word(addr - SCRATCHPAD_BASE),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END => 0, // Scratchpad disabled
+            SPU_DELAY => 0x200931E1,
+            RAM_SIZE => self.ram_size_reg,
+            MDEC_COMMAND | MDEC_STATUS => self.mdec.bus_read_word(addr),
+            BIOS_BASE..=BIOS_END => self.bios.read_word(addr - BIOS_BASE),
+            EXPANSION_2_BASE..=EXPANSION_2_END => 0,
+            TIMER_BASE..=TIMER_END => self.timers.read_word(addr, scheduler),
+            CACHE_CONTROL => self.cache_control.0,
             _ => panic!(
                 "Invalid word read at address {:#X}! This address is not mapped to any device.",
                 addr
@@ -81,8 +231,8 @@ impl MainBus {
         // if addr > 0x1f_ffff && !(0x1F800000..=0x1F8003FF).contains(&addr) && !(0x1fc0_0000..=0x1fc7_ffff).contains(&addr) {
         //     println!("Read IO addr {:#X} value {:#X}", addr, value);
         // }
-        if unsafe { LOGGING } {
-            println!("Loaded {:#X} from addr {:#X}", value, addr)
+        if self.memory_logging {
+            trace!(target: "psx::mem", "Loaded {:#X} from addr {:#X}", value, addr)
         };
         value
     }
@@ -96,29 +246,35 @@ impl MainBus {
         // }
 
         match addr {
-            0x1F802002 => info!("Serial: {}", word),
-            0x1F802023 => info!("DUART A: {}", word),
-            0x1F80202B => info!("DUART B: {}", word),
-            0x1F801050 => info!("SIO: {}", word),
-            0x0..=0x001f_ffff => self.memory.write_word(addr, word), //KUSEG
-            0x1F801000 => info!("Expansion 1 base write"),
-            0x1F801004 => info!("Expansion 2 base write"),
-            0x1F801008 => info!("Expansion 1 delay/size write"),
-            0x1F801010 => info!("BIOS ROM Control WORD write"),
-            0x1F801060 => info!("RAM SIZE WORD write {:#X}", word),
-            0x1F801020 => info!("COM_DELAY WORD write"),
-            0x1F801014 => info!("SPU_DELAY size write"),
-            0x1F801018 => info!("CDROM_DELAY size write"),
-            0x1F80101C => info!("Expansion 2 delay/size write"),
-            0x1F801080..=0x1F8010F4 => self.dma.write_word(addr, word),
-            0x1F80100C => info!("Expansion 3 Delay/size write"),
-            0x1F801810 => self.gpu.send_gp0_command(word),
-            0x1F801814 => self.gpu.send_gp1_command(word),
-            0x1F801820..=0x1F801824 => self.mdec.bus_write_word(addr, word),
-            0x1F800000..=0x1F8003FF => self.scratchpad.write_word(addr - 0x1F800000, word),
-            0x1F801100..=0x1F801128 => self.timers.write_word(addr & 0x1fffffff, word, scheduler),
+            EXPANSION_2_SERIAL => info!(target: "psx::bus", "Serial: {}", word),
+            DUART_A => info!(target: "psx::bus", "DUART A: {}", word),
+            DUART_B => info!(target: "psx::bus", "DUART B: {}", word),
+            SIO_BASE => info!(target: "psx::bus", "SIO: {}", word),
+            RAM_BASE..=RAM_END => self.memory.write_word(self.memory.mirror(addr), word), //KUSEG
+            EXPANSION_1_BASE_ADDR => info!(target: "psx::bus", "Expansion 1 base write"),
+            EXPANSION_2_BASE_ADDR => info!(target: "psx::bus", "Expansion 2 base write"),
+            EXPANSION_1_DELAY => info!(target: "psx::bus", "Expansion 1 delay/size write"),
+            BIOS_DELAY => info!(target: "psx::bus", "BIOS ROM Control WORD write"),
+            RAM_SIZE => {
+                info!(target: "psx::bus", "RAM SIZE WORD write {:#X}", word);
+                self.ram_size_reg = word;
+            }
+            COM_DELAY => info!(target: "psx::bus", "COM_DELAY WORD write"),
+            SPU_DELAY => info!(target: "psx::bus", "SPU_DELAY size write"),
+            CDROM_DELAY => info!(target: "psx::bus", "CDROM_DELAY size write"),
+            EXPANSION_2_DELAY => info!(target: "psx::bus", "Expansion 2 delay/size write"),
+            DMA_BASE..=DMA_END => self.dma.write_word(addr, word),
+            EXPANSION_3_DELAY => info!(target: "psx::bus", "Expansion 3 Delay/size write"),
+            GPU_GP0 => self.gpu.send_gp0_command(word),
+            GPU_GP1 => self.gpu.send_gp1_command(word),
+            MDEC_COMMAND | MDEC_STATUS => self.mdec.bus_write_word(addr, word),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END if self.cache_control.scratchpad_enabled() => self.scratchpad.write_word(addr - SCRATCHPAD_BASE, word),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END => (), // Scratchpad disabled
+            TIMER_BASE..=TIMER_END => self.timers.write_word(addr, word, scheduler),
+            EXPANSION_2_BASE..=EXPANSION_2_END => (),
             //0x1f80_1000..=0x1f80_2fff => warn!("Something tried to write to the hardware control registers. These are not currently emulated. The address was {:#X}. Value {:#X}", addr, word),
-            0x1FFE0000..=0x1FFE0200 => warn!("Something tried to write to the cache control registers. These are not currently emulated. The address was {:#X}", addr),
+            CACHE_CONTROL => self.write_cache_control(word),
+            CACHE_CONTROL_BASE..=CACHE_CONTROL_END => warn!(target: "psx::bus", "Wrote unhandled address {:#X} near the cache control register", addr),
             _ => {
                 panic!(
                     "Invalid word write at address {:#X}! This address is not mapped to any device.",
@@ -131,23 +287,25 @@ impl MainBus {
     pub fn read_half_word(&mut self, og_addr: u32, scheduler: &mut Scheduler) -> u16 {
         let addr = translate_address(og_addr);
         let val = match addr {
-            0x1F801070 => {
+            IO_ISTAT => {
                 panic!("Tried to read i_status half");
             },
-            0x0..=0x001f_ffff => self.memory.read_half_word(addr),
-            0x1F801C00..=0x1F801E80 => self.spu.read_half_word(addr),
-            0x1F800000..=0x1F8003FF => self.scratchpad.read_half_word(addr - 0x1F800000),
-            0x1F80_1040..=0x1F80_104E => self.controllers.read_half_word(addr),
-            0x1fc0_0000..=0x1fc7_ffff => self.bios.read_half_word(addr - 0x1fc0_0000),
-            0x1f801050..=0x1f80105e => 0xBEEF, //SIO registers
-            0x1F801100..=0x1F801128 => self.timers.read_half_word(addr & 0x1fffffff, scheduler),
-            _ => {println!("Invalid half word read at address {:#X}! This address is not mapped to any device.", addr); 0}
+            RAM_BASE..=RAM_END => self.memory.read_half_word(self.memory.mirror(addr)),
+            SPU_BASE..=SPU_END => self.spu.read_half_word(addr),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END if self.cache_control.scratchpad_enabled() => self.scratchpad.read_half_word(addr - SCRATCHPAD_BASE),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END => 0, // Scratchpad disabled
+            JOY_BASE..=JOY_END => self.controllers.read_half_word(addr),
+            BIOS_BASE..=BIOS_END => self.bios.read_half_word(addr - BIOS_BASE),
+            SIO_BASE..=SIO_END => 0xBEEF,
+            DMA_BASE..=DMA_END => self.dma.read_half_word(addr),
+            TIMER_BASE..=TIMER_END => self.timers.read_half_word(addr, scheduler),
+            _ => {warn!(target: "psx::bus", "Invalid half word read at address {:#X}! This address is not mapped to any device.", addr); 0}
         };
         // if addr > 0x1f_ffff && !(0x1F800000..=0x1F8003FF).contains(&addr) && !(0x1fc0_0000..=0x1fc7_ffff).contains(&addr) {
         //     println!("Read IO hw addr {:#X} value {:#X}", addr, val);
         // }
-        if unsafe { LOGGING } {
-            println!("Loaded {:#X} from addr {:#X}", val, addr)
+        if self.memory_logging {
+            trace!(target: "psx::mem", "Loaded {:#X} from addr {:#X}", val, addr)
         };
         val
     }
@@ -165,19 +323,23 @@ impl MainBus {
         // }
 
         match addr {
-            0x1F802002 => info!("Serial: {}", value),
-            0x1F802023 => info!("DUART A: {}", value),
-            0x1F80202B => info!("DUART B: {}", value),
-            0x1F801050..=0x1f80105e => info!("SIO: {}", value),
-            0x0..=0x001f_ffff => self.memory.write_half_word(addr, value), //KUSEG
-            0x1F801C00..=0x1F801E80 => self.spu.write_half_word(addr, value),
-            0x1F800000..=0x1F8003FF => self.scratchpad.write_half_word(addr - 0x1F800000, value),
-            0x1F80_1040..=0x1F80_104E => self.controllers.write_half_word(addr, value),
-            0x1F801100..=0x1F801128 => self.timers.write_half_word(addr & 0x1fffffff, value, scheduler),
-            0x1F80_2082 => {
+            EXPANSION_2_SERIAL => info!(target: "psx::bus", "Serial: {}", value),
+            DUART_A => info!(target: "psx::bus", "DUART A: {}", value),
+            DUART_B => info!(target: "psx::bus", "DUART B: {}", value),
+            SIO_BASE..=SIO_END => info!(target: "psx::bus", "SIO: {}", value),
+            RAM_BASE..=RAM_END => self.memory.write_half_word(self.memory.mirror(addr), value), //KUSEG
+            SPU_BASE..=SPU_END => self.spu.write_half_word(addr, value, scheduler),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END if self.cache_control.scratchpad_enabled() => self.scratchpad.write_half_word(addr - SCRATCHPAD_BASE, value),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END => (), // Scratchpad disabled
+            JOY_BASE..=JOY_END => self.controllers.write_half_word(addr, value),
+            DMA_BASE..=DMA_END => self.dma.write_half_word(addr, value),
+            TIMER_BASE..=TIMER_END => self.timers.write_half_word(addr, value, scheduler),
+            TEST_EXIT_ADDR if self.test_harness => {
                 self.exit_requested = true;
-                println!("Exit requested via PCSX extension command");
+                self.exit_code = value;
+                info!(target: "psx::bus", "Exit requested via PCSX extension command with status {}", value);
             }, // PCSX extension exit command
+            EXPANSION_2_BASE..=EXPANSION_2_END => (),
             //0x1f801050..=0x1f80105e => (), //SIO registers
             //0x1F80_1000..=0x1F80_2000 => warn!("Something tried to half word write to the I/O ports. This is not currently emulated. The address was {:#X}. value was {:#X}", addr, value),
             _ => panic!("Invalid half word write at address {:#X}! This address is not mapped to any device.", addr)
@@ -188,25 +350,26 @@ impl MainBus {
         let addr = translate_address(og_addr);
 
         let val = match addr {
-            0x1F801070 => {
-                warn!("Tried to read i_status word");
+            IO_ISTAT => {
+                warn!(target: "psx::bus", "Tried to read i_status word");
                 0
             }
-            0x1F801074 => {
-                warn!("Tried to read i_mask byte");
+            IO_IMASK => {
+                warn!(target: "psx::bus", "Tried to read i_mask byte");
                 0
             }
 
-            0x0..=0x001f_ffff => self.memory.read_byte(addr), //KUSEG
-            0x1F00_0000..=0x1f00_FFFF => {
+            RAM_BASE..=RAM_END => self.memory.read_byte(self.memory.mirror(addr)), //KUSEG
+            EXPANSION_1_BASE..=EXPANSION_1_END => {
                 //println!("Something tried to read the parallel port. This is not currently emulated, so a 0 was returned. The address was {:#X}", addr);
                 0xBE
             }
-            0x1fc0_0000..=0x1fc7_ffff => self.bios.read_byte(addr - 0x1fc0_0000),
-            0x1F801800..=0x1F801803 => self.cd_drive.read_byte(addr), //CDROM
-            0x1F80_1040..=0x1F80_104E => self.controllers.read_byte(addr),
-            0x1F800000..=0x1F8003FF => self.scratchpad.read_byte(addr - 0x1F800000),
-            0x1F801080..=0x1F8010F7 => self.dma.read_byte(addr),
+            BIOS_BASE..=BIOS_END => self.bios.read_byte(addr - BIOS_BASE),
+            CDROM_BASE..=CDROM_END => self.cd_drive.read_byte(addr),
+            JOY_BASE..=JOY_END => self.controllers.read_byte(addr),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END if self.cache_control.scratchpad_enabled() => self.scratchpad.read_byte(addr - SCRATCHPAD_BASE),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END => 0, // Scratchpad disabled
+            DMA_BASE..=DMA_END => self.dma.read_byte(addr),
 
             // _ => {
             //     panic!(
@@ -220,8 +383,8 @@ impl MainBus {
         // if addr > 0x1f_ffff && !(0x1F800000..=0x1F8003FF).contains(&addr) && !(0x1fc0_0000..=0x1fc7_ffff).contains(&addr) {
         //     println!("Read IO byte addr {:#X} value {:#X}", addr, val);
         // }
-        if unsafe { LOGGING } {
-            println!("Loaded {:#X} from addr {:#X}", val, addr)
+        if self.memory_logging {
+            trace!(target: "psx::mem", "Loaded {:#X} from addr {:#X}", val, addr)
         };
         val
     }
@@ -235,16 +398,19 @@ impl MainBus {
         // }
 
         match addr {
-            0x0..=0x001f_ffff => self.memory.write_byte(addr, value), //KUSEG
-            0x1F801800..=0x1F801803 => self.cd_drive.write_byte(addr, value, scheduler), //CDROM
-            0x1F802002 => info!("Serial: {}", value),
-            0x1F802023 => info!("DUART A: {}", value),
-            0x1F80202B => info!("DUART B: {}", value),
-            0x1F801050 => info!("SIO: {}", value),
-            0x1F802000..=0x1F803000 => (), //Expansion port 2
-            0x1F801040 => self.controllers.write_byte(addr, value, scheduler),
-            0x1F800000..=0x1F8003FF => self.scratchpad.write_byte(addr - 0x1F800000, value),
-            0x1F801080..=0x1F8010F7 => self.dma.write_byte(addr, value),
+            RAM_BASE..=RAM_END => self.memory.write_byte(self.memory.mirror(addr), value), //KUSEG
+            CDROM_BASE..=CDROM_END => self.cd_drive.write_byte(addr, value, scheduler),
+            EXPANSION_2_SERIAL => info!(target: "psx::bus", "Serial: {}", value),
+            DUART_A => info!(target: "psx::bus", "DUART A: {}", value),
+            DUART_B => info!(target: "psx::bus", "DUART B: {}", value),
+            SIO_BASE => info!(target: "psx::bus", "SIO: {}", value),
+            POST => self.write_post(value),
+            TEST_LOG_ADDR if self.test_harness => self.test_log.push(value as char),
+            EXPANSION_2_BASE..=EXPANSION_2_END => (),
+            JOY_DATA => self.controllers.write_byte(addr, value, scheduler),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END if self.cache_control.scratchpad_enabled() => self.scratchpad.write_byte(addr - SCRATCHPAD_BASE, value),
+            SCRATCHPAD_BASE..=SCRATCHPAD_END => (), // Scratchpad disabled
+            DMA_BASE..=DMA_END => self.dma.write_byte(addr, value),
             _ => panic!(
                 "Invalid byte write at address {:#X}! This address is not mapped to any device.",
                 addr
@@ -254,9 +420,138 @@ impl MainBus {
 }
 
 fn translate_address(raw_addr: u32) -> u32 {
-    let mut addr = raw_addr & 0x1fffffff;
-    if addr < 0x7FFFFF {
-        addr = addr & 0x1FFFFF;
+    // Ram mirroring is handled by Memory::mirror, so this only strips the segment bits
+    raw_addr & 0x1fffffff
+}
+
+#[cfg(test)]
+mod bus_tests {
+    use super::*;
+    use crate::cpu::R3000;
+
+    fn test_bus() -> MainBus {
+        MainBus::new(Bios::new(vec![0; 0x80000]).unwrap(), Memory::new(), Gpu::new())
+    }
+
+    #[test]
+    fn test_ram_mirroring() {
+        let mut bus = test_bus();
+        let mut scheduler = Scheduler::new();
+        bus.write_word(0x00000100, 0x12345678, &mut scheduler);
+        assert_eq!(bus.read_word(0x00200100, &mut scheduler), 0x12345678);
+        assert_eq!(bus.read_word(0x80600100, &mut scheduler), 0x12345678);
+        assert_eq!(bus.read_byte(0xA0400101), 0x56);
+    }
+
+    #[test]
+    fn test_eight_megabyte_ram() {
+        let mut bus = test_bus();
+        let mut scheduler = Scheduler::new();
+        bus.set_ram_size(RamSize::EightMegabytes);
+        bus.write_word(0x00000100, 0x12345678, &mut scheduler);
+        bus.write_word(0x00200100, 0xCAFEBABE, &mut scheduler);
+        assert_eq!(bus.read_word(0x00000100, &mut scheduler), 0x12345678);
+        assert_eq!(bus.read_word(0x00200100, &mut scheduler), 0xCAFEBABE);
+    }
+
+    #[test]
+    fn test_isolated_store_goes_to_icache() {
+        let mut bus = test_bus();
+        let mut scheduler = Scheduler::new();
+        let mut cpu = R3000::new();
+        bus.write_word(CACHE_CONTROL, 0x800, &mut scheduler);
+        bus.write_word(0x100, 0x1111, &mut scheduler);
+        assert_eq!(bus.fetch_instruction(0x8000_0100, &mut scheduler), 0x1111);
+
+        // SR bit 16
+        cpu.cop0.write_reg(12, 1 << 16);
+        cpu.write_bus_word(0x8000_0100, 0x2222, &mut bus, &mut scheduler);
+        assert_eq!(bus.read_word(0x100, &mut scheduler), 0x1111);
+        assert_eq!(bus.fetch_instruction(0x8000_0100, &mut scheduler), 0x2222);
+
+        // Tag test mode invalidates the line, so the next fetch goes back to RAM
+        bus.write_word(CACHE_CONTROL, 0x804, &mut scheduler);
+        cpu.write_bus_word(0x8000_0100, 0, &mut bus, &mut scheduler);
+        assert_eq!(bus.fetch_instruction(0x8000_0100, &mut scheduler), 0x1111);
+    }
+
+    #[test]
+    fn test_scratchpad_enable() {
+        let mut bus = test_bus();
+        let mut scheduler = Scheduler::new();
+        bus.write_word(CACHE_CONTROL, 0x88, &mut scheduler);
+        bus.write_word(SCRATCHPAD_BASE, 0x1234_5678, &mut scheduler);
+        assert_eq!(bus.read_word(SCRATCHPAD_BASE, &mut scheduler), 0x1234_5678);
+
+        // Both enable bits are needed. While disabled, reads see nothing and writes are dropped
+        bus.write_word(CACHE_CONTROL, 0x08, &mut scheduler);
+        assert_eq!(bus.read_word(SCRATCHPAD_BASE, &mut scheduler), 0);
+        assert_eq!(bus.read_byte(SCRATCHPAD_BASE), 0);
+        bus.write_word(SCRATCHPAD_BASE, 0xFFFF_FFFF, &mut scheduler);
+
+        bus.write_word(CACHE_CONTROL, 0x88, &mut scheduler);
+        assert_eq!(bus.read_word(SCRATCHPAD_BASE, &mut scheduler), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_boot_cache_state() {
+        let mut bus = test_bus();
+        let mut scheduler = Scheduler::new();
+        bus.write_word(CACHE_CONTROL, 0x800, &mut scheduler);
+        bus.write_word(0x100, 0x1111, &mut scheduler);
+        assert_eq!(bus.fetch_instruction(0x8000_0100, &mut scheduler), 0x1111);
+        bus.memory.write_word(0x100, 0x2222);
+
+        bus.set_boot_cache_state();
+        assert_eq!(bus.fetch_instruction(0x8000_0100, &mut scheduler), 0x2222);
+        bus.write_word(SCRATCHPAD_BASE, 0x1234_5678, &mut scheduler);
+        assert_eq!(bus.read_word(SCRATCHPAD_BASE, &mut scheduler), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_post_register() {
+        let mut bus = test_bus();
+        let mut scheduler = Scheduler::new();
+        bus.write_byte(0xBF802041, 0x7, &mut scheduler);
+        assert_eq!(bus.post_code(), 0x7);
+        bus.write_word(0x1F802040, 0, &mut scheduler);
+        bus.write_half_word(0x1F802040, 0, &mut scheduler);
+        assert_eq!(bus.post_code(), 0x7);
+    }
+
+    #[test]
+    fn test_ram_size_register() {
+        let mut bus = test_bus();
+        let mut scheduler = Scheduler::new();
+        assert_eq!(bus.read_word(0x1F801060, &mut scheduler), 0xB88);
+        bus.write_word(0x1F801060, 0x888, &mut scheduler);
+        assert_eq!(bus.read_word(0x1F801060, &mut scheduler), 0x888);
+    }
+
+    #[test]
+    fn test_dma_register_widths() {
+        let mut bus = test_bus();
+        let mut scheduler = Scheduler::new();
+        // GPU channel MADR, which only keeps 24 bits
+        bus.write_word(0x1F80_10A0, 0xFF12_3456, &mut scheduler);
+        assert_eq!(bus.read_half_word(0x1F80_10A2, &mut scheduler), 0x0012);
+        assert_eq!(bus.read_byte(0x1F80_10A1), 0x34);
+        bus.write_half_word(0x1F80_10A0, 0xBEEF, &mut scheduler);
+        bus.write_byte(0x1F80_10A2, 0x56, &mut scheduler);
+        assert_eq!(bus.read_word(0x1F80_10A0, &mut scheduler), 0x0056_BEEF);
+    }
+
+    #[test]
+    fn test_timer_register_widths() {
+        let mut bus = test_bus();
+        let mut scheduler = Scheduler::new();
+        for target in [TIMER_0_TARGET, TIMER_1_TARGET, TIMER_2_TARGET] {
+            bus.write_half_word(target, 0x1234, &mut scheduler);
+            // Nothing lives in the upper half
+            bus.write_half_word(target + 2, 0xFFFF, &mut scheduler);
+            assert_eq!(bus.read_word(target, &mut scheduler), 0x1234);
+            assert_eq!(bus.read_half_word(target, &mut scheduler), 0x1234);
+            assert_eq!(bus.read_half_word(target + 2, &mut scheduler), 0);
+        }
     }
-    addr
 }
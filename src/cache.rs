@@ -0,0 +1,139 @@
+use bit_field::BitField;
+
+
+const ICACHE_LINES: usize = 256;
+const WORDS_PER_LINE: usize = 4;
+
+/// Cache control register (0xFFFE0130)
+///
+/// 0-1 Unknown
+/// 2   Tag test mode (isolated writes invalidate cache lines instead of writing data)
+/// 3   Scratchpad enable 1
+/// 4-6 Unknown
+/// 7   Scratchpad enable 2 (scratchpad is only mapped when both bits 3 and 7 are set)
+/// 8-10 Unknown
+/// 11  Code cache enable
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct CacheControl(pub u32);
+
+impl CacheControl {
+    /// What the BIOS leaves in the register before starting a game. Scratchpad and code cache on
+    pub const AFTER_BIOS: CacheControl = CacheControl(0x0001_E988);
+
+    pub fn tag_test_mode(&self) -> bool {
+        self.0.get_bit(2)
+    }
+
+    pub fn scratchpad_enabled(&self) -> bool {
+        self.0.get_bit(3) && self.0.get_bit(7)
+    }
+
+    pub fn icache_enabled(&self) -> bool {
+        self.0.get_bit(11)
+    }
+}
+
+/// 4KB direct mapped instruction cache. 256 lines of 4 words each.
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct ICache {
+    #[cfg_attr(feature = "savestate", serde(with = "serde_big_array::BigArray"))]
+    tags: [u32; ICACHE_LINES],
+    #[cfg_attr(feature = "savestate", serde(with = "serde_big_array::BigArray"))]
+    valid: [u8; ICACHE_LINES],
+    #[cfg_attr(feature = "savestate", serde(with = "serde_big_array::BigArray"))]
+    data: [u32; ICACHE_LINES * WORDS_PER_LINE],
+}
+
+impl ICache {
+    pub fn new() -> Self {
+        Self {
+            tags: [0; ICACHE_LINES],
+            valid: [0; ICACHE_LINES],
+            data: [0; ICACHE_LINES * WORDS_PER_LINE],
+        }
+    }
+
+    /// Returns the cached word at the given physical address, or None on a miss
+    pub fn lookup(&self, addr: u32) -> Option<u32> {
+        let line = line_index(addr);
+        let word = word_index(addr);
+        if self.tags[line] == tag(addr) && self.valid[line].get_bit(word) {
+            Some(self.data[line * WORDS_PER_LINE + word])
+        } else {
+            None
+        }
+    }
+
+    /// Stores a word fetched from memory and marks it valid. Switching tags invalidates the rest of the line
+    pub fn fill(&mut self, addr: u32, value: u32) {
+        let line = line_index(addr);
+        let word = word_index(addr);
+        if self.tags[line] != tag(addr) {
+            self.tags[line] = tag(addr);
+            self.valid[line] = 0;
+        }
+        self.valid[line].set_bit(word, true);
+        self.data[line * WORDS_PER_LINE + word] = value;
+    }
+
+    /// Handles a word write while the cache is isolated.
+    /// In tag test mode the write replaces the line's tag and clears its valid bits,
+    /// which is how the BIOS flushes the cache. Otherwise the data word itself is written.
+    pub fn write_isolated(&mut self, addr: u32, value: u32, tag_test_mode: bool) {
+        let line = line_index(addr);
+        if tag_test_mode {
+            self.tags[line] = tag(addr);
+            self.valid[line] = 0;
+        } else {
+            self.data[line * WORDS_PER_LINE + word_index(addr)] = value;
+        }
+    }
+}
+
+fn tag(addr: u32) -> u32 {
+    addr & 0xFFFF_F000
+}
+
+fn line_index(addr: u32) -> usize {
+    ((addr >> 4) & 0xFF) as usize
+}
+
+fn word_index(addr: u32) -> usize {
+    ((addr >> 2) & 0x3) as usize
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use super::*;
+
+    #[test]
+    fn test_control_bits() {
+        assert!(!CacheControl(0x8).scratchpad_enabled());
+        assert!(CacheControl(0x88).scratchpad_enabled());
+        assert!(CacheControl(0x1E988).icache_enabled());
+        assert!(!CacheControl(0x1E988).tag_test_mode());
+        assert!(CacheControl(0x804).tag_test_mode());
+    }
+
+    #[test]
+    fn test_fill_and_lookup() {
+        let mut cache = ICache::new();
+        assert_eq!(cache.lookup(0x1000), None);
+        cache.fill(0x1004, 0xDEADBEEF);
+        assert_eq!(cache.lookup(0x1004), Some(0xDEADBEEF));
+        assert_eq!(cache.lookup(0x1000), None);
+        // Same line, different tag
+        assert_eq!(cache.lookup(0x2004), None);
+    }
+
+    #[test]
+    fn test_tag_test_invalidates() {
+        let mut cache = ICache::new();
+        cache.fill(0x1010, 0x1234);
+        cache.write_isolated(0x10, 0, false);
+        assert_eq!(cache.lookup(0x1010), Some(0));
+        cache.write_isolated(0x10, 0, true);
+        assert_eq!(cache.lookup(0x1010), None);
+    }
+}
@@ -1,8 +1,26 @@
-use super::{disc::dec_to_bcd, CDDrive, DriveState, IntCause, MotorState, Packet};
-use crate::cdrom::{disc::DiscIndex, DriveSpeed};
+use super::{disc::{bcd_to_dec, dec_to_bcd}, CDDrive, DriveState, IntCause, MotorState, Packet};
+use super::{SPEED_CHANGE_CYCLES, SPIN_UP_CYCLES};
+use crate::cdrom::{disc::{DiscIndex, SECTORS_PER_SECOND}, DriveSpeed};
+use crate::{CpuCycles, ScheduleTarget, Scheduler};
+use bit_field::BitField;
 
 pub(super) const AVG_FIRST_RESPONSE_TIME: u32 = 0xc4e1;
 
+// Error codes, sent after the stat byte in INT5 responses
+pub(super) const ERROR_SEEK_FAILED: u8 = 0x04;
+pub(super) const ERROR_INVALID_SUB_FUNCTION: u8 = 0x10;
+/// Shares its code with ERROR_INVALID_SUB_FUNCTION
+pub(super) const ERROR_INVALID_PARAMETER: u8 = 0x10;
+pub(super) const ERROR_WRONG_PARAMETER_COUNT: u8 = 0x20;
+pub(super) const ERROR_INVALID_COMMAND: u8 = 0x40;
+pub(super) const ERROR_NO_DISC: u8 = 0x80;
+/// The drive hasn't read what the command asks for yet. Shares its code with ERROR_NO_DISC
+pub(super) const ERROR_CANNOT_RESPOND: u8 = 0x80;
+
+const STAT_ERROR: u8 = 0x01;
+pub(super) const STAT_MOTOR_ON: u8 = 0x02;
+const STAT_SEEK_ERROR: u8 = 0x04;
+
 pub(super) fn get_bios_date(state: &mut CDDrive) -> Packet {
     Packet {
         internal_id: state.next_packet_id(),
@@ -29,6 +47,18 @@ fn stat(state: &mut CDDrive, command: u8) -> Packet {
     }
 }
 
+/// An INT5 response, for commands the drive rejects
+pub(super) fn error(state: &mut CDDrive, command: u8, code: u8) -> Packet {
+    let mut packet = stat(state, command);
+    let mut stat = state.get_stat() | STAT_ERROR;
+    if code == ERROR_SEEK_FAILED {
+        stat |= STAT_SEEK_ERROR;
+    }
+    packet.cause = IntCause::INT5;
+    packet.response = vec![stat, code];
+    packet
+}
+
 pub(super) fn get_stat(state: &mut CDDrive) -> Packet {
     stat(state, 0x19)
 }
@@ -81,7 +111,7 @@ pub(super) fn set_loc(state: &mut CDDrive, minutes: u8, seconds: u8, frames: u8)
     state.next_seek_target =
         DiscIndex::new_bcd(minutes as usize, seconds as usize, frames as usize);
     state.seek_complete = false;
-    state.data_queue.clear();
+    state.sector_buffer.clear();
     //println!("set_loc to {}", state.next_seek_target);
 
     //println!("set_loc to {:?}, total sectors: {}", state.seek_target, state.seek_target.as_address() / BYTES_PER_SECTOR as u32);
@@ -108,7 +138,15 @@ pub(super) fn seek_data(state: &mut CDDrive) -> Packet {
     first_response
 }
 
-pub(super) fn set_mode(state: &mut CDDrive, mode: u8) -> Packet {
+// The sector size bit is latched per sector as they are read, so it only applies to the next sector.
+// Changing speed spins the motor up or down, and no sectors are delivered until that's done
+pub(super) fn set_mode(state: &mut CDDrive, mode: u8, scheduler: &mut Scheduler) -> Packet {
+    if (state.drive_mode ^ mode).get_bit(7) {
+        scheduler.invalidate_all_events_of_target(ScheduleTarget::CDSpeedChange);
+        state.speed_change = Some(
+            scheduler.schedule_event(ScheduleTarget::CDSpeedChange, CpuCycles(SPEED_CHANGE_CYCLES)),
+        );
+    }
     state.drive_mode = mode;
     stat(state, 0xE)
 }
@@ -120,7 +158,7 @@ pub(super) fn read_with_retry(state: &mut CDDrive) -> Packet {
     let mut initial_response = stat(state, 0x6);
     state.drive_state = DriveState::Read;
     state.read_enabled = true;
-    state.data_queue.clear();
+    state.sector_buffer.clear();
 
     // let cycles = match state.drive_speed() {
     //     DriveSpeed::Single => 0x686da,
@@ -129,21 +167,10 @@ pub(super) fn read_with_retry(state: &mut CDDrive) -> Packet {
 
     let cycles = 0x35CA8;
 
-    if !state.seek_complete {
-        state.read_offset = 0;
-        state.current_seek_target = state.next_seek_target.clone();
-        state.seek_complete = true;
-    }
+    finish_setloc_seek(state);
 
-    let response_packet = Packet {
-        internal_id: state.next_packet_id(),
-        cause: IntCause::INT1,
-        response: vec![state.get_stat()],
-        execution_cycles: cycles,
-        extra_response: None,
-        command: 0x6,
-        need_irq: false,
-    };
+    let mut response_packet = state.sector_packet(0x6);
+    response_packet.execution_cycles = cycles;
     initial_response.execution_cycles = 42430;
     initial_response.extra_response = Some(Box::new(response_packet));
 
@@ -184,21 +211,18 @@ pub(super) fn pause_read(state: &mut CDDrive) -> Packet {
 }
 
 pub(super) fn demute(state: &mut CDDrive) -> Packet {
+    state.muted = false;
     stat(state, 0xC)
 }
 
-// Get number of tracks in session
+// Get first and last track numbers, in BCD
 // Assumes theres only one session
 pub(super) fn get_tn(state: &mut CDDrive) -> Packet {
     let first_track = 0x1;
-    let last_track = dec_to_bcd(
-        state
-            .disc
-            .as_ref()
-            .expect("Tried to read non-existent disc!")
-            .track_count()
-            + 1,
-    );
+    let last_track = match state.disc.as_ref() {
+        Some(disc) => dec_to_bcd(disc.track_count()),
+        None => return error(state, 0x13, ERROR_NO_DISC),
+    };
 
     let mut initial_response = stat(state, 0x13);
 
@@ -208,32 +232,114 @@ pub(super) fn get_tn(state: &mut CDDrive) -> Packet {
     initial_response
 }
 
-// Get starting index of given track
-// Because I'm lazy I'm just going to return the start of the first track, 00:02
-// In practice this will probably send code instead of music to the SPU, and play some crazy audio
-// Future colin, you have been warned
-pub(super) fn get_td(state: &mut CDDrive, _track: u8) -> Packet {
+// Get the minutes and seconds the given BCD track number starts at, in BCD
+// Track 0 gives the lead-out, which is the total length of the disc
+pub(super) fn get_td(state: &mut CDDrive, track: u8) -> Packet {
+    let start = match state.disc.as_ref() {
+        Some(disc) => disc.track_start(bcd_to_dec(track as usize)),
+        None => return error(state, 0x14, ERROR_NO_DISC),
+    };
+    let start = match start {
+        Some(start) => start,
+        None => return error(state, 0x14, ERROR_INVALID_PARAMETER),
+    };
+
     let mut initial_response = stat(state, 0x14);
-    initial_response.response.push(0x0);
-    initial_response.response.push(0x2);
+    initial_response.response.push(dec_to_bcd(start.minutes()) as u8);
+    initial_response.response.push(dec_to_bcd(start.seconds()) as u8);
 
     initial_response
 }
 
+/// GetlocL. The header and subheader of the last data sector read, as they are on the disc. There's
+/// no stat byte in the response
+pub(super) fn get_loc_l(state: &mut CDDrive) -> Packet {
+    match state.last_sector_header {
+        Some(header) => {
+            let mut packet = stat(state, 0x10);
+            packet.response = header.to_vec();
+            packet
+        }
+        None => error(state, 0x10, ERROR_CANNOT_RESPOND),
+    }
+}
+
+/// GetlocP. The position of the head from the disc's subchannel: track, index, position in the
+/// track and position on the disc, all in BCD and without a stat byte. The two seconds before the
+/// first track are its index 0, counting down to it. Anything past the last track is the lead-out
+pub(super) fn get_loc_p(state: &mut CDDrive) -> Packet {
+    let location = state.head_location();
+    let (track, track_start) = match state.disc.as_ref() {
+        Some(disc) => {
+            let track = match location.sector_number() {
+                Some(_) => disc.track_at(location),
+                None => Some(1),
+            };
+            // Track 0 is the lead-out, which always has a start
+            (track, disc.track_start(track.unwrap_or(0)).unwrap())
+        }
+        None => return error(state, 0x11, ERROR_NO_DISC),
+    };
+
+    let disc_sector = |index: DiscIndex| {
+        (index.minutes() * 60 + index.seconds()) * SECTORS_PER_SECOND + index.sectors()
+    };
+    let (absolute, start) = (disc_sector(location), disc_sector(track_start));
+    let (index, in_track) = match absolute.checked_sub(start) {
+        Some(in_track) => (1, in_track),
+        None => (0, start - absolute),
+    };
+    let relative = DiscIndex::new_dec(0, 0, 0).plus_sector_offset(in_track);
+
+    let mut packet = stat(state, 0x11);
+    packet.response = vec![
+        track.map_or(0xAA, |track| dec_to_bcd(track) as u8),
+        index,
+        dec_to_bcd(relative.minutes()) as u8,
+        dec_to_bcd(relative.seconds()) as u8,
+        dec_to_bcd(relative.sectors()) as u8,
+        dec_to_bcd(location.minutes()) as u8,
+        dec_to_bcd(location.seconds()) as u8,
+        dec_to_bcd(location.sectors()) as u8,
+    ];
+    packet
+}
+
+/// Reads and plays start wherever Setloc pointed, unless a seek has already gone there
+fn finish_setloc_seek(state: &mut CDDrive) {
+    if !state.seek_complete {
+        state.read_offset = 0;
+        state.current_seek_target = state.next_seek_target.clone();
+        state.seek_complete = true;
+    }
+}
+
+// Plays from the Setloc position. Each sector is handled in the post condition, like ReadN
 pub(super) fn play(state: &mut CDDrive) -> Packet {
+    finish_setloc_seek(state);
     state.drive_state = DriveState::Play;
-    stat(state, 0x3)
+    state.read_enabled = true;
+    state.play_track = state
+        .disc
+        .as_ref()
+        .and_then(|disc| disc.track_at(state.read_location()));
+
+    let mut initial_response = stat(state, 0x3);
+    initial_response.extra_response = Some(Box::new(state.sector_packet(0x3)));
+    initial_response
 }
 
 pub(super) fn mute(state: &mut CDDrive) -> Packet {
+    state.muted = true;
     stat(state, 0xB)
 }
 
 // Slams the brakes on the drive completely
+// The motor is still spinning down until the second response, where it's marked as off
 pub(super) fn stop(state: &mut CDDrive) -> Packet {
     let mut pre_stop_packet = stat(state, 0x8);
     state.drive_state = DriveState::Idle;
-    state.motor_state = MotorState::Off;
+    state.read_enabled = false;
     let mut second_packet = stat(state, 0x8);
 
     second_packet.cause = IntCause::INT2;
@@ -241,8 +347,23 @@ pub(super) fn stop(state: &mut CDDrive) -> Packet {
     pre_stop_packet
 }
 
-// Filters out some sectors for playing music. We don't care about that here
-pub(super) fn set_filter(state: &mut CDDrive) -> Packet {
+// Spins the motor back up after a Stop. The second response comes once it's up to speed
+pub(super) fn motor_on(state: &mut CDDrive) -> Packet {
+    if state.motor_state != MotorState::Off {
+        return error(state, 0x7, ERROR_WRONG_PARAMETER_COUNT);
+    }
+    let mut first_response = stat(state, 0x7);
+    state.motor_state = MotorState::SpinUp;
+    let mut second_response = stat(state, 0x7);
+    second_response.cause = IntCause::INT2;
+    second_response.execution_cycles = SPIN_UP_CYCLES;
+    first_response.extra_response = Some(Box::new(second_response));
+    first_response
+}
+
+// Picks the file and channel XA-ADPCM sectors have to match while mode bit 3 is set
+pub(super) fn set_filter(state: &mut CDDrive, file: u8, channel: u8) -> Packet {
+    state.xa_filter = (file, channel);
     stat(state, 0xD)
 }
 
@@ -281,4 +402,85 @@ pub(super) fn end_sce(state: &mut CDDrive) -> Packet {
         command: 0x19,
         need_irq: false,
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod commands_tests {
+    use super::*;
+    use crate::cdrom::disc::{Disc, DiscTrack, BYTES_PER_SECTOR};
+
+    /// One data track followed by three audio tracks, laid out like a cue with a bin per track
+    fn four_track_drive() -> CDDrive {
+        let mut disc = Disc::new("tracks.cue");
+        for sectors in [30 * 75, 3 * 60 * 75 + 20, 75 * 58, 45 * 75 + 74] {
+            disc.add_track(DiscTrack::new(vec![0; sectors * BYTES_PER_SECTOR]));
+        }
+        let mut drive = CDDrive::new();
+        drive.load_disc(disc);
+        drive
+    }
+
+    #[test]
+    fn test_get_tn() {
+        let mut drive = four_track_drive();
+        let packet = get_tn(&mut drive);
+        assert_eq!(packet.cause, IntCause::INT3);
+        assert_eq!(packet.response[1..], [0x01, 0x04]);
+    }
+
+    #[test]
+    fn test_get_td() {
+        let mut drive = four_track_drive();
+        let expected = [
+            // The lead-out is at 05:16:19. Frames aren't part of the response
+            (0x00, [0x05, 0x16]),
+            (0x01, [0x00, 0x02]),
+            (0x02, [0x00, 0x32]),
+            (0x03, [0x03, 0x32]),
+            (0x04, [0x04, 0x30]),
+        ];
+        for (track, start) in expected {
+            let packet = get_td(&mut drive, track);
+            assert_eq!(packet.cause, IntCause::INT3, "track {:#X}", track);
+            assert_eq!(packet.response.len(), 3);
+            assert_eq!(packet.response[1..], start, "track {:#X}", track);
+        }
+    }
+
+    #[test]
+    fn test_get_td_past_last_track() {
+        let mut drive = four_track_drive();
+        let packet = get_td(&mut drive, 0x05);
+        assert_eq!(packet.cause, IntCause::INT5);
+        assert_eq!(packet.response[1], ERROR_INVALID_PARAMETER);
+    }
+
+    #[test]
+    fn test_get_loc_p_outside_tracks() {
+        let mut drive = four_track_drive();
+        // Half a second before track 1 starts
+        drive.current_seek_target = DiscIndex::new_dec(0, 1, 38);
+        assert_eq!(get_loc_p(&mut drive).response, [0x01, 0x00, 0x00, 0x00, 0x37, 0x00, 0x01, 0x38]);
+
+        // Just past the end of track 4
+        drive.current_seek_target = DiscIndex::new_dec(5, 16, 20);
+        assert_eq!(get_loc_p(&mut drive).response, [0xAA, 0x01, 0x00, 0x00, 0x01, 0x05, 0x16, 0x20]);
+    }
+
+    #[test]
+    fn test_speed_change_delay() {
+        let mut drive = four_track_drive();
+        let mut scheduler = Scheduler::new();
+
+        // Only the speed bit spins the motor up or down
+        set_mode(&mut drive, 0x20, &mut scheduler);
+        assert!(!drive.speed_change_in_progress());
+
+        set_mode(&mut drive, 0xA0, &mut scheduler);
+        assert!(drive.speed_change_in_progress());
+        let remaining = drive.speed_change_remaining(&scheduler).map(|cycles| cycles.0);
+        assert_eq!(remaining, Some(SPEED_CHANGE_CYCLES));
+
+        drive.finish_speed_change();
+        assert!(drive.speed_change_remaining(&scheduler).is_none());
+    }
+}
@@ -1,5 +1,7 @@
+use std::cell::OnceCell;
 use std::fmt::Display;
 
+use super::iso9660::{boot_file_name, serial_from_file_name, Filesystem};
 use super::SectorSize;
 
 pub(super) const SECTORS_PER_SECOND: usize = 75;
@@ -7,17 +9,18 @@ pub(super) const BYTES_PER_SECTOR: usize = 2352;
 // Sector format is Mode2/Form1 CD-XA
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct DiscIndex {
     minutes: usize,
     seconds: usize,
     sectors: usize,
 }
 
-pub fn bcd_to_dec(hex: usize) -> usize {
+pub(crate) fn bcd_to_dec(hex: usize) -> usize {
     ((hex & 0xF0) >> 4) * 10 + (hex & 0x0F)
 }
 
-pub fn dec_to_bcd(dec: usize) -> usize {
+pub(crate) fn dec_to_bcd(dec: usize) -> usize {
     (dec / 10 * 16) + (dec % 10)
 }
 
@@ -38,13 +41,27 @@ impl DiscIndex {
         }
     }
 
-    pub fn sector_number(&self) -> usize {
+    /// Sector number relative to the start of the data track, 2 seconds in.
+    /// Returns None for locations in the lead-in before it
+    pub fn sector_number(&self) -> Option<usize> {
         let total_seconds = (self.minutes * 60) + self.seconds;
-        ((total_seconds * SECTORS_PER_SECOND) + self.sectors) - 150
+        ((total_seconds * SECTORS_PER_SECOND) + self.sectors).checked_sub(150)
     }
 
-    pub fn as_address(&self) -> u32 {
-        (self.sector_number() * BYTES_PER_SECTOR) as u32
+    pub fn minutes(&self) -> usize {
+        self.minutes
+    }
+
+    pub fn seconds(&self) -> usize {
+        self.seconds
+    }
+
+    pub fn sectors(&self) -> usize {
+        self.sectors
+    }
+
+    pub fn as_address(&self) -> Option<usize> {
+        self.sector_number()?.checked_mul(BYTES_PER_SECTOR)
     }
 
     pub fn plus_sector_offset(&self, offset_sectors: usize) -> DiscIndex {
@@ -62,19 +79,39 @@ impl Display for DiscIndex {
     }
 }
 
+/// A whole track image held in memory. Frontends read the track file up front, so
+/// `Disc::read_sector` never touches the host filesystem and can't stall the emulator mid-frame.
+/// If tracks are ever streamed from disk instead, reads will need a read-ahead cache.
 pub struct DiscTrack {
     data: Vec<u8>,
+    /// CD-DA tracks hold raw 16 bit stereo samples rather than data sectors
+    audio: bool,
 }
 
 impl DiscTrack {
+    /// A data track
     pub fn new(data: Vec<u8>) -> Self {
-        Self { data }
+        Self { data, audio: false }
+    }
+
+    /// A CD-DA track
+    pub fn audio(data: Vec<u8>) -> Self {
+        Self { data, audio: true }
     }
 }
 
+/// What the disc's filesystem says about the game
+#[derive(Debug, Default)]
+struct DiscIdentity {
+    serial: Option<String>,
+    title_hint: Option<String>,
+}
+
 pub struct Disc {
     tracks: Vec<DiscTrack>,
     title: String,
+    /// Read from the filesystem the first time it is asked for
+    identity: OnceCell<DiscIdentity>,
 }
 
 impl Disc {
@@ -82,6 +119,7 @@ impl Disc {
         Self {
             tracks: Vec::new(),
             title: String::from(title),
+            identity: OnceCell::new(),
         }
     }
 
@@ -91,32 +129,119 @@ impl Disc {
 
     pub fn add_track(&mut self, track: DiscTrack) {
         self.tracks.push(track);
+        self.identity = OnceCell::new();
     }
 
-    pub fn read_sector(&self, location: DiscIndex) -> Sector {
-        let address = location.as_address() as usize;
-        let (track, track_offset) = self.track_of_offset(address as usize);
+    /// Returns None if the location isn't on the disc. Games can seek anywhere, so this has to be handled
+    pub fn read_sector(&self, location: DiscIndex) -> Option<Sector> {
+        let address = location.as_address()?;
+        let (_, track, track_offset) = self.track_of_offset(address)?;
         let sector_address = address - track_offset;
-        let data = &track.data[sector_address..sector_address + SectorSize::WholeSector as usize];
-        Sector::new(data.to_vec())
+        let data = track.data.get(sector_address..sector_address + BYTES_PER_SECTOR)?;
+        Some(Sector::new(data.to_vec()))
     }
 
-    fn track_of_offset(&self, offset: usize) -> (&DiscTrack, usize) {
+    /// Returns the track's number, the track and where it starts
+    fn track_of_offset(&self, offset: usize) -> Option<(usize, &DiscTrack, usize)> {
         let mut total_size = 0;
-        for track in &self.tracks {
+        for (i, track) in self.tracks.iter().enumerate() {
             if offset >= total_size && offset < total_size + track.data.len() {
-                return (&track, total_size);
+                return Some((i + 1, &track, total_size));
             }
             total_size += track.data.len();
         }
-        panic!("Unable to locate track at offset {}!", offset);
+        None
+    }
+
+    /// Number of the track the location is in, or None if it isn't on the disc
+    pub fn track_at(&self, location: DiscIndex) -> Option<usize> {
+        let (number, _, _) = self.track_of_offset(location.as_address()?)?;
+        Some(number)
+    }
+
+    /// True if the location is in a CD-DA track
+    pub fn is_audio(&self, location: DiscIndex) -> bool {
+        location
+            .as_address()
+            .and_then(|address| self.track_of_offset(address))
+            .is_some_and(|(_, track, _)| track.audio)
     }
 
     pub fn track_count(&self) -> usize {
         self.tracks.len()
     }
+
+    /// Where the given track starts. The first track starts at 00:02:00 and each track file holds
+    /// exactly one track, so every other track starts right where the previous one ends.
+    /// Track 0 is the lead-out, just past the end of the last track
+    pub fn track_start(&self, track: usize) -> Option<DiscIndex> {
+        let tracks_before = match track {
+            0 => self.tracks.len(),
+            track if track <= self.tracks.len() => track - 1,
+            _ => return None,
+        };
+        let sectors: usize = self.tracks[..tracks_before]
+            .iter()
+            .map(|track| track.data.len() / BYTES_PER_SECTOR)
+            .sum();
+        Some(DiscIndex::new_dec(0, 2, 0).plus_sector_offset(sectors))
+    }
+
+    /// Returns the 2048 bytes of user data in a data track sector, or None if the sector is past the end of the track
+    pub(super) fn read_user_data(&self, lba: usize) -> Option<&[u8]> {
+        let track = self.tracks.first()?;
+        let start = lba * BYTES_PER_SECTOR + 24;
+        track.data.get(start..start + 0x800)
+    }
+
+    /// Returns the game's serial, e.g. SCUS-94163. This comes from the BOOT line of SYSTEM.CNF,
+    /// falling back to the volume label for discs that boot something like PSX.EXE
+    pub fn serial(&self) -> Option<&str> {
+        self.identity().serial.as_deref()
+    }
+
+    /// Returns the volume label if it looks like a name rather than just the serial again.
+    /// Labels are often abbreviated, so this is only good enough to show until something better is known
+    pub fn title_hint(&self) -> Option<&str> {
+        self.identity().title_hint.as_deref()
+    }
+
+    fn identity(&self) -> &DiscIdentity {
+        self.identity.get_or_init(|| {
+            let filesystem = match Filesystem::open(self) {
+                Some(filesystem) => filesystem,
+                None => return DiscIdentity::default(),
+            };
+
+            let label = filesystem.volume_label();
+            let label = if label.is_empty() { None } else { Some(label.to_string()) };
+            let boot_serial = filesystem
+                .read_root_file("SYSTEM.CNF")
+                .and_then(|cnf| boot_file_name(&String::from_utf8_lossy(&cnf)))
+                .and_then(|name| serial_from_file_name(&name));
+
+            DiscIdentity {
+                serial: boot_serial.or_else(|| label.clone()),
+                title_hint: label
+                    .filter(|label| serial_from_file_name(label).is_none())
+                    .map(|label| label.replace('_', " ")),
+            }
+        })
+    }
 }
 
+/// Mode 2 sectors come in two forms. Form 1 has 2048 bytes of data protected by error correction,
+/// form 2 drops the error correction for 2324 bytes of data and is used for XA audio and video
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SectorForm {
+    Form1,
+    Form2,
+}
+
+const FORM1_DATA_SIZE: usize = 0x800;
+const FORM2_DATA_SIZE: usize = 0x914;
+
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sector {
     data: Vec<u8>,
 }
@@ -134,18 +259,177 @@ impl Sector {
         )
     }
 
+    /// The sector as 16 bit stereo samples, for CD-DA sectors
+    pub fn audio_samples(&self) -> impl Iterator<Item = (i16, i16)> + '_ {
+        self.data.chunks_exact(4).map(|sample| {
+            (
+                i16::from_le_bytes([sample[0], sample[1]]),
+                i16::from_le_bytes([sample[2], sample[3]]),
+            )
+        })
+    }
+
+    /// The header (minutes, seconds and sector in BCD, then the mode) followed by the subheader
+    /// (file, channel, submode and coding info)
+    pub fn header(&self) -> [u8; 8] {
+        self.data[12..20].try_into().unwrap()
+    }
+
     pub fn full_sector_data(&self) -> &[u8] {
         &self.data[0xC..]
     }
 
     pub fn data_only(&self) -> &[u8] {
-        &self.data[24..24 + 0x800]
+        &self.data[24..24 + FORM1_DATA_SIZE]
+    }
+
+    /// Submode byte of the mode 2 subheader. Bit 5 picks the form, and the XA audio and video bits
+    /// are in here too
+    pub fn submode(&self) -> u8 {
+        self.data[18]
     }
 
-    pub fn consume(self, sector_size: &SectorSize) -> Vec<u8> {
-        match sector_size {
-            SectorSize::DataOnly => self.data[24..24 + 0x800].to_vec(),
-            SectorSize::WholeSector => self.data[0xC..].to_vec(),
+    /// Data area of a form 2 sector. XA-ADPCM sectors keep their sound groups here
+    pub fn form2_data(&self) -> &[u8] {
+        &self.data[24..24 + FORM2_DATA_SIZE]
+    }
+
+    /// Anything that isn't a mode 2 sector is treated as form 1, since it holds 2048 bytes of data too
+    pub fn form(&self) -> SectorForm {
+        if self.data[15] == 2 && self.submode() & 0x20 != 0 {
+            SectorForm::Form2
+        } else {
+            SectorForm::Form1
+        }
+    }
+
+    /// Data only reads deliver however much data the sector's form holds
+    pub(crate) fn consume(self, sector_size: &SectorSize) -> Vec<u8> {
+        match (sector_size, self.form()) {
+            (SectorSize::DataOnly, SectorForm::Form1) => self.data[24..24 + FORM1_DATA_SIZE].to_vec(),
+            (SectorSize::DataOnly, SectorForm::Form2) => self.data[24..24 + FORM2_DATA_SIZE].to_vec(),
+            (SectorSize::WholeSector, _) => self.data[0xC..].to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod disc_tests {
+    use super::*;
+
+    const SYSTEM_CNF_LBA: usize = 20;
+
+    fn write_user_data(track: &mut [u8], lba: usize, data: &[u8]) {
+        let start = lba * BYTES_PER_SECTOR + 24;
+        track[start..start + data.len()].copy_from_slice(data);
+    }
+
+    fn directory_record(name: &[u8], lba: u32, size: u32) -> Vec<u8> {
+        let len = 33 + name.len() + (name.len() + 1) % 2;
+        let mut record = vec![0; len];
+        record[0] = len as u8;
+        record[2..6].copy_from_slice(&lba.to_le_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[32] = name.len() as u8;
+        record[33..33 + name.len()].copy_from_slice(name);
+        record
+    }
+
+    /// Builds a single track disc with just enough ISO9660 for the serial lookup
+    fn iso_with_system_cnf(label: &str, system_cnf: Option<&str>) -> Disc {
+        let mut track = vec![0; (SYSTEM_CNF_LBA + 1) * BYTES_PER_SECTOR];
+
+        let mut pvd = vec![0; 0x800];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        let mut padded_label = label.as_bytes().to_vec();
+        padded_label.resize(32, b' ');
+        pvd[40..72].copy_from_slice(&padded_label);
+        pvd[156..156 + 34].copy_from_slice(&directory_record(&[0], 18, 0x800));
+        write_user_data(&mut track, 16, &pvd);
+
+        let mut root = directory_record(&[0], 18, 0x800);
+        root.extend(directory_record(&[1], 18, 0x800));
+        if let Some(system_cnf) = system_cnf {
+            root.extend(directory_record(b"SYSTEM.CNF;1", SYSTEM_CNF_LBA as u32, system_cnf.len() as u32));
+            write_user_data(&mut track, SYSTEM_CNF_LBA, system_cnf.as_bytes());
+        }
+        write_user_data(&mut track, 18, &root);
+
+        let mut disc = Disc::new("test.bin");
+        disc.add_track(DiscTrack::new(track));
+        disc
+    }
+
+    #[test]
+    fn test_serial_from_system_cnf() {
+        let variants = [
+            "BOOT = cdrom:\\SCUS_941.63;1\r\nTCB = 4\r\n",
+            "BOOT=cdrom:\\SCUS_941.63;1\r\n",
+            "BOOT = cdrom:SCUS_941.63;1\n",
+            "  boot  =  cdrom:\\GAME\\SCUS_941.63;1  \n",
+            "TCB = 4\nBOOT = cdrom0:/SCUS_941.63\n",
+        ];
+
+        for system_cnf in variants {
+            let disc = iso_with_system_cnf("SCUS_94163", Some(system_cnf));
+            assert_eq!(disc.serial(), Some("SCUS-94163"), "SYSTEM.CNF: {:?}", system_cnf);
+            assert_eq!(disc.title_hint(), None);
         }
     }
+
+    #[test]
+    fn test_serial_falls_back_to_volume_label() {
+        let disc = iso_with_system_cnf("MY_HOMEBREW", Some("BOOT = cdrom:\\PSX.EXE;1\n"));
+        assert_eq!(disc.serial(), Some("MY_HOMEBREW"));
+        assert_eq!(disc.title_hint(), Some("MY HOMEBREW"));
+
+        let disc = iso_with_system_cnf("MY_HOMEBREW", None);
+        assert_eq!(disc.serial(), Some("MY_HOMEBREW"));
+    }
+
+    /// A mode 2 sector with every data byte set to its offset into the sector
+    fn mode2_sector(submode: u8) -> Sector {
+        let mut data: Vec<u8> = (0..BYTES_PER_SECTOR).map(|i| i as u8).collect();
+        data[15] = 2;
+        data[18] = submode;
+        data[22] = submode;
+        Sector::new(data)
+    }
+
+    #[test]
+    fn test_form1_sector() {
+        let sector = mode2_sector(0x08);
+        assert_eq!(sector.form(), SectorForm::Form1);
+
+        let data = sector.consume(&SectorSize::DataOnly);
+        assert_eq!(data.len(), 0x800);
+        assert_eq!(data[0], 24);
+        assert_eq!(data[0x7FF], (24 + 0x7FF) as u8);
+    }
+
+    #[test]
+    fn test_form2_sector() {
+        // XA audio sector. Audio, real time and form 2 bits set
+        let sector = mode2_sector(0x64);
+        assert_eq!(sector.form(), SectorForm::Form2);
+
+        let data = sector.consume(&SectorSize::DataOnly);
+        assert_eq!(data.len(), 2324);
+        assert_eq!(data[0], 24);
+        assert_eq!(data[2323], (24 + 2323) as u8);
+
+        // Whole sector reads are the same size for either form
+        let data = mode2_sector(0x64).consume(&SectorSize::WholeSector);
+        assert_eq!(data.len(), SectorSize::WholeSector as usize);
+        assert_eq!(data[0], 12);
+    }
+
+    #[test]
+    fn test_serial_without_filesystem() {
+        let mut disc = Disc::new("audio.bin");
+        disc.add_track(DiscTrack::new(vec![0; 20 * BYTES_PER_SECTOR]));
+        assert_eq!(disc.serial(), None);
+        assert_eq!(disc.title_hint(), None);
+    }
 }
@@ -0,0 +1,120 @@
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::disc::Disc;
+
+/// The primary volume descriptor always lives at sector 16
+const PRIMARY_VOLUME_DESCRIPTOR: usize = 16;
+const LOGICAL_BLOCK_SIZE: usize = 0x800;
+
+/// Just enough of an ISO9660 reader to find files in the root directory
+pub(super) struct Filesystem<'a> {
+    disc: &'a Disc,
+    volume_label: String,
+    root_lba: usize,
+    root_size: usize,
+}
+
+impl<'a> Filesystem<'a> {
+    /// Returns None if the first track doesn't have a valid primary volume descriptor
+    pub fn open(disc: &'a Disc) -> Option<Self> {
+        let pvd = disc.read_user_data(PRIMARY_VOLUME_DESCRIPTOR)?;
+        if pvd[0] != 1 || &pvd[1..6] != b"CD001" {
+            return None;
+        }
+
+        let volume_label = String::from_utf8_lossy(&pvd[40..72]).trim().to_string();
+        let root_record = &pvd[156..156 + 34];
+
+        Some(Self {
+            disc,
+            volume_label,
+            root_lba: LittleEndian::read_u32(&root_record[2..6]) as usize,
+            root_size: LittleEndian::read_u32(&root_record[10..14]) as usize,
+        })
+    }
+
+    pub fn volume_label(&self) -> &str {
+        &self.volume_label
+    }
+
+    /// Reads a file from the root directory. Names are matched case insensitively, without the ";1" version suffix
+    pub fn read_root_file(&self, name: &str) -> Option<Vec<u8>> {
+        let sectors = self.root_size.div_ceil(LOGICAL_BLOCK_SIZE);
+
+        for sector in 0..sectors {
+            let data = self.disc.read_user_data(self.root_lba + sector)?;
+            let mut offset = 0;
+
+            // Records never cross a sector boundary. A zero length means the rest of the sector is padding
+            while offset < LOGICAL_BLOCK_SIZE && data[offset] != 0 {
+                let record = &data[offset..];
+                let record_len = record[0] as usize;
+                let name_len = record[32] as usize;
+                if record_len < 33 || 33 + name_len > record.len() {
+                    break;
+                }
+
+                let record_name = String::from_utf8_lossy(&record[33..33 + name_len]);
+                let record_name = record_name.split(';').next().unwrap_or("");
+                if record_name.eq_ignore_ascii_case(name) {
+                    let lba = LittleEndian::read_u32(&record[2..6]) as usize;
+                    let size = LittleEndian::read_u32(&record[10..14]) as usize;
+                    return self.read_file(lba, size);
+                }
+
+                offset += record_len;
+            }
+        }
+
+        None
+    }
+
+    fn read_file(&self, lba: usize, size: usize) -> Option<Vec<u8>> {
+        let mut contents = Vec::with_capacity(size);
+        let mut sector = lba;
+        while contents.len() < size {
+            let data = self.disc.read_user_data(sector)?;
+            let remaining = size - contents.len();
+            contents.extend_from_slice(&data[..remaining.min(LOGICAL_BLOCK_SIZE)]);
+            sector += 1;
+        }
+        Some(contents)
+    }
+}
+
+/// Pulls the boot executable's file name out of SYSTEM.CNF, e.g. `BOOT = cdrom:\SCUS_941.63;1` gives SCUS_941.63
+pub(super) fn boot_file_name(system_cnf: &str) -> Option<String> {
+    let line = system_cnf.lines().find(|line| {
+        let line = line.trim_start();
+        match (line.get(..4), line.get(4..)) {
+            (Some(key), Some(rest)) => key.eq_ignore_ascii_case("BOOT") && rest.trim_start().starts_with('='),
+            _ => false,
+        }
+    })?;
+
+    let (_, path) = line.split_once('=')?;
+    let name = path.trim().rsplit(['\\', '/', ':']).next()?;
+    let name = name.split(';').next()?.trim();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// Turns a boot file name like SCUS_941.63 into the serial printed on the case, SCUS-94163.
+/// Returns None for names that don't follow the usual pattern, like PSX.EXE
+pub(super) fn serial_from_file_name(name: &str) -> Option<String> {
+    let prefix: String = name.chars().take_while(|c| c.is_ascii_alphabetic()).collect();
+    let digits: String = name[prefix.len()..]
+        .chars()
+        .filter(|c| *c != '_' && *c != '-' && *c != '.')
+        .collect();
+
+    if prefix.len() != 4 || digits.len() != 5 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(format!("{}-{}", prefix.to_ascii_uppercase(), digits))
+}
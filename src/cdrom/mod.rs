@@ -1,18 +1,27 @@
 use bit_field::BitField;
 use commands::*;
 use disc::*;
+use sector_buffer::SectorBuffer;
+use xa::{CodingInfo, XaDecoder, SECTOR_AUDIO_SIZE};
+pub use sector_buffer::SectorBufferInfo;
 use log::{trace, warn};
 
 use crate::cpu::{InterruptSource, R3000};
+use crate::mmio::{CDROM_INDEX, CDROM_REG1, CDROM_REG2, CDROM_REG3};
 use std::collections::VecDeque;
 use crate::{CpuCycles, MainBus, Scheduler};
+use crate::scheduler::EventHandle;
 use crate::ScheduleTarget::{CDIrq, CDPacket};
 
 mod commands;
 pub mod disc;
+mod iso9660;
+mod sector_buffer;
+mod xa;
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 #[allow(dead_code)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub(super) enum DriveState {
     Play,
     Seek,
@@ -23,14 +32,23 @@ pub(super) enum DriveState {
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 #[allow(dead_code)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub(super) enum MotorState {
     Off,
     SpinUp,
     On,
 }
 
+/// Switching between single and double speed takes about 650ms
+const SPEED_CHANGE_CYCLES: u32 = 22_014_720;
+/// Spinning the motor back up after a Stop takes about 1.5 seconds
+const SPIN_UP_CYCLES: u32 = 50_803_200;
+/// Play, ReadN, ReadS, SeekL and SeekP can't run until the motor is up to speed
+const MOTOR_COMMANDS: [u8; 5] = [0x3, 0x6, 0x1B, 0x15, 0x16];
+
 #[derive(Debug, Copy, Clone)]
-pub enum SectorSize {
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum SectorSize {
     DataOnly = 0x800,
     WholeSector = 0x924,
 }
@@ -41,6 +59,7 @@ enum DriveSpeed {
 }
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[allow(dead_code)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub(super) enum IntCause {
     INT1,
     INT2,
@@ -70,6 +89,7 @@ impl IntCause {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub(super) struct Packet {
     internal_id: u32,
     cause: IntCause,
@@ -80,12 +100,94 @@ pub(super) struct Packet {
     need_irq: bool,
 }
 
+/// How much of each CD channel is sent to each SPU input. 0x80 is 100%
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+struct AudioVolume {
+    left_to_left: u8,
+    left_to_right: u8,
+    right_to_right: u8,
+    right_to_left: u8,
+}
+
+impl AudioVolume {
+    fn new() -> Self {
+        Self {
+            left_to_left: 0x80,
+            left_to_right: 0,
+            right_to_right: 0x80,
+            right_to_left: 0,
+        }
+    }
+
+    fn apply(&self, (left, right): (i16, i16)) -> (i16, i16) {
+        let mix = |a: i16, a_volume: u8, b: i16, b_volume: u8| {
+            ((a as i32 * a_volume as i32 + b as i32 * b_volume as i32) >> 7)
+                .clamp(i16::MIN as i32, i16::MAX as i32) as i16
+        };
+        (
+            mix(left, self.left_to_left, right, self.right_to_left),
+            mix(right, self.right_to_right, left, self.left_to_right),
+        )
+    }
+}
+
+const RESPONSE_FIFO_SIZE: usize = 16;
+/// Parameters written while the FIFO is full are dropped
+const PARAMETER_FIFO_SIZE: usize = 16;
+
+/// The drive's 16 byte response FIFO. A new response is written from the start without clearing the
+/// rest, so reading past its end returns whatever older responses left there, and the read
+/// pointer wraps back to the start after 16 bytes. Some games read one byte too many
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+struct ResponseFifo {
+    data: [u8; RESPONSE_FIFO_SIZE],
+    read_pos: usize,
+    /// Bytes of the current response that haven't been read
+    remaining: usize,
+}
+
+impl ResponseFifo {
+    fn new() -> Self {
+        Self {
+            data: [0; RESPONSE_FIFO_SIZE],
+            read_pos: 0,
+            remaining: 0,
+        }
+    }
+
+    fn load(&mut self, response: &[u8]) {
+        let len = response.len().min(RESPONSE_FIFO_SIZE);
+        self.data[..len].copy_from_slice(&response[..len]);
+        self.read_pos = 0;
+        self.remaining = len;
+    }
+
+    fn pop(&mut self) -> u8 {
+        let val = self.data[self.read_pos];
+        self.read_pos = (self.read_pos + 1) % RESPONSE_FIFO_SIZE;
+        self.remaining = self.remaining.saturating_sub(1);
+        val
+    }
+
+    /// Drops the rest of the response. The bytes stay in the FIFO
+    fn clear(&mut self) {
+        self.remaining = 0;
+    }
+
+    /// RSLRRDY. Set until every byte of the response has been read
+    fn ready(&self) -> bool {
+        self.remaining > 0
+    }
+}
+
 #[derive(Debug)]
 pub(super) struct Block {
     _data: Vec<u8>,
 }
 #[allow(dead_code)]
 
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct CDDrive {
     cycle_counter: u32,
     next_id: u32,
@@ -95,16 +197,32 @@ pub struct CDDrive {
     drive_state: DriveState,
     motor_state: MotorState,
     drive_mode: u8,
+    /// Set while the motor is changing speed. Sectors aren't delivered until it's done
+    speed_change: Option<EventHandle>,
+    /// The response that is waiting on the motor to spin up
+    spin_up_packet: Option<u32>,
 
+    #[cfg_attr(feature = "savestate", serde(skip))]
     disc: Option<Disc>,
 
     parameter_queue: VecDeque<u8>,
-    response_queue: VecDeque<u8>,
-    data_queue: Vec<Sector>,
-    response_data_queue: Vec<u8>,
+    response_fifo: ResponseFifo,
+    sector_buffer: SectorBuffer,
+    /// The sector BFRD last loaded. Clearing BFRD only rewinds data_pos, so a game can read the
+    /// same sector again
+    data_fifo: Vec<u8>,
+    data_pos: usize,
+    /// An interrupt has been acknowledged since data_fifo was loaded, so the next BFRD loads a
+    /// new sector
+    data_fifo_stale: bool,
+    /// Set while BFRD is set and the data FIFO has unread bytes. CD DMA waits for this
+    data_request: bool,
     ready_packets: Vec<Packet>, // List of packets that have been run and are ready to be delivered upon ack
 
+    /// 0x1F801803.0 bit 7 (BFRD)
     want_data: bool,
+    /// 0x1F801803.0 bit 5 (SMEN). Raises INT10 when the next command starts
+    want_command_irq: bool,
 
     status_index: u8,
 
@@ -112,6 +230,10 @@ pub struct CDDrive {
     next_seek_target: DiscIndex,
     seek_complete: bool,
     read_offset: usize,
+    /// Header and subheader of the last data sector ReadN read, for GetlocL
+    last_sector_header: Option<[u8; 8]>,
+    /// Track Play is in. Play stops here at the end of it in auto-pause mode
+    play_track: Option<usize>,
 
     reg_interrupt_flag: u8,
     reg_interrupt_enable: u8,
@@ -122,8 +244,20 @@ pub struct CDDrive {
 
     pending_irq: bool,
 
-    //Probably useless registers
-    reg_sound_map_data_out: u8,
+    // Volume writes only take effect once the game applies them through 0x1F801803.3
+    pending_audio_volume: AudioVolume,
+    audio_volume: AudioVolume,
+    muted: bool,
+    audio_queue: VecDeque<(i16, i16)>,
+    /// Set through 0x1F801803.3. Mutes XA-ADPCM but not CD-DA
+    adpcm_muted: bool,
+    xa_decoder: XaDecoder,
+    /// File and channel from SetFilter
+    xa_filter: (u8, u8),
+
+    // XA-ADPCM the CPU writes through 0x1F801801.1, decoded once a whole sector has arrived
+    sound_map_data: Vec<u8>,
+    sound_map_coding: CodingInfo,
 }
 
 impl CDDrive {
@@ -136,9 +270,12 @@ impl CDDrive {
             running_commands: Vec::new(),
 
             parameter_queue: VecDeque::new(),
-            data_queue: Vec::new(),
-            response_queue: VecDeque::new(),
-            response_data_queue: Vec::new(),
+            sector_buffer: SectorBuffer::new(),
+            response_fifo: ResponseFifo::new(),
+            data_fifo: Vec::new(),
+            data_pos: 0,
+            data_fifo_stale: true,
+            data_request: false,
             ready_packets: Vec::new(),
 
             status_index: 0,
@@ -146,14 +283,19 @@ impl CDDrive {
             disc: None,
 
             want_data: false,
+            want_command_irq: false,
             drive_state: DriveState::Idle,
             motor_state: MotorState::On,
             drive_mode: 0,
+            speed_change: None,
+            spin_up_packet: None,
 
             next_seek_target: DiscIndex::new_dec(0, 0, 0),
             current_seek_target: DiscIndex::new_dec(0, 0, 0),
             seek_complete: false,
             read_offset: 0,
+            last_sector_header: None,
+            play_track: None,
 
             read_enabled: false,
             packet_awaiting_delivery: None,
@@ -164,54 +306,60 @@ impl CDDrive {
 
             pending_irq: false,
 
-            //Probably useless registers
-            reg_sound_map_data_out: 0,
+            pending_audio_volume: AudioVolume::new(),
+            audio_volume: AudioVolume::new(),
+            muted: false,
+            audio_queue: VecDeque::new(),
+            adpcm_muted: false,
+            xa_decoder: XaDecoder::default(),
+            xa_filter: (0, 0),
+
+            sound_map_data: Vec::with_capacity(SECTOR_AUDIO_SIZE),
+            sound_map_coding: CodingInfo::default(),
         }
     }
 
     pub fn write_byte(&mut self, addr: u32, val: u8, scheduler: &mut Scheduler) {
         ////println!("CDROM writing {:#X}.Index({}) val {:#X}", addr, self.status_index & 0x3, val);
         match addr {
-            0x1F801800 => self.status_index = val & 0x3, //Status
-            0x1F801801 => match self.status_index {
+            CDROM_INDEX => self.status_index = val & 0x3, //Status
+            CDROM_REG1 => match self.status_index {
                 0 => self.execute_command(val, scheduler),
-                1 => self.reg_sound_map_data_out = val,
-                2 => panic!("CD: 0x1F801801 write byte unknown index 2"),
-                3 => trace!("CD: Wrote Right-CD-Out Right SPU volume"),
+                1 => self.push_sound_map_data(val),
+                2 => self.sound_map_coding = CodingInfo::from_byte(val),
+                3 => self.pending_audio_volume.right_to_right = val,
                 _ => unreachable!(),
             },
-            0x1F801802 => match self.status_index {
+            CDROM_REG2 => match self.status_index {
                 0 => self.push_parameter(val),
                 1 => self.write_interrupt_enable_register(val),
-                2 => trace!("CD: Wrote Left-CD-Out Right SPU volume"),
-                3 => trace!("CD: Wrote Right-CD-Out Left SPU volume"),
+                2 => self.pending_audio_volume.left_to_left = val,
+                3 => self.pending_audio_volume.right_to_left = val,
                 _ => unreachable!(),
             },
-            0x1F801803 => match self.status_index {
+            CDROM_REG3 => match self.status_index {
                 0 => {
-                    if val.get_bit(5) {
-                        panic!("CD INT10 requested");
-                    }
-                    if val.get_bit(7) {
-                        // Try to load latest sector from buffer
-                        let _sector_size = *self.sector_size() as usize;
-                        if self.data_queue.len() > 0 {
-                            let sector = self.data_queue.remove(0);
-                            ////println!("Loaded a sector!");
-                            ////println!("Loaded sector. Index {}, sector # {}", sector.index(), sector.index().sector_number());
-                            ////println!("Filling buffer with sector size {:?}", self.sector_size());
-                            self.response_data_queue
-                                .extend(sector.consume(self.sector_size()));
-                        } else {
-                            ////println!("Game requested sector load, but the input buffer was empty!");
-                        }
+                    // Writing 0 to SMEN leaves it as it was
+                    self.want_command_irq |= val.get_bit(5);
+                    // Bit 6 (BFWR) writes to the sector buffer, which only dev drives can do
+                    self.want_data = val.get_bit(7);
+                    if self.want_data {
+                        self.load_data_fifo();
                     } else {
-                        self.response_data_queue.clear();
+                        // The data stays put, only the read position goes back to the start
+                        self.data_pos = 0;
                     }
+                    self.update_data_request();
                 }
                 1 => self.write_interrupt_flag_register(val, scheduler),
-                2 => trace!("CD: Wrote Left-CD-Out Left SPU volume"),
-                3 => (), // Apply audio changes
+                2 => self.pending_audio_volume.left_to_right = val,
+                3 => {
+                    self.adpcm_muted = val.get_bit(0);
+                    // Apply audio changes
+                    if val.get_bit(5) {
+                        self.audio_volume = self.pending_audio_volume;
+                    }
+                }
                 _ => unreachable!(),
             },
             _ => panic!(
@@ -223,26 +371,24 @@ impl CDDrive {
 
     pub fn read_byte(&mut self, addr: u32) -> u8 {
         let v = match addr {
-            0x1F801800 => self.get_status_register(),
-            0x1F801801 => match self.status_index {
+            CDROM_INDEX => self.get_status_register(),
+            CDROM_REG1 => match self.status_index {
                 0 => self.pop_response(), // mirror
                 1 => self.pop_response(),
-                2 => panic!("CD: 0x1F801801 read byte unknown index 2"),
-                3 => panic!("CD: 0x1F801801 read byte unknown index 3"),
+                2 => self.pop_response(), // mirror
+                3 => self.pop_response(), // mirror
                 _ => unreachable!(),
             },
-            0x1F801802 => match self.status_index {
-                0 => self.pop_data(),
-                1 => panic!("CD: 0x1F801802 read byte unknown index 1"),
-                2 => panic!("CD: 0x1F801802 read byte unknown index 2"),
-                3 => panic!("CD: 0x1F801802 read byte unknown index 3"),
+            CDROM_REG2 => match self.status_index {
+                // Every index reads from the data FIFO
+                0..=3 => self.pop_data(),
                 _ => unreachable!(),
             },
-            0x1F801803 => {
+            CDROM_REG3 => {
                 match self.status_index {
                     0 => self.reg_interrupt_enable,
                     1 => self.reg_interrupt_flag | 0xE0,
-                    2 => panic!("CD: 0x1F801803 read byte unknown index 2"),
+                    2 => self.reg_interrupt_enable, //Register mirror
                     3 => self.reg_interrupt_flag | 0xE0, //Register mirror
                     _ => unreachable!(),
                 }
@@ -261,6 +407,19 @@ impl CDDrive {
         v
     }
 
+    /// Back to the power on state, with the same disc in the drive
+    pub fn reset(&mut self) {
+        let disc = self.disc.take();
+        *self = CDDrive::new();
+        self.disc = disc;
+    }
+
+    /// Moves the disc over from `old`, which this drive replaces
+    #[cfg(feature = "savestate")]
+    pub(crate) fn keep_host_state(&mut self, old: CDDrive) {
+        self.disc = old.disc;
+    }
+
     pub fn load_disc(&mut self, disc: Disc) {
         self.disc = Some(disc);
     }
@@ -273,27 +432,110 @@ impl CDDrive {
         &self.disc
     }
 
+    /// Parameters written for a command that hasn't been sent yet
+    pub(crate) fn in_flight_state(&self) -> Vec<u64> {
+        self.parameter_queue.iter().map(|parameter| *parameter as u64).collect()
+    }
+
+    /// Queues a decoded stereo sample to be sent to the SPU's CD input
+    pub(crate) fn push_audio_sample(&mut self, sample: (i16, i16)) {
+        self.audio_queue.push_back(sample);
+    }
+
+    /// Returns the next sample for the SPU's CD input, after the mute flag and the drive's volume
+    /// matrix have been applied. The drive outputs silence when it has nothing buffered
+    pub(crate) fn next_audio_sample(&mut self) -> (i16, i16) {
+        let sample = self.audio_queue.pop_front().unwrap_or((0, 0));
+        if self.muted {
+            (0, 0)
+        } else {
+            self.audio_volume.apply(sample)
+        }
+    }
+
+    /// Sound map playback lets the CPU send XA-ADPCM to the drive's audio output, in the same
+    /// format as an XA sector. Each sector is decoded and queued like audio read from the disc
+    fn push_sound_map_data(&mut self, val: u8) {
+        self.sound_map_data.push(val);
+        if self.sound_map_data.len() < SECTOR_AUDIO_SIZE {
+            return;
+        }
+
+        let samples = self
+            .xa_decoder
+            .decode_sector(&self.sound_map_data, self.sound_map_coding);
+        self.sound_map_data.clear();
+        self.queue_adpcm(samples);
+    }
+
+    /// ReadN sends XA-ADPCM sectors to the audio output instead of the CPU when mode bit 6 is set
+    fn is_xa_audio(&self, sector: &Sector) -> bool {
+        self.drive_mode.get_bit(6) && sector.form() == SectorForm::Form2 && sector.submode().get_bit(2)
+    }
+
+    /// Decodes an XA-ADPCM sector ReadN came across. With mode bit 3 set, only sectors from the
+    /// SetFilter file and channel are played
+    fn play_xa_sector(&mut self, sector: &Sector) {
+        let header = sector.header();
+        if self.drive_mode.get_bit(3) && (header[4], header[5]) != self.xa_filter {
+            return;
+        }
+        let samples = self
+            .xa_decoder
+            .decode_sector(sector.form2_data(), CodingInfo::from_byte(header[7]));
+        self.queue_adpcm(samples);
+    }
+
+    fn queue_adpcm(&mut self, samples: Vec<(i16, i16)>) {
+        if !self.adpcm_muted {
+            for sample in samples {
+                self.push_audio_sample(sample);
+            }
+        }
+    }
+
     fn execute_command(&mut self, command: u8, scheduler: &mut Scheduler) {
         //println!("Received command {:#X}", command);
 
+        if self.want_command_irq {
+            self.want_command_irq = false;
+            self.reg_interrupt_flag |= IntCause::INT10h.bitflag();
+            if self.reg_interrupt_enable & IntCause::INT10h.bitflag() != 0 {
+                self.queue_irq(scheduler);
+            }
+        }
+
         //Execute
         {
             let parameters: Vec<u8> = self.parameter_queue.iter().map(|v| v.clone()).collect();
-            let response = match command {
+            let mut response = match command {
                 0x1 => get_stat(self),
-                0x2 => set_loc(self, parameters[0], parameters[1], parameters[2]),
+                0x2 => match parameters[..] {
+                    [minutes, seconds, frames] => set_loc(self, minutes, seconds, frames),
+                    _ => error(self, command, ERROR_WRONG_PARAMETER_COUNT),
+                },
                 0x3 => play(self),
                 0x6 => read_with_retry(self),
+                0x7 => motor_on(self),
                 0x8 => stop(self),
                 0x9 => pause_read(self),
                 0xA => init(self),
                 0xB => mute(self),
-                0xD => set_filter(self),
-                0xE => set_mode(self, parameters[0]),
-                0x10 => set_filter(self), //This is actually GetlocL. But I'm lazy right now. TODO: Implement this
-                0x11 => set_filter(self), //This is actually GetlocP. But I'm lazy right now. TODO: Implement this
+                0xD => match parameters[..] {
+                    [file, channel] => set_filter(self, file, channel),
+                    _ => error(self, command, ERROR_WRONG_PARAMETER_COUNT),
+                },
+                0xE => match parameters[..] {
+                    [mode] => set_mode(self, mode, scheduler),
+                    _ => error(self, command, ERROR_WRONG_PARAMETER_COUNT),
+                },
+                0x10 => get_loc_l(self),
+                0x11 => get_loc_p(self),
                 0x13 => get_tn(self),
-                0x14 => get_td(self, parameters[0]),
+                0x14 => match parameters[..] {
+                    [track] => get_td(self, track),
+                    _ => error(self, command, ERROR_WRONG_PARAMETER_COUNT),
+                },
                 0x15 => seek_data(self),
                 0x16 => seek_data(self), //This should actually be seek_p, but I'm never using audio discs so we can reuse the data seek function
                 0x1A => get_id(self),
@@ -302,15 +544,28 @@ impl CDDrive {
                 0xC => demute(self),
                 0x19 => {
                     //sub_function commands
-                    match parameters[0] {
-                        0x20 => commands::get_bios_date(self),
-                        0x4 => start_sce(self),
-                        0x5 => end_sce(self),
-                        _ => panic!("CD: Unknown sub_function command {:#X}", parameters[0]),
+                    match parameters.first() {
+                        Some(0x20) => commands::get_bios_date(self),
+                        Some(0x4) => start_sce(self),
+                        Some(0x5) => end_sce(self),
+                        Some(sub_function) => {
+                            warn!(target: "psx::cd", "CD: Unknown sub_function command {:#X}", sub_function);
+                            error(self, command, ERROR_INVALID_SUB_FUNCTION)
+                        }
+                        None => error(self, command, ERROR_WRONG_PARAMETER_COUNT),
                     }
                 }
-                _ => panic!("CD: Unknown command {:#X}!", command),
+                _ => {
+                    warn!(target: "psx::cd", "CD: Unknown command {:#X}!", command);
+                    error(self, command, ERROR_INVALID_COMMAND)
+                }
             };
+            if MOTOR_COMMANDS.contains(&command) && self.motor_state == MotorState::Off {
+                // The motor has to spin back up first, so the response takes much longer than usual
+                self.motor_state = MotorState::SpinUp;
+                self.spin_up_packet = Some(response.internal_id);
+                response.execution_cycles += SPIN_UP_CYCLES;
+            }
             scheduler.schedule_event(CDPacket(response.internal_id), CpuCycles(response.execution_cycles));
             self.running_commands.push(response);
         }
@@ -330,11 +585,11 @@ impl CDDrive {
         //3 prmempt
         status |= (self.parameter_queue.is_empty() as u8) << 3;
         //4 prmrdy
-        status |= (!(self.parameter_queue.len() >= 16) as u8) << 4;
+        status |= ((self.parameter_queue.len() < PARAMETER_FIFO_SIZE) as u8) << 4;
         //5 RSLRRDY
-        status |= (!self.response_queue.is_empty() as u8) << 5;
+        status |= (self.response_fifo.ready() as u8) << 5;
         //6 DRQSTS
-        status |= (!self.response_data_queue.is_empty() as u8) << 6;
+        status |= (self.data_request as u8) << 6;
         // 7 BUSYSTS
         status |= (self.busy() as u8) << 7;
         //println!("Status: {:#X}", status);
@@ -348,6 +603,98 @@ impl CDDrive {
         }
     }
 
+    /// Cycles between sectors passing under the head
+    fn sector_cycles(&self) -> u32 {
+        match self.drive_speed() {
+            DriveSpeed::Single => 0x686da,
+            DriveSpeed::Double => 0x322df,
+        }
+    }
+
+    /// The sector the next ReadN or Play sector comes from
+    fn read_location(&self) -> DiscIndex {
+        self.next_seek_target.plus_sector_offset(self.read_offset)
+    }
+
+    /// The sector that last went past the head. Before anything has been read, that's where the
+    /// last seek ended
+    fn head_location(&self) -> DiscIndex {
+        self.current_seek_target.plus_sector_offset(self.read_offset.saturating_sub(1))
+    }
+
+    /// The INT1 that marks the next sector reaching the head during ReadN or Play
+    fn sector_packet(&mut self, command: u8) -> Packet {
+        Packet {
+            internal_id: self.next_packet_id(),
+            cause: IntCause::INT1,
+            response: vec![self.get_stat()],
+            execution_cycles: self.sector_cycles(),
+            extra_response: None,
+            command,
+            need_irq: false,
+        }
+    }
+
+    /// Plays the sector under the head and moves on to the next one. Returns the response if this
+    /// sector interrupts the CPU, which only happens for reports (mode bit 2) and at the end of the
+    /// track (mode bit 1) or the disc
+    fn play_sector(&mut self, scheduler: &mut Scheduler) -> Option<(IntCause, Vec<u8>)> {
+        if self.drive_state != DriveState::Play {
+            return None;
+        }
+
+        let location = self.read_location();
+        let track = self.disc.as_ref().and_then(|disc| disc.track_at(location));
+        let end_of_track = track != self.play_track && self.drive_mode.get_bit(1);
+        if track.is_none() || end_of_track {
+            // Reached the lead-out, or auto-pause is on and the track is over
+            self.drive_state = DriveState::Idle;
+            self.read_enabled = false;
+            return Some((IntCause::INT4, vec![self.get_stat()]));
+        }
+        self.play_track = track;
+
+        let disc = self.disc.as_ref()?;
+        if let Some(sector) = disc.read_sector(location).filter(|_| disc.is_audio(location)) {
+            let samples: Vec<_> = sector.audio_samples().collect();
+            for sample in samples {
+                self.push_audio_sample(sample);
+            }
+        }
+        let report = self.drive_mode.get_bit(2) && location.sectors().is_multiple_of(10);
+        let report = report.then(|| self.position_report(location));
+
+        self.read_offset += 1;
+        let next_sector = self.sector_packet(0x3);
+        scheduler.schedule_event(CDPacket(next_sector.internal_id), CpuCycles(next_sector.execution_cycles));
+        self.running_commands.push(next_sector);
+
+        report.map(|report| (IntCause::INT1, report))
+    }
+
+    /// Play report for the sector. Every other report gives the position in the track rather than
+    /// on the disc, marked by bit 7 of the seconds. The peak level isn't emulated
+    fn position_report(&self, location: DiscIndex) -> Vec<u8> {
+        let track = self.play_track.unwrap_or(1);
+        let (position, relative_flag) = if (location.sectors() / 10).is_multiple_of(2) {
+            (location, 0)
+        } else {
+            let start = self.disc.as_ref().and_then(|disc| disc.track_start(track)).unwrap_or(location);
+            let in_track = location.sector_number().unwrap_or(0).saturating_sub(start.sector_number().unwrap_or(0));
+            (DiscIndex::new_dec(0, 0, 0).plus_sector_offset(in_track), 0x80)
+        };
+        vec![
+            self.get_stat(),
+            dec_to_bcd(track) as u8,
+            0x01,
+            dec_to_bcd(position.minutes()) as u8,
+            dec_to_bcd(position.seconds()) as u8 | relative_flag,
+            dec_to_bcd(position.sectors()) as u8,
+            0,
+            0,
+        ]
+    }
+
     fn get_stat(&self) -> u8 {
         let mut status: u8 = 0;
         status |= match self.drive_state {
@@ -358,7 +705,7 @@ impl CDDrive {
         };
 
         if self.motor_state == MotorState::On {
-            status |= 0x2;
+            status |= STAT_MOTOR_ON;
         };
 
         status
@@ -378,34 +725,79 @@ impl CDDrive {
     }
 
     fn push_parameter(&mut self, val: u8) {
+        if self.parameter_queue.len() >= PARAMETER_FIFO_SIZE {
+            trace!(target: "psx::cd", "Parameter FIFO full, dropping {:#X}", val);
+            return;
+        }
         self.parameter_queue.push_back(val);
     }
 
     fn pop_response(&mut self) -> u8 {
-        //println!("Popping response");
-        match self.response_queue.pop_front() {
-            Some(val) => val,
-            None => {
-                println!("CD: Tried to read response from empty response queue! Returning 0...");
-                0
-            }
+        if !self.response_fifo.ready() {
+            trace!(target: "psx::cd", "Read past the end of the response");
         }
+        self.response_fifo.pop()
     }
 
-    pub fn data_queue(&mut self) -> &mut Vec<u8> {
-        &mut self.response_data_queue
+    pub fn data_request(&self) -> bool {
+        self.data_request
+    }
+
+    /// Takes `len` bytes from the data FIFO for DMA. Reading past the end of the loaded sector
+    /// wraps around to its start
+    pub fn take_dma_data(&mut self, len: usize) -> Vec<u8> {
+        if !self.want_data || self.data_fifo.is_empty() {
+            warn!(target: "psx::cd", "CD: DMA from an empty data FIFO! Returning 0s...");
+            return vec![0; len];
+        }
+        let fifo_len = self.data_fifo.len();
+        let taken = (0..len)
+            .map(|i| self.data_fifo[(self.data_pos + i) % fifo_len])
+            .collect();
+        self.data_pos = (self.data_pos + len).min(fifo_len);
+        self.update_data_request();
+        taken
     }
 
     pub fn pop_data(&mut self) -> u8 {
-        self.response_data_queue.remove(0) // This is slow, but whatever for now. Using a proper deque is a bit difficult here
+        if !self.data_request {
+            warn!(target: "psx::cd", "CD: Tried to read from empty data queue! Returning 0...");
+            return 0;
+        }
+        let val = self.data_fifo[self.data_pos];
+        self.data_pos += 1;
+        self.update_data_request();
+        val
+    }
+
+    /// Handles BFRD being set. The FIFO moves on to the oldest unread sector once the game has
+    /// acknowledged an interrupt, and otherwise reloads the sector it already holds
+    fn load_data_fifo(&mut self) {
+        if self.data_fifo_stale || self.data_fifo.is_empty() {
+            match self.sector_buffer.pop() {
+                Some((sector, size)) => {
+                    self.data_fifo = sector.consume(&size);
+                    self.data_fifo_stale = false;
+                }
+                None => trace!(target: "psx::cd", "CD: Data requested, but the sector buffer is empty"),
+            }
+        }
+        self.data_pos = 0;
+    }
+
+    fn update_data_request(&mut self) {
+        self.data_request = self.want_data && self.data_pos < self.data_fifo.len();
     }
 
     fn write_interrupt_flag_register(&mut self, val: u8, scheduler: &mut Scheduler) {
         //println!("Writing flag with val {:#X}   pre flag val {:#X}", val, self.reg_interrupt_flag);
+        if self.reg_interrupt_flag & val & 0x7 != 0 {
+            self.data_fifo_stale = true;
+        }
         self.reg_interrupt_flag &= !(val & 0x1F);
 
         ////println!("Post flag {:#X}", self.reg_interrupt_flag);
-        self.response_queue = VecDeque::new(); //Reset queue
+        self.response_fifo.clear();
         if val.get_bit(6) {
             ////println!("Clearing parameters");
             self.parameter_queue = VecDeque::new();
@@ -432,6 +824,27 @@ impl CDDrive {
         self.reg_interrupt_flag
     }
 
+    pub fn sector_buffer_info(&self) -> SectorBufferInfo {
+        self.sector_buffer.info()
+    }
+
+    /// The last mode byte set with SetMode
+    pub fn drive_mode(&self) -> u8 {
+        self.drive_mode
+    }
+
+    pub fn speed_change_in_progress(&self) -> bool {
+        self.speed_change.is_some()
+    }
+
+    fn speed_change_remaining(&self, scheduler: &Scheduler) -> Option<CpuCycles> {
+        scheduler.cycles_remaining(self.speed_change.as_ref()?)
+    }
+
+    pub(crate) fn finish_speed_change(&mut self) {
+        self.speed_change = None;
+    }
+
     fn queue_irq(&self, scheduler: &mut Scheduler) {
         // Wait 25k cycles before sending IRQ to simulate mechacon -> cpu communication delay
         scheduler.schedule_event(CDIrq, CpuCycles(1));
@@ -458,14 +871,11 @@ impl CDDrive {
 
     fn present_packet(&mut self, packet: Packet, scheduler: &mut Scheduler) {
         //println!("Presenting packet with cause {:#X}", packet.cause.bitflag());
-        self.response_queue = VecDeque::with_capacity(packet.response.len()); //Clear queue
-        self
-            .response_queue
-            .extend(packet.response.iter());
+        self.response_fifo.load(&packet.response);
 
         // This packet still needs to raise it's IRQ. Do it now
         if packet.need_irq {
-            self.reg_interrupt_flag = packet.cause.bitflag();
+            self.reg_interrupt_flag = (self.reg_interrupt_flag & 0x10) | packet.cause.bitflag();
             //println!("Raising IRQ because it wasn't done earlier");
             if self.reg_interrupt_enable & packet.cause.bitflag() == packet.cause.bitflag()
             {
@@ -480,7 +890,7 @@ impl CDDrive {
     }
 }
 
-pub fn cdpacket_event(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Scheduler, packet_id: u32) {
+pub(crate) fn cdpacket_event(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Scheduler, packet_id: u32) {
 
     let mut packet = match main_bus.cd_drive.take_packet_by_id(packet_id) {
         Some(p) => p,
@@ -498,6 +908,25 @@ pub fn cdpacket_event(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut S
         return;
     }
 
+    if main_bus.cd_drive.spin_up_packet == Some(packet_id) {
+        // The motor is up to speed. These responses were built while it was still off
+        main_bus.cd_drive.spin_up_packet = None;
+        main_bus.cd_drive.motor_state = MotorState::On;
+        packet.response[0] |= STAT_MOTOR_ON;
+        if let Some(extra_response) = &mut packet.extra_response {
+            extra_response.response[0] |= STAT_MOTOR_ON;
+        }
+    }
+
+    if packet.cause == IntCause::INT1 && packet.command == 0x6 {
+        if let Some(CpuCycles(remaining)) = main_bus.cd_drive.speed_change_remaining(scheduler) {
+            // The motor is still changing speed, so hold the sector until it's done
+            scheduler.schedule_event(CDPacket(packet.internal_id), CpuCycles(remaining + 1));
+            main_bus.cd_drive.running_commands.push(packet);
+            return;
+        }
+    }
+
     //If the response has an extra response, push that to the in progress commands
     if let Some(mut ext_response) = packet.extra_response.clone() {
         ////println!("Extra response, filling. {:?}", ext_response);
@@ -518,6 +947,29 @@ pub fn cdpacket_event(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut S
             }
         }
 
+        0x7 if packet.extra_response.is_none() => {
+            //MotorOn. The motor is up to speed by the second response
+            main_bus.cd_drive.motor_state = MotorState::On;
+            packet.response[0] |= STAT_MOTOR_ON;
+        }
+
+        0x8 if packet.extra_response.is_none() => {
+            //Stop. The motor has spun down by the second response
+            main_bus.cd_drive.motor_state = MotorState::Off;
+            packet.response[0] &= !STAT_MOTOR_ON;
+        }
+
+        0x3 if packet.cause == IntCause::INT1 => {
+            //Play. Most sectors go by without interrupting the CPU
+            match main_bus.cd_drive.play_sector(scheduler) {
+                Some((cause, response)) => {
+                    packet.cause = cause;
+                    packet.response = response;
+                }
+                None => return,
+            }
+        }
+
         0x9 => {
             //pause
             if packet.extra_response.is_none() {
@@ -528,66 +980,69 @@ pub fn cdpacket_event(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut S
         0x6 => {
             //ReadN
             if packet.cause == IntCause::INT1 {
-                let new_sector = main_bus.cd_drive
-                    .disc
-                    .as_ref()
-                    .expect("Tried to read nonexistent disc!")
-                    .read_sector(
-                        main_bus.cd_drive.next_seek_target
-                            .plus_sector_offset(main_bus.cd_drive.read_offset),
-                    );
-
-                //println!("Read {} from disc. Read offset {}", new_sector.index(), main_bus.cd_drive.read_offset);
-
-                main_bus.cd_drive.read_offset += 1;
+                let location = main_bus.cd_drive.read_location();
+                let disc = main_bus.cd_drive.disc.as_ref();
+                let audio = disc.is_some_and(|disc| disc.is_audio(location));
+                // CD-DA sectors can only be read with mode bit 0 set
+                let new_sector = disc
+                    .and_then(|disc| disc.read_sector(location))
+                    .filter(|_| !audio || main_bus.cd_drive.drive_mode.get_bit(0));
+
+                if let Some(new_sector) = new_sector {
+                    //println!("Read {} from disc. Read offset {}", new_sector.index(), main_bus.cd_drive.read_offset);
+
+                    main_bus.cd_drive.read_offset += 1;
+                    // CD-DA sectors have no header
+                    main_bus.cd_drive.last_sector_header = (!audio).then(|| new_sector.header());
+
+                    let xa_audio = !audio && main_bus.cd_drive.is_xa_audio(&new_sector);
+                    if xa_audio {
+                        main_bus.cd_drive.play_xa_sector(&new_sector);
+                    } else {
+                        // The sector size is latched as each sector is read, so SetMode only affects
+                        // sectors read after it. CD-DA sectors have no header, so they are always whole
+                        let size = match audio {
+                            true => SectorSize::WholeSector,
+                            false => *main_bus.cd_drive.sector_size(),
+                        };
+                        main_bus.cd_drive.sector_buffer.push(new_sector, size);
+                    }
 
-                if main_bus.cd_drive.data_queue.len() >= 2 {
-                    ////println!("DROPPED SECTOR");
-                }
+                    if main_bus.cd_drive.read_enabled {
+                        //println!("Inserting next ReadN");
+                        let response_packet = main_bus.cd_drive.sector_packet(0x6);
+                        scheduler.schedule_event(CDPacket(response_packet.internal_id), CpuCycles(response_packet.execution_cycles));
+                        main_bus.cd_drive.running_commands.push(response_packet);
+                    }
 
-                // Get rid of all the middle sectors, leave only the oldest
-
-                // if main_bus.cd_drive.data_queue.len() > 1 {
-                //     main_bus.cd_drive
-                //         .data_queue
-                //         .drain(1..main_bus.cd_drive.data_queue.len());
-
-                // }
-
-                //main_bus.cd_drive.data_queue.clear();
-                main_bus.cd_drive.data_queue.push(new_sector);
-
-                if main_bus.cd_drive.read_enabled {
-                    //println!("Inserting next ReadN");
-                    let cycles = match main_bus.cd_drive.drive_speed() {
-                        DriveSpeed::Single => 0x686da,
-                        DriveSpeed::Double => 0x322df,
-                    };
-                    let response_packet = Packet {
-                        internal_id: main_bus.cd_drive.next_packet_id(),
-                        cause: IntCause::INT1,
-                        response: vec![main_bus.cd_drive.get_stat()],
-                        execution_cycles: cycles,
-                        extra_response: None,
-                        command: 0x6,
-                        need_irq: false
-                    };
-                    scheduler.schedule_event(CDPacket(response_packet.internal_id), CpuCycles(response_packet.execution_cycles));
-                    main_bus.cd_drive.running_commands.push(response_packet);
+                    if xa_audio {
+                        // The CPU never hears about sectors that went to the audio output
+                        return;
+                    }
+                } else {
+                    // Read past the end of the disc, into a CD-DA track without mode bit 0, or there
+                    // is no disc at all. Stop reading and report it
+                    main_bus.cd_drive.read_enabled = false;
+                    main_bus.cd_drive.drive_state = DriveState::Idle;
+                    let error_packet = error(&mut main_bus.cd_drive, 0x6, ERROR_SEEK_FAILED);
+                    packet.cause = error_packet.cause;
+                    packet.response = error_packet.response;
                 }
             }
         }
         _ => (), //No actions for this command
     };
 
-    if main_bus.cd_drive.reg_interrupt_flag != 0 {
+    // INT10 sits in its own bit, so it doesn't hold back responses
+    if main_bus.cd_drive.reg_interrupt_flag & 0x7 != 0 {
         //println!("need_irq branch");
         // There is a pending IRQ. Save this info so we know to raise the IRQ later
         packet.need_irq = true;
     } else {
         //println!("Immediate IRQ branch");
         // There is no pending IRQ. Raise the IRQ and present it now
-        main_bus.cd_drive.reg_interrupt_flag = packet.cause.bitflag();
+        main_bus.cd_drive.reg_interrupt_flag =
+            (main_bus.cd_drive.reg_interrupt_flag & 0x10) | packet.cause.bitflag();
             
         if main_bus.cd_drive.reg_interrupt_enable & packet.cause.bitflag() == packet.cause.bitflag()
         {
@@ -601,3 +1056,538 @@ pub fn cdpacket_event(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut S
     // Insert this packet into the queue
     main_bus.cd_drive.queue_ready_packet(packet);
 }
+
+#[cfg(test)]
+mod cdrom_tests {
+    use super::*;
+    use crate::{bios::BIOS_SIZE, PSXEmu};
+
+    const CD_INDEX: u32 = 0x1F80_1800;
+    const CD_COMMAND: u32 = 0x1F80_1801;
+    const CD_REQUEST: u32 = 0x1F80_1803;
+    const IDLE_LOOP_ADDR: u32 = 0x8002_0000;
+
+    fn write_cd(emu: &mut PSXEmu, index: u8, addr: u32, value: u8) {
+        emu.main_bus.write_byte(CD_INDEX, index, &mut emu.scheduler);
+        emu.main_bus.write_byte(addr, value, &mut emu.scheduler);
+    }
+
+    /// Runs the machine for up to `max_cycles`, returning the first interrupt the drive raises
+    /// along with the stat byte of its response. The interrupt is acknowledged
+    fn wait_for_irq(emu: &mut PSXEmu, max_cycles: usize) -> Option<(u8, u8)> {
+        let (flag, response) = wait_for_response(emu, max_cycles, 1)?;
+        Some((flag, response[0]))
+    }
+
+    /// Like `wait_for_irq`, but returns the first `len` bytes of the response
+    fn wait_for_response(emu: &mut PSXEmu, max_cycles: usize, len: usize) -> Option<(u8, Vec<u8>)> {
+        for _ in 0..max_cycles {
+            emu.step_cycle();
+            emu.main_bus.write_byte(CD_INDEX, 1, &mut emu.scheduler);
+            let flag = emu.main_bus.read_byte(CD_REQUEST) & 0x1F;
+            if flag != 0 {
+                let response = (0..len).map(|_| emu.main_bus.read_byte(CD_COMMAND)).collect();
+                write_cd(emu, 1, CD_REQUEST, 0x1F);
+                return Some((flag, response));
+            }
+        }
+        None
+    }
+
+    /// Sends a command with its parameters, and returns the interrupt it is first answered with
+    fn send_command(emu: &mut PSXEmu, command: u8, parameters: &[u8]) -> u8 {
+        for &param in parameters {
+            write_cd(emu, 0, 0x1F80_1802, param);
+        }
+        write_cd(emu, 0, CD_COMMAND, command);
+        wait_for_irq(emu, 1_000_000).unwrap().0
+    }
+
+    /// A machine that spins in an idle loop, with a blank disc in the drive
+    fn emu_with_disc() -> PSXEmu {
+        emu_with_track(vec![0; 300 * BYTES_PER_SECTOR])
+    }
+
+    /// A machine that spins in an idle loop, with a single track disc made from `data`
+    fn emu_with_track(data: Vec<u8>) -> PSXEmu {
+        let mut disc = Disc::new("test.bin");
+        disc.add_track(DiscTrack::new(data));
+        emu_with(disc)
+    }
+
+    /// A machine that spins in an idle loop, with a 20 sector data track followed by two 20 sector
+    /// audio tracks. Every sample in an audio track is the track's number
+    fn emu_with_audio_tracks() -> PSXEmu {
+        let mut disc = Disc::new("mixed.cue");
+        disc.add_track(DiscTrack::new(vec![0; 20 * BYTES_PER_SECTOR]));
+        for track in [2u16, 3] {
+            let samples = vec![track; 20 * BYTES_PER_SECTOR / 2];
+            disc.add_track(DiscTrack::audio(samples.iter().flat_map(|s| s.to_le_bytes()).collect()));
+        }
+        emu_with(disc)
+    }
+
+    fn emu_with(disc: Disc) -> PSXEmu {
+        // A zeroed BIOS runs nops until the fast exe load hook jumps to the loop
+        let mut emu = PSXEmu::new(vec![0; BIOS_SIZE]).unwrap();
+        let idle_loop = [(0x02 << 26) | (IDLE_LOOP_ADDR & 0x0FFF_FFFF) >> 2, 0];
+        let exe = idle_loop.iter().flat_map(|inst: &u32| inst.to_le_bytes()).collect();
+        emu.load_executable(IDLE_LOOP_ADDR, IDLE_LOOP_ADDR, 0, &exe);
+        emu.load_disc(disc);
+        write_cd(&mut emu, 1, 0x1F80_1802, 0x1F);
+        emu
+    }
+
+    #[test]
+    fn test_stop_then_spin_up() {
+        let mut emu = emu_with_disc();
+
+        write_cd(&mut emu, 0, CD_COMMAND, 0x8);
+        let (flag, stat) = wait_for_irq(&mut emu, 1_000_000).unwrap();
+        assert_eq!(flag, 3);
+        assert_ne!(stat & STAT_MOTOR_ON, 0, "motor is still spinning down");
+        let (flag, stat) = wait_for_irq(&mut emu, 1_000_000).unwrap();
+        assert_eq!(flag, 2);
+        assert_eq!(stat & STAT_MOTOR_ON, 0);
+
+        write_cd(&mut emu, 0, CD_COMMAND, 0x1);
+        let (_, stat) = wait_for_irq(&mut emu, 1_000_000).unwrap();
+        assert_eq!(stat & STAT_MOTOR_ON, 0, "GetStat sees the stopped motor");
+
+        // ReadN normally answers within 50k cycles, but has to wait for the motor now
+        write_cd(&mut emu, 0, CD_COMMAND, 0x6);
+        assert!(wait_for_irq(&mut emu, 1_000_000).is_none());
+        assert_eq!(emu.main_bus.cd_drive.motor_state, MotorState::SpinUp);
+    }
+
+    #[test]
+    fn test_motor_on() {
+        let mut emu = emu_with_disc();
+        write_cd(&mut emu, 0, CD_COMMAND, 0x7);
+        let (flag, response) = wait_for_response(&mut emu, 1_000_000, 2).unwrap();
+        assert_eq!(flag, 5, "the motor is already on");
+        assert_eq!(response[1], ERROR_WRONG_PARAMETER_COUNT);
+
+        send_command(&mut emu, 0x8, &[]);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 2);
+
+        write_cd(&mut emu, 0, CD_COMMAND, 0x7);
+        let (flag, stat) = wait_for_irq(&mut emu, 1_000_000).unwrap();
+        assert_eq!(flag, 3);
+        assert_eq!(stat & STAT_MOTOR_ON, 0);
+        assert_eq!(emu.main_bus.cd_drive.motor_state, MotorState::SpinUp);
+
+        let (flag, stat) = wait_for_irq(&mut emu, SPIN_UP_CYCLES as usize + 100_000).unwrap();
+        assert_eq!(flag, 2);
+        assert_ne!(stat & STAT_MOTOR_ON, 0);
+        assert_eq!(emu.main_bus.cd_drive.motor_state, MotorState::On);
+    }
+
+    #[test]
+    fn test_every_command_answers() {
+        // The first response to each command, with its parameters. None is an INT3 acknowledge,
+        // Some is an INT5 with that error code
+        let commands: [(u8, &[u8], Option<u8>); 0x20] = [
+            (0x00, &[], Some(ERROR_INVALID_COMMAND)), // Sync
+            (0x01, &[], None),
+            (0x02, &[0x00, 0x02, 0x00], None),
+            (0x03, &[], None),
+            (0x04, &[], Some(ERROR_INVALID_COMMAND)), // Forward, not emulated
+            (0x05, &[], Some(ERROR_INVALID_COMMAND)), // Backward, not emulated
+            (0x06, &[], None),
+            (0x07, &[], Some(ERROR_WRONG_PARAMETER_COUNT)), // The motor is already on
+            (0x08, &[], None),
+            (0x09, &[], None),
+            (0x0A, &[], None),
+            (0x0B, &[], None),
+            (0x0C, &[], None),
+            (0x0D, &[0x01, 0x00], None),
+            (0x0E, &[0x80], None),
+            (0x0F, &[], Some(ERROR_INVALID_COMMAND)), // GetParam, not emulated
+            (0x10, &[], Some(ERROR_CANNOT_RESPOND)), // Nothing has been read yet
+            (0x11, &[], None),
+            (0x12, &[0x01], Some(ERROR_INVALID_COMMAND)), // SetSession, not emulated
+            (0x13, &[], None),
+            (0x14, &[0x01], None),
+            (0x15, &[], None),
+            (0x16, &[], None),
+            (0x17, &[], Some(ERROR_INVALID_COMMAND)), // SetClock, not on retail drives
+            (0x18, &[], Some(ERROR_INVALID_COMMAND)), // GetClock, not on retail drives
+            (0x19, &[0x20], None),
+            (0x1A, &[], None),
+            (0x1B, &[], None),
+            (0x1C, &[], Some(ERROR_INVALID_COMMAND)), // Reset, not emulated
+            (0x1D, &[], Some(ERROR_INVALID_COMMAND)), // GetQ, not emulated
+            (0x1E, &[], None),
+            (0x1F, &[], Some(ERROR_INVALID_COMMAND)), // VideoCD, only on the SCPH-5903
+        ];
+        for (command, parameters, error_code) in commands {
+            let mut emu = emu_with_disc();
+            for &param in parameters {
+                write_cd(&mut emu, 0, 0x1F80_1802, param);
+            }
+            write_cd(&mut emu, 0, CD_COMMAND, command);
+            let (flag, response) = wait_for_response(&mut emu, 1_000_000, 2)
+                .unwrap_or_else(|| panic!("Command {:#X} was never answered", command));
+            match error_code {
+                None => assert_eq!(flag, 3, "Command {:#X}", command),
+                Some(code) => {
+                    assert_eq!(flag, 5, "Command {:#X}", command);
+                    assert_eq!(response[0] & 0x1, 0x1, "Command {:#X} stat", command);
+                    assert_eq!(response[1], code, "Command {:#X} error", command);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parameter_fifo_full() {
+        let mut emu = emu_with_disc();
+        emu.main_bus.write_byte(CD_INDEX, 0, &mut emu.scheduler);
+        let status = |emu: &mut PSXEmu| emu.main_bus.read_byte(CD_INDEX);
+        assert_eq!(status(&mut emu) & 0x18, 0x18, "empty and not full");
+
+        for count in 1..=20u8 {
+            emu.main_bus.write_byte(0x1F80_1802, count, &mut emu.scheduler);
+            let stat = status(&mut emu);
+            assert_eq!(stat & 0x08, 0, "not empty after {} writes", count);
+            assert_eq!(stat & 0x10 != 0, count < 16, "full bit after {} writes", count);
+        }
+
+        let parameters: Vec<u8> = emu.main_bus.cd_drive.parameter_queue.iter().copied().collect();
+        assert_eq!(parameters, (1..=16).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_response_fifo_wraps() {
+        let mut emu = emu_with_disc();
+        // Test(20) leaves its 4 byte response in the FIFO
+        assert_eq!(send_command(&mut emu, 0x19, &[0x20]), 3);
+
+        write_cd(&mut emu, 0, CD_COMMAND, 0x01);
+        emu.main_bus.write_byte(CD_INDEX, 1, &mut emu.scheduler);
+        while emu.main_bus.read_byte(CD_REQUEST) & 0x1F == 0 {
+            emu.step_cycle();
+        }
+        let rslrrdy = |emu: &mut PSXEmu| emu.main_bus.read_byte(CD_INDEX) & 0x20 != 0;
+        assert!(rslrrdy(&mut emu));
+        let stat = emu.main_bus.read_byte(CD_COMMAND);
+        assert!(!rslrrdy(&mut emu), "GetStat only answers with one byte");
+
+        let rest: Vec<u8> = (0..19).map(|_| emu.main_bus.read_byte(CD_COMMAND)).collect();
+        let mut expected = vec![0x09, 0x19, 0xC0];
+        expected.extend([0; 12]);
+        expected.extend([stat, 0x09, 0x19, 0xC0]);
+        assert_eq!(rest, expected);
+    }
+
+    #[test]
+    fn test_reset_cancels_read() {
+        for soft in [true, false] {
+            let mut emu = emu_with_disc();
+            assert_eq!(send_command(&mut emu, 0x06, &[]), 3);
+            if soft {
+                emu.soft_reset();
+            } else {
+                emu.reset();
+            }
+            assert!(wait_for_irq(&mut emu, 2_000_000).is_none(), "a sector arrived after the reset");
+            assert!(emu.loaded_disc().is_some());
+        }
+    }
+
+    #[test]
+    fn test_play_auto_pauses_at_track_end() {
+        let mut emu = emu_with_audio_tracks();
+        assert_eq!(send_command(&mut emu, 0xE, &[0x02]), 3);
+        // The last sector of track 2
+        assert_eq!(send_command(&mut emu, 0x2, &[0x00, 0x02, 0x39]), 3);
+        assert_eq!(send_command(&mut emu, 0x3, &[]), 3);
+
+        let (flag, stat) = wait_for_irq(&mut emu, 1_000_000).unwrap();
+        assert_eq!(flag, 4);
+        assert_eq!(stat & 0x80, 0, "still playing");
+        assert_eq!(emu.main_bus.cd_drive.drive_state, DriveState::Idle);
+        assert!(wait_for_irq(&mut emu, 1_000_000).is_none());
+    }
+
+    #[test]
+    fn test_play_continues_into_next_track() {
+        let mut emu = emu_with_audio_tracks();
+        // Reports, but no auto-pause
+        assert_eq!(send_command(&mut emu, 0xE, &[0x04]), 3);
+        assert_eq!(send_command(&mut emu, 0x2, &[0x00, 0x02, 0x39]), 3);
+        assert_eq!(send_command(&mut emu, 0x3, &[]), 3);
+
+        // 00:02:40 is the first sector of track 3, and reports where it is on the disc
+        let (flag, report) = wait_for_response(&mut emu, 1_000_000, 8).unwrap();
+        assert_eq!(flag, 1);
+        assert_eq!(report[1..], [0x03, 0x01, 0x00, 0x02, 0x40, 0, 0]);
+        assert_eq!(emu.main_bus.cd_drive.audio_queue.back(), Some(&(3, 3)));
+
+        // Ten sectors later the report is the position in the track
+        let (flag, report) = wait_for_response(&mut emu, 5_000_000, 8).unwrap();
+        assert_eq!(flag, 1);
+        assert_ne!(report[0] & 0x80, 0);
+        assert_eq!(report[1..], [0x03, 0x01, 0x00, 0x80, 0x10, 0, 0]);
+    }
+
+    #[test]
+    fn test_read_audio_track() {
+        let mut emu = emu_with_audio_tracks();
+        // The first sector of track 2
+        assert_eq!(send_command(&mut emu, 0x2, &[0x00, 0x02, 0x20]), 3);
+        assert_eq!(send_command(&mut emu, 0x6, &[]), 3);
+        let (flag, response) = wait_for_response(&mut emu, 1_000_000, 2).unwrap();
+        assert_eq!(flag, 5);
+        assert_eq!(response[1], ERROR_SEEK_FAILED);
+
+        // With CD-DA reads allowed the raw sector comes through
+        assert_eq!(send_command(&mut emu, 0xE, &[0x01]), 3);
+        assert_eq!(send_command(&mut emu, 0x2, &[0x00, 0x02, 0x20]), 3);
+        assert_eq!(send_command(&mut emu, 0x6, &[]), 3);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 1);
+        write_cd(&mut emu, 0, CD_REQUEST, 0x80);
+        assert_eq!(emu.main_bus.cd_drive.pop_data(), 2);
+    }
+
+    #[test]
+    fn test_getloc_follows_reads() {
+        // Mode 2 sectors with their own position in the header, from file 1 channel 2
+        let mut data = vec![0; 300 * BYTES_PER_SECTOR];
+        for (n, sector) in data.chunks_mut(BYTES_PER_SECTOR).enumerate() {
+            let index = DiscIndex::new_dec(0, 2, 0).plus_sector_offset(n);
+            let position = [index.minutes(), index.seconds(), index.sectors()].map(|v| dec_to_bcd(v) as u8);
+            sector[12..15].copy_from_slice(&position);
+            sector[15..20].copy_from_slice(&[2, 1, 2, 0x08, 0]);
+        }
+        let mut emu = emu_with_track(data);
+
+        // Nothing has been read yet
+        assert_eq!(send_command(&mut emu, 0x10, &[]), 5);
+
+        assert_eq!(send_command(&mut emu, 0x2, &[0x00, 0x04, 0x70]), 3);
+        assert_eq!(send_command(&mut emu, 0x6, &[]), 3);
+        for _ in 0..8 {
+            assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 1);
+        }
+
+        // 00:04:70 through 00:05:02 have been read
+        write_cd(&mut emu, 0, CD_COMMAND, 0x11);
+        let (flag, position) = wait_for_response(&mut emu, 1_000_000, 8).unwrap();
+        assert_eq!(flag, 3);
+        assert_eq!(position, [0x01, 0x01, 0x00, 0x03, 0x02, 0x00, 0x05, 0x02]);
+
+        write_cd(&mut emu, 0, CD_COMMAND, 0x10);
+        let (flag, header) = wait_for_response(&mut emu, 1_000_000, 8).unwrap();
+        assert_eq!(flag, 3);
+        assert_eq!(header, [0x00, 0x05, 0x02, 0x02, 0x01, 0x02, 0x08, 0x00]);
+
+        // Once the next sector comes in, both move on
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 1);
+        write_cd(&mut emu, 0, CD_COMMAND, 0x11);
+        let (_, position) = wait_for_response(&mut emu, 1_000_000, 8).unwrap();
+        assert_eq!(position[2..], [0x00, 0x03, 0x03, 0x00, 0x05, 0x03]);
+    }
+
+    #[test]
+    fn test_dma_waits_for_data_request() {
+        const DPCR: u32 = 0x1F80_10F0;
+        const CD_MADR: u32 = 0x1F80_10B0;
+        const CD_BCR: u32 = 0x1F80_10B4;
+        const CD_CHCR: u32 = 0x1F80_10B8;
+        const DEST: usize = 0x1000;
+
+        // Every sector's user data is its position xored with the sector number, so the
+        // transfer shows both which sector arrived and whether its bytes are in order
+        let mut data = vec![0; 300 * BYTES_PER_SECTOR];
+        for (n, sector) in data.chunks_mut(BYTES_PER_SECTOR).enumerate() {
+            for (i, byte) in sector[24..24 + 2048].iter_mut().enumerate() {
+                *byte = i as u8 ^ n as u8;
+            }
+        }
+        let mut emu = emu_with_track(data);
+
+        // Arm a one sector transfer before there's anything to read
+        emu.main_bus.write_word(DPCR, 0x0000_8000, &mut emu.scheduler);
+        emu.main_bus.write_word(CD_MADR, DEST as u32, &mut emu.scheduler);
+        emu.main_bus.write_word(CD_BCR, 512, &mut emu.scheduler);
+        emu.main_bus.write_word(CD_CHCR, 0x1100_0000, &mut emu.scheduler);
+        emu.step_cycle();
+        assert!(!emu.main_bus.cd_drive.data_request());
+        assert_ne!(emu.main_bus.read_word(CD_CHCR, &mut emu.scheduler) & (1 << 24), 0);
+
+        // Setloc 00:02:05, then ReadN
+        for param in [0x00, 0x02, 0x05] {
+            write_cd(&mut emu, 0, 0x1F80_1802, param);
+        }
+        write_cd(&mut emu, 0, CD_COMMAND, 0x2);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 3);
+        write_cd(&mut emu, 0, CD_COMMAND, 0x6);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 3);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 1);
+
+        // A sector is buffered, but it hasn't been requested yet
+        assert_ne!(emu.main_bus.read_word(CD_CHCR, &mut emu.scheduler) & (1 << 24), 0);
+        assert!(emu.main_bus.memory.data[DEST..DEST + 2048].iter().all(|&b| b == 0));
+
+        write_cd(&mut emu, 0, CD_REQUEST, 0x80);
+        assert!(emu.main_bus.cd_drive.data_request());
+        emu.step_cycle();
+
+        assert_eq!(emu.main_bus.read_word(CD_CHCR, &mut emu.scheduler) & (1 << 24), 0);
+        assert!(!emu.main_bus.cd_drive.data_request());
+        for (i, &byte) in emu.main_bus.memory.data[DEST..DEST + 2048].iter().enumerate() {
+            assert_eq!(byte, i as u8 ^ 5, "byte {} of the sector", i);
+        }
+    }
+
+    #[test]
+    fn test_clearing_bfrd_rewinds_the_data_fifo() {
+        const CD_DATA: u32 = 0x1F80_1802;
+
+        let mut data = vec![0; 300 * BYTES_PER_SECTOR];
+        for (n, sector) in data.chunks_mut(BYTES_PER_SECTOR).enumerate() {
+            for (i, byte) in sector[24..24 + 2048].iter_mut().enumerate() {
+                *byte = i as u8 ^ n as u8;
+            }
+        }
+        let mut emu = emu_with_track(data);
+        let read_data = |emu: &mut PSXEmu| -> Vec<u8> {
+            (0..8).map(|_| emu.main_bus.read_byte(CD_DATA)).collect()
+        };
+
+        // Setloc 00:02:05, then ReadN
+        for param in [0x00, 0x02, 0x05] {
+            write_cd(&mut emu, 0, 0x1F80_1802, param);
+        }
+        write_cd(&mut emu, 0, CD_COMMAND, 0x2);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 3);
+        write_cd(&mut emu, 0, CD_COMMAND, 0x6);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 3);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 1);
+
+        let first_sector: Vec<u8> = (0..8).map(|i| i ^ 5).collect();
+        write_cd(&mut emu, 0, CD_REQUEST, 0x80);
+        assert_eq!(read_data(&mut emu), first_sector);
+
+        // Without an interrupt acknowledged in between, asserting BFRD again starts the same
+        // sector over rather than moving on
+        write_cd(&mut emu, 0, CD_REQUEST, 0x00);
+        assert!(!emu.main_bus.cd_drive.data_request());
+        write_cd(&mut emu, 0, CD_REQUEST, 0x80);
+        assert!(emu.main_bus.cd_drive.data_request());
+        assert_eq!(read_data(&mut emu), first_sector);
+
+        // With BFRD clear the FIFO reads as empty
+        write_cd(&mut emu, 0, CD_REQUEST, 0x00);
+        assert_eq!(read_data(&mut emu), vec![0; 8]);
+
+        // Once the next sector's INT1 is acknowledged, BFRD loads that sector
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 1);
+        write_cd(&mut emu, 0, CD_REQUEST, 0x80);
+        assert_eq!(read_data(&mut emu), (0..8).map(|i| i ^ 6).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_smen_raises_command_start_interrupt() {
+        let mut emu = emu_with_track(vec![0; 20 * BYTES_PER_SECTOR]);
+        write_cd(&mut emu, 1, 0x1F80_1802, 0x1F);
+
+        // SMEN only covers the next command
+        write_cd(&mut emu, 0, CD_REQUEST, 0x20);
+        write_cd(&mut emu, 0, CD_COMMAND, 0x1);
+        assert_eq!(emu.main_bus.cd_drive.get_flag() & 0x10, 0x10);
+        write_cd(&mut emu, 1, CD_REQUEST, 0x10);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 3);
+
+        write_cd(&mut emu, 0, CD_COMMAND, 0x1);
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 3);
+    }
+
+    /// Raw form 2 XA-ADPCM sector from the given file and channel, holding one loud sample at the
+    /// start of its first sound unit
+    fn xa_sector(file: u8, channel: u8) -> Vec<u8> {
+        let mut sector = vec![0; BYTES_PER_SECTOR];
+        sector[15..20].copy_from_slice(&[2, file, channel, 0x24, 0]);
+        sector[24 + 16] = 0x7;
+        sector
+    }
+
+    #[test]
+    fn test_xa_filter() {
+        let mut cd_drive = CDDrive::new();
+        cd_drive.xa_filter = (1, 1);
+        let sectors = [xa_sector(1, 0), xa_sector(1, 1), xa_sector(2, 1)].map(Sector::new);
+
+        // Without mode bit 6, XA sectors are data like any other
+        assert!(!cd_drive.is_xa_audio(&sectors[1]));
+        cd_drive.drive_mode = 0x40;
+        assert!(sectors.iter().all(|sector| cd_drive.is_xa_audio(sector)));
+        assert!(!cd_drive.is_xa_audio(&Sector::new(vec![0; BYTES_PER_SECTOR])));
+
+        // Every channel plays until the filter is on
+        cd_drive.play_xa_sector(&sectors[0]);
+        let one_sector = cd_drive.audio_queue.len();
+        assert_eq!(cd_drive.audio_queue[0], (0x7000, 0x7000));
+
+        cd_drive.drive_mode = 0x48;
+        for sector in &sectors {
+            cd_drive.play_xa_sector(sector);
+        }
+        assert_eq!(cd_drive.audio_queue.len(), 2 * one_sector);
+    }
+
+    #[test]
+    fn test_read_skips_xa_audio() {
+        // Sector 0 is data, then come 4 XA audio sectors before data again
+        let mut data = vec![0; 20 * BYTES_PER_SECTOR];
+        for sector in 1..5 {
+            data[sector * BYTES_PER_SECTOR..(sector + 1) * BYTES_PER_SECTOR].copy_from_slice(&xa_sector(1, 0));
+        }
+        let mut emu = emu_with_track(data);
+
+        send_command(&mut emu, 0xE, &[0x40]);
+        send_command(&mut emu, 0x2, &[0x00, 0x02, 0x00]);
+        assert_eq!(send_command(&mut emu, 0x6, &[]), 3);
+
+        // The CPU only hears about the data sectors
+        assert_eq!(wait_for_irq(&mut emu, 1_000_000).unwrap().0, 1);
+        assert_eq!(emu.main_bus.cd_drive.read_offset, 1);
+        assert_eq!(wait_for_irq(&mut emu, 5_000_000).unwrap().0, 1);
+        assert_eq!(emu.main_bus.cd_drive.read_offset, 6);
+    }
+
+    #[test]
+    fn test_sound_map_playback() {
+        let mut cd_drive = CDDrive::new();
+        let mut scheduler = Scheduler::new();
+        let mut write = |cd_drive: &mut CDDrive, index: u8, addr: u32, value: u8| {
+            cd_drive.write_byte(CD_INDEX, index, &mut scheduler);
+            cd_drive.write_byte(addr, value, &mut scheduler);
+        };
+        // Mono 37800Hz 4 bit audio, with every sample 0x1000
+        write(&mut cd_drive, 2, CD_COMMAND, 0x00);
+        let mut sector = vec![0x11; SECTOR_AUDIO_SIZE];
+        for group in sector.chunks_mut(xa::SOUND_GROUP_SIZE) {
+            group[..16].fill(0);
+        }
+
+        // Nothing plays until the whole sector has been written
+        for &byte in &sector[..SECTOR_AUDIO_SIZE - 1] {
+            write(&mut cd_drive, 1, CD_COMMAND, byte);
+        }
+        assert_eq!(cd_drive.next_audio_sample(), (0, 0));
+        write(&mut cd_drive, 1, CD_COMMAND, sector[SECTOR_AUDIO_SIZE - 1]);
+        let queued = cd_drive.audio_queue.len();
+        assert!(queued > 0);
+        assert_eq!(cd_drive.next_audio_sample(), (0x1000, 0x1000));
+
+        // ADPCM mute drops sound map audio
+        write(&mut cd_drive, 3, CD_REQUEST, 0x01);
+        for &byte in &sector {
+            write(&mut cd_drive, 1, CD_COMMAND, byte);
+        }
+        assert_eq!(cd_drive.audio_queue.len(), queued - 1);
+    }
+}
@@ -0,0 +1,143 @@
+use super::disc::Sector;
+use super::SectorSize;
+
+pub(super) const SECTOR_BUFFER_SLOTS: usize = 8;
+
+/// Snapshot of the drive's sector buffer for debugging
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SectorBufferInfo {
+    /// Sectors read from the disc that the game hasn't loaded yet
+    pub unread: usize,
+    /// Slot the next sector from the disc will be written to
+    pub write_slot: usize,
+    /// Slot the next data FIFO load will come from
+    pub read_slot: usize,
+    /// Unread sectors that were overwritten because the game fell behind
+    pub dropped: usize,
+}
+
+/// The drive's 8 sector ring buffer. Sectors are handed to the game oldest first, which matches
+/// the order their INT1s are delivered in. When the game falls behind and the ring is full, the
+/// newest sector overwrites the oldest unread one, so after a stall the game picks up from the
+/// oldest sector still in the ring.
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub(super) struct SectorBuffer {
+    /// Each sector is stored with the size it will be loaded into the data FIFO as
+    slots: [Option<(Sector, SectorSize)>; SECTOR_BUFFER_SLOTS],
+    write_slot: usize,
+    read_slot: usize,
+    unread: usize,
+    dropped: usize,
+}
+
+impl SectorBuffer {
+    pub(super) fn new() -> Self {
+        Self {
+            slots: Default::default(),
+            write_slot: 0,
+            read_slot: 0,
+            unread: 0,
+            dropped: 0,
+        }
+    }
+
+    pub(super) fn push(&mut self, sector: Sector, size: SectorSize) {
+        if self.unread == SECTOR_BUFFER_SLOTS {
+            // The oldest unread sector is about to be overwritten
+            self.read_slot = (self.read_slot + 1) % SECTOR_BUFFER_SLOTS;
+            self.unread -= 1;
+            self.dropped += 1;
+        }
+        self.slots[self.write_slot] = Some((sector, size));
+        self.write_slot = (self.write_slot + 1) % SECTOR_BUFFER_SLOTS;
+        self.unread += 1;
+    }
+
+    /// Takes the oldest unread sector
+    pub(super) fn pop(&mut self) -> Option<(Sector, SectorSize)> {
+        if self.unread == 0 {
+            return None;
+        }
+        let sector = self.slots[self.read_slot].take();
+        self.read_slot = (self.read_slot + 1) % SECTOR_BUFFER_SLOTS;
+        self.unread -= 1;
+        sector
+    }
+
+    /// Discards every unread sector. The ring position is kept, like on hardware
+    pub(super) fn clear(&mut self) {
+        self.slots = Default::default();
+        self.read_slot = self.write_slot;
+        self.unread = 0;
+    }
+
+    pub(super) fn info(&self) -> SectorBufferInfo {
+        SectorBufferInfo {
+            unread: self.unread,
+            write_slot: self.write_slot,
+            read_slot: self.read_slot,
+            dropped: self.dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod sector_buffer_tests {
+    use super::*;
+
+    fn sector(number: u8) -> Sector {
+        let mut data = vec![0; 2352];
+        data[24] = number;
+        Sector::new(data)
+    }
+
+    fn sector_number((sector, _size): (Sector, SectorSize)) -> usize {
+        sector.data_only()[0] as usize
+    }
+
+    #[test]
+    fn test_sectors_are_read_oldest_first() {
+        let mut buffer = SectorBuffer::new();
+        buffer.push(sector(1), SectorSize::DataOnly);
+        buffer.push(sector(2), SectorSize::DataOnly);
+        assert_eq!(sector_number(buffer.pop().unwrap()), 1);
+        buffer.push(sector(3), SectorSize::DataOnly);
+        assert_eq!(sector_number(buffer.pop().unwrap()), 2);
+        assert_eq!(sector_number(buffer.pop().unwrap()), 3);
+        assert!(buffer.pop().is_none());
+        assert_eq!(buffer.info().dropped, 0);
+    }
+
+    #[test]
+    fn test_overflow_overwrites_oldest() {
+        let mut buffer = SectorBuffer::new();
+        for number in 0..SECTOR_BUFFER_SLOTS as u8 + 3 {
+            buffer.push(sector(number), SectorSize::DataOnly);
+        }
+
+        let info = buffer.info();
+        assert_eq!(info.unread, SECTOR_BUFFER_SLOTS);
+        assert_eq!(info.dropped, 3);
+        assert_eq!(info.read_slot, info.write_slot);
+
+        // Reading resumes at the oldest sector that survived, then wraps around the ring
+        for number in 3..SECTOR_BUFFER_SLOTS + 3 {
+            assert_eq!(sector_number(buffer.pop().unwrap()), number);
+        }
+        assert!(buffer.pop().is_none());
+    }
+
+    #[test]
+    fn test_clear_keeps_ring_position() {
+        let mut buffer = SectorBuffer::new();
+        buffer.push(sector(1), SectorSize::DataOnly);
+        buffer.push(sector(2), SectorSize::DataOnly);
+        buffer.clear();
+        assert!(buffer.pop().is_none());
+        assert_eq!(buffer.info().write_slot, 2);
+        assert_eq!(buffer.info().read_slot, 2);
+
+        buffer.push(sector(3), SectorSize::DataOnly);
+        assert_eq!(sector_number(buffer.pop().unwrap()), 3);
+    }
+}
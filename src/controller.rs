@@ -4,25 +4,53 @@ use bit_field::BitField;
 use log::{error, warn};
 
 use crate::cpu::{InterruptSource, R3000};
+use crate::memcard::MemoryCard;
+use crate::mmio::{JOY_BAUD, JOY_CTRL, JOY_DATA, JOY_MODE, JOY_STAT};
 use crate::scheduler::CpuCycles;
 use crate::{MainBus, Scheduler, ScheduleTarget};
 
-pub(super) const JOY_DATA: u32 = 0x1F801040;
-pub(super) const JOY_STAT: u32 = 0x1F801044;
-pub(super) const JOY_MODE: u32 = 0x1F801048;
-pub(super) const JOY_CTRL: u32 = 0x1F80104A;
-pub(super) const JOY_BAUD: u32 = 0x1F80104E;
 
 const DEFAULT_JOY_BAUD: u16 = 0x88;
 
-#[allow(dead_code)]
 const MEMORY_CARD_SELECT_BYTE: u8 = 0x81;
 const CONTROLER_SELECT_BYTE: u8 = 0x1;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ControllerType {
     DigitalPad,
 }
 
+/// Which report format a pad answers polls with. The analog button switches between Digital and
+/// Analog, unless a game has locked the mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PadMode {
+    Digital,
+    Analog,
+    /// Analog, with the analog button disabled. Only games can set or clear this
+    AnalogLocked,
+}
+
+impl PadMode {
+    fn id_byte(&self) -> u8 {
+        match self {
+            PadMode::Digital => 0x41,
+            PadMode::Analog | PadMode::AnalogLocked => 0x73,
+        }
+    }
+
+    /// Index of the last byte of a poll response
+    fn last_step(&self) -> usize {
+        match self {
+            PadMode::Digital => 3,
+            PadMode::Analog | PadMode::AnalogLocked => 7,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ButtonState {
     pub controller_type: ControllerType,
 
@@ -108,18 +136,21 @@ impl ButtonState {
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 enum Slot {
     MemoryCard,
     Controller,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 enum TXstate {
     Disabled,
     Ready,
-    Transfering { slot: Slot, step: usize },
+    Transfering { slot: Slot, port: usize, step: usize },
 }
 
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub(super) struct Controllers {
     joy_ctrl: u16,
     joy_baud: u16,
@@ -132,6 +163,22 @@ pub(super) struct Controllers {
     pub(super) pending_irq: bool,
 
     latest_button_state: ButtonState,
+    /// Pad in port 2, if one is connected
+    port2_button_state: Option<ButtonState>,
+    /// Updates that arrived while a pad was being polled. Applied at the next vblank, so a poll
+    /// never mixes two states and the next one sees the update
+    pending_button_state: Option<ButtonState>,
+    pending_port2_state: Option<Option<ButtonState>>,
+
+    /// Card in port 1, if one is inserted
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    pub(super) memory_card: Option<MemoryCard>,
+
+    /// Mode of the pad in each port
+    modes: [PadMode; 2],
+    /// Mode the current poll started in. Toggling mid poll only applies from the next one,
+    /// otherwise the ID byte already sent wouldn't match the rest of the response
+    transfer_mode: PadMode,
 }
 
 impl Controllers {
@@ -148,11 +195,107 @@ impl Controllers {
             pending_irq: false,
 
             latest_button_state: ButtonState::new_digital_pad(),
+            port2_button_state: None,
+            pending_button_state: None,
+            pending_port2_state: None,
+
+            memory_card: None,
+
+            modes: [PadMode::Digital; 2],
+            transfer_mode: PadMode::Digital,
+        }
+    }
+
+    /// Back to the power on state, unlike the JOY_CTRL reset. The pads, their modes and the card stay
+    /// plugged in
+    pub(super) fn power_on_reset(&mut self) {
+        let mut old = std::mem::replace(self, Controllers::new());
+        // Nothing is being polled after a reset
+        old.apply_pending_state();
+        self.latest_button_state = old.latest_button_state;
+        self.port2_button_state = old.port2_button_state;
+        self.memory_card = old.memory_card;
+        self.modes = old.modes;
+    }
+
+    /// Moves the memory card over from `old`, which these ports replace
+    #[cfg(feature = "savestate")]
+    pub(super) fn keep_host_state(&mut self, old: Controllers) {
+        self.memory_card = old.memory_card;
+    }
+
+    pub(super) fn mode(&self, port: usize) -> PadMode {
+        self.modes[port]
+    }
+
+    /// Presses the pad's analog button, which flips between digital and analog unless locked
+    pub(super) fn press_analog_button(&mut self, port: usize) {
+        self.modes[port] = match self.modes[port] {
+            PadMode::Digital => PadMode::Analog,
+            PadMode::Analog => PadMode::Digital,
+            PadMode::AnalogLocked => PadMode::AnalogLocked,
+        };
+    }
+
+    /// How far into a pad or memory card exchange the serial port is
+    pub(super) fn in_flight_state(&self) -> u64 {
+        match self.tx_state {
+            TXstate::Disabled => 0,
+            TXstate::Ready => 1,
+            TXstate::Transfering { slot, port, step } => {
+                2 | (slot as u64) << 8 | (port as u64) << 16 | (step as u64) << 32
+            }
         }
     }
 
     pub(super) fn update_button_state(&mut self, new_state: ButtonState) {
-        self.latest_button_state = new_state;
+        if self.polling_pad() {
+            self.pending_button_state = Some(new_state);
+        } else {
+            self.latest_button_state = new_state;
+            self.pending_button_state = None;
+        }
+    }
+
+    /// Connects a pad to port 2 with the given state, or disconnects it with None
+    pub(super) fn update_port2_button_state(&mut self, new_state: Option<ButtonState>) {
+        if self.polling_pad() {
+            self.pending_port2_state = Some(new_state);
+        } else {
+            self.port2_button_state = new_state;
+            self.pending_port2_state = None;
+        }
+    }
+
+    /// Called as vblank starts
+    pub(super) fn vblank(&mut self) {
+        self.apply_pending_state();
+    }
+
+    fn apply_pending_state(&mut self) {
+        if let Some(state) = self.pending_button_state.take() {
+            self.latest_button_state = state;
+        }
+        if let Some(state) = self.pending_port2_state.take() {
+            self.port2_button_state = state;
+        }
+    }
+
+    fn polling_pad(&self) -> bool {
+        matches!(
+            self.tx_state,
+            TXstate::Transfering {
+                slot: Slot::Controller,
+                ..
+            }
+        )
+    }
+
+    fn pad(&self, port: usize) -> Option<&ButtonState> {
+        match port {
+            0 => Some(&self.latest_button_state),
+            _ => self.port2_button_state.as_ref(),
+        }
     }
 
     pub(super) fn write_half_word(&mut self, addr: u32, val: u16) {
@@ -161,6 +304,7 @@ impl Controllers {
             JOY_BAUD => self.write_joy_baud(val),
             JOY_MODE => self.write_joy_mode(val),
             _ => error!(
+                target: "psx::pad",
                 "CONTROLLER: Unknown half word write! Addr {:#X} val: {:#X}",
                 addr, val
             ),
@@ -172,7 +316,7 @@ impl Controllers {
             JOY_STAT => self.read_joy_stat(),
             JOY_CTRL => self.read_joy_ctrl(),
             _ => {
-                error!("CONTROLLER: Unknown half word read! Addr {:#X}", addr);
+                error!(target: "psx::pad", "CONTROLLER: Unknown half word read! Addr {:#X}", addr);
                 0
             }
         }
@@ -182,7 +326,7 @@ impl Controllers {
         match addr {
             JOY_DATA => self.read_joy_data() as u8,
             _ => {
-                error!("CONTROLLER: Unknown byte read! Addr {:#X}", addr);
+                error!(target: "psx::pad", "CONTROLLER: Unknown byte read! Addr {:#X}", addr);
                 0
             }
         }
@@ -192,6 +336,7 @@ impl Controllers {
         match addr {
             JOY_DATA => self.write_joy_data(val, scheduler),
             _ => error!(
+                target: "psx::pad",
                 "CONTROLLER: Unknown byte write! Addr {:#X} val: {:#X}",
                 addr, val
             ),
@@ -234,7 +379,7 @@ impl Controllers {
         //println!("Joy data written {:#X} state = {:?}", val, self.tx_state);
         let new_state = match self.tx_state.clone() {
             TXstate::Disabled => {
-                warn!("CONTROLLER: Tried to write JOY_DATA while TX is disabled!");
+                warn!(target: "psx::pad", "CONTROLLER: Tried to write JOY_DATA while TX is disabled!");
                 TXstate::Disabled
             }
             TXstate::Ready => {
@@ -246,52 +391,77 @@ impl Controllers {
                     return;
                 };
 
-                if slot == Slot::MemoryCard {
+                if !self.joy_ctrl.get_bit(13) && !self.joy_ctrl.get_bit(1) {
+                    // Nothing is selected
                     self.push_rx_buf(0);
                     return;
                 }
 
-                if !self.joy_ctrl.get_bit(13) && !self.joy_ctrl.get_bit(1)
-                    || self.joy_ctrl.get_bit(13) && self.joy_ctrl.get_bit(1)
-                {
-                    // Controller 2
+                let port = if self.joy_ctrl.get_bit(13) && self.joy_ctrl.get_bit(1) { 1 } else { 0 };
+                if port == 1 && (slot == Slot::MemoryCard || self.port2_button_state.is_none()) {
+                    // Port 2 never has a card, and only has a pad when one is connected
                     self.push_rx_buf(0);
                     return;
                 }
 
+                if slot == Slot::MemoryCard && self.memory_card.is_none() {
+                    // No card inserted, the line stays high and nothing acknowledges
+                    self.push_rx_buf(0xFF);
+                    return;
+                }
+
                 self.push_rx_buf(0);
                 self.queue_interrupt(scheduler);
+                if slot == Slot::Controller {
+                    self.transfer_mode = self.modes[port];
+                }
                 TXstate::Transfering {
                     slot: slot,
+                    port,
                     step: 0,
                 }
             }
-            TXstate::Transfering { slot, step } => {
+            TXstate::Transfering { slot, port, step } => {
                 if slot == Slot::Controller {
                     if step == 0 && val != 0x42 {
                         // Invalid command for digital pad. Send junk
                         self.push_rx_buf(0xFF);
                         TXstate::Ready
                     } else {
-                        // Normal digital pad communication
+                        // Normal pad communication. Analog mode adds the sticks, which are
+                        // always centered for now
+                        let pad = self.pad(port).copied().unwrap_or(ButtonState::new_digital_pad());
+                        let mode = self.transfer_mode;
                         let response = match step {
-                            0 => 0x41, // Digital pad idlo
-                            1 => 0x5A, // Digital pad idhi
-                            2 => self.latest_button_state.digital_low_byte(),
-                            3 => self.latest_button_state.digital_high_byte(),
+                            0 => mode.id_byte(), // idlo
+                            1 => 0x5A,           // idhi
+                            2 => pad.digital_low_byte(),
+                            3 => pad.digital_high_byte(),
+                            _ if step <= mode.last_step() => 0x80,
                             _ => 0,
                         };
                         self.push_rx_buf(response);
-                        if step < 3 {
+                        if step < mode.last_step() {
                             self.queue_interrupt(scheduler);
                         }
                         TXstate::Transfering {
                             slot: slot.clone(),
+                            port,
                             step: step + 1,
                         }
                     }
                 } else {
-                    panic!("Tried to read memory card! It's not implemented yet :(");
+                    let (response, ack) = match self.memory_card.as_mut() {
+                        Some(card) => card.transfer(step, val),
+                        None => (0xFF, false),
+                    };
+                    self.push_rx_buf(response);
+                    if ack {
+                        self.queue_interrupt(scheduler);
+                        TXstate::Transfering { slot, port, step: step + 1 }
+                    } else {
+                        TXstate::Ready
+                    }
                 }
             }
         };
@@ -377,3 +547,83 @@ pub(super) fn controller_delay_event(cpu: &mut R3000, state: &mut Controllers) {
         state.pending_irq = false;
     }
 }
+
+#[cfg(test)]
+mod controller_tests {
+    use super::*;
+
+    /// Selects port 1 and polls its pad, toggling analog mode after `toggle_after` bytes
+    fn poll(controllers: &mut Controllers, toggle_after: Option<usize>) -> Vec<u8> {
+        let mut scheduler = Scheduler::new();
+        controllers.write_joy_ctrl(0x1003);
+        let mut response = vec![];
+        for (i, byte) in [0x01, 0x42, 0, 0, 0, 0, 0, 0, 0].into_iter().enumerate() {
+            if toggle_after == Some(i) {
+                controllers.press_analog_button(0);
+            }
+            controllers.write_joy_data(byte, &mut scheduler);
+            response.push(controllers.read_joy_data());
+        }
+        controllers.write_joy_ctrl(0);
+        response
+    }
+
+    #[test]
+    fn test_analog_button_switches_report() {
+        let mut controllers = Controllers::new();
+        assert_eq!(controllers.mode(0), PadMode::Digital);
+        assert_eq!(&poll(&mut controllers, None)[1..3], &[0x41, 0x5A]);
+
+        controllers.press_analog_button(0);
+        assert_eq!(controllers.mode(0), PadMode::Analog);
+        assert_eq!(controllers.mode(1), PadMode::Digital);
+        assert_eq!(
+            poll(&mut controllers, None),
+            vec![0, 0x73, 0x5A, 0xFF, 0xFF, 0x80, 0x80, 0x80, 0x80]
+        );
+
+        controllers.modes[0] = PadMode::AnalogLocked;
+        controllers.press_analog_button(0);
+        assert_eq!(controllers.mode(0), PadMode::AnalogLocked);
+    }
+
+    #[test]
+    fn test_update_mid_poll_waits_for_vblank() {
+        let mut scheduler = Scheduler::new();
+        let mut controllers = Controllers::new();
+        let mut pressed = ButtonState::new_digital_pad();
+        pressed.button_x = true;
+
+        // Between polls the update applies straight away
+        controllers.update_button_state(pressed);
+        assert_eq!(poll(&mut controllers, None)[4], 0xBF);
+        controllers.update_button_state(ButtonState::new_digital_pad());
+
+        controllers.write_joy_ctrl(0x1003);
+        controllers.write_joy_data(0x01, &mut scheduler);
+        controllers.read_joy_data();
+        controllers.update_button_state(pressed);
+        let response: Vec<u8> = [0x42, 0, 0, 0]
+            .into_iter()
+            .map(|byte| {
+                controllers.write_joy_data(byte, &mut scheduler);
+                controllers.read_joy_data()
+            })
+            .collect();
+        controllers.write_joy_ctrl(0);
+        assert_eq!(&response[2..], &[0xFF, 0xFF]);
+        assert_eq!(poll(&mut controllers, None)[4], 0xFF);
+
+        controllers.vblank();
+        assert_eq!(poll(&mut controllers, None)[4], 0xBF);
+    }
+
+    #[test]
+    fn test_toggle_mid_poll_waits_for_next_poll() {
+        let mut controllers = Controllers::new();
+        let response = poll(&mut controllers, Some(3));
+        assert_eq!(response, vec![0, 0x41, 0x5A, 0xFF, 0xFF, 0, 0, 0, 0]);
+        assert_eq!(controllers.mode(0), PadMode::Analog);
+        assert_eq!(poll(&mut controllers, None)[1], 0x73);
+    }
+}
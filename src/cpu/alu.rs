@@ -0,0 +1,301 @@
+//! Arithmetic, logic, shift and compare semantics for the R3000, without any CPU state. The
+//! interpreter calls these after reading its operands, so they can be checked in isolation.
+//! Immediate forms take the already extended immediate as `b`.
+
+/// ADD/ADDI. The flag is set when the signed result overflows, which raises Ovf and leaves the
+/// destination untouched
+pub(super) fn add(a: u32, b: u32) -> (u32, bool) {
+    let (result, overflow) = (a as i32).overflowing_add(b as i32);
+    (result as u32, overflow)
+}
+
+/// ADDU/ADDIU
+pub(super) fn addu(a: u32, b: u32) -> u32 {
+    a.wrapping_add(b)
+}
+
+/// SUB. Overflows like `add`
+pub(super) fn sub(a: u32, b: u32) -> (u32, bool) {
+    let (result, overflow) = (a as i32).overflowing_sub(b as i32);
+    (result as u32, overflow)
+}
+
+/// SUBU
+pub(super) fn subu(a: u32, b: u32) -> u32 {
+    a.wrapping_sub(b)
+}
+
+pub(super) fn and(a: u32, b: u32) -> u32 {
+    a & b
+}
+
+pub(super) fn or(a: u32, b: u32) -> u32 {
+    a | b
+}
+
+pub(super) fn xor(a: u32, b: u32) -> u32 {
+    a ^ b
+}
+
+pub(super) fn nor(a: u32, b: u32) -> u32 {
+    !(a | b)
+}
+
+/// SLT/SLTI
+pub(super) fn slt(a: u32, b: u32) -> u32 {
+    ((a as i32) < (b as i32)) as u32
+}
+
+/// SLTU/SLTIU. SLTIU still sign extends its immediate before the unsigned compare
+pub(super) fn sltu(a: u32, b: u32) -> u32 {
+    (a < b) as u32
+}
+
+/// SLL/SLLV. Only the low 5 bits of the shift amount are used
+pub(super) fn sll(value: u32, shift: u32) -> u32 {
+    value << (shift & 0x1F)
+}
+
+/// SRL/SRLV
+pub(super) fn srl(value: u32, shift: u32) -> u32 {
+    value >> (shift & 0x1F)
+}
+
+/// SRA/SRAV
+pub(super) fn sra(value: u32, shift: u32) -> u32 {
+    ((value as i32) >> (shift & 0x1F)) as u32
+}
+
+/// MULT. Returns (hi, lo)
+pub(super) fn mult(a: u32, b: u32) -> (u32, u32) {
+    let result = ((a as i32) as i64 * (b as i32) as i64) as u64;
+    ((result >> 32) as u32, result as u32)
+}
+
+/// MULTU. Returns (hi, lo)
+pub(super) fn multu(a: u32, b: u32) -> (u32, u32) {
+    let result = (a as u64) * (b as u64);
+    ((result >> 32) as u32, result as u32)
+}
+
+/// DIV. Returns (hi, lo). Dividing by zero doesn't trap, it leaves the dividend in hi and -1 in lo
+/// (1 for negative dividends). i32::MIN / -1 gives i32::MIN with a remainder of 0
+pub(super) fn div(a: u32, b: u32) -> (u32, u32) {
+    let (a, b) = (a as i32, b as i32);
+    if b == 0 {
+        (a as u32, if a < 0 { 1 } else { 0xFFFF_FFFF })
+    } else if a == i32::MIN && b == -1 {
+        (0, i32::MIN as u32)
+    } else {
+        ((a % b) as u32, (a / b) as u32)
+    }
+}
+
+/// DIVU. Returns (hi, lo). Dividing by zero leaves the dividend in hi and 0xFFFFFFFF in lo
+pub(super) fn divu(a: u32, b: u32) -> (u32, u32) {
+    if b == 0 {
+        (a, 0xFFFF_FFFF)
+    } else {
+        (a % b, a / b)
+    }
+}
+
+#[cfg(test)]
+mod alu_tests {
+    use super::*;
+
+    const MIN: u32 = i32::MIN as u32;
+    const MAX: u32 = i32::MAX as u32;
+    const NEG_ONE: u32 = 0xFFFF_FFFF;
+
+    fn check<T: PartialEq + std::fmt::Debug + Copy>(
+        name: &str,
+        op: fn(u32, u32) -> T,
+        cases: &[(u32, u32, T)],
+    ) {
+        for &(a, b, expected) in cases {
+            assert_eq!(op(a, b), expected, "{} {:#X}, {:#X}", name, a, b);
+        }
+    }
+
+    #[test]
+    fn test_add_sub() {
+        check(
+            "add",
+            add,
+            &[
+                (1, 2, (3, false)),
+                (NEG_ONE, 1, (0, false)),
+                (MAX, 1, (MIN, true)),
+                (MIN, NEG_ONE, (MAX, true)),
+                (MIN, MAX, (NEG_ONE, false)),
+            ],
+        );
+        check(
+            "addu",
+            addu,
+            &[(1, 2, 3), (MAX, 1, MIN), (NEG_ONE, 1, 0), (MIN, MIN, 0)],
+        );
+        check(
+            "sub",
+            sub,
+            &[
+                (3, 2, (1, false)),
+                (0, 1, (NEG_ONE, false)),
+                (MIN, 1, (MAX, true)),
+                (MAX, NEG_ONE, (MIN, true)),
+                (0, MIN, (MIN, true)),
+                (NEG_ONE, MIN, (MAX, false)),
+            ],
+        );
+        check("subu", subu, &[(3, 2, 1), (0, 1, NEG_ONE), (MIN, 1, MAX)]);
+    }
+
+    #[test]
+    fn test_logic() {
+        let a = 0xF0F0_1234;
+        let b = 0x0FF0_FF00;
+        check(
+            "and",
+            and,
+            &[(a, b, 0x00F0_1200), (a, 0, 0), (a, NEG_ONE, a)],
+        );
+        check(
+            "or",
+            or,
+            &[(a, b, 0xFFF0_FF34), (a, 0, a), (a, NEG_ONE, NEG_ONE)],
+        );
+        check(
+            "xor",
+            xor,
+            &[(a, b, 0xFF00_ED34), (a, a, 0), (a, NEG_ONE, !a)],
+        );
+        check(
+            "nor",
+            nor,
+            &[(a, b, 0x000F_00CB), (0, 0, NEG_ONE), (a, NEG_ONE, 0)],
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        check(
+            "slt",
+            slt,
+            &[
+                (1, 2, 1),
+                (2, 1, 0),
+                (1, 1, 0),
+                (NEG_ONE, 0, 1),
+                (0, NEG_ONE, 0),
+                (MIN, MAX, 1),
+                (MAX, MIN, 0),
+            ],
+        );
+        check(
+            "sltu",
+            sltu,
+            &[
+                (1, 2, 1),
+                (2, 1, 0),
+                (1, 1, 0),
+                (NEG_ONE, 0, 0),
+                (0, NEG_ONE, 1),
+                (MIN, MAX, 0),
+                (MAX, MIN, 1),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_shifts() {
+        check(
+            "sll",
+            sll,
+            &[
+                (0x8000_0001, 0, 0x8000_0001),
+                (0x8000_0001, 1, 2),
+                (1, 31, MIN),
+                (1, 32, 1),
+                (1, 33, 2),
+            ],
+        );
+        check(
+            "srl",
+            srl,
+            &[
+                (MIN, 0, MIN),
+                (MIN, 1, 0x4000_0000),
+                (MIN, 31, 1),
+                (MIN, 32, MIN),
+            ],
+        );
+        check(
+            "sra",
+            sra,
+            &[
+                (MIN, 0, MIN),
+                (MIN, 1, 0xC000_0000),
+                (MIN, 31, NEG_ONE),
+                (MAX, 31, 0),
+                (MIN, 32, MIN),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_multiply() {
+        check(
+            "mult",
+            mult,
+            &[
+                (3, 4, (0, 12)),
+                (NEG_ONE, 1, (NEG_ONE, NEG_ONE)),
+                (NEG_ONE, NEG_ONE, (0, 1)),
+                (MIN, MIN, (0x4000_0000, 0)),
+                (MIN, NEG_ONE, (0, MIN)),
+                (MAX, MAX, (0x3FFF_FFFF, 1)),
+            ],
+        );
+        check(
+            "multu",
+            multu,
+            &[
+                (3, 4, (0, 12)),
+                (NEG_ONE, 1, (0, NEG_ONE)),
+                (NEG_ONE, NEG_ONE, (0xFFFF_FFFE, 1)),
+                (MIN, 2, (1, 0)),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_divide() {
+        check(
+            "div",
+            div,
+            &[
+                (7, 2, (1, 3)),
+                ((-7i32) as u32, 2, ((-1i32) as u32, (-3i32) as u32)),
+                (7, (-2i32) as u32, (1, (-3i32) as u32)),
+                (MIN, NEG_ONE, (0, MIN)),
+                (MIN, 1, (0, MIN)),
+                (5, 0, (5, NEG_ONE)),
+                (0, 0, (0, NEG_ONE)),
+                ((-5i32) as u32, 0, ((-5i32) as u32, 1)),
+                (MIN, 0, (MIN, 1)),
+            ],
+        );
+        check(
+            "divu",
+            divu,
+            &[
+                (7, 2, (1, 3)),
+                (NEG_ONE, 2, (1, MAX)),
+                (MIN, NEG_ONE, (MIN, 0)),
+                (5, 0, (5, NEG_ONE)),
+                (NEG_ONE, 0, (NEG_ONE, NEG_ONE)),
+            ],
+        );
+    }
+}
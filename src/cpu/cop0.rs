@@ -2,7 +2,22 @@ use bit_field::BitField;
 
 use crate::cpu::Exception;
 
+/// R3000A processor id
+const PRID: u32 = 0x0000_0002;
+
+/// Bits of each register that MTC0 can change. Unlisted registers are read only or don't exist
+fn write_mask(register_number: u8) -> u32 {
+    match register_number {
+        3 | 5 | 9 | 11 => 0xFFFF_FFFF, // BPC, BDA, BDAM, BPCM
+        7 => 0xFF80_F03F,              // DCIC
+        12 => 0xF27F_FF3F,             // SR
+        13 => 0x0000_0300,             // CAUSE, only the software interrupt bits
+        _ => 0,
+    }
+}
+
 #[derive(Debug)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct Cop0 {
     gen_registers: [u32; 32],
 }
@@ -18,28 +33,44 @@ impl Cop0 {
 
     /// Returns the value stored within the given register. Will panic if register_number > 31
     pub fn read_reg(&self, register_number: u8) -> u32 {
-        self.gen_registers[register_number as usize]
+        match register_number {
+            15 => PRID,
+            _ => self.gen_registers[register_number as usize],
+        }
     }
 
-    /// Sets register to given value. Prevents setting R0, which should always be zero. Will panic if register_number > 31
+    /// Writes register like MTC0 does. Only the writable bits of the register are changed. Will panic if register_number > 31
     pub fn write_reg(&mut self, register_number: u8, value: u32) {
-        if register_number == 13 {
-            self.gen_registers[register_number as usize] &= !0x300;
-            self.gen_registers[register_number as usize] |= value & 0x300;
-        } else {
-            self.gen_registers[register_number as usize] = value;
-        }
+        let mask = write_mask(register_number);
+        let register = &mut self.gen_registers[register_number as usize];
+        *register = (*register & !mask) | (value & mask);
+    }
+
+    /// Sets register to given value, including read only registers and bits. This is for the cpu's
+    /// own updates, like EPC and BadVaddr on exceptions. Will panic if register_number > 31
+    pub fn set_reg(&mut self, register_number: u8, value: u32) {
+        self.gen_registers[register_number as usize] = value;
     }
 
     pub fn cache_isolated(&self) -> bool {
         ((self.gen_registers[12] >> 16) & 0x1) == 1
     }
 
-    pub fn set_cause_execode(&mut self, exception: &Exception) {
+    pub(crate) fn set_cause_execode(&mut self, exception: &Exception) {
         self.gen_registers[13] =
             ((!((0x1F as u32) << 2)) & self.gen_registers[13]) | ((exception.clone() as u32) << 2);
     }
 
+    /// Sets the BD bit of cause. This is read only to software, so write_reg can't touch it
+    pub fn set_branch_delay(&mut self, branch_delay: bool) {
+        self.gen_registers[13].set_bit(31, branch_delay);
+    }
+
+    /// Sets the BT bit of cause, which says whether the branch before the faulting delay slot was taken
+    pub fn set_branch_taken(&mut self, branch_taken: bool) {
+        self.gen_registers[13].set_bit(30, branch_taken);
+    }
+
     pub fn interrupts_enabled(&self) -> bool {
         self.gen_registers[12].get_bit(0)
     }
@@ -61,4 +92,53 @@ mod cop0_tests {
         cop0.write_reg(12, 0);
         assert_eq!(cop0.cache_isolated(), false);
     }
+
+    #[test]
+    fn test_register_write_masks() {
+        let expected: [u32; 16] = [
+            0,
+            0,
+            0,
+            0xFFFF_FFFF, // BPC
+            0,
+            0xFFFF_FFFF, // BDA
+            0,           // JUMPDEST
+            0xFF80_F03F, // DCIC
+            0,           // BadVaddr
+            0xFFFF_FFFF, // BDAM
+            0,
+            0xFFFF_FFFF, // BPCM
+            0xF27F_FF3F, // SR
+            0x0000_0300, // CAUSE
+            0,           // EPC
+            PRID,
+        ];
+
+        for (register, expected) in expected.iter().enumerate() {
+            let mut cop0 = Cop0::new();
+            cop0.set_reg(register as u8, 0);
+            cop0.write_reg(register as u8, 0xFFFF_FFFF);
+            assert_eq!(cop0.read_reg(register as u8), *expected, "cop0r{} all set", register);
+        }
+    }
+
+    #[test]
+    fn test_read_only_registers_keep_hardware_values() {
+        let mut cop0 = Cop0::new();
+        for register in [6, 8, 14] {
+            cop0.set_reg(register, 0x1234_5678);
+            cop0.write_reg(register, 0);
+            assert_eq!(cop0.read_reg(register), 0x1234_5678, "cop0r{}", register);
+        }
+
+        // Only the software interrupt bits of CAUSE are writable
+        cop0.set_reg(13, 0x8000_0410);
+        cop0.write_reg(13, 0x300);
+        assert_eq!(cop0.read_reg(13), 0x8000_0710);
+        cop0.write_reg(13, 0);
+        assert_eq!(cop0.read_reg(13), 0x8000_0410);
+
+        cop0.write_reg(15, 0);
+        assert_eq!(cop0.read_reg(15), PRID);
+    }
 }
@@ -1,9 +1,11 @@
 use std::{cmp::min, mem::size_of_val};
 
 use bit_field::BitField;
+use log::warn;
 use nalgebra::clamp;
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 struct Color {
     pub r: u8,
     pub g: u8,
@@ -43,6 +45,7 @@ impl Color {
 }
 
 #[allow(non_snake_case)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub(super) struct GTE {
     // Control Registers
     ZSF3: i16,
@@ -504,7 +507,8 @@ impl GTE {
             0x3d => self.gpf(command),
             0x3e => self.gpl(command),
             0x3f => self.ncct(command),
-            _ => panic!("Unknown GTE command {:#X}!", command & 0x3F),
+            // Reserved commands don't do anything useful on hardware. Don't take the emulator down over them
+            _ => warn!(target: "psx::cpu", "Unknown GTE command {:#X}!", command & 0x3F),
         };
     }
 }
@@ -689,6 +693,10 @@ impl GTE {
         self.do_rtps(self.VX2, self.VY2, self.VZ2, shift, true, lm);
     }
 
+    /// Cross product of the screen triangle's edges. Screen y grows downwards, so MAC0 is positive
+    /// when SXY0, SXY1, SXY2 go clockwise on screen, negative when they go counterclockwise, and 0
+    /// for collinear points. Games use the sign for backface culling. Keep this in line with
+    /// `sort_points_clockwise` in the gpu
     fn nclip(&mut self) {
         self.truncate_write_mac0(
             ((self.SX0 as i64) * (self.SY1 as i64))
@@ -1566,3 +1574,204 @@ const CTRL_REG_NAME: [&str; 32] = [
     "lr1lr2", "lr3lg1", "lg2lg3", "lb1lb2", "lb3", "rfc", "gfc", "bfc", // 10
     "ofx", "ofy", "h", "dqa", "dqb", "zsf3", "zsf4", "flag", // 18
 ];
+
+#[cfg(test)]
+mod gte_tests {
+    use super::*;
+
+    #[test]
+    fn test_reserved_command_does_not_panic() {
+        let mut gte = GTE::new();
+        for command in [0x0, 0x2, 0x1A, 0x1FFFFC0] {
+            gte.execute_command(command);
+        }
+    }
+    /// Every matrix, vector and translation gets a distinct value, so picking the wrong register
+    /// shows up in the result
+    fn mvmva_gte() -> GTE {
+        let mut gte = GTE::new();
+        (gte.RT11, gte.RT12, gte.RT13) = (0x0FFF, -0x0800, 0x0123);
+        (gte.RT21, gte.RT22, gte.RT23) = (0x0400, 0x0F00, -0x0100);
+        (gte.RT31, gte.RT32, gte.RT33) = (-0x0321, 0x0200, 0x0ABC);
+        (gte.L11, gte.L12, gte.L13) = (0x0100, 0x0200, -0x0300);
+        (gte.L21, gte.L22, gte.L23) = (0x0400, -0x0500, 0x0600);
+        (gte.L31, gte.L32, gte.L33) = (0x0700, -0x0800, 0x0900);
+        (gte.LR1, gte.LR2, gte.LR3) = (0x1000, -0x0111, 0x0222);
+        (gte.LG1, gte.LG2, gte.LG3) = (0x0333, 0x0F00, -0x0444);
+        (gte.LB1, gte.LB2, gte.LB3) = (0x0555, 0x0666, 0x0777);
+        (gte.TRX, gte.TRY, gte.TRZ) = (100, -200, 300);
+        (gte.RBK, gte.GBK, gte.BBK) = (0x10, 0x20, -0x30);
+        (gte.RFC, gte.GFC, gte.BFC) = (0x100, -0x80, 0x40);
+        (gte.VX0, gte.VY0, gte.VZ0) = (0x100, -0x200, 0x300);
+        (gte.VX1, gte.VY1, gte.VZ1) = (-0x400, 0x500, 0x80);
+        (gte.VX2, gte.VY2, gte.VZ2) = (0x7FF, 0x10, -0x7FF);
+        (gte.IR0, gte.IR1, gte.IR2, gte.IR3) = (0x800, 0x123, -0x456, 0x789);
+        gte.RGBC = Color::new(0x9A, 0, 0, 0);
+        gte
+    }
+
+    fn mvmva_command(mx: u32, vx: u32, tx: u32, sf: bool, lm: bool) -> u32 {
+        0x12 | (sf as u32) << 19 | mx << 17 | vx << 15 | tx << 13 | (lm as u32) << 10
+    }
+
+    fn mac(gte: &GTE) -> [i32; 3] {
+        [gte.MAC1, gte.MAC2, gte.MAC3]
+    }
+
+    fn ir(gte: &GTE) -> [i16; 3] {
+        [gte.IR1, gte.IR2, gte.IR3]
+    }
+
+    fn nclip_gte(points: [(i16, i16); 3]) -> GTE {
+        let mut gte = GTE::new();
+        for (i, (x, y)) in points.into_iter().enumerate() {
+            gte.set_data_register(12 + i, (y as u16 as u32) << 16 | x as u16 as u32);
+        }
+        gte.execute_command(0x6);
+        gte
+    }
+
+    #[test]
+    fn test_nclip_winding() {
+        let clockwise = nclip_gte([(0, 0), (10, 0), (0, 10)]);
+        assert_eq!(clockwise.MAC0, 100);
+        let counterclockwise = nclip_gte([(0, 0), (0, 10), (10, 0)]);
+        assert_eq!(counterclockwise.MAC0, -100);
+        let collinear = nclip_gte([(-5, -5), (0, 0), (20, 20)]);
+        assert_eq!(collinear.MAC0, 0);
+        for gte in [clockwise, counterclockwise, collinear] {
+            assert_eq!(gte.control_register(31), 0);
+        }
+    }
+
+    #[test]
+    fn test_nclip_overflow() {
+        // Register writes skip the screen clamp, so the full 16 bit range can be used
+        let (min, max) = (i16::MIN, i16::MAX);
+        let gte = nclip_gte([(min, min), (max, min), (min, max)]);
+        assert_eq!(gte.MAC0, (0xFFFF_i64 * 0xFFFF) as i32);
+        assert_eq!(gte.control_register(31), 0x8001_0000);
+
+        let gte = nclip_gte([(min, min), (min, max), (max, min)]);
+        assert_eq!(gte.MAC0, (-0xFFFF_i64 * 0xFFFF) as i32);
+        assert_eq!(gte.control_register(31), 0x8000_8000);
+    }
+
+    #[test]
+    fn test_mvmva_all_combinations() {
+        // (mx, vx, tx, MAC1-3) with sf=1 and lm=0. None of these leave the IR range, so IR1-3
+        // should match the MACs and no flags should be raised
+        let expected = [
+        (0, 0, 0, [666, -664, 701]),
+        (0, 0, 1, [582, -432, 353]),
+        (0, 0, 2, [310, -528, 451]),
+        (0, 0, 3, [566, -464, 401]),
+        (0, 1, 0, [-1555, 736, 746]),
+        (0, 1, 1, [-1639, 968, 398]),
+        (0, 1, 2, [-631, 1192, 245]),
+        (0, 1, 3, [-1655, 936, 446]),
+        (0, 2, 0, [1993, 454, -1472]),
+        (0, 2, 1, [1909, 686, -1820]),
+        (0, 2, 2, [-154, 142, -1372]),
+        (0, 2, 3, [1893, 654, -1772]),
+        (0, 3, 0, [1082, -1289, 1398]),
+        (0, 3, 1, [998, -1057, 1050]),
+        (0, 3, 2, [692, -1162, 1155]),
+        (0, 3, 3, [982, -1089, 1098]),
+        (1, 0, 0, [-92, 312, 1100]),
+        (1, 0, 1, [-176, 544, 752]),
+        (1, 0, 2, [-208, 448, 688]),
+        (1, 0, 3, [-192, 512, 800]),
+        (1, 1, 0, [172, -808, -716]),
+        (1, 1, 1, [88, -576, -1064]),
+        (1, 1, 2, [136, -352, -568]),
+        (1, 1, 3, [72, -608, -1016]),
+        (1, 2, 0, [613, -461, 36]),
+        (1, 2, 1, [529, -229, -312]),
+        (1, 2, 2, [385, -773, -1160]),
+        (1, 2, 3, [513, -261, -264]),
+        (1, 3, 0, [-383, 943, 2067]),
+        (1, 3, 1, [-467, 1175, 1719]),
+        (1, 3, 2, [-501, 1070, 1640]),
+        (1, 3, 3, [-483, 1143, 1767]),
+        (2, 0, 0, [492, -834, 538]),
+        (2, 0, 1, [408, -602, 190]),
+        (2, 0, 2, [136, -685, 153]),
+        (2, 0, 3, [392, -634, 238]),
+        (2, 1, 0, [-993, 761, 530]),
+        (2, 1, 1, [-1077, 993, 182]),
+        (2, 1, 2, [-69, 1165, 571]),
+        (2, 1, 3, [-1093, 961, 230]),
+        (2, 2, 0, [1873, 770, 33]),
+        (2, 2, 1, [1789, 1002, -315]),
+        (2, 2, 2, [-274, 560, -949]),
+        (2, 2, 3, [1773, 970, -267]),
+        (2, 3, 0, [722, -1697, 853]),
+        (2, 3, 1, [638, -1465, 505]),
+        (2, 3, 2, [331, -1555, 456]),
+        (2, 3, 3, [622, -1497, 553]),
+        (3, 0, 0, [22, -164, 780]),
+        (3, 0, 1, [-62, 68, 432]),
+        (3, 0, 2, [76, 18, 240]),
+        (3, 0, 3, [-78, 36, 480]),
+        (3, 1, 0, [1550, -173, 660]),
+        (3, 1, 1, [1466, 59, 312]),
+        (3, 1, 2, [834, 100, 1320]),
+        (3, 1, 3, [1450, 27, 360]),
+        (3, 2, 0, [-2146, -199, 315]),
+        (3, 2, 1, [-2230, 33, -33]),
+        (3, 2, 2, [-1014, -145, -1905]),
+        (3, 2, 3, [-2246, 1, 15]),
+        (3, 3, 0, [221, -122, 1340]),
+        (3, 3, 1, [137, 110, 992]),
+        (3, 3, 2, [296, 58, 767]),
+        (3, 3, 3, [121, 78, 1040]),
+        ];
+        for (mx, vx, tx, macs) in expected {
+            let mut gte = mvmva_gte();
+            gte.execute_command(mvmva_command(mx, vx, tx, true, false));
+            let combination = format!("mx={} vx={} tx={}", mx, vx, tx);
+            assert_eq!(mac(&gte), macs, "{}", combination);
+            assert_eq!(ir(&gte), macs.map(|mac| mac as i16), "{}", combination);
+            assert_eq!(gte.control_register(31), 0, "{}", combination);
+        }
+    }
+
+    #[test]
+    fn test_mvmva_garbage_matrix() {
+        // mx=3 multiplies by [-R<<4, R<<4, IR0], [RT13, RT13, RT13], [RT22, RT22, RT22]
+        let mut gte = mvmva_gte();
+        gte.execute_command(mvmva_command(3, 0, 3, false, true));
+        assert_eq!(
+            mac(&gte),
+            [
+                -0x9A0 * 0x100 + 0x9A0 * -0x200 + 0x800 * 0x300,
+                0x123 * (0x100 - 0x200 + 0x300),
+                0xF00 * (0x100 - 0x200 + 0x300),
+            ]
+        );
+        // lm clamps the negative row to 0, the others saturate
+        assert_eq!(ir(&gte), [0, 0x7FFF, 0x7FFF]);
+        assert_eq!(gte.control_register(31), 0x81C0_0000);
+    }
+
+    #[test]
+    fn test_mvmva_buggy_far_color() {
+        // With tx=2 the far color and first column only affect the flags. The result is just the
+        // second and third columns
+        let mut gte = mvmva_gte();
+        gte.RFC = 0x10000;
+        gte.execute_command(mvmva_command(0, 0, 2, true, false));
+        assert_eq!(mac(&gte), [310, -528, 451]);
+        assert_eq!(ir(&gte), [310, -528, 451]);
+        // IR1 saturated while the discarded far color part was being calculated
+        assert_eq!(gte.control_register(31), 0x8100_0000);
+
+        // The discarded part ignores lm, but the final result doesn't
+        let mut gte = mvmva_gte();
+        gte.RFC = 0x10000;
+        gte.execute_command(mvmva_command(0, 0, 2, true, true));
+        assert_eq!(ir(&gte), [310, 0, 451]);
+        assert_eq!(gte.control_register(31), 0x8180_0000);
+    }
+}
@@ -161,6 +161,8 @@ pub(super) enum Instruction {
     MTC2 { rt: u8, rd: u8 },
     CFC2 { rt: u8, rd: u8 },
     IMM25 { command: u32 },
+    BC2F { offset: u16 },
+    BC2T { offset: u16 },
     LB { rt: u8, offset: u16, base: u8 },
     LH { rt: u8, offset: u16, base: u8 },
     LW { rt: u8, offset: u16, base: u8 },
@@ -235,6 +237,8 @@ impl Instruction {
             Instruction::MTC2 { rt, rd } => "mtc2",
             Instruction::CFC2 { rt, rd } => "cfc2",
             Instruction::IMM25 { command } => "imm25",
+            Instruction::BC2F { offset } => "bc2f",
+            Instruction::BC2T { offset } => "bc2t",
             Instruction::LB { rt, offset, base } => "lb",
             Instruction::LH { rt, offset, base } => "lh",
             Instruction::LW { rt, offset, base } => "lw",
@@ -408,6 +412,8 @@ impl Instruction {
 
             Instruction::IMM25 { command } => format!("{:08x}", command),
 
+            Instruction::BC2F { offset } | Instruction::BC2T { offset } => format!("{:#x}", offset),
+
             Instruction::LB { rt, offset, base }
             | Instruction::LH { rt, offset, base }
             | Instruction::LW { rt, offset, base }
@@ -475,7 +481,7 @@ impl Instruction {
             Instruction::JR { rs } => interpreter::op_jr(cpu, *rs),
             Instruction::JALR { rd, rs } => interpreter::op_jalr(cpu, *rs, *rd),
             Instruction::SYSCALL { .. } => interpreter::op_syscall(cpu),
-            Instruction::BREAK { .. } => interpreter::op_break(cpu),
+            Instruction::BREAK { code } => interpreter::op_break(cpu, *code),
             Instruction::MFHI { rd } => interpreter::op_mfhi(cpu, *rd),
             Instruction::MTHI { rs } => interpreter::op_mthi(cpu, *rs),
             Instruction::MFLO { rd } => interpreter::op_mflo(cpu, *rd),
@@ -522,6 +528,7 @@ impl Instruction {
             Instruction::MTC2 { rt, rd } => interpreter::op_mtc2(cpu, *rt, *rd),
             Instruction::CFC2 { rt, rd } => interpreter::op_cfc2(cpu, *rt, *rd),
             Instruction::IMM25 { command } => interpreter::op_imm25(cpu, *command),
+            Instruction::BC2F { .. } | Instruction::BC2T { .. } => interpreter::op_bc2(cpu),
             Instruction::LB { rt, offset, base } => {
                 interpreter::op_lb(cpu, main_bus, *base, *rt, *offset as u32)
             }
@@ -603,10 +610,10 @@ pub(super) fn decode_opcode(inst: u32) -> Option<Instruction> {
                     rs: inst.rs(),
                 }),
                 0xC => Some(Instruction::SYSCALL {
-                    code: (inst >> 5) & 0x1FFFFF,
+                    code: (inst >> 6) & 0xFFFFF,
                 }),
                 0xD => Some(Instruction::BREAK {
-                    code: (inst >> 5) & 0x1FFFFF,
+                    code: (inst >> 6) & 0xFFFFF,
                 }),
                 0x10 => Some(Instruction::MFHI { rd: inst.rd() }),
                 0x11 => Some(Instruction::MTHI { rs: inst.rs() }),
@@ -822,6 +829,18 @@ pub(super) fn decode_opcode(inst: u32) -> Option<Instruction> {
                         rt: inst.rt(),
                         rd: inst.rd(),
                     }),
+                    0x8 => {
+                        // Condition branches. rt bit 0 selects between branch on false and branch on true
+                        if inst.get_bit(16) {
+                            Some(Instruction::BC2T {
+                                offset: inst.immediate(),
+                            })
+                        } else {
+                            Some(Instruction::BC2F {
+                                offset: inst.immediate(),
+                            })
+                        }
+                    }
                     _ => None,
                 }
             }
@@ -1011,7 +1030,7 @@ impl TryFrom<usize> for RegisterNames {
 
 #[cfg(test)]
 mod instruction_tests {
-    use super::InstructionArgs;
+    use super::{decode_opcode, Instruction, InstructionArgs};
     #[test]
     fn test_opcode() {
         let test: u32 = 0b11111100000000000000000000000000;
@@ -1059,4 +1078,28 @@ mod instruction_tests {
         let test: u32 = 0xFFFFFFF;
         assert_eq!(test.address(), 0x3FFFFFF);
     }
+
+    #[test]
+    fn test_decode_cop2_rs_space() {
+        for rs in 0..32u32 {
+            for rt in [0u32, 1] {
+                let inst = (0x12 << 26) | (rs << 21) | (rt << 16) | (3 << 11) | 0x1234;
+                let decoded = decode_opcode(inst);
+                match rs {
+                    0x0 => assert!(matches!(decoded, Some(Instruction::MFC2 { rd: 3, .. }))),
+                    0x2 => assert!(matches!(decoded, Some(Instruction::CFC2 { rd: 3, .. }))),
+                    0x4 => assert!(matches!(decoded, Some(Instruction::MTC2 { rd: 3, .. }))),
+                    0x6 => assert!(matches!(decoded, Some(Instruction::CTC2 { rd: 3, .. }))),
+                    0x8 if rt == 0 => {
+                        assert!(matches!(decoded, Some(Instruction::BC2F { offset: 0x1A34 })))
+                    }
+                    0x8 => assert!(matches!(decoded, Some(Instruction::BC2T { offset: 0x1A34 }))),
+                    0x10..=0x1F => assert!(
+                        matches!(decoded, Some(Instruction::IMM25 { command }) if command == inst & 0x1FFFFFF)
+                    ),
+                    _ => assert!(decoded.is_none(), "rs {:#X} should not decode", rs),
+                }
+            }
+        }
+    }
 }
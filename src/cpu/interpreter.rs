@@ -4,8 +4,9 @@ use log::trace;
 use crate::{cpu::Exception, MainBus, Scheduler, timer::TimerState};
 
 use super::{
+    alu,
     instruction::{InstructionArgs, NumberHelpers},
-    R3000,
+    R3000, SEMIHOST_BREAK_CODE,
 };
 
 pub(super) fn op_sw(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Scheduler, rs: u8, rt: u8, offset: u32) {
@@ -18,8 +19,8 @@ pub(super) fn op_sw(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Sch
 
     if addr % 4 != 0 {
         //unaligned address
-        trace!("AdES fired by op_sw");
-        cpu.fire_exception(Exception::AdES);
+        trace!(target: "psx::cpu", "AdES fired by op_sw");
+        cpu.fire_address_error(Exception::AdES, addr);
     } else {
         cpu.write_bus_word(addr, val, main_bus, scheduler);
     };
@@ -74,15 +75,8 @@ pub(super) fn op_lwr(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Sc
 
     let word = cpu.read_bus_word(addr & !3, main_bus, scheduler);
 
-    // LWR can ignore the load delay, so check if theres an existing load delay and fetch the rt value
-    // from there if it exists
-    let mut reg_val = cpu.read_reg(rt);
-
-    if let Some(delay) = &cpu.load_delay {
-        if delay.register == rt {
-            reg_val = delay.value;
-        }
-    }
+    // LWR merges with an in flight load to the same register instead of the committed value
+    let reg_val = cpu.pending_load_value(rt);
 
     cpu.delayed_load(
         rt,
@@ -103,15 +97,8 @@ pub(super) fn op_lwl(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Sc
 
     let word = cpu.read_bus_word(addr & !3, main_bus, scheduler);
 
-    // LWL can ignore the load delay, so check if theres an existing load delay and fetch the rt value
-    // from there if it exists
-    let mut reg_val = cpu.read_reg(rt);
-
-    if let Some(delay) = &cpu.load_delay {
-        if delay.register == rt {
-            reg_val = delay.value;
-        }
-    }
+    // LWL merges with an in flight load to the same register instead of the committed value
+    let reg_val = cpu.pending_load_value(rt);
 
     cpu.delayed_load(
         rt,
@@ -133,8 +120,8 @@ pub(super) fn op_sh(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Sch
     cpu.flush_load_delay();
     if addr % 2 != 0 {
         //unaligned address
-        trace!("AdES fired by op_sh pc {:#X}  addr {:#X}   s_reg  {}   s_reg_val  {:#X}   offset   {:#X}", cpu.current_pc, addr, rs, offset , base);
-        cpu.fire_exception(Exception::AdES);
+        trace!(target: "psx::cpu", "AdES fired by op_sh pc {:#X}  addr {:#X}   s_reg  {}   s_reg_val  {:#X}   offset   {:#X}", cpu.current_pc, addr, rs, offset , base);
+        cpu.fire_address_error(Exception::AdES, addr);
     } else {
         cpu.write_bus_half_word(addr, val, main_bus, scheduler);
     };
@@ -152,9 +139,9 @@ pub(super) fn op_sb(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Sch
 pub(super) fn op_lhu(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Scheduler, rs: u8, rt: u8, offset: u32) {
     let addr = (offset.immediate_sign_extended()).wrapping_add(cpu.read_reg(rs));
     if addr % 2 != 0 {
-        trace!("AdEl fired by op_lhu");
+        trace!(target: "psx::cpu", "AdEl fired by op_lhu");
         cpu.flush_load_delay();
-        cpu.fire_exception(Exception::AdEL);
+        cpu.fire_address_error(Exception::AdEL, addr);
     } else {
         let val = cpu.read_bus_half_word(addr, main_bus, scheduler).zero_extended();
         cpu.delayed_load(rt, val);
@@ -173,13 +160,14 @@ pub(super) fn op_lw(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Sch
     let addr = base.wrapping_add(offset);
     if addr % 4 != 0 {
         trace!(
+            target: "psx::cpu",
             "AdEl fired by op_lw   addr {:#X}   s_reg  {}   s_reg_val  {:#X}   offset   {:#X}",
             addr,
             rs,
             offset,
             base
         );
-        cpu.fire_exception(Exception::AdEL);
+        cpu.fire_address_error(Exception::AdEL, addr);
     } else {
         let val = cpu.read_bus_word(addr as u32, main_bus, scheduler);
 
@@ -192,8 +180,8 @@ pub(super) fn op_lw(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Sch
 pub(super) fn op_lh(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Scheduler, rs: u8, rt: u8, offset: u32) {
     let addr = (offset.immediate_sign_extended()).wrapping_add(cpu.read_reg(rs));
     if addr % 2 != 0 {
-        trace!("AdEl fired by op_lh");
-        cpu.fire_exception(Exception::AdEL);
+        trace!(target: "psx::cpu", "AdEl fired by op_lh");
+        cpu.fire_address_error(Exception::AdEL, addr);
     } else {
         let val = cpu.read_bus_half_word(addr, main_bus, scheduler).sign_extended();
         cpu.delayed_load(rt, val as u32);
@@ -210,7 +198,7 @@ pub(super) fn op_rfe(cpu: &mut R3000) {
     cpu.flush_load_delay();
     let mode = cpu.cop0.read_reg(12) & 0x3f;
     let status = cpu.cop0.read_reg(12);
-    cpu.cop0.write_reg(12, (status & !0xf) | (mode >> 2));
+    cpu.cop0.set_reg(12, (status & !0xf) | (mode >> 2));
 }
 
 pub(super) fn op_mfc0(cpu: &mut R3000, rd: u8, rt: u8) {
@@ -233,252 +221,202 @@ pub(super) fn op_lui(cpu: &mut R3000, rt: u8, offset: u32) {
 pub(super) fn op_xori(cpu: &mut R3000, rs: u8, rt: u8, offset: u32) {
     let val = cpu.read_reg(rs);
     cpu.flush_load_delay();
-    cpu.write_reg(rt, val ^ offset.immediate().zero_extended());
+    cpu.write_reg(rt, alu::xor(val, offset.immediate().zero_extended()));
 }
 
 pub(super) fn op_ori(cpu: &mut R3000, rs: u8, rt: u8, offset: u32) {
     let val = cpu.read_reg(rs);
     cpu.flush_load_delay();
-    cpu.write_reg(rt, val | offset.immediate().zero_extended());
+    cpu.write_reg(rt, alu::or(val, offset.immediate().zero_extended()));
 }
 
 pub(super) fn op_andi(cpu: &mut R3000, rs: u8, rt: u8, offset: u32) {
     let val = cpu.read_reg(rs);
     cpu.flush_load_delay();
-    cpu.write_reg(rt, offset.immediate().zero_extended() & val);
+    cpu.write_reg(rt, alu::and(val, offset.immediate().zero_extended()));
 }
 
 pub(super) fn op_sltiu(cpu: &mut R3000, rs: u8, rt: u8, offset: u32) {
     let val = cpu.read_reg(rs);
     cpu.flush_load_delay();
-    cpu.write_reg(rt, (val < offset.immediate_sign_extended() as u32) as u32);
+    cpu.write_reg(rt, alu::sltu(val, offset.immediate_sign_extended()));
 }
 
 pub(super) fn op_slti(cpu: &mut R3000, rs: u8, rt: u8, offset: u32) {
     let val = cpu.read_reg(rs);
     cpu.flush_load_delay();
-    cpu.write_reg(
-        rt,
-        ((val as i32) < offset.immediate_sign_extended() as i32) as u32,
-    );
+    cpu.write_reg(rt, alu::slt(val, offset.immediate_sign_extended()));
 }
 
 pub(super) fn op_addiu(cpu: &mut R3000, rs: u8, rt: u8, offset: u32) {
     let val = cpu.read_reg(rs);
     cpu.flush_load_delay();
-    cpu.write_reg(
-        rt,
-        val.wrapping_add(offset.immediate_sign_extended()) as u32,
-    );
+    cpu.write_reg(rt, alu::addu(val, offset.immediate_sign_extended()));
 }
 
 pub(super) fn op_addi(cpu: &mut R3000, rs: u8, rt: u8, offset: u32) {
     let val = cpu.read_reg(rs);
     cpu.flush_load_delay();
-    cpu.write_reg(
-        rt,
-        match (val as i32).checked_add(offset.immediate_sign_extended() as i32) {
-            Some(val) => val as u32,
-            None => {
-                cpu.fire_exception(Exception::Ovf);
-                return;
-            }
-        },
-    );
+    let (result, overflow) = alu::add(val, offset.immediate_sign_extended());
+    if overflow {
+        cpu.fire_exception(Exception::Ovf);
+        return;
+    }
+    cpu.write_reg(rt, result);
 }
 
 pub(super) fn op_bgtz(cpu: &mut R3000, rs: u8, offset: u32) {
-    if (cpu.read_reg(rs) as i32) > 0 {
-        cpu.delay_slot = cpu.pc;
-        cpu.pc = ((offset.immediate_sign_extended() as u32) << 2).wrapping_add(cpu.delay_slot);
-    };
+    let taken = (cpu.read_reg(rs) as i32) > 0;
+    relative_branch(cpu, offset, taken);
     cpu.flush_load_delay();
 }
 
 pub(super) fn op_blez(cpu: &mut R3000, rs: u8, offset: u32) {
-    if (cpu.read_reg(rs) as i32) <= 0 {
-        cpu.delay_slot = cpu.pc;
-        cpu.pc = ((offset.immediate_sign_extended() as u32) << 2).wrapping_add(cpu.delay_slot);
-    };
+    let taken = (cpu.read_reg(rs) as i32) <= 0;
+    relative_branch(cpu, offset, taken);
     cpu.flush_load_delay();
 }
 
 pub(super) fn op_bne(cpu: &mut R3000, rs: u8, rt: u8, offset: u32) {
-    if cpu.read_reg(rs) != cpu.read_reg(rt) {
-        cpu.delay_slot = cpu.pc;
-        cpu.pc = ((offset.immediate_sign_extended() as u32) << 2).wrapping_add(cpu.delay_slot);
-    };
+    let taken = cpu.read_reg(rs) != cpu.read_reg(rt);
+    relative_branch(cpu, offset, taken);
     cpu.flush_load_delay();
 }
 
 pub(super) fn op_beq(cpu: &mut R3000, rs: u8, rt: u8, offset: u32) {
-    if cpu.read_reg(rs) == cpu.read_reg(rt) {
-        cpu.delay_slot = cpu.pc;
-        cpu.pc = ((offset.immediate_sign_extended() as u32) << 2).wrapping_add(cpu.delay_slot);
-    };
+    let taken = cpu.read_reg(rs) == cpu.read_reg(rt);
+    relative_branch(cpu, offset, taken);
     cpu.flush_load_delay();
 }
 
 pub(super) fn op_jal(cpu: &mut R3000, target: u32) {
-    cpu.delay_slot = cpu.pc;
+    let delay_slot = cpu.delay_slot_address();
     cpu.flush_load_delay();
-    cpu.write_reg(31, cpu.delay_slot + 4);
-    cpu.pc = (target << 2) | (cpu.delay_slot & 0xF0000000);
+    cpu.write_reg(31, delay_slot.wrapping_add(4));
+    cpu.jump((target << 2) | (delay_slot & 0xF0000000));
 }
 
 pub(super) fn op_j(cpu: &mut R3000, target: u32) {
-    cpu.delay_slot = cpu.pc;
-    cpu.pc = (target << 2) | ((cpu.delay_slot) & 0xF0000000);
+    cpu.jump((target << 2) | (cpu.delay_slot_address() & 0xF0000000));
     cpu.flush_load_delay();
 }
 
+/// Branches are relative to their delay slot. The next instruction is a delay slot even if the branch isn't taken
+fn relative_branch(cpu: &mut R3000, offset: u32, taken: bool) {
+    if taken {
+        let target = ((offset.immediate_sign_extended() as u32) << 2).wrapping_add(cpu.delay_slot_address());
+        cpu.jump(target);
+    } else {
+        cpu.branch = true;
+    }
+}
+
 pub(super) fn op_slt(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
-    let t_val = cpu.read_reg(rt) as i32;
-    let s_val = cpu.read_reg(rs) as i32;
+    let rs = cpu.read_reg(rs);
+    let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, (s_val < t_val) as u32);
+    cpu.write_reg(rd, alu::slt(rs, rt));
 }
 
 pub(super) fn op_multu(cpu: &mut R3000, rs: u8, rt: u8) {
-    let m1 = cpu.read_reg(rs);
-    let m2 = cpu.read_reg(rt);
+    let rs = cpu.read_reg(rs);
+    let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-
-    let result = (m1 as u64) * (m2 as u64);
-    cpu.lo = result as u32;
-    cpu.hi = (result >> 32) as u32;
+    (cpu.hi, cpu.lo) = alu::multu(rs, rt);
 }
 
 pub(super) fn op_mult(cpu: &mut R3000, rs: u8, rt: u8) {
-    let m1 = cpu.read_reg(rs);
-    let m2 = cpu.read_reg(rt);
+    let rs = cpu.read_reg(rs);
+    let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    let result = ((m1 as i32) as i64 * (m2 as i32) as i64) as u64;
-    cpu.lo = result as u32;
-    cpu.hi = (result >> 32) as u32;
+    (cpu.hi, cpu.lo) = alu::mult(rs, rt);
 }
 
 pub(super) fn op_addu(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, rt.wrapping_add(rs) as u32);
+    cpu.write_reg(rd, alu::addu(rs, rt));
 }
 
 pub(super) fn op_nor(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, !(rt | rs));
+    cpu.write_reg(rd, alu::nor(rs, rt));
 }
 
 pub(super) fn op_xor(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, rs ^ rt);
+    cpu.write_reg(rd, alu::xor(rs, rt));
 }
 
 pub(super) fn op_or(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, rs | rt);
-    //println!("or ${}({:08x}) | ${}({:08x}) = ${}({:08x})", rs, cpu.read_reg(rs), rt, cpu.read_reg(rt), rd, cpu.read_reg(rd))
+    cpu.write_reg(rd, alu::or(rs, rt));
 }
 
 pub(super) fn op_and(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, rs & rt);
+    cpu.write_reg(rd, alu::and(rs, rt));
 }
 
 pub(super) fn op_subu(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, rs.wrapping_sub(rt));
+    cpu.write_reg(rd, alu::subu(rs, rt));
 }
 
 pub(super) fn op_sltu(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, (rs < rt) as u32);
+    cpu.write_reg(rd, alu::sltu(rs, rt));
 }
 
 pub(super) fn op_sub(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(
-        rd,
-        match (rs as i32).checked_sub(rt as i32) {
-            Some(val) => val as u32,
-            None => {
-                cpu.fire_exception(Exception::Ovf);
-                return;
-            }
-        },
-    );
+    let (result, overflow) = alu::sub(rs, rt);
+    if overflow {
+        cpu.fire_exception(Exception::Ovf);
+        return;
+    }
+    cpu.write_reg(rd, result);
 }
 
 pub(super) fn op_add(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    let val = match (rs as i32).checked_add(rt as i32) {
-        Some(val) => val as u32,
-        None => {
-            cpu.fire_exception(Exception::Ovf);
-            return;
-        }
-    };
-    cpu.write_reg(rd, val)
+    let (result, overflow) = alu::add(rs, rt);
+    if overflow {
+        cpu.fire_exception(Exception::Ovf);
+        return;
+    }
+    cpu.write_reg(rd, result);
 }
 
 pub(super) fn op_divu(cpu: &mut R3000, rs: u8, rt: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    match rs.checked_div(rt) {
-        Some(lo) => {
-            cpu.lo = lo;
-            cpu.hi = rs % rt;
-        }
-        None => {
-            //println!("CPU: Tried to divide by zero at pc: {:#X}!", cpu.old_pc);
-            cpu.hi = rs as u32;
-            cpu.lo = 0xFFFFFFFF;
-            return;
-        }
-    };
+    (cpu.hi, cpu.lo) = alu::divu(rs, rt);
 }
 
 pub(super) fn op_div(cpu: &mut R3000, rs: u8, rt: u8) {
-    let rs = cpu.read_reg(rs) as i32;
-    let rt = cpu.read_reg(rt) as i32;
-    cpu.flush_load_delay();
-    match rs.checked_div(rt) {
-        Some(lo) => {
-            cpu.lo = lo as u32;
-            cpu.hi = (rs % rt) as u32;
-        }
-        None => {
-            if rt == -1 {
-                cpu.hi = 0;
-                cpu.lo = 0x80000000 as u32;
-            } else if rs < 0 {
-                cpu.hi = rs as u32;
-                cpu.lo = 1;
-            } else {
-                cpu.hi = rs as u32;
-                cpu.lo = 0xffffffff as u32;
-            }
-            return;
-        }
-    };
+    let rs = cpu.read_reg(rs);
+    let rt = cpu.read_reg(rt);
+    cpu.flush_load_delay();
+    (cpu.hi, cpu.lo) = alu::div(rs, rt);
 }
 
 pub(super) fn op_mtlo(cpu: &mut R3000, rs: u8) {
@@ -509,69 +447,63 @@ pub(super) fn op_syscall(cpu: &mut R3000) {
 pub(super) fn op_jalr(cpu: &mut R3000, rs: u8, rd: u8) {
     let target = cpu.read_reg(rs);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, cpu.pc + 4);
-    if target % 4 != 0 {
-        trace!("AdEl fired by op_jalr");
-        cpu.fire_exception(Exception::AdEL);
-    } else {
-        cpu.delay_slot = cpu.pc;
-        cpu.pc = target;
-    }
+    cpu.write_reg(rd, cpu.delay_slot_address().wrapping_add(4));
+    // Misaligned targets fault when they are fetched, not here
+    cpu.jump(target);
 }
 
 pub(super) fn op_jr(cpu: &mut R3000, rs: u8) {
     let target = cpu.read_reg(rs);
     cpu.flush_load_delay();
-    if target % 4 != 0 {
-        trace!("AdEl fired by op_jr");
-        cpu.fire_exception(Exception::AdEL);
-    } else {
-        cpu.delay_slot = cpu.pc;
-        cpu.pc = target;
-    }
+    // Misaligned targets fault when they are fetched, not here
+    cpu.jump(target);
 }
 
 pub(super) fn op_srav(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, ((rt as i32) >> (rs & 0x1F)) as u32);
+    cpu.write_reg(rd, alu::sra(rt, rs));
 }
 
 pub(super) fn op_srlv(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, ((rt) >> (rs & 0x1F)) as u32);
+    cpu.write_reg(rd, alu::srl(rt, rs));
 }
 
 pub(super) fn op_sllv(cpu: &mut R3000, rs: u8, rt: u8, rd: u8) {
     let rs = cpu.read_reg(rs);
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, ((rt) << (rs & 0x1F)) as u32);
+    cpu.write_reg(rd, alu::sll(rt, rs));
 }
 
 pub(super) fn op_sra(cpu: &mut R3000, rd: u8, rt: u8, sa: u8) {
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, ((rt as i32) >> sa) as u32);
+    cpu.write_reg(rd, alu::sra(rt, sa as u32));
 }
 
 pub(super) fn op_srl(cpu: &mut R3000, rd: u8, rt: u8, sa: u8) {
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, rt >> sa);
+    cpu.write_reg(rd, alu::srl(rt, sa as u32));
 }
 
 pub(super) fn op_sll(cpu: &mut R3000, rd: u8, rt: u8, sa: u8) {
     let rt = cpu.read_reg(rt);
     cpu.flush_load_delay();
-    cpu.write_reg(rd, rt << sa);
+    cpu.write_reg(rd, alu::sll(rt, sa as u32));
 }
 
-pub(super) fn op_break(cpu: &mut R3000) {
+pub(super) fn op_break(cpu: &mut R3000, code: u32) {
     cpu.flush_load_delay();
+    if cpu.semihosting && code == SEMIHOST_BREAK_CODE {
+        cpu.semihost_pending = true;
+        return;
+    }
     cpu.fire_exception(Exception::Bp);
 }
 
@@ -600,6 +532,13 @@ pub(super) fn op_imm25(cpu: &mut R3000, command: u32) {
     cpu.gte.execute_command(command);
 }
 
+/// BC2F/BC2T. The GTE never drives the cop2 condition line, so these are never taken
+pub(super) fn op_bc2(cpu: &mut R3000) {
+    // Not taken, but the next instruction is still a delay slot
+    cpu.branch = true;
+    cpu.flush_load_delay();
+}
+
 pub(super) fn op_lwc2(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Scheduler, rs: u8, rt: u8, offset: u32) {
     let addr = offset
         .immediate_sign_extended()
@@ -613,6 +552,7 @@ pub(super) fn op_swc2(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut S
     let addr = offset
         .immediate_sign_extended()
         .wrapping_add(cpu.read_reg(rs));
+    // GTE commands finish as soon as they are issued, so there is no busy window to wait out
     let val = if rt > 31 {
         cpu.gte.control_register(rt as usize - 32)
     } else {
@@ -637,11 +577,8 @@ pub(super) fn op_branch(cpu: &mut R3000, instruction: u32) {
     cpu.flush_load_delay();
 
     if is_link {
-        cpu.write_reg(31, cpu.pc + 4);
+        cpu.write_reg(31, cpu.delay_slot_address().wrapping_add(4));
     }
 
-    if test != 0 {
-        cpu.delay_slot = cpu.pc;
-        cpu.pc = ((instruction.immediate_sign_extended() as u32) << 2).wrapping_add(cpu.delay_slot);
-    }
+    relative_branch(cpu, instruction, test != 0);
 }
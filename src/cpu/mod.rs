@@ -5,20 +5,34 @@ use bit_field::BitField;
 
 use cop0::Cop0;
 use instruction::decode_opcode;
-use log::warn;
+use log::{debug, error, trace, warn};
 
 use crate::bus::MainBus;
 use crate::cpu::instruction::RegisterNames;
+use crate::mmio::{extract_byte, extract_half, insert_byte, insert_half, word_address, IO_IMASK, IO_ISTAT};
 use crate::Scheduler;
 
 use self::gte::GTE;
 
+mod alu;
 mod cop0;
 mod gte;
 mod instruction;
 mod interpreter;
 
+/// BREAK code that asks the host to do file I/O, when semihosting is enabled. The whole
+/// instruction is 0x0003C40D. See `PSXEmu::semihost_call`
+pub const SEMIHOST_BREAK_CODE: u32 = 0xF10;
+
+/// A host file I/O request made with a semihosting BREAK. `op` comes from a0 and `args` from a1-a3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SemihostCall {
+    pub op: u32,
+    pub args: [u32; 3],
+}
+
 #[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
 pub enum InterruptSource {
     VBLANK,
     GPU,
@@ -33,8 +47,10 @@ pub enum InterruptSource {
     Lightpen,
 }
 
+// Named after the hardware's exception codes
+#[allow(dead_code, clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, Copy)]
-pub enum Exception {
+pub(crate) enum Exception {
     IBE = 6,  //Bus error
     DBE = 7,  //Bus error Data
     AdEL = 4, //Address Error Load
@@ -51,31 +67,46 @@ pub enum Exception {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 struct LoadDelay {
     register: u8,
     value: u32,
 }
 
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct R3000 {
     pub gen_registers: [u32; 32],
-    cycle_count: u32,
+    /// Address of the next instruction to execute
     pub pc: u32,
+    /// Address of the instruction after that. Branches write here, which is what gives them a delay slot
+    next_pc: u32,
     current_pc: u32,
     pub hi: u32,
     pub lo: u32,
-    delay_slot: u32,
     pub cop0: Cop0,
     load_delay: Option<LoadDelay>,
     pub i_mask: u32,
     pub i_status: u32,
+    #[cfg_attr(feature = "savestate", serde(skip))]
     pub log: bool,
     pub load_exe: bool,
-    exec_delay: bool,
-    last_was_branch: bool,
+    /// Set by every branch and jump instruction, taken or not
+    branch: bool,
+    /// True while executing the instruction after a branch
+    in_delay_slot: bool,
     gte: GTE,
     pub last_touched_addr: u32,
     pub entrypoint: u32,
-
+    /// Turns BREAK SEMIHOST_BREAK_CODE into a host call instead of an exception
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    pub semihosting: bool,
+    /// Set by a semihosting BREAK until the host answers it
+    semihost_pending: bool,
+    /// Characters the program has written with the kernel's putchar and write calls
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    tty_output: String,
+
+    #[cfg_attr(feature = "savestate", serde(skip))]
     pub inst_map: HashMap<String, u32>
 }
 
@@ -83,78 +114,94 @@ impl R3000 {
     pub fn new() -> R3000 {
         R3000 {
             gen_registers: [0; 32],
-            cycle_count: 0,
             pc: 0,
+            next_pc: 4,
             current_pc: 0,
             hi: 0,
             lo: 0,
-            delay_slot: 0,
             cop0: Cop0::new(),
             load_delay: None,
             i_mask: 0,
             i_status: 0,
             log: false,
             load_exe: false,
-            exec_delay: false,
-            last_was_branch: false,
+            branch: false,
+            in_delay_slot: false,
             gte: GTE::new(),
             last_touched_addr: 0,
             entrypoint: 0,
+            semihosting: false,
+            semihost_pending: false,
+            tty_output: String::new(),
             inst_map: HashMap::new()
         }
     }
     /// Resets cpu registers to zero and sets program counter to reset vector (0xBFC00000)
+    /// Back to the power on state. The host's settings and the executable to boot into are kept
     pub fn reset(&mut self) {
-        //Clear registers
-        for reg in self.gen_registers.iter_mut() {
-            *reg = 0;
-        }
-        self.hi = 0;
-        self.lo = 0;
-        self.pc = 0xBFC00000; // Points to the bios entry point
+        let old = std::mem::replace(self, R3000::new());
+        self.log = old.log;
+        self.load_exe = old.load_exe;
+        self.entrypoint = old.entrypoint;
+        self.semihosting = old.semihosting;
+
+        self.set_pc(0xBFC00000); // Points to the bios entry point
         self.cop0
-            .write_reg(12, self.cop0.read_reg(12).set_bit(23, true).clone());
-        self.load_delay = None;
+            .set_reg(12, self.cop0.read_reg(12).set_bit(23, true).clone());
     }
 
-    #[allow(dead_code)]
-    fn print_string(&mut self, addr: u32, main_bus: &mut MainBus) {
-        let val = main_bus.read_byte(addr);
-        if val == 0 {
-            //Null, end of string
-            return;
-        }
-        print!("{}", std::str::from_utf8(&[val]).unwrap());
-        self.print_string(addr + 1, main_bus);
+    /// Moves the debugging switches and the TTY output the host hasn't taken yet over from `old`,
+    /// which this cpu replaces
+    #[cfg(feature = "savestate")]
+    pub(crate) fn keep_host_state(&mut self, old: R3000) {
+        self.log = old.log;
+        self.semihosting = old.semihosting;
+        self.tty_output = old.tty_output;
+        self.inst_map = old.inst_map;
+    }
+
+    /// Moves execution to the given address. Any pending branch is discarded
+    pub fn set_pc(&mut self, addr: u32) {
+        self.pc = addr;
+        self.next_pc = addr.wrapping_add(4);
+        self.branch = false;
+        self.in_delay_slot = false;
     }
 
-    fn print_registers(&self) {
-        for r in 0..=32 {
-            print!(
+    /// Takes everything the program has written to the TTY since the last call
+    pub fn take_tty_output(&mut self) -> String {
+        std::mem::take(&mut self.tty_output)
+    }
+
+    fn register_dump(&self) -> String {
+        let mut dump = String::new();
+        for r in 0..32 {
+            dump += &format!(
                 "{:#4} : {:#10X}, ",
                 RegisterNames::try_from(r as usize).unwrap(),
                 self.read_reg(r)
             );
-            if r % 8 == 0 && r != 0 {
-                println!("");
+            if r % 8 == 7 {
+                dump.push('\n');
             }
         }
-        println!("");
+        dump
     }
 
-    pub fn step_instruction(&mut self, main_bus: &mut MainBus, scheduler: &mut Scheduler) -> bool {
-
-        let mut ran_delay_inst = false;
-
+    pub fn step_instruction(&mut self, main_bus: &mut MainBus, scheduler: &mut Scheduler) {
         //Fast load exe
         if self.load_exe && self.pc == 0xbfc0700c {
-            println!("Jumping to exe...");
-            self.pc = self.entrypoint;
+            debug!(target: "psx::cpu", "Jumping to exe...");
+            self.set_pc(self.entrypoint);
         }
 
+        // The instruction we are about to run is a delay slot if the last one was a branch
+        self.in_delay_slot = self.branch;
+        self.branch = false;
+
         if self.pc == 0xB0 {
             // SYSCALL: Send character to serial port
-            // This catches any characters and prints them to stdout instead
+            // This catches any characters and keeps them for the frontend instead
             match self.read_reg(9) {
                 0x35 => {
                     if self.read_reg(RegisterNames::a0 as u8) == 1 {
@@ -163,16 +210,12 @@ impl R3000 {
                         let base = self.read_reg(RegisterNames::a1 as u8);
                         for i in 0..len {
                             let char = self.read_bus_byte(base + i, main_bus);
-                            print!("{}", unsafe { std::str::from_utf8_unchecked(&[char]) });
+                            self.tty_output.push(char as char);
                         }
                     }
                 }
 
-                0x3D => {
-                    print!("{}", unsafe {
-                        std::str::from_utf8_unchecked(&[self.read_reg(4) as u8])
-                    })
-                }
+                0x3D => self.tty_output.push(self.read_reg(4) as u8 as char),
                 _ => (),
             }
         }
@@ -180,11 +223,12 @@ impl R3000 {
         if self.pc == 0xA0 {
             //println!("SYSCALL A({:#X}) pc: {:#X}", self.read_reg(9), self.current_pc);
             if self.read_reg(9) == 0x40 {
-                println!("Unhandled exception hit!");
-                println!("PC was {:#X}", self.current_pc);
-                println!("Registers were:");
-                self.print_registers();
-                println!("");
+                error!(
+                    target: "psx::cpu",
+                    "Unhandled exception hit! PC was {:#X}. Registers were:\n{}",
+                    self.current_pc,
+                    self.register_dump()
+                );
                 panic!();
             }
         }
@@ -201,47 +245,51 @@ impl R3000 {
         // Handle interrupts
         let mut cause = self.cop0.read_reg(13);
         cause.set_bit(10, self.i_status & self.i_mask != 0);
-        self.cop0.write_reg(13, cause);
-
-        if self.cop0.interrupts_enabled() && cause & 0x700 != 0 {
+        self.cop0.set_reg(13, cause);
+
+        // Only one exception is taken per instruction. In priority order:
+        // 1. AdEL on the instruction fetch
+        // 2. Interrupt
+        // 3. Exceptions raised by the instruction itself (Ovf, Sys, Bp, data AdEL/AdES)
+        // A faulting fetch never gets far enough to be interrupted, and an interrupted
+        // instruction never runs, so it can't raise anything
+        if self.pc % 4 != 0 {
+            // Misaligned fetch. EPC and BadVaddr both point at the bad address
+            warn!(target: "psx::cpu", "Tried to execute out of alignment at {:#X}", self.pc);
+            self.cop0.set_reg(8, self.pc);
+            self.enter_exception(Exception::AdEL, self.pc, self.in_delay_slot);
+        } else if self.cop0.interrupts_enabled() && cause & 0x700 != 0 {
             //println!("Interrupt hit! i_status: {:#X}", self.i_status);
-            self.fire_exception(Exception::Int);
+            // The interrupted instruction hasn't run yet, so EPC points at it (or its branch)
+            self.enter_exception(Exception::Int, self.pc, self.in_delay_slot);
         }
 
-        let instruction = main_bus.read_word(self.pc, scheduler);
+        let instruction = main_bus.fetch_instruction(self.pc, scheduler);
         self.current_pc = self.pc;
-        self.pc += 4;
-
-        self.exec_delay = false;
-        self.last_was_branch = false;
+        self.pc = self.next_pc;
+        self.next_pc = self.pc.wrapping_add(4);
 
         if self.log {
             self.log_instruction(instruction, main_bus);
         }
-        self.cycle_count = self.cycle_count.wrapping_add(1);
         self.run_opcode(instruction, main_bus, scheduler);
 
         // if main_bus.last_touched_addr == 0x121CA8 {
         //     println!("lta pc {:#X} val {:#X}", self.current_pc, main_bus.read_word(0x121CA8));
         //     self.last_touched_addr = 0;
         // }
+    }
 
-        //Execute branch delay operation
-        if self.delay_slot != 0 {
-            ran_delay_inst = true;
-            let delay_instruction = main_bus.read_word(self.delay_slot, scheduler);
-            if self.log {
-                self.log_instruction(delay_instruction, main_bus);
-            }
-            //self.trace_file.write(format!("{:08x}: {:08x}\n", self.delay_slot, delay_instruction).as_bytes());
-            //println!("{:08x}: {:08x}", self.delay_slot, delay_instruction);
-            self.exec_delay = true;
-            self.cycle_count = self.cycle_count.wrapping_add(1);
-            self.run_opcode(delay_instruction, main_bus, scheduler);
-            self.exec_delay = false;
-            self.delay_slot = 0;
-        };
-        ran_delay_inst
+    /// Returns the address of the current instruction's delay slot.
+    /// Relative branch targets and link addresses are based on this, not on pc
+    fn delay_slot_address(&self) -> u32 {
+        self.current_pc.wrapping_add(4)
+    }
+
+    /// Redirects execution to target after the delay slot
+    fn jump(&mut self, target: u32) {
+        self.branch = true;
+        self.next_pc = target;
     }
 
     fn flush_load_delay(&mut self) {
@@ -261,7 +309,8 @@ impl R3000 {
         //     self.read_reg(instruction.rd()),
         // );
 
-        println!(
+        trace!(
+            target: "psx::cpu",
             "{:08x} {:08x}: {:<7}{}",
             self.current_pc,
             instruction,
@@ -271,12 +320,6 @@ impl R3000 {
     }
 
     pub fn run_opcode(&mut self, opcode: u32, main_bus: &mut MainBus, scheduler: &mut Scheduler) {
-        if self.pc % 4 != 0 || self.delay_slot % 4 != 0 {
-            warn!("Tried to execute out of alignment");
-            self.fire_exception(Exception::AdEL);
-            return;
-        }
-
         if let Some(inst) = decode_opcode(opcode) {
             // let inst_count = self.inst_map.entry(inst.mnemonic().into()).or_insert(0);
             // *inst_count += 1;
@@ -286,34 +329,50 @@ impl R3000 {
         }
     }
 
-    pub fn fire_exception(&mut self, exception: Exception) {
+    /// Raises an address error for addr. BadVaddr holds the address that caused it
+    pub(crate) fn fire_address_error(&mut self, exception: Exception, addr: u32) {
+        self.cop0.set_reg(8, addr);
+        self.fire_exception(exception);
+    }
+
+    /// Raises an exception caused by the current instruction
+    pub(crate) fn fire_exception(&mut self, exception: Exception) {
         //println!("CPU EXCEPTION: Type: {:?} PC: {:#X}", exception, self.current_pc);
+        self.enter_exception(exception, self.current_pc, self.in_delay_slot);
+    }
+
+    /// Records the exception in cop0 and jumps to the exception vector.
+    /// epc is the address of the faulting instruction. If that instruction is in a delay slot,
+    /// BD is set and EPC points at the word before it so the branch runs again on return.
+    /// Like the real hardware, this is just epc - 4, which is not the branch when the delay slot
+    /// is the target of a branch that was itself in a delay slot.
+    fn enter_exception(&mut self, exception: Exception, epc: u32, branch_delay: bool) {
         self.flush_load_delay();
 
         self.cop0.set_cause_execode(&exception);
-
-        if self.delay_slot != 0 {
-            self.cop0.write_reg(13, self.cop0.read_reg(13) | (1 << 31));
-            self.cop0.write_reg(14, self.pc - 8);
+        self.cop0.set_branch_delay(branch_delay);
+        if branch_delay {
+            self.cop0.set_reg(14, epc.wrapping_sub(4));
+            // JUMPDEST latches where the branch was going. If epc is the instruction about to be
+            // fetched the target is still in next_pc, otherwise it has already moved into pc
+            let target = if epc == self.pc { self.next_pc } else { self.pc };
+            self.cop0.set_reg(6, target);
+            self.cop0.set_branch_taken(target != epc.wrapping_add(4));
         } else {
-            self.cop0.write_reg(13, self.cop0.read_reg(13) & !(1 << 31));
-            if exception == Exception::Int {
-                self.cop0.write_reg(14, self.pc);
-            } else {
-                self.cop0.write_reg(14, self.pc - 4);
-            }
+            self.cop0.set_reg(14, epc);
+            self.cop0.set_branch_taken(false);
         }
 
         let old_status = self.cop0.read_reg(12);
-        self.cop0.write_reg(
+        self.cop0.set_reg(
             12,
             (old_status & !0x3F) | (((old_status & 0x3f) << 2) & 0x3f),
         );
-        self.pc = if self.cop0.read_reg(12).get_bit(23) {
+        self.set_pc(if self.cop0.read_reg(12).get_bit(23) {
             0xBFC0_0180
         } else {
             0x8000_0080
-        };
+        });
 
         //self.cop0.write_reg(12, self.cop0.read_reg(12) << 4)
     }
@@ -328,11 +387,11 @@ impl R3000 {
         //self.last_touched_addr = addr & 0x1fffffff;
 
         match addr & 0x1fffffff {
-            0x1F801070 => {
+            IO_ISTAT => {
                 //println!("Reading ISTATUS");
                 self.i_status
             }
-            0x1F801074 => self.i_mask,
+            IO_IMASK => self.i_mask,
             _ => main_bus.read_word(addr, scheduler),
         }
     }
@@ -341,15 +400,16 @@ impl R3000 {
         self.last_touched_addr = addr & 0x1fffffff;
 
         if self.cop0.cache_isolated() {
-            //Cache is isolated, so don't write
+            //Cache is isolated, so the write goes to the cache instead of memory
+            main_bus.write_isolated_word(addr, val);
             return;
         }
 
         match addr & 0x1fffffff {
-            0x1F801070 => {
+            IO_ISTAT => {
                 self.i_status &= val & 0x3FF;
             }
-            0x1F801074 => {
+            IO_IMASK => {
                 //println!("Writing I_MASK val {:#X}", val);
                 self.i_mask = val;
             }
@@ -361,20 +421,20 @@ impl R3000 {
         // if addr == 0x1F801C0C {
         //     println!("Read spu thing at pc {:#X}", self.current_pc);
         // }
-        match addr & 0x1fffffff {
-            0x1F801070 => self.i_status as u16,
-            0x1F801074 => self.i_mask as u16,
+        let phys_addr = addr & 0x1fffffff;
+        match word_address(phys_addr) {
+            IO_ISTAT => extract_half(self.i_status, phys_addr),
+            IO_IMASK => extract_half(self.i_mask, phys_addr),
             _ => main_bus.read_half_word(addr, scheduler),
         }
     }
 
     pub fn read_bus_byte(&mut self, addr: u32, main_bus: &mut MainBus) -> u8 {
         //self.last_touched_addr = addr & 0x1fffffff;
-        match addr & 0x1fffffff {
-            0x1F801070 => self.i_status as u8,
-            0x1F801072 => (self.i_status >> 8) as u8,
-            0x1F801074 => self.i_mask as u8,
-            0x1F801076 => (self.i_mask >> 8) as u8,
+        let phys_addr = addr & 0x1fffffff;
+        match word_address(phys_addr) {
+            IO_ISTAT => extract_byte(self.i_status, phys_addr),
+            IO_IMASK => extract_byte(self.i_mask, phys_addr),
             _ => main_bus.read_byte(addr),
         }
     }
@@ -386,9 +446,11 @@ impl R3000 {
             return;
         }
 
-        match addr & 0x1fffffff {
-            0x1F801070 => self.i_status &= (val & 0x3FF) as u32,
-            0x1F801074 => self.i_mask = val as u32,
+        let phys_addr = addr & 0x1fffffff;
+        match word_address(phys_addr) {
+            // The other half acks nothing
+            IO_ISTAT => self.i_status &= insert_half(!0, phys_addr, val) & 0x3FF,
+            IO_IMASK => self.i_mask = insert_half(self.i_mask, phys_addr, val),
             _ => main_bus.write_half_word(addr, val, scheduler),
         };
     }
@@ -399,13 +461,46 @@ impl R3000 {
             //Cache is isolated, so don't write
             return;
         }
-        match addr & 0x1fffffff {
-            0x1F801070 => self.i_status &= (val as u32) & 0x3FF,
-            0x1F801074 => self.i_mask = val as u32,
+        let phys_addr = addr & 0x1fffffff;
+        match word_address(phys_addr) {
+            IO_ISTAT => self.i_status &= insert_byte(!0, phys_addr, val) & 0x3FF,
+            IO_IMASK => self.i_mask = insert_byte(self.i_mask, phys_addr, val),
             _ => main_bus.write_byte(addr, val, scheduler),
         };
     }
 
+    /// Address of the instruction being executed. Between instructions that is the next one to
+    /// run, which is pc even in a delay slot, since the branch target waits in next_pc. A
+    /// semihosting BREAK is still executing until the host answers it
+    pub fn current_instruction_pc(&self) -> u32 {
+        if self.semihost_pending {
+            self.current_pc
+        } else {
+            self.pc
+        }
+    }
+
+    /// The semihosting call waiting on the host, if there is one
+    pub fn semihost_call(&self) -> Option<SemihostCall> {
+        if !self.semihost_pending {
+            return None;
+        }
+        Some(SemihostCall {
+            op: self.read_reg(RegisterNames::a0 as u8),
+            args: [
+                self.read_reg(RegisterNames::a1 as u8),
+                self.read_reg(RegisterNames::a2 as u8),
+                self.read_reg(RegisterNames::a3 as u8),
+            ],
+        })
+    }
+
+    /// Answers the pending semihosting call, putting the result in v0
+    pub fn finish_semihost_call(&mut self, result: u32) {
+        self.write_reg(RegisterNames::v0 as u8, result);
+        self.semihost_pending = false;
+    }
+
     /// Returns the value stored within the given register. Will panic if register_number > 31
     pub fn read_reg(&self, register_number: u8) -> u32 {
         if register_number != 0 {
@@ -423,6 +518,15 @@ impl R3000 {
         }
     }
 
+    /// Returns the value a pending load will write to the register, or the committed value if there is none.
+    /// Only LWL/LWR can see through the load delay like this
+    fn pending_load_value(&self, register_number: u8) -> u32 {
+        match &self.load_delay {
+            Some(delay) if delay.register == register_number => delay.value,
+            _ => self.read_reg(register_number),
+        }
+    }
+
     /// Processes the current load delay and replaces it with a new one
     fn delayed_load(&mut self, register_number: u8, value: u32) {
         if let Some(current_delay) = self.load_delay.take() {
@@ -436,3 +540,340 @@ impl R3000 {
         });
     }
 }
+
+#[cfg(test)]
+mod cpu_tests {
+    use super::*;
+    use crate::bios::{Bios, BIOS_SIZE};
+    use crate::gpu::Gpu;
+    use crate::memory::Memory;
+
+    const A0: u32 = 4;
+    const A1: u32 = 5;
+    const A2: u32 = 6;
+    const T0: u32 = 8;
+    const T1: u32 = 9;
+
+    fn i_type(op: u32, rs: u32, rt: u32, imm: i16) -> u32 {
+        (op << 26) | (rs << 21) | (rt << 16) | (imm as u16 as u32)
+    }
+
+    fn setup(code_addr: u32, code: &[u32]) -> (R3000, MainBus, Scheduler) {
+        let bios = Bios::new(vec![0; BIOS_SIZE]).unwrap();
+        let mut bus = MainBus::new(bios, Memory::new(), Gpu::new());
+        let mut scheduler = Scheduler::new();
+        for (i, inst) in code.iter().enumerate() {
+            bus.write_word(code_addr + (i as u32 * 4), *inst, &mut scheduler);
+        }
+        let mut cpu = R3000::new();
+        cpu.set_pc(code_addr);
+        (cpu, bus, scheduler)
+    }
+
+    fn run_until(cpu: &mut R3000, bus: &mut MainBus, scheduler: &mut Scheduler, end: u32) {
+        for _ in 0..10_000 {
+            if cpu.pc == end {
+                return;
+            }
+            cpu.step_instruction(bus, scheduler);
+        }
+        panic!("Never reached {:#X}. pc is {:#X}", end, cpu.pc);
+    }
+
+    #[test]
+    fn test_unaligned_memcpy() {
+        let code = [
+            i_type(0x22, A0, T0, 3),  // lwl t0, 3(a0)
+            i_type(0x26, A0, T0, 0),  // lwr t0, 0(a0)
+            i_type(0x09, A0, A0, 4),  // addiu a0, a0, 4
+            i_type(0x2B, A1, T0, 0),  // sw t0, 0(a1)
+            i_type(0x09, A2, A2, -1), // addiu a2, a2, -1
+            i_type(0x05, A2, 0, -6),  // bne a2, zero, loop
+            i_type(0x09, A1, A1, 4),  // addiu a1, a1, 4 (delay slot)
+        ];
+        let (mut cpu, mut bus, mut scheduler) = setup(0x1000, &code);
+
+        let source: Vec<u8> = (0..32).map(|i| (i * 7 + 3) as u8).collect();
+        for (i, byte) in source.iter().enumerate() {
+            bus.write_byte(0x2001 + i as u32, *byte, &mut scheduler);
+        }
+
+        cpu.gen_registers[A0 as usize] = 0x2001;
+        cpu.gen_registers[A1 as usize] = 0x3000;
+        cpu.gen_registers[A2 as usize] = 8;
+        cpu.gen_registers[T0 as usize] = 0xDEADBEEF;
+
+        run_until(&mut cpu, &mut bus, &mut scheduler, 0x101C);
+
+        for (i, byte) in source.iter().enumerate() {
+            assert_eq!(bus.read_byte(0x3000 + i as u32), *byte, "Mismatch at byte {}", i);
+        }
+    }
+
+    fn test_misaligned_jump(offset: u32) {
+        let code = [
+            0x01000008, // jr t0
+            0,          // nop
+        ];
+        let (mut cpu, mut bus, mut scheduler) = setup(0x1000, &code);
+        let target = 0x2000 | offset;
+        cpu.gen_registers[T0 as usize] = target;
+
+        // The jump and its delay slot execute without faulting
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        assert_eq!(cpu.pc, target);
+        assert_eq!(cpu.cop0.read_reg(14), 0);
+
+        // The fetch of the target faults
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        assert_eq!(cpu.cop0.read_reg(14), target);
+        assert_eq!(cpu.cop0.read_reg(8), target);
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::AdEL as u32);
+        assert_eq!(cpu.cop0.read_reg(13) >> 31, 0);
+    }
+
+    #[test]
+    fn test_jump_to_pc_plus_1() {
+        test_misaligned_jump(1);
+    }
+
+    #[test]
+    fn test_jump_to_pc_plus_2() {
+        test_misaligned_jump(2);
+    }
+
+    #[test]
+    fn test_misaligned_load_in_delay_slot() {
+        let code = [
+            (0x02 << 26) | (0x2000 >> 2), // j 0x2000
+            i_type(0x23, A0, T0, 0),      // lw t0, 0(a0) (delay slot)
+        ];
+        let (mut cpu, mut bus, mut scheduler) = setup(0x1000, &code);
+        cpu.gen_registers[A0 as usize] = 0x3002;
+
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::AdEL as u32);
+        assert_eq!(cpu.cop0.read_reg(8), 0x3002);
+        assert_eq!(cpu.cop0.read_reg(14), 0x1000);
+        assert_eq!(cpu.cop0.read_reg(6), 0x2000);
+        // BD and BT
+        assert_eq!(cpu.cop0.read_reg(13) >> 30, 0b11);
+    }
+
+    #[test]
+    fn test_lwl_lwr_merge_pending_load() {
+        // lwr immediately followed by lwl on the same register must merge with the in flight value,
+        // not the stale register contents
+        let code = [
+            i_type(0x26, A0, T0, 0), // lwr t0, 0(a0)
+            i_type(0x22, A0, T0, 3), // lwl t0, 3(a0)
+            0,                       // nop
+        ];
+        let (mut cpu, mut bus, mut scheduler) = setup(0x1000, &code);
+        bus.write_word(0x2000, 0x44332211, &mut scheduler);
+        bus.write_word(0x2004, 0x88776655, &mut scheduler);
+        cpu.gen_registers[A0 as usize] = 0x2002;
+        cpu.gen_registers[T0 as usize] = 0xAAAAAAAA;
+
+        run_until(&mut cpu, &mut bus, &mut scheduler, 0x100C);
+        assert_eq!(cpu.read_reg(T0 as u8), 0x66554433);
+    }
+
+    /// j 0x2000 with `j 0x3000` in its delay slot
+    fn branch_in_delay_slot_setup() -> (R3000, MainBus, Scheduler) {
+        let (cpu, mut bus, mut scheduler) = setup(
+            0x1000,
+            &[
+                (0x02 << 26) | (0x2000 >> 2), // j 0x2000
+                (0x02 << 26) | (0x3000 >> 2), // j 0x3000
+            ],
+        );
+        bus.write_word(0x2000, i_type(0x09, 0, T0, 1), &mut scheduler); // addiu t0, zero, 1
+        bus.write_word(0x2004, i_type(0x09, 0, T1, 1), &mut scheduler); // addiu t1, zero, 1
+        (cpu, bus, scheduler)
+    }
+
+    #[test]
+    fn test_branch_in_delay_slot() {
+        let (mut cpu, mut bus, mut scheduler) = branch_in_delay_slot_setup();
+
+        // The second jump runs as the first one's delay slot. The first target then runs as
+        // the second jump's delay slot, and execution continues at the second target
+        for _ in 0..3 {
+            cpu.step_instruction(&mut bus, &mut scheduler);
+        }
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.read_reg(T0 as u8), 1);
+        assert_eq!(cpu.read_reg(T1 as u8), 0);
+    }
+
+    #[test]
+    fn test_interrupt_on_branch_in_delay_slot() {
+        let (mut cpu, mut bus, mut scheduler) = branch_in_delay_slot_setup();
+        cpu.cop0.write_reg(12, 0x401);
+        cpu.i_mask = 1;
+
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        cpu.i_status = 1;
+        cpu.step_instruction(&mut bus, &mut scheduler);
+
+        // The interrupt is taken before the second jump runs. It's in a delay slot, so EPC points at the first jump
+        assert_eq!(cpu.cop0.read_reg(14), 0x1000);
+        assert_eq!(cpu.cop0.read_reg(13) >> 31, 1);
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::Int as u32);
+        assert_eq!(cpu.read_reg(T0 as u8), 0);
+
+        // Returning to EPC replays both jumps
+        cpu.i_status = 0;
+        cpu.set_pc(cpu.cop0.read_reg(14));
+        for _ in 0..3 {
+            cpu.step_instruction(&mut bus, &mut scheduler);
+        }
+        assert_eq!(cpu.pc, 0x3000);
+        assert_eq!(cpu.read_reg(T0 as u8), 1);
+    }
+
+    #[test]
+    fn test_interrupt_in_delay_slot_of_delay_slot_branch() {
+        let (mut cpu, mut bus, mut scheduler) = branch_in_delay_slot_setup();
+        cpu.cop0.write_reg(12, 0x401);
+        cpu.i_mask = 1;
+
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        cpu.i_status = 1;
+        cpu.step_instruction(&mut bus, &mut scheduler);
+
+        // The interrupted instruction at 0x2000 is the second jump's delay slot. The hardware
+        // reports the word before it, not the jump at 0x1004
+        assert_eq!(cpu.cop0.read_reg(14), 0x1FFC);
+        assert_eq!(cpu.cop0.read_reg(13) >> 31, 1);
+        assert_eq!(cpu.read_reg(T0 as u8), 0);
+    }
+
+    #[test]
+    fn test_swc2_right_after_rtpt() {
+        let code = [
+            0x4A08_0030,              // rtpt (sf=1)
+            i_type(0x3A, A0, 12, 0),  // swc2 sxy0, 0(a0)
+            i_type(0x3A, A0, 13, 4),  // swc2 sxy1, 4(a0)
+            i_type(0x3A, A0, 14, 8),  // swc2 sxy2, 8(a0)
+        ];
+        let (mut cpu, mut bus, mut scheduler) = setup(0x1000, &code);
+        cpu.gen_registers[A0 as usize] = 0x2000;
+        // Identity rotation, no translation and H equal to every Z, so each vertex projects onto
+        // its own x and y
+        cpu.gte.set_control_register(0, 0x1000);
+        cpu.gte.set_control_register(2, 0x1000);
+        cpu.gte.set_control_register(4, 0x1000);
+        cpu.gte.set_control_register(26, 0x100);
+        for (i, (x, y)) in [(10, 20), (30, 40), (50, 60)].iter().enumerate() {
+            cpu.gte.set_data_register(i * 2, (y << 16) | x);
+            cpu.gte.set_data_register(i * 2 + 1, 0x100);
+        }
+
+        run_until(&mut cpu, &mut bus, &mut scheduler, 0x1010);
+
+        // The stores see the new screen coordinates, not whatever was in SXY before
+        assert_eq!(bus.read_word(0x2000, &mut scheduler), (20 << 16) | 10);
+        assert_eq!(bus.read_word(0x2004, &mut scheduler), (40 << 16) | 30);
+        assert_eq!(bus.read_word(0x2008, &mut scheduler), (60 << 16) | 50);
+    }
+
+    #[test]
+    fn test_fetch_error_beats_interrupt() {
+        let code = [
+            0x01000008, // jr t0
+            0,          // nop
+        ];
+        let (mut cpu, mut bus, mut scheduler) = setup(0x1000, &code);
+        cpu.gen_registers[T0 as usize] = 0x2002;
+        cpu.cop0.write_reg(12, 0x401);
+        cpu.i_mask = 1;
+
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        cpu.i_status = 1;
+        cpu.step_instruction(&mut bus, &mut scheduler);
+
+        // The misaligned fetch faults first. The interrupt stays pending for when the handler returns
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::AdEL as u32);
+        assert_eq!(cpu.cop0.read_reg(14), 0x2002);
+        assert_eq!(cpu.cop0.read_reg(8), 0x2002);
+        assert!(cpu.cop0.read_reg(13).get_bit(10));
+    }
+
+    #[test]
+    fn test_interrupt_beats_instruction_exceptions() {
+        let code = [
+            i_type(0x09, 0, T0, 1),  // addiu t0, zero, 1
+            0x0000000C,              // syscall
+            i_type(0x23, A0, T1, 0), // lw t1, 0(a0)
+        ];
+        let (mut cpu, mut bus, mut scheduler) = setup(0x1000, &code);
+        cpu.gen_registers[A0 as usize] = 0x3002;
+        cpu.cop0.set_reg(8, 0xCAFE);
+        cpu.cop0.write_reg(12, 0x401);
+        cpu.i_mask = 1;
+
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        cpu.i_status = 1;
+        cpu.step_instruction(&mut bus, &mut scheduler);
+
+        // The syscall never runs, so EPC points at it and returning runs it
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::Int as u32);
+        assert_eq!(cpu.cop0.read_reg(14), 0x1004);
+
+        cpu.i_status = 0;
+        cpu.set_pc(0x1004);
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::Sys as u32);
+        assert_eq!(cpu.cop0.read_reg(14), 0x1004);
+
+        // Same for a misaligned load. BadVaddr is left alone
+        cpu.cop0.write_reg(12, 0x401);
+        cpu.i_status = 1;
+        cpu.set_pc(0x1008);
+        cpu.step_instruction(&mut bus, &mut scheduler);
+        assert_eq!((cpu.cop0.read_reg(13) >> 2) & 0x1F, Exception::Int as u32);
+        assert_eq!(cpu.cop0.read_reg(14), 0x1008);
+        assert_eq!(cpu.cop0.read_reg(8), 0xCAFE);
+    }
+
+    #[test]
+    fn test_interrupt_registers_every_width() {
+        let (mut cpu, mut bus, mut scheduler) = setup(0x1000, &[]);
+        let bus = &mut bus;
+        let scheduler = &mut scheduler;
+
+        cpu.i_mask = 0x0000_0000;
+        cpu.write_bus_half_word(0x9F80_1074, 0x0123, bus, scheduler);
+        cpu.write_bus_byte(0x1F80_1075, 0x45, bus, scheduler);
+        assert_eq!(cpu.i_mask, 0x4523);
+        // Writes to the upper half don't disturb the lower one
+        cpu.write_bus_half_word(0x1F80_1076, 0xFFFF, bus, scheduler);
+        cpu.write_bus_byte(0x1F80_1077, 0x00, bus, scheduler);
+        assert_eq!(cpu.i_mask, 0x00FF_4523);
+        assert_eq!(cpu.read_bus_half_word(0x1F80_1074, bus, scheduler), 0x4523);
+        assert_eq!(cpu.read_bus_half_word(0x1F80_1076, bus, scheduler), 0x00FF);
+        assert_eq!(cpu.read_bus_byte(0x1F80_1075, bus), 0x45);
+        assert_eq!(cpu.read_bus_byte(0x1F80_1076, bus), 0xFF);
+
+        cpu.i_status = 0x3FF;
+        assert_eq!(cpu.read_bus_byte(0x1F80_1071, bus), 0x03);
+        assert_eq!(cpu.read_bus_byte(0x1F80_1072, bus), 0x00);
+        assert_eq!(cpu.read_bus_half_word(0x1F80_1072, bus, scheduler), 0x0000);
+        // Acking one byte leaves the other byte's bits pending
+        cpu.write_bus_byte(0x1F80_1070, 0xFE, bus, scheduler);
+        assert_eq!(cpu.i_status, 0x3FE);
+        cpu.write_bus_byte(0x1F80_1071, 0x01, bus, scheduler);
+        assert_eq!(cpu.i_status, 0x1FE);
+        cpu.write_bus_half_word(0x1F80_1072, 0x0000, bus, scheduler);
+        assert_eq!(cpu.i_status, 0x1FE);
+        cpu.write_bus_half_word(0x1F80_1070, 0xFF00, bus, scheduler);
+        assert_eq!(cpu.i_status, 0x100);
+        assert_eq!(cpu.read_bus_word(0x1F80_1070, bus, scheduler), 0x100);
+    }
+}
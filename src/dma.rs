@@ -1,13 +1,16 @@
 use crate::cpu::{InterruptSource, R3000};
 use bit_field::BitField;
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
+use crate::mmio::*;
 use crate::{MainBus, Scheduler};
+use std::cmp::Reverse;
 
 const NUM_CHANNELS: usize = 7;
 
 const DMA_CHANNEL_NAMES: [&str; 7] = ["MDECin", "MDECout", "GPU", "CDROM", "SPU", "PIO", "OTC"];
 
 #[derive(Clone)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 struct Channel {
     channel_num: usize,
     base_addr: u32,
@@ -34,14 +37,15 @@ impl Channel {
             }
     }
 
+    /// Clears start/busy, which is what games poll to see the transfer is done
     fn complete(&mut self) {
         self.control.set_bit(24, false);
-        //self.control.set_bit(28, false);
+        self.control.set_bit(28, false);
     }
 
     fn print_stats(&self) {
-        info!("");
-        info!("Channel: {}", DMA_CHANNEL_NAMES[self.channel_num]);
+        info!(target: "psx::dma", "");
+        info!(target: "psx::dma", "Channel: {}", DMA_CHANNEL_NAMES[self.channel_num]);
         let sync_mode = match (self.control & 0x600) >> 9 {
             0 => "Immediate (0)",
             1 => "Sync (1)",
@@ -50,12 +54,13 @@ impl Channel {
             _ => "Invalid sync mode",
         };
 
-        info!("SyncMode: {}", sync_mode);
-        info!("Base Address: {:#X}", self.base_addr);
+        info!(target: "psx::dma", "SyncMode: {}", sync_mode);
+        info!(target: "psx::dma", "Base Address: {:#X}", self.base_addr);
 
         match (self.control & 0x600) >> 9 {
-            0 => info!("BC: {} words", self.block & 0xFFFF),
+            0 => info!(target: "psx::dma", "BC: {} words", self.block & 0xFFFF),
             1 => info!(
+                target: "psx::dma",
                 "BS: {} words per block  BA: {} blocks",
                 self.block & 0xFFFF,
                 (self.block >> 16) & 0xFFFF
@@ -64,6 +69,7 @@ impl Channel {
         };
 
         info!(
+            target: "psx::dma",
             "Direction: {} RAM",
             if self.control.get_bit(0) {
                 "From"
@@ -72,6 +78,7 @@ impl Channel {
             }
         );
         info!(
+            target: "psx::dma",
             "Address Step: {}",
             if self.control.get_bit(1) {
                 "Backward"
@@ -80,6 +87,7 @@ impl Channel {
             }
         );
         info!(
+            target: "psx::dma",
             "Chopping: {}",
             if self.control.get_bit(8) {
                 "True"
@@ -89,14 +97,17 @@ impl Channel {
         );
 
         info!(
+            target: "psx::dma",
             "Chopping DMA Window Size: {} words",
             self.control.get_bits(16..18) << 1
         );
         info!(
+            target: "psx::dma",
             "Chopping CPU Window Size: {} cycles",
             self.control.get_bits(20..22) << 1
         );
         info!(
+            target: "psx::dma",
             "Start/Busy: {}",
             if self.control.get_bit(24) {
                 "Start"
@@ -105,6 +116,7 @@ impl Channel {
             }
         );
         info!(
+            target: "psx::dma",
             "Start/Trigger: {}",
             if self.control.get_bit(28) {
                 "Start"
@@ -112,18 +124,35 @@ impl Channel {
                 "Stopped"
             }
         );
-        info!("");
+        info!(target: "psx::dma", "");
     }
 
     fn sync_mode(&self) -> usize {
         self.control.get_bits(9..=10) as usize
     }
+
+    /// Words a block transfer will move. Linked lists don't know their length up front, so they
+    /// count their words as they walk the list instead
+    fn transfer_words(&self) -> u64 {
+        let block_size = self.block & 0xFFFF;
+        let blocks = (self.block >> 16) & 0xFFFF;
+        match self.sync_mode() {
+            0 if block_size == 0 => 0x10000,
+            0 => block_size as u64,
+            1 => (block_size.max(1) * blocks.max(1)) as u64,
+            _ => 0,
+        }
+    }
 }
 
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct DMAState {
     channels: [Channel; NUM_CHANNELS],
     control: u32,
     interrupt: u32,
+    /// Words moved since take_transferred_words was last called
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    transferred_words: u64,
 }
 
 impl DMAState {
@@ -140,14 +169,15 @@ impl DMAState {
             ],
             control: 0x07654321, //Initial value on reset
             interrupt: 0,
+            transferred_words: 0,
         }
     }
 
     pub fn read_word(&mut self, addr: u32) -> u32 {
         let channel_num = (((addr & 0x000000F0) >> 4) - 0x8) as usize;
         match addr {
-            0x1F8010F0 => self.control,
-            0x1F8010F4 => {
+            DMA_DPCR => self.control,
+            DMA_DICR => {
                 //println!("Reading DMA interrupt. val {:#X}", self.interrupt);
                 self.interrupt
             }
@@ -155,12 +185,12 @@ impl DMAState {
                 match addr & 0xFFFFFF0F {
                     0x1F801000 => {
                         //read base address
-                        trace!("DMA ACCESS: Read base");
+                        trace!(target: "psx::dma", "DMA ACCESS: Read base");
                         self.channels[channel_num].base_addr
                     }
                     0x1F801004 => {
                         //read block control
-                        trace!("DMA ACCESS: Read block");
+                        trace!(target: "psx::dma", "DMA ACCESS: Read block");
                         self.channels[channel_num].block
                     }
                     0x1F801008 => {
@@ -178,8 +208,8 @@ impl DMAState {
         let channel_num = (((addr & 0x000000F0) >> 4) - 0x8) as usize;
         //println!("Write DMA word: addr {:#X} value {:#X}", addr, value);
         match addr {
-            0x1F8010F0 => self.control = value,
-            0x1F8010F4 => {
+            DMA_DPCR => self.control = value,
+            DMA_DICR => {
                 self.interrupt = write_dicr(self.interrupt, value);
                 self.update_master_flag();
             }
@@ -207,22 +237,30 @@ impl DMAState {
         };
     }
 
+    pub fn read_half_word(&mut self, addr: u32) -> u16 {
+        extract_half(self.read_word(word_address(addr)), addr)
+    }
+
+    pub fn write_half_word(&mut self, addr: u32, value: u16) {
+        let word = insert_half(self.word_before_partial_write(addr), addr, value);
+        self.write_word(word_address(addr), word);
+    }
+
     pub fn read_byte(&mut self, addr: u32) -> u8 {
-        let _channel_num = (((addr & 0x000000F0) >> 4) - 0x8) as usize;
-        match addr {
-            0x1F8010F6 => ((self.interrupt >> 16) & 0xFF) as u8,
-            _ => panic!("Unknown DMA read byte {:#X}", addr),
-        }
+        extract_byte(self.read_word(word_address(addr)), addr)
     }
 
     pub fn write_byte(&mut self, addr: u32, value: u8) {
-        let _channel_num = (((addr & 0x000000F0) >> 4) - 0x8) as usize;
-        match addr {
-            0x1F8010F6 => {
-                self.interrupt &= !(0xFF << 16);
-                self.interrupt |= (value as u32) << 16
-            }
-            _ => panic!("Unknown DMA read byte {:#X}", addr),
+        let word = insert_byte(self.word_before_partial_write(addr), addr, value);
+        self.write_word(word_address(addr), word);
+    }
+
+    /// What the lanes a half word or byte write leaves alone hold. DICR's flags are left out, since
+    /// writing them back as 1 would acknowledge them
+    fn word_before_partial_write(&mut self, addr: u32) -> u32 {
+        match word_address(addr) {
+            DMA_DICR => self.interrupt & 0x00FF_FFFF,
+            word_addr => self.read_word(word_addr),
         }
     }
 
@@ -237,9 +275,29 @@ impl DMAState {
         self.control.get_bit((channel_num * 4) + 3)
     }
 
+    fn channel_priority(&self, channel_num: usize) -> u32 {
+        self.control.get_bits((channel_num * 4)..(channel_num * 4 + 3))
+    }
+
+    /// Channels that are enabled in DPCR and waiting to transfer, in the order they should be
+    /// serviced. Priority 0 goes first, and on a tie the higher channel number wins
+    fn pending_channels(&self) -> Vec<usize> {
+        let mut pending: Vec<usize> = (0..NUM_CHANNELS)
+            .filter(|&num| self.channel_enabled(num) && self.channels[num].enabled())
+            .collect();
+        pending.sort_by_key(|&num| (self.channel_priority(num), Reverse(num)));
+        pending
+    }
+
+    /// Takes the number of words DMA has moved since the last call
+    pub fn take_transferred_words(&mut self) -> u64 {
+        std::mem::take(&mut self.transferred_words)
+    }
+
     fn raise_irq(&mut self, channel_num: usize) {
         if self.interrupt.get_bit(16 + channel_num) {
             self.interrupt.set_bit(24 + channel_num, true);
+            self.update_master_flag();
         }
     }
 
@@ -251,18 +309,10 @@ impl DMAState {
 }
 
 pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mut Scheduler) {
-    //Populate list of running and enabled dma channels
-    let mut channels_to_run: Vec<usize> = Vec::new();
-    for i in 0..NUM_CHANNELS {
-        let channel = &main_bus.dma.channels[i];
-        if main_bus.dma.channel_enabled(i) && channel.enabled() {
-            channels_to_run.push(i);
-            //break; // Only try one channel per cycle
-        }
-    }
     //Execute dma copy for each channel
-    for num in channels_to_run {
+    for num in main_bus.dma.pending_channels() {
         main_bus.dma.channels[num].print_stats();
+        let words = main_bus.dma.channels[num].transfer_words();
         //main_bus.dma.channels[num].control.set_bit(28, false); // Disable this channel's Start/Trigger bit because the transfer has begun
         match num {
             0 => {
@@ -285,7 +335,7 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
                             for j in 0..block_size {
                                 let word = main_bus
                                     .read_word(base_addr + ((i * block_size) * 4) + (j * 4), scheduler);
-                                main_bus.mdec.bus_write_word(0x1f801820, word);
+                                main_bus.mdec.bus_write_word(MDEC_COMMAND, word);
                             }
                         }
                     }
@@ -297,8 +347,8 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
                 if main_bus.dma.irq_channel_enabled(num) {
                     cpu.fire_external_interrupt(InterruptSource::DMA);
                 } else {
-                    trace!("DMA IRQ Rejected");
-                    trace!("DICR: {:#X}", main_bus.dma.interrupt);
+                    trace!(target: "psx::dma", "DMA IRQ Rejected");
+                    trace!(target: "psx::dma", "DICR: {:#X}", main_bus.dma.interrupt);
                 }
             }
 
@@ -320,25 +370,25 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
                     0x01000200 => {
                         for i in 0..entries {
                             for j in 0..block_size {
-                                let word = main_bus.mdec.bus_read_word(0x1f801820);
+                                let word = main_bus.mdec.bus_read_word(MDEC_COMMAND);
                                 //println!("MDEC_out DMA pushing word {:#X}", word);
                                 main_bus
                                     .write_word(base_addr + ((i * block_size) * 4) + (j * 4), word, scheduler);
                             }
                         }
-                        trace!("MDEC_out transfer done!")
+                        trace!(target: "psx::dma", "MDEC_out transfer done!")
                     }
-                    control => println!("Unknown MDEC DMA transfer! {:#X}", control),
+                    control => warn!(target: "psx::dma", "Unknown MDEC DMA transfer! {:#X}", control),
                 }
 
                 main_bus.dma.channels[num].complete();
                 main_bus.dma.raise_irq(num);
                 if main_bus.dma.irq_channel_enabled(num) {
                     cpu.fire_external_interrupt(InterruptSource::DMA);
-                    trace!("IRQ fired");
+                    trace!(target: "psx::dma", "IRQ fired");
                 } else {
-                    trace!("DMA IRQ Rejected");
-                    trace!("DICR: {:#X}", main_bus.dma.interrupt);
+                    trace!(target: "psx::dma", "DMA IRQ Rejected");
+                    trace!(target: "psx::dma", "DICR: {:#X}", main_bus.dma.interrupt);
                 }
             }
 
@@ -347,47 +397,45 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
                 match main_bus.dma.channels[num].control {
                     0x01000401 => {
                         //Linked list mode. mem -> gpu
-                        let mut addr = main_bus.dma.channels[num].base_addr;
-                        trace!("Starting linked list transfer. addr {:#X}", addr);
-                        let mut header = main_bus.read_word(addr, scheduler);
-                        trace!("base addr: {:#X}. base header: {:#X}", addr, header);
-                        loop {
-                            let num_words = (header >> 24) & 0xFF;
-                            //trace!("addr {:#X}, header {:#X}, nw {}", addr, header, num_words);
-                            for i in 0..num_words {
-                                let packet = main_bus.read_word((addr + 4) + (i * 4), scheduler);
-                                main_bus.gpu.send_gp0_command(packet);
-                            }
-                            if header & 0x800000 != 0 || header == 0x00FFFFFF {
-                                break;
-                            }
-
-                            if addr == 0 {
-                                trace!("Hit DMA infinite loop");
-                                break;
-                            }
-
-                            //println!("Addr {:X}", addr);
-
-                            addr = header & 0xFFFFFF;
-                            header = main_bus.read_word(addr, scheduler);
+                        // One node is sent per DMA cycle, with MADR pointing at the next one, so
+                        // clearing the start bit stops the list part way through
+                        let addr = main_bus.dma.channels[num].base_addr;
+                        let header = main_bus.read_word(addr, scheduler);
+                        let num_words = (header >> 24) & 0xFF;
+                        trace!(target: "psx::dma", "Linked list node. addr {:#X} header {:#X}", addr, header);
+                        if num_words > 0 {
+                            main_bus.gpu.start_gp0_packet();
                         }
-                        main_bus.dma.channels[num].base_addr = 0xFFFFFF;
-                        //println!("DMA2 linked list transfer done.");
-                        main_bus.dma.channels[num].complete();
+                        for i in 0..num_words {
+                            let packet = main_bus.read_word((addr + 4) + (i * 4), scheduler);
+                            main_bus.gpu.send_gp0_command(packet);
+                        }
+                        main_bus.dma.transferred_words += num_words as u64 + 1;
 
-                        main_bus.dma.raise_irq(num);
-                        if main_bus.dma.irq_channel_enabled(num) {
-                            cpu.fire_external_interrupt(InterruptSource::DMA);
+                        let end_of_list = header & 0x800000 != 0 || header == 0x00FFFFFF;
+                        if addr == 0 && !end_of_list {
+                            trace!(target: "psx::dma", "Hit DMA infinite loop");
+                        }
+                        if end_of_list || addr == 0 {
+                            main_bus.dma.channels[num].base_addr = 0xFFFFFF;
+                            //println!("DMA2 linked list transfer done.");
+                            main_bus.dma.channels[num].complete();
+
+                            main_bus.dma.raise_irq(num);
+                            if main_bus.dma.irq_channel_enabled(num) {
+                                cpu.fire_external_interrupt(InterruptSource::DMA);
+                            } else {
+                                trace!(target: "psx::dma", "DMA IRQ Rejected");
+                                trace!(target: "psx::dma", "DICR: {:#X}", main_bus.dma.interrupt);
+                            }
                         } else {
-                            trace!("DMA IRQ Rejected");
-                            trace!("DICR: {:#X}", main_bus.dma.interrupt);
+                            main_bus.dma.channels[num].base_addr = header & 0xFFFFFF;
                         }
                     }
 
                     0x01000201 => {
                         //VramWrite
-                        trace!("DMA: Starting VramWrite");
+                        trace!(target: "psx::dma", "DMA: Starting VramWrite");
                         let mut entries = (main_bus.dma.channels[num].block >> 16) & 0xFFFF;
                         let mut block_size = (main_bus.dma.channels[num].block) & 0xFFFF;
                         let base_addr = main_bus.dma.channels[num].base_addr & 0xFFFFFF;
@@ -398,6 +446,7 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
                             block_size = 1
                         };
                         trace!(
+                            target: "psx::dma",
                             "Block size {} Num blocks {} base {:#X}",
                             block_size,
                             entries,
@@ -410,46 +459,34 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
                                 main_bus.gpu.send_gp0_command(packet);
                             }
                         }
-                        trace!("DMA2 block transfer done.");
+                        trace!(target: "psx::dma", "DMA2 block transfer done.");
                         main_bus.dma.channels[num].base_addr += entries * block_size * 4;
                         main_bus.dma.channels[num].complete();
                         main_bus.dma.raise_irq(num);
                         if main_bus.dma.irq_channel_enabled(num) {
                             cpu.fire_external_interrupt(InterruptSource::DMA);
                         } else {
-                            trace!("DMA IRQ Rejected");
-                            trace!("DICR: {:#X}", main_bus.dma.interrupt);
+                            trace!(target: "psx::dma", "DMA IRQ Rejected");
+                            trace!(target: "psx::dma", "DICR: {:#X}", main_bus.dma.interrupt);
                         }
                     }
-                    0x1000200 => {
-                        //VramRead
-                        //TODO: Implement properly
-                        trace!("VRAM read");
-
-                        let mut entries = (main_bus.dma.channels[num].block >> 16) & 0xFFFF;
-                        let mut block_size = (main_bus.dma.channels[num].block) & 0xFFFF;
+                    control if !control.get_bit(0) && main_bus.dma.channels[num].sync_mode() != 2 => {
+                        //VramRead. Pulls from the transfer GP0(C0h) set up. Stops at the word
+                        // count in BCR, even when the transfer has more left
+                        trace!(target: "psx::dma", "DMA: Starting VramRead of {} words", words);
                         let base_addr = main_bus.dma.channels[num].base_addr & 0xFFFFFF;
-                        if entries == 0 {
-                            entries = 1
-                        };
-                        if block_size == 0 {
-                            block_size = 1
-                        };
-                        for i in 0..entries {
-                            for j in 0..block_size {
-                                let val = main_bus.gpu.read_word_gp0();
-                                main_bus
-                                    .write_word(base_addr + ((i * block_size) * 4) + (j * 4), val, scheduler);
-                            }
+                        for i in 0..words as u32 {
+                            let val = main_bus.gpu.read_word_gp0();
+                            main_bus.write_word(base_addr + i * 4, val, scheduler);
                         }
-                        main_bus.dma.channels[num].base_addr += entries * block_size * 4;
+                        main_bus.dma.channels[num].base_addr = (base_addr + words as u32 * 4) & 0xFFFFFF;
                         main_bus.dma.channels[num].complete();
                         main_bus.dma.raise_irq(num);
                         if main_bus.dma.irq_channel_enabled(num) {
                             cpu.fire_external_interrupt(InterruptSource::DMA);
                         } else {
-                            trace!("DMA IRQ Rejected");
-                            trace!("DICR: {:#X}", main_bus.dma.interrupt);
+                            trace!(target: "psx::dma", "DMA IRQ Rejected");
+                            trace!(target: "psx::dma", "DICR: {:#X}", main_bus.dma.interrupt);
                         }
                     }
                     _ => {
@@ -459,39 +496,32 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
             }
 
             3 => {
+                // The transfer waits until the drive has a sector in its data FIFO
+                if !main_bus.cd_drive.data_request() {
+                    continue;
+                }
+
                 let mut words = (main_bus.dma.channels[num].block) & 0xFFFF;
                 let base_addr = (main_bus.dma.channels[num].base_addr & 0xFFFFFF) as usize;
-                let data = main_bus.cd_drive.data_queue();
 
                 if words == 0 {
                     words = 0x10000;
                 }
 
-                if data.len() == 0 {
-                    panic!("Tried to do dma on empty cd buffer");
-                } else {
-                    if data.len() < (words as usize) * 4 {
-                        let diff = ((words as usize) * 4) - data.len();
-                        for i in 0..diff {
-                            data.push(data[i]);
-                        }
-                    }
-                }
-
-                trace!("Words {} base_addr {:#X}", words, base_addr);
+                trace!(target: "psx::dma", "Words {} base_addr {:#X}", words, base_addr);
 
-                for i in 0..(words * 4) {
-                    main_bus.memory.data[(base_addr + i as usize)] = data[i as usize];
+                let data = main_bus.cd_drive.take_dma_data((words as usize) * 4);
+                for (i, byte) in data.into_iter().enumerate() {
+                    let addr = main_bus.memory.mirror((base_addr + i) as u32) as usize;
+                    main_bus.memory.data[addr] = byte;
                 }
-                //main_bus.memory.data[base_addr..(base_addr + (words * 4) as usize)].copy_from_slice(data);
-                data.drain(0..((words as usize) * 4));
                 main_bus.dma.channels[num].complete();
                 main_bus.dma.raise_irq(num);
                 if main_bus.dma.irq_channel_enabled(num) {
                     cpu.fire_external_interrupt(InterruptSource::DMA);
                 } else {
-                    trace!("DMA IRQ Rejected");
-                    trace!("DICR: {:#X}", main_bus.dma.interrupt);
+                    trace!(target: "psx::dma", "DMA IRQ Rejected");
+                    trace!(target: "psx::dma", "DICR: {:#X}", main_bus.dma.interrupt);
                 }
             }
 
@@ -500,7 +530,7 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
 
                 let mut entries = (main_bus.dma.channels[num].block >> 16) & 0xFFFF;
                 let mut block_size = (main_bus.dma.channels[num].block) & 0xFFFF;
-                let _base_addr = main_bus.dma.channels[num].base_addr & 0xFFFFFF;
+                let base_addr = main_bus.dma.channels[num].base_addr & 0xFFFFFF;
 
                 if entries == 0 {
                     entries = 1
@@ -511,14 +541,22 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
 
                 match main_bus.dma.channels[num].control {
                     0x01000201 => {
-                        for _ in 0..entries {
-                            for _ in 0..block_size {
-                                main_bus.spu.write_half_word(0x1F801DA8, 0);
-                                main_bus.spu.write_half_word(0x1F801DA8, 0);
-                            }
+                        // Main RAM to SPU RAM, low half word first
+                        for i in 0..(entries * block_size) {
+                            let word = main_bus.read_word(base_addr + i * 4, scheduler);
+                            main_bus.spu.dma_write(word as u16);
+                            main_bus.spu.dma_write((word >> 16) as u16);
                         }
                     }
-                    control => println!("Unknown SPU DMA transfer! {:#X}", control),
+                    0x01000200 => {
+                        // SPU RAM to main RAM. Games use this to read back the capture buffers
+                        for i in 0..(entries * block_size) {
+                            let low = main_bus.spu.read_half_word(0x1F801DA8) as u32;
+                            let high = main_bus.spu.read_half_word(0x1F801DA8) as u32;
+                            main_bus.write_word(base_addr + i * 4, (high << 16) | low, scheduler);
+                        }
+                    }
+                    control => warn!(target: "psx::dma", "Unknown SPU DMA transfer! {:#X}", control),
                 }
 
                 main_bus.dma.channels[num].complete();
@@ -526,8 +564,8 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
                 if main_bus.dma.irq_channel_enabled(num) {
                     cpu.fire_external_interrupt(InterruptSource::DMA);
                 } else {
-                    trace!("DMA IRQ Rejected");
-                    trace!("DICR: {:#X}", main_bus.dma.interrupt);
+                    trace!(target: "psx::dma", "DMA IRQ Rejected");
+                    trace!(target: "psx::dma", "DICR: {:#X}", main_bus.dma.interrupt);
                 }
             }
 
@@ -536,7 +574,7 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
                 //OTC is only used to reset the ordering table. So we can ignore a lot of the parameters
                 let mut entries = main_bus.dma.channels[num].block & 0xFFFF;
                 let base = main_bus.dma.channels[num].base_addr & 0xFFFFFF;
-                trace!("Initializing {} entries ending at {:#X}", entries, base);
+                trace!(target: "psx::dma", "Initializing {} entries ending at {:#X}", entries, base);
 
                 if entries == 0 {
                     entries = 1;
@@ -554,18 +592,19 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
                         //println!("Wrote DMA6 header at {:#X} val {:#X}", addr, (addr - 4) & 0xFFFFFF);
                     }
                 }
-                trace!("DMA6 done. Marking complete and raising irq");
+                trace!(target: "psx::dma", "DMA6 done. Marking complete and raising irq");
                 main_bus.dma.channels[num].complete();
                 main_bus.dma.raise_irq(num);
                 if main_bus.dma.irq_channel_enabled(num) {
                     cpu.fire_external_interrupt(InterruptSource::DMA);
                 } else {
-                    trace!("DMA IRQ Rejected");
-                    trace!("DICR: {:#X}", main_bus.dma.interrupt);
+                    trace!(target: "psx::dma", "DMA IRQ Rejected");
+                    trace!(target: "psx::dma", "DICR: {:#X}", main_bus.dma.interrupt);
                 }
             }
             _ => panic!("Unable to transfer unknown DMA channel {}!", num),
         }
+        main_bus.dma.transferred_words += words;
     }
 
     let old_flag = main_bus.dma.interrupt.get_bit(31);
@@ -577,7 +616,7 @@ pub fn execute_dma_cycle(cpu: &mut R3000, main_bus: &mut MainBus, scheduler: &mu
 
 fn write_dicr(current_value: u32, value: u32) -> u32 {
     if value.get_bit(15) {
-        error!("OH GOD BIT 15 IS SET")
+        error!(target: "psx::dma", "OH GOD BIT 15 IS SET")
     }
     let normal_bits = value & 0xFFFFFF; //These bits are written normally
     let ack_bits = (value >> 24) & 0x7F; //These bits are written as a one to clear. 0x7F0000
@@ -588,6 +627,9 @@ fn write_dicr(current_value: u32, value: u32) -> u32 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bios::{Bios, BIOS_SIZE};
+    use crate::gpu::Gpu;
+    use crate::memory::Memory;
 
     #[test]
     fn test_write_dicr() {
@@ -596,4 +638,237 @@ mod tests {
         assert_eq!(write_dicr(0x7F000000, 0x7F000000), 0x0);
         assert_eq!(write_dicr(0x0, 0x7F000001), 0x1);
     }
+
+    const DPCR: u32 = DMA_DPCR;
+    const DICR: u32 = DMA_DICR;
+    const OTC_CHCR: u32 = 0x1F8010E8;
+    const GPU_MADR: u32 = 0x1F8010A0;
+    const GPU_CHCR: u32 = 0x1F8010A8;
+    const SPU_MADR: u32 = 0x1F8010C0;
+    const SPU_CHCR: u32 = 0x1F8010C8;
+
+    #[test]
+    fn test_dicr_partial_writes() {
+        let mut dma = DMAState::new();
+        dma.write_word(DICR, 0x0084_0000);
+        dma.raise_irq(2);
+        dma.update_master_flag();
+        assert_eq!(dma.read_word(DICR), 0x8484_0000);
+
+        // Rewriting the enables by byte or half word leaves the pending flag alone
+        dma.write_byte(DICR, 0x3F);
+        dma.write_half_word(DICR + 2, 0x0084);
+        assert_eq!(dma.read_word(DICR), 0x8484_003F);
+        assert_eq!(dma.read_byte(DICR + 2), 0x84);
+        assert_eq!(dma.read_half_word(DICR + 2), 0x8484);
+
+        // Acking it by byte drops the master flag with it
+        dma.write_byte(DICR + 3, 0x04);
+        assert_eq!(dma.read_word(DICR), 0x0084_003F);
+    }
+
+    /// Bus holding a linked list of empty nodes at 0x1000, 0x1010, 0x1020 and 0x1030, armed on the
+    /// GPU channel with its IRQ enabled
+    fn linked_list_setup() -> (R3000, MainBus, Scheduler) {
+        let bios = Bios::new(vec![0; BIOS_SIZE]).unwrap();
+        let mut bus = MainBus::new(bios, Memory::new(), Gpu::new());
+        let mut scheduler = Scheduler::new();
+        for node in 0..3 {
+            let addr = 0x1000 + node * 0x10;
+            bus.write_word(addr, addr + 0x10, &mut scheduler);
+        }
+        bus.write_word(0x1030, 0x00FF_FFFF, &mut scheduler);
+
+        bus.dma.write_word(DPCR, 0x0000_0800);
+        bus.dma.write_word(DICR, 0x0084_0000);
+        bus.dma.write_word(GPU_MADR, 0x1000);
+        bus.dma.write_word(GPU_CHCR, 0x0100_0401);
+        (R3000::new(), bus, scheduler)
+    }
+
+    #[test]
+    fn test_linked_list_completes() {
+        let (mut cpu, mut bus, mut scheduler) = linked_list_setup();
+        for _ in 0..3 {
+            execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+            assert!(bus.dma.read_word(GPU_CHCR).get_bit(24));
+        }
+        execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        assert!(!bus.dma.read_word(GPU_CHCR).get_bit(24));
+        assert_eq!(bus.dma.read_word(GPU_MADR), 0xFF_FFFF);
+        assert!(bus.dma.read_word(DICR).get_bit(26));
+    }
+
+    #[test]
+    fn test_linked_list_abort() {
+        let (mut cpu, mut bus, mut scheduler) = linked_list_setup();
+        execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        assert_eq!(bus.dma.read_word(GPU_MADR), 0x1020);
+
+        // Clearing start/busy stops the list where it is, without an IRQ
+        bus.dma.write_word(GPU_CHCR, 0x0000_0401);
+        for _ in 0..10 {
+            execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        }
+        assert_eq!(bus.dma.read_word(GPU_MADR), 0x1020);
+        assert!(!bus.dma.read_word(DICR).get_bit(26));
+
+        // Setting it again picks the list back up from MADR
+        bus.dma.write_word(GPU_CHCR, 0x0100_0401);
+        for _ in 0..2 {
+            execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        }
+        assert_eq!(bus.dma.read_word(GPU_MADR), 0xFF_FFFF);
+        assert!(!bus.dma.read_word(GPU_CHCR).get_bit(24));
+    }
+
+    #[test]
+    fn test_linked_list_paused_by_dpcr() {
+        let (mut cpu, mut bus, mut scheduler) = linked_list_setup();
+        execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        assert_eq!(bus.dma.read_word(GPU_MADR), 0x1020);
+
+        // Disabling the channel holds the list where it is, still busy
+        bus.dma.write_word(DPCR, 0);
+        for _ in 0..10 {
+            execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        }
+        assert_eq!(bus.dma.read_word(GPU_MADR), 0x1020);
+        assert!(bus.dma.read_word(GPU_CHCR).get_bit(24));
+        assert!(!bus.dma.read_word(DICR).get_bit(26));
+
+        // Enabling it again finishes the list
+        bus.dma.write_word(DPCR, 0x0000_0800);
+        for _ in 0..2 {
+            execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        }
+        assert_eq!(bus.dma.read_word(GPU_MADR), 0xFF_FFFF);
+        assert!(!bus.dma.read_word(GPU_CHCR).get_bit(24));
+        assert!(bus.dma.read_word(DICR).get_bit(26));
+    }
+
+    #[test]
+    fn test_disabled_channel_does_not_run() {
+        let mut dma = DMAState::new();
+        dma.write_word(DPCR, 0);
+        dma.write_word(OTC_CHCR, 0x1100_0002);
+        assert!(dma.pending_channels().is_empty());
+
+        // Enabling the channel lets the waiting transfer start
+        dma.write_word(DPCR, 0x0800_0000);
+        assert_eq!(dma.pending_channels(), vec![6]);
+
+        // Disabling it again holds the transfer back without clearing the start bit
+        dma.write_word(DPCR, 0);
+        assert!(dma.pending_channels().is_empty());
+        assert!(dma.read_word(OTC_CHCR).get_bit(24));
+    }
+
+    #[test]
+    fn test_channels_run_in_priority_order() {
+        let mut dma = DMAState::new();
+        dma.write_word(OTC_CHCR, 0x1100_0002);
+        dma.write_word(GPU_CHCR, 0x0100_0401);
+
+        // GPU priority 1, OTC priority 3
+        dma.write_word(DPCR, 0x0B00_0900);
+        assert_eq!(dma.pending_channels(), vec![2, 6]);
+
+        // OTC priority 0
+        dma.write_word(DPCR, 0x0800_0900);
+        assert_eq!(dma.pending_channels(), vec![6, 2]);
+
+        // On a tie the higher channel goes first
+        dma.write_word(DPCR, 0x0900_0900);
+        assert_eq!(dma.pending_channels(), vec![6, 2]);
+    }
+
+    #[test]
+    fn test_spu_upload() {
+        let bios = Bios::new(vec![0; BIOS_SIZE]).unwrap();
+        let mut bus = MainBus::new(bios, Memory::new(), Gpu::new());
+        let mut scheduler = Scheduler::new();
+        let mut cpu = R3000::new();
+        let words: Vec<u32> = (0..8).map(|i| 0x1111_0000 * (i + 1) + i).collect();
+        for (i, word) in words.iter().enumerate() {
+            bus.write_word(0x3000 + i as u32 * 4, *word, &mut scheduler);
+        }
+
+        // Start of SPU RAM after the capture buffers
+        bus.write_half_word(0x1F80_1DA6, 0x200, &mut scheduler);
+        bus.dma.write_word(DPCR, 0x0008_0000);
+        bus.dma.write_word(SPU_MADR, 0x3000);
+        // 2 blocks of 4 words
+        bus.dma.write_word(SPU_MADR + 4, 0x0002_0004);
+        bus.dma.write_word(SPU_CHCR, 0x0100_0201);
+        execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        assert!(!bus.dma.read_word(SPU_CHCR).get_bit(24));
+
+        bus.write_half_word(0x1F80_1DA6, 0x200, &mut scheduler);
+        for (i, word) in words.iter().enumerate() {
+            let low = bus.read_half_word(0x1F80_1DA8, &mut scheduler) as u32;
+            let high = bus.read_half_word(0x1F80_1DA8, &mut scheduler) as u32;
+            assert_eq!((high << 16) | low, *word, "word {}", i);
+        }
+    }
+
+    #[test]
+    fn test_vram_read() {
+        let bios = Bios::new(vec![0; BIOS_SIZE]).unwrap();
+        let mut bus = MainBus::new(bios, Memory::new(), Gpu::new());
+        let mut scheduler = Scheduler::new();
+        let mut cpu = R3000::new();
+        let pixel = |x: u32, y: u32| (y << 8) | x;
+
+        // A 16x8 pattern at (0, 0)
+        bus.write_word(GPU_GP0, 0xA000_0000, &mut scheduler);
+        bus.write_word(GPU_GP0, 0, &mut scheduler);
+        bus.write_word(GPU_GP0, (8 << 16) | 16, &mut scheduler);
+        for y in 0..8 {
+            for x in (0..16).step_by(2) {
+                bus.write_word(GPU_GP0, pixel(x, y) | (pixel(x + 1, y) << 16), &mut scheduler);
+            }
+        }
+
+        // Read back 5x3 from (3, 2). Odd widths carry on into the next row within a word
+        bus.write_word(GPU_GP0, 0xC000_0000, &mut scheduler);
+        bus.write_word(GPU_GP0, (2 << 16) | 3, &mut scheduler);
+        bus.write_word(GPU_GP0, (3 << 16) | 5, &mut scheduler);
+        assert!(bus.read_word(GPU_GP1, &mut scheduler).get_bit(27));
+
+        bus.dma.write_word(DPCR, 0x0000_0800);
+        bus.dma.write_word(DICR, 0x0084_0000);
+        bus.dma.write_word(GPU_MADR, 0x2000);
+        // 2 blocks of 4 words, the 15 pixels and a pad
+        bus.dma.write_word(0x1F80_10A4, 0x0002_0004);
+        bus.dma.write_word(GPU_CHCR, 0x0100_0200);
+        execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+
+        let expected: Vec<u32> = (2..5).flat_map(|y| (3..8).map(move |x| pixel(x, y))).chain([0]).collect();
+        for (i, pair) in expected.chunks(2).enumerate() {
+            let word = bus.read_word(0x2000 + i as u32 * 4, &mut scheduler);
+            assert_eq!(word, pair[0] | (pair[1] << 16), "word {}", i);
+        }
+        assert!(!bus.read_word(GPU_GP1, &mut scheduler).get_bit(27), "transfer is used up");
+        assert!(!bus.dma.read_word(GPU_CHCR).get_bit(24));
+        assert_eq!(bus.dma.read_word(GPU_MADR), 0x2020);
+        assert_eq!(bus.dma.read_word(DICR) >> 24, 0x84);
+        assert!(cpu.i_status.get_bit(InterruptSource::DMA as usize));
+
+        // A shorter count leaves the rest of the transfer for GPUREAD
+        bus.write_word(GPU_GP0, 0xC000_0000, &mut scheduler);
+        bus.write_word(GPU_GP0, 0, &mut scheduler);
+        bus.write_word(GPU_GP0, (1 << 16) | 8, &mut scheduler);
+        bus.dma.write_word(GPU_MADR, 0x3000);
+        bus.dma.write_word(0x1F80_10A4, 0x0001_0002);
+        bus.dma.write_word(GPU_CHCR, 0x0100_0200);
+        execute_dma_cycle(&mut cpu, &mut bus, &mut scheduler);
+        assert_eq!(bus.read_word(0x3000, &mut scheduler), pixel(0, 0) | (pixel(1, 0) << 16));
+        assert_eq!(bus.read_word(0x3004, &mut scheduler), pixel(2, 0) | (pixel(3, 0) << 16));
+        assert_eq!(bus.read_word(0x3008, &mut scheduler), 0);
+        assert!(bus.read_word(GPU_GP1, &mut scheduler).get_bit(27));
+        assert_eq!(bus.read_word(GPU_GP0, &mut scheduler), pixel(4, 0) | (pixel(5, 0) << 16));
+    }
 }
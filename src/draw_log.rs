@@ -0,0 +1,476 @@
+//! Saving and loading GPU draw logs, so a frame's calls can be attached to a bug report and looked at on another machine
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::gpu::{
+    ClutSnapshot, DrawCall, DrawOperation, Point, Shading, Surface, TextureColorMode, Transparency,
+    VramChange,
+};
+
+const MAGIC: &[u8; 4] = b"FSDL";
+/// Version 2 added the VRAM each call changed. Version 3 made the texture fields optional and
+/// added the CLUT position. Older logs still load
+const VERSION: u16 = 3;
+
+/// Used for the Option fields, so 0 can mean None
+const NONE: u8 = 0;
+
+#[derive(Debug)]
+pub enum DrawLogError {
+    Io(io::Error),
+    NotADrawLog,
+    UnsupportedVersion(u16),
+    Corrupt(&'static str),
+}
+
+impl Display for DrawLogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DrawLogError::Io(e) => write!(f, "{}", e),
+            DrawLogError::NotADrawLog => write!(f, "Not a FogStation draw log"),
+            DrawLogError::UnsupportedVersion(version) => {
+                write!(f, "Draw log version {} is not supported", version)
+            }
+            DrawLogError::Corrupt(reason) => write!(f, "Draw log is corrupt: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DrawLogError {}
+
+impl From<io::Error> for DrawLogError {
+    fn from(e: io::Error) -> Self {
+        DrawLogError::Io(e)
+    }
+}
+
+pub fn save_draw_log(calls: &[DrawCall], path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_draw_log(calls, &mut writer)?;
+    writer.flush()
+}
+
+pub fn load_draw_log(path: &Path) -> Result<Vec<DrawCall>, DrawLogError> {
+    read_draw_log(&mut BufReader::new(File::open(path)?))
+}
+
+pub(crate) fn write_draw_log<W: Write>(calls: &[DrawCall], writer: &mut W) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_u16::<LittleEndian>(VERSION)?;
+    writer.write_u32::<LittleEndian>(calls.len() as u32)?;
+
+    for call in calls {
+        writer.write_u8(operation_id(call.operation))?;
+        writer.write_u8(call.shading.map_or(NONE, shading_id))?;
+        writer.write_u8(call.surface.map_or(NONE, surface_id))?;
+        writer.write_u8(call.transparency.map_or(NONE, transparency_id))?;
+
+        match &call.points {
+            Some(points) => {
+                writer.write_u8(1)?;
+                writer.write_u16::<LittleEndian>(points.len() as u16)?;
+                for point in points {
+                    writer.write_i32::<LittleEndian>(point.x)?;
+                    writer.write_i32::<LittleEndian>(point.y)?;
+                    writer.write_u16::<LittleEndian>(point.color)?;
+                    writer.write_i16::<LittleEndian>(point.tex_x)?;
+                    writer.write_i16::<LittleEndian>(point.tex_y)?;
+                }
+            }
+            None => writer.write_u8(NONE)?,
+        }
+
+        writer.write_u8(call.blending_enabled as u8)?;
+        writer.write_u8(call.call_dropped as u8)?;
+        match (call.clut_size, call.tex_base_x, call.tex_base_y) {
+            (Some(mode), Some(x), Some(y)) => {
+                writer.write_u8(1)?;
+                writer.write_u8(texture_mode_id(mode))?;
+                writer.write_u16::<LittleEndian>(x)?;
+                writer.write_u16::<LittleEndian>(y)?;
+            }
+            _ => writer.write_u8(NONE)?,
+        }
+        match (call.clut_x, call.clut_y) {
+            (Some(x), Some(y)) => {
+                writer.write_u8(1)?;
+                writer.write_u16::<LittleEndian>(x)?;
+                writer.write_u16::<LittleEndian>(y)?;
+            }
+            _ => writer.write_u8(NONE)?,
+        }
+
+        match &call.clut {
+            Some(clut) => {
+                writer.write_u8(1)?;
+                writer.write_u16::<LittleEndian>(clut.x)?;
+                writer.write_u16::<LittleEndian>(clut.y)?;
+                writer.write_u16::<LittleEndian>(clut.entries.len() as u16)?;
+                for entry in &clut.entries {
+                    writer.write_u16::<LittleEndian>(*entry)?;
+                }
+            }
+            None => writer.write_u8(NONE)?,
+        }
+
+        match &call.vram_change {
+            Some(change) => {
+                writer.write_u8(1)?;
+                for value in [change.x, change.y, change.width, change.height] {
+                    writer.write_u16::<LittleEndian>(value)?;
+                }
+                for pixel in change.before.iter().chain(&change.after) {
+                    writer.write_u16::<LittleEndian>(*pixel)?;
+                }
+            }
+            None => writer.write_u8(NONE)?,
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn read_draw_log<R: Read>(reader: &mut R) -> Result<Vec<DrawCall>, DrawLogError> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(DrawLogError::NotADrawLog);
+    }
+
+    let version = reader.read_u16::<LittleEndian>()?;
+    if version == 0 || version > VERSION {
+        return Err(DrawLogError::UnsupportedVersion(version));
+    }
+
+    let count = reader.read_u32::<LittleEndian>()?;
+    let mut calls = Vec::new();
+
+    for _ in 0..count {
+        let operation = operation_from_id(reader.read_u8()?)?;
+        let shading = optional(reader.read_u8()?, shading_from_id)?;
+        let surface = optional(reader.read_u8()?, surface_from_id)?;
+        let transparency = optional(reader.read_u8()?, transparency_from_id)?;
+
+        let points = match reader.read_u8()? {
+            NONE => None,
+            _ => {
+                let point_count = reader.read_u16::<LittleEndian>()?;
+                let mut points = Vec::with_capacity(point_count as usize);
+                for _ in 0..point_count {
+                    points.push(Point {
+                        x: reader.read_i32::<LittleEndian>()?,
+                        y: reader.read_i32::<LittleEndian>()?,
+                        color: reader.read_u16::<LittleEndian>()?,
+                        tex_x: reader.read_i16::<LittleEndian>()?,
+                        tex_y: reader.read_i16::<LittleEndian>()?,
+                    });
+                }
+                Some(points)
+            }
+        };
+
+        let blending_enabled = reader.read_u8()? != 0;
+        let call_dropped = reader.read_u8()? != 0;
+        let texture = match version {
+            // Older logs wrote the texture fields for every call, but they only meant something
+            // for textured ones
+            1 | 2 => Some(read_texture(reader)?).filter(|_| surface == Some(Surface::Textured)),
+            _ => match reader.read_u8()? {
+                NONE => None,
+                _ => Some(read_texture(reader)?),
+            },
+        };
+        let clut_position = match version {
+            1 | 2 => None,
+            _ => match reader.read_u8()? {
+                NONE => None,
+                _ => Some((reader.read_u16::<LittleEndian>()?, reader.read_u16::<LittleEndian>()?)),
+            },
+        };
+
+        let clut = match reader.read_u8()? {
+            NONE => None,
+            _ => {
+                let x = reader.read_u16::<LittleEndian>()?;
+                let y = reader.read_u16::<LittleEndian>()?;
+                let entry_count = reader.read_u16::<LittleEndian>()?;
+                let mut entries = Vec::with_capacity(entry_count as usize);
+                for _ in 0..entry_count {
+                    entries.push(reader.read_u16::<LittleEndian>()?);
+                }
+                Some(ClutSnapshot { x, y, entries })
+            }
+        };
+        // Older logs only know where the CLUT was if it was captured
+        let clut_position = clut_position.or_else(|| clut.as_ref().map(|clut| (clut.x, clut.y)));
+
+        let vram_change = match version {
+            1 => None,
+            _ => match reader.read_u8()? {
+                NONE => None,
+                _ => {
+                    let x = reader.read_u16::<LittleEndian>()?;
+                    let y = reader.read_u16::<LittleEndian>()?;
+                    let width = reader.read_u16::<LittleEndian>()?;
+                    let height = reader.read_u16::<LittleEndian>()?;
+                    let size = width as usize * height as usize;
+                    let mut pixels = vec![0; size * 2];
+                    reader.read_u16_into::<LittleEndian>(&mut pixels)?;
+                    let after = pixels.split_off(size);
+                    Some(VramChange { x, y, width, height, before: pixels, after })
+                }
+            },
+        };
+
+        calls.push(DrawCall {
+            operation,
+            shading,
+            surface,
+            transparency,
+            points,
+            blending_enabled,
+            call_dropped,
+            clut_size: texture.map(|(mode, _, _)| mode),
+            tex_base_x: texture.map(|(_, x, _)| x),
+            tex_base_y: texture.map(|(_, _, y)| y),
+            clut_x: clut_position.map(|(x, _)| x),
+            clut_y: clut_position.map(|(_, y)| y),
+            clut,
+            vram_change,
+        });
+    }
+
+    Ok(calls)
+}
+
+/// Color mode and texture page
+fn read_texture<R: Read>(reader: &mut R) -> Result<(TextureColorMode, u16, u16), DrawLogError> {
+    Ok((
+        texture_mode_from_id(reader.read_u8()?)?,
+        reader.read_u16::<LittleEndian>()?,
+        reader.read_u16::<LittleEndian>()?,
+    ))
+}
+
+fn optional<T>(id: u8, from_id: fn(u8) -> Result<T, DrawLogError>) -> Result<Option<T>, DrawLogError> {
+    match id {
+        NONE => Ok(None),
+        id => from_id(id).map(Some),
+    }
+}
+
+fn operation_id(operation: DrawOperation) -> u8 {
+    match operation {
+        DrawOperation::QuickFill => 0,
+        DrawOperation::Quad => 1,
+        DrawOperation::Triangle => 2,
+        DrawOperation::RectangleDynamic => 3,
+        DrawOperation::Rectangle16 => 4,
+        DrawOperation::Rectangle8 => 5,
+        DrawOperation::Pixel => 6,
+        DrawOperation::PolyLine => 7,
+        DrawOperation::Line => 8,
+        DrawOperation::CpuBlit => 9,
+    }
+}
+
+fn operation_from_id(id: u8) -> Result<DrawOperation, DrawLogError> {
+    Ok(match id {
+        0 => DrawOperation::QuickFill,
+        1 => DrawOperation::Quad,
+        2 => DrawOperation::Triangle,
+        3 => DrawOperation::RectangleDynamic,
+        4 => DrawOperation::Rectangle16,
+        5 => DrawOperation::Rectangle8,
+        6 => DrawOperation::Pixel,
+        7 => DrawOperation::PolyLine,
+        8 => DrawOperation::Line,
+        9 => DrawOperation::CpuBlit,
+        _ => return Err(DrawLogError::Corrupt("unknown draw operation")),
+    })
+}
+
+fn shading_id(shading: Shading) -> u8 {
+    match shading {
+        Shading::Gouraud => 1,
+        Shading::Flat => 2,
+    }
+}
+
+fn shading_from_id(id: u8) -> Result<Shading, DrawLogError> {
+    match id {
+        1 => Ok(Shading::Gouraud),
+        2 => Ok(Shading::Flat),
+        _ => Err(DrawLogError::Corrupt("unknown shading")),
+    }
+}
+
+fn surface_id(surface: Surface) -> u8 {
+    match surface {
+        Surface::Textured => 1,
+        Surface::Flat => 2,
+    }
+}
+
+fn surface_from_id(id: u8) -> Result<Surface, DrawLogError> {
+    match id {
+        1 => Ok(Surface::Textured),
+        2 => Ok(Surface::Flat),
+        _ => Err(DrawLogError::Corrupt("unknown surface")),
+    }
+}
+
+fn transparency_id(transparency: Transparency) -> u8 {
+    match transparency {
+        Transparency::SemiTransparent => 1,
+        Transparency::Solid => 2,
+    }
+}
+
+fn transparency_from_id(id: u8) -> Result<Transparency, DrawLogError> {
+    match id {
+        1 => Ok(Transparency::SemiTransparent),
+        2 => Ok(Transparency::Solid),
+        _ => Err(DrawLogError::Corrupt("unknown transparency")),
+    }
+}
+
+fn texture_mode_id(mode: TextureColorMode) -> u8 {
+    match mode {
+        TextureColorMode::FourBit => 0,
+        TextureColorMode::EightBit => 1,
+        TextureColorMode::FifteenBit => 2,
+    }
+}
+
+fn texture_mode_from_id(id: u8) -> Result<TextureColorMode, DrawLogError> {
+    match id {
+        0 => Ok(TextureColorMode::FourBit),
+        1 => Ok(TextureColorMode::EightBit),
+        2 => Ok(TextureColorMode::FifteenBit),
+        _ => Err(DrawLogError::Corrupt("unknown texture color mode")),
+    }
+}
+
+#[cfg(test)]
+mod draw_log_tests {
+    use super::*;
+    use crate::gpu::Gpu;
+
+    const CLUT_X: u32 = 0;
+    const CLUT_Y: u32 = 480;
+
+    /// Uploads a 16 color CLUT, all set to color
+    fn upload_clut(gpu: &mut Gpu, color: u16) {
+        gpu.send_gp0_command(0xA000_0000);
+        gpu.send_gp0_command((CLUT_Y << 16) | CLUT_X);
+        gpu.send_gp0_command((1 << 16) | 16);
+        for _ in 0..8 {
+            gpu.send_gp0_command(((color as u32) << 16) | color as u32);
+        }
+    }
+
+    /// Draws a 4 bit 16x16 sprite using the CLUT
+    fn draw_sprite(gpu: &mut Gpu) {
+        // 4 bit texture page
+        gpu.send_gp0_command(0xE100_0000);
+        gpu.send_gp0_command(0x7C80_8080);
+        gpu.send_gp0_command(0);
+        gpu.send_gp0_command(((CLUT_Y << 6) | (CLUT_X / 16)) << 16);
+    }
+
+    #[test]
+    fn test_deep_capture_keeps_overwritten_clut() {
+        let mut gpu = Gpu::new();
+        gpu.set_deep_capture(true);
+
+        upload_clut(&mut gpu, 0x1234);
+        draw_sprite(&mut gpu);
+        upload_clut(&mut gpu, 0x7FFF);
+
+        let log = gpu.take_call_log();
+        let clut = log
+            .iter()
+            .find_map(|call| call.clut.as_ref())
+            .expect("Sprite CLUT wasn't captured");
+
+        assert_eq!(clut.x as u32, CLUT_X);
+        assert_eq!(clut.y as u32, CLUT_Y);
+        assert_eq!(clut.entries, vec![0x1234; 16]);
+    }
+
+    #[test]
+    fn test_no_clut_without_deep_capture() {
+        let mut gpu = Gpu::new();
+
+        upload_clut(&mut gpu, 0x1234);
+        draw_sprite(&mut gpu);
+
+        assert!(gpu.take_call_log().iter().all(|call| call.clut.is_none()));
+    }
+
+    #[test]
+    fn test_texture_metadata() {
+        let mut gpu = Gpu::new();
+        // 8 bit texture page 5, 1 with its CLUT at 32, 400
+        let clut = (400 << 6) | (32 / 16);
+        let texpage = 5 | (1 << 4) | (1 << 7);
+        for word in [0x2C80_8080, 0, clut << 16, 64, texpage << 16, 64 << 16, 0, 0x0040_0040, 0] {
+            gpu.send_gp0_command(word);
+        }
+        draw_sprite(&mut gpu);
+        // Flat triangle
+        for word in [0x2000_00FF, 0, 64, 64 << 16] {
+            gpu.send_gp0_command(word);
+        }
+
+        let log = gpu.take_call_log();
+        let quad = &log[0];
+        assert_eq!(quad.clut_size, Some(TextureColorMode::EightBit));
+        assert_eq!((quad.tex_base_x, quad.tex_base_y), (Some(5), Some(1)));
+        assert_eq!((quad.clut_x, quad.clut_y), (Some(32), Some(400)));
+
+        // Sprites use the global texture page
+        let sprite = &log[1];
+        assert_eq!(sprite.clut_size, Some(TextureColorMode::FourBit));
+        assert_eq!((sprite.tex_base_x, sprite.tex_base_y), (Some(0), Some(0)));
+        assert_eq!((sprite.clut_x, sprite.clut_y), (Some(CLUT_X as u16), Some(CLUT_Y as u16)));
+
+        let triangle = &log[2];
+        assert_eq!(triangle.clut_size, None);
+        assert_eq!((triangle.tex_base_x, triangle.clut_x), (None, None));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut gpu = Gpu::new();
+        gpu.set_deep_capture(true);
+        upload_clut(&mut gpu, 0x1234);
+        draw_sprite(&mut gpu);
+        // Gouraud triangle
+        for word in [0x3000_00FF, 0, 0x0000_FF00, 64, 0x00FF_0000, 64 << 16] {
+            gpu.send_gp0_command(word);
+        }
+        let log = gpu.take_call_log();
+
+        let mut data = vec![];
+        write_draw_log(&log, &mut data).unwrap();
+        let loaded = read_draw_log(&mut data.as_slice()).unwrap();
+
+        assert_eq!(loaded.len(), log.len());
+        for (original, loaded) in log.iter().zip(&loaded) {
+            assert_eq!(format!("{:?}", original), format!("{:?}", loaded));
+        }
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        let result = read_draw_log(&mut b"PS-X EXE".as_slice());
+        assert!(matches!(result, Err(DrawLogError::NotADrawLog)));
+    }
+}
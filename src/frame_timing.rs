@@ -0,0 +1,22 @@
+use std::collections::BTreeMap;
+
+use crate::gpu::GpuStats;
+
+/// Where the emulated cycles went since the last call to `PSXEmu::take_frame_timing`. Taken once
+/// per frame, this shows whether a slow game is running more code or the emulator is just slow
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameTiming {
+    /// System cycles emulated
+    pub cycles: u64,
+    /// CPU instructions executed. The CPU runs one every other cycle, so this is at most half of `cycles`
+    pub instructions: u64,
+    /// Cycles the CPU spent waiting on the GTE or a multiply/divide. Always 0 until those stalls
+    /// are emulated
+    pub stall_cycles: u64,
+    /// Cycles DMA held the bus. Transfers finish instantly here, so this counts the words moved,
+    /// which is what they would take on hardware
+    pub dma_cycles: u64,
+    /// How many times each kind of scheduler event fired
+    pub events: BTreeMap<&'static str, u64>,
+    pub gpu: GpuStats,
+}
@@ -0,0 +1,225 @@
+//! The record of draw calls the GPU keeps for debuggers. `crate::draw_log` saves it to a file
+
+use std::{fmt::Display, mem};
+
+use enum_display_derive::Display;
+
+use super::transfer::read_vram_rect;
+use super::{point_to_address, Gpu, Point, TextureColorMode, VRAM_HEIGHT, VRAM_WIDTH};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum DrawOperation {
+    QuickFill,
+    Quad,
+    Triangle,
+    RectangleDynamic,
+    Rectangle16,
+    Rectangle8,
+    Pixel,
+    PolyLine,
+    Line,
+    CpuBlit,
+}
+
+impl Display for DrawOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DrawOperation::QuickFill => write!(f, "QuickFill"),
+            DrawOperation::Quad => write!(f, "Quad"),
+            DrawOperation::Triangle => write!(f, "Tri"),
+            DrawOperation::RectangleDynamic => write!(f, "VarRect"),
+            DrawOperation::Rectangle16 => write!(f, "Rect16"),
+            DrawOperation::Rectangle8 => write!(f, "Rect8"),
+            DrawOperation::Pixel => write!(f, "Pixel"),
+            DrawOperation::PolyLine => write!(f, "Polyline"),
+            DrawOperation::Line => write!(f, "Line"),
+            DrawOperation::CpuBlit => write!(f, "CpuBlit"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+pub enum Shading {
+    Gouraud,
+    Flat,
+}
+
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+pub enum Surface {
+    Textured,
+    Flat,
+}
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+pub enum Transparency {
+    SemiTransparent,
+    Solid,
+}
+#[derive(Clone, Debug)]
+pub struct DrawCall {
+    pub operation: DrawOperation,
+    pub shading: Option<Shading>,
+    pub surface: Option<Surface>,
+    pub transparency: Option<Transparency>,
+    pub points: Option<Vec<Point>>,
+    pub blending_enabled: bool,
+    pub call_dropped: bool,
+    /// Texture color mode the call drew with. Only set for textured calls, like the rest of the
+    /// texture fields
+    pub clut_size: Option<TextureColorMode>,
+    /// Texture page, in units of 64 pixels across and 256 down
+    pub tex_base_x: Option<u16>,
+    pub tex_base_y: Option<u16>,
+    /// Position of the CLUT in VRAM. Only set for 4 and 8 bit textures
+    pub clut_x: Option<u16>,
+    pub clut_y: Option<u16>,
+    /// Only captured for paletted textures, and only while deep capture is enabled
+    pub clut: Option<ClutSnapshot>,
+    /// Only captured while deep capture is enabled
+    pub vram_change: Option<VramChange>,
+}
+
+/// Copy of the palette a textured call used, taken when the call was made.
+/// Later uploads can overwrite the CLUT in VRAM before the log is looked at
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClutSnapshot {
+    /// Position of the CLUT in VRAM
+    pub x: u16,
+    pub y: u16,
+    /// 16 entries for 4 bit textures, 256 for 8 bit
+    pub entries: Vec<u16>,
+}
+
+/// VRAM around a call's points from just before and just after it ran, so the pixels it touched can
+/// be picked out. Covers the points both with and without the draw offset, plus `CHANGE_MARGIN`
+#[derive(Clone, Debug, PartialEq)]
+pub struct VramChange {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    /// Rows of `width` pixels
+    pub before: Vec<u16>,
+    pub after: Vec<u16>,
+}
+
+impl VramChange {
+    /// VRAM coordinates of every pixel the call changed
+    pub fn changed_pixels(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.before
+            .iter()
+            .zip(&self.after)
+            .enumerate()
+            .filter(|(_, (before, after))| before != after)
+            .map(|(i, _)| {
+                let width = self.width as usize;
+                (self.x + (i % width) as u16, self.y + (i / width) as u16)
+            })
+    }
+}
+
+/// Pixels around a call's points that are captured along with it, to catch off by one edges
+const CHANGE_MARGIN: i32 = 8;
+
+impl Gpu {
+    pub fn take_call_log(&mut self) -> Vec<DrawCall> {
+        mem::take(&mut self.draw_log)
+    }
+
+    pub fn set_call_logging(&mut self, enabled: bool) {
+        self.draw_logging_enabled = enabled;
+    }
+
+    pub fn clear_call_log(&mut self) {
+        self.draw_log.clear();
+    }
+
+    pub fn call_log(&self) -> &[DrawCall] {
+        &self.draw_log
+    }
+
+    /// Snapshot the CLUT of every paletted call in the draw log. Costs up to 512 bytes per call
+    pub fn set_deep_capture(&mut self, enabled: bool) {
+        self.deep_capture = enabled;
+    }
+
+    /// Adds a call to the draw log. With deep capture on, the VRAM the call is about to draw over is
+    /// saved with it, and the packet's end fills in what it looks like afterwards
+    pub(super) fn log_call(&mut self, mut call: DrawCall) {
+        if self.deep_capture {
+            if let Some(points) = &call.points {
+                self.renderer.sync_vram(&mut self.vram);
+                call.vram_change = Some(self.vram_change_area(points));
+            }
+        }
+        self.draw_log.push(call);
+    }
+
+    pub(super) fn vram_change_area(&self, points: &[Point]) -> VramChange {
+        let offset = &self.draw_offset;
+        let xs = points.iter().flat_map(|point| [point.x, point.x + offset.x]);
+        let ys = points.iter().flat_map(|point| [point.y, point.y + offset.y]);
+        let clamp_x = |x: i32| x.clamp(0, VRAM_WIDTH) as u16;
+        let clamp_y = |y: i32| y.clamp(0, VRAM_HEIGHT) as u16;
+
+        let x = clamp_x(xs.clone().min().unwrap() - CHANGE_MARGIN);
+        let y = clamp_y(ys.clone().min().unwrap() - CHANGE_MARGIN);
+        let width = clamp_x(xs.max().unwrap() + CHANGE_MARGIN) - x;
+        let height = clamp_y(ys.max().unwrap() + CHANGE_MARGIN) - y;
+        VramChange {
+            x,
+            y,
+            width,
+            height,
+            before: read_vram_rect(&self.vram, x, y, width, height),
+            after: vec![],
+        }
+    }
+
+    /// Passes a CLUT coordinate through for the log if the current texture mode uses a CLUT
+    pub(super) fn if_paletted(&self, value: u16) -> Option<u16> {
+        match self.texmode {
+            TextureColorMode::FifteenBit => None,
+            _ => Some(value),
+        }
+    }
+
+    pub(super) fn clut_snapshot(&mut self, clut_x: u32, clut_y: u32) -> Option<ClutSnapshot> {
+        if !self.deep_capture {
+            return None;
+        }
+        self.renderer.sync_vram(&mut self.vram);
+
+        let count = match self.texmode {
+            TextureColorMode::FourBit => 16,
+            TextureColorMode::EightBit => 256,
+            TextureColorMode::FifteenBit => return None,
+        };
+
+        let x = clut_x * 16;
+        let entries = (0..count)
+            .map(|i| self.vram.read(point_to_address(x + i, clut_y) as usize))
+            .collect();
+
+        Some(ClutSnapshot {
+            x: x as u16,
+            y: clut_y as u16,
+            entries,
+        })
+    }
+
+    /// With deep capture on, fills in what VRAM looks like after the calls logged since
+    /// `logged_calls`
+    pub(super) fn finish_deep_capture(&mut self, logged_calls: usize) {
+        if !self.deep_capture {
+            return;
+        }
+        self.renderer.sync_vram(&mut self.vram);
+        let vram = &self.vram;
+        for call in &mut self.draw_log[logged_calls..] {
+            if let Some(change) = &mut call.vram_change {
+                change.after = read_vram_rect(vram, change.x, change.y, change.width, change.height);
+            }
+        }
+    }
+}
@@ -0,0 +1,2534 @@
+use std::{
+    fmt::Display,
+    mem::{self, size_of_val},
+    sync::mpsc::Receiver,
+};
+
+use bit_field::BitField;
+use enum_display_derive::Display;
+use log::{error, trace, warn};
+use crate::{CpuCycles, R3000, Scheduler, cpu::InterruptSource};
+use crate::scheduler::{ScheduleTarget, CPU_CLOCK};
+use crate::ScheduleTarget::GpuHblank;
+
+mod drawlog;
+pub(crate) mod raster;
+mod renderer;
+mod threaded;
+mod transfer;
+
+pub use drawlog::{
+    ClutSnapshot, DrawCall, DrawOperation, Shading, Surface, Transparency, VramChange,
+};
+pub use renderer::{
+    DrawState, PolygonFill, RectFill, RendererBackend, SoftwareRenderer, TextureSource, Vram,
+};
+use threaded::ThreadedRenderer;
+use transfer::{transfer_height, transfer_width, VramTransfer};
+
+const CYCLES_PER_SCANLINE: u32 = 3413;
+const TOTAL_SCANLINES: u32 = 263;
+const VRAM_WIDTH: i32 = 1024;
+const VRAM_HEIGHT: i32 = 512;
+/// VRAM writes are tracked in tiles of this many pixels square
+const TILE_SIZE: u32 = 64;
+const TILES_X: u32 = VRAM_WIDTH as u32 / TILE_SIZE;
+const TILES_Y: u32 = VRAM_HEIGHT as u32 / TILE_SIZE;
+/// Triangles are dropped if two vertices are this far apart
+const MAX_POLYGON_WIDTH: i32 = 1024;
+const MAX_POLYGON_HEIGHT: i32 = 512;
+
+/// Polygon counts since the last call to `Gpu::take_stats`. Quads count as two triangles
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuStats {
+    pub triangles_drawn: u64,
+    /// Triangles the size limit threw away
+    pub triangles_dropped: u64,
+}
+
+#[derive(Copy, Clone, Debug, Display, PartialEq)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub enum TextureColorMode {
+    FourBit,
+    EightBit,
+    FifteenBit,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextureDraw {
+    Flat,
+    Shaded,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Resolution {
+    pub height: u32,
+    pub width: u32,
+}
+
+/// Video standard selected by GP1(08) bit 3
+#[derive(Clone, Copy, Debug, Display, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VideoMode {
+    Ntsc,
+    Pal,
+}
+
+impl VideoMode {
+    /// Exact refresh rate of a progressive frame, derived from the video clock and the line timings
+    pub fn refresh_rate(&self) -> f64 {
+        self.video_clock() / (self.lines_per_frame() as f64 * self.line_cycles() as f64)
+    }
+
+    /// GPU video clock in Hz
+    pub fn video_clock(&self) -> f64 {
+        match self {
+            VideoMode::Ntsc => 53_693_175.0,
+            VideoMode::Pal => 53_203_425.0,
+        }
+    }
+
+    /// Video clock cycles per scanline
+    pub fn line_cycles(&self) -> u32 {
+        match self {
+            VideoMode::Ntsc => 3413,
+            VideoMode::Pal => 3406,
+        }
+    }
+
+    /// Scanlines in a progressive frame
+    pub fn lines_per_frame(&self) -> u32 {
+        match self {
+            VideoMode::Ntsc => 263,
+            VideoMode::Pal => 314,
+        }
+    }
+
+    /// Scanlines before vblank starts. The rest of the frame is vblank
+    pub fn active_lines(&self) -> u32 {
+        match self {
+            VideoMode::Ntsc => 240,
+            VideoMode::Pal => 288,
+        }
+    }
+
+    /// CPU cycles taken by `lines` scanlines. Rounded once, so whole frames don't drift
+    pub(crate) fn lines_to_cpu_cycles(&self, lines: u32) -> CpuCycles {
+        let video_cycles = lines as f64 * self.line_cycles() as f64;
+        CpuCycles((video_cycles * CPU_CLOCK / self.video_clock()).round() as u32)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub struct Point {
+    pub x: i32,
+    pub y: i32,
+    pub color: u16,
+    pub tex_x: i16,
+    pub tex_y: i16,
+}
+
+#[derive(PartialEq)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+enum ColorDepth {
+    Full,    // 24 bit
+    Reduced, // 15 bit
+}
+
+impl Point {
+    fn from_word(word: u32, color: u16) -> Self {
+        let result = Self {
+            x: sign_extend((word & 0x7FF) as i32, 11),
+            y: sign_extend(((word >> 16) & 0x7FF) as i32, 11),
+            color,
+            tex_x: 0,
+            tex_y: 0,
+        };
+        result
+    }
+
+    fn from_word_with_offset(word: u32, color: u16, offset: &Point) -> Self {
+        Self {
+            x: sign_extend((word & 0x7FF) as i32, 11) + offset.x,
+            y: sign_extend(((word >> 16) & 0x7FF) as i32, 11) + offset.y,
+            color: color,
+            tex_x: 0,
+            tex_y: 0,
+        }
+    }
+
+    fn from_components(x: i32, y: i32, color: u16) -> Self {
+        Self {
+            x,
+            y,
+            color,
+            tex_x: 0,
+            tex_y: 0,
+        }
+    }
+
+    fn new_textured_point(word: u32, tex_y: i16, tex_x: i16) -> Self {
+        Self {
+            x: sign_extend((word & 0x7FF) as i32, 11),
+            y: sign_extend(((word >> 16) & 0x7FF) as i32, 11),
+            color: 0,
+            tex_x,
+            tex_y,
+        }
+    }
+
+    fn new_textured_point_with_color(word: u32, tex_y: i16, tex_x: i16, color: u16) -> Self {
+        Self {
+            x: sign_extend((word & 0x7FF) as i32, 11),
+            y: sign_extend(((word >> 16) & 0x7FF) as i32, 11),
+            color,
+            tex_x,
+            tex_y,
+        }
+    }
+}
+fn sign_extend(x: i32, nbits: u32) -> i32 {
+    let notherbits = size_of_val(&x) as u32 * 8 - nbits;
+    x.wrapping_shl(notherbits).wrapping_shr(notherbits)
+}
+
+#[allow(dead_code)]
+
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub struct Gpu {
+    vram: Vram,
+    #[cfg_attr(feature = "savestate", serde(skip, default = "default_renderer"))]
+    renderer: Box<dyn RendererBackend>,
+    /// Set while `renderer` runs the real backend on a worker thread. The worker sends the backend
+    /// back through here once it's stopped
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    threaded_backend: Option<Receiver<Box<dyn RendererBackend>>>,
+    status_reg: u32,
+    pixel_count: u32,
+    enabled: bool,
+    gp0_buffer: Vec<u32>,
+    color_depth: ColorDepth,
+
+    texpage_x_base: u16,
+    texpage_y_base: u16,
+    texmode: TextureColorMode,
+    palette_x: u16,
+    palette_y: u16,
+    blend_enabled: bool,
+    blend_color: u16,
+
+    draw_area_tl_point: Point,
+    draw_area_br_point: Point,
+    draw_offset: Point,
+
+    irq_fired: bool,
+    show_frame: bool,
+    frame_ready: bool,
+
+    display_h_res: u32,
+    display_v_res: u32,
+
+    ntsc_y1: u32,
+    ntsc_y2: u32,
+    cycle_counter: u32,
+
+    blend_mode: BlendMode,
+    check_mask: bool,
+
+    tex_mask_x: u32,
+    tex_mask_y: u32,
+    tex_offset_x: u32,
+    tex_offset_y: u32,
+
+    current_transfer: Option<VramTransfer>,
+
+    display_origin_x: usize,
+    display_origin_y: usize,
+
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    draw_logging_enabled: bool,
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    draw_log: Vec<DrawCall>,
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    deep_capture: bool,
+
+    force_b15: bool,
+    /// GP0(E1h) bit 10. While clear, interlaced drawing skips the lines of the field on screen
+    draw_to_display: bool,
+    interlace: bool,
+    /// The mode the game asked for with GP1(08h)
+    video_mode: VideoMode,
+    /// Timing the frontend pins the GPU to, whatever the game asks for
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    video_mode_override: Option<VideoMode>,
+    dot_clock_divider: u32,
+    dots_per_line: u32,
+    /// Lines since vblank ended. Counted by the hblank event
+    scanline_counter: u32,
+    /// Set and cleared by the vblank event
+    is_vblank: bool,
+    /// Which field an interlaced display is showing. Flips every vblank
+    odd_field: bool,
+    /// Drop oversized triangles like the hardware. Turning it off draws them anyway
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    strict_size_check: bool,
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    stats: GpuStats,
+}
+
+/// Stands in for the renderer of a GPU loaded from a save state until the running one is moved in
+#[cfg(feature = "savestate")]
+fn default_renderer() -> Box<dyn RendererBackend> {
+    Box::new(SoftwareRenderer)
+}
+
+impl Gpu {
+    pub fn new() -> Gpu {
+        Gpu {
+            vram: Vram::new(),
+            renderer: Box::new(SoftwareRenderer),
+            threaded_backend: None,
+            status_reg: 0x1C000000,
+            pixel_count: 0,
+            enabled: false,
+            gp0_buffer: Vec::new(),
+            color_depth: ColorDepth::Reduced,
+
+            texpage_x_base: 0,
+            texpage_y_base: 0,
+            texmode: TextureColorMode::FifteenBit,
+            palette_x: 0,
+            palette_y: 0,
+            blend_enabled: false,
+            blend_color: 0xFFFF,
+
+            draw_area_tl_point: Point::from_components(0, 0, 0),
+            draw_area_br_point: Point::from_components(0, 0, 0),
+
+            draw_offset: Point::from_components(0, 0, 0),
+            irq_fired: false,
+            show_frame: false,
+            frame_ready: false,
+
+            display_h_res: 640,
+            display_v_res: 480,
+
+            ntsc_y1: 16,
+            ntsc_y2: 256,
+            cycle_counter: 0,
+
+            blend_mode: BlendMode::BAF,
+            check_mask: false,
+
+            tex_mask_x: 0,
+            tex_mask_y: 0,
+            tex_offset_x: 0,
+            tex_offset_y: 0,
+
+            current_transfer: None,
+
+            display_origin_x: 0,
+            display_origin_y: 0,
+
+            draw_logging_enabled: true,
+            draw_log: vec![],
+            deep_capture: false,
+
+            force_b15: false,
+            draw_to_display: false,
+            interlace: false,
+            video_mode: VideoMode::Ntsc,
+            video_mode_override: None,
+            dot_clock_divider: 4,
+            dots_per_line: 490,
+            scanline_counter: 0,
+            is_vblank: false,
+            odd_field: false,
+            strict_size_check: true,
+            stats: GpuStats::default(),
+        }
+    }
+
+    /// Back to the power on state. The renderer, the draw log and the frontend's settings are kept
+    pub fn reset(&mut self) {
+        let old = mem::replace(self, Gpu::new());
+        self.keep_host_state(old);
+    }
+
+    /// Moves the renderer and the host's settings over from `old`, which this GPU replaces
+    pub(crate) fn keep_host_state(&mut self, old: Gpu) {
+        self.renderer = old.renderer;
+        self.threaded_backend = old.threaded_backend;
+        self.renderer.replace_vram(&self.vram);
+        self.draw_logging_enabled = old.draw_logging_enabled;
+        self.draw_log = old.draw_log;
+        self.deep_capture = old.deep_capture;
+        self.video_mode_override = old.video_mode_override;
+        self.strict_size_check = old.strict_size_check;
+    }
+
+    pub fn set_strict_size_check(&mut self, enabled: bool) {
+        self.strict_size_check = enabled;
+    }
+
+    pub fn take_stats(&mut self) -> GpuStats {
+        mem::take(&mut self.stats)
+    }
+
+    pub fn read_status_register(&mut self) -> u32 {
+        //trace!("Reading GPUSTAT");
+        let mut stat: u32 = 0;
+
+        stat |= (self.texpage_x_base) as u32;
+        stat |= (self.texpage_y_base << 4) as u32;
+
+        stat |= match self.texmode {
+            TextureColorMode::FourBit => 0,
+            TextureColorMode::EightBit => 1,
+            TextureColorMode::FifteenBit => 2,
+        } << 7;
+
+        stat |= 0x14000000;
+        stat.set_bit(27, self.current_transfer.is_some());
+
+        // The blank bits follow the beam position kept by the hblank and vblank events. Bit 31 is
+        // the line being drawn, which is the field when interlaced, and always even in vblank
+        let odd_line = if self.interlace {
+            self.odd_field
+        } else {
+            self.scanline_counter % 2 == 1
+        };
+        stat.set_bit(31, odd_line && !self.is_vblank);
+        stat.set_bit(13, !self.interlace || self.odd_field);
+
+        if !self.enabled {
+            stat.set_bit(23, true);
+        }
+
+        if self.color_depth == ColorDepth::Full {
+            stat.set_bit(21, true);
+        }
+
+        stat.set_bit(10, self.draw_to_display);
+        stat.set_bit(11, self.force_b15);
+        // Always the requested mode, so a game never sees its mode change underneath it
+        stat.set_bit(20, self.video_mode == VideoMode::Pal);
+
+        stat
+    }
+
+    pub fn send_gp0_command(&mut self, value: u32) {
+        self.gp0_push(value);
+
+        match self.gp0_command_length() {
+            Some(length) if self.gp0_buffer.len() >= length => (),
+            // Not enough words for the command. Return early
+            _ => return,
+        }
+
+        let logged_calls = self.draw_log.len();
+        self.execute_gp0_packet();
+        self.finish_deep_capture(logged_calls);
+    }
+
+    fn execute_gp0_packet(&mut self) {
+        let command = self.gp0_buffer[0];
+
+        match command.gp0_header() {
+            0x0 => {
+                //Random junk
+                match command >> 24 {
+                    0x2 => {
+                        //Quick rectangle fill
+                        trace!(target: "psx::gpu", "Quick rect");
+
+                        let p1 = Point::from_components(
+                            (self.gp0_buffer[1] & 0x3F0) as i32,
+                            ((self.gp0_buffer[1] >> 16) & 0x1FF) as i32,
+                            0,
+                        );
+                        let mut p2 = Point::from_components(
+                            (((self.gp0_buffer[2] & 0x3FF) + 0xF) & !(0xF)) as i32,
+                            ((self.gp0_buffer[2] >> 16) & 0x1FF) as i32,
+                            0,
+                        );
+
+                        p2.x += p1.x;
+                        p2.y += p1.y;
+
+                        // println!("quick fill p1 {:?}  p2 {:?}", p1, p2);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::QuickFill,
+                                shading: None,
+                                surface: None,
+                                transparency: None,
+                                points: Some(vec![p1.clone(), p2.clone()]),
+                                blending_enabled: false,
+                                call_dropped: false,
+                                clut_size: None,
+                                tex_base_x: None,
+                                tex_base_y: None,
+                                clut_x: None,
+                                clut_y: None,
+                                clut: None,
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+                        
+                        let state = self.draw_state();
+                        self.renderer.fill(
+                            &mut self.vram,
+                            &state,
+                            &p1,
+                            &p2,
+                            b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF),
+                        );
+                    }
+                    _ => {
+                        //NOP
+                    }
+                }
+            }
+
+            0x1 => {
+                //Render Polygon
+
+                // If the polygon is textured or gouraud shaded, lets just lock up the emulator.
+                // I only want to test flat shaded polygons right now
+
+                let is_gouraud = command.get_bit(28);
+                let is_textured = command.get_bit(26);
+                let is_quad = command.get_bit(27);
+
+                let fill = b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF);
+                // TODO: Actually use this blend_enabled variable. It also doesn't need to be part of gpu state
+                self.blend_enabled = self.gp0_buffer[0].get_bit(24);
+                // TODO: This is also wrong. There is no such thing as a gpu wide blend color
+                self.blend_color = fill;
+                if is_quad {
+                    if is_textured && is_gouraud {
+                        trace!(target: "psx::gpu", "Drawing texture blended quad!");
+
+                        let points: Vec<Point> = vec![
+                            Point::new_textured_point_with_color(
+                                self.gp0_buffer[1],
+                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[2] & 0xFF) as i16,
+                                fill,
+                            ),
+                            Point::new_textured_point_with_color(
+                                self.gp0_buffer[4],
+                                ((self.gp0_buffer[5] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[5] & 0xFF) as i16,
+                                b24color_to_b15color(self.gp0_buffer[3] & 0x1FFFFFF),
+                            ),
+                            Point::new_textured_point_with_color(
+                                self.gp0_buffer[7],
+                                ((self.gp0_buffer[8] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[8] & 0xFF) as i16,
+                                b24color_to_b15color(self.gp0_buffer[6] & 0x1FFFFFF),
+                            ),
+                            Point::new_textured_point_with_color(
+                                self.gp0_buffer[10],
+                                ((self.gp0_buffer[11] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[11] & 0xFF) as i16,
+                                b24color_to_b15color(self.gp0_buffer[9] & 0x1FFFFFF),
+                            ),
+                        ];
+
+                        trace!(target: "psx::gpu", "points {:?}", points);
+
+                        let clut_x = (self.gp0_buffer[2] >> 16) & 0x3F;
+                        let clut_y = (self.gp0_buffer[2] >> 22) & 0x1FF;
+                        let page_x = (self.gp0_buffer[5] >> 16) & 0xF;
+                        let page_y = (self.gp0_buffer[5] >> 20) & 0x1;
+
+                        self.update_draw_settings(self.gp0_buffer[5] >> 16);
+
+                        self.blend_color = fill;
+
+                        let should_drop = self.polygon_dropped(&points);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::Quad,
+                                shading: Some(Shading::Gouraud),
+                                surface: Some(Surface::Textured),
+                                transparency: Some(if command.get_bit(25) {
+                                    Transparency::SemiTransparent
+                                } else {
+                                    Transparency::Solid
+                                }),
+                                points: Some(self.offset_points(&points)),
+                                blending_enabled: self.blend_enabled,
+                                call_dropped: should_drop,
+                                clut_size: Some(self.texmode),
+                                tex_base_x: Some(page_x as u16),
+                                tex_base_y: Some(page_y as u16),
+                                clut_x: self.if_paletted((clut_x * 16) as u16),
+                                clut_y: self.if_paletted(clut_y as u16),
+                                clut: self.clut_snapshot(clut_x, clut_y),
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+
+                        self.draw_polygon(
+                            &points,
+                            PolygonFill::Textured(
+                                TextureSource { page_x, page_y, clut_x, clut_y },
+                                TextureDraw::Shaded,
+                            ),
+                            command.get_bit(25),
+                        );
+                    } else if is_textured {
+                        trace!(target: "psx::gpu", "GPU: Tex quad");
+                        let points: Vec<Point> = vec![
+                            Point::new_textured_point(
+                                self.gp0_buffer[1],
+                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[2] & 0xFF) as i16,
+                            ),
+                            Point::new_textured_point(
+                                self.gp0_buffer[3],
+                                ((self.gp0_buffer[4] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[4] & 0xFF) as i16,
+                            ),
+                            Point::new_textured_point(
+                                self.gp0_buffer[5],
+                                ((self.gp0_buffer[6] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[6] & 0xFF) as i16,
+                            ),
+                            Point::new_textured_point(
+                                self.gp0_buffer[7],
+                                ((self.gp0_buffer[8] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[8] & 0xFF) as i16,
+                            ),
+                        ];
+
+                        trace!(target: "psx::gpu", "points {:?}", points);
+
+                        let clut_x = (self.gp0_buffer[2] >> 16) & 0x3F;
+                        let clut_y = (self.gp0_buffer[2] >> 22) & 0x1FF;
+                        let page_x = (self.gp0_buffer[4] >> 16) & 0xF;
+                        let page_y = (self.gp0_buffer[4] >> 20) & 0x1;
+
+                        self.update_draw_settings(self.gp0_buffer[4] >> 16);
+
+                        self.blend_color = fill;
+
+                        let should_drop = self.polygon_dropped(&points);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::Quad,
+                                shading: Some(Shading::Flat),
+                                surface: Some(Surface::Textured),
+                                transparency: Some(if command.get_bit(25) {
+                                    Transparency::SemiTransparent
+                                } else {
+                                    Transparency::Solid
+                                }),
+                                points: Some(self.offset_points(&points)),
+                                blending_enabled: self.blend_enabled,
+                                call_dropped: should_drop,
+                                clut_size: Some(self.texmode),
+                                tex_base_x: Some(page_x as u16),
+                                tex_base_y: Some(page_y as u16),
+                                clut_x: self.if_paletted((clut_x * 16) as u16),
+                                clut_y: self.if_paletted(clut_y as u16),
+                                clut: self.clut_snapshot(clut_x, clut_y),
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+
+                        self.draw_polygon(
+                            &points,
+                            PolygonFill::Textured(
+                                TextureSource { page_x, page_y, clut_x, clut_y },
+                                TextureDraw::Flat,
+                            ),
+                            command.get_bit(25),
+                        );
+                    } else if is_gouraud {
+                        trace!(target: "psx::gpu", "GPU: gouraud quad");
+                        let points: Vec<Point> = vec![
+                            Point::from_word(self.gp0_buffer[1], fill),
+                            Point::from_word(
+                                self.gp0_buffer[3],
+                                b24color_to_b15color(self.gp0_buffer[2]),
+                            ),
+                            Point::from_word(
+                                self.gp0_buffer[5],
+                                b24color_to_b15color(self.gp0_buffer[4]),
+                            ),
+                            Point::from_word(
+                                self.gp0_buffer[7],
+                                b24color_to_b15color(self.gp0_buffer[6]),
+                            ),
+                        ];
+
+                        let should_drop = self.polygon_dropped(&points);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::Quad,
+                                shading: Some(Shading::Gouraud),
+                                surface: Some(Surface::Flat),
+                                transparency: Some(if command.get_bit(25) {
+                                    Transparency::SemiTransparent
+                                } else {
+                                    Transparency::Solid
+                                }),
+                                points: Some(self.offset_points(&points)),
+                                blending_enabled: self.blend_enabled,
+                                call_dropped: should_drop,
+                                clut_size: None,
+                                tex_base_x: None,
+                                tex_base_y: None,
+                                clut_x: None,
+                                clut_y: None,
+                                clut: None,
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+
+                        self.draw_polygon(&points, PolygonFill::Shaded, command.get_bit(25));
+                    } else {
+                        trace!(target: "psx::gpu", "GPU: Solid quad");
+                        let points: Vec<Point> = vec![
+                            Point::from_word(self.gp0_buffer[1], 0),
+                            Point::from_word(self.gp0_buffer[2], 0),
+                            Point::from_word(self.gp0_buffer[3], 0),
+                            Point::from_word(self.gp0_buffer[4], 0),
+                        ];
+                        let should_drop = self.polygon_dropped(&points);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::Quad,
+                                shading: Some(Shading::Flat),
+                                surface: Some(Surface::Flat),
+                                transparency: Some(if command.get_bit(25) {
+                                    Transparency::SemiTransparent
+                                } else {
+                                    Transparency::Solid
+                                }),
+                                points: Some(self.offset_points(&points)),
+                                blending_enabled: self.blend_enabled,
+                                call_dropped: should_drop,
+                                clut_size: None,
+                                tex_base_x: None,
+                                tex_base_y: None,
+                                clut_x: None,
+                                clut_y: None,
+                                clut: None,
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+
+                        self.draw_polygon(&points, PolygonFill::Solid(fill), command.get_bit(25));
+
+                        //let center = center_of_points(&points);
+
+                        // points.sort_unstable_by(|a, b| sort_clockwise_big_match(*a, *b, center));
+                    };
+                } else {
+                    if is_gouraud && is_textured {
+                        trace!(
+                            target: "psx::gpu",
+                            "Tried to try draw texture blended tri! Queue {:?}",
+                            self.gp0_buffer
+                        );
+
+                        let points: Vec<Point> = vec![
+                            Point::new_textured_point_with_color(
+                                self.gp0_buffer[1],
+                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[2] & 0xFF) as i16,
+                                fill,
+                            ),
+                            Point::new_textured_point_with_color(
+                                self.gp0_buffer[4],
+                                ((self.gp0_buffer[5] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[5] & 0xFF) as i16,
+                                b24color_to_b15color(self.gp0_buffer[3] & 0x1FFFFFF),
+                            ),
+                            Point::new_textured_point_with_color(
+                                self.gp0_buffer[7],
+                                ((self.gp0_buffer[8] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[8] & 0xFF) as i16,
+                                b24color_to_b15color(self.gp0_buffer[6] & 0x1FFFFFF),
+                            ),
+                        ];
+
+                        trace!(target: "psx::gpu", "points {:?}", points);
+
+                        let clut_x = (self.gp0_buffer[2] >> 16) & 0x3F;
+                        let clut_y = (self.gp0_buffer[2] >> 22) & 0x1FF;
+                        let page_x = (self.gp0_buffer[5] >> 16) & 0xF;
+                        let page_y = (self.gp0_buffer[5] >> 20) & 0x1;
+
+                        self.update_draw_settings(self.gp0_buffer[5] >> 16);
+
+                        self.blend_color = fill;
+
+                        let should_drop = self.polygon_dropped(&points);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::Triangle,
+                                shading: Some(Shading::Gouraud),
+                                surface: Some(Surface::Textured),
+                                transparency: Some(if command.get_bit(25) {
+                                    Transparency::SemiTransparent
+                                } else {
+                                    Transparency::Solid
+                                }),
+                                points: Some(self.offset_points(&points)),
+                                blending_enabled: self.blend_enabled,
+                                call_dropped: should_drop,
+                                clut_size: Some(self.texmode),
+                                tex_base_x: Some(page_x as u16),
+                                tex_base_y: Some(page_y as u16),
+                                clut_x: self.if_paletted((clut_x * 16) as u16),
+                                clut_y: self.if_paletted(clut_y as u16),
+                                clut: self.clut_snapshot(clut_x, clut_y),
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+
+                        self.draw_polygon(
+                            &points,
+                            PolygonFill::Textured(
+                                TextureSource { page_x, page_y, clut_x, clut_y },
+                                TextureDraw::Shaded,
+                            ),
+                            command.get_bit(25),
+                        );
+                    } else if is_textured {
+                        trace!(target: "psx::gpu", "GPU: Tex tri");
+                        let points: Vec<Point> = vec![
+                            Point::new_textured_point(
+                                self.gp0_buffer[1],
+                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[2] & 0xFF) as i16,
+                            ),
+                            Point::new_textured_point(
+                                self.gp0_buffer[3],
+                                ((self.gp0_buffer[4] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[4] & 0xFF) as i16,
+                            ),
+                            Point::new_textured_point(
+                                self.gp0_buffer[5],
+                                ((self.gp0_buffer[6] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[6] & 0xFF) as i16,
+                            ),
+                        ];
+
+                        let clut_x = (self.gp0_buffer[2] >> 16) & 0x3F;
+                        let clut_y = (self.gp0_buffer[2] >> 22) & 0x1FF;
+                        let page_x = (self.gp0_buffer[4] >> 16) & 0xF;
+                        let page_y = (self.gp0_buffer[4] >> 20) & 0x1;
+
+                        self.update_draw_settings(self.gp0_buffer[4] >> 16);
+
+                        self.blend_color = fill;
+
+                        let should_drop = self.polygon_dropped(&points);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::Triangle,
+                                shading: Some(Shading::Flat),
+                                surface: Some(Surface::Textured),
+                                transparency: Some(if command.get_bit(25) {
+                                    Transparency::SemiTransparent
+                                } else {
+                                    Transparency::Solid
+                                }),
+                                points: Some(self.offset_points(&points)),
+                                blending_enabled: self.blend_enabled,
+                                call_dropped: should_drop,
+                                clut_size: Some(self.texmode),
+                                tex_base_x: Some(page_x as u16),
+                                tex_base_y: Some(page_y as u16),
+                                clut_x: self.if_paletted((clut_x * 16) as u16),
+                                clut_y: self.if_paletted(clut_y as u16),
+                                clut: self.clut_snapshot(clut_x, clut_y),
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+
+                        self.draw_polygon(
+                            &points,
+                            PolygonFill::Textured(
+                                TextureSource { page_x, page_y, clut_x, clut_y },
+                                TextureDraw::Flat,
+                            ),
+                            command.get_bit(25),
+                        );
+                    } else if is_gouraud {
+                        trace!(target: "psx::gpu", "GPU: gouraud tri");
+                        let points: Vec<Point> = vec![
+                            Point::from_word(self.gp0_buffer[1], fill),
+                            Point::from_word(
+                                self.gp0_buffer[3],
+                                b24color_to_b15color(self.gp0_buffer[2]),
+                            ),
+                            Point::from_word(
+                                self.gp0_buffer[5],
+                                b24color_to_b15color(self.gp0_buffer[4]),
+                            ),
+                        ];
+
+                        let should_drop = self.polygon_dropped(&points);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::Triangle,
+                                shading: Some(Shading::Gouraud),
+                                surface: Some(Surface::Flat),
+                                transparency: Some(if command.get_bit(25) {
+                                    Transparency::SemiTransparent
+                                } else {
+                                    Transparency::Solid
+                                }),
+                                points: Some(self.offset_points(&points)),
+                                blending_enabled: self.blend_enabled,
+                                call_dropped: should_drop,
+                                clut_size: None,
+                                tex_base_x: None,
+                                tex_base_y: None,
+                                clut_x: None,
+                                clut_y: None,
+                                clut: None,
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+
+                        self.draw_polygon(&points, PolygonFill::Shaded, command.get_bit(25));
+
+                        ////trace!("{:?}", points);
+                    } else {
+                        trace!(target: "psx::gpu", "GPU: Solid tri");
+                        let points: Vec<Point> = vec![
+                            Point::from_word(self.gp0_buffer[1], 0),
+                            Point::from_word(self.gp0_buffer[3], 0),
+                            Point::from_word(self.gp0_buffer[2], 0),
+                        ];
+
+                        let should_drop = self.polygon_dropped(&points);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::Triangle,
+                                shading: Some(Shading::Flat),
+                                surface: Some(Surface::Flat),
+                                transparency: Some(if command.get_bit(25) {
+                                    Transparency::SemiTransparent
+                                } else {
+                                    Transparency::Solid
+                                }),
+                                points: Some(self.offset_points(&points)),
+                                blending_enabled: self.blend_enabled,
+                                call_dropped: should_drop,
+                                clut_size: None,
+                                tex_base_x: None,
+                                tex_base_y: None,
+                                clut_x: None,
+                                clut_y: None,
+                                clut: None,
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+
+                        self.draw_polygon(&points, PolygonFill::Solid(fill), command.get_bit(25));
+                    }
+                }
+            }
+
+            0x2 => {
+                //Render line
+                let is_polyline = command.get_bit(27);
+                let is_gouraud = command.get_bit(28);
+                let transparent = command.get_bit(25);
+                trace!(
+                    target: "psx::gpu",
+                    "GPU: {}",
+                    if is_polyline { "Polyline" } else { "Line" }
+                );
+
+                // A polyline's terminator is always the last word
+                let words = if is_polyline {
+                    &self.gp0_buffer[1..self.gp0_buffer.len() - 1]
+                } else {
+                    &self.gp0_buffer[1..]
+                };
+                let fill = b24color_to_b15color(command & 0xFFFFFF);
+                let mut points = vec![Point::from_word(words[0], fill)];
+                if is_gouraud {
+                    points.extend(words[1..].chunks_exact(2).map(|pair| {
+                        Point::from_word(pair[1], b24color_to_b15color(pair[0]))
+                    }));
+                } else {
+                    points.extend(words[1..].iter().map(|word| Point::from_word(*word, fill)));
+                }
+
+                if self.draw_logging_enabled {
+                    let call = DrawCall {
+                        operation: if is_polyline {
+                            DrawOperation::PolyLine
+                        } else {
+                            DrawOperation::Line
+                        },
+                        shading: Some(if is_gouraud { Shading::Gouraud } else { Shading::Flat }),
+                        surface: Some(Surface::Flat),
+                        transparency: Some(if transparent {
+                            Transparency::SemiTransparent
+                        } else {
+                            Transparency::Solid
+                        }),
+                        points: Some(self.offset_points(&points)),
+                        blending_enabled: self.blend_enabled,
+                        call_dropped: points.windows(2).any(|segment| line_too_big(segment[0], segment[1])),
+                        clut_size: None,
+                        tex_base_x: None,
+                        tex_base_y: None,
+                        clut_x: None,
+                        clut_y: None,
+                        clut: None,
+                        vram_change: None,
+                    };
+                    self.log_call(call);
+                }
+
+                let points = self.offset_points(&points);
+                for segment in points.windows(2) {
+                    self.draw_line(&segment[0], &segment[1], transparent);
+                }
+            }
+
+            0x3 => {
+                //Render Rectangle
+
+                let size = (command >> 27) & 0x3;
+
+                match size {
+                    0b01 => {
+                        trace!(target: "psx::gpu", "GPU: Single point");
+                        //Draw single pixel
+                        let point = Point::from_word(self.gp0_buffer[1], 0);
+
+                        if self.draw_logging_enabled {
+                            let call = DrawCall {
+                                operation: DrawOperation::Pixel,
+                                shading: None,
+                                surface: None,
+                                transparency: None,
+                                points: Some(vec![point.clone()]),
+                                blending_enabled: false,
+                                call_dropped: false,
+                                clut_size: None,
+                                tex_base_x: None,
+                                tex_base_y: None,
+                                clut_x: None,
+                                clut_y: None,
+                                clut: None,
+                                vram_change: None,
+                            };
+                            self.log_call(call);
+                        }
+
+                        let fill = b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF);
+                        self.draw_rect(&point, 1, 1, RectFill::Solid(fill), false);
+                    }
+
+                    0b0 => {
+                        //Draw variable size
+                        if command.get_bit(26) {
+                            trace!(target: "psx::gpu", "GPU: Tex box");
+                            let mut tl_point = Point::new_textured_point(
+                                self.gp0_buffer[1],
+                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[2] & 0xFF) as i16,
+                            );
+
+                            let size = Point::from_word(self.gp0_buffer[3], 0);
+
+                            tl_point.x += self.draw_offset.x;
+                            tl_point.y += self.draw_offset.y;
+
+                            self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
+                            self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
+
+                            if self.draw_logging_enabled {
+                                // Calculate coordinates of bottom right point
+                                let mut br_point = tl_point.clone();
+                                br_point.x += size.x;
+                                br_point.y += size.y;
+
+                                let call = DrawCall {
+                                    operation: DrawOperation::RectangleDynamic,
+                                    shading: None,
+                                    surface: Some(Surface::Textured),
+                                    transparency: Some(if command.get_bit(25) {
+                                        Transparency::SemiTransparent
+                                    } else {
+                                        Transparency::Solid
+                                    }),
+                                    points: Some(vec![tl_point.clone(), br_point]),
+                                    blending_enabled: false,
+                                    call_dropped: false,
+                                    clut_size: Some(self.texmode),
+                                    tex_base_x: Some(self.texpage_x_base),
+                                    tex_base_y: Some(self.texpage_y_base),
+                                    clut_x: self.if_paletted(self.palette_x * 16),
+                                    clut_y: self.if_paletted(self.palette_y),
+                                    clut: self.clut_snapshot(self.palette_x as u32, self.palette_y as u32),
+                                    vram_change: None,
+                                };
+                                self.log_call(call);
+                            }
+
+                            self.draw_rect(
+                                &tl_point,
+                                size.x,
+                                size.y,
+                                RectFill::Textured(self.current_texture()),
+                                command.get_bit(25),
+                            );
+                        } else {
+                            trace!(target: "psx::gpu", "GPU: solid box");
+                            let tl_point = Point::from_word(self.gp0_buffer[1], 0);
+                            let br_point =
+                                Point::from_word_with_offset(self.gp0_buffer[2], 0, &tl_point);
+
+                            trace!(target: "psx::gpu", "tl: {:?} br: {:?}", tl_point, br_point);
+
+                            if self.draw_logging_enabled {
+                                let call = DrawCall {
+                                    operation: DrawOperation::RectangleDynamic,
+                                    shading: None,
+                                    surface: Some(Surface::Flat),
+                                    transparency: Some(if command.get_bit(25) {
+                                        Transparency::SemiTransparent
+                                    } else {
+                                        Transparency::Solid
+                                    }),
+                                    points: Some(vec![tl_point.clone(), br_point.clone()]),
+                                    blending_enabled: false,
+                                    call_dropped: false,
+                                    clut_size: None,
+                                    tex_base_x: None,
+                                    tex_base_y: None,
+                                    clut_x: None,
+                                    clut_y: None,
+                                    clut: None,
+                                    vram_change: None,
+                                };
+                                self.log_call(call);
+                            }
+
+                            self.draw_rect(
+                                &Point::from_components(
+                                    tl_point.x + self.draw_offset.x,
+                                    tl_point.y + self.draw_offset.y,
+                                    0,
+                                ),
+                                br_point.x - tl_point.x,
+                                br_point.y - tl_point.y,
+                                RectFill::Solid(b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF)),
+                                command.get_bit(25),
+                            );
+                        }
+                    }
+
+                    0b10 => {
+                        //8x8 sprite
+                        trace!(target: "psx::gpu", "GPU: 8x8 sprite");
+                        if command.get_bit(26) {
+                            let mut tl_point = Point::new_textured_point(
+                                self.gp0_buffer[1],
+                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[2] & 0xFF) as i16,
+                            );
+
+                            let size = Point::from_components(8, 8, 0);
+
+                            self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
+                            self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
+
+                            tl_point.x += self.draw_offset.x;
+                            tl_point.y += self.draw_offset.y;
+
+                            if self.draw_logging_enabled {
+                                // Calculate coordinates of bottom right point
+                                let mut br_point = tl_point.clone();
+                                br_point.x += size.x;
+                                br_point.y += size.y;
+
+                                let call = DrawCall {
+                                    operation: DrawOperation::Rectangle8,
+                                    shading: None,
+                                    surface: Some(Surface::Textured),
+                                    transparency: Some(if command.get_bit(25) {
+                                        Transparency::SemiTransparent
+                                    } else {
+                                        Transparency::Solid
+                                    }),
+                                    points: Some(vec![tl_point.clone(), br_point]),
+                                    blending_enabled: false,
+                                    call_dropped: false,
+                                    clut_size: Some(self.texmode),
+                                    tex_base_x: Some(self.texpage_x_base),
+                                    tex_base_y: Some(self.texpage_y_base),
+                                    clut_x: self.if_paletted(self.palette_x * 16),
+                                    clut_y: self.if_paletted(self.palette_y),
+                                    clut: self.clut_snapshot(self.palette_x as u32, self.palette_y as u32),
+                                    vram_change: None,
+                                };
+                                self.log_call(call);
+                            }
+
+                            self.draw_rect(
+                                &tl_point,
+                                size.x,
+                                size.y,
+                                RectFill::Textured(self.current_texture()),
+                                command.get_bit(25),
+                            );
+                        } else {
+                            let tl_point = Point::from_word(self.gp0_buffer[1], 0);
+                            let x1 = tl_point.x + self.draw_offset.x;
+                            let y1 = tl_point.y + self.draw_offset.y;
+
+                            if self.draw_logging_enabled {
+                                // Calculate coordinates of bottom right point
+                                let mut br_point = tl_point.clone();
+                                br_point.x += 8;
+                                br_point.y += 8;
+
+                                let call = DrawCall {
+                                    operation: DrawOperation::Rectangle8,
+                                    shading: None,
+                                    surface: Some(Surface::Flat),
+                                    transparency: Some(if command.get_bit(25) {
+                                        Transparency::SemiTransparent
+                                    } else {
+                                        Transparency::Solid
+                                    }),
+                                    points: Some(vec![tl_point.clone(), br_point]),
+                                    blending_enabled: false,
+                                    call_dropped: false,
+                                    clut_size: None,
+                                    tex_base_x: None,
+                                    tex_base_y: None,
+                                    clut_x: None,
+                                    clut_y: None,
+                                    clut: None,
+                                    vram_change: None,
+                                };
+                                self.log_call(call);
+                            }
+
+                            self.draw_rect(
+                                &Point::from_components(x1, y1, 0),
+                                8,
+                                8,
+                                RectFill::Solid(b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF)),
+                                command.get_bit(25),
+                            );
+                        }
+                    }
+
+                    0b11 => {
+                        //16x16 sprite
+                        trace!(target: "psx::gpu", "GPU: 16x16 sprite");
+                        if command.get_bit(26) {
+                            let mut tl_point = Point::new_textured_point(
+                                self.gp0_buffer[1],
+                                ((self.gp0_buffer[2] >> 8) & 0xFF) as i16,
+                                (self.gp0_buffer[2] & 0xFF) as i16,
+                            );
+
+                            let size = Point::from_components(16, 16, 0);
+
+                            self.palette_x = ((self.gp0_buffer[2] >> 16) & 0x3F) as u16;
+                            self.palette_y = ((self.gp0_buffer[2] >> 22) & 0x1FF) as u16;
+
+                            tl_point.x += self.draw_offset.x;
+                            tl_point.y += self.draw_offset.y;
+
+                            if self.draw_logging_enabled {
+                                // Calculate coordinates of bottom right point
+                                let mut br_point = tl_point.clone();
+                                br_point.x += size.x;
+                                br_point.y += size.y;
+
+                                let call = DrawCall {
+                                    operation: DrawOperation::Rectangle16,
+                                    shading: None,
+                                    surface: Some(Surface::Textured),
+                                    transparency: Some(if command.get_bit(25) {
+                                        Transparency::SemiTransparent
+                                    } else {
+                                        Transparency::Solid
+                                    }),
+                                    points: Some(vec![tl_point.clone(), br_point]),
+                                    blending_enabled: false,
+                                    call_dropped: false,
+                                    clut_size: Some(self.texmode),
+                                    tex_base_x: Some(self.texpage_x_base),
+                                    tex_base_y: Some(self.texpage_y_base),
+                                    clut_x: self.if_paletted(self.palette_x * 16),
+                                    clut_y: self.if_paletted(self.palette_y),
+                                    clut: self.clut_snapshot(self.palette_x as u32, self.palette_y as u32),
+                                    vram_change: None,
+                                };
+                                self.log_call(call);
+                            }
+
+                            self.draw_rect(
+                                &tl_point,
+                                size.x,
+                                size.y,
+                                RectFill::Textured(self.current_texture()),
+                                command.get_bit(25),
+                            );
+                        } else {
+                            let tl_point = Point::from_word(self.gp0_buffer[1], 0);
+                            let x1 = tl_point.x + self.draw_offset.x;
+                            let y1 = tl_point.y + self.draw_offset.y;
+
+                            if self.draw_logging_enabled {
+                                // Calculate coordinates of bottom right point
+                                let mut br_point = tl_point.clone();
+                                br_point.x += 16;
+                                br_point.y += 16;
+
+                                let call = DrawCall {
+                                    operation: DrawOperation::Rectangle16,
+                                    shading: None,
+                                    surface: Some(Surface::Flat),
+                                    transparency: Some(if command.get_bit(25) {
+                                        Transparency::SemiTransparent
+                                    } else {
+                                        Transparency::Solid
+                                    }),
+                                    points: Some(vec![tl_point.clone(), br_point]),
+                                    blending_enabled: false,
+                                    call_dropped: false,
+                                    clut_size: None,
+                                    tex_base_x: None,
+                                    tex_base_y: None,
+                                    clut_x: None,
+                                    clut_y: None,
+                                    clut: None,
+                                    vram_change: None,
+                                };
+                                self.log_call(call);
+                            }
+
+                            self.draw_rect(
+                                &Point::from_components(x1, y1, 0),
+                                16,
+                                16,
+                                RectFill::Solid(b24color_to_b15color(self.gp0_buffer[0] & 0x1FFFFFF)),
+                                command.get_bit(25),
+                            );
+                        }
+                    }
+
+                    _ => {
+                        //Lets do nothing with the others
+                        trace!(target: "psx::gpu", "GPU: Invalid size rect");
+                    }
+                }
+            }
+
+            0x4 => self.copy_vram_to_vram(),
+            0x5 => self.copy_cpu_to_vram(),
+            0x6 => self.start_vram_to_cpu(),
+            0x7 => {
+                //Env commands
+                match command.command() {
+                    0xE1 => {
+                        //Draw Mode Setting
+                        self.update_draw_settings(command);
+                    }
+
+                    0xE2 => {
+                        // Texture window settings
+                        self.tex_mask_x = command.get_bits(0..=4) * 8;
+                        self.tex_mask_y = command.get_bits(5..=9) * 8;
+                        self.tex_offset_x = command.get_bits(10..=14) * 8;
+                        self.tex_offset_y = command.get_bits(15..=19) * 8;
+                    }
+
+                    0xE3 => {
+                        //Set Drawing Area Top Left
+                        self.draw_area_tl_point = Point::from_components(
+                            ((command & 0x3FF) as u16) as i32,
+                            (((command >> 10) & 0x3FF) as u16) as i32,
+                            0,
+                        );
+                    }
+
+                    0xE4 => {
+                        //Set Drawing Area Bottom Right
+                        self.draw_area_br_point = Point::from_components(
+                            ((command & 0x3FF) as u16) as i32,
+                            (((command >> 10) & 0x3FF) as u16) as i32,
+                            0,
+                        );
+                    }
+
+                    0xE5 => {
+                        //Set Drawing Offset
+                        let x = sign_extend((command & 0x7FF) as i32, 11);
+                        let y = sign_extend(((command >> 11) & 0x7FF) as i32, 11);
+                        //println!("Set drawing offset ({}, {})", x, y);
+                        self.draw_offset = Point::from_components(x, y, 0);
+                    }
+
+                    0xE6 => {
+                        self.force_b15 = command.get_bit(0);
+                        self.check_mask = command.get_bit(1);
+                    }
+
+                    _ => error!(
+                        target: "psx::gpu",
+                        "Unknown GPU ENV command {:#X}. Full command queue is {:#X}",
+                        command.command(),
+                        self.gp0_buffer[0]
+                    ),
+                }
+            }
+
+            0x1F => {
+                panic!("GPU IRQ requested!");
+            }
+
+            _ => error!(target: "psx::gpu", "unknown gp0 {:#X}!", command.gp0_header()),
+        }
+        trace!(target: "psx::gpu", "Command was {:#X}", command);
+        //Made it to the end, so the command must have been executed
+        self.gp0_clear();
+    }
+
+    fn update_draw_settings(&mut self, command: u32) {
+        self.texpage_x_base = (command & 0xF) as u16;
+        self.texpage_y_base = if command.get_bit(4) { 1 } else { 0 };
+        self.texmode = match (command >> 7) & 0x3 {
+            0 => TextureColorMode::FourBit,
+            1 => TextureColorMode::EightBit,
+            2 => TextureColorMode::FifteenBit,
+            3 => TextureColorMode::FifteenBit, // This one is FifteenBit, for some reason
+            _ => panic!("Unknown texture color mode {}", (command >> 7) & 0x3),
+        };
+        self.blend_mode = match command.get_bits(5..=6) {
+            0 => BlendMode::B2F2,
+            1 => BlendMode::BAF,
+            2 => BlendMode::BSF,
+            3 => BlendMode::BF4,
+            mode => panic!("Unknown blend mode! {}", mode),
+        };
+        self.draw_to_display = command.get_bit(10);
+    }
+
+    pub fn send_gp1_command(&mut self, command: u32) {
+        //trace!("GP1 Command {:#X} parameter {:#X}", command.command(), command.parameter());
+        match command.command() {
+            0x0 => {
+                //Reset GPU
+                self.enabled = false;
+                self.status_reg = 0;
+                self.pixel_count = 0;
+                self.vram.clear();
+                self.renderer.replace_vram(&self.vram);
+            }
+
+            0x1 => {
+                //Reset Command buffer
+                self.gp0_buffer.clear();
+            }
+
+            // 0x2 => {
+            //     self.show_frame = true;
+            // }
+            0x4 => {
+                // gpu dma direction. I don't think this is needed
+            }
+
+            0x5 => {
+                let x = command.get_bits(0..=9);
+                let y = command.get_bits(10..=18);
+                self.display_origin_x = x as usize;
+                self.display_origin_y = y as usize;
+            }
+
+            0x6 => {
+                //Horizontal Display Range
+                //Ignore this one for now
+            }
+
+            0x7 => {
+                //Vertical display range
+                self.ntsc_y1 = command.get_bits(0..=9);
+                self.ntsc_y2 = command.get_bits(10..=19);
+
+                // Games can set y2 above y1. Treat that as an empty range
+                self.display_v_res = self.ntsc_y2.saturating_sub(self.ntsc_y1);
+                if self.interlace {
+                    self.display_v_res *= 2;
+                }
+            }
+
+            0x8 => {
+                //Display mode
+                (self.display_h_res, self.dot_clock_divider) = {
+                    if command.get_bit(6) {
+                        (368, 7)
+                    } else {
+                        match command & 0x3 {
+                            0 => (256, 10),
+                            1 => (320, 8),
+                            2 => (512, 5),
+                            3 => (640, 4),
+                            _ => unreachable!(),
+                        }
+                    }
+                };
+                self.dots_per_line = 3413 / (2560 / self.display_h_res);
+
+                self.display_v_res = if command.get_bit(2) && command.get_bit(5) {
+                    480
+                } else {
+                    240
+                };
+
+                self.color_depth = match command.get_bit(4) {
+                    true => ColorDepth::Full,
+                    false => ColorDepth::Reduced,
+                };
+
+                self.interlace = command.get_bit(5);
+                self.video_mode = if command.get_bit(3) {
+                    VideoMode::Pal
+                } else {
+                    VideoMode::Ntsc
+                };
+            }
+
+            0x10 => {
+                //Get gpu information
+                warn!(
+                    target: "psx::gpu",
+                    "CPU tried to query gpu parameter: {:#X}!",
+                    command.parameter()
+                );
+            }
+            _ => error!(
+                target: "psx::gpu",
+                "Unknown gp1 command {:#X} parameter {}!",
+                command.command(),
+                command.parameter()
+            ),
+        }
+    }
+
+    pub fn hblank_event(&mut self, cpu: &mut R3000, scheduler: &mut Scheduler){
+       self.scanline_counter += 1;
+
+        // Lines are a fixed number of video clocks, which isn't a whole number of CPU cycles. Timing
+        // each line from the start of the frame keeps the rounding from adding up. The resolution
+        // only changes the dot clock
+        let mode = self.video_mode();
+        let next_line = mode.lines_to_cpu_cycles(self.scanline_counter + 1).0
+            - mode.lines_to_cpu_cycles(self.scanline_counter).0;
+        scheduler.schedule_event(GpuHblank, CpuCycles(next_line));
+    }
+
+    /// Starts counting lines for a new frame. Hblank comes after the visible part of a line, so the
+    /// first one is half a line in, well clear of the vblank edges
+    pub fn start_scanlines(&mut self, scheduler: &mut Scheduler) {
+        self.scanline_counter = 0;
+        scheduler.invalidate_exact_events_of_target(GpuHblank);
+        let half_line = self.video_mode().lines_to_cpu_cycles(1).0 / 2;
+        scheduler.schedule_event(GpuHblank, CpuCycles(half_line));
+    }
+
+    /// Handles both edges of vblank, and schedules the other one. The frame is done and the VBLANK
+    /// IRQ fires when vblank starts. Returns true if vblank just started
+    pub fn vblank_event(&mut self, cpu: &mut R3000, scheduler: &mut Scheduler) -> bool {
+        self.is_vblank = !self.is_vblank;
+        if self.is_vblank {
+            self.odd_field = !self.odd_field;
+            self.frame_ready = true;
+            self.renderer.sync_vram(&mut self.vram);
+            self.renderer.end_frame(&self.vram);
+            cpu.fire_external_interrupt(InterruptSource::VBLANK);
+            // Schedule end of vblank time
+            let mode = self.video_mode();
+            let blank_lines = mode.lines_per_frame() - mode.active_lines();
+            scheduler.schedule_event(
+                ScheduleTarget::GpuVblank,
+                mode.lines_to_cpu_cycles(blank_lines),
+            );
+        } else {
+            self.start_scanlines(scheduler);
+            // Schedule next vblank
+            let mode = self.video_mode();
+            scheduler.schedule_event(
+                ScheduleTarget::GpuVblank,
+                mode.lines_to_cpu_cycles(mode.active_lines()),
+            );
+        }
+        self.is_vblank
+    }
+
+    pub fn is_vblank(&self) -> bool {
+        self.is_vblank
+    }
+
+    pub fn display_origin(&self) -> (usize, usize) {
+        (self.display_origin_x, self.display_origin_y)
+    }
+
+    /// Top left and bottom right corners of the drawing area, as set by GP0(E3h) and GP0(E4h)
+    pub fn draw_area(&self) -> ((u32, u32), (u32, u32)) {
+        let corner = |point: &Point| (point.x as u32, point.y as u32);
+        (corner(&self.draw_area_tl_point), corner(&self.draw_area_br_point))
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        Resolution {
+            width: self.display_h_res,
+            height: self.display_v_res,
+        }
+    }
+
+    /// The mode the GPU is timed with. This is the override if there is one
+    pub fn video_mode(&self) -> VideoMode {
+        self.video_mode_override.unwrap_or(self.video_mode)
+    }
+
+    /// The mode the game last asked for, which is what GPUSTAT reports
+    pub fn requested_video_mode(&self) -> VideoMode {
+        self.video_mode
+    }
+
+    pub fn set_video_mode_override(&mut self, mode: Option<VideoMode>) {
+        self.video_mode_override = mode;
+    }
+
+    /// Number of video clock cycles per output pixel. Wider modes use a smaller divider
+    pub fn dot_clock_divider(&self) -> u32 {
+        self.dot_clock_divider
+    }
+
+    pub fn take_frame_ready(&mut self) -> bool {
+        if self.frame_ready {
+            self.frame_ready = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn get_vram(&self) -> &Vec<u16> {
+        self.vram.pixels()
+    }
+
+    /// The words of a GP0 command that hasn't finished arriving, then where the current VRAM to
+    /// CPU transfer is up to
+    pub(crate) fn in_flight_state(&self) -> Vec<u64> {
+        let mut state: Vec<u64> = self.gp0_buffer.iter().map(|word| *word as u64).collect();
+        if let Some(transfer) = &self.current_transfer {
+            state.extend(
+                [
+                    transfer.base_x,
+                    transfer.base_y,
+                    transfer.current_x,
+                    transfer.current_y,
+                    transfer.width,
+                    transfer.height,
+                ]
+                .map(|value| value as u64),
+            );
+        }
+        state
+    }
+
+    /// See `Vram::region_generation`
+    pub fn region_generation(&self, x: u32, y: u32, width: u32, height: u32) -> u64 {
+        self.vram.region_generation(x, y, width, height)
+    }
+
+    /// Overwrites all of VRAM, for a defined power on state
+    pub(crate) fn fill_vram(&mut self, pixels: impl Iterator<Item = u16>) {
+        self.renderer.sync_vram(&mut self.vram);
+        self.vram.clear();
+        for (pixel, value) in self.vram.pixels.iter_mut().zip(pixels) {
+            *pixel = value;
+        }
+        self.renderer.replace_vram(&self.vram);
+    }
+
+    /// Replaces the backend primitives are drawn with. VRAM is kept as it is
+    pub fn set_renderer(&mut self, renderer: Box<dyn RendererBackend>) {
+        let threaded = self.threaded_backend.is_some();
+        self.set_threaded(false);
+        self.renderer = renderer;
+        self.set_threaded(threaded);
+    }
+
+    /// Runs the backend on a worker thread, so primitives are drawn while the CPU keeps going. The
+    /// GPU waits for the worker whenever VRAM is read and at the end of each frame
+    pub fn set_threaded(&mut self, enabled: bool) {
+        if enabled == self.threaded_backend.is_some() {
+            return;
+        }
+        self.renderer.sync_vram(&mut self.vram);
+        let renderer = mem::replace(&mut self.renderer, Box::new(SoftwareRenderer));
+        if enabled {
+            let (threaded, backend) = ThreadedRenderer::new(renderer, &self.vram);
+            self.renderer = Box::new(threaded);
+            self.threaded_backend = Some(backend);
+        } else {
+            // Stops the worker once it has drawn everything it was sent
+            drop(renderer);
+            self.renderer = self.threaded_backend.take().unwrap().recv().unwrap();
+        }
+    }
+
+    /// Waits for the backend to finish drawing, for reading VRAM in the middle of a frame
+    pub fn sync_vram(&mut self) {
+        self.renderer.sync_vram(&mut self.vram);
+    }
+
+    pub fn is_full_color_depth(&self) -> bool {
+        self.color_depth == ColorDepth::Full
+    }
+
+    /// Renders the display area exactly as it's shown on screen, as rows of `resolution().width`
+    /// RGBA8 pixels. Both fields of an interlaced picture are stored on alternating VRAM lines, so
+    /// reading the area line by line weaves them, starting with the field on the origin's line.
+    /// The display area wraps around the edges of VRAM like it does on hardware
+    pub fn render_display(&self, out: &mut Vec<u8>) {
+        let width = VRAM_WIDTH as usize;
+        let Resolution {
+            width: display_width,
+            height: display_height,
+        } = self.resolution();
+
+        out.clear();
+        out.reserve((display_width * display_height * 4) as usize);
+
+        for y in 0..display_height as usize {
+            let row_start = ((self.display_origin_y + y) % VRAM_HEIGHT as usize) * width;
+            let row = &self.vram.pixels()[row_start..row_start + width];
+            match self.color_depth {
+                ColorDepth::Reduced => {
+                    for x in 0..display_width as usize {
+                        let pixel = row[(self.display_origin_x + x) % width];
+                        out.extend(b15color_to_rgba(pixel));
+                    }
+                }
+                ColorDepth::Full => {
+                    // 24 bit pixels are packed as bytes, so most of them straddle two VRAM cells
+                    let byte = |offset: usize| row[(offset / 2) % width].to_le_bytes()[offset % 2];
+                    for x in 0..display_width as usize {
+                        let start = self.display_origin_x * 2 + x * 3;
+                        out.extend([byte(start), byte(start + 1), byte(start + 2), 255]);
+                    }
+                }
+            }
+        }
+    }
+
+    ///Returns irq status. If true, function will return true then clear irq status
+    pub fn consume_irq(&mut self) -> bool {
+        if self.irq_fired {
+            self.irq_fired = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Total number of words the buffered GP0 command needs. Returns None while that can't be
+    /// known yet: a polyline runs until its terminating vertex, and a CPU to VRAM transfer's length
+    /// comes from its third word
+    fn gp0_command_length(&self) -> Option<usize> {
+        let command = *self.gp0_buffer.first()?;
+        let length = match command.gp0_header() {
+            0x0 if command >> 24 == 0x2 => 3,
+            0x1 => {
+                let verts = if command.get_bit(27) { 4 } else { 3 };
+                1 + (verts * command.get_bit(26) as usize)
+                    + verts
+                    + if command.get_bit(28) { verts - 1 } else { 0 }
+            }
+            0x2 if command.get_bit(27) => {
+                // The terminator can't come before the end of the first segment
+                let first_segment = if command.get_bit(28) { 4 } else { 3 };
+                let last = self.gp0_buffer[self.gp0_buffer.len() - 1];
+                if self.gp0_buffer.len() <= first_segment || (last & 0xF000F000) != 0x50005000 {
+                    //Wait until terminating vertex
+                    return None;
+                }
+                self.gp0_buffer.len()
+            }
+            0x2 => 3 + if command.get_bit(28) { 1 } else { 0 },
+            0x3 => {
+                let size = (command >> 27) & 0x3;
+                2 + if size == 0 { 1 } else { 0 } + if command.get_bit(26) { 1 } else { 0 }
+            }
+            0x4 => 4,
+            0x5 => {
+                let size = *self.gp0_buffer.get(2)?;
+                let pixels = (transfer_width(size) * transfer_height(size)) as usize;
+                3 + (pixels + pixels % 2) / 2
+            }
+            0x6 => 3,
+            _ => 1,
+        };
+        Some(length)
+    }
+
+    /// Called by the DMA linked list before each packet is sent. Games only put whole commands in a
+    /// packet, so a command still waiting for words here was cut short and the packet starts with a
+    /// new command header. The partial command is dropped so it can't swallow the words that follow
+    pub(crate) fn start_gp0_packet(&mut self) {
+        if self.gp0_buffer.is_empty() {
+            return;
+        }
+
+        match self.gp0_command_length() {
+            Some(length) => warn!(
+                target: "psx::gpu",
+                "GPU: GP0 command {:#X} was {} words short. Resetting the command parser",
+                self.gp0_buffer[0],
+                length - self.gp0_buffer.len()
+            ),
+            None => warn!(
+                target: "psx::gpu",
+                "GPU: GP0 command {:#X} was never finished. Resetting the command parser",
+                self.gp0_buffer[0]
+            ),
+        }
+        self.gp0_clear();
+    }
+
+    fn gp0_push(&mut self, val: u32) {
+        self.gp0_buffer.push(val);
+    }
+
+    fn gp0_clear(&mut self) {
+        self.gp0_buffer.clear();
+    }
+
+    fn draw_state(&self) -> DrawState {
+        DrawState {
+            area_tl: self.draw_area_tl_point,
+            area_br: self.draw_area_br_point,
+            blend_mode: self.blend_mode,
+            check_mask: self.check_mask,
+            force_b15: self.force_b15,
+            skip_field: if self.interlace && !self.draw_to_display {
+                Some(self.odd_field as u32)
+            } else {
+                None
+            },
+            texmode: self.texmode,
+            tex_mask_x: self.tex_mask_x,
+            tex_mask_y: self.tex_mask_y,
+            tex_offset_x: self.tex_offset_x,
+            tex_offset_y: self.tex_offset_y,
+        }
+    }
+
+    /// Texture page and CLUT set by the last draw mode and rectangle commands
+    fn current_texture(&self) -> TextureSource {
+        TextureSource {
+            page_x: self.texpage_x_base as u32,
+            page_y: self.texpage_y_base as u32,
+            clut_x: self.palette_x as u32,
+            clut_y: self.palette_y as u32,
+        }
+    }
+
+    fn draw_triangle(&mut self, points: &[Point], fill: PolygonFill, transparent: bool) {
+        let state = self.draw_state();
+        self.renderer
+            .draw_triangle(&mut self.vram, &state, points, fill, transparent);
+    }
+
+    /// Draws a triangle or quad given in vertex coordinates, before the draw offset. Quads are
+    /// drawn as two triangles, and the size limit applies to each one on its own
+    fn draw_polygon(&mut self, points: &[Point], fill: PolygonFill, transparent: bool) {
+        for triangle in polygon_triangles(points) {
+            if self.strict_size_check && triangle_too_big(&triangle) {
+                trace!(target: "psx::gpu", "Triangle too big, dropping");
+                self.stats.triangles_dropped += 1;
+            } else {
+                let triangle = self.offset_points(&triangle);
+                self.draw_triangle(&triangle, fill, transparent);
+                self.stats.triangles_drawn += 1;
+            }
+        }
+    }
+
+    /// True if any part of the polygon will be dropped for being too big
+    fn polygon_dropped(&self, points: &[Point]) -> bool {
+        self.strict_size_check && polygon_triangles(points).iter().any(triangle_too_big)
+    }
+
+    fn offset_points(&self, points: &[Point]) -> Vec<Point> {
+        points
+            .iter()
+            .map(|point| {
+                let mut point = *point;
+                point.x += self.draw_offset.x;
+                point.y += self.draw_offset.y;
+                point
+            })
+            .collect()
+    }
+
+    /// Draws one segment of a line, given with the draw offset already applied
+    fn draw_line(&mut self, start: &Point, end: &Point, transparent: bool) {
+        let state = self.draw_state();
+        self.renderer
+            .draw_line(&mut self.vram, &state, start, end, transparent);
+    }
+
+    fn draw_rect(&mut self, tl: &Point, width: i32, height: i32, fill: RectFill, transparent: bool) {
+        let state = self.draw_state();
+        self.renderer
+            .draw_rect(&mut self.vram, &state, tl, width, height, fill, transparent);
+    }
+}
+
+/// Splits a quad into the two triangles the hardware draws it as. Triangles are left alone
+fn polygon_triangles(points: &[Point]) -> Vec<[Point; 3]> {
+    if points.len() == 4 {
+        vec![
+            [points[0], points[2], points[1]],
+            [points[1], points[2], points[3]],
+        ]
+    } else {
+        vec![[points[0], points[1], points[2]]]
+    }
+}
+
+/// The GPU won't draw a triangle with two vertices 1024 or more pixels apart horizontally, or
+/// 512 or more vertically
+fn triangle_too_big(triangle: &[Point; 3]) -> bool {
+    (0..3).any(|i| {
+        let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+        (a.x - b.x).abs() >= MAX_POLYGON_WIDTH || (a.y - b.y).abs() >= MAX_POLYGON_HEIGHT
+    })
+}
+
+/// Same limits as triangles, which the rasterizer skips lines over
+fn line_too_big(start: Point, end: Point) -> bool {
+    (start.x - end.x).abs() >= MAX_POLYGON_WIDTH || (start.y - end.y).abs() >= MAX_POLYGON_HEIGHT
+}
+
+/// Coordinates wrap at the edges of VRAM, like they do for the GPU's transfers and texture reads.
+/// Rasterizers must skip pixels outside of the drawing area before getting here, since a
+/// negative coordinate would wrap onto the other side
+fn point_to_address(x: u32, y: u32) -> u32 {
+    (y % VRAM_HEIGHT as u32) * VRAM_WIDTH as u32 + x % VRAM_WIDTH as u32
+}
+
+fn b24color_to_b15color(color: u32) -> u16 {
+    let b = ((color >> 16) & 0xFF) / 8;
+    let g = ((color >> 8) & 0xFF) / 8;
+    let r = (color & 0xFF) / 8;
+    (((b & 0x1F) << 10) | ((g & 0x1F) << 5) | r & 0x1F) as u16
+}
+
+fn b15_to_rgb(color: u16) -> (u8, u8, u8) {
+    (
+        (color & 0x1F) as u8,         //red
+        ((color >> 5) & 0x1F) as u8,  //green
+        ((color >> 10) & 0x1F) as u8, //blue
+    )
+}
+
+/// Scales each 5 bit channel up to 8 bits. Matches the desktop's VRAM viewer
+fn b15color_to_rgba(color: u16) -> [u8; 4] {
+    let (r, g, b) = b15_to_rgb(color);
+    [r << 3, g << 3, b << 3, 255]
+}
+
+fn rgb_to_b15(r: u8, g: u8, b: u8) -> u16 {
+    (((b & 0x1F) as u16) << 10) | (((g & 0x1F) as u16) << 5) | ((r & 0x1F) as u16)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub enum BlendMode {
+    B2F2, // B/2+F/2
+    BAF,  // B+F
+    BSF,  // B-F
+    BF4,  // B+F/4
+}
+//Helper trait + impl
+trait Command {
+    fn gp0_header(&self) -> u8;
+    fn command(&self) -> u8;
+    fn parameter(&self) -> u32;
+}
+
+impl Command for u32 {
+    fn gp0_header(&self) -> u8 {
+        ((self.clone() >> 29) & 0x7) as u8
+    }
+
+    fn command(&self) -> u8 {
+        ((self.clone() >> 24) & 0xFF) as u8
+    }
+
+    fn parameter(&self) -> u32 {
+        self.clone() & 0x7FFFFF
+    }
+}
+
+#[cfg(test)]
+mod gpu_tests {
+    use super::*;
+    use super::raster::sort_points_clockwise;
+
+    const RED: u32 = 0x0000FF;
+    const BLUE: u32 = 0xFF0000;
+
+    /// Sends a packet the same way a DMA linked list node does
+    fn send_packet(gpu: &mut Gpu, words: &[u32]) {
+        gpu.start_gp0_packet();
+        for word in words {
+            gpu.send_gp0_command(*word);
+        }
+    }
+
+    fn vertex(x: u32, y: u32) -> u32 {
+        (y << 16) | x
+    }
+
+    fn pixel(gpu: &Gpu, x: u32, y: u32) -> u16 {
+        gpu.get_vram()[point_to_address(x, y) as usize]
+    }
+
+    /// The same cross product the GTE's NCLIP calculates
+    fn winding(points: &[Point]) -> i32 {
+        (points[1].x - points[0].x) * (points[2].y - points[0].y)
+            - (points[2].x - points[0].x) * (points[1].y - points[0].y)
+    }
+
+    #[test]
+    fn test_sorted_triangles_match_nclip_winding() {
+        let corners = [(0, 0), (10, 0), (0, 10)];
+        for order in [[0, 1, 2], [0, 2, 1], [1, 0, 2], [2, 1, 0]] {
+            let points: Vec<Point> = order
+                .iter()
+                .map(|&i| Point::from_components(corners[i].0, corners[i].1, 0))
+                .collect();
+            assert!(winding(&sort_points_clockwise(&points)) > 0, "{:?}", order);
+        }
+    }
+
+    /// Runs `write` and returns which 64x64 tiles it changed the generation of
+    fn tiles_written(gpu: &mut Gpu, write: impl FnOnce(&mut Gpu)) -> Vec<(u32, u32)> {
+        let before: Vec<u64> = gpu.vram.tile_generations.clone();
+        write(gpu);
+        (0..TILES_X * TILES_Y)
+            .filter(|&tile| gpu.vram.tile_generations[tile as usize] != before[tile as usize])
+            .map(|tile| (tile % TILES_X, tile / TILES_X))
+            .collect()
+    }
+
+    #[test]
+    fn test_vram_writes_bump_tile_generations() {
+        let mut gpu = Gpu::new();
+        send_packet(&mut gpu, &[0xE100_0000, 0xE300_0000, 0xE407_FFFF, 0xE500_0000]);
+
+        // CPU to VRAM, 2x2 at (70, 10)
+        let written = tiles_written(&mut gpu, |gpu| {
+            send_packet(gpu, &[0xA000_0000, vertex(70, 10), vertex(2, 2), 0x7FFF_7FFF, 0x7FFF_7FFF])
+        });
+        assert_eq!(written, vec![(1, 0)]);
+
+        // VRAM to VRAM, copying that to (200, 100)
+        let written = tiles_written(&mut gpu, |gpu| {
+            send_packet(gpu, &[0x8000_0000, vertex(70, 10), vertex(200, 100), vertex(2, 2)])
+        });
+        assert_eq!(written, vec![(3, 1)]);
+
+        // Quick fill 32x16 at (112, 250), straddling four tiles
+        let written = tiles_written(&mut gpu, |gpu| {
+            send_packet(gpu, &[0x0200_0000 | RED, vertex(112, 250), vertex(32, 16)])
+        });
+        assert_eq!(written, vec![(1, 3), (2, 3), (1, 4), (2, 4)]);
+
+        // Rendering, a flat rectangle at (1000, 500)
+        let written = tiles_written(&mut gpu, |gpu| {
+            send_packet(gpu, &[0x6000_0000 | BLUE, vertex(1000, 500), vertex(8, 8)])
+        });
+        assert_eq!(written, vec![(15, 7)]);
+
+        let generation = gpu.region_generation(0, 0, 128, 64);
+        assert_eq!(generation, gpu.region_generation(64, 0, 1, 1));
+        assert!(generation > gpu.region_generation(0, 0, 64, 64));
+        assert!(gpu.region_generation(960, 448, 64, 64) > generation);
+    }
+
+    /// Backend that only records which methods were called
+    struct RecordingRenderer(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl RecordingRenderer {
+        fn record(&self, call: String) {
+            self.0.lock().unwrap().push(call);
+        }
+    }
+
+    impl RendererBackend for RecordingRenderer {
+        fn draw_triangle(&mut self, _: &mut Vram, _: &DrawState, points: &[Point], fill: PolygonFill, _: bool) {
+            let corners: Vec<(i32, i32)> = points.iter().map(|p| (p.x, p.y)).collect();
+            self.record(format!("triangle {:?} {:?}", corners, fill));
+        }
+
+        fn draw_rect(&mut self, _: &mut Vram, _: &DrawState, tl: &Point, width: i32, height: i32, fill: RectFill, _: bool) {
+            self.record(format!("rect ({}, {}) {}x{} {:?}", tl.x, tl.y, width, height, fill));
+        }
+
+        fn draw_line(&mut self, _: &mut Vram, _: &DrawState, start: &Point, end: &Point, _: bool) {
+            self.record(format!("line ({}, {}) ({}, {}) {:#x}", start.x, start.y, end.x, end.y, end.color));
+        }
+
+        fn fill(&mut self, _: &mut Vram, _: &DrawState, tl: &Point, br: &Point, color: u16) {
+            self.record(format!("fill ({}, {}) ({}, {}) {:#x}", tl.x, tl.y, br.x, br.y, color));
+        }
+
+        fn blit(&mut self, _: &mut Vram, _: &DrawState, source: (u32, u32), dest: (u32, u32), width: u32, height: u32) {
+            self.record(format!("blit {:?} {:?} {}x{}", source, dest, width, height));
+        }
+
+        fn upload(&mut self, _: &mut Vram, _: &DrawState, x: u32, y: u32, width: u32, pixels: &[u16]) {
+            self.record(format!("upload ({}, {}) {} {:?}", x, y, width, pixels));
+        }
+
+        fn sync_vram(&mut self, _: &mut Vram) {
+            self.record("sync".to_string());
+        }
+    }
+
+    #[test]
+    fn test_primitives_go_through_renderer() {
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut gpu = Gpu::new();
+        gpu.set_renderer(Box::new(RecordingRenderer(calls.clone())));
+        send_packet(&mut gpu, &[0xE100_0000, 0xE300_0000, 0xE407_FFFF, 0xE500_0000]);
+        // Draw offset of (10, 20)
+        send_packet(&mut gpu, &[0xE500_0000 | (20 << 11) | 10]);
+
+        send_packet(
+            &mut gpu,
+            &[0x2800_0000 | RED, vertex(0, 0), vertex(8, 0), vertex(0, 8), vertex(8, 8)],
+        );
+        send_packet(&mut gpu, &[0x6000_0000 | BLUE, vertex(1, 2), vertex(3, 4)]);
+        send_packet(&mut gpu, &[0x4800_0000 | BLUE, vertex(0, 0), vertex(5, 0), vertex(5, 5), 0x5555_5555]);
+        send_packet(&mut gpu, &[0x0200_0000 | RED, vertex(16, 16), vertex(16, 1)]);
+        send_packet(&mut gpu, &[0x8000_0000, vertex(0, 0), vertex(64, 0), vertex(2, 2)]);
+        send_packet(&mut gpu, &[0xA000_0000, vertex(5, 6), vertex(2, 1), 0x0002_0001]);
+        send_packet(&mut gpu, &[0xC000_0000, vertex(0, 0), vertex(1, 1)]);
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "triangle [(10, 20), (10, 28), (18, 20)] Solid(31)",
+                "triangle [(18, 20), (10, 28), (18, 28)] Solid(31)",
+                "rect (11, 22) 3x4 Solid(31744)",
+                "line (10, 20) (15, 20) 0x7c00",
+                "line (15, 20) (15, 25) 0x7c00",
+                "fill (16, 16) (32, 17) 0x1f",
+                "blit (0, 0) (64, 0) 2x2",
+                "upload (5, 6) 2 [1, 2]",
+                "sync",
+            ]
+        );
+        // Nothing was drawn in software
+        assert!(gpu.get_vram().iter().all(|&cell| cell == 0));
+    }
+
+    fn draw_test_scene(gpu: &mut Gpu) {
+        send_packet(gpu, &[0xE100_0000, 0xE300_0000, 0xE407_FFFF, 0xE500_0000]);
+        send_packet(gpu, &[0x0200_0000 | BLUE, vertex(0, 0), vertex(64, 64)]);
+        send_packet(
+            gpu,
+            &[0x3800_0000 | RED, vertex(4, 4), BLUE, vertex(60, 8), RED, vertex(8, 60), BLUE, vertex(50, 50)],
+        );
+        // Semi transparent rect over the quad
+        send_packet(gpu, &[0x6200_0000 | RED, vertex(10, 10), vertex(20, 20)]);
+        send_packet(gpu, &[0x5000_0000 | RED, vertex(2, 40), BLUE, vertex(60, 20)]);
+        send_packet(gpu, &[0xA000_0000, vertex(30, 2), vertex(2, 1), 0x7FFF_1234]);
+        send_packet(gpu, &[0x8000_0000, vertex(0, 0), vertex(100, 100), vertex(64, 64)]);
+    }
+
+    #[test]
+    fn test_threaded_matches_single_threaded() {
+        let mut expected = Gpu::new();
+        draw_test_scene(&mut expected);
+
+        let mut gpu = Gpu::new();
+        gpu.set_threaded(true);
+        draw_test_scene(&mut gpu);
+        // A VRAM to CPU transfer waits for the worker
+        send_packet(&mut gpu, &[0xC000_0000, vertex(30, 2), vertex(2, 1)]);
+        assert_eq!(gpu.read_word_gp0(), 0x7FFF_1234);
+
+        gpu.sync_vram();
+        assert!(gpu.get_vram() == expected.get_vram());
+        assert_eq!(gpu.region_generation(0, 0, 1024, 512), expected.region_generation(0, 0, 1024, 512));
+    }
+
+    #[test]
+    fn test_threaded_keeps_backend() {
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut gpu = Gpu::new();
+        gpu.set_threaded(true);
+        gpu.set_renderer(Box::new(RecordingRenderer(calls.clone())));
+        calls.lock().unwrap().clear();
+        send_packet(&mut gpu, &[0x0200_0000 | RED, vertex(16, 16), vertex(16, 1)]);
+        gpu.sync_vram();
+        assert_eq!(*calls.lock().unwrap(), vec!["fill (16, 16) (32, 17) 0x1f", "sync"]);
+
+        // Turning threading off hands the same backend back
+        gpu.set_threaded(false);
+        send_packet(&mut gpu, &[0x0200_0000 | RED, vertex(0, 0), vertex(16, 1)]);
+        assert_eq!(calls.lock().unwrap().len(), 3);
+    }
+
+    /// Vertex word for coordinates that may be negative
+    fn signed_vertex(x: i32, y: i32) -> u32 {
+        vertex(x as u32 & 0x7FF, y as u32 & 0x7FF)
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut gpu = Gpu::new();
+        // Drawing area from (0, 0) to (99, 99)
+        send_packet(&mut gpu, &[0xE100_0000, 0xE300_0000, 0xE401_8C63, 0xE500_0000]);
+
+        // Both ends are drawn, and a shallow line steps once per column
+        send_packet(&mut gpu, &[0x4000_0000 | RED, vertex(10, 10), vertex(20, 13)]);
+        for x in 10..=20 {
+            let y = (10..=13).filter(|&y| pixel(&gpu, x, y) == 0x1F).count();
+            assert_eq!(y, 1, "column {}", x);
+        }
+        assert_eq!(pixel(&gpu, 10, 10), 0x1F);
+        assert_eq!(pixel(&gpu, 20, 13), 0x1F);
+        assert_eq!(pixel(&gpu, 21, 13), 0);
+
+        // Gouraud, from red to blue down a column
+        send_packet(&mut gpu, &[0x5000_0000 | RED, vertex(30, 0), BLUE, vertex(30, 31)]);
+        assert_eq!(pixel(&gpu, 30, 1), rgb_to_b15(30, 0, 1));
+        assert_eq!(pixel(&gpu, 30, 16), rgb_to_b15(15, 0, 16));
+        assert_eq!(pixel(&gpu, 30, 31), rgb_to_b15(0, 0, 31));
+        // The top of the drawing area is its edge, so it isn't drawn
+        assert_eq!(pixel(&gpu, 30, 0), 0);
+
+        // Polyline running off the right of the drawing area
+        send_packet(
+            &mut gpu,
+            &[0x4800_0000 | BLUE, vertex(40, 50), vertex(40, 60), vertex(120, 60), 0x5000_5000],
+        );
+        assert_eq!(pixel(&gpu, 40, 55), 0x7C00);
+        assert_eq!(pixel(&gpu, 98, 60), 0x7C00);
+        assert_eq!(pixel(&gpu, 99, 60), 0);
+
+        // Semi transparent, B/2 + F/2 over the blue segment
+        send_packet(&mut gpu, &[0x4200_0000 | RED, vertex(35, 55), vertex(45, 55)]);
+        assert_eq!(pixel(&gpu, 40, 55), rgb_to_b15(15, 0, 15));
+        assert_eq!(pixel(&gpu, 36, 55), rgb_to_b15(15, 0, 0));
+    }
+
+    #[test]
+    fn test_oversized_triangle_is_dropped() {
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut gpu = Gpu::new();
+        gpu.set_renderer(Box::new(RecordingRenderer(calls.clone())));
+        // The offset pulls both triangles into range, but the limit is checked before it is added
+        send_packet(&mut gpu, &[0xE500_0000 | 200]);
+
+        // 1025 wide
+        let wide = [
+            0x2000_0000 | RED,
+            signed_vertex(-512, 0),
+            signed_vertex(513, 0),
+            signed_vertex(0, 8),
+        ];
+        send_packet(&mut gpu, &wide);
+        // 1023 wide
+        send_packet(
+            &mut gpu,
+            &[0x2000_0000 | RED, signed_vertex(-512, 0), signed_vertex(511, 0), signed_vertex(0, 8)],
+        );
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["triangle [(-312, 0), (200, 8), (711, 0)] Solid(31)"]
+        );
+        assert_eq!(gpu.take_stats(), GpuStats { triangles_drawn: 1, triangles_dropped: 1 });
+        assert!(gpu.take_call_log()[0].call_dropped);
+
+        // Without the strict check it is drawn anyway
+        gpu.set_strict_size_check(false);
+        send_packet(&mut gpu, &wide);
+        assert_eq!(calls.lock().unwrap().len(), 2);
+        assert_eq!(gpu.take_stats().triangles_dropped, 0);
+    }
+
+    #[test]
+    fn test_quad_halves_are_checked_separately() {
+        let mut gpu = Gpu::new();
+        send_packet(&mut gpu, &[0xE100_0000, 0xE300_0000, 0xE407_FFFF, 0xE500_0000 | 600]);
+
+        // The first half is 8 pixels wide. The second is 1030 wide
+        let quad = [
+            0x2800_0000 | RED,
+            signed_vertex(-500, 0),
+            signed_vertex(-492, 0),
+            signed_vertex(-500, 8),
+            signed_vertex(530, 8),
+        ];
+        send_packet(&mut gpu, &quad);
+
+        assert_eq!(gpu.take_stats(), GpuStats { triangles_drawn: 1, triangles_dropped: 1 });
+        // Only the first half is rasterized
+        assert_eq!(pixel(&gpu, 101, 1), 0x1F);
+        assert_eq!(pixel(&gpu, 900, 7), 0);
+    }
+
+    #[test]
+    fn test_triangle_off_the_top_left() {
+        let mut gpu = Gpu::new();
+        // Draw offset of (-5, -5) moves the triangles partly off of VRAM
+        let offset = (-5i32 as u32 & 0x7FF) | ((-5i32 as u32 & 0x7FF) << 11);
+        send_packet(&mut gpu, &[0xE100_0000, 0xE300_0000, 0xE407_FFFF, 0xE500_0000 | offset]);
+
+        send_packet(&mut gpu, &[0x2000_0000 | RED, vertex(0, 0), vertex(20, 0), vertex(0, 20)]);
+        send_packet(
+            &mut gpu,
+            &[0x3000_0000 | BLUE, vertex(0, 0), BLUE, vertex(20, 0), BLUE, vertex(0, 20)],
+        );
+
+        assert_eq!(pixel(&gpu, 2, 2), 0x7C00);
+        // Pixels above and left of VRAM are skipped, not wrapped or clamped onto the far corner
+        assert_eq!(gpu.get_vram()[524287], 0);
+        assert_eq!(pixel(&gpu, 1023, 2), 0);
+        assert_eq!(pixel(&gpu, 2, 511), 0);
+    }
+
+    #[test]
+    fn test_deep_capture_records_changed_pixels() {
+        let mut gpu = Gpu::new();
+        gpu.set_deep_capture(true);
+        send_packet(&mut gpu, &[0xE100_0000, 0xE300_0000, 0xE407_FFFF, 0xE500_0000 | 50 << 11 | 100]);
+        send_packet(&mut gpu, &[0x2000_0000 | RED, vertex(0, 0), vertex(10, 0), vertex(0, 10)]);
+
+        let change = gpu.take_call_log()[0].vram_change.clone().unwrap();
+        let mut changed: Vec<(u16, u16)> = change.changed_pixels().collect();
+        changed.sort_by_key(|(x, y)| (*y, *x));
+        let drawn: Vec<(u16, u16)> = (0..VRAM_HEIGHT as u16)
+            .flat_map(|y| (0..VRAM_WIDTH as u16).map(move |x| (x, y)))
+            .filter(|(x, y)| pixel(&gpu, *x as u32, *y as u32) != 0)
+            .collect();
+        assert!(!drawn.is_empty());
+        assert_eq!(changed, drawn);
+
+        // Without deep capture nothing is kept
+        gpu.set_deep_capture(false);
+        send_packet(&mut gpu, &[0x2000_0000 | RED, vertex(0, 0), vertex(10, 0), vertex(0, 10)]);
+        assert!(gpu.take_call_log()[0].vram_change.is_none());
+    }
+
+    #[test]
+    fn test_linked_list_with_env_commands() {
+        let mut gpu = Gpu::new();
+
+        // Draw mode, draw area covering all of VRAM and no offset
+        send_packet(&mut gpu, &[0xE100_0000, 0xE300_0000, 0xE407_FFFF, 0xE500_0000]);
+        // Flat triangle
+        send_packet(
+            &mut gpu,
+            &[0x2000_0000 | RED, vertex(0, 0), vertex(32, 0), vertex(0, 32)],
+        );
+        // Flat quad missing its last two vertices
+        send_packet(&mut gpu, &[0x2800_FF00, vertex(200, 0), vertex(232, 0)]);
+        // Move the draw offset to (100, 100), then a 16x16 flat rectangle at (40, 40)
+        send_packet(&mut gpu, &[0xE500_0000 | (100 << 11) | 100]);
+        send_packet(&mut gpu, &[0x6000_0000 | BLUE, vertex(40, 40), vertex(16, 16)]);
+
+        assert_eq!(pixel(&gpu, 4, 4), b24color_to_b15color(RED));
+        assert_eq!(pixel(&gpu, 148, 148), b24color_to_b15color(BLUE));
+        // Nothing was drawn where the rectangle would be without the offset
+        assert_eq!(pixel(&gpu, 48, 48), 0);
+        assert_eq!(gpu.draw_offset.x, 100);
+        assert_eq!(gpu.draw_offset.y, 100);
+        assert_eq!(gpu.draw_area(), ((0, 0), (1023, 511)));
+    }
+
+    /// Fills VRAM so every cell encodes its own coordinates: x in the low 10 bits and the low 5
+    /// bits of y above it
+    fn coordinate_vram(gpu: &mut Gpu) {
+        for (index, cell) in gpu.vram.pixels.iter_mut().enumerate() {
+            let (x, y) = (index % 1024, index / 1024);
+            *cell = (x | (y & 0x1F) << 10) as u16;
+        }
+    }
+
+    fn cell(x: usize, y: usize) -> u16 {
+        ((x % 1024) | ((y % 512) & 0x1F) << 10) as u16
+    }
+
+    fn rendered_cell(out: &[u8], index: usize) -> u16 {
+        let pixel = &out[index * 4..index * 4 + 4];
+        assert_eq!(pixel[3], 255);
+        (pixel[0] >> 3) as u16 | ((pixel[1] >> 3) as u16) << 5 | ((pixel[2] >> 3) as u16) << 10
+    }
+
+    fn set_display(gpu: &mut Gpu, mode: u32, origin_x: u32, origin_y: u32) {
+        gpu.send_gp1_command(0x0800_0000 | mode);
+        gpu.send_gp1_command(0x0500_0000 | origin_x | origin_y << 10);
+        coordinate_vram(gpu);
+    }
+
+    fn check_15bit_display(origin_x: usize, origin_y: usize, mode: u32, height: usize) {
+        let mut gpu = Gpu::new();
+        set_display(&mut gpu, mode, origin_x as u32, origin_y as u32);
+        let mut out = vec![];
+        gpu.render_display(&mut out);
+
+        assert_eq!(out.len(), 320 * height * 4);
+        for y in 0..height {
+            for x in 0..320 {
+                assert_eq!(
+                    rendered_cell(&out, y * 320 + x),
+                    cell(origin_x + x, origin_y + y),
+                    "pixel ({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_render_15bit_display() {
+        check_15bit_display(3, 5, 0x1, 240);
+        // Wraps around the right and bottom edges of VRAM
+        check_15bit_display(900, 400, 0x1, 240);
+    }
+
+    #[test]
+    fn test_draw_to_display_area() {
+        let quad = [0x2800_0000 | RED, vertex(0, 0), vertex(320, 0), vertex(0, 480), vertex(320, 480)];
+        let mut gpu = Gpu::new();
+        // 320x480 interlaced, showing the even field
+        gpu.send_gp1_command(0x0800_0025);
+        send_packet(&mut gpu, &[0xE100_0000, 0xE300_0000, 0xE407_FFFF, 0xE500_0000]);
+        assert!(!gpu.read_status_register().get_bit(10));
+
+        // Drawing to the displayed field is prohibited, so only the odd lines change
+        send_packet(&mut gpu, &quad);
+        assert_eq!(pixel(&gpu, 100, 100), 0);
+        assert_ne!(pixel(&gpu, 100, 101), 0);
+
+        send_packet(&mut gpu, &[0xE100_0400]);
+        assert!(gpu.read_status_register().get_bit(10));
+        send_packet(&mut gpu, &quad);
+        assert_ne!(pixel(&gpu, 100, 100), 0);
+    }
+
+    #[test]
+    fn test_render_interlaced_display() {
+        // 320x480 interlaced. The field order follows the parity of the origin's line
+        check_15bit_display(0, 0, 0x25, 480);
+        check_15bit_display(0, 17, 0x25, 480);
+    }
+
+    #[test]
+    fn test_render_24bit_display() {
+        for origin_x in [0, 1, 2, 3] {
+            let mut gpu = Gpu::new();
+            // 320x240, 24 bit
+            set_display(&mut gpu, 0x11, origin_x as u32, 2);
+            let mut out = vec![];
+            gpu.render_display(&mut out);
+
+            assert_eq!(out.len(), 320 * 240 * 4);
+            for y in 0..240 {
+                for x in 0..320 {
+                    let start = origin_x * 2 + x * 3;
+                    let expected: Vec<u8> = (start..start + 3)
+                        .map(|byte| cell(byte / 2, y + 2).to_le_bytes()[byte % 2])
+                        .chain([255])
+                        .collect();
+                    let index = (y * 320 + x) * 4;
+                    assert_eq!(
+                        out[index..index + 4],
+                        expected[..],
+                        "origin {} pixel ({}, {})",
+                        origin_x,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_command_length_tracking() {
+        let mut gpu = Gpu::new();
+
+        // Gouraud textured quad: command, then color, vertex and UV for each vertex but the first
+        // color, which lives in the command word
+        gpu.send_gp0_command(0x3C00_0000);
+        assert_eq!(gpu.gp0_command_length(), Some(12));
+
+        // Gouraud polyline. A color that looks like the terminator still belongs to the first segment
+        gpu.gp0_clear();
+        for word in [0x5800_0000, vertex(0, 0), 0x5555_5555] {
+            gpu.gp0_push(word);
+        }
+        assert_eq!(gpu.gp0_command_length(), None);
+        gpu.gp0_push(vertex(4, 4));
+        assert_eq!(gpu.gp0_command_length(), None);
+        gpu.gp0_push(0x5000_5000);
+        assert_eq!(gpu.gp0_command_length(), Some(5));
+
+        gpu.gp0_clear();
+        gpu.send_gp0_command(0xA000_0000);
+        assert_eq!(gpu.gp0_command_length(), None);
+        gpu.send_gp0_command(vertex(0, 0));
+        gpu.send_gp0_command(vertex(3, 1));
+        assert_eq!(gpu.gp0_command_length(), Some(5));
+    }
+}
@@ -0,0 +1,892 @@
+//! Software rasterizer for the GPU's primitives, drawing into anything that looks like VRAM
+
+use std::cmp::Ordering;
+
+use bit_field::BitField;
+use nalgebra::Vector2;
+use num_traits::clamp;
+
+use super::renderer::{DrawState, TextureSource, Vram};
+use super::{
+    b15_to_rgb, point_to_address, rgb_to_b15, BlendMode, Point, TextureColorMode, TextureDraw, VRAM_HEIGHT,
+    VRAM_WIDTH,
+};
+
+/// Where the rasterizer reads and writes pixels, by `point_to_address` address. Emulated VRAM is
+/// one, and a backend can provide its own to draw somewhere else
+pub(crate) trait VramView {
+    fn read(&self, addr: usize) -> u16;
+    fn write(&mut self, addr: usize, value: u16);
+}
+
+impl VramView for Vram {
+    fn read(&self, addr: usize) -> u16 {
+        Vram::read(self, addr)
+    }
+
+    fn write(&mut self, addr: usize, value: u16) {
+        Vram::write(self, addr, value)
+    }
+}
+
+/// Software rasterization of a single primitive
+pub(crate) struct Rasterizer<'a, V: VramView + ?Sized> {
+    vram: &'a mut V,
+    state: &'a DrawState,
+}
+
+impl<'a, V: VramView + ?Sized> Rasterizer<'a, V> {
+    pub(crate) fn new(vram: &'a mut V, state: &'a DrawState) -> Self {
+        Self { vram, state }
+    }
+
+    pub(crate) fn copy_horizontal_line(
+        &mut self,
+        x_source: u32,
+        y_source: u32,
+        x_dest: u32,
+        y_dest: u32,
+        width: u32,
+    ) {
+        for x_offset in 0..=width {
+            let mut val = self
+                .vram
+                .read(point_to_address(x_source + x_offset, y_source) as usize);
+            if self.state.force_b15 {
+                val.set_bit(15, true);
+            }
+            let addr = point_to_address(x_dest + x_offset, y_dest) as usize;
+            self.vram.write(addr, val);
+        }
+    }
+
+    fn draw_horizontal_line(
+        &mut self,
+        x1: i32,
+        x2: i32,
+        y: i32,
+        fill: u16,
+        transparent: bool,
+        clip: bool,
+    ) {
+        for x in x1..x2 {
+            if clip && self.out_of_draw_area(&Point::from_components(x, y, 0)) {
+                continue;
+            }
+            let address = point_to_address(x as u32, y as u32) as usize;
+            self.composite_and_place_pixel(address, fill, transparent, true);
+        }
+    }
+
+    fn out_of_draw_area(&self, test_point: &Point) -> bool {
+        !(test_point.x > self.state.area_tl.x
+            && test_point.x < self.state.area_br.x
+            && test_point.y > self.state.area_tl.y
+            && test_point.y < self.state.area_br.y)
+    }
+
+    // Both ends of the row in screen and texture space. Bundling them into a struct would only
+    // rename the arguments
+    #[allow(clippy::too_many_arguments)]
+    fn draw_horizontal_line_textured(
+        &mut self,
+        x1: i32,
+        x2: i32,
+        y: i32,
+        y1_tex: i32,
+        y2_tex: i32,
+        x1_tex: i32,
+        x2_tex: i32,
+        transparent: bool,
+        texture: &TextureSource,
+    ) {
+        let (start, end) = if x1 > x2 { (x2, x1) } else { (x1, x2) };
+        for x in start..end {
+            if self.out_of_draw_area(&Point::from_components(x, y, 0)) {
+                continue;
+            }
+
+            let address = point_to_address(x as u32, y as u32) as usize;
+
+            let fill = self.get_texel(
+                lerp_coords(x1_tex, x2_tex, start, end, x),
+                lerp_coords(y1_tex, y2_tex, start, end, x),
+                texture,
+            );
+
+            if fill == 0 {
+                continue;
+            }
+
+            self.composite_and_place_pixel(address, fill, transparent, false);
+        }
+    }
+
+    fn composite_and_place_pixel(
+        &mut self,
+        addr: usize,
+        fill: u16,
+        transparent: bool,
+        solid_source: bool,
+    ) {
+        // Return early if bit15 is set and we are checking the mask
+        if self.state.check_mask && self.vram.read(addr).get_bit(15) {
+            return;
+        }
+
+        if self.state.skip_field == Some((addr / VRAM_WIDTH as usize) as u32 % 2) {
+            return;
+        }
+
+        let mut color = if transparent && (fill.get_bit(15) || solid_source) {
+            alpha_composite(self.vram.read(addr), fill, &self.state.blend_mode)
+        } else {
+            fill
+        };
+
+        if self.state.force_b15 {
+            color.set_bit(15, true);
+        }
+
+        self.vram.write(addr, color);
+    }
+
+    pub(crate) fn draw_solid_box(
+        &mut self,
+        x1: i32,
+        y1: i32,
+        x2: i32,
+        y2: i32,
+        fill: u16,
+        transparent: bool,
+    ) {
+        // Offsets can push a box partly off of VRAM. Only draw the part that is actually in it
+        for y in y1.max(0)..y2.min(VRAM_HEIGHT) {
+            self.draw_horizontal_line(
+                x1.max(0),
+                x2.min(VRAM_WIDTH),
+                y,
+                fill,
+                transparent,
+                true,
+            );
+        }
+    }
+
+    /// Bresenham line with both ends drawn and the color interpolated at every pixel. Like
+    /// triangles, lines that are too big are skipped
+    pub(crate) fn draw_line(&mut self, start: &Point, end: &Point, transparent: bool) {
+        let (width, height) = ((end.x - start.x).abs(), (end.y - start.y).abs());
+        if width >= VRAM_WIDTH || height >= VRAM_HEIGHT {
+            return;
+        }
+        let (step_x, step_y) = ((end.x - start.x).signum(), (end.y - start.y).signum());
+        let steps = width.max(height);
+        let (start_color, end_color) = (b15_to_rgb(start.color), b15_to_rgb(end.color));
+
+        let (mut x, mut y) = (start.x, start.y);
+        let mut error = width - height;
+        for step in 0..=steps {
+            if x >= 0 && y >= 0 && !self.out_of_draw_area(&Point::from_components(x, y, 0)) {
+                let fill = lerp_color(start_color, end_color, step, steps);
+                let addr = point_to_address(x as u32, y as u32);
+                self.composite_and_place_pixel(addr as usize, fill, transparent, true);
+            }
+
+            let doubled = error * 2;
+            if doubled > -height {
+                error -= height;
+                x += step_x;
+            }
+            if doubled < width {
+                error += width;
+                y += step_y;
+            }
+        }
+    }
+
+    pub(crate) fn draw_textured_box(
+        &mut self,
+        tl_point: &Point,
+        width: i32,
+        height: i32,
+        transparent: bool,
+        texture: &TextureSource,
+    ) {
+        for offset in 0..height {
+            self.draw_horizontal_line_textured(
+                tl_point.x,
+                tl_point.x + width,
+                tl_point.y + offset,
+                tl_point.tex_y as i32 + offset,
+                tl_point.tex_y as i32 + offset,
+                tl_point.tex_x as i32,
+                tl_point.tex_x as i32 + width,
+                transparent,
+                texture,
+            )
+        }
+    }
+
+    /// Bounding box of a triangle, clipped to the drawing area and VRAM, so it never holds a
+    /// negative coordinate. Returns None if there is nothing to draw, including when the triangle
+    /// is too big. Real hardware skips those instead of drawing them
+    fn triangle_bounds(&self, points: &[Point]) -> Option<(i32, i32, i32, i32)> {
+        let min_x = points.iter().map(|v| v.x).min()?;
+        let max_x = points.iter().map(|v| v.x).max()?;
+
+        let min_y = points.iter().map(|v| v.y).min()?;
+        let max_y = points.iter().map(|v| v.y).max()?;
+
+        if max_x - min_x >= VRAM_WIDTH || max_y - min_y >= VRAM_HEIGHT {
+            return None;
+        }
+
+        Some((
+            min_x.max(self.state.area_tl.x).max(0),
+            max_x.min(self.state.area_br.x).min(VRAM_WIDTH - 1),
+            min_y.max(self.state.area_tl.y).max(0),
+            max_y.min(self.state.area_br.y).min(VRAM_HEIGHT - 1),
+        ))
+    }
+
+    pub(crate) fn draw_solid_triangle(&mut self, in_points: &[Point], fill: u16, transparent: bool) {
+        let points = sort_points_clockwise(in_points);
+
+        let (min_x, max_x, min_y, max_y) = match self.triangle_bounds(&points) {
+            Some(bounds) => bounds,
+            None => return,
+        };
+
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                let point = Vector2::new(x, y);
+                let inside = edge_function(&points[0], &points[1], &point) < 0
+                    && edge_function(&points[1], &points[2], &point) <= 0
+                    && edge_function(&points[2], &points[0], &point) <= 0;
+                if !self.out_of_draw_area(&Point::from_components(x, y, 0)) && inside {
+                    let addr = point_to_address(x as u32, y as u32);
+                    self.composite_and_place_pixel(addr as usize, fill, transparent, true);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn draw_shaded_triangle(&mut self, in_points: &[Point], transparent: bool) {
+        let points = sort_points_clockwise(in_points);
+
+        let (min_x, max_x, min_y, max_y) = match self.triangle_bounds(&points) {
+            Some(bounds) => bounds,
+            None => return,
+        };
+
+        let area = edge_function(
+            &points[0],
+            &points[1],
+            &Vector2::new(points[2].x, points[2].y),
+        );
+
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                let point = Vector2::new(x, y);
+                let mut w0 = edge_function(&points[1], &points[2], &point) as f32;
+                let mut w1 = edge_function(&points[2], &points[0], &point) as f32;
+                let mut w2 = edge_function(&points[0], &points[1], &point) as f32;
+
+                if !self.out_of_draw_area(&Point::from_components(x, y, 0))
+                    && w0 < 0.0
+                    && w1 <= 0.0
+                    && w2 <= 0.0
+                {
+                    let addr = point_to_address(x as u32, y as u32);
+                    w0 /= area as f32;
+                    w1 /= area as f32;
+                    w2 /= area as f32;
+
+                    // Jesus this is bad
+
+                    let c1 = b15_to_rgb(points[0].color);
+                    let c2 = b15_to_rgb(points[1].color);
+                    let c3 = b15_to_rgb(points[2].color);
+
+                    let red = (w0 * c1.0 as f32) + (w1 * c2.0 as f32) + (w2 * c3.0 as f32);
+
+                    let green = (w0 * c1.1 as f32) + (w1 * c2.1 as f32) + (w2 * c3.1 as f32);
+
+                    let blue = (w0 * c1.2 as f32) + (w1 * c2.2 as f32) + (w2 * c3.2 as f32);
+
+                    let fill = (((blue as u8 as u16) & 0x1f) << 10)
+                        | ((green as u8 as u16) << 5)
+                        | (red as u8 as u16);
+
+                    self.composite_and_place_pixel(addr as usize, fill, transparent, true);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn draw_textured_triangle(
+        &mut self,
+        in_points: &[Point],
+        transparent: bool,
+        texture: &TextureSource,
+        draw_type: TextureDraw,
+    ) {
+        let points = sort_points_clockwise(in_points);
+
+        let (min_x, max_x, min_y, max_y) = match self.triangle_bounds(&points) {
+            Some(bounds) => bounds,
+            None => return,
+        };
+
+        let area = edge_function(
+            &points[0],
+            &points[1],
+            &Vector2::new(points[2].x, points[2].y),
+        );
+
+        for x in min_x..=max_x {
+            for y in min_y..=max_y {
+                let point = Vector2::new(x, y);
+                let mut w0 = edge_function(&points[1], &points[2], &point) as f32;
+                let mut w1 = edge_function(&points[2], &points[0], &point) as f32;
+                let mut w2 = edge_function(&points[0], &points[1], &point) as f32;
+
+                if !self.out_of_draw_area(&Point::from_components(x, y, 0))
+                    && w0 < 0.0
+                    && w1 <= 0.0
+                    && w2 <= 0.0
+                {
+                    let addr = point_to_address(x as u32, y as u32);
+                    w0 /= area as f32;
+                    w1 /= area as f32;
+                    w2 /= area as f32;
+
+                    let tex_x = (w0 * points[0].tex_x as f32)
+                        + (w1 * points[1].tex_x as f32)
+                        + (w2 * points[2].tex_x as f32);
+                    let tex_y = (w0 * points[0].tex_y as f32)
+                        + (w1 * points[1].tex_y as f32)
+                        + (w2 * points[2].tex_y as f32);
+
+                    let tex_fill = self.get_texel(tex_x as i32, tex_y as i32, texture);
+
+                    if tex_fill == 0 {
+                        continue;
+                    }
+
+                    let mut final_fill = if draw_type == TextureDraw::Shaded {
+                        let c1 = b15_to_rgb(points[0].color);
+                        let c2 = b15_to_rgb(points[1].color);
+                        let c3 = b15_to_rgb(points[2].color);
+
+                        let shaded_red =
+                            ((w0 * c1.0 as f32) + (w1 * c2.0 as f32) + (w2 * c3.0 as f32)) as u16;
+                        let shaded_green =
+                            ((w0 * c1.1 as f32) + (w1 * c2.1 as f32) + (w2 * c3.1 as f32)) as u16;
+                        let shaded_blue =
+                            ((w0 * c1.2 as f32) + (w1 * c2.2 as f32) + (w2 * c3.2 as f32)) as u16;
+
+                        let shade_fill = ((shaded_blue & 0x1f) << 10)
+                            | (shaded_green << 5)
+                            | (shaded_red as u8 as u16);
+                        blend_b15(tex_fill, shade_fill)
+                    } else {
+                        tex_fill
+                    };
+
+                    if tex_fill.get_bit(15) {
+                        final_fill.set_bit(15, true);
+                    }
+
+                    self.composite_and_place_pixel(addr as usize, final_fill, transparent, false);
+                }
+            }
+        }
+    }
+
+    fn apply_texture_mask(&self, x: u32, y: u32) -> (u32, u32) {
+        let state = self.state;
+        let new_x = (x & !(state.tex_mask_x)) | (state.tex_offset_x & state.tex_mask_x);
+        let new_y = (y & !(state.tex_mask_y)) | (state.tex_offset_y & state.tex_mask_y);
+        (new_x, new_y)
+    }
+
+    fn get_texel(&self, in_x: i32, in_y: i32, texture: &TextureSource) -> u16 {
+        let (x, y) = self.apply_texture_mask((in_x as u32) % 256, (in_y as u32) % 256);
+        let TextureSource {
+            page_x,
+            page_y,
+            clut_x,
+            clut_y,
+        } = *texture;
+
+        match self.state.texmode {
+            TextureColorMode::FifteenBit => {
+                let tex_x = (page_x * 64) + x;
+                let tex_y = (page_y * 256) + y;
+                self.vram.read(point_to_address(tex_x, tex_y) as usize)
+            }
+            TextureColorMode::EightBit => {
+                let tex_x = (page_x * 64) + (x / 2);
+                let tex_y = (page_y * 256) + y;
+                let value = self.vram.read(point_to_address(tex_x, tex_y) as usize);
+                let clut_index = (value >> ((x % 2) * 8)) & 0xFF;
+                self.vram
+                    .read(point_to_address(clut_x * 16 + clut_index as u32, clut_y) as usize)
+            }
+            TextureColorMode::FourBit => {
+                let tex_x = (page_x * 64) + (x / 4);
+                let tex_y = (page_y * 256) + y;
+                let value = self.vram.read(point_to_address(tex_x, tex_y) as usize);
+                let clut_index = (value >> ((x % 4) * 4)) & 0xF;
+                self.vram
+                    .read(point_to_address(clut_x * 16 + clut_index as u32, clut_y) as usize)
+            }
+        }
+    }
+}
+
+fn edge_function(a: &Point, b: &Point, c: &Vector2<i32>) -> isize {
+    (c.x as isize - a.x as isize) * (b.y as isize - a.y as isize)
+        - (c.y as isize - a.y as isize) * (b.x as isize - a.x as isize)
+}
+
+fn lerp_coords(y0: i32, y1: i32, x0: i32, x1: i32, x: i32) -> i32 {
+    (y0 as f32 + ((y1 - y0) as f32 * ((x - x0) as f32 / (x1 - x0) as f32))) as i32
+}
+
+/// Color `step` of the way from `start` to `end`, which are `steps` apart
+fn lerp_color(start: (u8, u8, u8), end: (u8, u8, u8), step: i32, steps: i32) -> u16 {
+    if steps == 0 {
+        return rgb_to_b15(start.0, start.1, start.2);
+    }
+    let channel = |a: u8, b: u8| (a as i32 + (b as i32 - a as i32) * step / steps) as u8;
+    rgb_to_b15(
+        channel(start.0, end.0),
+        channel(start.1, end.1),
+        channel(start.2, end.2),
+    )
+}
+
+fn blend_b15(bg_color: u16, fg_color: u16) -> u16 {
+    let (b_r, b_g, b_b) = b15_to_rgb(bg_color);
+    let (f_r, f_g, f_b) = b15_to_rgb(fg_color);
+
+    let blend_r = clamp((b_r as f32 / 31.0) * ((f_r) as f32 / 31.0) * 2.0, 0.0, 1.0);
+    let blend_g = clamp((b_g as f32 / 31.0) * ((f_g) as f32 / 31.0) * 2.0, 0.0, 1.0);
+    let blend_b = clamp((b_b as f32 / 31.0) * ((f_b) as f32 / 31.0) * 2.0, 0.0, 1.0);
+
+    rgb_to_b15(
+        (blend_r * 31.0) as u8,
+        (blend_g * 31.0) as u8,
+        (blend_b * 31.0) as u8,
+    )
+}
+
+
+// TODO: Make not bad
+fn alpha_composite(background_color: u16, alpha_color: u16, mode: &BlendMode) -> u16 {
+    let (b_r, b_g, b_b) = b15_to_rgb(background_color);
+    let (a_r, a_g, a_b) = b15_to_rgb(alpha_color);
+
+    let mixed = match mode {
+        BlendMode::B2F2 => rgb_to_b15(
+            clamp((a_r / 2) as i16 + (b_r / 2) as i16, 0, 0x1F) as u8,
+            clamp((a_g / 2) as i16 + (b_g / 2) as i16, 0, 0x1F) as u8,
+            clamp((a_b / 2) as i16 + (b_b / 2) as i16, 0, 0x1F) as u8,
+        ),
+        BlendMode::BAF => rgb_to_b15(
+            clamp(a_r as i16 + b_r as i16, 0, 0x1F) as u8,
+            clamp(a_g as i16 + b_g as i16, 0, 0x1F) as u8,
+            clamp(a_b as i16 + b_b as i16, 0, 0x1F) as u8,
+        ),
+        BlendMode::BSF => rgb_to_b15(
+            clamp(b_r as i16 - a_r as i16, 0, 0x1F) as u8,
+            clamp(b_g as i16 - a_g as i16, 0, 0x1F) as u8,
+            clamp(b_b as i16 - a_b as i16, 0, 0x1F) as u8,
+        ),
+        BlendMode::BF4 => rgb_to_b15(
+            clamp(b_r as i16 + (a_r / 4) as i16, 0, 0x1F) as u8,
+            clamp(b_g as i16 + (a_g / 4) as i16, 0, 0x1F) as u8,
+            clamp(b_b as i16 + (a_b / 4) as i16, 0, 0x1F) as u8,
+        ),
+    };
+
+    mixed | (background_color & 0x8000)
+}
+
+/// Orders the points clockwise on screen, with y growing downwards. This is the winding that gives
+/// a positive MAC0 from the GTE's NCLIP, so the rasterizer and backface culling agree on which way
+/// is clockwise. The GPU itself never culls, so either winding gets drawn
+pub(super) fn sort_points_clockwise(points: &[Point]) -> Vec<Point> {
+    let center_x: i32 = points.iter().map(|p| p.x).sum::<i32>() / points.len() as i32;
+    let center_y: i32 = points.iter().map(|p| p.y).sum::<i32>() / points.len() as i32;
+
+    let center_point = Point::from_components(center_x, center_y, 0);
+
+    let mut sorted_points = points.to_vec();
+    sorted_points.sort_by(|a, b| sort_clockwise_big_match(a, b, &center_point));
+    sorted_points
+}
+
+// Stolen from https://wapl.es/rust/2020/07/25/optimising-with-cmp-and-ordering.html
+fn sort_clockwise_big_match(a: &Point, b: &Point, center: &Point) -> Ordering {
+    let d_ax = a.x - center.x;
+    let d_bx = b.x - center.x;
+
+    let cmp_ax = d_ax.cmp(&0);
+    let cmp_bx = d_bx.cmp(&0);
+
+    match (cmp_ax, cmp_bx) {
+        // d_ax >= 0 && d_bx < 0
+        (Ordering::Greater, Ordering::Less) | (Ordering::Equal, Ordering::Less) => {
+            Ordering::Greater
+        }
+        // d_ax < 0 && d_bx >= 0
+        (Ordering::Less, Ordering::Greater) | (Ordering::Less, Ordering::Equal) => Ordering::Less,
+        // d_ax == 0 && d_bx == 0
+        (Ordering::Equal, Ordering::Equal) if a.y - center.y >= 0 || b.y - center.y >= 0 => {
+            a.y.cmp(&b.y)
+        }
+        (Ordering::Equal, Ordering::Equal) => b.y.cmp(&a.y),
+        _ => {
+            // Compute the cross product of vectors (center -> a) x (center -> b)
+            let det = (d_ax) * (b.y - center.y) - (d_bx) * (a.y - center.y);
+
+            match det.cmp(&0) {
+                Ordering::Less => Ordering::Greater,
+                Ordering::Greater => Ordering::Less,
+                Ordering::Equal => {
+                    // Points a and b are on the same line from the center. Check which point is closer to
+                    // the center.
+                    let d1 = (d_ax) * (d_ax) + (a.y - center.y) * (a.y - center.y);
+                    let d2 = (d_bx) * (d_bx) + (b.y - center.y) * (b.y - center.y);
+
+                    d1.cmp(&d2)
+                }
+            }
+        }
+    }
+}
+
+/// Hashes of VRAM after drawing fixed scenes, recorded before the rasterizer moved out of the
+/// renderer. If one changes, so did what the GPU draws
+#[cfg(test)]
+mod raster_tests {
+    use crate::gpu::Gpu;
+    use crate::{hash_word, FNV_OFFSET_BASIS};
+
+    const RED: u32 = 0x0000FF;
+    const GREEN: u32 = 0x00FF00;
+    const BLUE: u32 = 0xFF0000;
+    const GREY: u32 = 0x808080;
+
+    /// Texture page 10, at (640, 0)
+    const PAGE: u32 = 0xA;
+    /// CLUT at (0, 480)
+    const CLUT: u32 = 480 << 6;
+
+    fn vertex(x: i32, y: i32) -> u32 {
+        ((y as u32 & 0xFFFF) << 16) | (x as u32 & 0xFFFF)
+    }
+
+    fn uv(u: u32, v: u32, upper: u32) -> u32 {
+        (upper << 16) | (v << 8) | u
+    }
+
+    /// Deterministic noise for texture and CLUT data
+    fn pattern(i: u32) -> u32 {
+        let x = i.wrapping_mul(0x9E37_79B9);
+        x ^ (x >> 15)
+    }
+
+    /// Draws `packets` on a GPU with the drawing area covering all of VRAM, and hashes the result
+    fn render(packets: &[&[u32]]) -> u64 {
+        let mut gpu = Gpu::new();
+        for packet in [&[0xE100_0000, 0xE300_0000, 0xE407_FFFF, 0xE500_0000][..]]
+            .iter()
+            .chain(packets)
+        {
+            gpu.start_gp0_packet();
+            for word in packet.iter() {
+                gpu.send_gp0_command(*word);
+            }
+        }
+        gpu.get_vram()
+            .chunks(4)
+            .fold(FNV_OFFSET_BASIS, |hash, pixels| {
+                hash_word(
+                    hash,
+                    pixels
+                        .iter()
+                        .rev()
+                        .fold(0, |acc, pixel| (acc << 16) | *pixel as u64),
+                )
+            })
+    }
+
+    /// A 16x16 cell texture at the page origin and a 256 entry CLUT, where entry 0 is transparent
+    /// and every fourth entry is semi transparent
+    fn texture_upload() -> Vec<Vec<u32>> {
+        let mut texture = vec![0xA000_0000, vertex(640, 0), vertex(16, 16)];
+        texture.extend((0..128).map(pattern));
+        let mut clut = vec![0xA000_0000, vertex(0, 480), vertex(256, 1)];
+        clut.extend((0..128).map(|i| {
+            let entry = |n: u32| {
+                if n == 0 {
+                    0
+                } else {
+                    (pattern(n + 1000) & 0x7FFF) | ((n % 4 == 0) as u32) << 15
+                }
+            };
+            entry(i * 2) | (entry(i * 2 + 1) << 16)
+        }));
+        vec![texture, clut]
+    }
+
+    #[test]
+    fn test_golden_untextured() {
+        let mut packets: Vec<Vec<u32>> = vec![
+            vec![0x0200_0000 | GREY, vertex(0, 0), vertex(256, 128)],
+            vec![
+                0x2000_0000 | RED,
+                vertex(10, 10),
+                vertex(60, 20),
+                vertex(20, 70),
+            ],
+            vec![
+                0x2800_0000 | GREEN,
+                vertex(70, 10),
+                vertex(120, 10),
+                vertex(70, 60),
+                vertex(125, 65),
+            ],
+            vec![
+                0x3000_0000 | RED,
+                vertex(130, 5),
+                GREEN,
+                vertex(200, 30),
+                BLUE,
+                vertex(140, 90),
+            ],
+            vec![
+                0x3800_0000 | BLUE,
+                vertex(5, 80),
+                GREEN,
+                vertex(90, 75),
+                RED,
+                vertex(10, 120),
+                GREY,
+                vertex(95, 125),
+            ],
+            vec![0x6000_0000 | GREEN, vertex(210, 10), vertex(13, 7)],
+            vec![0x6800_0000 | RED, vertex(230, 10)],
+            vec![0x7000_0000 | BLUE, vertex(210, 30)],
+            vec![0x7800_0000 | RED, vertex(225, 30)],
+        ];
+        // The same semi transparent triangle and rect in each blend mode
+        for mode in 0..4 {
+            let x = 100 + mode * 40;
+            packets.push(vec![0xE100_0000 | (mode << 5) as u32]);
+            packets.push(vec![
+                0x2200_0000 | GREEN,
+                vertex(x, 60),
+                vertex(x + 50, 70),
+                vertex(x + 10, 110),
+            ]);
+            packets.push(vec![0x6200_0000 | BLUE, vertex(x, 100), vertex(30, 20)]);
+            packets.push(vec![
+                0x3A00_0000 | RED,
+                vertex(x, 20),
+                BLUE,
+                vertex(x + 30, 25),
+                GREEN,
+                vertex(x + 5, 50),
+                GREY,
+                vertex(x + 35, 55),
+            ]);
+        }
+        let packets: Vec<&[u32]> = packets.iter().map(|p| &p[..]).collect();
+        assert_eq!(render(&packets), 14372240716038554055);
+    }
+
+    #[test]
+    fn test_golden_textured() {
+        let mut packets = texture_upload();
+        packets.push(vec![0x0200_0000 | GREY, vertex(0, 0), vertex(512, 256)]);
+        for (row, depth) in [0u32, 1, 2].into_iter().enumerate() {
+            let y = row as i32 * 80;
+            let page = PAGE | (depth << 7);
+            packets.extend([
+                vec![
+                    0x2C00_0000 | GREY,
+                    vertex(0, y),
+                    uv(0, 0, CLUT),
+                    vertex(60, y),
+                    uv(15, 0, page),
+                    vertex(0, y + 60),
+                    uv(0, 15, 0),
+                    vertex(60, y + 60),
+                    uv(15, 15, 0),
+                ],
+                vec![
+                    0x2D00_0000,
+                    vertex(70, y),
+                    uv(0, 0, CLUT),
+                    vertex(100, y + 10),
+                    uv(15, 2, page),
+                    vertex(65, y + 50),
+                    uv(3, 15, 0),
+                    vertex(110, y + 60),
+                    uv(15, 15, 0),
+                ],
+                vec![
+                    0x2E00_0000 | GREY,
+                    vertex(120, y),
+                    uv(0, 0, CLUT),
+                    vertex(180, y),
+                    uv(15, 0, page | (1 << 5)),
+                    vertex(120, y + 60),
+                    uv(0, 15, 0),
+                    vertex(180, y + 60),
+                    uv(15, 15, 0),
+                ],
+                vec![
+                    0x2400_0000 | GREY,
+                    vertex(190, y),
+                    uv(0, 0, CLUT),
+                    vertex(240, y + 20),
+                    uv(15, 4, page),
+                    vertex(200, y + 70),
+                    uv(2, 15, 0),
+                ],
+                vec![
+                    0x3C00_0000 | RED,
+                    vertex(250, y),
+                    uv(0, 0, CLUT),
+                    GREEN,
+                    vertex(310, y),
+                    uv(15, 0, page),
+                    BLUE,
+                    vertex(250, y + 60),
+                    uv(0, 15, 0),
+                    GREY,
+                    vertex(310, y + 60),
+                    uv(15, 15, 0),
+                ],
+                vec![
+                    0x3400_0000 | BLUE,
+                    vertex(320, y),
+                    uv(0, 0, CLUT),
+                    RED,
+                    vertex(370, y + 30),
+                    uv(15, 8, page),
+                    GREEN,
+                    vertex(330, y + 70),
+                    uv(4, 15, 0),
+                ],
+                // Rectangles use the page from the last draw mode command
+                vec![0xE100_0000 | page],
+                vec![
+                    0x6400_0000 | GREY,
+                    vertex(380, y),
+                    uv(0, 0, CLUT),
+                    vertex(24, 20),
+                ],
+                vec![0x7400_0000 | GREY, vertex(410, y), uv(4, 4, CLUT)],
+                vec![0x7C00_0000 | GREY, vertex(420, y), uv(0, 0, CLUT)],
+                vec![
+                    0x6600_0000 | GREY,
+                    vertex(440, y),
+                    uv(2, 3, CLUT),
+                    vertex(16, 16),
+                ],
+                // A texture window repeating the top left 8x8
+                vec![0xE200_0000 | 0x1F | (0x1F << 5)],
+                vec![0x6500_0000, vertex(460, y), uv(0, 0, CLUT), vertex(32, 32)],
+                vec![0xE200_0000],
+            ]);
+        }
+        let packets: Vec<&[u32]> = packets.iter().map(|p| &p[..]).collect();
+        assert_eq!(render(&packets), 1067401784324883286);
+    }
+
+    #[test]
+    fn test_golden_clipping_masks_and_transfers() {
+        let packets: Vec<Vec<u32>> = vec![
+            vec![0x0200_0000 | GREY, vertex(0, 0), vertex(128, 128)],
+            // Drawing area from (20, 20) to (100, 90), and an offset of (-10, 5)
+            vec![
+                0xE300_0000 | 20 | (20 << 10),
+                0xE400_0000 | 100 | (90 << 10),
+                0xE500_0000 | (0x7F6 & 0x7FF) | (5 << 11),
+            ],
+            vec![
+                0x2000_0000 | RED,
+                vertex(0, 0),
+                vertex(150, 30),
+                vertex(40, 120),
+            ],
+            vec![0x6000_0000 | GREEN, vertex(80, 70), vertex(40, 40)],
+            vec![
+                0x3000_0000 | RED,
+                vertex(-50, -50),
+                GREEN,
+                vertex(60, 10),
+                BLUE,
+                vertex(10, 60),
+            ],
+            // Set the mask bit on everything drawn, then only draw where it isn't set
+            vec![0xE300_0000, 0xE407_FFFF, 0xE500_0000],
+            vec![0xE600_0001],
+            vec![
+                0x2800_0000 | BLUE,
+                vertex(0, 100),
+                vertex(60, 100),
+                vertex(0, 160),
+                vertex(60, 160),
+            ],
+            vec![0xE600_0002],
+            vec![
+                0x2000_0000 | GREEN,
+                vertex(30, 90),
+                vertex(120, 130),
+                vertex(20, 180),
+            ],
+            vec![
+                0xA000_0000,
+                vertex(40, 150),
+                vertex(4, 4),
+                0x1234_5678,
+                0x1111_2222,
+                0x3333_4444,
+                0x5555_6666,
+                0x7777_0000,
+                0x0000_8888,
+                0x9999_AAAA,
+                0xBBBB_CCCC,
+            ],
+            vec![0xE600_0000],
+            // Copies that overlap their source, and one that wraps off the right edge
+            vec![0x8000_0000, vertex(0, 0), vertex(10, 5), vertex(64, 64)],
+            vec![
+                0x8000_0000,
+                vertex(0, 100),
+                vertex(1000, 200),
+                vertex(50, 20),
+            ],
+            vec![
+                0xA000_0000,
+                vertex(1020, 300),
+                vertex(8, 1),
+                0x0001_0002,
+                0x0003_0004,
+                0x0005_0006,
+                0x0007_0008,
+            ],
+        ];
+        let packets: Vec<&[u32]> = packets.iter().map(|p| &p[..]).collect();
+        assert_eq!(render(&packets), 653760578144716602);
+    }
+}
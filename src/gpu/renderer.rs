@@ -0,0 +1,267 @@
+use bit_field::BitField;
+
+use super::raster::Rasterizer;
+use super::{
+    point_to_address, BlendMode, Point, TextureColorMode, TextureDraw, TILES_X, TILES_Y, TILE_SIZE,
+    VRAM_WIDTH,
+};
+
+const VRAM_CELLS: usize = 1_048_576 / 2;
+
+/// The GPU's 1MB of VRAM, as 1024x512 16 bit cells
+#[derive(Clone)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub struct Vram {
+    pub(super) pixels: Vec<u16>,
+    /// Bumped on every write
+    write_generation: u64,
+    /// write_generation at the last write to each tile
+    pub(super) tile_generations: Vec<u64>,
+}
+
+impl Vram {
+    pub(super) fn new() -> Self {
+        Self {
+            pixels: vec![0; VRAM_CELLS],
+            write_generation: 0,
+            tile_generations: vec![0; (TILES_X * TILES_Y) as usize],
+        }
+    }
+
+    pub fn pixels(&self) -> &Vec<u16> {
+        &self.pixels
+    }
+
+    pub fn read(&self, addr: usize) -> u16 {
+        self.pixels[addr]
+    }
+
+    /// Every write to VRAM goes through here, so the tile generations stay up to date
+    pub fn write(&mut self, addr: usize, value: u16) {
+        self.pixels[addr] = value;
+        self.write_generation += 1;
+        let tile = (addr as u32 / VRAM_WIDTH as u32 / TILE_SIZE) * TILES_X
+            + (addr as u32 % VRAM_WIDTH as u32) / TILE_SIZE;
+        self.tile_generations[tile as usize] = self.write_generation;
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.pixels = vec![0; VRAM_CELLS];
+        self.write_generation += 1;
+        self.tile_generations.fill(self.write_generation);
+    }
+
+    /// Latest write generation of any 64x64 tile overlapping the rectangle. Renderers and texture
+    /// caches can compare this against the value from when they last read the area, and if it
+    /// changed something has been written there since. Parts outside of VRAM are ignored
+    pub fn region_generation(&self, x: u32, y: u32, width: u32, height: u32) -> u64 {
+        if width == 0 || height == 0 {
+            return 0;
+        }
+        let (first_x, first_y) = (x / TILE_SIZE, y / TILE_SIZE);
+        let last_x = ((x + width - 1) / TILE_SIZE).min(TILES_X - 1);
+        let last_y = ((y + height - 1) / TILE_SIZE).min(TILES_Y - 1);
+        (first_y..=last_y)
+            .flat_map(|tile_y| (first_x..=last_x).map(move |tile_x| tile_y * TILES_X + tile_x))
+            .map(|tile| self.tile_generations[tile as usize])
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Drawing settings from the GP0 environment commands, as they were when a primitive was sent
+#[derive(Clone, Copy, Debug)]
+pub struct DrawState {
+    /// Corners of the drawing area. Pixels on the edges aren't drawn
+    pub area_tl: Point,
+    pub area_br: Point,
+    pub blend_mode: BlendMode,
+    /// Pixels that already have bit 15 set can't be drawn over
+    pub check_mask: bool,
+    /// Sets bit 15 on every drawn pixel
+    pub force_b15: bool,
+    /// Lines with this parity aren't drawn, because an interlaced display is showing them
+    pub skip_field: Option<u32>,
+    pub texmode: TextureColorMode,
+    pub tex_mask_x: u32,
+    pub tex_mask_y: u32,
+    pub tex_offset_x: u32,
+    pub tex_offset_y: u32,
+}
+
+/// Where a textured primitive samples from. Pages are in units of 64x256 cells and CLUTs in units
+/// of 16 cells
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextureSource {
+    pub page_x: u32,
+    pub page_y: u32,
+    pub clut_x: u32,
+    pub clut_y: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PolygonFill {
+    Solid(u16),
+    /// Gouraud shaded between the colors of each point
+    Shaded,
+    Textured(TextureSource, TextureDraw),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RectFill {
+    Solid(u16),
+    Textured(TextureSource),
+}
+
+/// Draws primitives for the GPU. Points already have the drawing offset applied, and quads are
+/// split into two triangles before they get here.
+///
+/// `vram` is the emulated VRAM. The CPU, the display and the GPU's own transfers read from it, so a
+/// backend that draws somewhere else has to copy its results back in `sync_vram`
+pub trait RendererBackend: Send {
+    fn draw_triangle(
+        &mut self,
+        vram: &mut Vram,
+        state: &DrawState,
+        points: &[Point],
+        fill: PolygonFill,
+        transparent: bool,
+    );
+
+    /// Rectangle with its top left corner at `tl`, which also holds the texture coordinates
+    fn draw_rect(
+        &mut self,
+        vram: &mut Vram,
+        state: &DrawState,
+        tl: &Point,
+        width: i32,
+        height: i32,
+        fill: RectFill,
+        transparent: bool,
+    );
+
+    /// One segment of a line or polyline, including both ends. The color is interpolated from
+    /// `start` to `end`, so flat lines give both the same color
+    fn draw_line(&mut self, vram: &mut Vram, state: &DrawState, start: &Point, end: &Point, transparent: bool);
+
+    /// GP0(02) quick fill from `tl` up to, but not including, `br`
+    fn fill(&mut self, vram: &mut Vram, state: &DrawState, tl: &Point, br: &Point, color: u16);
+
+    /// GP0(80) VRAM to VRAM copy
+    fn blit(
+        &mut self,
+        vram: &mut Vram,
+        state: &DrawState,
+        source: (u32, u32),
+        dest: (u32, u32),
+        width: u32,
+        height: u32,
+    );
+
+    /// GP0(A0) CPU to VRAM transfer of `pixels`, in rows of `width`
+    fn upload(&mut self, vram: &mut Vram, state: &DrawState, x: u32, y: u32, width: u32, pixels: &[u16]);
+
+    /// Called before VRAM is read from outside of the backend, like for a VRAM to CPU transfer
+    fn sync_vram(&mut self, _vram: &mut Vram) {}
+
+    /// Called at vblank, once a frame is ready to show
+    fn end_frame(&mut self, _vram: &Vram) {}
+
+    /// Called after VRAM was changed without going through the backend, like when the GPU is reset
+    fn replace_vram(&mut self, _vram: &Vram) {}
+}
+
+/// The default backend. Rasterizes on the CPU, straight into emulated VRAM
+#[derive(Default)]
+pub struct SoftwareRenderer;
+
+impl RendererBackend for SoftwareRenderer {
+    fn draw_triangle(
+        &mut self,
+        vram: &mut Vram,
+        state: &DrawState,
+        points: &[Point],
+        fill: PolygonFill,
+        transparent: bool,
+    ) {
+        let mut rasterizer = Rasterizer::new(vram, state);
+        match fill {
+            PolygonFill::Solid(color) => rasterizer.draw_solid_triangle(points, color, transparent),
+            PolygonFill::Shaded => rasterizer.draw_shaded_triangle(points, transparent),
+            PolygonFill::Textured(texture, draw_type) => {
+                rasterizer.draw_textured_triangle(points, transparent, &texture, draw_type)
+            }
+        }
+    }
+
+    fn draw_rect(
+        &mut self,
+        vram: &mut Vram,
+        state: &DrawState,
+        tl: &Point,
+        width: i32,
+        height: i32,
+        fill: RectFill,
+        transparent: bool,
+    ) {
+        let mut rasterizer = Rasterizer::new(vram, state);
+        match fill {
+            RectFill::Solid(color) => rasterizer.draw_solid_box(
+                tl.x,
+                tl.y,
+                tl.x + width,
+                tl.y + height,
+                color,
+                transparent,
+            ),
+            RectFill::Textured(texture) => {
+                rasterizer.draw_textured_box(tl, width, height, transparent, &texture)
+            }
+        }
+    }
+
+    fn draw_line(&mut self, vram: &mut Vram, state: &DrawState, start: &Point, end: &Point, transparent: bool) {
+        Rasterizer::new(vram, state).draw_line(start, end, transparent);
+    }
+
+    fn fill(&mut self, vram: &mut Vram, state: &DrawState, tl: &Point, br: &Point, color: u16) {
+        Rasterizer::new(vram, state).draw_solid_box(tl.x, tl.y, br.x, br.y, color, false);
+    }
+
+    fn blit(
+        &mut self,
+        vram: &mut Vram,
+        state: &DrawState,
+        source: (u32, u32),
+        dest: (u32, u32),
+        width: u32,
+        height: u32,
+    ) {
+        let mut rasterizer = Rasterizer::new(vram, state);
+        for y_offset in 0..height {
+            rasterizer.copy_horizontal_line(
+                source.0,
+                source.1 + y_offset,
+                dest.0,
+                dest.1 + y_offset,
+                width,
+            );
+        }
+    }
+
+    fn upload(&mut self, vram: &mut Vram, state: &DrawState, x: u32, y: u32, width: u32, pixels: &[u16]) {
+        for (index, &pixel) in pixels.iter().enumerate() {
+            let mut val = pixel;
+            if state.force_b15 {
+                val.set_bit(15, true);
+            }
+
+            let addr = point_to_address(x + (index as u32 % width), y + (index as u32 / width));
+            if state.check_mask && vram.read(addr as usize).get_bit(15) {
+                continue;
+            }
+
+            vram.write(addr as usize, val);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::renderer::{DrawState, PolygonFill, RectFill, RendererBackend, Vram};
+use super::Point;
+
+/// A primitive or transfer waiting for the worker, with everything it needs copied out of the GPU
+enum Command {
+    Triangle {
+        state: DrawState,
+        points: Vec<Point>,
+        fill: PolygonFill,
+        transparent: bool,
+    },
+    Rect {
+        state: DrawState,
+        tl: Point,
+        width: i32,
+        height: i32,
+        fill: RectFill,
+        transparent: bool,
+    },
+    Line {
+        state: DrawState,
+        start: Point,
+        end: Point,
+        transparent: bool,
+    },
+    Fill {
+        state: DrawState,
+        tl: Point,
+        br: Point,
+        color: u16,
+    },
+    Blit {
+        state: DrawState,
+        source: (u32, u32),
+        dest: (u32, u32),
+        width: u32,
+        height: u32,
+    },
+    Upload {
+        state: DrawState,
+        x: u32,
+        y: u32,
+        width: u32,
+        pixels: Vec<u16>,
+    },
+    /// Answered once everything before it has been drawn
+    Sync,
+    ReplaceVram(Vram),
+    EndFrame,
+}
+
+/// Runs another backend on a worker thread, so primitives are rasterized while the CPU keeps
+/// going. The worker draws into its own copy of VRAM in the order primitives were sent, and
+/// `sync_vram` waits for it to catch up before copying the result back. Output is identical to
+/// running the backend directly.
+///
+/// Dropping this stops the worker, which then sends the wrapped backend back on the receiver
+/// returned by `new`
+pub(super) struct ThreadedRenderer {
+    commands: Sender<Command>,
+    synced: Receiver<()>,
+    vram: Arc<Mutex<Vram>>,
+    /// Something has been sent since the last sync
+    dirty: bool,
+}
+
+impl ThreadedRenderer {
+    pub(super) fn new(
+        mut backend: Box<dyn RendererBackend>,
+        vram: &Vram,
+    ) -> (Self, Receiver<Box<dyn RendererBackend>>) {
+        let (commands, command_rx) = channel();
+        let (synced_tx, synced) = channel();
+        let (backend_tx, backend_rx) = channel();
+        let vram = Arc::new(Mutex::new(vram.clone()));
+
+        let worker_vram = vram.clone();
+        thread::spawn(move || {
+            for command in command_rx {
+                let mut vram = worker_vram.lock().unwrap();
+                match command {
+                    Command::Triangle {
+                        state,
+                        points,
+                        fill,
+                        transparent,
+                    } => backend.draw_triangle(&mut vram, &state, &points, fill, transparent),
+                    Command::Rect {
+                        state,
+                        tl,
+                        width,
+                        height,
+                        fill,
+                        transparent,
+                    } => backend.draw_rect(&mut vram, &state, &tl, width, height, fill, transparent),
+                    Command::Line {
+                        state,
+                        start,
+                        end,
+                        transparent,
+                    } => backend.draw_line(&mut vram, &state, &start, &end, transparent),
+                    Command::Fill { state, tl, br, color } => backend.fill(&mut vram, &state, &tl, &br, color),
+                    Command::Blit {
+                        state,
+                        source,
+                        dest,
+                        width,
+                        height,
+                    } => backend.blit(&mut vram, &state, source, dest, width, height),
+                    Command::Upload {
+                        state,
+                        x,
+                        y,
+                        width,
+                        pixels,
+                    } => backend.upload(&mut vram, &state, x, y, width, &pixels),
+                    Command::Sync => {
+                        backend.sync_vram(&mut vram);
+                        let _ = synced_tx.send(());
+                    }
+                    Command::ReplaceVram(new_vram) => {
+                        *vram = new_vram;
+                        backend.replace_vram(&vram);
+                    }
+                    Command::EndFrame => {
+                        backend.end_frame(&vram);
+                        let _ = synced_tx.send(());
+                    }
+                }
+            }
+            let _ = backend_tx.send(backend);
+        });
+
+        let renderer = Self {
+            commands,
+            synced,
+            vram,
+            dirty: false,
+        };
+        (renderer, backend_rx)
+    }
+
+    fn send(&mut self, command: Command) {
+        self.dirty = true;
+        self.commands.send(command).unwrap();
+    }
+
+    /// Blocks until the worker has handled everything sent so far
+    fn wait(&mut self, command: Command) {
+        self.commands.send(command).unwrap();
+        self.synced.recv().unwrap();
+    }
+}
+
+impl RendererBackend for ThreadedRenderer {
+    fn draw_triangle(
+        &mut self,
+        _vram: &mut Vram,
+        state: &DrawState,
+        points: &[Point],
+        fill: PolygonFill,
+        transparent: bool,
+    ) {
+        self.send(Command::Triangle {
+            state: *state,
+            points: points.to_vec(),
+            fill,
+            transparent,
+        });
+    }
+
+    fn draw_rect(
+        &mut self,
+        _vram: &mut Vram,
+        state: &DrawState,
+        tl: &Point,
+        width: i32,
+        height: i32,
+        fill: RectFill,
+        transparent: bool,
+    ) {
+        self.send(Command::Rect {
+            state: *state,
+            tl: *tl,
+            width,
+            height,
+            fill,
+            transparent,
+        });
+    }
+
+    fn draw_line(&mut self, _vram: &mut Vram, state: &DrawState, start: &Point, end: &Point, transparent: bool) {
+        self.send(Command::Line {
+            state: *state,
+            start: *start,
+            end: *end,
+            transparent,
+        });
+    }
+
+    fn fill(&mut self, _vram: &mut Vram, state: &DrawState, tl: &Point, br: &Point, color: u16) {
+        self.send(Command::Fill {
+            state: *state,
+            tl: *tl,
+            br: *br,
+            color,
+        });
+    }
+
+    fn blit(
+        &mut self,
+        _vram: &mut Vram,
+        state: &DrawState,
+        source: (u32, u32),
+        dest: (u32, u32),
+        width: u32,
+        height: u32,
+    ) {
+        self.send(Command::Blit {
+            state: *state,
+            source,
+            dest,
+            width,
+            height,
+        });
+    }
+
+    fn upload(&mut self, _vram: &mut Vram, state: &DrawState, x: u32, y: u32, width: u32, pixels: &[u16]) {
+        self.send(Command::Upload {
+            state: *state,
+            x,
+            y,
+            width,
+            pixels: pixels.to_vec(),
+        });
+    }
+
+    fn sync_vram(&mut self, vram: &mut Vram) {
+        if !self.dirty {
+            return;
+        }
+        self.wait(Command::Sync);
+        vram.clone_from(&self.vram.lock().unwrap());
+        self.dirty = false;
+    }
+
+    fn end_frame(&mut self, _vram: &Vram) {
+        // The wrapped backend may hand its frame to the frontend here, so finish before returning
+        self.wait(Command::EndFrame);
+    }
+
+    fn replace_vram(&mut self, vram: &Vram) {
+        // Whatever is still queued was drawn before the change, and gets overwritten once it lands
+        self.send(Command::ReplaceVram(vram.clone()));
+    }
+}
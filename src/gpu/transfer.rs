@@ -0,0 +1,179 @@
+//! Copies between VRAM and the CPU, and within VRAM
+
+use log::trace;
+
+use super::renderer::Vram;
+use super::{point_to_address, DrawCall, DrawOperation, Gpu, Point};
+
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub(super) struct VramTransfer {
+    pub(super) base_x: usize,
+    pub(super) base_y: usize,
+    pub(super) current_x: usize,
+    pub(super) current_y: usize,
+    pub(super) width: usize,
+    pub(super) height: usize,
+}
+
+impl VramTransfer {
+    pub(super) fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self {
+            base_x: x,
+            base_y: y,
+            current_x: x,
+            current_y: y,
+            width,
+            height,
+        }
+    }
+
+    /// The next two pixels. Rows aren't padded, so with an odd width a word can hold the end of
+    /// one row and the start of the next
+    pub(super) fn next(&mut self, buf: &[u16]) -> u32 {
+        let low = self.next_pixel(buf) as u32;
+        let high = self.next_pixel(buf) as u32;
+        low | (high << 16)
+    }
+
+    fn next_pixel(&mut self, buf: &[u16]) -> u16 {
+        if self.complete() {
+            return 0;
+        }
+
+        // Transfers that run off the edge of VRAM wrap around to the other side
+        let address = point_to_address((self.current_x & 0x3FF) as u32, (self.current_y & 0x1FF) as u32);
+        self.current_x += 1;
+        if self.current_x >= self.base_x + self.width {
+            self.current_x = self.base_x;
+            self.current_y += 1;
+        }
+        buf[address as usize]
+    }
+
+    pub(super) fn complete(&self) -> bool {
+        self.current_y >= self.height + self.base_y
+    }
+}
+
+impl Gpu {
+    pub fn read_word_gp0(&mut self) -> u32 {
+        // Primitives sent during the transfer land before the rest is read
+        self.renderer.sync_vram(&mut self.vram);
+        if let Some(transfer) = &mut self.current_transfer {
+            let val = transfer.next(self.vram.pixels());
+            if transfer.complete() {
+                // That was the last of it, so GPUSTAT stops offering data
+                self.current_transfer = None;
+            }
+            val
+        } else {
+            // No transfer, return 0
+            0
+        }
+    }
+
+    /// GP0(80h) VRAM to VRAM copy
+    pub(super) fn copy_vram_to_vram(&mut self) {
+        trace!(target: "psx::gpu", "GPU: VRAM -> VRAM blit");
+        //trace!("Running VRAM to VRAM transfer");
+        let x_source = self.gp0_buffer[1] & 0xFFFF;
+        let y_source = (self.gp0_buffer[1] >> 16) & 0xFFFF;
+        let x_dest = self.gp0_buffer[2] & 0xFFFF;
+        let y_dest = (self.gp0_buffer[2] >> 16) & 0xFFFF;
+        let width = transfer_width(self.gp0_buffer[3]);
+        let height = transfer_height(self.gp0_buffer[3]);
+
+        let state = self.draw_state();
+        self.renderer.blit(
+            &mut self.vram,
+            &state,
+            (x_source, y_source),
+            (x_dest, y_dest),
+            width,
+            height,
+        );
+    }
+
+    /// GP0(A0h) CPU to VRAM copy
+    pub(super) fn copy_cpu_to_vram(&mut self) {
+        let width = transfer_width(self.gp0_buffer[2]);
+        let height = transfer_height(self.gp0_buffer[2]);
+        let length = self.gp0_buffer.len();
+
+        trace!(
+            target: "psx::gpu",
+            "GPU: CPU to VRAM length: {} ({} x {})",
+            length,
+            width,
+            height
+        );
+
+        let base_x = (self.gp0_buffer[1] & 0xFFFF) as u32;
+        let base_y = ((self.gp0_buffer[1] >> 16) & 0xFFFF) as u32;
+
+        if self.draw_logging_enabled {
+            // Calculate coordinates of transfer
+            let tl_point = Point::from_components(base_x as i32, base_y as i32, 0);
+            let mut br_point = tl_point.clone();
+            br_point.x += width as i32;
+            br_point.y += height as i32;
+
+            let call = DrawCall {
+                operation: DrawOperation::CpuBlit,
+                shading: None,
+                surface: None,
+                transparency: None,
+                points: Some(vec![tl_point, br_point]),
+                blending_enabled: false,
+                call_dropped: false,
+                clut_size: None,
+                tex_base_x: None,
+                tex_base_y: None,
+                clut_x: None,
+                clut_y: None,
+                clut: None,
+                vram_change: None,
+            };
+            self.log_call(call);
+        }
+
+        let pixels: Vec<u16> = self.gp0_buffer[3..]
+            .iter()
+            .flat_map(|word| [(word & 0xFFFF) as u16, (word >> 16) as u16])
+            .take((width * height) as usize)
+            .collect();
+        let state = self.draw_state();
+        self.renderer
+            .upload(&mut self.vram, &state, base_x, base_y, width, &pixels);
+    }
+
+    /// GP0(C0h) VRAM to CPU copy. The pixels are read back through GPUREAD
+    pub(super) fn start_vram_to_cpu(&mut self) {
+        let width = transfer_width(self.gp0_buffer[2]) as usize;
+        let height = transfer_height(self.gp0_buffer[2]) as usize;
+
+        let base_x = (self.gp0_buffer[1] & 0x3FF) as usize;
+        let base_y = ((self.gp0_buffer[1] >> 16) & 0x1FF) as usize;
+
+        trace!(target: "psx::gpu", "GPU: VRAM to CPU");
+        self.renderer.sync_vram(&mut self.vram);
+        self.current_transfer = Some(VramTransfer::new(base_x, base_y, width, height));
+    }
+}
+
+/// Transfer sizes wrap at the size of VRAM, so a width of 0 is a full 1024 pixels
+pub(super) fn transfer_width(size: u32) -> u32 {
+    ((size & 0xFFFF).wrapping_sub(1) & 0x3FF) + 1
+}
+
+pub(super) fn transfer_height(size: u32) -> u32 {
+    (((size >> 16) & 0xFFFF).wrapping_sub(1) & 0x1FF) + 1
+}
+
+/// Copies a rectangle out of VRAM, in rows of `width`
+pub(super) fn read_vram_rect(vram: &Vram, x: u16, y: u16, width: u16, height: u16) -> Vec<u16> {
+    (y..y + height)
+        .flat_map(|row| (x..x + width).map(move |column| (column, row)))
+        .map(|(column, row)| vram.read(point_to_address(column as u32, row as u32) as usize))
+        .collect()
+}
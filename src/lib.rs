@@ -1,77 +1,216 @@
-use bios::Bios;
+//! A PlayStation emulator core.
+//!
+//! Most frontends only need `psx_emu::prelude::*`, which covers creating and running a `PSXEmu`,
+//! feeding it discs and pad state, and the info and debug types its methods return. Those items and
+//! the methods on `PSXEmu` are the supported API. While the crate is at 0.x a breaking change to them
+//! bumps the minor version, and a patch release never breaks them.
+//!
+//! The `cpu`, `gpu`, `cdrom` and `controller` modules are public so debuggers and test harnesses can
+//! reach into the hardware, and so are the `r3000`, `main_bus` and `scheduler` fields. Anything not
+//! in the prelude can change in any release. Enums marked `#[non_exhaustive]` will gain variants.
+//!
+//! Features:
+//! - `serde`: Serialize and Deserialize for pad state, video modes and the other plain settings types
+//! - `savestate` (default): `PSXEmu::save_state` and `load_state`
+use std::path::Path;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+pub use bios::{BiosError, BiosInfo, BiosRegion};
 use bus::MainBus;
 use controller::ButtonState;
 use cpu::R3000;
-use gpu::{DrawCall, Resolution};
+use gpu::{DrawCall, Resolution, VideoMode};
 use timer::TimerState;
 
 use crate::cdrom::disc::Disc;
 use crate::cpu::InterruptSource;
 use crate::dma::execute_dma_cycle;
-use crate::gpu::Gpu;
-use crate::memory::Memory;
+use crate::draw_log::DrawLogError;
+use crate::memcard::MemoryCard;
 use crate::scheduler::{CpuCycles, Scheduler, ScheduleTarget};
 
 mod bios;
+mod builder;
 mod bus;
+mod cache;
 pub mod cdrom;
 pub mod controller;
 pub mod cpu;
 mod dma;
+pub mod draw_log;
+mod frame_timing;
 pub mod gpu;
 mod mdec;
+pub mod memcard;
 mod memory;
+mod mmio;
+#[cfg(feature = "savestate")]
+mod savestate;
 mod spu;
 mod timer;
 mod scheduler;
 
-static mut LOGGING: bool = false;
+pub use builder::PSXEmuBuilder;
+pub use frame_timing::FrameTiming;
+pub use memory::RamSize;
+#[cfg(feature = "savestate")]
+pub use savestate::SaveStateError;
+
+/// The supported API. Everything a frontend needs to run games
+pub mod prelude {
+    pub use crate::cdrom::disc::{Disc, DiscTrack};
+    pub use crate::cdrom::SectorBufferInfo;
+    pub use crate::controller::{ButtonState, ControllerType, PadMode};
+    pub use crate::gpu::{DrawCall, Resolution, VideoMode};
+    pub use crate::memcard::{MemoryCard, SaveInfo};
+    pub use crate::{BiosError, BiosInfo, BiosRegion, FrameTiming, PSXEmu, PSXEmuBuilder, RamSize, RunStatus};
+    #[cfg(feature = "savestate")]
+    pub use crate::SaveStateError;
+}
+
+/// System clock rate. The CPU runs an instruction every other cycle
+pub const CPU_CLOCK_HZ: u64 = 33_868_800;
 
+/// Highest multiplier `PSXEmu::set_cpu_overclock` accepts
+pub const MAX_CPU_OVERCLOCK: f32 = 4.0;
+
+/// The cpu clock multiplier is fixed point with this as 1x, so the instruction budget never drifts
+const OVERCLOCK_ONE: u32 = 256;
+/// Budget spent per instruction. At 1x the cpu runs one instruction every 2 system cycles
+const INSTRUCTION_COST: u32 = OVERCLOCK_ONE * 2;
+
+/// Why `run_frame` or `run_cycles` returned
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunStatus {
+    /// Ran everything that was asked for
+    Completed,
+    /// The program or the host asked to shut down, with this status code. Nothing runs from now on
+    ExitRequested(u16),
+}
+
+/// Emulation is deterministic. The core never reads host time and has no random state. Every memory
+/// powers on zeroed, or filled with a fixed pattern when `set_deterministic` is on, so the same inputs
+/// at the same cycles always produce the same machine state. Anything that breaks this, like seeding
+/// from the clock, would break replays and netplay. `set_deterministic` goes further for both, see there
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct PSXEmu {
     pub r3000: R3000,
     pub main_bus: MainBus,
     pub scheduler: Scheduler,
-    cpu_cycles: u32,
+    #[cfg_attr(feature = "savestate", serde(skip))]
     halt_requested: bool,
+    #[cfg_attr(feature = "savestate", serde(skip))]
     sw_breakpoints: Vec<u32>,
+    #[cfg_attr(feature = "savestate", serde(skip))]
     watchpoints: Vec<u32>,
-    frame_count: u32,
+    frame_count: u64,
+    /// Instructions and cycle count at the last call to take_frame_timing
+    timed_instructions: u64,
+    timing_start_cycle: u64,
     exit_requested: bool,
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    fast_boot: bool,
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    force_tty: bool,
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    deterministic: bool,
+    /// The executable to boot into and where it goes in RAM. Reset clears RAM, so it is copied back
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    executable: Option<(u32, Vec<u8>)>,
+    /// Cpu clock multiplier, in units of 1/OVERCLOCK_ONE
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    cpu_clock_rate: u32,
+    /// Added to every system cycle by `cpu_clock_rate`, spent by running instructions
+    instruction_budget: u32,
 }
 
 impl PSXEmu {
-    /// Creates a new instance of the emulator.
-    pub fn new(bios: Vec<u8>) -> PSXEmu {
-        let bios = Bios::new(bios);
-        let memory = Memory::new();
-        let gpu = Gpu::new();
-        let bus = MainBus::new(bios, memory, gpu);
-        let r3000 = R3000::new();
-
-        let mut emu = PSXEmu {
-            r3000: r3000,
-            main_bus: bus,
-            scheduler: Scheduler::new(),
-            cpu_cycles: 0,
-            halt_requested: false,
-            sw_breakpoints: Vec::new(),
-            watchpoints: Vec::new(),
-            frame_count: 0,
-            exit_requested: false,
-        };
-        emu.reset();
+    /// Creates a new instance of the emulator. Fails if the BIOS image isn't a valid size
+    pub fn new(bios: Vec<u8>) -> Result<PSXEmu, BiosError> {
+        Self::builder().bios(bios).build()
+    }
 
-        // Register initial events
-        emu.scheduler.schedule_event(ScheduleTarget::GpuHblank, CpuCycles(0).into());
-        emu.scheduler.schedule_event(ScheduleTarget::GpuVblank, CpuCycles(413664).into());
+    /// Starts building an emulator with non-default options
+    pub fn builder() -> PSXEmuBuilder {
+        PSXEmuBuilder::new()
+    }
 
-        emu
+    /// Sets the amount of installed RAM. Retail consoles have 2MB, mirrored 4 times in the first 8MB.
+    /// Dev consoles have 8MB, which some homebrew expects. This clears the contents of RAM.
+    pub fn set_ram_size(&mut self, size: RamSize) {
+        self.main_bus.set_ram_size(size);
+    }
+
+    /// Patches the BIOS to skip the SCE/PlayStation logo sequence. Takes effect on the next reset.
+    /// The shell never runs with this enabled, so the kernel state at game start differs slightly
+    /// from a normal boot. Keep that in mind when comparing savestates between the two.
+    pub fn set_fast_boot(&mut self, enabled: bool) {
+        self.fast_boot = enabled;
+    }
+
+    /// Patches the BIOS to enable the kernel TTY flag, so printf output from games reaches the TTY
+    /// on retail BIOSes where it is disabled. Takes effect on the next reset.
+    pub fn set_force_tty(&mut self, enabled: bool) {
+        self.force_tty = enabled;
     }
 
-    /// Resets system to startup condition
+    /// Keeps anything outside the emulated machine from affecting it, for replays and netplay. The
+    /// GPU stays on the emulator's thread and `set_threaded_gpu` is ignored. From the next reset on,
+    /// RAM, the scratchpad, VRAM and SPU RAM power on filled with a fixed pattern rather than zeros.
+    /// The SPU has no noise generator yet, so there's no other random state to seed
+    pub fn set_deterministic(&mut self, enabled: bool) {
+        self.deterministic = enabled;
+        if enabled {
+            self.main_bus.gpu.set_threaded(false);
+        }
+    }
+
+    /// Resets the whole machine to its power on state, the same as a new emulator with the same BIOS.
+    /// The disc, memory card, loaded executable, breakpoints and the host's settings are kept
     pub fn reset(&mut self) {
+        self.main_bus.reset();
+        if self.deterministic {
+            self.main_bus.fill_power_on_pattern();
+        }
+        self.restart();
+    }
+
+    /// Presses the console's reset button. RAM keeps its contents and the disc stays spun up, but
+    /// every device is reset and the BIOS boots again. Pending events and CD reads are dropped
+    pub fn soft_reset(&mut self) {
+        self.main_bus.soft_reset();
+        self.restart();
+    }
+
+    /// Restarts the cpu and scheduler from the reset vector once the bus has been reset
+    fn restart(&mut self) {
+        self.main_bus.bios.apply_patches(self.fast_boot, self.force_tty);
+        if self.fast_boot {
+            // Don't depend on which of the BIOS's setup the patch skips
+            self.main_bus.set_boot_cache_state();
+        }
         self.r3000.reset();
-        self.main_bus.gpu.reset();
+        self.scheduler = Scheduler::new();
+        self.halt_requested = false;
+        self.frame_count = 0;
+        self.timed_instructions = 0;
+        self.timing_start_cycle = 0;
+        self.exit_requested = false;
+        // Starts half an instruction ahead, so at 1x instructions run on even cycles
+        self.instruction_budget = INSTRUCTION_COST - OVERCLOCK_ONE;
+
+        if let Some((start_addr, data)) = self.executable.take() {
+            self.copy_executable(start_addr, &data);
+            self.executable = Some((start_addr, data));
+        }
+
+        // Register initial events
+        self.main_bus.gpu.start_scanlines(&mut self.scheduler);
+        let video_mode = self.main_bus.gpu.video_mode();
+        let first_vblank = video_mode.lines_to_cpu_cycles(video_mode.active_lines());
+        self.scheduler.schedule_event(ScheduleTarget::GpuVblank, first_vblank);
     }
 
     pub fn step_cycle(&mut self) {
@@ -86,50 +225,163 @@ impl PSXEmu {
         // (plz ignore the fact that scheduler is an argument, that is for later use)
         execute_dma_cycle(&mut self.r3000, &mut self.main_bus, &mut self.scheduler);
 
-        // Cpu run one instruction per 2 cycles, so only execute an instruction every other cycle
-        if self.cpu_cycles % 2 == 0 && self.run_cpu_instruction() {
-            // A branch delay slot was executed, so run an extra scheduler cycle
-            self.scheduler.run_cycle(&mut self.r3000, &mut self.main_bus);
-            self.cpu_cycles += 1
-        }
+        self.main_bus.spu.step_cycle(&mut self.main_bus.cd_drive);
 
-        self.cpu_cycles += 1;
+        // Cpu runs one instruction per 2 cycles at stock speed. Overclocking only adds instructions,
+        // everything else stays timed in system cycles
+        self.instruction_budget += self.cpu_clock_rate;
+        while self.instruction_budget >= INSTRUCTION_COST {
+            self.instruction_budget -= INSTRUCTION_COST;
+            self.run_cpu_instruction();
+        }
     }
 
-    pub fn run_cpu_instruction(&mut self) -> bool {
-        if self.sw_breakpoints.contains(&self.r3000.pc) {
+    pub fn run_cpu_instruction(&mut self) {
+        // The program is stuck on its BREAK until the host answers
+        if self.r3000.semihost_call().is_some() {
+            return;
+        }
+
+        if self.sw_breakpoints.contains(&self.r3000.current_instruction_pc()) {
             self.halt_requested = true;
-            return false;
+            return;
         }
 
         if self.watchpoints.contains(&self.r3000.last_touched_addr) {
             self.halt_requested = true;
-            return false;
+            return;
         }
 
-        self.r3000.step_instruction(&mut self.main_bus, &mut self.scheduler)
+        self.r3000.step_instruction(&mut self.main_bus, &mut self.scheduler);
+        self.timed_instructions += 1;
     }
 
-    ///Runs the emulator till one frame has been generated
-    pub fn run_frame(&mut self) {
+    ///Runs the emulator till one frame has been generated, or until the program asks to exit
+    pub fn run_frame(&mut self) -> RunStatus {
+        // Nothing runs once an exit is requested, so the frame would never finish
         while !self.frame_ready() {
+            if let Some(status) = self.exit_code() {
+                return RunStatus::ExitRequested(status);
+            }
             self.step_cycle();
         }
         self.frame_count += 1;
+        RunStatus::Completed
     }
 
-    pub fn load_executable(&mut self, start_addr: u32, entrypoint: u32, _sp: u32, data: &Vec<u8>) {
-        for (index, val) in data.iter().enumerate() {
-            self
-                .main_bus
-                .write_byte((index + start_addr as usize) as u32, val.clone(), &mut self.scheduler);
+    /// Runs `cycles` system cycles, stopping early if the program asks to exit
+    pub fn run_cycles(&mut self, cycles: u64) -> RunStatus {
+        for _ in 0..cycles {
+            if let Some(status) = self.exit_code() {
+                return RunStatus::ExitRequested(status);
+            }
+            self.step_cycle();
+        }
+        // The run can stop mid frame, so make sure VRAM shows everything drawn so far
+        self.main_bus.gpu.sync_vram();
+        match self.exit_code() {
+            Some(status) => RunStatus::ExitRequested(status),
+            None => RunStatus::Completed,
+        }
+    }
+
+    /// Runs exactly one frame with pads in both ports holding the given states. Nothing else from the
+    /// frontend is consulted, so as long as both sides of a netplay session start from the same state
+    /// and apply the same inputs on the same frames, they stay in sync.
+    /// Breakpoints and watchpoints must not be set, since halting would end the frame early on one side only
+    pub fn run_frame_with_inputs(&mut self, port1: ButtonState, port2: ButtonState) -> RunStatus {
+        self.main_bus.controllers.update_button_state(port1);
+        self.main_bus.controllers.update_port2_button_state(Some(port2));
+        self.run_frame()
+    }
+
+    /// Snapshots the whole machine, pending scheduler events included. The BIOS, disc, memory card
+    /// and the host's settings aren't part of it, so load it with the same BIOS and disc in place
+    #[cfg(feature = "savestate")]
+    pub fn save_state(&mut self) -> Vec<u8> {
+        self.main_bus.gpu.sync_vram();
+        savestate::write_state(self)
+    }
+
+    /// Restores a state from `save_state`. The BIOS, disc, memory card, breakpoints and the host's
+    /// settings stay as they are now. Nothing changes if the state can't be read
+    #[cfg(feature = "savestate")]
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), SaveStateError> {
+        let loaded = savestate::read_state(state)?;
+        let old = std::mem::replace(self, loaded);
+        self.r3000.keep_host_state(old.r3000);
+        self.main_bus.keep_host_state(old.main_bus);
+        self.sw_breakpoints = old.sw_breakpoints;
+        self.watchpoints = old.watchpoints;
+        self.fast_boot = old.fast_boot;
+        self.force_tty = old.force_tty;
+        self.deterministic = old.deterministic;
+        self.executable = old.executable;
+        self.cpu_clock_rate = old.cpu_clock_rate;
+        Ok(())
+    }
+
+    /// Fast 64 bit hash of RAM, VRAM, the cpu registers and any GPU command, CD parameters or serial
+    /// exchange still in progress. Machines that are in sync report the same hash for the same frame,
+    /// so netplay peers can exchange these to detect desyncs
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = FNV_OFFSET_BASIS;
+        for word in self.main_bus.memory.data.chunks(8) {
+            hash = hash_word(hash, word.iter().rev().fold(0, |acc, byte| (acc << 8) | *byte as u64));
+        }
+        for pixels in self.main_bus.gpu.get_vram().chunks(4) {
+            hash = hash_word(hash, pixels.iter().rev().fold(0, |acc, pixel| (acc << 16) | *pixel as u64));
+        }
+        for register in 0..32 {
+            hash = hash_word(hash, self.r3000.read_reg(register) as u64);
         }
+        for value in [
+            self.r3000.pc,
+            self.r3000.hi,
+            self.r3000.lo,
+            self.r3000.i_mask,
+            self.r3000.i_status,
+            self.r3000.cop0.read_reg(12),
+            self.r3000.cop0.read_reg(13),
+            self.r3000.cop0.read_reg(14),
+        ] {
+            hash = hash_word(hash, value as u64);
+        }
+        // Lengths go in first so an empty queue and a queue of zeroes differ
+        for state in [self.main_bus.gpu.in_flight_state(), self.main_bus.cd_drive.in_flight_state()] {
+            hash = hash_word(hash, state.len() as u64);
+            for value in state {
+                hash = hash_word(hash, value);
+            }
+        }
+        hash_word(hash, self.main_bus.controllers.in_flight_state())
+    }
+
+    pub fn load_executable(&mut self, start_addr: u32, entrypoint: u32, _sp: u32, data: &Vec<u8>) {
+        self.copy_executable(start_addr, data);
+        self.executable = Some((start_addr, data.clone()));
         self.r3000.load_exe = true;
         self.r3000.entrypoint = entrypoint;
         // self.gen_registers[29] = sp;
         // self.gen_registers[30] = sp;
     }
 
+    fn copy_executable(&mut self, start_addr: u32, data: &[u8]) {
+        for (index, val) in data.iter().enumerate() {
+            self
+                .main_bus
+                .write_byte((index + start_addr as usize) as u32, *val, &mut self.scheduler);
+        }
+        // The BIOS never loaded this, so nothing flushed the code cache or enabled the scratchpad
+        self.main_bus.set_boot_cache_state();
+    }
+
+    /// Stops a previously loaded executable from being jumped to on the next boot
+    pub fn clear_executable(&mut self) {
+        self.r3000.load_exe = false;
+        self.executable = None;
+    }
+
     pub fn load_disc(&mut self, disc: Disc) {
         self.main_bus.cd_drive.load_disc(disc);
     }
@@ -146,14 +398,50 @@ impl PSXEmu {
         self.main_bus.gpu.get_vram()
     }
 
+    /// Main RAM, for tools that need fast repeated reads. This goes straight to memory, so it
+    /// skips MMIO, watchpoints and the bus's last touched address
+    pub fn ram(&self) -> &[u8] {
+        &self.main_bus.memory.data
+    }
+
+    /// Mutable main RAM. Like `ram`, writes bypass MMIO and watchpoints
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.main_bus.memory.data
+    }
+
+    /// Reads a word of main RAM. `addr` may be in KUSEG, KSEG0 or KSEG1, including the mirrors of
+    /// RAM, and the low two bits are ignored. None if `addr` isn't RAM
+    pub fn read_u32_ram(&self, addr: u32) -> Option<u32> {
+        let memory = &self.main_bus.memory;
+        Some(memory.read_word(memory.mirror(ram_word_addr(addr)?)))
+    }
+
+    /// Writes a word of main RAM, addressed the same way as `read_u32_ram`. None, with nothing
+    /// written, if `addr` isn't RAM
+    pub fn write_u32_ram(&mut self, addr: u32, value: u32) -> Option<()> {
+        let memory = &mut self.main_bus.memory;
+        let addr = memory.mirror(ram_word_addr(addr)?);
+        memory.write_word(addr, value);
+        Some(())
+    }
+
     pub fn is_full_color_depth(&self) -> bool {
         self.main_bus.gpu.is_full_color_depth()
     }
 
+    /// Renders what the player sees into `out` as RGBA8. See `Gpu::render_display`
+    pub fn render_display(&self, out: &mut Vec<u8>) {
+        self.main_bus.gpu.render_display(out);
+    }
+
     pub fn get_bios(&self) -> &Vec<u8> {
         self.main_bus.bios.get_data()
     }
 
+    pub fn bios_info(&self) -> &BiosInfo {
+        self.main_bus.bios.info()
+    }
+
     pub fn manually_fire_interrupt(&mut self, source: InterruptSource) {
         self.r3000.fire_external_interrupt(source);
     }
@@ -175,7 +463,7 @@ impl PSXEmu {
     }
 
     pub fn add_sw_breakpoint(&mut self, addr: u32) {
-        println!("Adding breakpoint");
+        debug!(target: "psx::cpu", "Adding breakpoint at {:#X}", addr);
         self.sw_breakpoints.push(addr);
     }
 
@@ -187,10 +475,78 @@ impl PSXEmu {
         self.main_bus.gpu.resolution()
     }
 
+    /// The mode the GPU is timed with, which is what the frontend should pace frames at
+    pub fn video_mode(&self) -> VideoMode {
+        self.main_bus.gpu.video_mode()
+    }
+
+    /// Times the GPU in the given mode no matter what the game asks for with GP1(08h). GPUSTAT
+    /// still reports the requested mode, so the game carries on as if it got it. None goes back
+    /// to the requested mode. Forcing NTSC on a PAL game runs its game logic about 17% fast,
+    /// since most PAL games step once per vblank
+    pub fn set_video_mode_override(&mut self, mode: Option<VideoMode>) {
+        self.main_bus.gpu.set_video_mode_override(mode);
+    }
+
+    /// Runs the cpu `multiplier` times faster than stock, while the GPU, timers, SPU and CD drive keep
+    /// their stock timing. Games with unstable frame rates get more done per frame, but games that
+    /// time things with cycle counted busy loops will misbehave, so this is best set per game.
+    /// The multiplier is clamped to 1.0..=MAX_CPU_OVERCLOCK, and anything that isn't a number means 1x
+    pub fn set_cpu_overclock(&mut self, multiplier: f32) {
+        let multiplier = if multiplier.is_nan() { 1.0 } else { multiplier.clamp(1.0, MAX_CPU_OVERCLOCK) };
+        self.cpu_clock_rate = (multiplier * OVERCLOCK_ONE as f32).round() as u32;
+    }
+
+    /// Current cpu clock multiplier
+    pub fn cpu_overclock(&self) -> f32 {
+        self.cpu_clock_rate as f32 / OVERCLOCK_ONE as f32
+    }
+
+    pub fn dot_clock_divider(&self) -> u32 {
+        self.main_bus.gpu.dot_clock_divider()
+    }
+
+    /// Sets the state of the pad in port 1. If the game is polling the pad right now, the update
+    /// waits for the next vblank so the poll stays consistent. Otherwise the next poll sees it. Games
+    /// poll once a frame, so an update is seen within a frame either way
     pub fn update_controller_state(&mut self, state: ButtonState) {
         self.main_bus.controllers.update_button_state(state);
     }
 
+    /// Connects a pad to port 2 with the given state, or disconnects it with None. Timed like
+    /// `update_controller_state`
+    pub fn update_port2_controller_state(&mut self, state: Option<ButtonState>) {
+        self.main_bus.controllers.update_port2_button_state(state);
+    }
+
+    /// Mode of the pad in `port`, 0 or 1
+    pub fn controller_mode(&self, port: usize) -> controller::PadMode {
+        self.main_bus.controllers.mode(port)
+    }
+
+    /// Presses the analog button on the pad in `port`. A poll already in progress finishes in the
+    /// old mode
+    pub fn press_analog_button(&mut self, port: usize) {
+        self.main_bus.controllers.press_analog_button(port);
+    }
+
+    /// Inserts a card into port 1, returning the card that was there before
+    pub fn insert_memory_card(&mut self, card: MemoryCard) -> Option<MemoryCard> {
+        self.main_bus.controllers.memory_card.replace(card)
+    }
+
+    pub fn remove_memory_card(&mut self) -> Option<MemoryCard> {
+        self.main_bus.controllers.memory_card.take()
+    }
+
+    pub fn memory_card(&self) -> Option<&MemoryCard> {
+        self.main_bus.controllers.memory_card.as_ref()
+    }
+
+    pub fn memory_card_mut(&mut self) -> Option<&mut MemoryCard> {
+        self.main_bus.controllers.memory_card.as_mut()
+    }
+
     pub fn frame_ready(&mut self) -> bool {
         self.main_bus.gpu.take_frame_ready()
     }
@@ -207,8 +563,50 @@ impl PSXEmu {
         self.main_bus.gpu.clear_call_log();
     }
 
+    /// Snapshot CLUTs into the GPU log, so paletted calls can still be shown correctly after the CLUT is overwritten
+    pub fn set_gpu_deep_capture(&mut self, enabled: bool) {
+        self.main_bus.gpu.set_deep_capture(enabled);
+    }
+
+    /// Drop triangles that are too big to draw, like the hardware does. On by default. Turning
+    /// it off can bring back geometry a game relies on an enhancement to hide
+    pub fn set_gpu_strict_size_check(&mut self, enabled: bool) {
+        self.main_bus.gpu.set_strict_size_check(enabled);
+    }
+
+    /// Draws primitives with a different backend from now on. The software renderer is the default
+    pub fn set_renderer(&mut self, renderer: Box<dyn gpu::RendererBackend>) {
+        self.main_bus.gpu.set_renderer(renderer);
+    }
+
+    /// Draws primitives on a worker thread while the CPU keeps running. Off by default. The output
+    /// is the same either way, since primitives are still drawn in the order they were sent
+    pub fn set_threaded_gpu(&mut self, enabled: bool) {
+        if enabled && self.deterministic {
+            warn!("The GPU can't be threaded in deterministic mode");
+            return;
+        }
+        self.main_bus.gpu.set_threaded(enabled);
+    }
+
+    /// Saves the calls logged since the log was last taken or cleared
+    pub fn export_gpu_log(&self, path: &Path) -> std::io::Result<()> {
+        draw_log::save_draw_log(self.main_bus.gpu.call_log(), path)
+    }
+
+    /// Loads a log saved by export_gpu_log
+    pub fn load_gpu_log(path: &Path) -> Result<Vec<DrawCall>, DrawLogError> {
+        draw_log::load_draw_log(path)
+    }
+
+    /// Takes the 44.1KHz stereo samples the SPU has mixed since the last call
+    pub fn take_audio_samples(&mut self) -> Vec<(i16, i16)> {
+        self.main_bus.spu.drain_output()
+    }
+
     pub fn add_watchpoint(&mut self, addr: u32) {
-        println!(
+        debug!(
+            target: "psx::cpu",
             "Adding watchpoint for addr {:#X} ({:#X} masked)",
             addr,
             addr & 0x1fffffff
@@ -220,29 +618,802 @@ impl PSXEmu {
         self.watchpoints.retain(|&x| x != addr & 0x1FFFFFFF);
     }
 
+    /// Fetch pointer. Usually the next instruction to run, but it has already moved past a
+    /// semihosting BREAK that is waiting on the host. Debuggers want `current_instruction_pc`
     pub fn pc(&self) -> u32 {
         self.r3000.pc
     }
 
+    /// Address of the instruction the CPU is on. This is where a breakpoint halts, including
+    /// breakpoints on a delay slot
+    pub fn current_instruction_pc(&self) -> u32 {
+        self.r3000.current_instruction_pc()
+    }
+
     pub fn display_origin(&self) -> (usize, usize) {
         self.main_bus.gpu.display_origin()
     }
 
+    /// See `Gpu::draw_area`
+    pub fn draw_area(&self) -> ((u32, u32), (u32, u32)) {
+        self.main_bus.gpu.draw_area()
+    }
+
+    /// Latest BIOS boot stage code written to the POST register
+    pub fn post_code(&self) -> u8 {
+        self.main_bus.post_code()
+    }
+
     pub fn get_irq_mask(&self) -> u32 {
         self.r3000.i_mask
     }
 
+    /// True once the program or the host has asked to shut down. `run_frame` and `run_cycles`
+    /// report this too, so there is usually no need to poll it
     pub fn exit_requested(&self) -> bool {
+        self.exit_requested
+    }
+
+    /// Shuts the machine down from the host side, as if the program had exited with `status`
+    pub fn request_exit(&mut self, status: u16) {
+        self.main_bus.exit_requested = true;
+        self.main_bus.exit_code = status;
+        self.exit_requested = true;
+    }
+
+    /// Logs every memory access the CPU makes. Very slow
+    pub fn set_memory_logging(&mut self, enabled: bool) {
+        self.main_bus.memory_logging = enabled;
+    }
+
+    /// Enables the test interface, which lets programs log text and exit with a status code. Off
+    /// by default, so retail software can't trip it
+    pub fn set_test_harness(&mut self, enabled: bool) {
+        self.main_bus.test_harness = enabled;
+    }
+
+    /// Lets programs ask the host for file I/O by executing BREAK `cpu::SEMIHOST_BREAK_CODE`.
+    /// The CPU waits on the BREAK until the frontend answers with `finish_semihost_call`, while the
+    /// rest of the machine keeps running. Off by default, since BREAK normally raises an exception
+    pub fn set_semihosting(&mut self, enabled: bool) {
+        self.r3000.semihosting = enabled;
+    }
+
+    /// The semihosting call the program is waiting on, if any
+    pub fn semihost_call(&self) -> Option<cpu::SemihostCall> {
+        self.r3000.semihost_call()
+    }
+
+    /// Answers the pending semihosting call. The program sees `result` in v0
+    pub fn finish_semihost_call(&mut self, result: u32) {
+        self.r3000.finish_semihost_call(result);
+    }
+
+    /// Takes everything the program has printed through the BIOS TTY calls since the last call
+    pub fn take_tty_output(&mut self) -> String {
+        self.r3000.take_tty_output()
+    }
+
+    /// Takes everything the program has written to the test log since the last call
+    pub fn take_test_log(&mut self) -> String {
+        std::mem::take(&mut self.main_bus.test_log)
+    }
+
+    /// Frames emulated since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// System cycles emulated since power on
+    pub fn cycle_count(&self) -> u64 {
+        self.scheduler.current_cycle
+    }
+
+    /// Takes the breakdown of emulated cycles since the last call. Call it once a frame, after
+    /// `run_frame`, to get per frame numbers
+    pub fn take_frame_timing(&mut self) -> FrameTiming {
+        let timing = FrameTiming {
+            cycles: self.cycle_count() - self.timing_start_cycle,
+            instructions: self.timed_instructions,
+            stall_cycles: 0,
+            dma_cycles: self.main_bus.dma.take_transferred_words(),
+            events: self.scheduler.take_fired_events(),
+            gpu: self.main_bus.gpu.take_stats(),
+        };
+        self.timing_start_cycle = self.cycle_count();
+        self.timed_instructions = 0;
+        timing
+    }
+
+    /// How much time has passed on the emulated machine since power on
+    pub fn emulated_time(&self) -> Duration {
+        let cycles = self.cycle_count();
+        let seconds = cycles / CPU_CLOCK_HZ;
+        let nanos = (cycles % CPU_CLOCK_HZ) * 1_000_000_000 / CPU_CLOCK_HZ;
+        Duration::new(seconds, nanos as u32)
+    }
+
+    /// Status code passed by the program when it asked to exit, or None if it hasn't
+    pub fn exit_code(&self) -> Option<u16> {
         if self.exit_requested {
-            return true;
+            Some(self.main_bus.exit_code)
         } else {
-            return false;
+            None
         }
     }
 }
 
-pub fn toggle_memory_logging(enabled: bool) {
-    unsafe {
-        LOGGING = enabled;
+const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+/// FNV-1a, but mixing in a whole word at a time so hashing all of RAM each frame stays cheap
+/// Physical address of the RAM word `addr` points at. KSEG2 holds nothing but cache control, so
+/// only the lower segments are accepted
+fn ram_word_addr(addr: u32) -> Option<u32> {
+    let physical = addr & 0x1FFF_FFFC;
+    (addr < 0xC000_0000 && physical <= mmio::RAM_END).then_some(physical)
+}
+
+fn hash_word(hash: u64, word: u64) -> u64 {
+    (hash ^ word).wrapping_mul(FNV_PRIME)
+}
+
+#[cfg(test)]
+mod emu_tests {
+    use super::*;
+
+    const CODE_ADDR: u32 = 0x8002_0000;
+    const FRAMES: usize = 600;
+
+    fn i_type(op: u32, rs: u32, rt: u32, imm: u16) -> u32 {
+        (op << 26) | (rs << 21) | (rt << 16) | imm as u32
+    }
+
+    fn r_type(funct: u32, rs: u32, rt: u32, rd: u32, shamt: u32) -> u32 {
+        (rs << 21) | (rt << 16) | (rd << 11) | (shamt << 6) | funct
+    }
+
+    /// Scribbles pseudo random words over RAM and fills random squares of VRAM. Timer 0 is mixed into
+    /// the seed each iteration, so any timing difference between runs changes everything after it
+    fn scribble_exe() -> Vec<u8> {
+        let (t0, t1, t2, t3, t5, t6, t7, s0, s1, t9) = (8, 9, 10, 11, 13, 14, 15, 16, 17, 25);
+        let code = [
+            i_type(0x0F, 0, s0, 0x8003),        // lui s0, 0x8003
+            i_type(0x0F, 0, s1, 0x1F80),        // lui s1, 0x1F80
+            i_type(0x0D, 0, t0, 0x1234),        // ori t0, zero, 0x1234
+            i_type(0x0F, 0, t6, 0x0200),        // lui t6, 0x0200
+            i_type(0x0F, 0, t9, 0x41C6),        // lui t9, 0x41C6
+            i_type(0x0D, t9, t9, 0x4E6D),       // ori t9, t9, 0x4E6D
+            i_type(0x23, s1, t1, 0x1100),       // loop: lw t1, 0x1100(s1)
+            0,                                  // nop
+            r_type(0x26, t0, t1, t0, 0),        // xor t0, t0, t1
+            r_type(0x19, t0, t9, 0, 0),         // multu t0, t9
+            r_type(0x12, 0, 0, t0, 0),          // mflo t0
+            i_type(0x09, t0, t0, 12345),        // addiu t0, t0, 12345
+            i_type(0x0C, t0, t2, 0xFFFC),       // andi t2, t0, 0xFFFC
+            r_type(0x21, s0, t2, t3, 0),        // addu t3, s0, t2
+            i_type(0x2B, t3, t0, 0),            // sw t0, 0(t3)
+            r_type(0x00, 0, t0, t5, 8),         // sll t5, t0, 8
+            r_type(0x02, 0, t5, t5, 8),         // srl t5, t5, 8
+            r_type(0x25, t5, t6, t5, 0),        // or t5, t5, t6
+            i_type(0x2B, s1, t5, 0x1810),       // sw t5, 0x1810(s1)   fill rect
+            i_type(0x0C, t0, t7, 0x01F0),       // andi t7, t0, 0x1F0
+            i_type(0x2B, s1, t7, 0x1810),       // sw t7, 0x1810(s1)   at (t7, 0)
+            i_type(0x0F, 0, t7, 0x0010),        // lui t7, 0x10
+            i_type(0x0D, t7, t7, 0x0010),       // ori t7, t7, 0x10
+            i_type(0x2B, s1, t7, 0x1810),       // sw t7, 0x1810(s1)   16x16
+            (0x02 << 26) | ((CODE_ADDR + 6 * 4) & 0x0FFF_FFFF) >> 2, // j loop
+            0,                                  // nop
+        ];
+        code.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+    }
+
+    fn frame_hashes() -> Vec<md5::Digest> {
+        // A zeroed BIOS runs nops until the fast exe load hook
+        let mut emu = PSXEmu::builder().bios(vec![0; bios::BIOS_SIZE]).deterministic(true).build().unwrap();
+        // Ignored, the GPU stays on this thread
+        emu.set_threaded_gpu(true);
+        emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &scribble_exe());
+
+        (0..FRAMES)
+            .map(|_| {
+                emu.run_frame();
+                let mut context = md5::Context::new();
+                context.consume(&emu.main_bus.memory.data);
+                let vram: Vec<u8> = emu.get_vram().iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+                context.consume(&vram);
+                context.compute()
+            })
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "emulates 2000 frames. Run with cargo test --release -- --ignored"]
+    fn test_netplay_instances_stay_in_sync() {
+        let mut instances: Vec<PSXEmu> = (0..2)
+            .map(|_| {
+                let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+                emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &scribble_exe());
+                emu
+            })
+            .collect();
+
+        for frame in 0..1000 {
+            let mut port1 = ButtonState::new_digital_pad();
+            port1.button_x = frame % 3 == 0;
+            port1.button_left = frame % 7 < 3;
+            let mut port2 = ButtonState::new_digital_pad();
+            port2.button_start = frame % 60 == 0;
+
+            let hashes: Vec<u64> = instances
+                .iter_mut()
+                .map(|emu| {
+                    emu.run_frame_with_inputs(port1, port2);
+                    emu.state_hash()
+                })
+                .collect();
+            assert_eq!(hashes[0], hashes[1], "Desync at frame {}", frame);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "savestate")]
+    fn test_save_state_round_trip() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &scribble_exe());
+        for _ in 0..10 {
+            emu.run_frame();
+        }
+        // Mid frame, so restoring the wrong events would put the next vblank somewhere else
+        emu.run_cycles(12345);
+        let state = emu.save_state();
+        let snapshot = |emu: &PSXEmu| {
+            let registers: Vec<u32> = (0..32).map(|reg| emu.read_gen_reg(reg)).collect();
+            (emu.get_vram().clone(), registers, emu.pc(), emu.cycle_count(), emu.state_hash())
+        };
+        let saved = snapshot(&emu);
+
+        for _ in 0..10 {
+            emu.run_frame();
+        }
+        let later = snapshot(&emu);
+        assert_ne!(later.4, saved.4);
+
+        emu.load_state(&state).unwrap();
+        assert!(snapshot(&emu) == saved);
+        // The scheduler's events come back with their timestamps, so the same frames play out again
+        for _ in 0..10 {
+            emu.run_frame();
+        }
+        assert!(snapshot(&emu) == later);
+    }
+
+    /// Starts a 16x32 CPU to VRAM upload, then streams words into GP0 forever. The upload is
+    /// buffered until its last word arrives
+    #[cfg(feature = "savestate")]
+    fn upload_exe() -> Vec<u8> {
+        let (t0, s1) = (8, 17);
+        let code = [
+            i_type(0x0F, 0, s1, 0x1F80),        // lui s1, 0x1F80
+            i_type(0x0F, 0, t0, 0xA000),        // lui t0, 0xA000
+            i_type(0x2B, s1, t0, 0x1810),       // sw t0, 0x1810(s1)   copy to VRAM
+            i_type(0x2B, s1, 0, 0x1810),        // sw zero, 0x1810(s1) at (0, 0)
+            i_type(0x0F, 0, t0, 0x0020),        // lui t0, 0x20
+            i_type(0x0D, t0, t0, 0x0010),       // ori t0, t0, 0x10
+            i_type(0x2B, s1, t0, 0x1810),       // sw t0, 0x1810(s1)   16x32
+            i_type(0x2B, s1, t0, 0x1810),       // loop: sw t0, 0x1810(s1)
+            (0x02 << 26) | ((CODE_ADDR + 7 * 4) & 0x0FFF_FFFF) >> 2, // j loop
+            i_type(0x09, t0, t0, 0x1111),       // addiu t0, t0, 0x1111
+        ];
+        code.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+    }
+
+    /// Tests that need a real BIOS read it from the path in FOGSTATION_TEST_BIOS. None if it isn't set
+    #[cfg(feature = "savestate")]
+    fn test_bios() -> Option<Vec<u8>> {
+        let path = std::env::var("FOGSTATION_TEST_BIOS").ok()?;
+        Some(std::fs::read(&path).unwrap_or_else(|e| panic!("Unable to read {}: {}", path, e)))
+    }
+
+    /// Hash of everything a save state holds, scheduler, timers, DMA and SPU included
+    #[cfg(feature = "savestate")]
+    fn save_state_hash(emu: &mut PSXEmu) -> u64 {
+        emu.save_state()
+            .chunks(8)
+            .fold(FNV_OFFSET_BASIS, |hash, word| {
+                hash_word(hash, word.iter().rev().fold(0, |acc, byte| (acc << 8) | *byte as u64))
+            })
+    }
+
+    #[test]
+    #[cfg(feature = "savestate")]
+    #[ignore = "boots a BIOS and saves and loads 3000 states. Run with FOGSTATION_TEST_BIOS set and cargo test --release -- --ignored"]
+    fn test_save_state_every_cycle() {
+        const CYCLES: usize = 3000;
+        let Some(bios) = test_bios() else {
+            warn!("FOGSTATION_TEST_BIOS isn't set, skipping the save state test");
+            return;
+        };
+        let mut emu = PSXEmu::new(bios).unwrap();
+        emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &upload_exe());
+        // The BIOS boots the kernel, then the fast exe load hook jumps to the upload
+        while emu.pc() != CODE_ADDR + 7 * 4 {
+            emu.step_cycle();
+            assert!(emu.cycle_count() < CPU_CLOCK_HZ * 10, "The BIOS never reached the executable");
+        }
+        // In the middle of the upload, with the next word on its way
+        emu.run_cycles(101);
+        let start = emu.save_state();
+        let start_vram = emu.get_vram().clone();
+
+        let mut reference = vec![];
+        for _ in 0..CYCLES {
+            emu.step_cycle();
+            reference.push(save_state_hash(&mut emu));
+        }
+        let reference_vram = emu.get_vram().clone();
+
+        emu.load_state(&start).unwrap();
+        for (cycle, expected) in reference.iter().enumerate() {
+            let state = emu.save_state();
+            emu.load_state(&state).unwrap();
+            emu.step_cycle();
+            assert_eq!(save_state_hash(&mut emu), *expected, "Diverged at cycle {}", cycle);
+        }
+        assert!(emu.get_vram() == &reference_vram);
+        // The upload finished partway through
+        assert!(reference_vram != start_vram);
+    }
+
+    #[test]
+    #[cfg(feature = "savestate")]
+    fn test_load_state_rejects_other_data() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        let mut state = emu.save_state();
+        assert!(matches!(emu.load_state(b"FSDL"), Err(SaveStateError::NotASaveState)));
+        state.truncate(100);
+        assert!(matches!(emu.load_state(&state), Err(SaveStateError::Corrupt(_))));
+        state[4] = 99;
+        assert!(matches!(emu.load_state(&state), Err(SaveStateError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn test_spu_manual_write_upload() {
+        const SPUCNT: u32 = 0x1F80_1DAA;
+        const SPUSTAT: u32 = 0x1F80_1DAE;
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        let data: Vec<u16> = (0..512).map(|i| (i * 0x0101) as u16).collect();
+        let status = |emu: &mut PSXEmu| emu.main_bus.read_half_word(SPUSTAT, &mut emu.scheduler);
+
+        // 1KB, filled a FIFO's worth at a time the way the BIOS does it
+        emu.main_bus.write_half_word(0x1F80_1DA6, 0x200, &mut emu.scheduler);
+        for chunk in data.chunks(32) {
+            emu.main_bus.write_half_word(SPUCNT, 0x8000, &mut emu.scheduler);
+            for half_word in chunk {
+                emu.main_bus.write_half_word(0x1F80_1DA8, *half_word, &mut emu.scheduler);
+            }
+            assert_eq!(status(&mut emu) & 0x400, 0);
+
+            emu.main_bus.write_half_word(SPUCNT, 0x8010, &mut emu.scheduler);
+            assert_eq!(status(&mut emu) & 0x3F, 0x10);
+            let mut cycles = 0;
+            while status(&mut emu) & 0x400 != 0 {
+                emu.step_cycle();
+                cycles += 1;
+            }
+            assert!((chunk.len() * 16..chunk.len() * 16 + 16).contains(&cycles));
+        }
+
+        emu.main_bus.write_half_word(SPUCNT, 0x8000, &mut emu.scheduler);
+        emu.main_bus.write_half_word(0x1F80_1DA6, 0x200, &mut emu.scheduler);
+        let read_back: Vec<u16> = (0..data.len())
+            .map(|_| emu.main_bus.read_half_word(0x1F80_1DA8, &mut emu.scheduler))
+            .collect();
+        assert_eq!(read_back, data);
+    }
+
+    #[test]
+    fn test_state_hash_sees_ram_changes() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        let before = emu.state_hash();
+        emu.main_bus.memory.data[0x1234] = 1;
+        assert_ne!(emu.state_hash(), before);
+    }
+
+    #[test]
+    fn test_state_hash_sees_commands_in_flight() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        let mut hashes = vec![emu.state_hash()];
+
+        // Half of a CPU to VRAM upload
+        emu.main_bus.gpu.send_gp0_command(0xA000_0000);
+        hashes.push(emu.state_hash());
+        emu.main_bus.gpu.send_gp0_command(0);
+        hashes.push(emu.state_hash());
+
+        // A CD parameter, with nothing sent yet
+        emu.main_bus.write_byte(0x1F80_1802, 0, &mut emu.scheduler);
+        hashes.push(emu.state_hash());
+
+        // Enabling the serial port
+        emu.main_bus.write_half_word(0x1F80_104A, 1, &mut emu.scheduler);
+        hashes.push(emu.state_hash());
+
+        for (index, hash) in hashes.iter().enumerate() {
+            assert!(!hashes[..index].contains(hash), "step {} didn't change the hash", index);
+        }
+    }
+
+    #[test]
+    fn test_harness_is_opt_in() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.main_bus.write_byte(bus::TEST_LOG_ADDR, b'!', &mut emu.scheduler);
+        emu.main_bus.write_half_word(bus::TEST_EXIT_ADDR, 1, &mut emu.scheduler);
+        emu.step_cycle();
+        assert_eq!(emu.take_test_log(), "");
+        assert_eq!(emu.exit_code(), None);
+
+        emu.set_test_harness(true);
+        emu.main_bus.write_byte(bus::TEST_LOG_ADDR, b'!', &mut emu.scheduler);
+        emu.main_bus.write_half_word(bus::TEST_EXIT_ADDR, 1, &mut emu.scheduler);
+        emu.step_cycle();
+        assert_eq!(emu.take_test_log(), "!");
+        assert_eq!(emu.exit_code(), Some(1));
+    }
+
+    #[test]
+    fn test_run_reports_exit() {
+        let mut emu = idle_emu();
+        assert_eq!(emu.run_cycles(100), RunStatus::Completed);
+        assert_eq!(emu.run_frame(), RunStatus::Completed);
+
+        emu.request_exit(3);
+        let cycles = emu.cycle_count();
+        assert_eq!(emu.run_frame(), RunStatus::ExitRequested(3));
+        assert!(emu.exit_requested());
+        // Nothing runs after the exit
+        assert_eq!(emu.run_cycles(100), RunStatus::ExitRequested(3));
+        assert_eq!(emu.run_frame(), RunStatus::ExitRequested(3));
+        assert_eq!(emu.cycle_count(), cycles);
+    }
+
+    #[test]
+    fn test_emulated_time() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &scribble_exe());
+        for _ in 0..10 {
+            emu.step_cycle();
+        }
+        assert_eq!(emu.cycle_count(), 10);
+
+        // Ten minutes is well past where a 32 bit cycle counter would have wrapped
+        emu.scheduler.current_cycle = CPU_CLOCK_HZ * 600 + CPU_CLOCK_HZ / 4;
+        assert!(emu.cycle_count() > u32::MAX as u64);
+        assert_eq!(emu.emulated_time(), Duration::from_millis(600_250));
+    }
+
+    #[test]
+    fn test_frame_timing() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &scribble_exe());
+        emu.run_frame();
+        emu.take_frame_timing();
+
+        // Clear a 16 entry ordering table over DMA6
+        emu.main_bus.write_word(0x1F80_10F0, 0x0800_0000, &mut emu.scheduler);
+        emu.main_bus.write_word(0x1F80_10E0, 0x0010_0040, &mut emu.scheduler);
+        emu.main_bus.write_word(0x1F80_10E4, 16, &mut emu.scheduler);
+        emu.main_bus.write_word(0x1F80_10E8, 0x1100_0002, &mut emu.scheduler);
+        emu.run_frame();
+
+        let timing = emu.take_frame_timing();
+        assert!(timing.cycles > 500_000);
+        assert!(timing.instructions.abs_diff(timing.cycles / 2) <= 1);
+        assert_eq!(timing.stall_cycles, 0);
+        assert_eq!(timing.dma_cycles, 16);
+        // One event starts vblank and another ends it
+        assert_eq!(timing.events["GPU vblank"], 2);
+        assert!(timing.events["GPU hblank"] > 200);
+
+        // Everything was taken, so nothing is left until more cycles run
+        assert_eq!(emu.take_frame_timing(), FrameTiming::default());
+    }
+
+    fn scribble_emu() -> PSXEmu {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &scribble_exe());
+        emu
+    }
+
+    #[test]
+    fn test_instances_are_independent() {
+        let mut first = scribble_emu();
+        let mut second = scribble_emu();
+        first.set_memory_logging(true);
+        first.run_frame();
+        let first_frame = first.state_hash();
+        first.run_frame();
+        first.run_frame();
+
+        second.run_frame();
+        assert_eq!(second.state_hash(), first_frame);
+    }
+
+    #[test]
+    fn test_deterministic_power_on() {
+        let build = || PSXEmu::builder().bios(vec![0; bios::BIOS_SIZE]).deterministic(true).build().unwrap();
+        let (mut first, second) = (build(), build());
+        assert!(first.ram().iter().any(|byte| *byte != 0));
+        assert!(first.get_vram().iter().any(|pixel| *pixel != 0));
+        assert_eq!(first.state_hash(), second.state_hash());
+
+        first.main_bus.memory.data.fill(0);
+        first.reset();
+        assert_eq!(first.state_hash(), second.state_hash());
+        assert_eq!(PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap().ram().iter().max(), Some(&0));
+    }
+
+    #[test]
+    fn test_reset_matches_new() {
+        let mut emu = scribble_emu();
+        emu.run_frame();
+        let first_frame = emu.state_hash();
+        emu.run_frame();
+        emu.run_frame();
+
+        emu.reset();
+        assert_eq!(emu.cycle_count(), 0);
+        emu.run_frame();
+        assert_eq!(emu.state_hash(), first_frame);
+    }
+
+    #[test]
+    fn test_step_from_reset_vector() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        assert_eq!(emu.current_instruction_pc(), 0xBFC0_0000);
+        emu.run_cpu_instruction();
+        assert_eq!(emu.current_instruction_pc(), 0xBFC0_0004);
+        // Stepping the cpu alone doesn't run any scheduled events
+        assert_eq!(emu.cycle_count(), 0);
+        assert_eq!(emu.frame_count(), 0);
+    }
+
+    #[test]
+    fn test_soft_reset_keeps_ram() {
+        let mut emu = scribble_emu();
+        emu.run_frame();
+        emu.write_u32_ram(0x8000_1000, 0xDEAD_BEEF).unwrap();
+
+        emu.soft_reset();
+        assert_eq!(emu.cycle_count(), 0);
+        assert_eq!(emu.read_u32_ram(0x1000), Some(0xDEAD_BEEF));
+
+        emu.reset();
+        assert_eq!(emu.read_u32_ram(0x1000), Some(0));
+    }
+
+    /// A machine spinning in a loop at CODE_ADDR. Interrupts are never enabled, so nothing in the
+    /// machine acknowledges I_STAT
+    fn idle_emu() -> PSXEmu {
+        let idle_loop = [(0x02 << 26) | (CODE_ADDR & 0x0FFF_FFFF) >> 2, 0];
+        let exe = idle_loop.iter().flat_map(|inst: &u32| inst.to_le_bytes()).collect();
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &exe);
+        emu
+    }
+
+    #[test]
+    fn test_one_vblank_irq_per_frame() {
+        let mut emu = idle_emu();
+        let (mut frames, mut irqs) = (0, 0);
+        while frames < 5 {
+            emu.step_cycle();
+            if emu.r3000.i_status & 1 != 0 {
+                irqs += 1;
+                emu.r3000.i_status &= !1;
+            }
+            if emu.frame_ready() {
+                frames += 1;
+                // The frame ends as vblank starts, where GPUSTAT reports an even line
+                let stat = emu.main_bus.read_word(0x1F80_1814, &mut emu.scheduler);
+                assert_eq!(stat & (1 << 31), 0);
+            }
+        }
+        assert_eq!(irqs, 5);
+    }
+
+    #[test]
+    fn test_video_mode_override() {
+        let mut emu = idle_emu();
+        // The game asks for PAL
+        emu.main_bus.write_word(0x1F80_1814, 0x0800_0008, &mut emu.scheduler);
+        emu.set_video_mode_override(Some(VideoMode::Ntsc));
+        assert_eq!(emu.video_mode(), VideoMode::Ntsc);
+
+        // Let the events scheduled before the override run out
+        emu.run_frame();
+        emu.run_frame();
+        emu.take_frame_timing();
+        emu.run_frame();
+        let ntsc = VideoMode::Ntsc;
+        let frame_cycles = ntsc.lines_to_cpu_cycles(ntsc.lines_per_frame());
+        assert!(emu.take_frame_timing().cycles.abs_diff(frame_cycles.0 as u64) <= 2);
+
+        let stat = emu.main_bus.read_word(0x1F80_1814, &mut emu.scheduler);
+        assert_ne!(stat & (1 << 20), 0);
+
+        emu.set_video_mode_override(None);
+        assert_eq!(emu.video_mode(), VideoMode::Pal);
+    }
+
+    #[test]
+    fn test_cpu_overclock() {
+        let mut emu = idle_emu();
+        // The first frame only runs up to the first vblank
+        emu.run_frame();
+        emu.take_frame_timing();
+        emu.run_frame();
+        let stock = emu.take_frame_timing();
+
+        emu.set_cpu_overclock(1.5);
+        emu.run_frame();
+        let overclocked = emu.take_frame_timing();
+        // Only the cpu speeds up, frames are as long as before
+        assert_eq!(overclocked.cycles, stock.cycles);
+        assert!(overclocked.instructions.abs_diff(stock.instructions * 3 / 2) <= 1);
+
+        emu.set_cpu_overclock(100.0);
+        assert_eq!(emu.cpu_overclock(), MAX_CPU_OVERCLOCK);
+        emu.set_cpu_overclock(0.0);
+        assert_eq!(emu.cpu_overclock(), 1.0);
+        emu.set_cpu_overclock(f32::NAN);
+        assert_eq!(emu.cpu_overclock(), 1.0);
+    }
+
+    #[test]
+    fn test_timer_resets_at_vblank() {
+        let mut emu = idle_emu();
+        // Timer 1 counting system clocks, reset at every vblank
+        emu.main_bus.write_word(0x1F80_1114, 0x3, &mut emu.scheduler);
+        while !emu.frame_ready() {
+            emu.step_cycle();
+        }
+        for _ in 0..1000 {
+            emu.step_cycle();
+        }
+        let value = emu.main_bus.read_word(0x1F80_1110, &mut emu.scheduler);
+        assert!(value <= 1001, "timer read {} after vblank", value);
+    }
+
+    /// Timer 1 counting hblanks with the given sync bits, over one frame starting at vblank
+    fn hblanks_in_frame(video_mode: VideoMode, sync: u32) -> u32 {
+        let mut emu = idle_emu();
+        let pal = if video_mode == VideoMode::Pal { 0x8 } else { 0 };
+        emu.main_bus.write_word(0x1F80_1814, 0x0800_0000 | pal, &mut emu.scheduler);
+        // The frame already under way was timed before the mode changed
+        emu.run_frame();
+        emu.run_frame();
+
+        emu.main_bus.write_word(0x1F80_1114, 0x100 | sync, &mut emu.scheduler);
+        emu.run_frame();
+        emu.main_bus.read_word(0x1F80_1110, &mut emu.scheduler)
+    }
+
+    #[test]
+    fn test_timer_counts_hblanks() {
+        for mode in [VideoMode::Ntsc, VideoMode::Pal] {
+            assert_eq!(hblanks_in_frame(mode, 0), mode.lines_per_frame(), "{}", mode);
+            // Sync mode 0 pauses the count during vblank
+            assert_eq!(hblanks_in_frame(mode, 0x1), mode.active_lines(), "{}", mode);
+        }
+    }
+
+    #[test]
+    fn test_semihost_break() {
+        let (a0, a1, a3, v0, t0) = (4, 5, 7, 2, 8);
+        let code = [
+            i_type(0x0D, 0, a0, 2),          // ori a0, zero, 2
+            i_type(0x0D, 0, a1, 0x1234),     // ori a1, zero, 0x1234
+            i_type(0x0D, 0, a3, 0x10),       // ori a3, zero, 0x10
+            (cpu::SEMIHOST_BREAK_CODE << 6) | 0xD, // break 0xF10
+            r_type(0x21, v0, 0, t0, 0),      // addu t0, v0, zero
+            (0x02 << 26) | ((CODE_ADDR + 5 * 4) & 0x0FFF_FFFF) >> 2, // j .
+            0,                               // nop
+        ];
+        let exe = code.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &exe);
+        emu.set_semihosting(true);
+
+        while emu.semihost_call().is_none() {
+            emu.step_cycle();
+        }
+        assert_eq!(
+            emu.semihost_call(),
+            Some(cpu::SemihostCall { op: 2, args: [0x1234, 0, 0x10] })
+        );
+
+        // Nothing moves on until the call is answered. The debugger sees the BREAK
+        let pc = emu.pc();
+        for _ in 0..100 {
+            emu.step_cycle();
+        }
+        assert_eq!(emu.pc(), pc);
+        assert_eq!(emu.current_instruction_pc(), CODE_ADDR + 3 * 4);
+
+        emu.finish_semihost_call(42);
+        assert_eq!(emu.semihost_call(), None);
+        for _ in 0..100 {
+            emu.step_cycle();
+        }
+        assert_eq!(emu.read_gen_reg(t0 as usize), 42);
+    }
+
+    #[test]
+    fn test_breakpoint_in_delay_slot() {
+        let mut emu = idle_emu();
+        let delay_slot = CODE_ADDR + 4;
+        emu.add_sw_breakpoint(delay_slot);
+        while !emu.halt_requested() {
+            emu.step_cycle();
+        }
+        assert_eq!(emu.current_instruction_pc(), delay_slot);
+
+        // The jump is still pending, so resuming runs the delay slot and then lands on the target
+        emu.remove_sw_breakpoint(delay_slot);
+        emu.clear_halt();
+        emu.run_cpu_instruction();
+        assert_eq!(emu.current_instruction_pc(), CODE_ADDR);
+    }
+
+    #[test]
+    fn test_ram_access_handles_mirrors() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        emu.write_u32_ram(0x8000_1000, 0xDEAD_BEEF).unwrap();
+        assert_eq!(emu.read_u32_ram(0x0000_1000), Some(0xDEAD_BEEF));
+        assert_eq!(emu.read_u32_ram(0xA060_1000), Some(0xDEAD_BEEF));
+        assert_eq!(emu.read_u32_ram(0x8000_1002), Some(0xDEAD_BEEF));
+        assert_eq!(&emu.ram()[0x1000..0x1004], &[0xEF, 0xBE, 0xAD, 0xDE]);
+        assert_eq!(emu.ram().len(), 0x20_0000);
+
+        emu.ram_mut()[0x2000] = 0x42;
+        assert_eq!(emu.read_u32_ram(0x2000), Some(0x42));
+    }
+
+    #[test]
+    fn test_side_load_enables_scratchpad() {
+        for fast_boot in [false, true] {
+            let mut emu = PSXEmu::builder().bios(vec![0; bios::BIOS_SIZE]).fast_boot(fast_boot).build().unwrap();
+            if !fast_boot {
+                emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &scribble_exe());
+            }
+            emu.main_bus.write_word(mmio::SCRATCHPAD_BASE, 0x1234_5678, &mut emu.scheduler);
+            assert_eq!(emu.main_bus.read_word(mmio::SCRATCHPAD_BASE, &mut emu.scheduler), 0x1234_5678);
+        }
+    }
+
+    #[test]
+    fn test_ram_access_outside_ram() {
+        let mut emu = PSXEmu::new(vec![0; bios::BIOS_SIZE]).unwrap();
+        // I/O ports, past the last RAM mirror, and KSEG2
+        for addr in [0x1F80_1000, 0x0080_0000, 0xFFFE_0130] {
+            assert_eq!(emu.read_u32_ram(addr), None);
+            assert_eq!(emu.write_u32_ram(addr, 0xDEAD_BEEF), None);
+        }
+        assert!(emu.ram().iter().all(|byte| *byte == 0));
+        // The last word of the last mirror is still RAM
+        assert_eq!(emu.read_u32_ram(0x807F_FFFC), Some(0));
+    }
+
+    #[test]
+    #[ignore = "emulates 1200 frames. Run with cargo test --release -- --ignored"]
+    fn test_runs_are_deterministic() {
+        let first = frame_hashes();
+        let second = frame_hashes();
+        for (frame, (a, b)) in first.iter().zip(second.iter()).enumerate() {
+            assert_eq!(a, b, "Runs diverged at frame {}", frame);
+        }
+        // Make sure the program actually ran
+        assert_ne!(first[0], first[FRAMES - 1]);
     }
 }
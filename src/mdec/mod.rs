@@ -2,6 +2,8 @@ use std::collections::VecDeque;
 
 use bit_field::BitField;
 
+use crate::mmio::{MDEC_COMMAND, MDEC_STATUS};
+
 use self::{
     decode_macroblock::DecodeMacroblockCommand, set_quant_table::SetQuantTableCommand,
     set_scale_table::SetScaleTableCommand,
@@ -11,16 +13,43 @@ mod decode_macroblock;
 mod set_quant_table;
 mod set_scale_table;
 
+/// Save states store the command word, since the decoded command can't be serialized
+#[cfg_attr(
+    feature = "savestate",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "Option<u32>", into = "Option<u32>")
+)]
 enum InputState {
     Idle,
-    AwaitingParameters(Box<dyn MdecCommand>),
+    /// The command word and the command it decoded to
+    AwaitingParameters(u32, Box<dyn MdecCommand>),
 }
 
 impl Clone for InputState {
     fn clone(&self) -> Self {
         match self {
             Self::Idle => Self::Idle,
-            Self::AwaitingParameters(command) => Self::AwaitingParameters(command.box_clone()),
+            Self::AwaitingParameters(word, command) => {
+                Self::AwaitingParameters(*word, command.box_clone())
+            }
+        }
+    }
+}
+
+impl From<Option<u32>> for InputState {
+    fn from(word: Option<u32>) -> Self {
+        match word {
+            Some(word) => InputState::AwaitingParameters(word, decode_command(word)),
+            None => InputState::Idle,
+        }
+    }
+}
+
+impl From<InputState> for Option<u32> {
+    fn from(state: InputState) -> Self {
+        match state {
+            InputState::AwaitingParameters(word, _) => Some(word),
+            InputState::Idle => None,
         }
     }
 }
@@ -45,6 +74,7 @@ fn decode_command(command_word: u32) -> Box<dyn MdecCommand> {
     }
 }
 
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct MDEC {
     input_state: InputState,
     parameter_buffer: Vec<u32>,
@@ -79,16 +109,16 @@ impl MDEC {
 
     pub(crate) fn bus_read_word(&mut self, addr: u32) -> u32 {
         match addr {
-            0x1f801820 => self.read_response(),
-            0x1f801824 => self.read_status(),
+            MDEC_COMMAND => self.read_response(),
+            MDEC_STATUS => self.read_status(),
             _ => panic!("Tried to read unknown MDEC word! {:#X}", addr),
         }
     }
 
     pub(crate) fn bus_write_word(&mut self, addr: u32, word: u32) {
         match addr {
-            0x1f801820 => self.write_command_register(word),
-            0x1f801824 => self.write_control(word),
+            MDEC_COMMAND => self.write_command_register(word),
+            MDEC_STATUS => self.write_control(word),
             _ => panic!("Tried to write unknown MDEC word! {:#X}", addr),
         }
     }
@@ -98,9 +128,9 @@ impl MDEC {
         match current_state {
             InputState::Idle => {
                 let command = decode_command(word);
-                self.input_state = InputState::AwaitingParameters(command);
+                self.input_state = InputState::AwaitingParameters(word, command);
             }
-            InputState::AwaitingParameters(command) => {
+            InputState::AwaitingParameters(_, command) => {
                 let expected_words = command.parameter_words();
                 self.parameter_buffer.push(word);
 
@@ -117,7 +147,7 @@ impl MDEC {
     fn read_status(&self) -> u32 {
         let mut result: u32 = 0;
 
-        if let InputState::AwaitingParameters(command) = &self.input_state {
+        if let InputState::AwaitingParameters(_, command) = &self.input_state {
             let remaining_words =
                 command.parameter_words() as isize - self.parameter_buffer.len() as isize;
             result.set_bit(29, true);
@@ -0,0 +1,584 @@
+use std::fmt::Display;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Every card is 128KB: 16 blocks of 64 frames of 128 bytes
+pub const MEMORY_CARD_SIZE: usize = 128 * 1024;
+pub const BLOCK_SIZE: usize = 8192;
+const FRAME_SIZE: usize = 128;
+const SECTOR_COUNT: u16 = 0x400;
+
+/// Block 0 is the directory, so only 15 blocks hold saves
+pub const SAVE_BLOCKS: usize = 15;
+
+/// Set on power up, cleared by the first successful write. Games use it to notice a card swap
+const FLAG_NOT_WRITTEN: u8 = 0x08;
+
+const BLOCK_FIRST: u8 = 0x51;
+const BLOCK_MIDDLE: u8 = 0x52;
+const BLOCK_LAST: u8 = 0x53;
+const BLOCK_FREE: u8 = 0xA0;
+const NO_NEXT_BLOCK: u16 = 0xFFFF;
+
+const ICON_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum MemoryCardError {
+    InvalidSize(usize),
+    InvalidSave,
+    NotEnoughSpace,
+    AlreadyExists(String),
+}
+
+impl Display for MemoryCardError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemoryCardError::InvalidSize(size) => write!(
+                f,
+                "Memory card image is {} bytes, expected {} bytes",
+                size, MEMORY_CARD_SIZE
+            ),
+            MemoryCardError::InvalidSave => write!(f, "Not a valid save file"),
+            MemoryCardError::NotEnoughSpace => write!(f, "Not enough free blocks on the card"),
+            MemoryCardError::AlreadyExists(name) => write!(f, "{} is already on the card", name),
+        }
+    }
+}
+
+impl std::error::Error for MemoryCardError {}
+
+/// A save as listed in the card's directory
+#[derive(Debug, Clone)]
+pub struct SaveInfo {
+    /// Index of the first block, 1-15
+    pub first_block: usize,
+    pub block_count: usize,
+    /// Directory file name, e.g. BASCUS-94163FF7
+    pub file_name: String,
+    pub title: String,
+    /// 16x16 RGBA icon frames. Animated icons have 2 or 3
+    pub icon_frames: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CardCommand {
+    None,
+    Read,
+    Write,
+    GetId,
+}
+
+pub struct MemoryCard {
+    data: Vec<u8>,
+    flag: u8,
+    command: CardCommand,
+    sector: u16,
+    previous: u8,
+    checksum: u8,
+    write_buffer: [u8; FRAME_SIZE],
+    write_count: u64,
+}
+
+impl MemoryCard {
+    /// Creates a freshly formatted card
+    pub fn new() -> Self {
+        let mut data = vec![0; MEMORY_CARD_SIZE];
+
+        data[0] = b'M';
+        data[1] = b'C';
+        for frame in 1..=SAVE_BLOCKS {
+            let entry = &mut data[frame * FRAME_SIZE..(frame + 1) * FRAME_SIZE];
+            entry[0] = BLOCK_FREE;
+            LittleEndian::write_u16(&mut entry[8..10], NO_NEXT_BLOCK);
+        }
+        // Broken sector list. Every entry unused
+        for frame in 16..36 {
+            let entry = &mut data[frame * FRAME_SIZE..(frame + 1) * FRAME_SIZE];
+            LittleEndian::write_u32(&mut entry[0..4], 0xFFFF_FFFF);
+            LittleEndian::write_u16(&mut entry[8..10], NO_NEXT_BLOCK);
+        }
+        for frame in (0..36).chain(std::iter::once(63)) {
+            update_frame_checksum(&mut data, frame);
+        }
+        // The last frame of the directory block is a copy of the header, used by the BIOS as a write test
+        let header: Vec<u8> = data[0..FRAME_SIZE].to_vec();
+        data[63 * FRAME_SIZE..64 * FRAME_SIZE].copy_from_slice(&header);
+
+        Self::with_data(data)
+    }
+
+    /// Loads a raw card image (.mcd/.mcr)
+    pub fn from_data(data: Vec<u8>) -> Result<Self, MemoryCardError> {
+        if data.len() != MEMORY_CARD_SIZE {
+            return Err(MemoryCardError::InvalidSize(data.len()));
+        }
+        Ok(Self::with_data(data))
+    }
+
+    fn with_data(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            flag: FLAG_NOT_WRITTEN,
+            command: CardCommand::None,
+            sector: 0,
+            previous: 0,
+            checksum: 0,
+            write_buffer: [0; FRAME_SIZE],
+            write_count: 0,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Number of sectors the console has written. Frontends can watch this to tell when a save has finished
+    pub fn write_count(&self) -> u64 {
+        self.write_count
+    }
+
+    /// Handles one byte of a transfer, after the 0x81 select byte. Step 0 is the command byte.
+    /// Returns the reply, and whether the card acknowledges it (i.e. expects another byte)
+    pub(crate) fn transfer(&mut self, step: usize, value: u8) -> (u8, bool) {
+        if step == 0 {
+            self.command = match value {
+                0x52 => CardCommand::Read,
+                0x57 => CardCommand::Write,
+                0x53 => CardCommand::GetId,
+                _ => CardCommand::None,
+            };
+            return match self.command {
+                CardCommand::None => (0xFF, false),
+                _ => (self.flag, true),
+            };
+        }
+
+        match self.command {
+            CardCommand::None => (0xFF, false),
+            CardCommand::Read => self.transfer_read(step, value),
+            CardCommand::Write => self.transfer_write(step, value),
+            CardCommand::GetId => match step {
+                1 => (0x5A, true),
+                2 => (0x5D, true),
+                3 => (0x5C, true),
+                4 => (0x5D, true),
+                5 => (0x04, true),
+                6 => (0x00, true),
+                7 => (0x00, true),
+                8 => (0x80, false),
+                _ => (0xFF, false),
+            },
+        }
+    }
+
+    fn transfer_read(&mut self, step: usize, value: u8) -> (u8, bool) {
+        match step {
+            1 => (0x5A, true),
+            2 => (0x5D, true),
+            3 => self.receive_address_msb(value),
+            4 => self.receive_address_lsb(value),
+            5 => (0x5C, true),
+            6 => (0x5D, true),
+            7 if self.sector >= SECTOR_COUNT => (0xFF, false),
+            7 => ((self.sector >> 8) as u8, true),
+            8 => (self.sector as u8, true),
+            9..=136 => {
+                let byte = self.data[self.sector as usize * FRAME_SIZE + step - 9];
+                self.checksum ^= byte;
+                (byte, true)
+            }
+            137 => (self.checksum, true),
+            138 => (0x47, false),
+            _ => (0xFF, false),
+        }
+    }
+
+    fn transfer_write(&mut self, step: usize, value: u8) -> (u8, bool) {
+        // Bytes sent by the console are echoed back one byte late
+        let previous = self.previous;
+        self.previous = value;
+
+        match step {
+            1 => (0x5A, true),
+            2 => (0x5D, true),
+            3 => self.receive_address_msb(value),
+            4 => self.receive_address_lsb(value),
+            5..=132 => {
+                self.write_buffer[step - 5] = value;
+                self.checksum ^= value;
+                (previous, true)
+            }
+            133 => {
+                // Checksum byte. Compare it now, the result is reported in the end byte
+                self.checksum ^= value;
+                (previous, true)
+            }
+            134 => (0x5C, true),
+            135 => (0x5D, true),
+            136 => {
+                let end = if self.sector >= SECTOR_COUNT {
+                    0xFF
+                } else if self.checksum != 0 {
+                    0x4E
+                } else {
+                    let start = self.sector as usize * FRAME_SIZE;
+                    self.data[start..start + FRAME_SIZE].copy_from_slice(&self.write_buffer);
+                    self.flag &= !FLAG_NOT_WRITTEN;
+                    self.write_count += 1;
+                    0x47
+                };
+                (end, false)
+            }
+            _ => (0xFF, false),
+        }
+    }
+
+    fn receive_address_msb(&mut self, value: u8) -> (u8, bool) {
+        self.sector = (value as u16) << 8;
+        self.checksum = value;
+        self.previous = value;
+        (0x00, true)
+    }
+
+    fn receive_address_lsb(&mut self, value: u8) -> (u8, bool) {
+        let msb = (self.sector >> 8) as u8;
+        self.sector |= value as u16;
+        self.checksum ^= value;
+        self.previous = value;
+        (msb, true)
+    }
+
+    fn directory_entry(&self, block: usize) -> &[u8] {
+        &self.data[block * FRAME_SIZE..(block + 1) * FRAME_SIZE]
+    }
+
+    /// Follows a save's block chain, starting at its first block
+    fn block_chain(&self, first_block: usize) -> Vec<usize> {
+        let mut blocks = vec![first_block];
+        let mut next = LittleEndian::read_u16(&self.directory_entry(first_block)[8..10]);
+        while next != NO_NEXT_BLOCK && blocks.len() < SAVE_BLOCKS {
+            let block = next as usize + 1;
+            if !(1..=SAVE_BLOCKS).contains(&block) || blocks.contains(&block) {
+                break;
+            }
+            blocks.push(block);
+            next = LittleEndian::read_u16(&self.directory_entry(block)[8..10]);
+        }
+        blocks
+    }
+
+    /// Lists every save on the card
+    pub fn saves(&self) -> Vec<SaveInfo> {
+        (1..=SAVE_BLOCKS)
+            .filter(|block| self.directory_entry(*block)[0] == BLOCK_FIRST)
+            .map(|block| {
+                let entry = self.directory_entry(block);
+                let block_data = &self.data[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE];
+                SaveInfo {
+                    first_block: block,
+                    block_count: self.block_chain(block).len(),
+                    file_name: ascii_string(&entry[0x0A..0x1E]),
+                    title: decode_title(&block_data[0x04..0x44]),
+                    icon_frames: decode_icon_frames(block_data),
+                }
+            })
+            .collect()
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        (1..=SAVE_BLOCKS)
+            .filter(|block| self.directory_entry(*block)[0] == BLOCK_FREE)
+            .count()
+    }
+
+    /// Frees every block of the save starting at first_block
+    pub fn delete_save(&mut self, first_block: usize) {
+        if !(1..=SAVE_BLOCKS).contains(&first_block) || self.directory_entry(first_block)[0] != BLOCK_FIRST {
+            return;
+        }
+
+        for block in self.block_chain(first_block) {
+            let entry = &mut self.data[block * FRAME_SIZE..(block + 1) * FRAME_SIZE];
+            entry.fill(0);
+            entry[0] = BLOCK_FREE;
+            LittleEndian::write_u16(&mut entry[8..10], NO_NEXT_BLOCK);
+            update_frame_checksum(&mut self.data, block);
+        }
+    }
+
+    /// Exports a save as a .mcs file: its directory entry followed by its blocks
+    pub fn export_save(&self, first_block: usize) -> Option<Vec<u8>> {
+        if !(1..=SAVE_BLOCKS).contains(&first_block) || self.directory_entry(first_block)[0] != BLOCK_FIRST {
+            return None;
+        }
+
+        let mut header = self.directory_entry(first_block).to_vec();
+        LittleEndian::write_u16(&mut header[8..10], NO_NEXT_BLOCK);
+        header[FRAME_SIZE - 1] = frame_checksum(&header);
+
+        let mut result = header;
+        for block in self.block_chain(first_block) {
+            result.extend_from_slice(&self.data[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE]);
+        }
+        Some(result)
+    }
+
+    /// Imports a .mcs file into free blocks
+    pub fn import_save(&mut self, mcs: &[u8]) -> Result<(), MemoryCardError> {
+        if mcs.len() <= FRAME_SIZE || !(mcs.len() - FRAME_SIZE).is_multiple_of(BLOCK_SIZE) || mcs[0] != BLOCK_FIRST {
+            return Err(MemoryCardError::InvalidSave);
+        }
+
+        let file_name = ascii_string(&mcs[0x0A..0x1E]);
+        if self.saves().iter().any(|save| save.file_name == file_name) {
+            return Err(MemoryCardError::AlreadyExists(file_name));
+        }
+
+        let block_count = (mcs.len() - FRAME_SIZE) / BLOCK_SIZE;
+        let free: Vec<usize> = (1..=SAVE_BLOCKS)
+            .filter(|block| self.directory_entry(*block)[0] == BLOCK_FREE)
+            .take(block_count)
+            .collect();
+        if free.len() < block_count {
+            return Err(MemoryCardError::NotEnoughSpace);
+        }
+
+        for (i, block) in free.iter().enumerate() {
+            let mut entry = [0u8; FRAME_SIZE];
+            if i == 0 {
+                entry.copy_from_slice(&mcs[..FRAME_SIZE]);
+                LittleEndian::write_u32(&mut entry[4..8], (block_count * BLOCK_SIZE) as u32);
+            }
+            entry[0] = if i == 0 {
+                BLOCK_FIRST
+            } else if i == block_count - 1 {
+                BLOCK_LAST
+            } else {
+                BLOCK_MIDDLE
+            };
+            let next = free.get(i + 1).map_or(NO_NEXT_BLOCK, |next| (next - 1) as u16);
+            LittleEndian::write_u16(&mut entry[8..10], next);
+            entry[FRAME_SIZE - 1] = frame_checksum(&entry);
+            self.data[block * FRAME_SIZE..(block + 1) * FRAME_SIZE].copy_from_slice(&entry);
+
+            let source = &mcs[FRAME_SIZE + i * BLOCK_SIZE..FRAME_SIZE + (i + 1) * BLOCK_SIZE];
+            self.data[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE].copy_from_slice(source);
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for MemoryCard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn frame_checksum(frame: &[u8]) -> u8 {
+    frame[..FRAME_SIZE - 1].iter().fold(0, |acc, byte| acc ^ byte)
+}
+
+fn update_frame_checksum(data: &mut [u8], frame: usize) {
+    let start = frame * FRAME_SIZE;
+    data[start + FRAME_SIZE - 1] = frame_checksum(&data[start..start + FRAME_SIZE]);
+}
+
+fn ascii_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|b| **b != 0)
+        .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '?' })
+        .collect()
+}
+
+/// Decodes a save title. Titles are Shift-JIS, but almost always stick to the full width
+/// versions of ASCII characters, so those are mapped back and anything else becomes '?'
+fn decode_title(bytes: &[u8]) -> String {
+    let mut title = String::new();
+    let mut i = 0;
+    while i < bytes.len() && bytes[i] != 0 {
+        let byte = bytes[i];
+        if byte < 0x80 {
+            title.push(byte as char);
+            i += 1;
+            continue;
+        }
+
+        let code = ((byte as u16) << 8) | *bytes.get(i + 1).unwrap_or(&0) as u16;
+        i += 2;
+        let c = match code {
+            0x8140 => ' ',
+            0x8143 => ',',
+            0x8144 => '.',
+            0x8146 => ':',
+            0x8148 => '?',
+            0x8149 => '!',
+            0x815B..=0x815D => '-',
+            0x815E => '/',
+            0x8169 => '(',
+            0x816A => ')',
+            0x816D => '[',
+            0x816E => ']',
+            0x817B => '+',
+            0x8181 => '=',
+            0x8193 => '%',
+            0x8194 => '#',
+            0x8195 => '&',
+            0x8196 => '*',
+            0x8197 => '@',
+            0x824F..=0x8258 => (b'0' + (code - 0x824F) as u8) as char,
+            0x8260..=0x8279 => (b'A' + (code - 0x8260) as u8) as char,
+            0x8281..=0x829A => (b'a' + (code - 0x8281) as u8) as char,
+            _ => '?',
+        };
+        title.push(c);
+    }
+    title.trim_end().to_string()
+}
+
+/// Decodes the icon frames from a save's first block. Each frame is 16x16 at 4 bits per pixel,
+/// sharing the 16 color palette in the title frame. Color 0 is transparent
+pub fn decode_icon_frames(block: &[u8]) -> Vec<Vec<u8>> {
+    let frame_count = match block[2] {
+        0x12 => 2,
+        0x13 => 3,
+        _ => 1,
+    };
+
+    let palette: Vec<[u8; 4]> = block[0x60..0x80]
+        .chunks_exact(2)
+        .map(|color| {
+            let color = LittleEndian::read_u16(color);
+            if color == 0 {
+                [0, 0, 0, 0]
+            } else {
+                [
+                    ((color & 0x1F) << 3) as u8,
+                    (((color >> 5) & 0x1F) << 3) as u8,
+                    (((color >> 10) & 0x1F) << 3) as u8,
+                    255,
+                ]
+            }
+        })
+        .collect();
+
+    (0..frame_count)
+        .map(|frame| {
+            let start = FRAME_SIZE * (frame + 1);
+            block[start..start + ICON_SIZE * ICON_SIZE / 2]
+                .iter()
+                .flat_map(|pixels| {
+                    // Low nibble is the left pixel
+                    let left = palette[(pixels & 0xF) as usize];
+                    let right = palette[(pixels >> 4) as usize];
+                    left.into_iter().chain(right)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod memcard_tests {
+    use super::*;
+
+    /// Runs one whole command through the card, returning every reply
+    fn run_command(card: &mut MemoryCard, bytes: &[u8]) -> Vec<u8> {
+        bytes
+            .iter()
+            .enumerate()
+            .map(|(step, byte)| card.transfer(step, *byte).0)
+            .collect()
+    }
+
+    fn fake_save(name: &str, blocks: usize) -> Vec<u8> {
+        let mut mcs = vec![0; FRAME_SIZE + blocks * BLOCK_SIZE];
+        mcs[0] = BLOCK_FIRST;
+        mcs[0x0A..0x0A + name.len()].copy_from_slice(name.as_bytes());
+        let title = &mut mcs[FRAME_SIZE..];
+        title[0..2].copy_from_slice(b"SC");
+        title[2] = 0x11;
+        title[3] = blocks as u8;
+        // "ＡＢ１"
+        title[4..10].copy_from_slice(&[0x82, 0x60, 0x82, 0x61, 0x82, 0x50]);
+        // Palette color 1 is pure red
+        LittleEndian::write_u16(&mut title[0x62..0x64], 0x1F);
+        title[FRAME_SIZE] = 0x10;
+        mcs
+    }
+
+    #[test]
+    fn test_formatted_card_checksums() {
+        let card = MemoryCard::new();
+        for frame in 0..36 {
+            let data = &card.data()[frame * FRAME_SIZE..(frame + 1) * FRAME_SIZE];
+            assert_eq!(frame_checksum(data), data[FRAME_SIZE - 1], "Frame {}", frame);
+        }
+        assert_eq!(card.free_blocks(), SAVE_BLOCKS);
+    }
+
+    #[test]
+    fn test_write_then_read_sector() {
+        let mut card = MemoryCard::new();
+        let payload: Vec<u8> = (0..128).map(|i| i as u8).collect();
+        let checksum = payload.iter().fold(0x01 ^ 0x23, |acc, b| acc ^ b);
+
+        let mut write = vec![0x57, 0, 0, 0x01, 0x23];
+        write.extend_from_slice(&payload);
+        write.extend_from_slice(&[checksum, 0, 0, 0]);
+        let replies = run_command(&mut card, &write);
+        assert_eq!(replies[0], FLAG_NOT_WRITTEN);
+        assert_eq!(&replies[134..], &[0x5C, 0x5D, 0x47]);
+        assert_eq!(card.write_count(), 1);
+
+        let mut read = vec![0x52, 0, 0, 0x01, 0x23];
+        read.extend_from_slice(&[0; 134]);
+        let replies = run_command(&mut card, &read);
+        assert_eq!(replies[0], 0);
+        assert_eq!(&replies[7..9], &[0x01, 0x23]);
+        assert_eq!(&replies[9..137], payload.as_slice());
+        assert_eq!(replies[137], checksum);
+        assert_eq!(replies[138], 0x47);
+    }
+
+    #[test]
+    fn test_write_bad_checksum() {
+        let mut card = MemoryCard::new();
+        let mut write = vec![0x57, 0, 0, 0x00, 0x40];
+        write.extend_from_slice(&[0xAA; 128]);
+        write.extend_from_slice(&[0x12, 0, 0, 0]);
+        let replies = run_command(&mut card, &write);
+        assert_eq!(replies[136], 0x4E);
+        assert_eq!(card.write_count(), 0);
+    }
+
+    #[test]
+    fn test_import_export_delete() {
+        let mut card = MemoryCard::new();
+        let mcs = fake_save("BASCUS-94163TEST", 2);
+        card.import_save(&mcs).unwrap();
+        assert!(matches!(
+            card.import_save(&mcs),
+            Err(MemoryCardError::AlreadyExists(_))
+        ));
+
+        let saves = card.saves();
+        assert_eq!(saves.len(), 1);
+        assert_eq!(saves[0].file_name, "BASCUS-94163TEST");
+        assert_eq!(saves[0].block_count, 2);
+        assert_eq!(saves[0].title, "AB1");
+        assert_eq!(card.free_blocks(), SAVE_BLOCKS - 2);
+
+        // Top left pixel uses palette entry 0 (transparent), the next one entry 1 (red)
+        let icon = &saves[0].icon_frames[0];
+        assert_eq!(&icon[0..8], &[0, 0, 0, 0, 248, 0, 0, 255]);
+
+        let exported = card.export_save(saves[0].first_block).unwrap();
+        assert_eq!(&exported[FRAME_SIZE..], &mcs[FRAME_SIZE..]);
+
+        card.delete_save(saves[0].first_block);
+        assert!(card.saves().is_empty());
+        assert_eq!(card.free_blocks(), SAVE_BLOCKS);
+    }
+}
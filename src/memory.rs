@@ -1,5 +1,40 @@
 use byteorder::{ByteOrder, LittleEndian};
 
+/// Amount of main RAM installed. Retail units have 2MB, dev units have 8MB
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RamSize {
+    TwoMegabytes,
+    EightMegabytes,
+}
+
+impl RamSize {
+    pub fn bytes(&self) -> usize {
+        match self {
+            RamSize::TwoMegabytes => 0x20_0000,
+            RamSize::EightMegabytes => 0x80_0000,
+        }
+    }
+}
+
+/// Seed for the contents every memory gets at power on in deterministic mode
+const POWER_ON_SEED: u32 = 0x5053_5845;
+
+/// The bytes `PSXEmu::set_deterministic` fills memories with at power on. Real hardware powers on
+/// with whatever the chips settled to, so any fixed pattern is as good as another. This one isn't
+/// all zeros, so code that reads memory before writing it doesn't accidentally work
+pub(crate) fn power_on_pattern() -> impl Iterator<Item = u8> {
+    let mut state = POWER_ON_SEED;
+    std::iter::repeat_with(move || {
+        // xorshift32
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    })
+}
+
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct Memory {
     pub data: Vec<u8>,
 }
@@ -7,11 +42,21 @@ pub struct Memory {
 impl Memory {
     /// Initializes 2MiB of system memory
     pub fn new() -> Memory {
+        Memory::with_size(RamSize::TwoMegabytes)
+    }
+
+    pub fn with_size(size: RamSize) -> Memory {
         Memory {
-            data: vec![0; 2_100_000],
+            data: vec![0; size.bytes()],
         }
     }
 
+    /// Masks an address within the 8MB ram window down to the installed memory.
+    /// With 2MB installed this mirrors the memory 4 times
+    pub fn mirror(&self, addr: u32) -> u32 {
+        addr & (self.data.len() as u32 - 1)
+    }
+
     //1K scratchpad memory
     pub fn new_scratchpad() -> Memory {
         Memory {
@@ -0,0 +1,172 @@
+//! The physical memory map. Addresses are after `translate_address` strips the segment bits, and
+//! ranges are inclusive so they can be matched on directly.
+//!
+//! Most I/O registers are 32 bits wide, but games also reach them with half word and byte
+//! accesses. Those only see their own lanes of the register, which the helpers at the bottom pick
+//! out and put back
+
+pub(crate) const RAM_BASE: u32 = 0x0000_0000;
+/// 8MB, since the 2MB of RAM repeats through the first 8MB. See `Memory::mirror`
+pub(crate) const RAM_END: u32 = 0x007F_FFFF;
+
+/// Expansion region 1, the parallel port. Nothing is ever plugged in
+pub(crate) const EXPANSION_1_BASE: u32 = 0x1F00_0000;
+pub(crate) const EXPANSION_1_END: u32 = 0x1F00_FFFF;
+
+pub(crate) const SCRATCHPAD_BASE: u32 = 0x1F80_0000;
+pub(crate) const SCRATCHPAD_END: u32 = 0x1F80_03FF;
+
+// Memory control
+pub(crate) const EXPANSION_1_BASE_ADDR: u32 = 0x1F80_1000;
+pub(crate) const EXPANSION_2_BASE_ADDR: u32 = 0x1F80_1004;
+pub(crate) const EXPANSION_1_DELAY: u32 = 0x1F80_1008;
+pub(crate) const EXPANSION_3_DELAY: u32 = 0x1F80_100C;
+pub(crate) const BIOS_DELAY: u32 = 0x1F80_1010;
+pub(crate) const SPU_DELAY: u32 = 0x1F80_1014;
+pub(crate) const CDROM_DELAY: u32 = 0x1F80_1018;
+pub(crate) const EXPANSION_2_DELAY: u32 = 0x1F80_101C;
+pub(crate) const COM_DELAY: u32 = 0x1F80_1020;
+pub(crate) const RAM_SIZE: u32 = 0x1F80_1060;
+
+// Controller and memory card port
+pub(crate) const JOY_BASE: u32 = 0x1F80_1040;
+pub(crate) const JOY_DATA: u32 = 0x1F80_1040;
+pub(crate) const JOY_STAT: u32 = 0x1F80_1044;
+pub(crate) const JOY_MODE: u32 = 0x1F80_1048;
+pub(crate) const JOY_CTRL: u32 = 0x1F80_104A;
+pub(crate) const JOY_BAUD: u32 = 0x1F80_104E;
+pub(crate) const JOY_END: u32 = 0x1F80_104F;
+
+/// The serial port. Only logged
+pub(crate) const SIO_BASE: u32 = 0x1F80_1050;
+pub(crate) const SIO_END: u32 = 0x1F80_105F;
+
+/// Interrupt status. Writes acknowledge, clearing every bit written as 0
+pub(crate) const IO_ISTAT: u32 = 0x1F80_1070;
+pub(crate) const IO_IMASK: u32 = 0x1F80_1074;
+
+/// Seven channels of MADR, BCR and CHCR, 0x10 apart, followed by DPCR and DICR
+pub(crate) const DMA_BASE: u32 = 0x1F80_1080;
+pub(crate) const DMA_DPCR: u32 = 0x1F80_10F0;
+pub(crate) const DMA_DICR: u32 = 0x1F80_10F4;
+pub(crate) const DMA_END: u32 = 0x1F80_10F7;
+
+/// Three timers of count, mode and target, 0x10 apart
+pub(crate) const TIMER_BASE: u32 = 0x1F80_1100;
+pub(crate) const TIMER_0_COUNT: u32 = 0x1F80_1100;
+pub(crate) const TIMER_0_MODE: u32 = 0x1F80_1104;
+pub(crate) const TIMER_0_TARGET: u32 = 0x1F80_1108;
+pub(crate) const TIMER_1_COUNT: u32 = 0x1F80_1110;
+pub(crate) const TIMER_1_MODE: u32 = 0x1F80_1114;
+pub(crate) const TIMER_1_TARGET: u32 = 0x1F80_1118;
+pub(crate) const TIMER_2_COUNT: u32 = 0x1F80_1120;
+pub(crate) const TIMER_2_MODE: u32 = 0x1F80_1124;
+pub(crate) const TIMER_2_TARGET: u32 = 0x1F80_1128;
+pub(crate) const TIMER_END: u32 = 0x1F80_112F;
+
+/// Four byte registers. What registers 1 to 3 do depends on the index in register 0
+pub(crate) const CDROM_BASE: u32 = 0x1F80_1800;
+pub(crate) const CDROM_INDEX: u32 = 0x1F80_1800;
+pub(crate) const CDROM_REG1: u32 = 0x1F80_1801;
+pub(crate) const CDROM_REG2: u32 = 0x1F80_1802;
+pub(crate) const CDROM_REG3: u32 = 0x1F80_1803;
+pub(crate) const CDROM_END: u32 = 0x1F80_1803;
+
+/// GP0 when written, GPUREAD when read
+pub(crate) const GPU_GP0: u32 = 0x1F80_1810;
+/// GP1 when written, GPUSTAT when read
+pub(crate) const GPU_GP1: u32 = 0x1F80_1814;
+
+/// Command and parameters when written, decoded data when read
+pub(crate) const MDEC_COMMAND: u32 = 0x1F80_1820;
+/// Control when written, status when read
+pub(crate) const MDEC_STATUS: u32 = 0x1F80_1824;
+
+/// Half word registers only
+pub(crate) const SPU_BASE: u32 = 0x1F80_1C00;
+pub(crate) const SPU_END: u32 = 0x1F80_1FFF;
+
+/// Expansion region 2, where dev boards put their serial ports and POST display
+pub(crate) const EXPANSION_2_BASE: u32 = 0x1F80_2000;
+pub(crate) const EXPANSION_2_SERIAL: u32 = 0x1F80_2002;
+pub(crate) const DUART_A: u32 = 0x1F80_2023;
+pub(crate) const DUART_B: u32 = 0x1F80_202B;
+/// The BIOS writes its boot stage here
+pub(crate) const POST: u32 = 0x1F80_2041;
+pub(crate) const EXPANSION_2_END: u32 = 0x1F80_3FFF;
+
+pub(crate) const BIOS_BASE: u32 = 0x1FC0_0000;
+pub(crate) const BIOS_END: u32 = 0x1FC7_FFFF;
+
+/// This lives in KSEG2, but the bus translates it down to 0x1FFE0130 like everything else
+pub(crate) const CACHE_CONTROL: u32 = 0x1FFE_0130;
+pub(crate) const CACHE_CONTROL_BASE: u32 = 0x1FFE_0000;
+pub(crate) const CACHE_CONTROL_END: u32 = 0x1FFE_0200;
+
+/// The word register an access at `addr` lands in
+pub(crate) const fn word_address(addr: u32) -> u32 {
+    addr & !0x3
+}
+
+const fn half_shift(addr: u32) -> u32 {
+    (addr & 0x2) * 8
+}
+
+const fn byte_shift(addr: u32) -> u32 {
+    (addr & 0x3) * 8
+}
+
+/// The half of `word` a half word access at `addr` sees
+pub(crate) const fn extract_half(word: u32, addr: u32) -> u16 {
+    (word >> half_shift(addr)) as u16
+}
+
+/// The byte of `word` a byte access at `addr` sees
+pub(crate) const fn extract_byte(word: u32, addr: u32) -> u8 {
+    (word >> byte_shift(addr)) as u8
+}
+
+/// `word` with a half word written at `addr`. The other half is left alone
+pub(crate) const fn insert_half(word: u32, addr: u32, value: u16) -> u32 {
+    let shift = half_shift(addr);
+    (word & !(0xFFFF << shift)) | ((value as u32) << shift)
+}
+
+/// `word` with a byte written at `addr`. The other bytes are left alone
+pub(crate) const fn insert_byte(word: u32, addr: u32, value: u8) -> u32 {
+    let shift = byte_shift(addr);
+    (word & !(0xFF << shift)) | ((value as u32) << shift)
+}
+
+#[cfg(test)]
+mod mmio_tests {
+    use super::*;
+
+    const WORD: u32 = 0x8765_4321;
+
+    #[test]
+    fn test_half_word_lanes() {
+        for (offset, half) in [(0, 0x4321), (2, 0x8765)] {
+            for addr in [IO_IMASK + offset, IO_IMASK + offset + 1] {
+                assert_eq!(extract_half(WORD, addr), half, "{:#X}", addr);
+            }
+            let written = insert_half(WORD, IO_IMASK + offset, 0xABCD);
+            assert_eq!(extract_half(written, IO_IMASK + offset), 0xABCD);
+            assert_eq!(extract_half(written, IO_IMASK + (offset ^ 2)), extract_half(WORD, IO_IMASK + (offset ^ 2)));
+        }
+    }
+
+    #[test]
+    fn test_byte_lanes() {
+        for offset in 0..4 {
+            let addr = DMA_DICR + offset;
+            assert_eq!(extract_byte(WORD, addr), WORD.to_le_bytes()[offset as usize]);
+
+            let written = insert_byte(WORD, addr, 0xEE);
+            let mut expected = WORD.to_le_bytes();
+            expected[offset as usize] = 0xEE;
+            assert_eq!(written, u32::from_le_bytes(expected), "{:#X}", addr);
+            assert_eq!(word_address(addr), DMA_DICR);
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Save states. A state holds everything the machine would lose on power off, so it leaves out the
+//! BIOS, the disc, the memory card and the host's settings. Load it with the same BIOS and disc in place
+
+use std::fmt::Display;
+
+use crate::PSXEmu;
+
+const MAGIC: &[u8; 4] = b"FSST";
+/// Any change to the serialized structs has to bump this, since bincode has no field names to fall
+/// back on
+const VERSION: u16 = 5;
+const HEADER_SIZE: usize = MAGIC.len() + 2;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    NotASaveState,
+    UnsupportedVersion(u16),
+    Corrupt(String),
+}
+
+impl Display for SaveStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveStateError::NotASaveState => write!(f, "Not a FogStation save state"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "Save state version {} is not supported", version)
+            }
+            SaveStateError::Corrupt(reason) => write!(f, "Save state is corrupt: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+pub(crate) fn write_state(emu: &PSXEmu) -> Vec<u8> {
+    let mut state = MAGIC.to_vec();
+    state.extend_from_slice(&VERSION.to_le_bytes());
+    bincode::serialize_into(&mut state, emu).expect("Every field of the machine can be serialized");
+    state
+}
+
+pub(crate) fn read_state(state: &[u8]) -> Result<PSXEmu, SaveStateError> {
+    if state.len() < HEADER_SIZE || &state[..MAGIC.len()] != MAGIC {
+        return Err(SaveStateError::NotASaveState);
+    }
+    let version = u16::from_le_bytes([state[4], state[5]]);
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    bincode::deserialize(&state[HEADER_SIZE..]).map_err(|e| SaveStateError::Corrupt(e.to_string()))
+}
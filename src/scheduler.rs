@@ -1,11 +1,14 @@
 use crate::cdrom::cdpacket_event;
 use crate::controller::controller_delay_event;
 use crate::ScheduleTarget::{CDPacket, GpuHblank, TimerOverflow, TimerTarget};
+use crate::gpu::VideoMode;
 use crate::{InterruptSource, MainBus, PSXEmu, R3000};
-use std::array;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap};
 use std::mem::discriminant;
 
 #[derive(PartialEq, Copy, Clone)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub enum ScheduleTarget {
     GpuHblank,
     GpuVblank,
@@ -14,115 +17,159 @@ pub enum ScheduleTarget {
     TimerOverflow(u32),
     CDPacket(u32),
     CDIrq,
+    CDSpeedChange,
+    SpuTransfer,
 }
 
+impl ScheduleTarget {
+    /// Name for the kind of event, ignoring any data it carries
+    pub fn name(&self) -> &'static str {
+        match self {
+            GpuHblank => "GPU hblank",
+            ScheduleTarget::GpuVblank => "GPU vblank",
+            ScheduleTarget::ControllerIRQ => "Controller IRQ",
+            TimerTarget(_) => "Timer target",
+            TimerOverflow(_) => "Timer overflow",
+            CDPacket(_) => "CD packet",
+            ScheduleTarget::CDIrq => "CD IRQ",
+            ScheduleTarget::CDSpeedChange => "CD speed change",
+            ScheduleTarget::SpuTransfer => "SPU transfer",
+        }
+    }
+}
+
+/// The CPU runs at 33.8688MHz
+pub const CPU_CLOCK: f64 = 33_868_800.0;
+
 pub struct CpuCycles(pub u32);
+/// Cycles of the GPU's video clock
 pub struct GpuCycles(pub u32);
-pub struct HBlankCycles(pub u32);
 
-// GPU and HBlank cycles are a bit wrong because we don't have access to the real gpu timing values
+// The timers don't know the video mode, so GPU cycles are converted with NTSC timings. PAL's video
+// clock is within 1% of it
 
 impl From<GpuCycles> for CpuCycles {
     fn from(gpu_cycles: GpuCycles) -> Self {
-        CpuCycles((gpu_cycles.0 as f32 * 3.2) as u32)
-    }
-}
-
-impl From<HBlankCycles> for CpuCycles {
-    fn from(h_cycles: HBlankCycles) -> Self {
-        GpuCycles(h_cycles.0 * 853).into()
+        let cycles = gpu_cycles.0 as f64 * CPU_CLOCK / VideoMode::Ntsc.video_clock();
+        CpuCycles(cycles.round() as u32)
     }
 }
 
 #[derive(Copy, Clone)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 struct PendingEvent {
-    id: u32,
+    id: u64,
     target: ScheduleTarget,
-    cycles: u32,
-    complete: bool,
+    /// Absolute cycle the event fires on
+    timestamp: u64,
+}
+
+// BinaryHeap is a max heap, so the ordering is reversed to put the soonest event on top.
+// Events due on the same cycle fire in the order they were scheduled
+impl Ord for PendingEvent {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.timestamp, other.id).cmp(&(self.timestamp, self.id))
+    }
+}
+
+impl PartialOrd for PendingEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
-pub struct EventHandle(u32);
+impl PartialEq for PendingEvent {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
 
-const EVENT_SLOTS: usize = 32;
+impl Eq for PendingEvent {}
 
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub struct EventHandle(u64);
+
+/// Events are stored with absolute 64 bit timestamps, so they can't wrap around into the past no
+/// matter how long the emulator runs. Delays are still passed in as relative `CpuCycles`
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct Scheduler {
-    pending_events: [PendingEvent; EVENT_SLOTS],
-    next_id: u32,
+    pending_events: BinaryHeap<PendingEvent>,
+    /// System cycles since power on. This is the machine's only clock, `PSXEmu::cycle_count` reads it
+    pub(crate) current_cycle: u64,
+    next_id: u64,
+    /// Events fired since take_fired_events was last called, by name
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    fired_events: BTreeMap<&'static str, u64>,
 }
 
 impl Scheduler {
     pub fn new() -> Self {
         Self {
-            pending_events: [PendingEvent {
-                id: 0,
-                target: GpuHblank,
-                cycles: 0,
-                complete: true,
-            }; EVENT_SLOTS],
+            pending_events: BinaryHeap::new(),
+            current_cycle: 0,
             next_id: 0,
+            fired_events: BTreeMap::new(),
         }
     }
 
     pub fn schedule_event(&mut self, target: ScheduleTarget, cycles: CpuCycles) -> EventHandle {
         let id = self.next_id();
-        for i in 0..EVENT_SLOTS {
-            if self.pending_events[i].complete {
-                self.pending_events[i] = PendingEvent {
-                    id,
-                    target: target,
-                    cycles: cycles.0,
-                    complete: false,
-                };
-                return EventHandle(id);
-            }
-        }
-        // If we made it throug the loop, then there are no open event slots
-        panic!("Unable to find an open event slot!");
+        self.pending_events.push(PendingEvent {
+            id,
+            target,
+            timestamp: self.current_cycle + cycles.0 as u64,
+        });
+        EventHandle(id)
     }
 
     pub fn run_cycle(&mut self, emu: &mut R3000, main_bus: &mut MainBus) {
-        for i in 0..EVENT_SLOTS {
-            if !self.pending_events[i].complete {
-                if self.pending_events[i].cycles == 0 {
-                    self.execute(&self.pending_events[i].target.clone(), emu, main_bus);
-                    self.pending_events[i].complete = true;
-                } else {
-                    self.pending_events[i].cycles -= 1;
-                }
-            }
+        // Events scheduled by a handler wait for the next cycle, even with a delay of 0. Otherwise
+        // an event that reschedules itself with no delay would keep this loop going forever
+        let scheduled_before = self.next_id;
+        while let Some(target) = self.pop_due_event(scheduled_before) {
+            *self.fired_events.entry(target.name()).or_default() += 1;
+            self.execute(&target, emu, main_bus);
         }
+        self.current_cycle += 1;
     }
 
-    pub fn invalidate_all_events_of_target(&mut self, target: ScheduleTarget) {
-        for event in &mut self.pending_events {
-            if discriminant(&event.target) == discriminant(&target) {
-                event.complete = true;
-            }
+    /// Takes the count of each kind of event fired since the last call
+    pub fn take_fired_events(&mut self) -> BTreeMap<&'static str, u64> {
+        std::mem::take(&mut self.fired_events)
+    }
+
+    /// Removes the next event due on or before the current cycle, if it was scheduled before
+    /// `scheduled_before`. Events are ordered by timestamp then id, so once the next event is too
+    /// new, every due event after it is too
+    fn pop_due_event(&mut self, scheduled_before: u64) -> Option<ScheduleTarget> {
+        let next = self.pending_events.peek()?;
+        if next.timestamp > self.current_cycle || next.id >= scheduled_before {
+            return None;
         }
+        self.pending_events.pop().map(|event| event.target)
+    }
+
+    pub fn invalidate_all_events_of_target(&mut self, target: ScheduleTarget) {
+        self.pending_events
+            .retain(|event| discriminant(&event.target) != discriminant(&target));
     }
 
     pub fn invalidate_exact_events_of_target(&mut self, target: ScheduleTarget) {
-        for event in &mut self.pending_events {
-            if event.target == target {
-                event.complete = true;
-            }
-        }
+        self.pending_events.retain(|event| event.target != target);
     }
 
     pub fn cycles_remaining(&self, handle: &EventHandle) -> Option<CpuCycles> {
-        for event in &self.pending_events {
-            if event.id == handle.0 {
-                return Some(CpuCycles(event.cycles));
-            }
-        }
-        None
+        self.pending_events
+            .iter()
+            .find(|event| event.id == handle.0)
+            .map(|event| CpuCycles(event.timestamp.saturating_sub(self.current_cycle) as u32))
     }
 
     fn execute(&mut self, target: &ScheduleTarget, cpu: &mut R3000, main_bus: &mut MainBus) {
         match target {
             GpuHblank => {
                 main_bus.gpu.hblank_event(cpu, self);
+                main_bus.timers.hblank_start(cpu, self);
             }
             TimerOverflow(timer_num) => {
                 main_bus.timers.timer_overflow_event(cpu, self, *timer_num);
@@ -136,18 +183,113 @@ impl Scheduler {
             ScheduleTarget::CDIrq => {
                 cpu.fire_external_interrupt(InterruptSource::CDROM);
             }
+            ScheduleTarget::CDSpeedChange => {
+                main_bus.cd_drive.finish_speed_change();
+            }
             ScheduleTarget::ControllerIRQ => {
                 controller_delay_event(cpu, &mut main_bus.controllers);
             }
+            ScheduleTarget::SpuTransfer => {
+                main_bus.spu.transfer_event(self);
+            }
             ScheduleTarget::GpuVblank => {
-                main_bus.gpu.vblank_event(cpu, self);
+                if main_bus.gpu.vblank_event(cpu, self) {
+                    main_bus.timers.vblank_start(self);
+                    main_bus.controllers.vblank();
+                } else {
+                    main_bus.timers.vblank_end();
+                }
             }
         }
     }
 
-    fn next_id(&mut self) -> u32 {
+    fn next_id(&mut self) -> u64 {
         let id = self.next_id;
         self.next_id += 1;
         id
     }
 }
+
+#[cfg(test)]
+mod scheduler_tests {
+    use super::*;
+
+    /// Runs the scheduler for `cycles` cycles. Every event that fires is logged with the cycle it
+    /// fired on, then rescheduled with the period from `periods`
+    fn run_periodic(
+        scheduler: &mut Scheduler,
+        periods: &[(ScheduleTarget, u32)],
+        cycles: u64,
+    ) -> Vec<(ScheduleTarget, u64)> {
+        let mut fired = vec![];
+        for _ in 0..cycles {
+            let scheduled_before = scheduler.next_id;
+            while let Some(target) = scheduler.pop_due_event(scheduled_before) {
+                fired.push((target, scheduler.current_cycle));
+                let (_, period) = periods.iter().find(|(t, _)| *t == target).unwrap();
+                scheduler.schedule_event(target, CpuCycles(*period));
+            }
+            scheduler.current_cycle += 1;
+        }
+        fired
+    }
+
+    #[test]
+    fn test_periodic_events_past_32_bits() {
+        let mut scheduler = Scheduler::new();
+        let start = u32::MAX as u64 - 250_000;
+        scheduler.current_cycle = start;
+
+        let periods = [
+            (ScheduleTarget::GpuHblank, 3413),
+            (ScheduleTarget::TimerOverflow(1), 1000),
+            // CD events are scheduled hundreds of thousands of cycles out
+            (ScheduleTarget::CDPacket(0), 451_584),
+        ];
+        for (target, period) in periods {
+            scheduler.schedule_event(target, CpuCycles(period));
+        }
+        let fired = run_periodic(&mut scheduler, &periods, 1_000_000);
+
+        for (target, period) in periods {
+            let times: Vec<u64> = fired
+                .iter()
+                .filter(|(t, _)| *t == target)
+                .map(|(_, time)| *time)
+                .collect();
+            let expected: Vec<u64> = (1..)
+                .map(|n| start + n * period as u64)
+                .take_while(|time| *time < start + 1_000_000)
+                .collect();
+            assert_eq!(times, expected);
+        }
+        assert!(scheduler.current_cycle > u32::MAX as u64);
+    }
+
+    #[test]
+    fn test_cycles_remaining_and_invalidation() {
+        let mut scheduler = Scheduler::new();
+        scheduler.current_cycle = u32::MAX as u64 - 10;
+        let packet = scheduler.schedule_event(ScheduleTarget::CDPacket(1), CpuCycles(100));
+        scheduler.schedule_event(ScheduleTarget::CDPacket(2), CpuCycles(50));
+        scheduler.schedule_event(ScheduleTarget::CDIrq, CpuCycles(20));
+        run_periodic(&mut scheduler, &[], 20);
+        assert_eq!(scheduler.cycles_remaining(&packet).unwrap().0, 80);
+
+        scheduler.invalidate_exact_events_of_target(ScheduleTarget::CDPacket(2));
+        assert_eq!(scheduler.pending_events.len(), 2);
+        scheduler.invalidate_all_events_of_target(ScheduleTarget::CDPacket(0));
+        assert!(scheduler.cycles_remaining(&packet).is_none());
+    }
+
+    #[test]
+    fn test_zero_delay_reschedule_waits_a_cycle() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule_event(ScheduleTarget::CDIrq, CpuCycles(0));
+        let fired = run_periodic(&mut scheduler, &[(ScheduleTarget::CDIrq, 0)], 5);
+
+        let times: Vec<u64> = fired.iter().map(|(_, time)| *time).collect();
+        assert_eq!(times, vec![0, 1, 2, 3, 4]);
+        assert_eq!(scheduler.cycles_remaining(&EventHandle(5)).unwrap().0, 0);
+    }
+}
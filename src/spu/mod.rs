@@ -0,0 +1,617 @@
+use bit_field::BitField;
+use log::warn;
+use byteorder::{ByteOrder, LittleEndian};
+
+use std::collections::VecDeque;
+
+use self::voice::Voice;
+use crate::cdrom::CDDrive;
+use crate::scheduler::{CpuCycles, ScheduleTarget, Scheduler};
+
+mod voice;
+
+const VOICE_COUNT: usize = 24;
+const VOICE_REGISTERS_END: u32 = 0x1F801D80;
+
+/// The SPU generates one sample at 44.1KHz
+pub(crate) const CYCLES_PER_SAMPLE: usize = 768;
+
+// The first 4KB of SPU RAM holds four capture buffers of 0x200 samples each
+const CAPTURE_SAMPLES: u32 = 0x200;
+const CD_LEFT_CAPTURE: u32 = 0x000;
+const CD_RIGHT_CAPTURE: u32 = 0x400;
+const VOICE1_CAPTURE: u32 = 0x800;
+const VOICE3_CAPTURE: u32 = 0xC00;
+
+/// Halfwords the data transfer FIFO holds
+const TRANSFER_FIFO_SIZE: usize = 32;
+/// Cpu cycles a manual write takes to move each halfword from the FIFO into SPU RAM
+const TRANSFER_CYCLES_PER_HALFWORD: u32 = 16;
+
+/// Mixed samples are dropped once this many are waiting, so a frontend without audio output
+/// doesn't grow the buffer forever
+const MAX_BUFFERED_SAMPLES: usize = 44100;
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+enum SpuMode {
+    Stop = 0,
+    ManualWrite = 1,
+    DMAwrite = 2,
+    DMAread = 3,
+}
+
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub struct SPU {
+    main_volume: u32,
+    reverb_volume: u32,
+    spu_control: u16,
+    voice0_volume: u32,
+    current_mode: SpuMode,
+
+    voice_registers: Vec<u8>,
+
+    transfer_address_register: u16,
+    internal_transfer_address: u32,
+    /// Written through 0x1F801DA8. Drained into SPU RAM while the transfer mode is manual write
+    transfer_fifo: VecDeque<u16>,
+    /// Set while a halfword is on its way from the FIFO to SPU RAM. SPUSTAT bit 10
+    transfer_busy: bool,
+
+    memory: Vec<u8>,
+    irq_addr: u32,
+    pending_irq_acked: bool,
+
+    voices: [Voice; VOICE_COUNT],
+    capture_index: u32,
+    #[cfg_attr(feature = "savestate", serde(skip))]
+    output: VecDeque<(i16, i16)>,
+
+    cycle_count: usize,
+    sample_cycles: usize,
+}
+
+impl SPU {
+    pub fn new() -> Self {
+        Self {
+            main_volume: 0,
+            reverb_volume: 0,
+            spu_control: 0x8000, //Start with spu enabled
+            voice0_volume: 0,
+            current_mode: SpuMode::Stop,
+            voice_registers: vec![0; 608],
+
+            internal_transfer_address: 0,
+            transfer_address_register: 0,
+            transfer_fifo: VecDeque::new(),
+            transfer_busy: false,
+            irq_addr: 1,
+
+            memory: vec![0; 0x800000],
+
+            pending_irq_acked: true,
+
+            voices: Default::default(),
+            capture_index: 0,
+            output: VecDeque::new(),
+
+            cycle_count: 0,
+            sample_cycles: 0,
+        }
+    }
+
+    pub fn read_half_word(&mut self, addr: u32) -> u16 {
+        let val = match addr {
+            0x1F801DAE => self.status_register(),
+            0x1F801DAA => self.spu_control,
+            0x1F801DAC => 0x4, //SPU transfer control
+            0x1F801DA6 => self.transfer_address_register,
+            0x1F801DA8 => self.pop_transfer_fifo(),
+            0x1F801D9C => self.endx() as u16,
+            0x1F801D9E => (self.endx() >> 16) as u16,
+            0x1F801C00..=0x1F801D7F if addr & 0xF == 0xC => {
+                // Current ADSR volume
+                self.voices[voice_index(addr)].level() as u16
+            }
+            0x1F801C00..=0x1F801E5F => {
+                let offset = addr - 0x1F801C00;
+                LittleEndian::read_u16(
+                    &self.voice_registers[offset as usize..(offset + 2) as usize],
+                )
+            }
+            _ => 0, //{println!("Read unknown SPU address {:#X}", addr); 0}
+        };
+        //println!("Reading spu {:#X}  val {:#X}", addr, val);
+        val
+    }
+
+    pub fn write_half_word(&mut self, addr: u32, value: u16, scheduler: &mut Scheduler) {
+        //println!("Writing spu {:#X} v {:#X}", addr, value);
+        match addr {
+            0x1F801DA4 => self.irq_addr = value as u32,
+            0x1F801DA8 => self.push_transfer_fifo(value, scheduler), //SPU data transfer fifo
+            0x1F801DAA => {
+                self.spu_control = value;
+                self.current_mode = match value.get_bits(4..6) {
+                    0 => SpuMode::Stop,
+                    1 => SpuMode::ManualWrite,
+                    2 => SpuMode::DMAwrite,
+                    3 => SpuMode::DMAread,
+                    i => panic!("Unknown SPU mode {}", i),
+                };
+                self.start_transfer(scheduler);
+            }
+            0x1F801DA6 => self.set_transfer_address(value),
+            // Key on and off act on every 1 bit written, whatever state the voice is in. Reading them
+            // back gives the last value written
+            0x1F801D88..=0x1F801D8E => {
+                self.set_register(addr, value);
+                let voices = (value as u32) << if addr & 0x2 != 0 { 16 } else { 0 };
+                if addr < 0x1F801D8C {
+                    self.key_on(voices);
+                } else {
+                    self.key_off(voices);
+                }
+            }
+            // ENDX is read only
+            0x1F801D9C | 0x1F801D9E => (),
+
+            0x1F801C00..=0x1F801E5F => {
+                //println!("Write SPU voice reg at addr {:#X} with val {:#X}", addr, value);
+                self.set_register(addr, value);
+                if addr < VOICE_REGISTERS_END && addr & 0xF == 0xE {
+                    self.voices[voice_index(addr)].repeat_address = value;
+                }
+            }
+            _ => warn!(target: "psx::spu", "Wrote unknown SPU address {:#X} with {:#X}", addr, value),
+        }
+    }
+
+    fn set_transfer_address(&mut self, addr: u16) {
+        self.internal_transfer_address = (addr << 3) as u32;
+        self.transfer_address_register = addr;
+    }
+
+    fn push_transfer_fifo(&mut self, value: u16, scheduler: &mut Scheduler) {
+        if self.transfer_fifo.len() == TRANSFER_FIFO_SIZE {
+            warn!(target: "psx::spu", "SPU transfer FIFO is full, dropped {:#X}", value);
+            return;
+        }
+        self.transfer_fifo.push_back(value);
+        self.start_transfer(scheduler);
+    }
+
+    /// Starts moving the FIFO into SPU RAM, if the transfer mode is manual write and it isn't
+    /// already moving
+    fn start_transfer(&mut self, scheduler: &mut Scheduler) {
+        if matches!(self.current_mode, SpuMode::ManualWrite)
+            && !self.transfer_busy
+            && !self.transfer_fifo.is_empty()
+        {
+            self.transfer_busy = true;
+            scheduler.schedule_event(
+                ScheduleTarget::SpuTransfer,
+                CpuCycles(TRANSFER_CYCLES_PER_HALFWORD),
+            );
+        }
+    }
+
+    /// Writes the next halfword in the FIFO to SPU RAM. Switching out of manual write mode leaves
+    /// the rest queued
+    pub(crate) fn transfer_event(&mut self, scheduler: &mut Scheduler) {
+        self.transfer_busy = false;
+        if !matches!(self.current_mode, SpuMode::ManualWrite) {
+            return;
+        }
+        if let Some(value) = self.transfer_fifo.pop_front() {
+            self.write_transfer(value);
+        }
+        self.start_transfer(scheduler);
+    }
+
+    /// DMA moves data as soon as it starts, so it skips the FIFO
+    pub(crate) fn dma_write(&mut self, value: u16) {
+        self.write_transfer(value);
+    }
+
+    fn write_transfer(&mut self, value: u16) {
+        //println!("SPU FIFO pushing value: {:#X} to addr {:#X}", value, self.internal_transfer_address);
+        LittleEndian::write_u16(
+            &mut self.memory[self.internal_transfer_address as usize
+                ..(self.internal_transfer_address + 2) as usize],
+            value,
+        );
+        self.internal_transfer_address += 2;
+        if self.check_irq() {
+            self.queue_irq();
+        }
+    }
+
+    fn pop_transfer_fifo(&mut self) -> u16 {
+        let address = self.internal_transfer_address as usize;
+        let value = LittleEndian::read_u16(&self.memory[address..address + 2]);
+        self.internal_transfer_address += 2;
+        if self.check_irq() {
+            self.queue_irq();
+        }
+        value
+    }
+
+    fn voice_register(&self, voice: usize, offset: usize) -> u16 {
+        let base = voice * 0x10 + offset;
+        LittleEndian::read_u16(&self.voice_registers[base..base + 2])
+    }
+
+    fn key_on(&mut self, voices: u32) {
+        for voice in 0..VOICE_COUNT {
+            if voices.get_bit(voice) {
+                let start_address = self.voice_register(voice, 0x6);
+                self.voices[voice].key_on(start_address, &self.memory);
+            }
+        }
+    }
+
+    fn key_off(&mut self, voices: u32) {
+        for voice in 0..VOICE_COUNT {
+            if voices.get_bit(voice) {
+                self.voices[voice].key_off();
+            }
+        }
+    }
+
+    /// Advances the SPU by one cpu cycle. The CD drive is the source of the CD audio input
+    pub fn step_cycle(&mut self, cd_drive: &mut CDDrive) {
+        self.sample_cycles += 1;
+        if self.sample_cycles == CYCLES_PER_SAMPLE {
+            self.sample_cycles = 0;
+            let cd_input = cd_drive.next_audio_sample();
+            self.generate_sample(cd_input);
+        }
+    }
+
+    /// Overwrites all of SPU RAM, for a defined power on state
+    pub(crate) fn fill_memory(&mut self, bytes: impl Iterator<Item = u8>) {
+        for (byte, value) in self.memory.iter_mut().zip(bytes) {
+            *byte = value;
+        }
+    }
+
+    /// Takes every mixed stereo sample generated since the last call
+    pub fn drain_output(&mut self) -> Vec<(i16, i16)> {
+        self.output.drain(..).collect()
+    }
+
+    fn register(&self, addr: u32) -> u16 {
+        let offset = (addr - 0x1F801C00) as usize;
+        LittleEndian::read_u16(&self.voice_registers[offset..offset + 2])
+    }
+
+    fn set_register(&mut self, addr: u32, value: u16) {
+        let offset = (addr - 0x1F801C00) as usize;
+        LittleEndian::write_u16(&mut self.voice_registers[offset..offset + 2], value);
+    }
+
+    /// One bit per voice that has reached a loop end flag since it was last keyed on
+    fn endx(&self) -> u32 {
+        self.voices
+            .iter()
+            .enumerate()
+            .filter(|(_, voice)| voice.reached_end)
+            .fold(0, |endx, (index, _)| endx | 1 << index)
+    }
+
+    fn generate_sample(&mut self, (cd_left, cd_right): (i16, i16)) {
+        let mut outputs = [0; VOICE_COUNT];
+        let mut left = 0;
+        let mut right = 0;
+        for (voice, output) in outputs.iter_mut().enumerate() {
+            let pitch = self.voice_register(voice, 0x4);
+            let adsr = self.voice_register(voice, 0x8) as u32
+                | (self.voice_register(voice, 0xA) as u32) << 16;
+            *output = self.voices[voice].next_sample(pitch, adsr, &self.memory);
+            left += apply_volume(*output, fixed_volume(self.voice_register(voice, 0x0)));
+            right += apply_volume(*output, fixed_volume(self.voice_register(voice, 0x2)));
+        }
+
+        if self.spu_control.get_bit(0) {
+            left += apply_volume(cd_left, self.register(0x1F801DB0) as i16);
+            right += apply_volume(cd_right, self.register(0x1F801DB2) as i16);
+        }
+        // Bit 1 enables the external audio input, but nothing is ever connected to it
+
+        let left = apply_volume(clamp_sample(left), fixed_volume(self.register(0x1F801D80)));
+        let right = apply_volume(clamp_sample(right), fixed_volume(self.register(0x1F801D82)));
+        let sample = if self.spu_control.get_bit(14) {
+            (clamp_sample(left), clamp_sample(right))
+        } else {
+            (0, 0)
+        };
+
+        if self.output.len() == MAX_BUFFERED_SAMPLES {
+            self.output.pop_front();
+        }
+        self.output.push_back(sample);
+
+        self.write_capture(CD_LEFT_CAPTURE, cd_left);
+        self.write_capture(CD_RIGHT_CAPTURE, cd_right);
+        self.write_capture(VOICE1_CAPTURE, outputs[1]);
+        self.write_capture(VOICE3_CAPTURE, outputs[3]);
+        self.capture_index = (self.capture_index + 1) % CAPTURE_SAMPLES;
+    }
+
+    fn write_capture(&mut self, buffer: u32, sample: i16) {
+        let address = (buffer + self.capture_index * 2) as usize;
+        LittleEndian::write_i16(&mut self.memory[address..address + 2], sample);
+    }
+
+    fn queue_irq(&mut self) {
+        self.pending_irq_acked = false;
+    }
+
+    fn check_irq(&self) -> bool {
+        //println!("addr {:#X} irq addr {:#X}", self.internal_transfer_address, self.irq_addr << 3);
+        self.internal_transfer_address == self.irq_addr << 3
+    }
+
+    pub fn check_and_ack_irq(&mut self) -> bool {
+        self.cycle_count += 1;
+
+        if self.cycle_count % (340_220 / 2) == 0 && self.spu_control.get_bit(15) {
+            self.queue_irq();
+        }
+
+        let result = !self.pending_irq_acked;
+        self.pending_irq_acked = true;
+        result
+    }
+
+    fn status_register(&self) -> u16 {
+        //println!("Reading spu stat. mode is {:?}", self.current_mode);
+        //let mut result: u16 = 0;
+
+        //result |= self.current_mode.clone() as u16;
+
+        //result
+
+        let mut status = self.spu_control & 0x3F;
+        status.set_bit(7, self.spu_control.get_bit(5));
+        status.set_bit(10, self.transfer_busy);
+        // Games poll this to know which half of the capture buffers is safe to read
+        status.set_bit(11, self.capture_index >= CAPTURE_SAMPLES / 2);
+        status
+    }
+}
+
+fn voice_index(addr: u32) -> usize {
+    ((addr - 0x1F801C00) / 0x10) as usize
+}
+
+/// Converts a voice or main volume register in fixed mode to a signed volume.
+/// Sweep mode isn't emulated, so sweeping volumes are held at full volume
+fn fixed_volume(register: u16) -> i16 {
+    if register.get_bit(15) {
+        i16::MAX
+    } else {
+        (register << 1) as i16
+    }
+}
+
+fn apply_volume(sample: i16, volume: i16) -> i32 {
+    (sample as i32 * volume as i32) >> 15
+}
+
+fn clamp_sample(sample: i32) -> i16 {
+    sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+#[cfg(test)]
+mod spu_tests {
+    use super::voice::BLOCK_SIZE;
+    use super::*;
+    use crate::Scheduler;
+
+    const SINE_ADDRESS: u16 = 0x1000 >> 3;
+
+    /// One period of a sine per ADPCM block, quantized to 4 bits with filter 0 and shift 0
+    fn sine_nibbles() -> Vec<i16> {
+        (0..28)
+            .map(|i| (7.0 * (i as f64 * std::f64::consts::TAU / 28.0).sin()).round() as i16)
+            .collect()
+    }
+
+    fn sine_block(flags: u8) -> Vec<u16> {
+        let nibbles = sine_nibbles();
+        let mut block = vec![(flags as u16) << 8];
+        for pair in nibbles.chunks(4) {
+            let mut half_word = 0;
+            for (i, nibble) in pair.iter().enumerate() {
+                half_word |= ((*nibble as u16) & 0xF) << (i * 4);
+            }
+            block.push(half_word);
+        }
+        assert_eq!(block.len() * 2, BLOCK_SIZE as usize);
+        block
+    }
+
+    fn run_samples(spu: &mut SPU, samples: usize) {
+        let mut cd_drive = CDDrive::new();
+        for _ in 0..samples * CYCLES_PER_SAMPLE {
+            spu.step_cycle(&mut cd_drive);
+        }
+    }
+
+    /// Writes straight to SPU RAM, like a finished manual write
+    fn upload(spu: &mut SPU, address: u16, half_words: impl IntoIterator<Item = u16>) {
+        spu.set_transfer_address(address);
+        for half_word in half_words {
+            spu.write_transfer(half_word);
+        }
+    }
+
+    #[test]
+    fn test_voice1_capture_buffer() {
+        let mut spu = SPU::new();
+        let mut scheduler = Scheduler::new();
+
+        // Two sine blocks that loop forever
+        upload(&mut spu, SINE_ADDRESS, sine_block(0x4).into_iter().chain(sine_block(0x3)));
+
+        // Voice 1 at 44.1KHz, instant attack and a full sustain level
+        spu.write_half_word(0x1F801C14, 0x1000, &mut scheduler);
+        spu.write_half_word(0x1F801C16, SINE_ADDRESS, &mut scheduler);
+        spu.write_half_word(0x1F801C18, 0x000F, &mut scheduler);
+        spu.write_half_word(0x1F801C1A, 0x1F00, &mut scheduler);
+        spu.write_half_word(0x1F801D88, 1 << 1, &mut scheduler);
+
+        run_samples(&mut spu, CAPTURE_SAMPLES as usize / 2 - 1);
+        assert!(!spu.read_half_word(0x1F801DAE).get_bit(11));
+        run_samples(&mut spu, 1);
+        assert!(spu.read_half_word(0x1F801DAE).get_bit(11));
+        run_samples(&mut spu, CAPTURE_SAMPLES as usize / 2);
+        assert!(!spu.read_half_word(0x1F801DAE).get_bit(11));
+
+        let nibbles = sine_nibbles();
+        spu.write_half_word(0x1F801DA6, (VOICE1_CAPTURE >> 3) as u16, &mut scheduler);
+        let captured: Vec<i16> = (0..CAPTURE_SAMPLES)
+            .map(|_| spu.read_half_word(0x1F801DA8) as i16)
+            .collect();
+
+        // The first few samples are still in the attack phase. After that each output is the
+        // gaussian interpolation of the last four samples, with the weights for a whole position
+        assert_eq!(captured[0], 0);
+        let weights = [0x12C7, 0x59B3, 0x1307, -1];
+        for (i, sample) in captured.iter().enumerate().skip(3) {
+            let interpolated: i32 = weights
+                .iter()
+                .enumerate()
+                .map(|(j, weight)| (nibbles[(i + j + 25) % 28] as i32 * 0x1000 * weight) >> 15)
+                .sum();
+            let expected = (interpolated * 0x7FFF) >> 15;
+            assert_eq!(*sample as i32, expected, "sample {}", i);
+        }
+
+        // Voice 3 and the CD input are silent
+        spu.write_half_word(0x1F801DA6, (CD_LEFT_CAPTURE >> 3) as u16, &mut scheduler);
+        for _ in 0..CAPTURE_SAMPLES * 2 {
+            assert_eq!(spu.read_half_word(0x1F801DA8), 0);
+        }
+        spu.write_half_word(0x1F801DA6, (VOICE3_CAPTURE >> 3) as u16, &mut scheduler);
+        for _ in 0..CAPTURE_SAMPLES {
+            assert_eq!(spu.read_half_word(0x1F801DA8), 0);
+        }
+    }
+
+    #[test]
+    fn test_voice_end_flag() {
+        let mut spu = SPU::new();
+        let mut scheduler = Scheduler::new();
+
+        // A single block that ends without repeating
+        upload(&mut spu, SINE_ADDRESS, sine_block(0x1));
+
+        // Voice 17 at 44.1KHz, so it finishes the block after 28 samples
+        spu.write_half_word(0x1F801D14, 0x1000, &mut scheduler);
+        spu.write_half_word(0x1F801D16, SINE_ADDRESS, &mut scheduler);
+        spu.write_half_word(0x1F801D18, 0x000F, &mut scheduler);
+        spu.write_half_word(0x1F801D8A, 1 << 1, &mut scheduler);
+        assert_eq!(spu.read_half_word(0x1F801D8A), 1 << 1);
+
+        run_samples(&mut spu, 27);
+        assert_eq!(spu.read_half_word(0x1F801D9E), 0);
+        run_samples(&mut spu, 1);
+        assert_eq!(spu.read_half_word(0x1F801D9E), 1 << 1);
+        assert_eq!(spu.read_half_word(0x1F801D9C), 0);
+
+        // Writing the same value again keys the voice on again, which clears its end flag
+        spu.write_half_word(0x1F801D8A, 1 << 1, &mut scheduler);
+        assert_eq!(spu.read_half_word(0x1F801D9E), 0);
+
+        // Key off reads back what was written, even once the voice has gone silent
+        spu.write_half_word(0x1F801D8E, 1 << 1, &mut scheduler);
+        run_samples(&mut spu, 100);
+        assert_eq!(spu.read_half_word(0x1F801D8E), 1 << 1);
+        assert_eq!(spu.read_half_word(0x1F801C0C + 17 * 0x10), 0);
+    }
+
+    type Stage = fn(&mut SPU, &mut CDDrive, &mut Scheduler);
+
+    fn write_cd(cd_drive: &mut CDDrive, scheduler: &mut Scheduler, index: u8, addr: u32, val: u8) {
+        cd_drive.write_byte(0x1F801800, index, scheduler);
+        cd_drive.write_byte(addr, val, scheduler);
+    }
+
+    /// Full volume at every stage of the CD audio path
+    fn cd_audio_setup() -> (SPU, CDDrive, Scheduler) {
+        let mut spu = SPU::new();
+        let mut scheduler = Scheduler::new();
+        spu.write_half_word(0x1F801DAA, 0xC001, &mut scheduler);
+        spu.write_half_word(0x1F801DB0, 0x7FFF, &mut scheduler);
+        spu.write_half_word(0x1F801DB2, 0x7FFF, &mut scheduler);
+        spu.write_half_word(0x1F801D80, 0x3FFF, &mut scheduler);
+        spu.write_half_word(0x1F801D82, 0x3FFF, &mut scheduler);
+        (spu, CDDrive::new(), scheduler)
+    }
+
+    fn mix_cd_sample(spu: &mut SPU, cd_drive: &mut CDDrive) -> (i16, i16) {
+        cd_drive.push_audio_sample((0x4000, -0x4000));
+        for _ in 0..CYCLES_PER_SAMPLE {
+            spu.step_cycle(cd_drive);
+        }
+        *spu.drain_output().last().unwrap()
+    }
+
+    #[test]
+    fn test_cd_audio_volume_stages() {
+        let (mut spu, mut cd_drive, _) = cd_audio_setup();
+        let (left, right) = mix_cd_sample(&mut spu, &mut cd_drive);
+        assert!(left > 0 && right < 0);
+
+        let stages: [(&str, Stage); 6] = [
+            ("drive mute", |_, cd_drive, scheduler| {
+                write_cd(cd_drive, scheduler, 0, 0x1F801801, 0xB)
+            }),
+            ("drive volume", |_, cd_drive, scheduler| {
+                write_cd(cd_drive, scheduler, 2, 0x1F801802, 0);
+                write_cd(cd_drive, scheduler, 3, 0x1F801801, 0);
+                write_cd(cd_drive, scheduler, 3, 0x1F801803, 0x20);
+            }),
+            ("cd audio enable", |spu, _, scheduler| {
+                spu.write_half_word(0x1F801DAA, 0xC000, scheduler)
+            }),
+            ("cd volume", |spu, _, scheduler| {
+                spu.write_half_word(0x1F801DB0, 0, scheduler);
+                spu.write_half_word(0x1F801DB2, 0, scheduler);
+            }),
+            ("main volume", |spu, _, scheduler| {
+                spu.write_half_word(0x1F801D80, 0, scheduler);
+                spu.write_half_word(0x1F801D82, 0, scheduler);
+            }),
+            ("spu mute", |spu, _, scheduler| {
+                spu.write_half_word(0x1F801DAA, 0x8001, scheduler)
+            }),
+        ];
+
+        for (name, silence) in stages {
+            let (mut spu, mut cd_drive, mut scheduler) = cd_audio_setup();
+            silence(&mut spu, &mut cd_drive, &mut scheduler);
+            assert_eq!(mix_cd_sample(&mut spu, &mut cd_drive), (0, 0), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_cd_volume_latch() {
+        let (mut spu, mut cd_drive, mut scheduler) = cd_audio_setup();
+        write_cd(&mut cd_drive, &mut scheduler, 2, 0x1F801802, 0);
+        write_cd(&mut cd_drive, &mut scheduler, 3, 0x1F801801, 0);
+
+        // Nothing changes until the new volumes are applied
+        let (left, right) = mix_cd_sample(&mut spu, &mut cd_drive);
+        assert!(left > 0 && right < 0);
+        write_cd(&mut cd_drive, &mut scheduler, 3, 0x1F801803, 0);
+        let (left, right) = mix_cd_sample(&mut spu, &mut cd_drive);
+        assert!(left > 0 && right < 0);
+
+        write_cd(&mut cd_drive, &mut scheduler, 3, 0x1F801803, 0x20);
+        assert_eq!(mix_cd_sample(&mut spu, &mut cd_drive), (0, 0));
+    }
+}
@@ -0,0 +1,348 @@
+use bit_field::BitField;
+
+// Each 16 byte ADPCM block is a 2 byte header followed by 28 4-bit samples
+pub(super) const BLOCK_SIZE: u32 = 16;
+const SAMPLES_PER_BLOCK: u32 = 28;
+/// Interpolation looks at the three samples before the current one, so those are kept from the
+/// previous block
+const HISTORY_SAMPLES: usize = 3;
+
+const FILTER_POSITIVE: [i32; 5] = [0, 60, 115, 98, 122];
+const FILTER_NEGATIVE: [i32; 5] = [0, 0, -52, -55, -60];
+
+const MAX_LEVEL: i32 = 0x7FFF;
+const MAX_PITCH: u32 = 0x4000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub(super) enum AdsrPhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    #[default]
+    Off,
+}
+
+/// Playback state of a single voice. The voice registers themselves stay in the SPU's register
+/// block, and the values the voice needs are passed in each sample.
+#[derive(Default)]
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
+pub(super) struct Voice {
+    pub(super) repeat_address: u16,
+    current_address: u32,
+    pitch_counter: u32,
+    /// The end of the previous block followed by the current one
+    samples: [i16; HISTORY_SAMPLES + SAMPLES_PER_BLOCK as usize],
+    // [older, old]
+    history: [i16; 2],
+
+    phase: AdsrPhase,
+    level: i16,
+    envelope_wait: u32,
+    /// Set when the voice passes a block with the loop end flag, cleared by key on. Read through ENDX
+    pub(super) reached_end: bool,
+}
+
+impl Voice {
+    pub(super) fn key_on(&mut self, start_address: u16, memory: &[u8]) {
+        self.current_address = (start_address as u32) << 3;
+        self.pitch_counter = 0;
+        self.history = [0; 2];
+        self.samples = [0; HISTORY_SAMPLES + SAMPLES_PER_BLOCK as usize];
+        self.phase = AdsrPhase::Attack;
+        self.level = 0;
+        self.envelope_wait = 0;
+        self.reached_end = false;
+        self.decode_block(memory);
+    }
+
+    pub(super) fn key_off(&mut self) {
+        if self.phase != AdsrPhase::Off {
+            self.phase = AdsrPhase::Release;
+            self.envelope_wait = 0;
+        }
+    }
+
+    pub(super) fn level(&self) -> i16 {
+        self.level
+    }
+
+    /// Returns the voice's output after the envelope has been applied, then advances by the pitch.
+    /// The counter's whole part picks the sample, and the fraction picks the interpolation weights
+    pub(super) fn next_sample(&mut self, pitch: u16, adsr: u32, memory: &[u8]) -> i16 {
+        if self.phase == AdsrPhase::Off {
+            return 0;
+        }
+
+        let position = (self.pitch_counter >> 12) as usize;
+        let newest = HISTORY_SAMPLES + position;
+        let sample = interpolate(
+            &self.samples[newest - HISTORY_SAMPLES..=newest],
+            (self.pitch_counter >> 4) as u8,
+        );
+        let output = ((sample as i32 * self.level as i32) >> 15) as i16;
+
+        self.pitch_counter += (pitch as u32).min(MAX_PITCH);
+        while self.pitch_counter >= SAMPLES_PER_BLOCK << 12 {
+            self.pitch_counter -= SAMPLES_PER_BLOCK << 12;
+            self.next_block(memory);
+        }
+
+        self.tick_envelope(adsr);
+        output
+    }
+
+    fn next_block(&mut self, memory: &[u8]) {
+        let flags = memory[self.current_address as usize + 1];
+        if flags.get_bit(0) {
+            // Loop end. Without the repeat bit the voice is silenced
+            self.reached_end = true;
+            self.current_address = (self.repeat_address as u32) << 3;
+            if !flags.get_bit(1) {
+                self.phase = AdsrPhase::Release;
+                self.level = 0;
+            }
+        } else {
+            self.current_address = (self.current_address + BLOCK_SIZE) & 0x7FFFF;
+        }
+        self.decode_block(memory);
+    }
+
+    fn decode_block(&mut self, memory: &[u8]) {
+        self.samples.copy_within(SAMPLES_PER_BLOCK as usize.., 0);
+
+        let address = self.current_address as usize;
+        let block = &memory[address..address + BLOCK_SIZE as usize];
+
+        if block[1].get_bit(2) {
+            // Loop start
+            self.repeat_address = (self.current_address >> 3) as u16;
+        }
+
+        let shift = match block[0] & 0xF {
+            shift if shift > 12 => 9,
+            shift => shift,
+        };
+        let filter = ((block[0] >> 4) & 0x7).min(4) as usize;
+
+        for i in 0..SAMPLES_PER_BLOCK as usize {
+            let byte = block[2 + i / 2];
+            let nibble = if i % 2 == 0 { byte & 0xF } else { byte >> 4 };
+            let raw = (((nibble as u16) << 12) as i16 as i32) >> shift;
+            let prediction = (self.history[1] as i32 * FILTER_POSITIVE[filter]
+                + self.history[0] as i32 * FILTER_NEGATIVE[filter]
+                + 32)
+                >> 6;
+            let sample = (raw + prediction).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            self.history = [self.history[1], sample];
+            self.samples[HISTORY_SAMPLES + i] = sample;
+        }
+    }
+
+    fn tick_envelope(&mut self, adsr: u32) {
+        if self.envelope_wait > 0 {
+            self.envelope_wait -= 1;
+            return;
+        }
+
+        // Move on to the next phase once the previous one has reached its target level
+        let sustain_level = ((adsr.get_bits(0..4) + 1) * 0x800) as i32;
+        if self.phase == AdsrPhase::Attack && self.level as i32 == MAX_LEVEL {
+            self.phase = AdsrPhase::Decay;
+        }
+        if self.phase == AdsrPhase::Decay && self.level as i32 <= sustain_level {
+            self.phase = AdsrPhase::Sustain;
+        }
+
+        // (exponential, decreasing, shift, step)
+        let (exponential, decreasing, shift, step) = match self.phase {
+            AdsrPhase::Attack => (
+                adsr.get_bit(15),
+                false,
+                adsr.get_bits(10..15),
+                7 - adsr.get_bits(8..10) as i32,
+            ),
+            AdsrPhase::Decay => (true, true, adsr.get_bits(4..8), -8),
+            AdsrPhase::Sustain => {
+                let decreasing = adsr.get_bit(30);
+                let step = adsr.get_bits(22..24) as i32;
+                (
+                    adsr.get_bit(31),
+                    decreasing,
+                    adsr.get_bits(24..29),
+                    if decreasing { -8 + step } else { 7 - step },
+                )
+            }
+            AdsrPhase::Release => (adsr.get_bit(21), true, adsr.get_bits(16..21), -8),
+            AdsrPhase::Off => return,
+        };
+
+        let mut cycles = 1 << shift.saturating_sub(11);
+        let mut step = step << 11u32.saturating_sub(shift);
+        if exponential && !decreasing && self.level as i32 > 0x6000 {
+            cycles *= 4;
+        }
+        if exponential && decreasing {
+            step = step * self.level as i32 / 0x8000;
+        }
+
+        self.level = (self.level as i32 + step).clamp(0, MAX_LEVEL) as i16;
+        self.envelope_wait = cycles - 1;
+
+        if self.phase == AdsrPhase::Release && self.level == 0 {
+            self.phase = AdsrPhase::Off;
+        }
+    }
+}
+
+/// Gaussian interpolation between the four samples up to and including the current one, oldest
+/// first. `index` is the fractional position between the two newest samples. Each product is
+/// shifted down before summing, like the hardware does
+fn interpolate(samples: &[i16], index: u8) -> i16 {
+    let index = index as usize;
+    let weights = [
+        GAUSS_TABLE[0xFF - index],
+        GAUSS_TABLE[0x1FF - index],
+        GAUSS_TABLE[0x100 + index],
+        GAUSS_TABLE[index],
+    ];
+    // The weights add up to less than 1.0, so the sum can't overflow
+    samples
+        .iter()
+        .zip(weights)
+        .map(|(sample, weight)| (*sample as i32 * weight as i32) >> 15)
+        .sum::<i32>() as i16
+}
+
+/// Interpolation weights from the SPU's ROM. Each set of four weights adds up to just under 0x8000
+#[rustfmt::skip]
+const GAUSS_TABLE: [i16; 512] = [
+    -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001,
+    -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001, -0x0001,
+    0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0001,
+    0x0001, 0x0001, 0x0001, 0x0002, 0x0002, 0x0002, 0x0003, 0x0003,
+    0x0003, 0x0004, 0x0004, 0x0005, 0x0005, 0x0006, 0x0007, 0x0007,
+    0x0008, 0x0009, 0x0009, 0x000A, 0x000B, 0x000C, 0x000D, 0x000E,
+    0x000F, 0x0010, 0x0011, 0x0012, 0x0013, 0x0015, 0x0016, 0x0018,
+    0x0019, 0x001B, 0x001C, 0x001E, 0x0020, 0x0021, 0x0023, 0x0025,
+    0x0027, 0x0029, 0x002C, 0x002E, 0x0030, 0x0033, 0x0035, 0x0038,
+    0x003A, 0x003D, 0x0040, 0x0043, 0x0046, 0x0049, 0x004D, 0x0050,
+    0x0054, 0x0057, 0x005B, 0x005F, 0x0063, 0x0067, 0x006B, 0x006F,
+    0x0074, 0x0078, 0x007D, 0x0082, 0x0087, 0x008C, 0x0091, 0x0096,
+    0x009C, 0x00A1, 0x00A7, 0x00AD, 0x00B3, 0x00BA, 0x00C0, 0x00C7,
+    0x00CD, 0x00D4, 0x00DB, 0x00E3, 0x00EA, 0x00F2, 0x00FA, 0x0101,
+    0x010A, 0x0112, 0x011B, 0x0123, 0x012C, 0x0135, 0x013F, 0x0148,
+    0x0152, 0x015C, 0x0166, 0x0171, 0x017B, 0x0186, 0x0191, 0x019C,
+    0x01A8, 0x01B4, 0x01C0, 0x01CC, 0x01D9, 0x01E5, 0x01F2, 0x0200,
+    0x020D, 0x021B, 0x0229, 0x0237, 0x0246, 0x0255, 0x0264, 0x0273,
+    0x0283, 0x0293, 0x02A3, 0x02B4, 0x02C4, 0x02D6, 0x02E7, 0x02F9,
+    0x030B, 0x031D, 0x0330, 0x0343, 0x0356, 0x036A, 0x037E, 0x0392,
+    0x03A7, 0x03BC, 0x03D1, 0x03E7, 0x03FC, 0x0413, 0x042A, 0x0441,
+    0x0458, 0x0470, 0x0488, 0x04A0, 0x04B9, 0x04D2, 0x04EC, 0x0506,
+    0x0520, 0x053B, 0x0556, 0x0572, 0x058E, 0x05AA, 0x05C7, 0x05E4,
+    0x0601, 0x061F, 0x063E, 0x065C, 0x067C, 0x069B, 0x06BB, 0x06DC,
+    0x06FD, 0x071E, 0x0740, 0x0762, 0x0784, 0x07A7, 0x07CB, 0x07EF,
+    0x0813, 0x0838, 0x085D, 0x0883, 0x08A9, 0x08D0, 0x08F7, 0x091E,
+    0x0946, 0x096F, 0x0998, 0x09C1, 0x09EB, 0x0A16, 0x0A40, 0x0A6C,
+    0x0A98, 0x0AC4, 0x0AF1, 0x0B1E, 0x0B4C, 0x0B7A, 0x0BA9, 0x0BD8,
+    0x0C07, 0x0C38, 0x0C68, 0x0C99, 0x0CCB, 0x0CFD, 0x0D30, 0x0D63,
+    0x0D97, 0x0DCB, 0x0E00, 0x0E35, 0x0E6B, 0x0EA1, 0x0ED7, 0x0F0F,
+    0x0F46, 0x0F7F, 0x0FB7, 0x0FF1, 0x102A, 0x1065, 0x109F, 0x10DB,
+    0x1116, 0x1153, 0x118F, 0x11CD, 0x120B, 0x1249, 0x1288, 0x12C7,
+    0x1307, 0x1347, 0x1388, 0x13C9, 0x140B, 0x144D, 0x1490, 0x14D4,
+    0x1517, 0x155C, 0x15A0, 0x15E6, 0x162C, 0x1672, 0x16B9, 0x1700,
+    0x1747, 0x1790, 0x17D8, 0x1821, 0x186B, 0x18B5, 0x1900, 0x194B,
+    0x1996, 0x19E2, 0x1A2E, 0x1A7B, 0x1AC8, 0x1B16, 0x1B64, 0x1BB3,
+    0x1C02, 0x1C51, 0x1CA1, 0x1CF1, 0x1D42, 0x1D93, 0x1DE5, 0x1E37,
+    0x1E89, 0x1EDC, 0x1F2F, 0x1F82, 0x1FD6, 0x202A, 0x207F, 0x20D4,
+    0x2129, 0x217F, 0x21D5, 0x222C, 0x2282, 0x22DA, 0x2331, 0x2389,
+    0x23E1, 0x2439, 0x2492, 0x24EB, 0x2545, 0x259E, 0x25F8, 0x2653,
+    0x26AD, 0x2708, 0x2763, 0x27BE, 0x281A, 0x2876, 0x28D2, 0x292E,
+    0x298B, 0x29E7, 0x2A44, 0x2AA1, 0x2AFF, 0x2B5C, 0x2BBA, 0x2C18,
+    0x2C76, 0x2CD4, 0x2D33, 0x2D91, 0x2DF0, 0x2E4F, 0x2EAE, 0x2F0D,
+    0x2F6C, 0x2FCC, 0x302B, 0x308B, 0x30EA, 0x314A, 0x31AA, 0x3209,
+    0x3269, 0x32C9, 0x3329, 0x3389, 0x33E9, 0x3449, 0x34A9, 0x3509,
+    0x3569, 0x35C9, 0x3629, 0x3689, 0x36E8, 0x3748, 0x37A8, 0x3807,
+    0x3867, 0x38C6, 0x3926, 0x3985, 0x39E4, 0x3A43, 0x3AA2, 0x3B00,
+    0x3B5F, 0x3BBD, 0x3C1B, 0x3C79, 0x3CD7, 0x3D35, 0x3D92, 0x3DEF,
+    0x3E4C, 0x3EA9, 0x3F05, 0x3F62, 0x3FBD, 0x4019, 0x4074, 0x40D0,
+    0x412A, 0x4185, 0x41DF, 0x4239, 0x4292, 0x42EB, 0x4344, 0x439C,
+    0x43F4, 0x444C, 0x44A3, 0x44FA, 0x4550, 0x45A6, 0x45FC, 0x4651,
+    0x46A6, 0x46FA, 0x474E, 0x47A1, 0x47F4, 0x4846, 0x4898, 0x48E9,
+    0x493A, 0x498A, 0x49D9, 0x4A29, 0x4A77, 0x4AC5, 0x4B13, 0x4B5F,
+    0x4BAC, 0x4BF7, 0x4C42, 0x4C8D, 0x4CD7, 0x4D20, 0x4D68, 0x4DB0,
+    0x4DF7, 0x4E3E, 0x4E84, 0x4EC9, 0x4F0E, 0x4F52, 0x4F95, 0x4FD7,
+    0x5019, 0x505A, 0x509A, 0x50DA, 0x5118, 0x5156, 0x5194, 0x51D0,
+    0x520C, 0x5247, 0x5281, 0x52BA, 0x52F3, 0x532A, 0x5361, 0x5397,
+    0x53CC, 0x5401, 0x5434, 0x5467, 0x5499, 0x54CA, 0x54FA, 0x5529,
+    0x5558, 0x5585, 0x55B2, 0x55DE, 0x5608, 0x5632, 0x565B, 0x5684,
+    0x56AB, 0x56D1, 0x56F7, 0x571B, 0x573F, 0x5762, 0x5784, 0x57A4,
+    0x57C4, 0x57E3, 0x5801, 0x581E, 0x583A, 0x5855, 0x586F, 0x5888,
+    0x58A0, 0x58B7, 0x58CD, 0x58E2, 0x58F6, 0x5909, 0x591B, 0x592C,
+    0x593C, 0x594B, 0x5959, 0x5965, 0x5971, 0x597C, 0x5986, 0x598F,
+    0x5997, 0x599E, 0x59A4, 0x59A9, 0x59AD, 0x59B0, 0x59B2, 0x59B3,
+];
+
+#[cfg(test)]
+mod voice_tests {
+    use super::*;
+    use std::fs;
+
+    /// Made by tests/fixtures/spu/generate.py
+    const FIXTURE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/spu");
+    const FIXTURE_PITCHES: [u16; 7] = [0x0400, 0x0800, 0x0C00, 0x1000, 0x1234, 0x2000, 0x3FFF];
+    const SAMPLE_ADDRESS: usize = 0x1000;
+    /// The reference interpolates in floating point, while each product is rounded down here
+    const TOLERANCE: i32 = 4;
+
+    /// Plays the fixture sample at a full envelope level, so the output is just the interpolation
+    fn render(pitch: u16, count: usize) -> Vec<i16> {
+        let sample = fs::read(format!("{}/looped_sample.adpcm", FIXTURE_DIR)).unwrap();
+        let mut memory = vec![0; SAMPLE_ADDRESS * 2];
+        memory[SAMPLE_ADDRESS..SAMPLE_ADDRESS + sample.len()].copy_from_slice(&sample);
+
+        let mut voice = Voice::default();
+        voice.key_on((SAMPLE_ADDRESS >> 3) as u16, &memory);
+        // Skip the attack. A rising sustain holds the level at the top
+        voice.phase = AdsrPhase::Sustain;
+        voice.level = MAX_LEVEL as i16;
+        (0..count).map(|_| voice.next_sample(pitch, 0, &memory)).collect()
+    }
+
+    #[test]
+    fn test_pitch_fixtures() {
+        for pitch in FIXTURE_PITCHES {
+            let fixture: Vec<i16> = fs::read(format!("{}/pitch_{:04x}.pcm", FIXTURE_DIR, pitch))
+                .unwrap()
+                .chunks(2)
+                .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+                .collect();
+            let output = render(pitch, fixture.len());
+            for (i, (actual, expected)) in output.iter().zip(&fixture).enumerate() {
+                assert!(
+                    (*actual as i32 - *expected as i32).abs() <= TOLERANCE,
+                    "pitch {:#X} sample {}: got {}, expected {}",
+                    pitch,
+                    i,
+                    actual,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pitch_is_limited() {
+        assert_eq!(render(0xFFFF, 256), render(0x4000, 256));
+    }
+
+    #[test]
+    fn test_interpolation_weights() {
+        // Every set of weights adds up to just under 1.0, so a constant signal stays put
+        for index in 0..=255 {
+            let level = interpolate(&[0x4000; 4], index);
+            assert!((0x3FB0..0x4000).contains(&level), "index {} gave {:#X}", index, level);
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use crate::cpu::{InterruptSource, R3000};
 use bit_field::BitField;
 use crate::{CpuCycles, Scheduler};
-use crate::scheduler::{EventHandle, GpuCycles, HBlankCycles};
+use crate::mmio::*;
+use crate::scheduler::{EventHandle, GpuCycles};
 use crate::ScheduleTarget::{TimerOverflow, TimerTarget};
+use log::{trace, warn};
 
 #[derive(PartialEq, Debug)]
 enum Cause {
@@ -17,6 +19,7 @@ enum Source {
     HBlank
 }
 
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct Timer {
     timer_number: usize,
     pub value: u32,
@@ -58,9 +61,13 @@ impl Timer {
     }
 
     fn read_value(&self, scheduler: &mut Scheduler) -> u16 {
-        if let Some(handle) = &self.overflow_event_handle {
+        if self.counts_hblanks() {
+            self.value as u16
+        } else if let Some(handle) = &self.overflow_event_handle {
             if let Some(cycles_remaining) = scheduler.cycles_remaining(handle) {
-                0xFFFF - (((cycles_remaining.0 as f32) / (self.overflow_cpu_cycles as f32)) * 0xFFFF as f32) as u16
+                // Round down, so the counter only ticks once a whole tick has passed
+                let elapsed = self.overflow_cpu_cycles.saturating_sub(cycles_remaining.0) as u64;
+                (elapsed * 0xFFFF / self.overflow_cpu_cycles.max(1) as u64) as u16
             } else {
                 0
             }
@@ -73,6 +80,12 @@ impl Timer {
         // Get rid of old timer events
         scheduler.invalidate_exact_events_of_target(TimerTarget(self.timer_number as u32));
         scheduler.invalidate_exact_events_of_target(TimerOverflow(self.timer_number as u32));
+        self.overflow_event_handle = None;
+
+        // Hblanks are counted as they happen, so there is nothing to schedule
+        if self.counts_hblanks() {
+            return;
+        }
 
         // Schedule events for timer expiration
         // Event when target reached
@@ -88,6 +101,60 @@ impl Timer {
         self.overflow_event_handle = Some(scheduler.schedule_event(TimerOverflow(self.timer_number as u32), overflow_cycles));
     }
 
+    /// Applies the sync mode at the start of the blank this timer syncs to. Only the modes that
+    /// reset the counter are emulated here. Pausing would need the counter to stop between events,
+    /// so only hblank counting pauses, in count_hblank
+    fn blank_start(&mut self, scheduler: &mut Scheduler) {
+        if self.mode.get_bit(0) && matches!(self.mode.get_bits(1..=2), 1 | 2) {
+            self.value = 0;
+            self.reschedule_events(scheduler);
+        }
+    }
+
+    fn counts_hblanks(&self) -> bool {
+        matches!(self.source(), Source::HBlank)
+    }
+
+    /// Ticks a timer counting hblanks. Sync modes 0 and 2 pause it during and outside of vblank
+    fn count_hblank(&mut self, cpu: &mut R3000, in_vblank: bool) {
+        if self.mode.get_bit(0) {
+            match self.mode.get_bits(1..=2) {
+                0 if in_vblank => return,
+                2 if !in_vblank => return,
+                _ => (),
+            }
+        }
+
+        self.value += 1;
+        if self.value == self.target {
+            self.reached(cpu, Cause::Target);
+            if self.mode.get_bit(3) {
+                self.value = 0;
+            }
+        }
+        if self.value > 0xFFFF {
+            self.reached(cpu, Cause::Full);
+            self.value = 0;
+        }
+    }
+
+    /// Sets the reached flag for the cause, and fires the IRQ if it is enabled for it
+    fn reached(&mut self, cpu: &mut R3000, cause: Cause) {
+        let (flag_bit, irq_bit) = match cause {
+            Cause::Target => (11, 4),
+            Cause::Full => (12, 5),
+        };
+        self.mode.set_bit(flag_bit, true);
+
+        if !self.irq_fired && self.mode.get_bit(irq_bit) {
+            // If in one shot mode, disable further IRQs
+            if !self.mode.get_bit(6) {
+                self.irq_fired = true;
+            }
+            cpu.fire_external_interrupt(self.irq_source());
+        }
+    }
+
     fn source(&self) -> Source {
         match self.timer_number {
             0 => {
@@ -126,15 +193,17 @@ impl Timer {
             Source::Sys => CpuCycles(cycle_count).into(),
             Source::SysDiv => CpuCycles(cycle_count * 8).into(),
             Source::Dot => GpuCycles(cycle_count).into(),
-            Source::HBlank => HBlankCycles(cycle_count).into(),
+            Source::HBlank => unreachable!("Hblank timers count events instead of cycles"),
         }
     }
 }
 
+#[cfg_attr(feature = "savestate", derive(serde::Serialize, serde::Deserialize))]
 pub struct TimerState {
     pub timer_0: Timer,
     pub timer_1: Timer,
     pub timer_2: Timer,
+    in_vblank: bool,
 }
 
 impl TimerState {
@@ -143,9 +212,28 @@ impl TimerState {
             timer_0: Timer::new(0),
             timer_1: Timer::new(1),
             timer_2: Timer::new(2),
+            in_vblank: false,
+        }
+    }
+
+    /// Called by the scheduler as each hblank starts. Timer 0 can sync to it, and timer 1 can count it
+    pub fn hblank_start(&mut self, cpu: &mut R3000, scheduler: &mut Scheduler) {
+        self.timer_0.blank_start(scheduler);
+        if self.timer_1.counts_hblanks() {
+            self.timer_1.count_hblank(cpu, self.in_vblank);
         }
     }
 
+    /// Called by the scheduler as each vblank starts. Timer 1 can sync to it
+    pub fn vblank_start(&mut self, scheduler: &mut Scheduler) {
+        self.in_vblank = true;
+        self.timer_1.blank_start(scheduler);
+    }
+
+    pub fn vblank_end(&mut self) {
+        self.in_vblank = false;
+    }
+
     pub fn timer_overflow_event(&mut self, cpu: &mut R3000, scheduler: &mut Scheduler, timer_num: u32) {
         let timer = match timer_num {
             0 => &mut self.timer_0,
@@ -154,15 +242,7 @@ impl TimerState {
             _ => panic!("Unknown timer num!")
         };
 
-        timer.mode.set_bit(12, true);
-
-        if !timer.irq_fired && timer.mode.get_bit(5) {
-            // If in one shot mode, disable further IRQs
-            if !timer.mode.get_bit(6) {
-                timer.irq_fired = true;
-            }
-            cpu.fire_external_interrupt(timer.irq_source());
-        }
+        timer.reached(cpu, Cause::Full);
 
         timer.value = 0;
 
@@ -180,15 +260,7 @@ impl TimerState {
             _ => panic!("Unknown timer num!")
         };
 
-        timer.mode.set_bit(11, true);
-
-        if !timer.irq_fired && timer.mode.get_bit(4) {
-            // If in one shot mode, disable further IRQs
-            if !timer.mode.get_bit(6) {
-                timer.irq_fired = true;
-            }
-            cpu.fire_external_interrupt(timer.irq_source());
-        }
+        timer.reached(cpu, Cause::Target);
 
         timer.value = timer.target;
         if timer.mode.get_bit(3) {
@@ -216,19 +288,19 @@ impl TimerState {
 
     pub fn read_word(&mut self, addr: u32, scheduler: &mut Scheduler) -> u32 {
         let val = match addr {
-            0x1F801100 => self.timer_0.read_value(scheduler) as u32,
-            0x1F801104 => self.timer_0.read_mode(),
-            0x1F801108 => self.timer_0.target,
+            TIMER_0_COUNT => self.timer_0.read_value(scheduler) as u32,
+            TIMER_0_MODE => self.timer_0.read_mode(),
+            TIMER_0_TARGET => self.timer_0.target,
 
-            0x1F801110 => self.timer_1.read_value(scheduler) as u32,
-            0x1F801114 => self.timer_1.read_mode(),
-            0x1F801118 => self.timer_1.target,
+            TIMER_1_COUNT => self.timer_1.read_value(scheduler) as u32,
+            TIMER_1_MODE => self.timer_1.read_mode(),
+            TIMER_1_TARGET => self.timer_1.target,
 
-            0x1F801120 => self.timer_2.read_value(scheduler) as u32,
-            0x1F801124 => self.timer_2.read_mode(),
-            0x1F801128 => self.timer_2.target,
+            TIMER_2_COUNT => self.timer_2.read_value(scheduler) as u32,
+            TIMER_2_MODE => self.timer_2.read_mode(),
+            TIMER_2_TARGET => self.timer_2.target,
             _ => {
-                println!("Unknown timer address {:#X}. Returning 0", addr);
+                warn!(target: "psx::timer", "Unknown timer address {:#X}. Returning 0", addr);
                 0
             }
         };
@@ -237,105 +309,49 @@ impl TimerState {
     }
 
     pub fn write_word(&mut self, addr: u32, val: u32, scheduler: &mut Scheduler) {
-        println!("Timer write word addr {:#X} val {:#X}", addr, val);
+        trace!(target: "psx::timer", "Timer write word addr {:#X} val {:#X}", addr, val);
         match addr {
-            0x1F801100 => {
+            TIMER_0_COUNT => {
                 self.timer_0.value = val;
                 self.timer_0.reschedule_events(scheduler);
             },
-            0x1F801104 => self.timer_0.write_mode(val, scheduler),
-            0x1F801108 => {
+            TIMER_0_MODE => self.timer_0.write_mode(val, scheduler),
+            TIMER_0_TARGET => {
                 self.timer_0.target = val;
                 self.timer_0.reschedule_events(scheduler);
             },
 
-            0x1F801110 => {
+            TIMER_1_COUNT => {
                 self.timer_1.value = val;
                 self.timer_1.reschedule_events(scheduler);
             },
-            0x1F801114 => self.timer_1.write_mode(val, scheduler),
-            0x1F801118 => {
+            TIMER_1_MODE => self.timer_1.write_mode(val, scheduler),
+            TIMER_1_TARGET => {
                 self.timer_1.target = val;
                 self.timer_1.reschedule_events(scheduler);
             }
 
-            0x1F801120 => {
+            TIMER_2_COUNT => {
                 self.timer_2.value = val;
                 self.timer_2.reschedule_events(scheduler);
             },
-            0x1F801124 => self.timer_2.write_mode(val, scheduler),
-            0x1F801128 => {
+            TIMER_2_MODE => self.timer_2.write_mode(val, scheduler),
+            TIMER_2_TARGET => {
                 self.timer_2.target = val;
                 self.timer_2.reschedule_events(scheduler);
             }
-            _ => println!("Unknown timer address"),
+            _ => warn!(target: "psx::timer", "Wrote unknown timer address {:#X}", addr),
         }
     }
 
     pub fn read_half_word(&mut self, addr: u32, scheduler: &mut Scheduler) -> u16 {
-        //println!("Reading halfword timer addr {:#X}", addr);
-
-        match addr {
-            0x1F801100 => self.timer_0.read_value(scheduler) as u16,
-            //0x1F801102 => (self.timer_0.read_value(scheduler) >> 16) as u16,
-
-            0x1F801104 => self.timer_0.read_mode() as u16,
-            0x1F801106 => (self.timer_0.read_mode() >> 16) as u16,
-
-            0x1F801108 => self.timer_0.target as u16,
-            0x1F80110A => (self.timer_0.target >> 16) as u16,
-
-            0x1F801110 => self.timer_1.read_value(scheduler) as u16,
-            //0x1F801112 => (self.timer_1.read_value(scheduler) >> 16) as u16,
-
-            0x1F801114 => self.timer_1.read_mode() as u16,
-            0x1F801116 => (self.timer_1.read_mode() >> 16) as u16,
-
-            0x1F801118 => self.timer_1.target as u16,
-            0x1F80111A => (self.timer_1.target >> 16) as u16,
-
-            0x1F801120 => self.timer_2.read_value(scheduler) as u16,
-            //0x1F801122 => (self.timer_2.read_value(scheduler) >> 16) as u16,
-
-            0x1F801124 => self.timer_2.read_mode() as u16,
-            0x1F801126 => (self.timer_2.read_mode() >> 16) as u16,
-
-            0x1F801128 => self.timer_2.target as u16,
-            0x1F80112A => (self.timer_2.target >> 16) as u16,
-            _ => {
-                println!("Unknown timer address. Returning 0");
-                0
-            }
-        }
+        extract_half(self.read_word(word_address(addr), scheduler), addr)
     }
 
+    /// The registers are 16 bits wide, so writes to their upper halves go nowhere
     pub fn write_half_word(&mut self, addr: u32, value: u16, scheduler: &mut Scheduler,) {
-        //println!("Tried to write timer half addr {:#X} val {:#X}", addr, value);
-        match addr {
-            0x1F801100 => self.timer_0.value &= (value as u32) & 0xFFFF0000,
-            0x1F801102 => self.timer_0.value &= ((value as u32) << 16) & 0xFFFF,
-
-            0x1F801104 => self.timer_0.write_mode(value as u32, scheduler),
-            //0x1F801106 => self.timer_0.read_mode(value as u32),
-            0x1F801108 => self.timer_0.target = value as u32,
-            0x1F80110A => self.timer_0.target &= (value as u32) << 16,
-
-            0x1F801110 => self.timer_1.value &= value as u32,
-            0x1F801112 => self.timer_1.value &= (value as u32) << 16,
-
-            0x1F801114 => self.timer_1.write_mode(value as u32, scheduler),
-            //0x1F801116 => self.timer_1.read_mode(value as u32),
-            0x1F801118 => self.timer_1.target = value as u32,
-            //0x1F80111A => (self.timer_1.target >> 16) as u16,
-            0x1F801120 => self.timer_2.value = value as u32,
-            //0x1F801122 => (self.timer_2.value >> 16) as u16,
-            0x1F801124 => self.timer_2.write_mode(value as u32, scheduler),
-            //0x1F801126 => (self.timer_2.read_mode() >> 16) as u16,
-            0x1F801128 => self.timer_2.target = value as u32,
-            //0x1F80112A => (self.timer_2.target >> 16) as u16,
-            _ => {
-                println!("Half wrote unknown timer address {:#X}", addr)
-            }
+        if addr & 0x2 == 0 {
+            self.write_word(addr, value as u32, scheduler);
         }
     }
 }
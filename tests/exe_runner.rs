@@ -0,0 +1,70 @@
+//! Runs every PS-X EXE in tests/roms and checks that it exits with status 0.
+//! See tests/roms/README.md for how ROMs report results.
+
+use std::fs;
+use std::path::Path;
+
+use psx_emu::{PSXEmu, RunStatus};
+
+const ROM_DIR: &str = "tests/roms";
+const BIOS_SIZE: usize = 512 * 1024;
+/// Ten seconds of emulated time. ROMs that haven't exited by then are counted as hung
+const MAX_FRAMES: usize = 600;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Runs a ROM until it exits. Returns the exit status and the test log, or an error if it never exits
+fn run_rom(path: &Path) -> Result<(u16, String), String> {
+    let exe = fs::read(path).map_err(|e| format!("unable to read: {}", e))?;
+    if exe.len() < 0x800 || &exe[0..8] != b"PS-X EXE" {
+        return Err("not a PS-X EXE".to_string());
+    }
+
+    // A zeroed BIOS runs nops until the fast exe load hook jumps to the ROM
+    let mut emu = PSXEmu::new(vec![0; BIOS_SIZE]).unwrap();
+    emu.set_test_harness(true);
+    emu.load_executable(
+        read_u32(&exe, 0x18),
+        read_u32(&exe, 0x10),
+        read_u32(&exe, 0x30),
+        &exe[0x800..].to_vec(),
+    );
+
+    let mut log = String::new();
+    for _ in 0..MAX_FRAMES {
+        let run = emu.run_frame();
+        log.push_str(&emu.take_test_log());
+        if let RunStatus::ExitRequested(status) = run {
+            return Ok((status, log));
+        }
+    }
+    Err(format!("didn't exit within {} frames. Log:\n{}", MAX_FRAMES, log))
+}
+
+#[test]
+fn test_roms_exit_cleanly() {
+    let mut roms: Vec<_> = fs::read_dir(ROM_DIR)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("exe")))
+        .collect();
+    roms.sort();
+    assert!(!roms.is_empty(), "No ROMs found in {}", ROM_DIR);
+
+    let failures: Vec<String> = roms
+        .iter()
+        .filter_map(|rom| match run_rom(rom) {
+            Ok((0, _)) => None,
+            Ok((status, log)) => Some(format!(
+                "{} exited with status {}. Log:\n{}",
+                rom.display(),
+                status,
+                log
+            )),
+            Err(e) => Some(format!("{} {}", rom.display(), e)),
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
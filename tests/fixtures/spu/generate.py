@@ -0,0 +1,116 @@
+#!/usr/bin/env python3
+"""Generates the SPU pitch fixtures used by the voice tests in src/spu/voice.rs.
+
+This is a straightforward reference implementation, written separately from the emulator: it
+decodes the whole looped sample up front, steps a pitch counter over it and interpolates in
+floating point. Only the gaussian table is shared, and it's read out of voice.rs.
+
+Run from the repository root: python3 tests/fixtures/spu/generate.py
+"""
+
+import math
+import re
+import struct
+from pathlib import Path
+
+FIXTURE_DIR = Path(__file__).parent
+VOICE_SOURCE = FIXTURE_DIR.parent.parent.parent / "src" / "spu" / "voice.rs"
+
+PITCHES = [0x0400, 0x0800, 0x0C00, 0x1000, 0x1234, 0x2000, 0x3FFF]
+OUTPUT_SAMPLES = 512
+BLOCKS = 4
+SAMPLES_PER_BLOCK = 28
+MAX_LEVEL = 0x7FFF
+
+FILTERS = [(0, 0), (60, 0), (115, -52), (98, -55), (122, -60)]
+
+
+def gauss_table():
+    source = VOICE_SOURCE.read_text()
+    body = re.search(r"GAUSS_TABLE: \[i16; 512\] = \[(.*?)\];", source, re.S).group(1)
+    table = [int(value, 16) for value in re.findall(r"-?0x[0-9A-F]+", body)]
+    assert len(table) == 512
+    return table
+
+
+def waveform():
+    """Two periods of a tone with a strong 9th harmonic, quantized to 4 bits"""
+    length = BLOCKS * SAMPLES_PER_BLOCK
+    samples = []
+    for n in range(length):
+        phase = 2 * math.pi * n / length
+        value = 5 * math.sin(2 * phase) + 2.5 * math.sin(9 * phase)
+        samples.append(max(-8, min(7, round(value))))
+    return samples
+
+
+def encode(nibbles):
+    """ADPCM blocks with filter 0 and shift 0. The first block starts the loop, the last repeats it"""
+    data = bytearray()
+    for block in range(BLOCKS):
+        flags = 0x4 if block == 0 else 0x3 if block == BLOCKS - 1 else 0x0
+        data += bytes([0x00, flags])
+        chunk = nibbles[block * SAMPLES_PER_BLOCK:(block + 1) * SAMPLES_PER_BLOCK]
+        for i in range(0, SAMPLES_PER_BLOCK, 2):
+            data.append((chunk[i] & 0xF) | ((chunk[i + 1] & 0xF) << 4))
+    return bytes(data)
+
+
+def decode(data, count):
+    """Decodes at least `count` samples, following the loop flags"""
+    samples = []
+    older, old = 0, 0
+    address = 0
+    repeat = 0
+    while len(samples) < count:
+        header, flags = data[address], data[address + 1]
+        if flags & 0x4:
+            repeat = address
+        shift = header & 0xF
+        shift = 9 if shift > 12 else shift
+        positive, negative = FILTERS[min((header >> 4) & 0x7, 4)]
+        for i in range(SAMPLES_PER_BLOCK):
+            byte = data[address + 2 + i // 2]
+            nibble = byte & 0xF if i % 2 == 0 else byte >> 4
+            raw = (nibble - 16 if nibble >= 8 else nibble) * 4096 >> shift
+            sample = raw + ((old * positive + older * negative + 32) >> 6)
+            sample = max(-0x8000, min(0x7FFF, sample))
+            older, old = old, sample
+            samples.append(sample)
+        if flags & 0x1:
+            assert flags & 0x2, "The fixture sample should loop forever"
+            address = repeat
+        else:
+            address += 16
+    return samples
+
+
+def render(table, samples, pitch):
+    output = []
+    counter = 0
+    for _ in range(OUTPUT_SAMPLES):
+        position = counter >> 12
+        index = (counter >> 4) & 0xFF
+        weights = [table[0xFF - index], table[0x1FF - index], table[0x100 + index], table[index]]
+        # Nothing has been played before the voice starts
+        window = [samples[n] if n >= 0 else 0 for n in range(position - 3, position + 1)]
+        value = sum(w * s for w, s in zip(weights, window)) / 0x8000
+        output.append((math.floor(value) * MAX_LEVEL) >> 15)
+        counter += min(pitch, 0x4000)
+    return output
+
+
+def main():
+    table = gauss_table()
+    data = encode(waveform())
+    (FIXTURE_DIR / "looped_sample.adpcm").write_bytes(data)
+
+    samples = decode(data, (OUTPUT_SAMPLES * 4) + 4 * SAMPLES_PER_BLOCK)
+    for pitch in PITCHES:
+        output = render(table, samples, pitch)
+        path = FIXTURE_DIR / "pitch_{:04x}.pcm".format(pitch)
+        path.write_bytes(struct.pack("<{}h".format(len(output)), *output))
+
+
+if __name__ == "__main__":
+    main()
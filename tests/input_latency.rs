@@ -0,0 +1,57 @@
+//! Measures how many frames a pad update takes to reach a game, using
+//! tests/roms/input/pad_latency.exe. See tests/roms/README.md for what the ROM does.
+
+use std::fs;
+
+use psx_emu::controller::ButtonState;
+use psx_emu::{PSXEmu, RunStatus};
+
+const ROM_PATH: &str = "tests/roms/input/pad_latency.exe";
+const BIOS_SIZE: usize = 512 * 1024;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[test]
+fn test_press_before_vblank_is_seen_next_frame() {
+    let exe = fs::read(ROM_PATH).unwrap();
+    let mut emu = PSXEmu::new(vec![0; BIOS_SIZE]).unwrap();
+    emu.set_test_harness(true);
+    emu.load_executable(
+        read_u32(&exe, 0x18),
+        read_u32(&exe, 0x10),
+        read_u32(&exe, 0x30),
+        &exe[0x800..].to_vec(),
+    );
+
+    // Nothing is pressed yet, so the ROM keeps polling
+    for _ in 0..5 {
+        assert_eq!(emu.run_frame(), RunStatus::Completed);
+    }
+    let start = emu.cycle_count();
+    assert_eq!(emu.run_frame(), RunStatus::Completed);
+    let frame_cycles = emu.cycle_count() - start;
+
+    // Press a thousand cycles before the next vblank
+    assert_eq!(emu.run_cycles(frame_cycles - 1000), RunStatus::Completed);
+    let mut pressed = ButtonState::new_digital_pad();
+    pressed.button_x = true;
+    emu.update_controller_state(pressed);
+
+    let mut frames = 0;
+    let status = loop {
+        frames += 1;
+        assert!(frames <= 10, "The ROM never saw the press");
+        if let RunStatus::ExitRequested(status) = emu.run_frame() {
+            break status;
+        }
+    };
+
+    assert_eq!(status, 0);
+    // The first run_frame finishes the frame the press landed in, and the poll after that
+    // vblank sees it
+    assert_eq!(frames, 2);
+    // The pad's second button byte, with cross held
+    assert_eq!(emu.take_test_log(), "\u{BF}");
+}
@@ -0,0 +1,32 @@
+//! Fails if the core prints straight to stdout or stderr. Everything should go through the log
+//! crate, or a buffer the frontend drains, so embedders and headless runs control the output.
+
+use std::fs;
+use std::path::Path;
+
+const PRINT_MACROS: [&str; 5] = ["print!(", "println!(", "eprint!(", "eprintln!(", "dbg!("];
+
+fn collect_prints(dir: &Path, found: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_prints(&path, found);
+        } else if path.extension().is_some_and(|ext| ext == "rs") {
+            let source = fs::read_to_string(&path).unwrap();
+            for (number, line) in source.lines().enumerate() {
+                // Commented out debugging code is fine
+                let code = line.split("//").next().unwrap();
+                if PRINT_MACROS.iter().any(|print| code.contains(print)) {
+                    found.push(format!("{}:{}: {}", path.display(), number + 1, line.trim()));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn core_does_not_print() {
+    let mut found = vec![];
+    collect_prints(Path::new("src"), &mut found);
+    assert!(found.is_empty(), "Use the log crate instead of printing:\n{}", found.join("\n"));
+}
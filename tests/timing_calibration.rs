@@ -0,0 +1,91 @@
+//! Measures the video timings with the root counters and checks them against a real NTSC console.
+//! The CPU, GPU and timer clocks all have to agree for these to line up.
+//!
+//! This doesn't boot a BIOS. It runs an idle loop and times vblanks itself, so it says nothing about
+//! what a real BIOS stores in RAM after booting.
+
+use psx_emu::PSXEmu;
+
+const BIOS_SIZE: usize = 512 * 1024;
+const CODE_ADDR: u32 = 0x8001_0000;
+
+const TIMER1_VALUE: u32 = 0x1F80_1110;
+const TIMER1_MODE: u32 = 0x1F80_1114;
+const TIMER2_VALUE: u32 = 0x1F80_1120;
+const TIMER2_MODE: u32 = 0x1F80_1124;
+
+const CPU_CLOCK: f64 = 33_868_800.0;
+const NTSC_VIDEO_CLOCK: f64 = 53_693_175.0;
+const NTSC_LINES: u32 = 263;
+const NTSC_LINE_CYCLES: f64 = 3413.0;
+/// How far a measured frame may be from the real one
+const FRAME_TOLERANCE: f64 = 0.0005;
+
+/// A machine spinning in a loop with interrupts masked, so the test can poll I_STAT itself
+fn idle_emu() -> PSXEmu {
+    let idle_loop = [(0x02 << 26) | (CODE_ADDR & 0x0FFF_FFFF) >> 2, 0u32];
+    let exe = idle_loop
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+    let mut emu = PSXEmu::new(vec![0; BIOS_SIZE]).unwrap();
+    emu.load_executable(CODE_ADDR, CODE_ADDR, 0, &exe);
+    emu
+}
+
+fn read(emu: &mut PSXEmu, addr: u32) -> u32 {
+    emu.main_bus.read_word(addr, &mut emu.scheduler)
+}
+
+fn write(emu: &mut PSXEmu, addr: u32, value: u32) {
+    emu.main_bus.write_word(addr, value, &mut emu.scheduler);
+}
+
+/// Steps until the VBLANK IRQ is raised, then acknowledges it. Returns the cycles it took
+fn wait_for_vblank(emu: &mut PSXEmu) -> u64 {
+    let start = emu.cycle_count();
+    while emu.r3000.i_status & 1 == 0 {
+        emu.step_cycle();
+    }
+    emu.r3000.i_status &= !1;
+    emu.cycle_count() - start
+}
+
+#[test]
+fn test_ntsc_frame_timing() {
+    let mut emu = idle_emu();
+    wait_for_vblank(&mut emu);
+
+    for frame in 0..3 {
+        // Timer 1 counts hblanks and timer 2 counts the system clock / 8
+        write(&mut emu, TIMER1_MODE, 0x100);
+        write(&mut emu, TIMER2_MODE, 0x200);
+        let cycles = wait_for_vblank(&mut emu) as f64;
+        let hblanks = read(&mut emu, TIMER1_VALUE);
+        let sys_div_8 = read(&mut emu, TIMER2_VALUE);
+
+        let expected_cycles = NTSC_LINES as f64 * NTSC_LINE_CYCLES * CPU_CLOCK / NTSC_VIDEO_CLOCK;
+        assert!(
+            (cycles / expected_cycles - 1.0).abs() < FRAME_TOLERANCE,
+            "frame {} took {} cycles, expected {:.0}",
+            frame,
+            cycles,
+            expected_cycles
+        );
+        assert!(
+            (NTSC_LINES - 1..=NTSC_LINES).contains(&hblanks),
+            "frame {} had {} hblanks",
+            frame,
+            hblanks
+        );
+        // Timer 2 wraps during a frame
+        let expected_sys_div_8 = (cycles / 8.0) as u32 % 0x10000;
+        assert!(
+            sys_div_8.abs_diff(expected_sys_div_8) <= 2,
+            "frame {} counted {:#X} system clocks / 8, expected {:#X}",
+            frame,
+            sys_div_8,
+            expected_sys_div_8
+        );
+    }
+}